@@ -0,0 +1,7 @@
+#!/bin/sh
+set -eu
+
+mkdir -p ~/bin
+cp -a bin/cub-copy bin/cub-paste ~/bin/
+
+tar -c -C ~ --verbatim-files-from --files-from ~/w/provides.txt -f ~/provides.tar
@@ -1,7 +1,7 @@
 #!/bin/bash
 
 if [ -n "${CUBICLE:-}" ]; then
-    HISTFILE="$HOME/w/.bash_history"
+    HISTFILE="$HOME/${CUBICLE_WORK_DIR:-w}/.bash_history"
 fi
 
 HISTCONTROL=ignoreboth
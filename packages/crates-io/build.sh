@@ -1,13 +1,19 @@
 #!/bin/sh
 set -eu
-cd
+# shellcheck source=/dev/null
+. ~/w/.package-manager.sh
 
-if ! [ -e exclude.txt ]; then
-    find .cargo/bin/* > exclude.txt
+cd
+snapshot .cargo/bin
+set -- "$PACKAGE"
+if [ -n "$PACKAGE_VERSION" ]; then
+    set -- --version "$PACKAGE_VERSION" "$@"
 fi
-cargo install --force "$PACKAGE"
+cargo install --force "$@"
 
 # shellcheck disable=SC2016
 echo '$HOME/.cargo/bin' > .config/profile.d/path/33-cargo
 
-tar --create --file provides.tar --exclude-from exclude.txt .cargo/bin .config/profile.d/path/33-cargo
+provide_new .cargo/bin
+provide .config/profile.d/path/33-cargo
+finish
@@ -3,7 +3,7 @@ set -eu
 
 cd
 bin=$(basename "$PACKAGE")
-go install "$PACKAGE"@latest
+go install "$PACKAGE@${PACKAGE_VERSION:-latest}"
 asdf reshim golang
 install="$(asdf where golang)/packages/bin/$bin"
 install="${install#"$HOME/"}"
@@ -0,0 +1,7 @@
+#!/bin/sh
+set -eu
+
+mkdir -p ~/bin
+cp -a bin/cub-host bin/xdg-open ~/bin/
+
+tar -c -C ~ --verbatim-files-from --files-from ~/w/provides.txt -f ~/provides.tar
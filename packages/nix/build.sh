@@ -0,0 +1,7 @@
+#!/bin/sh
+set -eu
+
+# With a `[nix]` section in the Cubicle config, Cubicle builds `nix.*`
+# packages with Nix on the host instead of running this script.
+echo "ERROR: Package nix.$PACKAGE needs a [nix] section in the Cubicle config" >&2
+exit 1
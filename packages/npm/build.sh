@@ -1,17 +1,11 @@
 #!/bin/sh
 set -eu
+# shellcheck source=/dev/null
+. ~/w/.package-manager.sh
 
 cd
 mkdir -p "opt/npm/$PACKAGE"
-cd "opt/npm/$PACKAGE"
-npm install --global-style "$PACKAGE"
-cd node_modules/.bin
-set -- "opt/npm/$PACKAGE"
-for f in *; do
-    echo "Found executable: $f"
-    ln -fs "../opt/npm/$PACKAGE/node_modules/.bin/$f" ~/bin/
-    set -- "$@" "bin/$f"
-done
-
-cd
-tar --create --file provides.tar "$@"
+(cd "opt/npm/$PACKAGE" && npm install --global-style "$PACKAGE@${PACKAGE_VERSION:-latest}")
+provide "opt/npm/$PACKAGE"
+link_bins "opt/npm/$PACKAGE/node_modules/.bin"
+finish
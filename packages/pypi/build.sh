@@ -1,9 +1,21 @@
 #!/bin/sh
 set -eu
+# shellcheck source=/dev/null
+. ~/w/.package-manager.sh
 
-# Note: this assumption is unlikely to hold for many packages.
-BIN="$PACKAGE"
-
+# This installs the package and its dependencies into the user site
+# directory, so libraries can be imported and programs land in
+# `~/.local/bin`.
 cd
-shiv --console-script "$BIN" --output-file "bin/$BIN" "$PACKAGE"
-tar --create --file provides.tar "bin/$BIN"
+site="$(python3 -c 'import site; print(site.getusersitepackages())')"
+site="${site#"$HOME/"}"
+snapshot "$site" .local/bin
+pip3 install --user --upgrade "$PACKAGE$PACKAGE_VERSION"
+
+mkdir -p .config/profile.d/path
+# shellcheck disable=SC2016
+echo '$HOME/.local/bin' > .config/profile.d/path/36-pypi
+
+provide_new "$site" .local/bin
+provide .config/profile.d/path/36-pypi
+finish
@@ -4,6 +4,10 @@ set -eu
 
 shellcheck "$@" \
     packages/apt-binary/bin/apt-binary \
+    packages/clipboard/bin/cub-copy \
+    packages/clipboard/bin/cub-paste \
+    packages/host-commands/bin/cub-host \
+    packages/host-commands/bin/xdg-open \
     packages/configs-core/dot-bash_profile \
     packages/configs-core/dot-bashrc \
     packages/configs-core/dot-profile \
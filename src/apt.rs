@@ -29,7 +29,7 @@ pub fn simulate_satisfy(deps: &[&str]) -> Result<Summary> {
             not_upgraded: 0,
         });
     }
-    let output = Command::new("apt-get")
+    let output = Command::query("apt-get")
         .arg("satisfy")
         .arg("--dry-run")
         .arg("--no-install-recommends")
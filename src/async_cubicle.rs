@@ -0,0 +1,152 @@
+//! An async interface to [`Cubicle`], for applications that run an async
+//! executor like Tokio.
+//!
+//! Cubicle's operations block while they run Docker, Bubblewrap, and other
+//! programs, which may take minutes. [`AsyncCubicle`] runs each of them on a
+//! new thread instead, so that awaiting them doesn't block the executor.
+
+use std::collections::BTreeSet;
+use std::process::{ExitStatus, Output};
+use std::sync::Arc;
+use std::thread;
+
+use tokio::sync::oneshot;
+
+use super::config::Config;
+use super::{Cubicle, EnvironmentName, Force, FullPackageName, KeepHome, Locked, Quiet};
+use crate::somehow::{somehow as anyhow, Context, Result};
+
+/// Runs [`Cubicle`] operations on other threads and awaits their results.
+///
+/// Operations may run concurrently, as with a [`Cubicle`] shared between
+/// threads. Clones share the same `Cubicle`.
+#[derive(Clone)]
+pub struct AsyncCubicle {
+    cubicle: Arc<Cubicle>,
+}
+
+impl From<Cubicle> for AsyncCubicle {
+    fn from(cubicle: Cubicle) -> Self {
+        Self {
+            cubicle: Arc::new(cubicle),
+        }
+    }
+}
+
+impl AsyncCubicle {
+    /// Creates a [`Cubicle`], as in [`Cubicle::new`].
+    ///
+    /// This blocks while the `Cubicle` is created, which is usually quick.
+    pub fn new(config: Config) -> Result<Self> {
+        Ok(Self::from(Cubicle::new(config)?))
+    }
+
+    /// Runs `f` with the [`Cubicle`] on a new thread and returns its result.
+    ///
+    /// This is the general form of the other methods, for operations that
+    /// don't have their own.
+    pub async fn run<F, T>(&self, f: F) -> Result<T>
+    where
+        F: FnOnce(&Cubicle) -> Result<T> + Send + 'static,
+        T: Send + 'static,
+    {
+        let (sender, receiver) = oneshot::channel();
+        let cubicle = self.cubicle.clone();
+        thread::Builder::new()
+            .name(String::from("cubicle"))
+            .spawn(move || {
+                // The caller may have stopped waiting for the result.
+                let _ = sender.send(f(&cubicle));
+            })
+            .context("failed to start Cubicle thread")?;
+        receiver
+            .await
+            .map_err(|_| anyhow!("Cubicle thread panicked"))?
+    }
+
+    /// Async version of [`Cubicle::new_environment`].
+    pub async fn new_environment(
+        &self,
+        name: EnvironmentName,
+        packages: Option<BTreeSet<FullPackageName>>,
+    ) -> Result<()> {
+        self.run(move |cub| cub.new_environment(&name, packages))
+            .await
+    }
+
+    /// Async version of [`Cubicle::reset_environment`].
+    pub async fn reset_environment(
+        &self,
+        name: EnvironmentName,
+        packages: Option<BTreeSet<FullPackageName>>,
+        force: Force,
+        keep_home: KeepHome,
+        locked: Locked,
+    ) -> Result<()> {
+        self.run(move |cub| cub.reset_environment(&name, packages, force, keep_home, locked))
+            .await
+    }
+
+    /// Async version of [`Cubicle::purge_environment`].
+    pub async fn purge_environment(
+        &self,
+        name: EnvironmentName,
+        quiet: Quiet,
+        force: Force,
+    ) -> Result<()> {
+        self.run(move |cub| cub.purge_environment(&name, quiet, force))
+            .await
+    }
+
+    /// Async version of [`Cubicle::exec_environment_status`].
+    pub async fn exec_environment_status(
+        &self,
+        name: EnvironmentName,
+        command: Vec<String>,
+    ) -> Result<ExitStatus> {
+        self.run(move |cub| cub.exec_environment_status(&name, &command))
+            .await
+    }
+
+    /// Async version of [`Cubicle::exec_environment_captured`].
+    pub async fn exec_environment_captured(
+        &self,
+        name: EnvironmentName,
+        command: Vec<String>,
+    ) -> Result<Output> {
+        self.run(move |cub| cub.exec_environment_captured(&name, &command))
+            .await
+    }
+
+    /// Async version of [`Cubicle::get_environment_names`].
+    pub async fn get_environment_names(&self) -> Result<BTreeSet<EnvironmentName>> {
+        self.run(|cub| cub.get_environment_names()).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+    use std::str::FromStr;
+
+    #[test]
+    fn run() {
+        let root = tempfile::tempdir().unwrap();
+        let cub = AsyncCubicle::from(testing::cubicle(root.path(), ""));
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+
+        let name = EnvironmentName::from_str("async").unwrap();
+        runtime
+            .block_on(cub.new_environment(name.clone(), Some(BTreeSet::new())))
+            .unwrap();
+        let names = runtime.block_on(cub.get_environment_names()).unwrap();
+        assert_eq!(names, BTreeSet::from([name]));
+        let err = runtime
+            .block_on(cub.run(|_| -> Result<()> { Err(anyhow!("oops")) }))
+            .unwrap_err();
+        assert_eq!(err.to_string(), "oops");
+    }
+}
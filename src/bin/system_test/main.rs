@@ -12,8 +12,9 @@ use clap::Parser;
 use cubicle::config::Config;
 use cubicle::somehow::{somehow as anyhow, Context, Result};
 use cubicle::{
-    Cubicle, EnvironmentName, FullPackageName, ListFormat, ListPackagesFormat, Quiet,
-    ShouldPackageUpdate, UpdatePackagesConditions,
+    init_logging, Cubicle, EnvironmentName, Ephemeral, Force, FullPackageName, KeepHome,
+    ListFormat, ListPackagesFormat, ListSort, Locked, Platform, Quiet, Refresh,
+    ShouldPackageUpdate, UpdatePackagesConditions, Verbosity,
 };
 use expect_test::expect;
 use std::collections::BTreeSet;
@@ -43,7 +44,7 @@ fn rewrite<P: AsRef<Path>>(path: P) -> Result<()> {
 }
 
 fn test_package_not_found_errors(cub: &Cubicle, test_env: &EnvironmentName) -> Result<()> {
-    cub.purge_environment(test_env, Quiet(false))?;
+    cub.purge_environment(test_env, Quiet(false), Force(true))?;
 
     let not_exist = BTreeSet::from([FullPackageName::from_str("does-not-exist")?]);
 
@@ -62,7 +63,7 @@ fn test_package_not_found_errors(cub: &Cubicle, test_env: &EnvironmentName) -> R
 
     // cub tmp --packages=does-not-exist
     let err = cub
-        .create_enter_tmp_environment(Some(not_exist.clone()))
+        .create_enter_tmp_environment(Some(not_exist.clone()), Ephemeral(false))
         .expect_err("should not be able to use does-not-exist package in `cub tmp`");
     expect![[r#"could not find package definition for "does-not-exist""#]]
         .assert_eq(&err.debug_without_backtrace());
@@ -80,7 +81,13 @@ fn test_package_not_found_errors(cub: &Cubicle, test_env: &EnvironmentName) -> R
     cub.new_environment(test_env, Some(BTreeSet::new()))?;
     cub.exec_environment(test_env, &[String::from("touch"), String::from("../foo")])?;
     let err = cub
-        .reset_environment(test_env, Some(not_exist.clone()))
+        .reset_environment(
+            test_env,
+            Some(not_exist.clone()),
+            Force(true),
+            KeepHome(false),
+            Locked(false),
+        )
         .expect_err("should not be able to use does-not-exist package in `cub reset`");
     expect![[r#"could not find package definition for "does-not-exist""#]]
         .assert_eq(&err.debug_without_backtrace());
@@ -96,6 +103,7 @@ fn test_package_not_found_errors(cub: &Cubicle, test_env: &EnvironmentName) -> R
                 dependencies: ShouldPackageUpdate::Always,
                 named: ShouldPackageUpdate::Always,
             },
+            &Platform::host(),
         )
         .expect_err("should not be able to use does-not-exist package in `cub tmp`");
     expect![[r#"could not find package definition for "does-not-exist""#]]
@@ -117,31 +125,32 @@ fn main() -> Result<()> {
     };
 
     let args = Args::parse();
+    init_logging(Verbosity::default());
     let config = Config::read_from_file(&args.config)?;
     let cub = Cubicle::new(config)?;
 
     let test_env = EnvironmentName::from_str("system_test")?;
     let configs_pkg = FullPackageName::from_str("configs-interactive")?;
 
-    cub.list_environments(ListFormat::Default)?;
+    cub.list_environments(ListFormat::Default, &[], ListSort::Name, Refresh(true))?;
 
     test_package_not_found_errors(&cub, &test_env)?;
 
-    cub.purge_environment(&test_env, Quiet(false))?;
+    cub.purge_environment(&test_env, Quiet(false), Force(true))?;
     cub.new_environment(&test_env, Some(BTreeSet::new()))?;
     cub.exec_environment(&test_env, &["ls", "-l", ".."].map(String::from))?;
-    cub.reset_environment(&test_env, None)?;
+    cub.reset_environment(&test_env, None, Force(true), KeepHome(false), Locked(false))?;
 
-    cub.purge_environment(&test_env, Quiet(false))?;
+    cub.purge_environment(&test_env, Quiet(false), Force(true))?;
     cub.new_environment(&test_env, Some(BTreeSet::from([configs_pkg])))?;
     cub.exec_environment(&test_env, &["ls", "-al", ".."].map(String::from))?;
     // This should cause the configs-interactive package to be rebuilt.
     rewrite(project_root.join("packages/configs-interactive/build.sh"))?;
-    cub.reset_environment(&test_env, None)?;
+    cub.reset_environment(&test_env, None, Force(true), KeepHome(false), Locked(false))?;
     cub.exec_environment(&test_env, &["ls", "-al", ".."].map(String::from))?;
 
-    cub.list_environments(ListFormat::Default)?;
-    cub.purge_environment(&test_env, Quiet(false))?;
+    cub.list_environments(ListFormat::Default, &[], ListSort::Name, Refresh(true))?;
+    cub.purge_environment(&test_env, Quiet(false), Force(true))?;
 
     cub.list_packages(ListPackagesFormat::Default)?;
     let packages = BTreeSet::from([FullPackageName::from_str("no-op")?]);
@@ -152,8 +161,11 @@ fn main() -> Result<()> {
             dependencies: ShouldPackageUpdate::Always,
             named: ShouldPackageUpdate::Always,
         },
+        &Platform::host(),
     )?;
     cub.list_packages(ListPackagesFormat::Default)?;
 
+    cub.self_test()?;
+
     Ok(())
 }
@@ -1,25 +1,50 @@
 use std::collections::BTreeSet;
+use std::ffi::{OsStr, OsString};
 use std::io::{self, Write};
+use std::os::unix::ffi::OsStrExt;
 use std::os::unix::fs::PermissionsExt;
-use std::path::Path;
-use std::process::{ChildStdout, Stdio};
-use std::rc::Rc;
-use tempfile::NamedTempFile;
-
-use super::apt;
-use super::command_ext::Command;
-use super::fs_util::{rmtree, summarize_dir, try_exists, try_iterdir_dirs, DirSummary};
+use std::path::{Path, PathBuf};
+use std::process::{ChildStdout, ExitStatus, Stdio};
+use std::sync::Arc;
+use tracing::info;
+
+use super::clipboard::env_clipboard_share;
+use super::command_ext::{Command, OutputStream};
+use super::config::NestedContainers;
+use super::copy::{copy_in_to_host_dirs, copy_out_from_host_dirs};
+use super::fs_util::{
+    host_tempfile, rmtree, summarize_dir, tar_size, try_exists, write_seeds, DirSummary,
+};
+use super::host_commands::env_host_commands_share;
+use super::os_util::{get_uids, host_user};
+use super::packages::{shared_mount, SHARED_ENV_PATH};
 use super::paths::EnvPath;
 use super::runner::{
-    EnvFilesSummary, EnvironmentExists, Init, Runner, RunnerCommand, Target,
-    LOCALE_ENVIRONMENT_VARIABLES,
+    env_dir_names, host_audio_sockets, host_container_socket, host_devices, host_wayland_socket,
+    query_host, run_du, systemd_scope_args, ConnectInfo, EnvDirectorySizes, EnvFilesSummary,
+    EnvironmentExists, HostSocket, Init, Orphan, Runner, RunnerCommand, Target,
+    LOCALE_ENVIRONMENT_VARIABLES, SYSTEMD_RUN_ENV_VARS,
 };
-use super::{CubicleShared, EnvironmentName, ExitStatusError, HostPath};
-use crate::somehow::{Context, Result};
+use super::{seed_delta, seed_store};
+use super::{
+    CubicleShared, Distro, EnvironmentName, Event, ExitStatusError, HostPath, WorkspaceName,
+};
+use crate::somehow::{somehow as anyhow, warn, Context, Result};
+
+mod userns;
+use userns::{Ids, UserNamespace};
+
+/// Where the environment init script is mounted in the sandbox.
+const INIT_SCRIPT_PATH: &str = "/cubicle-init.sh";
 
 pub struct Bubblewrap {
-    pub(super) program: Rc<CubicleShared>,
+    pub(super) program: Arc<CubicleShared>,
     home_dirs: HostPath,
+    /// The overlayfs work directories for environments with
+    /// [`overlay_home`](super::config::Bubblewrap::overlay_home). This is
+    /// next to `home_dirs`, since overlayfs needs the work directory on the
+    /// same filesystem as the upper layer.
+    overlay_dirs: HostPath,
     work_dirs: HostPath,
 }
 
@@ -31,26 +56,41 @@ struct Dirs {
 struct BwrapArgs<'a> {
     bind: &'a [(&'a HostPath, &'a EnvPath)],
     run: &'a RunnerCommand<'a>,
-    stdin: Option<ChildStdout>,
+    io: BwrapIo<'a>,
+    home: HomeMount,
 }
 
-impl Bubblewrap {
-    pub(super) fn new(program: Rc<CubicleShared>) -> Result<Self> {
-        let xdg_cache_home = match std::env::var("XDG_CACHE_HOME") {
-            Ok(path) => HostPath::try_from(path)?,
-            Err(_) => program.home.join(".cache"),
-        };
-        let home_dirs = xdg_cache_home.join("cubicle").join("home");
+/// What [`Bubblewrap::bwrap`] connects to the command's standard streams.
+enum BwrapIo<'a> {
+    /// Inherit stdin, stdout, and stderr.
+    Inherit,
+    /// Feed stdin from another process and inherit stdout and stderr.
+    Stdin(ChildStdout),
+    /// Pass stdout and stderr to a callback, with stdin closed. See
+    /// [`Command::stream`].
+    Stream(&'a mut dyn FnMut(OutputStream, &[u8])),
+}
 
-        let xdg_data_home = match std::env::var("XDG_DATA_HOME") {
-            Ok(path) => HostPath::try_from(path)?,
-            Err(_) => program.home.join(".local").join("share"),
-        };
-        let work_dirs = xdg_data_home.join("cubicle").join("work");
+/// How [`Bubblewrap::bwrap`] mounts the environment's home directory.
+#[derive(Clone, Copy, Eq, PartialEq)]
+enum HomeMount {
+    ReadWrite,
+    ReadOnly,
+    /// An empty tmpfs, with no work directory from the host.
+    Tmpfs,
+}
+
+impl Bubblewrap {
+    pub(super) fn new(program: Arc<CubicleShared>) -> Result<Self> {
+        let home_dirs = program.home_dirs.clone();
+        let overlay_dirs =
+            HostPath::try_from(home_dirs.as_host_raw().with_file_name("home-overlay"))?;
+        let work_dirs = program.data_dir.join("work");
 
         Ok(Self {
             program,
             home_dirs,
+            overlay_dirs,
             work_dirs,
         })
     }
@@ -63,102 +103,201 @@ impl Bubblewrap {
         }
     }
 
+    /// Returns the lower layers of the environment's home directory, lowest
+    /// first. This is empty unless the home directory is an overlay.
+    fn overlay_layers(&self, name: &EnvironmentName) -> Result<Vec<HostPath>> {
+        seed_store::references(&self.program, &overlay_user(name))
+    }
+
+    /// Makes the environment's home directory an overlay on top of `layers`,
+    /// or a plain directory if `layers` is empty. Then it removes the builds
+    /// that no environment uses anymore from the seed store.
+    fn set_overlay_layers(&self, name: &EnvironmentName, layers: &[HostPath]) -> Result<()> {
+        let dir = self.overlay_dirs.join(name.as_filename());
+        rmtree(&dir)?;
+        if !layers.is_empty() {
+            std::fs::create_dir_all(dir.join("work").as_host_raw())
+                .with_context(|| format!("failed to create directory {dir}"))?;
+        }
+        seed_store::set_references(&self.program, &overlay_user(name), layers)?;
+        if let Err(e) = seed_store::prune(&self.program) {
+            warn(e.context("failed to remove unused builds from the seed store"));
+        }
+        Ok(())
+    }
+
     fn config(&self) -> &super::config::Bubblewrap {
         self.program
             .config
+            .runners
             .bubblewrap
             .as_ref()
             .expect("Bubblewrap config needed")
     }
 
+    /// Resets the environment's existing home directory by extracting only
+    /// the seed entries that differ from it. See [`seed_delta`].
+    fn reset_incrementally(&self, name: &EnvironmentName, init: &Init) -> Result<()> {
+        let Dirs {
+            host_home,
+            host_work,
+        } = self.dirs(name);
+        let work_dir_name = self.program.config.work_dir_name(name);
+        let delta = seed_delta::prepare(&host_home, work_dir_name, &init.seeds)?;
+        info!(
+            "Updating home directory: {} changed, {} unchanged, {} removed",
+            delta.changed, delta.unchanged, delta.removed
+        );
+        std::fs::create_dir_all(host_work.as_host_raw()).todo_context()?;
+        self.init(
+            name,
+            &Init {
+                distro_packages: init.distro_packages.clone(),
+                env_distro_packages: init.env_distro_packages.clone(),
+                env_vars: init.env_vars.clone(),
+                seeds: vec![HostPath::try_from(delta.tar.path().to_owned())?],
+            },
+        )
+    }
+
     fn init(
         &self,
         name: &EnvironmentName,
         Init {
-            debian_packages,
+            distro_packages,
+            env_distro_packages,
             env_vars,
             seeds,
         }: &Init,
     ) -> Result<()> {
-        apt::check_satisfied(
-            &debian_packages
+        Distro::host().check_host_packages(
+            &distro_packages
                 .iter()
+                .chain(env_distro_packages)
                 .map(|s| s.as_str())
                 .collect::<Vec<&str>>(),
         );
 
+        let had_seeds = !seeds.is_empty();
+        let seeds = if self.config().overlay_home {
+            let (layers, rest) = seed_store::lower_layers(&self.program, seeds)?;
+            self.set_overlay_layers(name, &layers)?;
+            rest
+        } else {
+            if !self.overlay_layers(name)?.is_empty() {
+                // `overlay_home` was turned off, so the seeds are all
+                // extracted into the home directory instead.
+                self.set_overlay_layers(name, &[])?;
+            }
+            seed_store::populate(&self.program, &self.dirs(name).host_home, seeds)
+        };
         if !seeds.is_empty() {
-            println!("Copying/extracting seed tarball");
+            info!("Copying/extracting seed tarball");
+            let mut size: u64 = 0;
+            for seed in seeds {
+                size += tar_size(seed)?;
+            }
+            // The seeds are written to `pv` from here, since some are
+            // compressed.
             let mut child = Command::new("pv")
-                .args(["--interval", "0.1"])
-                .args(seeds.iter().map(|s| s.as_host_raw()))
+                .args(["--interval", "0.1", "--size", &size.to_string()])
+                .stdin(Stdio::piped())
                 .stdout(Stdio::piped())
                 .scoped_spawn()?;
-            self.bwrap(
-                name,
-                BwrapArgs {
-                    bind: &[],
-                    run: &RunnerCommand::Exec {
-                        command: &["tar", "--ignore-zero", "--directory", "..", "--extract"]
-                            .map(|s| s.to_owned()),
-                        env_vars: &[],
+            let mut stdin = child.stdin().take().unwrap();
+            std::thread::scope(|s| {
+                let writer = s.spawn(move || write_seeds(seeds, &mut stdin));
+                self.bwrap(
+                    name,
+                    BwrapArgs {
+                        bind: &[],
+                        run: &RunnerCommand::Exec {
+                            command: &["tar", "--ignore-zero", "--directory", "..", "--extract"]
+                                .map(|s| s.to_owned()),
+                            env_vars: &[],
+                            dir: None,
+                        },
+                        io: child
+                            .stdout()
+                            .take()
+                            .map_or(BwrapIo::Inherit, BwrapIo::Stdin),
+                        home: HomeMount::ReadWrite,
                     },
-                    stdin: child.stdout().take(),
-                },
-            )?;
-        };
+                )
+                .and_then(|status| ExitStatusError::check(status, "bwrap"))?;
+                writer.join().expect("seed writer thread panicked")
+            })?;
+        }
+        if had_seeds {
+            self.program
+                .progress(Event::SeedCopied { environment: name });
+        }
 
-        let host_script_temp = {
-            let file = NamedTempFile::new()
-                .context("failed to create temp file on host for environment init script")?;
-            file.as_file()
-                .set_permissions(std::fs::Permissions::from_mode(0o500))
-                .with_context(|| {
-                    format!(
-                        "failed to set permissions of environment init script on host: {:?}",
-                        file.path()
-                    )
-                })?;
-            file.as_file()
-                .write_all(self.program.env_init_script)
-                .with_context(|| {
-                    format!(
-                        "failed to write environment init script on host: {:?}",
-                        file.path()
-                    )
-                })?;
-            file.into_temp_path()
-        };
+        let host_script_temp = self.write_init_script()?;
         let host_script = HostPath::try_from(host_script_temp.to_path_buf())?;
 
-        let init_script_str = "/cubicle-init.sh";
-        let init_script = EnvPath::try_from(init_script_str.to_owned()).unwrap();
+        let init_script = EnvPath::try_from(INIT_SCRIPT_PATH.to_owned()).unwrap();
         self.bwrap(
             name,
             BwrapArgs {
                 bind: &[(&host_script, &init_script)],
                 run: &RunnerCommand::Exec {
-                    command: &[init_script_str.to_owned()],
+                    command: &[INIT_SCRIPT_PATH.to_owned()],
                     env_vars,
+                    dir: None,
                 },
-                stdin: None,
+                io: BwrapIo::Inherit,
+                home: HomeMount::ReadWrite,
             },
         )
+        .and_then(|status| ExitStatusError::check(status, "bwrap"))
+    }
+
+    /// Writes the environment init script to a temporary file on the host.
+    fn write_init_script(&self) -> Result<tempfile::TempPath> {
+        let file = host_tempfile()
+            .context("failed to create temp file on host for environment init script")?;
+        file.as_file()
+            .set_permissions(std::fs::Permissions::from_mode(0o500))
+            .with_context(|| {
+                format!(
+                    "failed to set permissions of environment init script on host: {:?}",
+                    file.path()
+                )
+            })?;
+        file.as_file()
+            .write_all(self.program.env_init_script)
+            .with_context(|| {
+                format!(
+                    "failed to write environment init script on host: {:?}",
+                    file.path()
+                )
+            })?;
+        Ok(file.into_temp_path())
     }
 
     fn bwrap(
         &self,
         name: &EnvironmentName,
-        BwrapArgs { bind, run, stdin }: BwrapArgs,
-    ) -> Result<()> {
+        BwrapArgs {
+            bind,
+            run,
+            io,
+            home,
+        }: BwrapArgs,
+    ) -> Result<ExitStatus> {
         let Dirs {
             host_home,
             host_work,
         } = self.dirs(name);
+        let layers = match home {
+            HomeMount::ReadWrite | HomeMount::ReadOnly => self.overlay_layers(name)?,
+            HomeMount::Tmpfs => Vec::new(),
+        };
 
         let seccomp: Option<std::fs::File> = {
             use super::config::PathOrDisabled::*;
-            match &self.config().seccomp {
+            match self.config().seccomp_for(name) {
                 Path(path) => Some(
                     std::fs::File::open(path)
                         .with_context(|| format!("failed to open seccomp filter: {path:?}"))?,
@@ -167,21 +306,50 @@ impl Bubblewrap {
             }
         };
 
-        let mut command = Command::new("bwrap");
+        let uids = get_uids();
+        let inside_uid = self.config().uid.unwrap_or(uids.real_user);
+        let inside_gid = self.config().gid.unwrap_or(uids.group);
+        let nested = self.program.config.nested_containers_for(name);
+        // Rootless Podman needs subordinate IDs to map into its containers.
+        let userns = if self.config().subids || nested == Some(NestedContainers::Podman) {
+            Some(
+                UserNamespace::new(&Ids {
+                    user: &host_user(&self.program.config)?,
+                    uid: uids.real_user,
+                    gid: uids.group,
+                    inside_uid,
+                    inside_gid,
+                })
+                .context("failed to set up a user namespace with subordinate IDs")?,
+            )
+        } else {
+            None
+        };
+
+        let limits = systemd_scope_args(&self.program.config);
+        let mut command = if limits.is_empty() {
+            Command::new("bwrap")
+        } else {
+            let mut command = Command::new("systemd-run");
+            command.args(&limits).arg("bwrap");
+            command
+        };
 
         let env_home = EnvPath::try_from(self.program.home.as_host_raw().to_owned())?;
+        let work_dir_name = self.program.config.work_dir_name(name);
 
         command.env_clear();
-        command.env(
-            "PATH",
-            match self.program.home.as_host_raw().to_str() {
-                Some(home) => format!("{home}/bin:/bin:/usr/bin:/sbin:/usr/sbin"),
-                None => String::from("/bin:/usr/bin:/sbin:/usr/sbin"),
-            },
-        );
+        let mut path = OsString::from(self.program.home.as_host_raw());
+        path.push("/bin:/bin:/usr/bin:/sbin:/usr/sbin");
+        command.env("PATH", path);
         command.env("HOME", env_home.as_env_raw());
         command.env("CUBICLE", name.as_str());
-        command.env("TMPDIR", env_home.join("tmp").as_env_raw());
+        command.env("CUBICLE_WORK_DIR", work_dir_name);
+        if home == HomeMount::ReadOnly {
+            command.env("TMPDIR", "/tmp");
+        } else {
+            command.env("TMPDIR", env_home.join("tmp").as_env_raw());
+        }
         for key in ["DISPLAY", "SHELL", "TERM", "USER"]
             .iter()
             .chain(LOCALE_ENVIRONMENT_VARIABLES)
@@ -190,8 +358,14 @@ impl Bubblewrap {
                 command.env(key, value);
             }
         }
+        let workspace = self.program.config.workspace_for(name);
+        if let Some(workspace) = workspace {
+            for (var, value) in &workspace.env {
+                command.env(var, value);
+            }
+        }
         match run {
-            RunnerCommand::Interactive => {}
+            RunnerCommand::Interactive { .. } => {}
             RunnerCommand::Exec { env_vars, .. } => {
                 for (var, value) in *env_vars {
                     command.env(var, value);
@@ -199,14 +373,57 @@ impl Bubblewrap {
             }
         }
 
+        if !limits.is_empty() {
+            // `systemd-run` needs these, but the environment shouldn't get
+            // them.
+            for var in SYSTEMD_RUN_ENV_VARS {
+                if let Ok(value) = std::env::var(var) {
+                    command.env(var, value);
+                    command.args(["--unsetenv", var]);
+                }
+            }
+        }
+
         command.arg("--die-with-parent");
         command.arg("--unshare-cgroup");
         command.arg("--unshare-ipc");
         command.arg("--unshare-pid");
         command.arg("--unshare-uts");
+        if self.program.network_disabled(name) {
+            command.arg("--unshare-net");
+        }
+        match &userns {
+            Some(userns) => {
+                command
+                    .arg("--userns")
+                    .arg(get_fd_for_child(userns.file()).context(
+                        "failed to set up user namespace file descriptor to be inherited by bwrap",
+                    )?);
+            }
+            None if self.config().uid.is_some() || self.config().gid.is_some() => {
+                command.arg("--unshare-user");
+            }
+            None => {}
+        }
+        if userns.is_some() || self.config().uid.is_some() || self.config().gid.is_some() {
+            command.args(["--uid", &inside_uid.to_string()]);
+            command.args(["--gid", &inside_gid.to_string()]);
+        }
 
         command.args(["--symlink", "/usr/bin", "/bin"]);
         command.args(["--dev", "/dev"]);
+        if self.program.config.gpu_enabled(name) {
+            for path in gpu_devices().context("failed to find GPU devices on host")? {
+                command.arg("--dev-bind").arg(&path).arg(&path);
+            }
+        }
+        for device in host_devices(&self.program.config, name) {
+            command.arg("--dev-bind").arg(&device).arg(&device);
+        }
+        if nested == Some(NestedContainers::Podman) {
+            command.args(["--dev-bind", "/dev/fuse", "/dev/fuse"]);
+            command.args(["--ro-bind", "/sys", "/sys"]);
+        }
 
         for (host_path, env_path) in bind {
             command
@@ -216,20 +433,98 @@ impl Bubblewrap {
         }
 
         command.args(ro_bind_try("/etc"));
-        command
-            .arg("--bind")
-            .arg(host_home.as_host_raw())
-            .arg(env_home.as_env_raw());
-        command
-            .arg("--bind")
-            .arg(host_work.as_host_raw())
-            .arg(env_home.join("w").as_env_raw());
+        match home {
+            HomeMount::ReadWrite | HomeMount::ReadOnly if !layers.is_empty() => {
+                // `bwrap` stacks each `--overlay-src` on top of the previous
+                // ones.
+                for layer in &layers {
+                    command.arg("--overlay-src").arg(layer.as_host_raw());
+                }
+                if home == HomeMount::ReadOnly {
+                    command
+                        .arg("--overlay-src")
+                        .arg(host_home.as_host_raw())
+                        .arg("--ro-overlay");
+                } else {
+                    command.arg("--overlay").arg(host_home.as_host_raw()).arg(
+                        self.overlay_dirs
+                            .join(name.as_filename())
+                            .join("work")
+                            .as_host_raw(),
+                    );
+                }
+                command.arg(env_home.as_env_raw());
+                command
+                    .arg("--bind")
+                    .arg(host_work.as_host_raw())
+                    .arg(env_home.join(work_dir_name).as_env_raw());
+            }
+            HomeMount::ReadWrite | HomeMount::ReadOnly => {
+                command
+                    .arg(if home == HomeMount::ReadOnly {
+                        "--ro-bind"
+                    } else {
+                        "--bind"
+                    })
+                    .arg(host_home.as_host_raw())
+                    .arg(env_home.as_env_raw());
+                command
+                    .arg("--bind")
+                    .arg(host_work.as_host_raw())
+                    .arg(env_home.join(work_dir_name).as_env_raw());
+            }
+            HomeMount::Tmpfs => {
+                command.arg("--tmpfs").arg(env_home.as_env_raw());
+                command
+                    .arg("--dir")
+                    .arg(env_home.join(work_dir_name).as_env_raw());
+            }
+        }
         command.args(["--symlink", "/usr/lib", "/lib"]);
         command.args(["--symlink", "/usr/lib64", "/lib64"]);
         command.args(ro_bind_try("/opt"));
+        // Nix packages are symlinks into the host's store.
+        if self.program.config.nix.is_some() {
+            command.args(ro_bind_try("/nix/store"));
+        }
         command.args(["--proc", "/proc"]);
         command.args(["--symlink", "/usr/sbin", "/sbin"]);
         command.args(["--tmpfs", "/tmp"]);
+        let mut sockets: Vec<HostSocket> = host_wayland_socket().into_iter().collect();
+        if self.program.config.audio_enabled(name) {
+            sockets.extend(host_audio_sockets());
+        }
+        sockets.extend(env_clipboard_share(&self.program, name)?);
+        sockets.extend(env_host_commands_share(&self.program, name)?);
+        if nested == Some(NestedContainers::HostSocket) {
+            sockets.extend(host_container_socket());
+        }
+        for HostSocket {
+            host_path,
+            env_path,
+            env_var: (var, value),
+        } in sockets
+        {
+            command
+                .arg("--bind")
+                .arg(host_path.as_host_raw())
+                .arg(env_path);
+            command.env(var, value);
+        }
+        for mount in workspace.iter().flat_map(|workspace| &workspace.mounts) {
+            command
+                .arg(if mount.read_only {
+                    "--ro-bind"
+                } else {
+                    "--bind"
+                })
+                .arg(&mount.source)
+                .arg(&mount.target);
+        }
+        command
+            .arg("--ro-bind")
+            .arg(shared_mount(&self.program)?.as_host_raw())
+            .arg(SHARED_ENV_PATH);
         command.args(ro_bind_try("/usr"));
         command.args(ro_bind_try("/var/lib/apt/lists"));
         command.args(ro_bind_try("/var/lib/dpkg"));
@@ -240,22 +535,27 @@ impl Bubblewrap {
                     "failed to set up seccomp file descriptor to be inherited by bwrap",
                 )?);
         }
-        command.arg("--chdir").arg(env_home.join("w").as_env_raw());
+        let mut start_dir = env_home.join(work_dir_name);
+        if let Some(dir) = run.dir() {
+            start_dir = start_dir.join(dir);
+        }
+        command.arg("--chdir").arg(start_dir.as_env_raw());
         command.arg("--");
         command.arg(&self.program.shell);
         command.arg("-l");
 
         match run {
-            RunnerCommand::Interactive => {}
+            RunnerCommand::Interactive { .. } => {}
             RunnerCommand::Exec { command: exec, .. } => {
                 command.arg("-c");
                 command.arg(shlex::try_join(exec.iter().map(|a| a.as_str())).expect("TODO"));
             }
         }
 
-        let status = match stdin {
-            None => command.status(),
-            Some(mut reader) => {
+        let status = match io {
+            BwrapIo::Inherit => command.status(),
+            BwrapIo::Stream(on_output) => command.stream(on_output),
+            BwrapIo::Stdin(mut reader) => {
                 command.stdin(Stdio::piped());
                 let mut child = command.scoped_spawn()?;
                 {
@@ -266,12 +566,7 @@ impl Bubblewrap {
                 child.wait()
             }
         }?;
-
-        if status.success() {
-            Ok(())
-        } else {
-            Err(ExitStatusError::new(status, "bwrap").into())
-        }
+        Ok(status)
     }
 }
 
@@ -286,6 +581,19 @@ where
     Ok(file.as_raw_fd().to_string())
 }
 
+/// Returns the host's GPU device paths: `/dev/dri` and any `/dev/nvidia*`.
+fn gpu_devices() -> std::io::Result<Vec<PathBuf>> {
+    let mut paths = Vec::new();
+    for entry in std::fs::read_dir("/dev")? {
+        let name = entry?.file_name();
+        if name == "dri" || name.as_encoded_bytes().starts_with(b"nvidia") {
+            paths.push(Path::new("/dev").join(name));
+        }
+    }
+    paths.sort();
+    Ok(paths)
+}
+
 fn ro_bind_try(path: &str) -> [&str; 3] {
     ["--ro-bind-try", path, path]
 }
@@ -298,13 +606,21 @@ impl Runner for Bubblewrap {
         w: &mut dyn io::Write,
     ) -> Result<()> {
         let Dirs { host_home, .. } = self.dirs(name);
-        let home_dir = cap_std::fs::Dir::open_ambient_dir(
-            host_home.as_host_raw(),
-            cap_std::ambient_authority(),
-        )
-        .todo_context()?;
-        let mut file = home_dir.open(path).todo_context()?;
-        io::copy(&mut file, w).todo_context()?;
+        // With an overlay home directory, the file may be in a lower layer.
+        let mut dirs = vec![host_home];
+        dirs.extend(self.overlay_layers(name)?.into_iter().rev());
+        let last = dirs.len() - 1;
+        for (i, dir) in dirs.into_iter().enumerate() {
+            let dir =
+                cap_std::fs::Dir::open_ambient_dir(dir.as_host_raw(), cap_std::ambient_authority())
+                    .todo_context()?;
+            let mut file = match dir.open(path) {
+                Err(e) if e.kind() == io::ErrorKind::NotFound && i < last => continue,
+                result => result.todo_context()?,
+            };
+            io::copy(&mut file, w).todo_context()?;
+            break;
+        }
         Ok(())
     }
 
@@ -325,6 +641,24 @@ impl Runner for Bubblewrap {
         Ok(())
     }
 
+    fn copy_out(&self, name: &EnvironmentName, path: &Path, w: &mut dyn io::Write) -> Result<()> {
+        let Dirs {
+            host_home,
+            host_work,
+        } = self.dirs(name);
+        let work_dir_name = self.program.config.work_dir_name(name);
+        copy_out_from_host_dirs(&host_home, &host_work, work_dir_name, path, w)
+    }
+
+    fn copy_in(&self, name: &EnvironmentName, dir: &Path, r: &mut dyn io::Read) -> Result<()> {
+        let Dirs {
+            host_home,
+            host_work,
+        } = self.dirs(name);
+        let work_dir_name = self.program.config.work_dir_name(name);
+        copy_in_to_host_dirs(&host_home, &host_work, work_dir_name, dir, r)
+    }
+
     fn create(&self, name: &EnvironmentName, init: &Init) -> Result<()> {
         let Dirs {
             host_home,
@@ -353,33 +687,81 @@ impl Runner for Bubblewrap {
         })
     }
 
+    fn directory_sizes(&self, name: &EnvironmentName, depth: usize) -> Result<EnvDirectorySizes> {
+        let Dirs {
+            host_home,
+            host_work,
+        } = self.dirs(name);
+        let mut sizes = EnvDirectorySizes::default();
+        if try_exists(&host_home).todo_context()? {
+            sizes.home_dir = run_du(&mut Command::query("du"), depth, host_home.as_host_raw())?;
+        }
+        if try_exists(&host_work).todo_context()? {
+            sizes.work_dir = run_du(&mut Command::query("du"), depth, host_work.as_host_raw())?;
+        }
+        Ok(sizes)
+    }
+
+    fn connect_info(&self, name: &EnvironmentName) -> Result<ConnectInfo> {
+        let Dirs {
+            host_home,
+            host_work,
+        } = self.dirs(name);
+        let env_home = self.program.home.as_host_raw();
+        // Each session runs in its own sandbox, so there's nothing to attach
+        // to from outside.
+        Ok(ConnectInfo {
+            user: Some(host_user(&self.program.config)?),
+            home_dir: Some(env_home.to_owned()),
+            work_dir: Some(env_home.join(self.program.config.work_dir_name(name))),
+            host_home_dir: Some(host_home.as_host_raw().to_owned()),
+            host_work_dir: Some(host_work.as_host_raw().to_owned()),
+            ..ConnectInfo::default()
+        })
+    }
+
+    fn storage_usage(&self) -> Result<Vec<(&'static str, u64)>> {
+        Ok(Vec::new())
+    }
+
     fn stop(&self, _name: &EnvironmentName) -> Result<()> {
         // don't know how to enumerate such processes, so don't bother
         Ok(())
     }
 
+    fn is_running(&self, name: &EnvironmentName) -> Result<bool> {
+        let Dirs { host_home, .. } = self.dirs(name);
+        let entries = std::fs::read_dir("/proc").context("failed to read /proc")?;
+        for entry in entries {
+            let entry = entry.context("failed to read /proc")?;
+            // Processes may exit while this runs, and other users' processes
+            // may not be readable, so errors here are ignored.
+            if let Ok(cmdline) = std::fs::read(entry.path().join("cmdline")) {
+                if bwrap_binds_home(&cmdline, host_home.as_host_raw()) {
+                    return Ok(true);
+                }
+            }
+        }
+        Ok(false)
+    }
+
+    fn running_processes(&self, _name: &EnvironmentName) -> Result<Option<usize>> {
+        // don't know how to enumerate such processes
+        Ok(None)
+    }
+
+    fn checkpoint(&self, _name: &EnvironmentName) -> Result<()> {
+        Err(anyhow!(
+            "checkpointing is not supported by the Bubblewrap runner"
+        ))
+    }
+
     fn list(&self) -> Result<Vec<EnvironmentName>> {
         let mut envs = BTreeSet::new();
 
-        for name in try_iterdir_dirs(&self.home_dirs)? {
-            let env = EnvironmentName::from_filename(&name).with_context(|| {
-                format!(
-                    "error parsing environment name from path {}",
-                    self.home_dirs.join(&name)
-                )
-            })?;
-            envs.insert(env);
-        }
+        envs.extend(env_dir_names(&self.home_dirs)?);
 
-        for name in try_iterdir_dirs(&self.work_dirs)? {
-            let env = EnvironmentName::from_filename(&name).with_context(|| {
-                format!(
-                    "error parsing environment name from path {}",
-                    self.work_dirs.join(&name)
-                )
-            })?;
-            envs.insert(env);
-        }
+        envs.extend(env_dir_names(&self.work_dirs)?);
 
         Ok(Vec::from_iter(envs))
     }
@@ -417,32 +799,146 @@ impl Runner for Bubblewrap {
             host_home,
             host_work,
         } = self.dirs(name);
+        if self.config().overlay_home {
+            // This removes the overlay's work directory. The upper layer is
+            // `host_home`, which is removed below like any home directory.
+            // `init` then sets up the lower layers again, with the current
+            // package builds.
+            rmtree(&self.overlay_dirs.join(name.as_filename()))?;
+        } else if self.config().incremental_reset && try_exists(&host_home).todo_context()? {
+            match self.reset_incrementally(name, init) {
+                Ok(()) => return Ok(()),
+                Err(e) => warn(e.context(format!(
+                    "incremental reset of environment {name} failed; resetting fully"
+                ))),
+            }
+        }
         rmtree(&host_home)?;
         std::fs::create_dir_all(host_home.as_host_raw()).todo_context()?;
         std::fs::create_dir_all(host_work.as_host_raw()).todo_context()?;
         self.init(name, init)
     }
 
+    fn refresh(&self, name: &EnvironmentName, init: &Init) -> Result<()> {
+        self.init(name, init)
+    }
+
     fn purge(&self, name: &EnvironmentName) -> Result<()> {
         let Dirs {
             host_home,
             host_work,
         } = self.dirs(name);
         rmtree(&host_home)?;
+        self.set_overlay_layers(name, &[])?;
         rmtree(&host_work)
     }
 
-    fn run(&self, name: &EnvironmentName, run: &RunnerCommand) -> Result<()> {
+    fn purge_workspace(&self, _name: &WorkspaceName) -> Result<()> {
+        Ok(())
+    }
+
+    fn run(&self, name: &EnvironmentName, run: &RunnerCommand) -> Result<ExitStatus> {
         self.bwrap(
             name,
             BwrapArgs {
                 bind: &[],
                 run,
-                stdin: None,
+                io: BwrapIo::Inherit,
+                home: HomeMount::ReadWrite,
             },
         )
     }
 
+    fn run_streaming(
+        &self,
+        name: &EnvironmentName,
+        run: &RunnerCommand,
+        on_output: &mut dyn FnMut(OutputStream, &[u8]),
+    ) -> Result<ExitStatus> {
+        self.bwrap(
+            name,
+            BwrapArgs {
+                bind: &[],
+                run,
+                io: BwrapIo::Stream(on_output),
+                home: HomeMount::ReadWrite,
+            },
+        )
+    }
+
+    fn run_ephemeral(
+        &self,
+        name: &EnvironmentName,
+        Init {
+            distro_packages,
+            env_distro_packages,
+            env_vars,
+            seeds,
+        }: &Init,
+    ) -> Result<()> {
+        Distro::host().check_host_packages(
+            &distro_packages
+                .iter()
+                .chain(env_distro_packages)
+                .map(|s| s.as_str())
+                .collect::<Vec<&str>>(),
+        );
+
+        // The seeds and init script are mounted into the sandbox, since the
+        // home directory only exists while it runs.
+        let host_script_temp = self.write_init_script()?;
+        let host_script = HostPath::try_from(host_script_temp.to_path_buf())?;
+        let init_script = EnvPath::try_from(INIT_SCRIPT_PATH.to_owned()).unwrap();
+        let seed_paths = (0..seeds.len())
+            .map(|i| EnvPath::try_from(format!("/cubicle-seeds/{i}.tar")))
+            .collect::<Result<Vec<_>>>()?;
+        let mut bind = vec![(&host_script, &init_script)];
+        bind.extend(seeds.iter().zip(&seed_paths));
+
+        let script = format!(
+            "set -e; \
+            for seed in {}; do tar --ignore-zero --directory .. --extract --file \"$seed\"; done; \
+            {INIT_SCRIPT_PATH}; \
+            cd; cd \"$CUBICLE_WORK_DIR\"; exec \"${{SHELL:-/bin/sh}}\" -l",
+            seed_paths
+                .iter()
+                .map(|path| path.as_env_raw().to_string_lossy())
+                .collect::<Vec<_>>()
+                .join(" "),
+        );
+        self.bwrap(
+            name,
+            BwrapArgs {
+                bind: &bind,
+                run: &RunnerCommand::Exec {
+                    command: &[String::from("sh"), String::from("-c"), script],
+                    env_vars,
+                    dir: None,
+                },
+                io: BwrapIo::Inherit,
+                home: HomeMount::Tmpfs,
+            },
+        )
+        .and_then(|status| ExitStatusError::check(status, "bwrap"))
+    }
+
+    fn run_read_only_home(&self, name: &EnvironmentName, run: &RunnerCommand) -> Result<()> {
+        self.bwrap(
+            name,
+            BwrapArgs {
+                bind: &[],
+                run,
+                io: BwrapIo::Inherit,
+                home: HomeMount::ReadOnly,
+            },
+        )
+        .and_then(|status| ExitStatusError::check(status, "bwrap"))
+    }
+
+    fn query_image(&self, _name: Option<&EnvironmentName>, script: &str) -> Result<String> {
+        query_host(script)
+    }
+
     fn supports_any(&self, targets: &[Target]) -> Result<bool> {
         Ok(targets.iter().any(|Target { arch, os }| {
             (match arch {
@@ -454,4 +950,99 @@ impl Runner for Bubblewrap {
             })
         }))
     }
+
+    fn clean_orphans(&self) -> Result<Vec<String>> {
+        // The home and work directories are the environment, so there's
+        // nothing that can be orphaned.
+        Ok(Vec::new())
+    }
+
+    fn orphans(&self) -> Result<Vec<Orphan>> {
+        Ok(Vec::new())
+    }
+
+    fn remove_orphan(&self, orphan: &Orphan) -> Result<()> {
+        Err(anyhow!("the Bubblewrap runner has no {orphan}"))
+    }
+}
+
+/// Returns the name that an environment's overlay home directory has as a
+/// user of the seed store.
+fn overlay_user(name: &EnvironmentName) -> String {
+    format!("bubblewrap/{}", name.as_str())
+}
+
+/// Returns true if the given `/proc/PID/cmdline` contents are for a `bwrap`
+/// process that mounts `host_home`, which means it's running the environment.
+fn bwrap_binds_home(cmdline: &[u8], host_home: &Path) -> bool {
+    let args = cmdline
+        .split(|b| *b == 0)
+        .map(|arg| Path::new(OsStr::from_bytes(arg)))
+        .collect::<Vec<_>>();
+    let Some(program) = args.first() else {
+        return false;
+    };
+    program.file_name() == Some(OsStr::new("bwrap"))
+        && args.windows(2).any(|pair| {
+            matches!(
+                pair[0].to_str(),
+                Some("--bind" | "--ro-bind" | "--overlay" | "--overlay-src")
+            ) && pair[1] == host_home
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    #[test]
+    fn bwrap_binds_home() {
+        let home = Path::new("/home/ci/.local/share/cubicle/home/web");
+        let cmdline = |args: &[&str]| args.join("\0").into_bytes();
+        assert!(super::bwrap_binds_home(
+            &cmdline(&[
+                "/usr/bin/bwrap",
+                "--bind",
+                "/home/ci/.local/share/cubicle/home/web",
+                "/home/ci"
+            ]),
+            home
+        ));
+        assert!(super::bwrap_binds_home(
+            &cmdline(&[
+                "bwrap",
+                "--ro-bind",
+                "/home/ci/.local/share/cubicle/home/web",
+                "/home/ci",
+                ""
+            ]),
+            home
+        ));
+        assert!(super::bwrap_binds_home(
+            &cmdline(&[
+                "bwrap",
+                "--overlay-src",
+                "/home/ci/.cache/cubicle/seed-store/packages%2Frust.tar/1-2.000000003",
+                "--overlay",
+                "/home/ci/.local/share/cubicle/home/web",
+                "/home/ci/.local/share/cubicle/home-overlay/web/work",
+                "/home/ci"
+            ]),
+            home
+        ));
+        assert!(!super::bwrap_binds_home(
+            &cmdline(&[
+                "bwrap",
+                "--bind",
+                "/home/ci/.local/share/cubicle/home/webapp",
+                "/home/ci"
+            ]),
+            home
+        ));
+        assert!(!super::bwrap_binds_home(
+            &cmdline(&["ls", "--bind", "/home/ci/.local/share/cubicle/home/web"]),
+            home
+        ));
+        assert!(!super::bwrap_binds_home(b"", home));
+    }
 }
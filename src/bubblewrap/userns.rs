@@ -0,0 +1,190 @@
+//! Maps user and group IDs into Bubblewrap sandboxes.
+//!
+//! An unprivileged user namespace can only map the host user's own IDs, which
+//! is what `bwrap --unshare-user` does. With
+//! [`subids`](crate::config::Bubblewrap::subids), Cubicle instead creates the
+//! namespace itself, maps in the ranges that `/etc/subuid` and `/etc/subgid`
+//! allot to the host user using the setuid `newuidmap` and `newgidmap`
+//! helpers, and has `bwrap` join it with `--userns`. That gives programs in
+//! the sandbox, like rootless Podman, a full range of IDs to work with.
+
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::process::Stdio;
+
+use crate::command_ext::{Command, ScopedChild};
+use crate::somehow::{somehow as anyhow, Context, Result};
+
+/// A range of subordinate IDs from `/etc/subuid` or `/etc/subgid`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+struct SubIdRange {
+    start: u64,
+    count: u64,
+}
+
+/// Finds the first range for the given user, by name or numeric ID, in the
+/// contents of `/etc/subuid` or `/etc/subgid`.
+fn parse_subids(contents: &str, user: &str, id: u64) -> Option<SubIdRange> {
+    contents.lines().find_map(|line| {
+        let fields = line.split(':').collect::<Vec<_>>();
+        match fields.as_slice() {
+            [owner, start, count] if *owner == user || owner.parse() == Ok(id) => {
+                Some(SubIdRange {
+                    start: start.parse().ok()?,
+                    count: count.parse().ok()?,
+                })
+            }
+            _ => None,
+        }
+    })
+}
+
+fn read_subids(path: &str, user: &str, id: u64) -> Result<SubIdRange> {
+    let contents =
+        std::fs::read_to_string(path).with_context(|| format!("failed to read {path}"))?;
+    match parse_subids(&contents, user, id) {
+        Some(range) if range.count > 0 => Ok(range),
+        _ => Err(anyhow!(
+            "no subordinate IDs for user {user:?} in {path} \
+            (hint: add them with `usermod --add-subuids` and `--add-subgids`, \
+            or unset `subids` in `[runners.bubblewrap]`)"
+        )),
+    }
+}
+
+/// One line of a `uid_map` or `gid_map`: the first ID inside the namespace,
+/// the first ID outside, and the number of IDs.
+type IdMapping = [u64; 3];
+
+/// Maps the host user's ID to `inside` and fills in the IDs around it from the
+/// subordinate range, starting at 0.
+///
+/// This is the same layout as Podman's `--userns=keep-id`.
+fn id_map(inside: u64, outside: u64, sub: SubIdRange) -> Vec<IdMapping> {
+    let mut map = vec![[inside, outside, 1]];
+    let below = inside.min(sub.count);
+    if below > 0 {
+        map.push([0, sub.start, below]);
+    }
+    if sub.count > below {
+        map.push([inside + 1, sub.start + below, sub.count - below]);
+    }
+    map
+}
+
+/// The host and sandbox IDs for a user namespace with subordinate IDs.
+pub struct Ids<'a> {
+    pub user: &'a str,
+    pub uid: u64,
+    pub gid: u64,
+    pub inside_uid: u64,
+    pub inside_gid: u64,
+}
+
+/// A user namespace with subordinate IDs mapped in.
+///
+/// A placeholder process keeps the namespace around until `bwrap` joins it
+/// through the file. The process is killed when this is dropped.
+pub struct UserNamespace {
+    file: File,
+    _holder: ScopedChild,
+}
+
+impl UserNamespace {
+    pub fn new(ids: &Ids) -> Result<Self> {
+        let uid_map = id_map(
+            ids.inside_uid,
+            ids.uid,
+            read_subids("/etc/subuid", ids.user, ids.uid)?,
+        );
+        let gid_map = id_map(
+            ids.inside_gid,
+            ids.gid,
+            read_subids("/etc/subgid", ids.user, ids.gid)?,
+        );
+
+        // The shell only starts once `unshare` has created the namespace, so
+        // the line it prints means the namespace is ready to map.
+        let mut holder = Command::new("unshare")
+            .args(["--user", "--", "sh", "-c", "echo && exec sleep infinity"])
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .scoped_spawn()?;
+        let mut line = String::new();
+        BufReader::new(holder.stdout().take().unwrap())
+            .read_line(&mut line)
+            .context("failed to wait for `unshare --user`")?;
+        if line.is_empty() {
+            return Err(anyhow!(
+                "`unshare --user` exited before creating a namespace"
+            ));
+        }
+        let pid = holder.id();
+
+        for (program, map) in [("newuidmap", &uid_map), ("newgidmap", &gid_map)] {
+            let status = Command::new(program)
+                .arg(pid.to_string())
+                .args(map.iter().flatten().map(|id| id.to_string()))
+                .status()?;
+            if !status.success() {
+                return Err(anyhow!("`{program}` exited with {status}"));
+            }
+        }
+
+        let path = format!("/proc/{pid}/ns/user");
+        let file = File::open(&path).with_context(|| format!("failed to open {path}"))?;
+        Ok(Self {
+            file,
+            _holder: holder,
+        })
+    }
+
+    /// Returns the namespace file, to pass to `bwrap --userns`.
+    pub fn file(&self) -> &File {
+        &self.file
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::SubIdRange;
+
+    #[test]
+    fn parse_subids() {
+        let contents = "\
+            ci:100000:65536\n\
+            1002:165536:65536\n\
+            bad:x:1\n";
+        let range = |start, count| Some(SubIdRange { start, count });
+        assert_eq!(
+            range(100_000, 65_536),
+            super::parse_subids(contents, "ci", 1001)
+        );
+        assert_eq!(
+            range(165_536, 65_536),
+            super::parse_subids(contents, "other", 1002)
+        );
+        assert_eq!(None, super::parse_subids(contents, "bad", 1003));
+        assert_eq!(None, super::parse_subids(contents, "nobody", 1004));
+    }
+
+    #[test]
+    fn id_map() {
+        let sub = SubIdRange {
+            start: 100_000,
+            count: 65_536,
+        };
+        assert_eq!(
+            vec![[1000, 1001, 1], [0, 100_000, 1000], [1001, 101_000, 64_536]],
+            super::id_map(1000, 1001, sub)
+        );
+        assert_eq!(
+            vec![[0, 1001, 1], [1, 100_000, 65_536]],
+            super::id_map(0, 1001, sub)
+        );
+        assert_eq!(
+            vec![[70_000, 1001, 1], [0, 100_000, 65_536]],
+            super::id_map(70_000, 1001, sub)
+        );
+    }
+}
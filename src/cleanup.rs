@@ -0,0 +1,157 @@
+//! Cleans up after earlier runs of Cubicle that crashed or were killed.
+//!
+//! Such runs can leave behind lock files, partial package builds, temporary
+//! files, and Docker containers. Over time, these waste disk space and cause
+//! confusing conflicts, so Cubicle looks for them on startup.
+
+use std::ffi::OsString;
+use std::time::Duration;
+use tracing::info;
+
+use super::encoding::FilenameEncoder;
+use super::fs_util::{try_iterdir, try_iterdir_dirs, TEMP_FILE_PREFIX};
+use super::lock::LockFile;
+use super::os_util::process_exists;
+use super::runner::Runner;
+use super::{Cubicle, HostPath};
+use crate::somehow::{warn, Context, Result};
+
+/// How often to look for things to clean up.
+const INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+impl Cubicle {
+    /// Removes things left behind by earlier runs that crashed or were
+    /// killed, printing a message for each one.
+    ///
+    /// This runs at most once per [`INTERVAL`], to keep startup fast. Errors
+    /// are reported as warnings only.
+    pub(super) fn clean_up_after_crashes(&self) {
+        let marker = self.shared.cache_dir.join("last-cleanup");
+        if let Ok(modified) =
+            std::fs::metadata(marker.as_host_raw()).and_then(|metadata| metadata.modified())
+        {
+            if matches!(self.shared.clock.now().duration_since(modified), Ok(d) if d < INTERVAL) {
+                return;
+            }
+        }
+        if let Err(e) = std::fs::create_dir_all(self.shared.cache_dir.as_host_raw())
+            .and_then(|()| std::fs::write(marker.as_host_raw(), ""))
+            .with_context(|| format!("failed to write {marker}"))
+        {
+            warn(e);
+            return;
+        }
+
+        let mut cleaned = Vec::new();
+        // Stale locks must go first, since cleaning the package cache needs
+        // its lock.
+        let results = [
+            self.clean_stale_locks(&mut cleaned),
+            self.clean_testing_tars(&mut cleaned),
+            clean_temp_files(&mut cleaned),
+            self.runner
+                .clean_orphans()
+                .map(|orphans| cleaned.extend(orphans)),
+        ];
+        for result in results {
+            if let Err(e) = result {
+                warn(e.context("failed to clean up after earlier runs"));
+            }
+        }
+        for what in cleaned {
+            info!("Cleaned up after an earlier run that crashed: {what}");
+        }
+    }
+
+    fn clean_stale_locks(&self, cleaned: &mut Vec<String>) -> Result<()> {
+        let mut locks = vec![
+            self.shared.package_cache.join("update.lock"),
+            self.dotfiles_dir().join("lock"),
+        ];
+        locks.extend(self.package_locks()?);
+        let env_locks = self.environment_locks_dir();
+        for filename in try_iterdir(&env_locks)? {
+            if FilenameEncoder::decode(&filename).is_ok_and(|name| name.ends_with(".lock")) {
+                locks.push(env_locks.join(filename));
+            }
+        }
+        let shared = self.shared.cache_dir.join("shared");
+        for arch in try_iterdir_dirs(&shared)? {
+            let arch = shared.join(arch);
+            for package in try_iterdir_dirs(&arch)? {
+                locks.push(arch.join(package).join("lock"));
+            }
+        }
+        for lock in locks {
+            if LockFile::remove_if_stale(&lock)? {
+                cleaned.push(format!("stale lock file {lock}"));
+            }
+        }
+        Ok(())
+    }
+
+    /// Removes the outputs of package builds that were interrupted before
+    /// they were tested.
+    fn clean_testing_tars(&self, cleaned: &mut Vec<String>) -> Result<()> {
+        let package_cache = &self.shared.package_cache;
+        // Holding this keeps new package builds from starting.
+        let Some(_lock) = LockFile::try_acquire(&package_cache.join("update.lock"))? else {
+            return Ok(());
+        };
+        for arch in try_iterdir_dirs(package_cache)? {
+            let dir = package_cache.join(arch);
+            for filename in try_iterdir(&dir)? {
+                let Some(package) = testing_tar_package(&filename) else {
+                    continue;
+                };
+                // The package may be building now. If so, skip it for now.
+                let package_lock =
+                    dir.join(FilenameEncoder::new().push(&package).push(".lock").encode());
+                if let Some(_package_lock) = LockFile::try_acquire(&package_lock)? {
+                    let path = dir.join(filename);
+                    std::fs::remove_file(path.as_host_raw())
+                        .with_context(|| format!("failed to remove {path}"))?;
+                    cleaned.push(format!("partial package build {path}"));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Returns the name of the package (unquoted) that `filename` is a testing
+/// tar for, or `None` if it's some other file.
+fn testing_tar_package(filename: &OsString) -> Option<String> {
+    let name = FilenameEncoder::decode(filename).ok()?;
+    name.strip_suffix(".testing.tar").map(String::from)
+}
+
+/// Removes temporary files created by [`host_tempfile`] in processes that
+/// no longer exist.
+///
+/// [`host_tempfile`]: super::fs_util::host_tempfile
+fn clean_temp_files(cleaned: &mut Vec<String>) -> Result<()> {
+    let dir = HostPath::try_from(std::env::temp_dir())?;
+    for filename in try_iterdir(&dir)? {
+        let Some(pid) = filename
+            .to_str()
+            .and_then(|name| name.strip_prefix(TEMP_FILE_PREFIX))
+            .and_then(|rest| rest.split_once('-'))
+            .and_then(|(pid, _)| pid.parse().ok())
+        else {
+            continue;
+        };
+        if process_exists(pid) {
+            continue;
+        }
+        let path = dir.join(&filename);
+        match std::fs::symlink_metadata(path.as_host_raw()) {
+            Ok(metadata) if metadata.is_file() => {}
+            _ => continue,
+        }
+        std::fs::remove_file(path.as_host_raw())
+            .with_context(|| format!("failed to remove {path}"))?;
+        cleaned.push(format!("temporary file {path}"));
+    }
+    Ok(())
+}
@@ -6,20 +6,34 @@
 
 use clap::{Parser, Subcommand};
 use clap_complete::{generate, shells::Shell};
-use std::collections::BTreeSet;
+use std::collections::{BTreeMap, BTreeSet};
+use std::ffi::OsString;
 use std::fmt::{self, Debug, Display};
 use std::io;
+use std::os::unix::process::ExitStatusExt;
 use std::path::{Path, PathBuf};
+use std::process::ExitStatus;
 use std::str::FromStr;
 use wildmatch::WildMatch;
 
 use cubicle::hidden::host_home_dir;
 use cubicle::somehow::{somehow as anyhow, warn, Context, Error, Result};
 use cubicle::{
-    Cubicle, EnvironmentName, FullPackageName, ListFormat, ListPackagesFormat, Quiet,
-    ShouldPackageUpdate, UpdatePackagesConditions,
+    AssumeYes, ConnectInfoFormat, CopyPath, Cubicle, DryRun, EnvironmentName, Ephemeral, Force,
+    FullPackageName, Json, KeepHome, ListFilter, ListFormat, ListPackagesFormat, ListSort, Locked,
+    NewPackage, PackageGraphFormat, Platform, ProgressFormat, Quiet, ReadOnlyHome, Refresh,
+    ShouldPackageUpdate, UpdatePackagesConditions, Verbosity, WorkspaceListFormat, WorkspaceName,
 };
 
+/// Returns the exit code for `cub` to pass on the given status of a child
+/// process, following the shell convention for signals.
+fn exit_code(status: ExitStatus) -> i32 {
+    match status.code() {
+        Some(code) => code,
+        None => 128 + status.signal().unwrap_or(0),
+    }
+}
+
 /// Manage sandboxed development environments.
 #[derive(Debug, Parser)]
 pub struct Args {
@@ -32,12 +46,51 @@ pub struct Args {
     )]
     config: PathWithVarExpansion,
 
+    /// Set how to report progress.
+    ///
+    /// With `json`, long operations like `cub new` also write
+    /// newline-delimited JSON events to stderr, such as when package builds
+    /// start and finish, for wrappers and editor integrations to show their
+    /// own progress.
+    #[arg(long, global = true, value_enum, default_value_t)]
+    progress: ProgressFormat,
+
+    /// Log more details, such as every command that Cubicle runs.
+    ///
+    /// This may be given twice to also log the commands' exit statuses.
+    #[arg(short, long, global = true, action = clap::ArgAction::Count)]
+    verbose: u8,
+
+    /// Log less: only warnings and errors, or only errors if given twice.
+    #[arg(short, long, global = true, action = clap::ArgAction::Count, conflicts_with = "verbose")]
+    quiet: u8,
+
+    /// Proceed without asking for confirmation.
+    ///
+    /// Commands like `purge` normally ask before deleting anything, and fail
+    /// if they can't ask because stdin isn't a terminal.
+    #[arg(short, long, global = true)]
+    yes: bool,
+
     #[command(subcommand)]
     command: Commands,
 }
 
 #[derive(Debug, Subcommand)]
 enum Commands {
+    /// Share the host's clipboard with environments.
+    ///
+    /// This runs in the foreground until interrupted, copying data between
+    /// the host's clipboard and environments selected by the `clipboard`
+    /// setting in `cubicle.toml`. Inside those environments, `cub-copy`
+    /// (which reads from stdin) and `cub-paste` (which writes to stdout)
+    /// access the host's clipboard, even without access to the host's
+    /// display server. These commands come from the `clipboard` package.
+    ///
+    /// On the host, this uses `wl-copy` and `wl-paste` under Wayland or
+    /// `xclip` under X11.
+    Clipboard,
+
     /// Generate tab-completions for your shell.
     ///
     /// Installation for Bash:
@@ -57,9 +110,102 @@ enum Commands {
     #[command(arg_required_else_help(true))]
     Completions { shell: Shell },
 
+    /// Show how to reach an environment without `cub enter`.
+    ///
+    /// This is meant for editors and IDE plugins, which can attach to the
+    /// environment's container or open its files directly. The output
+    /// includes the user, the home and work directories inside the
+    /// environment and on the host (where accessible), and a command that
+    /// starts a shell in the environment (where the runner allows it).
+    #[command(arg_required_else_help(true))]
+    ConnectInfo {
+        /// Set output format.
+        #[arg(long, value_enum, default_value_t)]
+        format: ConnectInfoFormat,
+
+        /// Environment name.
+        ///
+        /// Wildcards are allowed: `?` matches a single character and `*`
+        /// matches zero or more characters.
+        name: EnvironmentPattern,
+    },
+
+    /// Copy files or directories into or out of an environment.
+    ///
+    /// Paths in the environment are written as `ENV:PATH`, where `PATH` is
+    /// relative to the environment's home directory. Exactly one of SRC and
+    /// DST must be in the environment. Directories are copied recursively.
+    ///
+    /// For example, `cub cp eg:w/out.log .` copies `out.log` from the work
+    /// directory of the environment `eg` into the current directory.
+    #[command(arg_required_else_help(true))]
+    Cp {
+        /// File or directory to copy, as `ENV:PATH` or a host path.
+        src: CopyArg,
+
+        /// Where to copy to, as `ENV:PATH` or a host path.
+        ///
+        /// If this is an existing directory on the host, or an environment
+        /// path that's empty or ends in `/`, SRC is copied into it.
+        dst: CopyArg,
+    },
+
+    /// Check Cubicle's files and settings for problems.
+    ///
+    /// This reports problems that don't stop Cubicle from working yet, like
+    /// files left in the locations that older versions used. It doesn't
+    /// create or change any environments. It exits with an error if it
+    /// found any problems.
+    Doctor,
+
+    /// Show the largest directories in an environment.
+    ///
+    /// This lists the directories within the environment's home and work
+    /// directories that use the most disk space, largest first. A
+    /// directory's size includes everything under it.
+    #[command(arg_required_else_help(true))]
+    Du {
+        /// How many levels of subdirectories to look into.
+        #[arg(long, default_value_t = 3)]
+        depth: usize,
+
+        /// How many directories to show for each of the home and work
+        /// directories.
+        #[arg(long, default_value_t = 10)]
+        top: usize,
+
+        /// Environment name.
+        ///
+        /// Wildcards are allowed: `?` matches a single character and `*`
+        /// matches zero or more characters.
+        name: EnvironmentPattern,
+    },
+
     /// Run a shell in an existing environment.
     #[command(arg_required_else_help(true))]
     Enter {
+        /// Mount the home directory read-only for this session.
+        ///
+        /// The work directory remains writable. This is useful for inspecting
+        /// or demoing an environment without changing it. It's not supported
+        /// with the User runner.
+        #[arg(long)]
+        read_only_home: bool,
+
+        /// Record the session's terminal output.
+        ///
+        /// The recording is saved as an asciinema cast file with the
+        /// environment's metadata and can be played back with `cub replay`.
+        /// It's deleted when the environment is purged. This requires
+        /// `script` from util-linux on the host.
+        #[arg(long)]
+        record: bool,
+
+        /// Start the shell in this directory, relative to the environment's
+        /// work directory, instead of the work directory itself.
+        #[arg(long, value_name = "DIR")]
+        at: Option<PathBuf>,
+
         /// Environment name.
         ///
         /// Wildcards are allowed: `?` matches a single character and `*`
@@ -67,7 +213,15 @@ enum Commands {
         name: EnvironmentPattern,
     },
 
+    /// Manage an existing environment.
+    #[command(subcommand)]
+    Env(EnvCommands),
+
     /// Run a command in an existing environment.
+    ///
+    /// `cub` exits with the command's exit status. If the command is killed
+    /// by a signal, the exit status is 128 plus the signal number, as in
+    /// shells.
     #[command(arg_required_else_help(true))]
     Exec {
         /// Environment name.
@@ -80,11 +234,45 @@ enum Commands {
         command: Vec<String>,
     },
 
+    /// Generate configuration files for other programs.
+    #[command(subcommand)]
+    Generate(GenerateCommands),
+
+    /// Let environments run allowlisted commands on the host.
+    ///
+    /// This runs in the foreground until interrupted, listening for requests
+    /// from environments selected by the `host_commands` setting in
+    /// `cubicle.toml`. Inside those environments, `cub-host COMMAND [ARGS]`
+    /// runs `COMMAND` on the host if it's allowed for that environment. The
+    /// `host-commands` package provides `cub-host` and an `xdg-open` that
+    /// uses it, so that links opened inside an environment open on the host.
+    #[command(name = "host-commands")]
+    HostBroker,
+
+    /// Inspect the OS image that environments are based on.
+    #[command(subcommand)]
+    Image(ImageCommands),
+
     /// Show existing environments.
     List {
+        /// Only show environments matching this filter.
+        ///
+        /// `label=KEY` matches environments with the label KEY,
+        /// `label=KEY:VALUE` matches environments where KEY has that value,
+        /// and `workspace=NAME` matches environments in the workspace NAME.
+        /// When given more than once, environments must match every filter.
+        #[arg(long)]
+        filter: Vec<ListFilter>,
         /// Set output format.
         #[arg(long, value_enum, default_value_t)]
         format: ListFormat,
+        /// Set the order of environments.
+        #[arg(long, value_enum, default_value_t)]
+        sort: ListSort,
+        /// Only show disk usage that was cached by an earlier listing,
+        /// instead of summarizing environments that may have changed.
+        #[arg(long)]
+        no_refresh: bool,
     },
 
     /// View and manage packages.
@@ -97,6 +285,17 @@ enum Commands {
         /// Run a shell in new environment.
         #[arg(long)]
         enter: bool,
+        /// Choose packages from a list on the terminal.
+        ///
+        /// The list starts with the "default" package's dependencies
+        /// selected.
+        #[arg(long, conflicts_with = "packages")]
+        interactive: bool,
+        /// Set a label on the new environment, as KEY=VALUE.
+        ///
+        /// This may be given more than once.
+        #[arg(long = "label", value_name = "KEY=VALUE")]
+        labels: Vec<LabelArg>,
         /// Comma-separated names of packages to inject into home directory.
         ///
         /// If omitted, uses the "default" package.
@@ -109,6 +308,19 @@ enum Commands {
         name: EnvironmentName,
     },
 
+    /// Remove leftover builder environments and orphaned runner resources.
+    ///
+    /// This finds package builder environments for packages that no longer
+    /// exist, package test environments left by failed tests, Docker
+    /// containers and checkpoint volumes without an environment, and User
+    /// runner work directory backups from failed resets over a week ago. It
+    /// lists them and asks before removing them.
+    Prune {
+        /// Only list what would be removed.
+        #[arg(long)]
+        dry_run: bool,
+    },
+
     /// Delete environment(s) and their work directories.
     #[command(arg_required_else_help(true))]
     Purge {
@@ -120,9 +332,51 @@ enum Commands {
         names: Vec<EnvironmentPattern>,
     },
 
+    /// Play back a session recorded with `cub enter --record`.
+    #[command(arg_required_else_help(true))]
+    Replay {
+        /// List the environment's recordings instead of playing one.
+        #[arg(long)]
+        list: bool,
+
+        /// Environment name.
+        ///
+        /// Wildcards are allowed: `?` matches a single character and `*`
+        /// matches zero or more characters.
+        name: EnvironmentPattern,
+
+        /// Recording to play, as shown by `--list`.
+        ///
+        /// If omitted, plays the most recent recording.
+        recording: Option<u64>,
+    },
+
     /// Recreate an environment (keeping only its work directory).
     #[command(arg_required_else_help(true))]
     Reset {
+        /// Reset without asking for confirmation.
+        ///
+        /// Without this flag, `reset` looks for uncommitted Git changes and
+        /// recently modified files in the work directory first, and it checks
+        /// whether `--packages` changes the environment's packages. If so, it
+        /// asks for confirmation (or fails, if not run from a terminal).
+        #[arg(long)]
+        force: bool,
+        /// Keep the existing home directory, copying package files over it.
+        ///
+        /// This updates the environment's packages while keeping other files
+        /// in the home directory, like shell history and configuration
+        /// outside of packages. Files from older package versions that are no
+        /// longer provided are left behind.
+        #[arg(long)]
+        keep_home: bool,
+        /// Use the package builds recorded by `cub package lock`.
+        ///
+        /// This reads `packages.lock` from the environment's work directory
+        /// and seeds the environment with exactly those builds, without
+        /// building any packages.
+        #[arg(long, conflicts_with = "packages")]
+        locked: bool,
         /// Comma-separated names of packages to inject into home directory.
         ///
         /// If omitted, uses the packages from the `package.txt` file in the
@@ -141,8 +395,71 @@ enum Commands {
         names: Vec<EnvironmentPattern>,
     },
 
+    /// Check that Cubicle works with the configured runner.
+    ///
+    /// This creates a disposable environment with a trivial package, runs
+    /// commands in it, copies a file out, resets it, and purges it. It
+    /// prints whether each step passed and exits with an error if any
+    /// failed. This is useful after installing or upgrading Cubicle or the
+    /// runner's dependencies.
+    SelfTest,
+
+    /// Report on Cubicle's resource usage.
+    #[command(subcommand)]
+    Stats(StatsCommands),
+
+    /// Stop all processes running in environment(s).
+    ///
+    /// The environments' home and work directories are kept.
+    #[command(arg_required_else_help(true))]
+    Stop {
+        /// Save the state of running processes first, so that they resume
+        /// the next time the environment is entered.
+        ///
+        /// This is only supported with the Docker runner, which must have
+        /// experimental features and CRIU enabled.
+        #[arg(long)]
+        checkpoint: bool,
+        /// Environment name(s).
+        ///
+        /// Wildcards are allowed: `?` matches a single character and `*`
+        /// matches zero or more characters.
+        #[arg(required(true))]
+        names: Vec<EnvironmentPattern>,
+    },
+
+    /// Stop environments once they've been idle for a while.
+    ///
+    /// This runs in the foreground until interrupted, checking periodically
+    /// for environments that have no processes running in them (such as
+    /// interactive shells or commands from `exec`). Once an environment has
+    /// had no processes and no `enter` or `exec` session for long enough, it
+    /// is stopped, freeing up memory.
+    ///
+    /// To run this in the background whenever you're logged in, use `cub
+    /// generate systemd --service stop-idle`.
+    ///
+    /// This is not supported with the Bubblewrap runner, since its
+    /// environments already stop when their last process exits.
+    StopIdle {
+        /// Stop environments after they've been idle for this many minutes.
+        ///
+        /// Defaults to the `idle_timeout` setting, which is 30 minutes
+        /// unless configured otherwise.
+        #[arg(long)]
+        minutes: Option<u64>,
+    },
+
     /// Create and enter a new temporary environment.
     Tmp {
+        /// Keep the home directory on a tmpfs and discard the environment
+        /// when the shell exits.
+        ///
+        /// Nothing persists, so there's nothing to purge afterwards. This is
+        /// not supported with the User runner.
+        #[arg(long)]
+        ephemeral: bool,
+
         /// Comma-separated names of packages to inject into home directory.
         ///
         /// If omitted, uses the "default" package.
@@ -152,18 +469,331 @@ enum Commands {
         #[arg(long, value_delimiter = ',')]
         packages: Option<Vec<String>>,
     },
+
+    /// Manage groups of related environments.
+    ///
+    /// An environment named `WORKSPACE/NAME`, like `proj/frontend`, belongs
+    /// to the workspace WORKSPACE, which must be created first. The
+    /// environments in a workspace can share a network, mounts, and
+    /// environment variables, configured in a `[workspaces.WORKSPACE]`
+    /// section of `cubicle.toml`.
+    #[command(subcommand)]
+    Workspace(WorkspaceCommands),
+}
+
+/// Manage groups of related environments.
+#[derive(Debug, Subcommand)]
+enum WorkspaceCommands {
+    /// Create a new, empty workspace.
+    #[command(arg_required_else_help(true))]
+    Create {
+        /// New workspace name.
+        name: WorkspaceName,
+    },
+
+    /// Run a shell in an environment in the workspace.
+    ///
+    /// If the workspace has more than one environment, this asks which one
+    /// to enter.
+    #[command(arg_required_else_help(true))]
+    Enter {
+        /// Workspace name.
+        name: WorkspaceName,
+    },
+
+    /// Show existing workspaces and their environments.
+    List {
+        /// Set output format.
+        #[arg(long, value_enum, default_value_t)]
+        format: WorkspaceListFormat,
+    },
+
+    /// Delete workspace(s), including all their environments and work
+    /// directories.
+    #[command(arg_required_else_help(true))]
+    Purge {
+        /// Workspace name(s).
+        #[arg(required(true))]
+        names: Vec<WorkspaceName>,
+    },
+
+    /// Stop all processes running in the environments of workspace(s).
+    #[command(arg_required_else_help(true))]
+    Stop {
+        /// Workspace name(s).
+        #[arg(required(true))]
+        names: Vec<WorkspaceName>,
+    },
+}
+
+/// Manage an existing environment.
+#[derive(Debug, Subcommand)]
+enum EnvCommands {
+    /// Set or remove an environment's labels.
+    ///
+    /// Labels are arbitrary KEY=VALUE pairs that Cubicle stores for each
+    /// environment. `cub list --filter` selects environments by label, and
+    /// `cub list --format json` includes them.
+    #[command(arg_required_else_help(true))]
+    Label {
+        /// Remove the label with this key.
+        ///
+        /// This may be given more than once.
+        #[arg(long, value_name = "KEY")]
+        remove: Vec<String>,
+        /// Environment name.
+        ///
+        /// Wildcards are allowed: `?` matches a single character and `*`
+        /// matches zero or more characters.
+        name: EnvironmentPattern,
+        /// Labels to set, as KEY=VALUE.
+        #[arg(value_name = "KEY=VALUE")]
+        labels: Vec<LabelArg>,
+    },
+
+    /// Add packages to an existing environment without resetting it.
+    ///
+    /// This adds the packages to the environment's `packages.txt`, builds
+    /// them if needed, copies their files into its home directory, and runs
+    /// their `~/.dev-init/` scripts. Only packages that the environment
+    /// didn't already have are copied in, so the rest of the home directory
+    /// is left alone. OS-level packages are only installed on the next
+    /// `reset`.
+    #[command(arg_required_else_help(true))]
+    AddPackages {
+        /// Environment name.
+        ///
+        /// Wildcards are allowed: `?` matches a single character and `*`
+        /// matches zero or more characters.
+        name: EnvironmentPattern,
+        /// Package name(s).
+        ///
+        /// Wildcards are allowed: `?` matches a single character and `*`
+        /// matches zero or more characters.
+        #[arg(required(true))]
+        packages: Vec<String>,
+    },
+
+    /// Remove packages and their files from an environment.
+    ///
+    /// This removes the packages from the environment's `packages.txt` and
+    /// deletes the files they provided from its home directory, along with
+    /// those of any dependencies that the remaining packages don't need.
+    /// Packages may clean up other files with a `remove.sh` script or a
+    /// `removes` list in their manifest.
+    #[command(arg_required_else_help(true))]
+    RemovePackages {
+        /// Only print what would be removed and the commands that would run.
+        #[arg(long)]
+        dry_run: bool,
+        /// Environment name.
+        ///
+        /// Wildcards are allowed: `?` matches a single character and `*`
+        /// matches zero or more characters.
+        name: EnvironmentPattern,
+        /// Package name(s).
+        ///
+        /// Wildcards are allowed: `?` matches a single character and `*`
+        /// matches zero or more characters.
+        #[arg(required(true))]
+        packages: Vec<String>,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+enum StatsCommands {
+    /// Show how much disk space Cubicle is using.
+    ///
+    /// This adds up the home and work directories of all environments, the
+    /// package cache, environment metadata, dotfiles, and the runner's own
+    /// storage, like Docker base images.
+    Disk {
+        /// Print detailed JSON output for machine consumption.
+        #[arg(long)]
+        json: bool,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+enum ImageCommands {
+    /// List the OS packages installed in the image, with their versions.
+    ///
+    /// With the Docker runner, this looks inside the base image that Cubicle
+    /// builds, which must exist already. Other runners use the host's OS
+    /// packages, so this lists those.
+    ///
+    /// With `--check`, this instead checks that the OS packages that package
+    /// manifests depend on, like those in `[depends.debian]`, are installed
+    /// in the image or available from its package repositories.
+    Packages {
+        /// Check package manifests' OS dependencies against the image.
+        #[arg(long)]
+        check: bool,
+        /// Use the image for this environment, which may differ from the
+        /// default image (see `images` in the Docker runner's config).
+        #[arg(long)]
+        env: Option<EnvironmentName>,
+        /// Print detailed JSON output for machine consumption.
+        #[arg(long, conflicts_with = "check")]
+        json: bool,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+enum GenerateCommands {
+    /// Print a systemd user unit for a long-running service.
+    ///
+    /// With `--env`, the unit runs the given command line inside that
+    /// environment, starting when you log in and stopping the environment
+    /// when you log out. Without `--env`, the service must be `clipboard`,
+    /// `host-commands`, or `stop-idle`, and the unit runs that `cub` command
+    /// on the host.
+    ///
+    /// For example:
+    ///
+    ///   $ cub generate systemd --env web --service 'npm start' > ~/.config/systemd/user/cub-web.service
+    ///
+    ///   $ systemctl --user enable --now cub-web.service
+    #[command(arg_required_else_help(true))]
+    Systemd {
+        /// Environment in which to run the service.
+        #[arg(long)]
+        env: Option<EnvironmentName>,
+        /// Service to run: a command line when `--env` is given, or else the
+        /// name of a `cub` command.
+        #[arg(long)]
+        service: String,
+    },
 }
 
 /// View and manage packages.
 #[derive(Debug, Subcommand)]
 enum PackageCommands {
+    /// Show which packages depend on which.
+    ///
+    /// This prints the named packages and everything they transitively
+    /// depend on or build-depend on. A package is rebuilt when any package
+    /// it reaches in this graph changes.
+    Graph {
+        /// Set output format.
+        #[arg(long, value_enum, default_value_t)]
+        format: PackageGraphFormat,
+        /// Package name(s).
+        ///
+        /// Defaults to all packages. Wildcards are allowed: `?` matches a
+        /// single character and `*` matches zero or more characters.
+        packages: Vec<String>,
+    },
+
     /// Show available packages.
+    ///
+    /// With `--verbose`, this also lists packages that are hidden by
+    /// packages of the same name from package sources that take precedence.
     List {
         /// Set output format.
         #[arg(long, value_enum, default_value_t)]
         format: ListPackagesFormat,
     },
 
+    /// Record an environment's current package builds.
+    ///
+    /// This writes the content hash of every package build that the
+    /// environment's packages use to `packages.lock` in its work directory
+    /// and keeps those builds in the package cache. Later, `cub reset
+    /// --locked` seeds the environment with exactly those builds again.
+    #[command(arg_required_else_help(true))]
+    Lock {
+        /// Environment name.
+        name: EnvironmentName,
+    },
+
+    /// Show the output of a package's last build.
+    ///
+    /// This also includes the output of the package's tests since that
+    /// build, whether run as part of the build or by `cub package test`.
+    #[command(arg_required_else_help(true))]
+    Logs {
+        /// Show the log of the build for this Docker platform, like
+        /// `linux/arm64`, instead of the host's.
+        #[arg(long)]
+        platform: Option<String>,
+        /// Package name.
+        name: FullPackageName,
+    },
+
+    /// Create a new local package from templates.
+    ///
+    /// This creates a directory for the package in one of the local package
+    /// directories, with a `package.toml` manifest and `build.sh`,
+    /// `provides.txt`, and `test.sh` files to fill in. Options that aren't
+    /// given are asked for when stdin is a terminal.
+    #[command(arg_required_else_help(true))]
+    New {
+        /// The local package directory to create the package in, like
+        /// `local`.
+        ///
+        /// Defaults to the local package directory that's searched first, or
+        /// `local` if there are none.
+        #[arg(long, value_name = "DIR")]
+        source: Option<String>,
+        /// A one-line description of the package.
+        #[arg(long)]
+        description: Option<String>,
+        /// Comma-separated names of packages that the new package depends
+        /// on, like `asdf,npm.prettier`.
+        #[arg(long, value_delimiter = ',')]
+        depends: Option<Vec<FullPackageName>>,
+        /// Name of the new package.
+        name: String,
+    },
+
+    /// Show which built packages provide a file in the home directory.
+    ///
+    /// This searches the cached package builds for PATH, or for anything
+    /// under it if it's a directory. It helps to find which packages ship
+    /// the same dotfile.
+    #[command(arg_required_else_help(true))]
+    Provides {
+        /// Search the builds for this Docker platform, like `linux/arm64`,
+        /// instead of the host's.
+        #[arg(long)]
+        platform: Option<String>,
+        /// Path relative to the home directory, like `~/.bashrc` or
+        /// `.config/nvim`.
+        path: PathBuf,
+    },
+
+    /// Show which packages and environments depend on a package.
+    ///
+    /// This lists the packages that depend on or build-depend on the named
+    /// package, directly or transitively, so they'd be rebuilt after it
+    /// changes. It also lists the existing environments whose packages
+    /// depend on it.
+    #[command(arg_required_else_help(true))]
+    Rdeps {
+        /// Package name.
+        name: FullPackageName,
+    },
+
+    /// Test one or more packages without rebuilding them.
+    ///
+    /// This runs each package's `test.sh` in a new environment seeded with
+    /// the package's current build and its dependencies. Dependencies that
+    /// have never been built are built first.
+    #[command(arg_required_else_help(true))]
+    Test {
+        /// Test the builds for this Docker platform, like `linux/arm64`,
+        /// instead of the host's.
+        #[arg(long)]
+        platform: Option<String>,
+        /// Package name(s).
+        ///
+        /// Wildcards are allowed: `?` matches a single character and `*`
+        /// matches zero or more characters.
+        #[arg(required(true))]
+        packages: Vec<String>,
+    },
+
     /// (Re-)build one or more packages.
     #[command(arg_required_else_help(true))]
     Update {
@@ -180,6 +810,10 @@ enum PackageCommands {
         /// strictly needed because have never been built successfully before.
         #[arg(long)]
         skip_deps: bool,
+        /// Build for this Docker platform, like `linux/arm64`, instead of the
+        /// host's.
+        #[arg(long)]
+        platform: Option<String>,
         /// Package name(s).
         ///
         /// Wildcards are allowed: `?` matches a single character and `*`
@@ -187,6 +821,17 @@ enum PackageCommands {
         #[arg(required(true))]
         packages: Vec<String>,
     },
+
+    /// Show which package source a package comes from.
+    ///
+    /// This prints the directory of the package's definition, any other
+    /// definitions of the same name that it hides, and why it takes
+    /// precedence over them. See `package_precedence` in the config file.
+    #[command(arg_required_else_help(true))]
+    Which {
+        /// Package name.
+        name: FullPackageName,
+    },
 }
 
 /// Parses the command-line arguments given to this executable.
@@ -203,6 +848,14 @@ impl Args {
     pub fn config_path(&self) -> &Path {
         self.config.as_ref()
     }
+
+    /// Returns how much to log, from `--verbose` and `--quiet`.
+    pub fn verbosity(&self) -> Verbosity {
+        Verbosity(
+            i8::try_from(self.verbose).unwrap_or(i8::MAX)
+                - i8::try_from(self.quiet).unwrap_or(i8::MAX),
+        )
+    }
 }
 
 /// This type wrapper stores a normal path but understands "$HOME".
@@ -327,7 +980,7 @@ fn write_completions<W: io::Write>(shell: Shell, out: &mut W) -> Result<()> {
         let mut buf: Vec<u8> = Vec::new();
         generate(shell, cmd, "cub", &mut buf);
         let buf = String::from_utf8(buf).context("error reading clap shell completion output")?;
-        let mut counts = [0; 5];
+        let mut counts = [0; 6];
         let mut write = || -> std::io::Result<()> {
             for line in buf.lines() {
                 match line {
@@ -343,6 +996,10 @@ fn write_completions<W: io::Write>(shell: Shell, out: &mut W) -> Result<()> {
                         counts[2] += 1;
                         writeln!(out, r#"'*::packages -- Package name(s):_cub_pkgs' \"#)?;
                     }
+                    r#"':name -- Package name:' \"# => {
+                        counts[5] += 1;
+                        writeln!(out, r#"':name -- Package name:_cub_pkgs' \"#)?;
+                    }
                     r#"'*--packages=[Comma-separated names of packages to inject into home directory]:PACKAGES: ' \"# =>
                     {
                         counts[3] += 1;
@@ -381,7 +1038,7 @@ _cub_pkgs_comma() {
         write().context("failed to write zsh completions")?;
         debug_assert_eq!(
             counts,
-            [2, 2, 1, 3, 1],
+            [9, 3, 5, 3, 1, 3],
             "zsh completions not patched as expected"
         );
     } else {
@@ -393,73 +1050,346 @@ _cub_pkgs_comma() {
 /// Execute the subcommand requested on the command line.
 pub fn run(args: Args, program: &Cubicle) -> Result<()> {
     use Commands::*;
+    let verbosity = args.verbosity();
+    program.set_progress_format(args.progress);
+    if args.yes {
+        program.set_confirmer(Box::new(AssumeYes));
+    }
     match args.command {
+        Clipboard => program.bridge_clipboard(),
         Completions { shell } => write_completions(shell, &mut io::stdout()),
-        Enter { name } => {
-            program.enter_environment(&name.matching_environment(program.get_environment_names()?)?)
+        ConnectInfo { format, name } => program.print_connect_info(
+            &name.matching_environment(program.get_environment_names()?)?,
+            format,
+        ),
+        Cp { src, dst } => {
+            let name = match (&src.env, &dst.env) {
+                (Some(name), None) | (None, Some(name)) => name,
+                (Some(_), Some(_)) => {
+                    return Err(anyhow!(
+                        "can't copy between environments: only one of SRC and DST may be `ENV:PATH`"
+                    ))
+                }
+                (None, None) => {
+                    return Err(anyhow!("one of SRC and DST must be `ENV:PATH`"));
+                }
+            };
+            program.copy(name, &src.path(), &dst.path())
         }
-        Exec { name, command } => program.exec_environment(
+        Du { depth, top, name } => program.du_environment(
             &name.matching_environment(program.get_environment_names()?)?,
-            &command,
+            depth,
+            top,
         ),
-        List { format } => program.list_environments(format),
+        Enter {
+            read_only_home,
+            record: true,
+            at,
+            name,
+        } => {
+            let name = name.matching_environment(program.get_environment_names()?)?;
+            // The session runs in a new `cub` process under `script`, which
+            // provides the terminal to record.
+            let exe = std::env::current_exe()
+                .context("error getting the path of the current executable")?;
+            let mut command = vec![
+                exe.into_os_string(),
+                OsString::from("--config"),
+                args.config.0.into_os_string(),
+                OsString::from("enter"),
+            ];
+            if read_only_home {
+                command.push(OsString::from("--read-only-home"));
+            }
+            if let Some(at) = at {
+                command.push(OsString::from("--at"));
+                command.push(at.into_os_string());
+            }
+            command.push(OsString::from("--"));
+            command.push(OsString::from(name.as_str()));
+            program.record_session(&name, &command)
+        }
+        Enter {
+            read_only_home,
+            record: false,
+            at,
+            name,
+        } => {
+            // Listing every environment can be slow, and `cub enter` should
+            // start quickly, so exact names skip it.
+            let name = match name.exact_name() {
+                Some(name) => name,
+                None => name.matching_environment(program.get_environment_names()?)?,
+            };
+            program.enter_environment(&name, ReadOnlyHome(read_only_home), at.as_deref())
+        }
+        Env(EnvCommands::Label {
+            remove,
+            name,
+            labels,
+        }) => program.label_environment(
+            &name.matching_environment(program.get_environment_names()?)?,
+            &label_map(labels),
+            &remove,
+        ),
+        Env(EnvCommands::AddPackages { name, packages }) => {
+            let name = name.matching_environment(program.get_environment_names()?)?;
+            let packages = package_set_from_patterns(&packages, program.get_package_names()?)?;
+            program.add_packages_to_environment(&name, &packages)
+        }
+        Env(EnvCommands::RemovePackages {
+            dry_run,
+            name,
+            packages,
+        }) => {
+            let name = name.matching_environment(program.get_environment_names()?)?;
+            let packages = package_set_from_patterns(&packages, program.get_package_names()?)?;
+            program.remove_packages_from_environment(&name, &packages, DryRun(dry_run))
+        }
+        Generate(GenerateCommands::Systemd { env, service }) => {
+            print!("{}", program.systemd_unit(env.as_ref(), &service)?);
+            Ok(())
+        }
+        Exec { name, command } => {
+            let status = program.exec_environment_status(
+                &name.matching_environment(program.get_environment_names()?)?,
+                &command,
+            )?;
+            if !status.success() {
+                std::process::exit(exit_code(status));
+            }
+            Ok(())
+        }
+        HostBroker => program.serve_host_commands(),
+        Image(ImageCommands::Packages {
+            check: true, env, ..
+        }) => program.check_image_packages(env.as_ref()),
+        Image(ImageCommands::Packages { env, json, .. }) => {
+            program.print_image_packages(env.as_ref(), Json(json))
+        }
+        List {
+            filter,
+            format,
+            sort,
+            no_refresh,
+        } => program.list_environments(format, &filter, sort, Refresh(!no_refresh)),
         New {
             name,
             enter,
+            interactive,
+            labels,
             packages,
         } => {
-            let packages = packages
-                .map(|packages| package_set_from_patterns(&packages, program.get_package_names()?))
-                .transpose()?;
+            let packages = if interactive {
+                Some(program.choose_packages(&name)?)
+            } else {
+                packages
+                    .map(|packages| {
+                        package_set_from_patterns(&packages, program.get_package_names()?)
+                    })
+                    .transpose()?
+            };
             program.new_environment(&name, packages)?;
+            if !labels.is_empty() {
+                program.label_environment(&name, &label_map(labels), &[])?;
+            }
             if enter {
-                program.enter_environment(&name)?;
+                program.enter_environment(&name, ReadOnlyHome(false), None)?;
             }
             Ok(())
         }
-        Package(command) => run_package_command(command, program),
+        Package(command) => run_package_command(command, verbosity, program),
+        Prune { dry_run } => program.prune(DryRun(dry_run)),
         Purge { names } => {
             for name in matching_environments(&names, program.get_environment_names()?)? {
-                program.purge_environment(&name, Quiet(false))?;
+                program.purge_environment(&name, Quiet(false), Force(false))?;
             }
             Ok(())
         }
+        Replay {
+            list,
+            name,
+            recording,
+        } => {
+            let name = name.matching_environment(program.get_environment_names()?)?;
+            if list {
+                program.list_recordings(&name)
+            } else {
+                program.replay_session(&name, recording)
+            }
+        }
+        SelfTest => program.self_test(),
+        Doctor => program.doctor(),
+        Stats(StatsCommands::Disk { json }) => program.print_disk_usage(Json(json)),
         // TODO: rename
-        Reset { names, packages } => {
+        Reset {
+            force,
+            keep_home,
+            locked,
+            names,
+            packages,
+        } => {
             let packages = packages
                 .map(|packages| package_set_from_patterns(&packages, program.get_package_names()?))
                 .transpose()?;
             for name in matching_environments(&names, program.get_environment_names()?)? {
-                program.reset_environment(&name, packages.clone())?;
+                program.reset_environment(
+                    &name,
+                    packages.clone(),
+                    Force(force),
+                    KeepHome(keep_home),
+                    Locked(locked),
+                )?;
             }
             Ok(())
         }
-        Tmp { packages } => {
+        Stop { checkpoint, names } => {
+            for name in matching_environments(&names, program.get_environment_names()?)? {
+                if checkpoint {
+                    program.checkpoint_environment(&name)?;
+                } else {
+                    program.stop_environment(&name)?;
+                }
+            }
+            Ok(())
+        }
+        StopIdle { minutes } => {
+            let idle_timeout = match minutes {
+                Some(minutes) => {
+                    let seconds = minutes
+                        .checked_mul(60)
+                        .ok_or_else(|| anyhow!("--minutes is too large: {minutes}"))?;
+                    std::time::Duration::from_secs(seconds)
+                }
+                None => program.idle_timeout().ok_or_else(|| {
+                    anyhow!("`idle_timeout` is set to \"never\" (use `--minutes` to override it)")
+                })?,
+            };
+            program.stop_idle_environments(idle_timeout)
+        }
+        Tmp {
+            ephemeral,
+            packages,
+        } => {
             let packages = packages
                 .map(|packages| package_set_from_patterns(&packages, program.get_package_names()?))
                 .transpose()?;
-            program.create_enter_tmp_environment(packages)
+            program.create_enter_tmp_environment(packages, Ephemeral(ephemeral))
+        }
+        Workspace(command) => run_workspace_command(command, program),
+    }
+}
+
+fn run_workspace_command(command: WorkspaceCommands, program: &Cubicle) -> Result<()> {
+    use WorkspaceCommands::*;
+    match command {
+        Create { name } => program.create_workspace(&name),
+        Enter { name } => program.enter_workspace(&name),
+        List { format } => program.list_workspaces(format),
+        Purge { names } => {
+            for name in names {
+                program.purge_workspace(&name, Force(false))?;
+            }
+            Ok(())
+        }
+        Stop { names } => {
+            for name in names {
+                program.stop_workspace(&name)?;
+            }
+            Ok(())
         }
     }
 }
 
-fn run_package_command(command: PackageCommands, program: &Cubicle) -> Result<()> {
+fn run_package_command(
+    command: PackageCommands,
+    verbosity: Verbosity,
+    program: &Cubicle,
+) -> Result<()> {
     use PackageCommands::*;
     match command {
-        List { format } => program.list_packages(format),
+        List { format } => {
+            program.list_packages(format)?;
+            if format == ListPackagesFormat::Default && verbosity.0 > 0 {
+                program.list_hidden_packages()?;
+            }
+            Ok(())
+        }
+
+        Lock { name } => program.lock_packages(&name),
+
+        New {
+            source,
+            description,
+            depends,
+            name,
+        } => program
+            .new_package(
+                &name,
+                NewPackage {
+                    source,
+                    description,
+                    depends: depends.map(|depends| depends.into_iter().collect()),
+                },
+            )
+            .map(|_| ()),
+
+        Which { name } => program.which_package(&name),
+
+        Rdeps { name } => program.list_reverse_dependencies(&name),
+
+        Provides { platform, path } => {
+            let platform = match platform {
+                Some(platform) => Platform::from_docker(&platform)?,
+                None => Platform::host(),
+            };
+            program.list_package_providers(&path, &platform)
+        }
+
+        Graph { format, packages } => {
+            let packages = if packages.is_empty() {
+                BTreeSet::new()
+            } else {
+                package_set_from_patterns(&packages, program.get_package_names()?)?
+            };
+            program.package_graph(&packages, format)
+        }
+
+        Logs { platform, name } => {
+            let platform = match platform {
+                Some(platform) => Platform::from_docker(&platform)?,
+                None => Platform::host(),
+            };
+            program.package_logs(&name, &platform)
+        }
+
+        Test { platform, packages } => {
+            let platform = match platform {
+                Some(platform) => Platform::from_docker(&platform)?,
+                None => Platform::host(),
+            };
+            let packages = package_set_from_patterns(&packages, program.get_package_names()?)?;
+            program.test_packages(&packages, &platform)
+        }
 
         Update {
             clean,
             skip_deps,
+            platform,
             packages,
         } => {
             use ShouldPackageUpdate::*;
+            let platform = match platform {
+                Some(platform) => Platform::from_docker(&platform)?,
+                None => Platform::host(),
+            };
             let packages = package_set_from_patterns(&packages, program.get_package_names()?)?;
             if clean {
                 for package in &packages {
                     program.purge_environment(
-                        &EnvironmentName::for_builder_package(package),
+                        &EnvironmentName::for_builder_package(package, &platform),
                         Quiet(true),
+                        Force(true),
                     )?;
                 }
             }
@@ -471,6 +1401,7 @@ fn run_package_command(command: PackageCommands, program: &Cubicle) -> Result<()
                     dependencies: if skip_deps { IfRequired } else { IfStale },
                     named: Always,
                 },
+                &platform,
             )
         }
     }
@@ -500,6 +1431,72 @@ impl GlobPattern {
     }
 }
 
+/// An environment label given on the command line as `KEY=VALUE`.
+#[derive(Clone, Debug)]
+struct LabelArg {
+    key: String,
+    value: String,
+}
+
+impl FromStr for LabelArg {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.split_once('=') {
+            Some((key, value)) => Ok(Self {
+                key: key.to_owned(),
+                value: value.to_owned(),
+            }),
+            None => Err(anyhow!("expected KEY=VALUE, found {s:?}")),
+        }
+    }
+}
+
+fn label_map(labels: Vec<LabelArg>) -> BTreeMap<String, String> {
+    labels
+        .into_iter()
+        .map(|LabelArg { key, value }| (key, value))
+        .collect()
+}
+
+/// One end of `cub cp`, given as `ENV:PATH` or a host path.
+#[derive(Clone, Debug)]
+struct CopyArg {
+    env: Option<EnvironmentName>,
+    path: PathBuf,
+}
+
+impl CopyArg {
+    fn path(&self) -> CopyPath {
+        match self.env {
+            Some(_) => CopyPath::Env(self.path.clone()),
+            None => CopyPath::Host(self.path.clone()),
+        }
+    }
+}
+
+impl FromStr for CopyArg {
+    type Err = std::convert::Infallible;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        // Host paths like `./a:b` and `/a:b` can be written unambiguously.
+        if !s.starts_with(['.', '/']) {
+            if let Some((env, path)) = s.split_once(':') {
+                if let Ok(env) = EnvironmentName::from_str(env) {
+                    return Ok(Self {
+                        env: Some(env),
+                        path: PathBuf::from(path),
+                    });
+                }
+            }
+        }
+        Ok(Self {
+            env: None,
+            path: PathBuf::from(s),
+        })
+    }
+}
+
 #[derive(Clone, Debug)]
 struct EnvironmentPattern(GlobPattern);
 
@@ -518,6 +1515,15 @@ impl FromStr for EnvironmentPattern {
 }
 
 impl EnvironmentPattern {
+    /// Returns the environment name if this is not a pattern. The
+    /// environment may or may not exist.
+    fn exact_name(&self) -> Option<EnvironmentName> {
+        if self.0.is_pattern() {
+            return None;
+        }
+        EnvironmentName::from_str(&self.0.str).ok()
+    }
+
     fn matches(&self, name: &EnvironmentName) -> bool {
         self.0.matches(name.as_ref())
     }
@@ -584,6 +1590,45 @@ mod tests {
     use clap::CommandFactory;
     use expect_test::{expect, expect_file};
 
+    #[test]
+    fn copy_arg() {
+        let parse = |s| {
+            let arg = CopyArg::from_str(s).unwrap();
+            (
+                arg.env.as_ref().map(|env| env.as_str().to_owned()),
+                arg.path(),
+            )
+        };
+        assert_eq!(
+            parse("eg:w/a"),
+            (
+                Some(String::from("eg")),
+                CopyPath::Env(PathBuf::from("w/a"))
+            )
+        );
+        assert_eq!(
+            parse("eg:"),
+            (Some(String::from("eg")), CopyPath::Env(PathBuf::new()))
+        );
+        assert_eq!(parse("a"), (None, CopyPath::Host(PathBuf::from("a"))));
+        assert_eq!(
+            parse("./eg:a"),
+            (None, CopyPath::Host(PathBuf::from("./eg:a")))
+        );
+        assert_eq!(
+            parse("/eg:a"),
+            (None, CopyPath::Host(PathBuf::from("/eg:a")))
+        );
+    }
+
+    #[test]
+    fn exit_code() {
+        assert_eq!(0, super::exit_code(ExitStatus::from_raw(0)));
+        assert_eq!(3, super::exit_code(ExitStatus::from_raw(3 << 8)));
+        // SIGKILL
+        assert_eq!(137, super::exit_code(ExitStatus::from_raw(9)));
+    }
+
     #[test]
     fn sub_home_prefix() {
         let p = PathWithVarExpansion(PathBuf::from("/home/foo/bar"));
@@ -714,17 +1759,51 @@ mod tests {
     fn usage() {
         for cmd in [
             "",
+            "clipboard",
             "completions",
+            "connect-info",
+            "cp",
+            "doctor",
+            "du",
             "enter",
+            "env",
+            "env add-packages",
+            "env remove-packages",
             "exec",
+            "generate",
+            "generate systemd",
+            "host-commands",
+            "image",
+            "image packages",
             "list",
             "new",
             "package",
+            "package graph",
             "package list",
+            "package lock",
+            "package logs",
+            "package new",
+            "package provides",
+            "package rdeps",
+            "package test",
             "package update",
+            "package which",
+            "prune",
             "purge",
+            "replay",
             "reset",
+            "self-test",
+            "stats",
+            "stats disk",
+            "stop",
+            "stop-idle",
             "tmp",
+            "workspace",
+            "workspace create",
+            "workspace enter",
+            "workspace list",
+            "workspace purge",
+            "workspace stop",
         ] {
             let split_cmd = shlex::split(&format!("cub {cmd} --help")).unwrap();
             let err = Args::command()
@@ -0,0 +1,174 @@
+//! Bridges the clipboard between the host and environments.
+//!
+//! Environments selected by [`Config::clipboard`](super::config::Config::clipboard)
+//! get a directory shared with the host. Inside the environment, the
+//! `cub-copy` script (from the `clipboard` package) writes a file named
+//! `copy` there, and `cub-paste` reads a file named `paste`. On the host, `cub
+//! clipboard` moves data between these files and the host's clipboard, using
+//! `wl-copy`/`wl-paste` under Wayland or `xclip` under X11. This works even
+//! when the environment can't reach the host's display server.
+
+use std::io::Write;
+use std::process::Stdio;
+use std::thread::sleep;
+use std::time::Duration;
+
+use super::command_ext::Command;
+use super::runner::HostSocket;
+use super::{Cubicle, CubicleShared, EnvironmentName, HostPath};
+use crate::somehow::{somehow as anyhow, warn, Context, Result};
+
+/// Where the shared clipboard directory is placed inside environments.
+const ENV_PATH: &str = "/run/cubicle-clipboard";
+
+/// How often `cub clipboard` checks for changes.
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Returns the directory to share with the environment for its clipboard, if
+/// the environment has clipboard access. Creates the directory on the host if
+/// needed.
+pub fn env_clipboard_share(
+    program: &CubicleShared,
+    name: &EnvironmentName,
+) -> Result<Option<HostSocket>> {
+    if !program.config.clipboard_enabled(name) {
+        return Ok(None);
+    }
+    let host_path = host_clipboard_dir(program, name);
+    std::fs::create_dir_all(host_path.as_host_raw())
+        .with_context(|| format!("failed to create clipboard directory {host_path}"))?;
+    Ok(Some(HostSocket {
+        host_path,
+        env_path: String::from(ENV_PATH),
+        env_var: ("CUBICLE_CLIPBOARD", String::from(ENV_PATH)),
+    }))
+}
+
+fn host_clipboard_dir(program: &CubicleShared, name: &EnvironmentName) -> HostPath {
+    program.data_dir.join("clipboard").join(name.as_filename())
+}
+
+/// Commands used to access the host's clipboard.
+struct HostClipboard {
+    copy: Vec<&'static str>,
+    paste: Vec<&'static str>,
+}
+
+impl HostClipboard {
+    fn detect() -> Result<Self> {
+        if std::env::var_os("WAYLAND_DISPLAY").is_some() {
+            Ok(Self {
+                copy: vec!["wl-copy"],
+                paste: vec!["wl-paste", "--no-newline"],
+            })
+        } else if std::env::var_os("DISPLAY").is_some() {
+            Ok(Self {
+                copy: vec!["xclip", "-selection", "clipboard", "-in"],
+                paste: vec!["xclip", "-selection", "clipboard", "-out"],
+            })
+        } else {
+            Err(anyhow!(
+                "could not find the host's clipboard: neither `$WAYLAND_DISPLAY` nor `$DISPLAY` is set"
+            ))
+        }
+    }
+
+    fn copy(&self, data: &[u8]) -> Result<()> {
+        let mut child = Command::new(self.copy[0])
+            .args(&self.copy[1..])
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .scoped_spawn()?;
+        let mut stdin = child.stdin().take().unwrap();
+        stdin
+            .write_all(data)
+            .with_context(|| format!("failed to write to `{}`", self.copy[0]))?;
+        drop(stdin);
+        let status = child.wait()?;
+        if !status.success() {
+            return Err(anyhow!("`{}` exited with {status}", self.copy[0]));
+        }
+        Ok(())
+    }
+
+    fn paste(&self) -> Result<Vec<u8>> {
+        let output = Command::new(self.paste[0])
+            .args(&self.paste[1..])
+            .stderr(Stdio::null())
+            .output()?;
+        // These exit with an error when the clipboard is empty.
+        if !output.status.success() {
+            return Ok(Vec::new());
+        }
+        Ok(output.stdout)
+    }
+}
+
+impl Cubicle {
+    /// Corresponds to `cub clipboard`.
+    ///
+    /// Runs until interrupted, copying between the host's clipboard and the
+    /// shared clipboard directories of the environments that have clipboard
+    /// access.
+    pub fn bridge_clipboard(&self) -> Result<()> {
+        let host = HostClipboard::detect()?;
+        let mut last_paste: Option<Vec<u8>> = None;
+        loop {
+            let dirs = self
+                .get_environment_names()?
+                .into_iter()
+                .filter(|name| self.shared.config.clipboard_enabled(name))
+                .map(|name| {
+                    let share = env_clipboard_share(&self.shared, &name)?
+                        .expect("clipboard should be enabled");
+                    Ok((name, share.host_path))
+                })
+                .collect::<Result<Vec<_>>>()?;
+
+            for (name, dir) in &dirs {
+                if let Err(e) = self.bridge_copy(&host, dir) {
+                    warn(e.context(format!("failed to copy from environment {name}")));
+                }
+            }
+
+            let paste = host.paste()?;
+            let changed = last_paste.as_ref() != Some(&paste);
+            for (name, dir) in &dirs {
+                if changed || !dir.join("paste").as_host_raw().exists() {
+                    if let Err(e) = write_paste(dir, &paste) {
+                        warn(e.context(format!("failed to paste into environment {name}")));
+                    }
+                }
+            }
+            last_paste = Some(paste);
+
+            sleep(POLL_INTERVAL);
+        }
+    }
+
+    fn bridge_copy(&self, host: &HostClipboard, dir: &HostPath) -> Result<()> {
+        // Move the file aside first, so that a concurrent `cub-copy` isn't
+        // lost.
+        let path = dir.join("copy");
+        let taken = dir.join("copy.taken");
+        match std::fs::rename(path.as_host_raw(), taken.as_host_raw()) {
+            Ok(()) => {}
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e).with_context(|| format!("failed to rename {path}")),
+        }
+        let data = std::fs::read(taken.as_host_raw())
+            .with_context(|| format!("failed to read {taken}"))?;
+        std::fs::remove_file(taken.as_host_raw())
+            .with_context(|| format!("failed to remove {taken}"))?;
+        host.copy(&data)
+    }
+}
+
+/// Atomically replaces the `paste` file in the shared clipboard directory.
+fn write_paste(dir: &HostPath, data: &[u8]) -> Result<()> {
+    let tmp = dir.join("paste.tmp");
+    std::fs::write(tmp.as_host_raw(), data).with_context(|| format!("failed to write {tmp}"))?;
+    let path = dir.join("paste");
+    std::fs::rename(tmp.as_host_raw(), path.as_host_raw())
+        .with_context(|| format!("failed to rename {tmp} to {path}"))
+}
@@ -1,49 +1,182 @@
 #![allow(clippy::disallowed_types)]
+use std::cell::RefCell;
 use std::ffi::{OsStr, OsString};
+use std::fmt::{self, Display};
+use std::io::{self, Read};
 use std::process::{Child, Command as StdCommand};
 pub use std::process::{ChildStderr, ChildStdin, ChildStdout, ExitStatus, Output, Stdio};
+use std::rc::Rc;
+use std::sync::mpsc;
+use tracing::{debug, trace};
 
 use crate::somehow::{Context, Result};
+use crate::DryRun;
+
+thread_local! {
+    /// The transcript that commands spawned on this thread are recorded
+    /// into, if any. See [`Transcript::record`].
+    static RECORDING: RefCell<Option<(Transcript, DryRun)>> = const { RefCell::new(None) };
+}
+
+/// A record of the external commands that Cubicle ran, or would have run.
+///
+/// Use [`Transcript::record`] to start recording.
+#[derive(Clone, Debug, Default)]
+pub struct Transcript {
+    entries: Rc<RefCell<Vec<TranscriptEntry>>>,
+}
+
+/// One external command in a [`Transcript`].
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct TranscriptEntry {
+    /// The program name followed by its arguments.
+    pub argv: Vec<String>,
+    /// The command's exit code, or `None` if it wasn't run (in dry-run mode),
+    /// hasn't been waited on, or was killed by a signal.
+    pub exit_code: Option<i32>,
+    /// Whether the command only queries state. Such commands run even in
+    /// dry-run mode.
+    pub read_only: bool,
+}
+
+impl Display for TranscriptEntry {
+    /// Formats the command as a shell command line.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match shlex::try_join(self.argv.iter().map(|a| a.as_str())) {
+            Ok(line) => f.write_str(&line),
+            Err(_) => write!(f, "{:?}", self.argv),
+        }
+    }
+}
+
+/// Formats a program and its arguments as a shell command line, for logging.
+fn shell_line(argv: &[OsString]) -> String {
+    let argv = argv
+        .iter()
+        .map(|arg| arg.to_string_lossy())
+        .collect::<Vec<_>>();
+    match shlex::try_join(argv.iter().map(|arg| arg.as_ref())) {
+        Ok(line) => line,
+        Err(_) => format!("{argv:?}"),
+    }
+}
+
+impl Transcript {
+    /// Creates an empty transcript.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Records the external commands spawned on the current thread into this
+    /// transcript, until the returned guard is dropped.
+    ///
+    /// With `dry_run`, the commands are recorded but not run, except for
+    /// read-only queries. Instead, each one acts like a process that discards
+    /// its input, produces no output, and exits successfully. Operations that
+    /// depend on real output from such a command may fail or take a
+    /// different path in this mode.
+    pub fn record(&self, dry_run: DryRun) -> Recording {
+        let previous =
+            RECORDING.with(|recording| recording.borrow_mut().replace((self.clone(), dry_run)));
+        Recording { previous }
+    }
+
+    /// Returns the commands recorded so far, in the order they were spawned.
+    pub fn entries(&self) -> Vec<TranscriptEntry> {
+        self.entries.borrow().clone()
+    }
+
+    fn push(&self, argv: &[OsString], read_only: bool) -> usize {
+        let mut entries = self.entries.borrow_mut();
+        entries.push(TranscriptEntry {
+            argv: argv
+                .iter()
+                .map(|arg| arg.to_string_lossy().into_owned())
+                .collect(),
+            exit_code: None,
+            read_only,
+        });
+        entries.len() - 1
+    }
+
+    fn set_status(&self, index: usize, status: &ExitStatus) {
+        if let Some(entry) = self.entries.borrow_mut().get_mut(index) {
+            entry.exit_code = status.code();
+        }
+    }
+}
+
+/// Returned from [`Transcript::record`]. Recording stops when this is
+/// dropped.
+#[must_use]
+pub struct Recording {
+    previous: Option<(Transcript, DryRun)>,
+}
+
+impl Drop for Recording {
+    fn drop(&mut self) {
+        RECORDING.with(|recording| *recording.borrow_mut() = self.previous.take());
+    }
+}
 
 #[must_use]
 pub struct ScopedChild {
     inner: Option<Child>,
     name: OsString,
+    /// Where to record the exit status, unless this is a dry run.
+    entry: Option<(Transcript, usize)>,
 }
 
 impl ScopedChild {
-    fn new(inner: Child, name: &OsStr) -> Self {
+    fn new(inner: Child, name: &OsStr, entry: Option<(Transcript, usize)>) -> Self {
         Self {
             inner: Some(inner),
             name: name.to_owned(),
+            entry,
+        }
+    }
+
+    fn record(&self, status: &ExitStatus) {
+        trace!("{:?} exited with {status}", self.name);
+        if let Some((transcript, index)) = &self.entry {
+            transcript.set_status(*index, status);
         }
     }
 
+    pub fn id(&self) -> u32 {
+        self.inner.as_ref().unwrap().id()
+    }
+
     pub fn stdin(&mut self) -> &mut Option<ChildStdin> {
         &mut self.inner.as_mut().unwrap().stdin
     }
     pub fn stdout(&mut self) -> &mut Option<ChildStdout> {
         &mut self.inner.as_mut().unwrap().stdout
     }
-    #[allow(dead_code)]
     pub fn stderr(&mut self) -> &mut Option<ChildStderr> {
         &mut self.inner.as_mut().unwrap().stderr
     }
 
     pub fn wait(&mut self) -> Result<ExitStatus> {
-        self.inner
+        let status = self
+            .inner
             .as_mut()
             .unwrap()
             .wait()
-            .with_context(|| format!("error waiting on child process {:?}", self.name))
+            .with_context(|| format!("error waiting on child process {:?}", self.name))?;
+        self.record(&status);
+        Ok(status)
     }
 
     pub fn wait_with_output(mut self) -> Result<Output> {
-        self.inner
+        let output = self
+            .inner
             .take()
             .unwrap()
             .wait_with_output()
-            .with_context(|| format!("error waiting on child process {:?}", self.name))
+            .with_context(|| format!("error waiting on child process {:?}", self.name))?;
+        self.record(&output.status);
+        Ok(output)
     }
 }
 
@@ -58,9 +191,59 @@ impl Drop for ScopedChild {
     }
 }
 
+/// One of a child process's output streams. See
+/// [`Cubicle::exec_environment_streaming`](crate::Cubicle::exec_environment_streaming).
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum OutputStream {
+    /// Standard output.
+    Stdout,
+    /// Standard error.
+    Stderr,
+}
+
+impl Display for OutputStream {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Stdout => "stdout",
+            Self::Stderr => "stderr",
+        })
+    }
+}
+
+/// Reads from a child's output stream until EOF or an error, sending what
+/// it reads to [`Command::stream`].
+fn forward_output<R: Read>(
+    stream: OutputStream,
+    mut reader: R,
+    sender: mpsc::Sender<(OutputStream, io::Result<Vec<u8>>)>,
+) {
+    let mut buf = [0; 8192];
+    loop {
+        let chunk = match reader.read(&mut buf) {
+            Ok(0) => return,
+            Ok(n) => Ok(buf[..n].to_vec()),
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => Err(e),
+        };
+        let failed = chunk.is_err();
+        if sender.send((stream, chunk)).is_err() || failed {
+            return;
+        }
+    }
+}
+
+/// The program that runs in place of each command during a dry run.
+const DRY_RUN_STAND_IN: [&str; 3] = ["/bin/sh", "-c", "cat >/dev/null"];
+
 #[derive(Debug)]
 pub struct Command {
+    /// During a dry run, this runs [`DRY_RUN_STAND_IN`] instead.
     inner: StdCommand,
+    /// The program and arguments, as requested.
+    argv: Vec<OsString>,
+    /// See [`Command::query`].
+    read_only: bool,
+    recording: Option<(Transcript, DryRun)>,
     set_stdin: bool,
     set_stdout: bool,
     set_stderr: bool,
@@ -68,26 +251,69 @@ pub struct Command {
 
 impl Command {
     pub fn new<S: AsRef<OsStr>>(program: S) -> Self {
+        Self::with_read_only(program, false)
+    }
+
+    /// Like [`Command::new`], for a command that only queries state and
+    /// doesn't change anything. Such commands run even during a dry run (see
+    /// [`Transcript::record`]).
+    pub fn query<S: AsRef<OsStr>>(program: S) -> Self {
+        Self::with_read_only(program, true)
+    }
+
+    fn with_read_only<S: AsRef<OsStr>>(program: S, read_only: bool) -> Self {
+        let recording = RECORDING.with(|recording| recording.borrow().clone());
+        let inner = match &recording {
+            Some((_, DryRun(true))) if !read_only => {
+                let mut inner = StdCommand::new(DRY_RUN_STAND_IN[0]);
+                inner.args(&DRY_RUN_STAND_IN[1..]);
+                inner
+            }
+            _ => StdCommand::new(&program),
+        };
         Self {
-            inner: StdCommand::new(program),
+            inner,
+            argv: vec![program.as_ref().to_owned()],
+            read_only,
+            recording,
             set_stdin: false,
             set_stdout: false,
             set_stderr: false,
         }
     }
 
+    fn is_dry_run(&self) -> bool {
+        !self.read_only && matches!(self.recording, Some((_, DryRun(true))))
+    }
+
     pub fn scoped_spawn(&mut self) -> Result<ScopedChild> {
+        let entry = match &self.recording {
+            Some((transcript, _)) => {
+                let index = transcript.push(&self.argv, self.read_only);
+                (!self.is_dry_run()).then(|| (transcript.clone(), index))
+            }
+            None => None,
+        };
+        if self.is_dry_run() {
+            debug!("Not running (dry run): {}", shell_line(&self.argv));
+            if !self.set_stdin {
+                // Don't let the stand-in read from the terminal.
+                self.inner.stdin(Stdio::null());
+            }
+        } else {
+            debug!("Running: {}", shell_line(&self.argv));
+        }
         let child = self.inner.spawn().with_context(|| {
             format!(
                 "failed to spawn {:?} process ($PATH is {:?})",
-                self.inner.get_program(),
+                self.argv[0],
                 match std::env::var_os("PATH") {
                     Some(path) => path,
                     None => OsString::from("not set"),
                 }
             )
         })?;
-        Ok(ScopedChild::new(child, self.inner.get_program()))
+        Ok(ScopedChild::new(child, &self.argv[0], entry))
     }
 
     pub fn output(&mut self) -> Result<Output> {
@@ -107,6 +333,47 @@ impl Command {
         child.wait()
     }
 
+    /// Runs the command with stdin closed, passing its stdout and stderr to
+    /// `on_output` as they arrive, and returns its exit status.
+    ///
+    /// `on_output` is called on the current thread.
+    pub fn stream(&mut self, on_output: &mut dyn FnMut(OutputStream, &[u8])) -> Result<ExitStatus> {
+        self.inner.stdin(Stdio::null());
+        self.inner.stdout(Stdio::piped());
+        self.inner.stderr(Stdio::piped());
+        let mut child = self.scoped_spawn()?;
+        let stdout = child.stdout().take().unwrap();
+        let stderr = child.stderr().take().unwrap();
+
+        let (sender, receiver) = mpsc::channel();
+        let read_error = std::thread::scope(|scope| {
+            let stdout_sender = sender.clone();
+            scope.spawn(move || forward_output(OutputStream::Stdout, stdout, stdout_sender));
+            scope.spawn(move || forward_output(OutputStream::Stderr, stderr, sender));
+            // Keep draining both pipes after an error, so that the child
+            // can't block writing to them.
+            let mut read_error = None;
+            for (stream, chunk) in receiver {
+                match chunk {
+                    Ok(chunk) => on_output(stream, &chunk),
+                    Err(e) => read_error = read_error.or(Some((stream, e))),
+                }
+            }
+            read_error
+        });
+
+        let status = child.wait()?;
+        match read_error {
+            Some((stream, e)) => Err(e).with_context(|| {
+                format!(
+                    "failed to read {stream} of child process {:?}",
+                    self.argv[0]
+                )
+            }),
+            None => Ok(status),
+        }
+    }
+
     pub fn stdin<T: Into<Stdio>>(&mut self, cfg: T) -> &mut Self {
         self.set_stdin = true;
         self.inner.stdin(cfg);
@@ -126,7 +393,10 @@ impl Command {
     }
 
     pub fn arg<S: AsRef<OsStr>>(&mut self, arg: S) -> &mut Self {
-        self.inner.arg(arg);
+        self.argv.push(arg.as_ref().to_owned());
+        if !self.is_dry_run() {
+            self.inner.arg(arg);
+        }
         self
     }
 
@@ -135,7 +405,9 @@ impl Command {
         I: IntoIterator<Item = S>,
         S: AsRef<OsStr>,
     {
-        self.inner.args(args);
+        for arg in args {
+            self.arg(arg);
+        }
         self
     }
 
@@ -153,3 +425,72 @@ impl Command {
         self
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn transcript() {
+        let transcript = Transcript::new();
+        {
+            let _recording = transcript.record(DryRun(false));
+            let output = Command::new("sh").args(["-c", "exit 3"]).output().unwrap();
+            assert_eq!(output.status.code(), Some(3));
+            {
+                let _recording = Transcript::new().record(DryRun(true));
+                Command::new("false").status().unwrap();
+            }
+            let output = Command::new("echo").arg("hi there").output().unwrap();
+            assert_eq!(output.stdout, b"hi there\n");
+        }
+        Command::new("true").status().unwrap();
+        assert_eq!(
+            transcript
+                .entries()
+                .iter()
+                .map(|entry| format!("{entry} => {:?}", entry.exit_code))
+                .collect::<Vec<_>>(),
+            ["sh -c 'exit 3' => Some(3)", "echo 'hi there' => Some(0)"]
+        );
+
+        let transcript = Transcript::new();
+        let _recording = transcript.record(DryRun(true));
+        let output = Command::new("sh").args(["-c", "exit 3"]).output().unwrap();
+        assert!(output.status.success());
+        assert!(output.stdout.is_empty());
+        let output = Command::query("echo").arg("hi").output().unwrap();
+        assert_eq!(output.stdout, b"hi\n");
+        assert_eq!(
+            transcript.entries(),
+            [
+                TranscriptEntry {
+                    argv: ["sh", "-c", "exit 3"].map(String::from).to_vec(),
+                    exit_code: None,
+                    read_only: false,
+                },
+                TranscriptEntry {
+                    argv: ["echo", "hi"].map(String::from).to_vec(),
+                    exit_code: Some(0),
+                    read_only: true,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn stream() {
+        let mut stdout = Vec::new();
+        let mut stderr = Vec::new();
+        let status = Command::new("sh")
+            .args(["-c", "echo out; echo err >&2; echo more; exit 2"])
+            .stream(&mut |stream, chunk| match stream {
+                OutputStream::Stdout => stdout.extend_from_slice(chunk),
+                OutputStream::Stderr => stderr.extend_from_slice(chunk),
+            })
+            .unwrap();
+        assert_eq!(status.code(), Some(2));
+        assert_eq!(stdout, b"out\nmore\n");
+        assert_eq!(stderr, b"err\n");
+    }
+}
@@ -2,22 +2,41 @@
 
 use regex::{Regex, RegexBuilder};
 use serde::{Deserialize, Deserializer};
-use std::path::{Path, PathBuf};
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::{Component, Path, PathBuf};
 use std::str::FromStr;
 use std::sync::OnceLock;
 use std::time::Duration;
+use wildmatch::WildMatch;
 
 use super::os_util::host_home_dir;
-use super::HostPath;
-use super::RunnerKind;
-use crate::somehow::{somehow as anyhow, Context, LowLevelResult, Result};
+use super::{Distro, EnvironmentName, HostPath, Platform, RunnerKind, WorkspaceName};
+use crate::somehow::{somehow as anyhow, warn_brief, Context, LowLevelResult, Result};
 
 /// Main Cubicle program configuration, normally read from a `cubicle.toml`
 /// file.
 #[derive(Debug, Deserialize, Eq, PartialEq)]
 #[serde(deny_unknown_fields)]
 pub struct Config {
-    /// Which runner to use.
+    /// Which runner to use, like `"docker"`, or a list of runners to try in
+    /// order, like `["bubblewrap", "docker", "user"]`.
+    ///
+    /// A list lets one config work across different machines. Cubicle uses
+    /// the first runner in the list whose prerequisites are available on the
+    /// host, such as the `bwrap` program or a running Docker daemon. The
+    /// settings for each listed runner must still be valid.
+    ///
+    /// Cubicle records which runner created each environment and warns when
+    /// entering it with a different one.
+    #[serde(rename = "runner", deserialize_with = "deserialize_runner_choices")]
+    pub runner_choices: Vec<RunnerKind>,
+
+    /// The runner in use. This is set to the first of
+    /// [`Config::runner_choices`] when reading the config. When Cubicle
+    /// starts, it's set again from [`Config::runner_choices`], even for a
+    /// config that was deserialized directly: to the only choice, or to the
+    /// first available one.
+    #[serde(skip_deserializing, default = "placeholder_runner")]
     pub runner: RunnerKind,
 
     /// Packages will be re-built when accessed if they haven't been built for
@@ -39,8 +58,557 @@ pub struct Config {
     #[serde(default, deserialize_with = "deserialize_opt_path")]
     pub builtin_package_dir: Option<PathBuf>,
 
-    /// Configuration specific to the Bubblewrap runner. Set to `None` for
-    /// other runners.
+    /// The order in which to search package sources, where a package found
+    /// in an earlier source hides any of the same name in later ones.
+    ///
+    /// Each entry is `"built-in"`, the name of a directory of local
+    /// packages, like `"00local"`, or the name of one of the
+    /// [`Config::package_repos`]. Sources that aren't listed come after the
+    /// listed ones, in the default order: local package directories sorted
+    /// by name, then package repositories sorted by name, then the built-in
+    /// packages. `cub package which` shows which
+    /// source a package comes from.
+    ///
+    /// Default: empty.
+    #[serde(default)]
+    pub package_precedence: Vec<String>,
+
+    /// Remote package sources, keyed by name, like `package_repos = { team =
+    /// { git = "https://example.com/packages.git" } }`.
+    ///
+    /// Cubicle keeps a copy of each one in its cache directory, fetching it
+    /// on first use and updating it as often as [`Config::auto_update`]
+    /// allows. The name identifies the source as the origin of its packages
+    /// in `cub package list` and in [`Config::package_precedence`], so it
+    /// must differ from the names of the local package directories.
+    ///
+    /// Default: none.
+    #[serde(default)]
+    pub package_repos: BTreeMap<String, PackageRepo>,
+
+    /// A remote cache of package builds shared between machines, in a
+    /// `[remote_cache]` section. Cubicle downloads a package build from it
+    /// instead of building the package when the package's sources and
+    /// dependencies match.
+    ///
+    /// Default: no remote cache.
+    #[serde(default)]
+    pub remote_cache: Option<RemoteCache>,
+
+    /// If true, packages are built with networking disabled, unless their
+    /// manifest sets `network = true`. This checks that package builds don't
+    /// download anything. A package can also opt into this on its own with
+    /// `network = false` in its manifest.
+    ///
+    /// The Docker runner creates these package builder containers with
+    /// `--network none`, and the Bubblewrap runner passes `--unshare-net`.
+    /// The user runner can't disable networking, so it fails these builds.
+    ///
+    /// Default: false.
+    #[serde(default)]
+    pub offline_package_builds: bool,
+
+    /// Enables packages from Nix, in a `[nix]` section. Cubicle then builds
+    /// packages like `nix.ripgrep` with `nix build` on the host, which must
+    /// have Nix installed, and the environments use the builds from the
+    /// host's `/nix/store`. The Bubblewrap and Docker runners mount that
+    /// read-only into every environment.
+    ///
+    /// Default: Nix packages are disabled.
+    #[serde(default)]
+    pub nix: Option<Nix>,
+
+    /// How the Bubblewrap runner and the Docker runner with `bind_mounts`
+    /// put package builds into environments' home directories: `"extract"`
+    /// extracts each package's tar file into each home directory, while
+    /// `"reflink"` and `"hardlink"` extract it once into a store in the cache
+    /// directory and copy it from there with reflinks or hard links. See
+    /// [`SeedCopy`].
+    ///
+    /// Default: `"extract"`.
+    #[serde(default)]
+    pub seed_copy: SeedCopy,
+
+    /// Names of environments that should have access to the host's GPUs.
+    /// These may contain `*` and `?` wildcards, so `["*"]` selects all
+    /// environments.
+    ///
+    /// The Docker runner passes `--gpus all` to these containers, which
+    /// requires the NVIDIA Container Toolkit on the host. The Bubblewrap runner
+    /// binds `/dev/dri` and `/dev/nvidia*` into these environments. The user
+    /// runner ignores this setting.
+    ///
+    /// Default: no environments.
+    #[serde(default)]
+    pub gpu: Vec<String>,
+
+    /// Names of environments that should have access to the host's audio
+    /// server. These may contain `*` and `?` wildcards, like [`Config::gpu`].
+    ///
+    /// The Docker and Bubblewrap runners share the host's PulseAudio and/or
+    /// PipeWire sockets (from `$XDG_RUNTIME_DIR`) with these environments and
+    /// set `PULSE_SERVER` and `PIPEWIRE_REMOTE` to point to them. The user
+    /// runner ignores this setting.
+    ///
+    /// Default: no environments.
+    #[serde(default)]
+    pub audio: Vec<String>,
+
+    /// Names of environments that should be able to use the host's
+    /// clipboard. These may contain `*` and `?` wildcards, like
+    /// [`Config::gpu`].
+    ///
+    /// The Docker and Bubblewrap runners share a directory with these
+    /// environments at `/run/cubicle-clipboard`, which the `cub-copy` and
+    /// `cub-paste` commands from the `clipboard` package use. Running `cub
+    /// clipboard` on the host connects these to the host's clipboard. The
+    /// user runner ignores this setting.
+    ///
+    /// Default: no environments.
+    #[serde(default)]
+    pub clipboard: Vec<String>,
+
+    /// Commands that environments may run on the host, keyed by environment
+    /// name. The keys may contain `*` and `?` wildcards, like
+    /// [`Config::gpu`], and an environment may use the commands from every
+    /// key that matches its name. Each command must be a program name or
+    /// path, like `"xdg-open"`; its arguments are not restricted.
+    ///
+    /// The Docker and Bubblewrap runners share a directory with these
+    /// environments at `/run/cubicle-host`, where `cub host-commands` listens
+    /// on a Unix socket on the host. The `cub-host` command from the
+    /// `host-commands` package sends requests to it. The user runner ignores
+    /// this setting.
+    ///
+    /// Default: no commands for any environment.
+    #[serde(default)]
+    pub host_commands: BTreeMap<String, Vec<String>>,
+
+    /// Host device files that environments may use, keyed by environment
+    /// name, like `devices = { vm = ["/dev/kvm", "/dev/net/tun"] }`. The keys
+    /// may contain `*` and `?` wildcards, like [`Config::gpu`], and an
+    /// environment gets the devices from every key that matches its name.
+    /// The last component of each device path may also contain wildcards,
+    /// like `"/dev/ttyUSB*"`.
+    ///
+    /// The Docker runner passes these to `docker run --device` and adds the
+    /// user to the devices' groups inside the container. The Bubblewrap runner
+    /// binds them into the sandbox with `--dev-bind`. Devices that don't exist
+    /// when the environment starts are skipped with a warning. The user runner
+    /// ignores this setting.
+    ///
+    /// Default: no devices for any environment.
+    #[serde(default)]
+    pub devices: BTreeMap<String, Vec<String>>,
+
+    /// How environments may run containers of their own, keyed by
+    /// environment name. The keys may contain `*` and `?` wildcards, like
+    /// [`Config::gpu`]; if several match, the first in sorted order applies.
+    ///
+    /// See [`NestedContainers`] for the choices. The user runner ignores this
+    /// setting.
+    ///
+    /// Default: no nested containers for any environment.
+    #[serde(default)]
+    pub nested_containers: BTreeMap<String, NestedContainers>,
+
+    /// Names of the work directories inside specific environments, keyed by
+    /// environment name. Each must be a single path component, like `"work"`
+    /// or the name of a project.
+    ///
+    /// Changing this for an existing environment does not move its work
+    /// directory; reset or re-create the environment after changing it.
+    ///
+    /// Default: `w` (so the work directory is at `~/w`) for every
+    /// environment.
+    #[serde(default)]
+    pub work_dirs: BTreeMap<String, String>,
+
+    /// Maximum number of CPUs that each environment may use.
+    ///
+    /// The Docker runner passes this as `--cpus`. The Bubblewrap and user
+    /// runners start environments in a transient systemd scope with a
+    /// `CPUQuota`, which requires a systemd user session on the host.
+    ///
+    /// Default: no limit.
+    #[serde(default)]
+    pub cpus: Option<u32>,
+
+    /// Maximum amount of memory that each environment may use, like `"4 GiB"`
+    /// or `"512M"`. Units are powers of 1024.
+    ///
+    /// The Docker runner passes this as `--memory`. The Bubblewrap and user
+    /// runners set `MemoryMax` on a transient systemd scope, like with
+    /// [`Config::cpus`].
+    ///
+    /// Default: no limit.
+    #[serde(default, deserialize_with = "deserialize_opt_bytes")]
+    pub memory: Option<u64>,
+
+    /// Maximum combined size of each environment's home and work
+    /// directories, like `"20 GiB"`. Units are powers of 1024.
+    ///
+    /// This is enforced softly, for all runners: `cub list` warns about
+    /// environments over their quota (see [`Config::hints`]), and `cub reset` refuses to copy package
+    /// files into an environment whose work directory alone exceeds it.
+    ///
+    /// Default: no limit.
+    #[serde(default, deserialize_with = "deserialize_opt_bytes")]
+    pub disk_quota: Option<u64>,
+
+    /// How long an environment may go without an `enter` or `exec` session
+    /// or any processes running in it before `cub stop-idle` stops it, like
+    /// `"8h"`. `cub stop-idle --minutes` overrides this.
+    ///
+    /// Set to `"never"` in TOML or `None` in code to disable.
+    ///
+    /// Default: 30 minutes.
+    #[serde(
+        default = "thirty_minutes",
+        deserialize_with = "deserialize_opt_duration"
+    )]
+    pub idle_timeout: Option<Duration>,
+
+    /// If true, keep environments' home and work directories and the package
+    /// cache in a subdirectory specific to this host, named after its
+    /// machine ID or hostname.
+    ///
+    /// This is useful when multiple machines share one home directory, such
+    /// as over NFS. Package definitions in the user's package directory are
+    /// still shared.
+    ///
+    /// Default: false.
+    #[serde(default)]
+    pub host_scoped_dirs: bool,
+
+    /// The name of the user running Cubicle on the host, which the Docker
+    /// runner also uses inside containers.
+    ///
+    /// This is useful in minimal containers, such as in CI, that don't set
+    /// `$USER`.
+    ///
+    /// Default: `$USER`, or the current user's name from `/etc/passwd`.
+    #[serde(default)]
+    pub host_user: Option<String>,
+
+    /// The shell to run inside environments, like `"/bin/bash"`.
+    ///
+    /// Default: `$SHELL`, or the current user's login shell from
+    /// `/etc/passwd`, or `/bin/sh`.
+    #[serde(default)]
+    pub shell: Option<String>,
+
+    /// Dotfiles to copy into the home directory of every new or reset
+    /// environment, either from a host directory (`dotfiles = { dir =
+    /// "~/dotfiles" }`) or from a git repository (`dotfiles = { git =
+    /// "https://..." }`).
+    ///
+    /// These are copied after the packages' files, so they take precedence.
+    /// A `.git` directory at the top level is not copied.
+    ///
+    /// Default: no dotfiles.
+    #[serde(default)]
+    pub dotfiles: Option<Dotfiles>,
+
+    /// If true, copy the host's git `user.name` and `user.email` into a
+    /// `.gitconfig` in every new or reset environment, so that commits work
+    /// right away. A `.gitconfig` from [`Config::dotfiles`] replaces this one.
+    ///
+    /// Default: false.
+    #[serde(default)]
+    pub git_identity: bool,
+
+    /// If true, along with [`Config::git_identity`], also copy the host's git
+    /// `user.signingkey`, `gpg.format`, and `commit.gpgsign` settings. This
+    /// does not copy the signing key itself, which must be made available in
+    /// the environment some other way (such as through an SSH agent).
+    ///
+    /// Default: false.
+    #[serde(default)]
+    pub git_signing_key: bool,
+
+    /// Shell commands to run on the host when environments are created,
+    /// entered, or purged, in a `[hooks]` section. These are useful for
+    /// registering environments with other tools.
+    ///
+    /// Default: no hooks.
+    #[serde(default)]
+    pub hooks: Hooks,
+
+    /// Settings for the advice that Cubicle prints, in a `[hints]` section.
+    ///
+    /// Default: all hints enabled.
+    #[serde(default)]
+    pub hints: Hints,
+
+    /// Settings shared by the environments in each workspace, keyed by
+    /// workspace name, in `[workspaces.NAME]` sections. An environment named
+    /// `proj/frontend` belongs to the workspace `proj`, which must be created
+    /// first with `cub workspace create proj`.
+    ///
+    /// Changes to these settings take effect the next time each environment
+    /// starts.
+    ///
+    /// Default: no settings for any workspace.
+    #[serde(default)]
+    pub workspaces: BTreeMap<String, Workspace>,
+
+    /// Configuration specific to each runner, in `[runners.docker]` and
+    /// similar sections. Only the section for the selected runner is used.
+    ///
+    /// The settings above apply to all runners.
+    #[serde(default)]
+    pub runners: Runners,
+}
+
+/// How package builds get into environments' home directories. See
+/// [`Config::seed_copy`].
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum SeedCopy {
+    /// Extract the package's tar file into the home directory.
+    #[default]
+    Extract,
+
+    /// Copy the package's files from the store with `cp --reflink=always`.
+    /// This is only as fast as extracting on filesystems without reflinks
+    /// (copy-on-write copies), like ext4, where it fails and falls back to
+    /// extracting. Btrfs and XFS support reflinks.
+    Reflink,
+
+    /// Hard-link the package's read-only files from the store with `cp
+    /// --link`, and copy its writable files, so that environments can change
+    /// those. The store and the home directories must be on the same
+    /// filesystem.
+    ///
+    /// Every environment shares the read-only files. Programs that replace
+    /// files, as most package managers do, still work, but a program that
+    /// makes one of them writable and changes it in place changes it for
+    /// every environment.
+    Hardlink,
+}
+
+/// How an environment may run containers of its own. See
+/// [`Config::nested_containers`].
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum NestedContainers {
+    /// Share the host's Docker socket (from `$DOCKER_HOST`,
+    /// `/var/run/docker.sock`, or rootless Podman's socket) at
+    /// `/run/cubicle-docker.sock` and point `$DOCKER_HOST` at it.
+    ///
+    /// Anything that can use the host's Docker daemon can take over the host,
+    /// so this gives up most of the environment's isolation. Cubicle warns
+    /// when creating such an environment.
+    HostSocket,
+
+    /// Set up the environment so that rootless Podman can run inside it.
+    ///
+    /// The Docker runner passes `/dev/fuse` into these containers and relaxes
+    /// the seccomp, AppArmor, and SELinux confinement that would block user
+    /// namespaces and mounts, unless those are configured explicitly. The
+    /// Bubblewrap runner passes `/dev/fuse`, mounts `/sys` read-only, and maps
+    /// the host user's subordinate IDs, as with `subids` in
+    /// `[runners.bubblewrap]`. The environment still needs `podman`,
+    /// `fuse-overlayfs`, and `uidmap` installed, such as through a package.
+    Podman,
+}
+
+/// Where to fetch a package source from. See [`Config::package_repos`].
+///
+/// Either way, the package definitions are the subdirectories at the top
+/// level.
+#[derive(Debug, Deserialize, Eq, PartialEq)]
+#[serde(deny_unknown_fields, rename_all = "lowercase")]
+pub enum PackageRepo {
+    /// The URL of a git repository.
+    Git(String),
+
+    /// The URL of a tar file, which may be compressed. If it holds a single
+    /// directory, like the archives that code hosting sites provide, the
+    /// packages are taken from within that directory.
+    Tar(String),
+}
+
+/// Settings for packages from Nix. See [`Config::nix`].
+#[derive(Debug, Deserialize, Eq, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Nix {
+    /// The flake to build packages from. `nix.ATTR` is built as
+    /// `FLAKE#ATTR`. Pinning a revision, like
+    /// `"github:NixOS/nixpkgs/nixos-24.05"`, makes the builds reproducible.
+    ///
+    /// Default: `"nixpkgs"`, from the host's flake registry.
+    #[serde(default = "nixpkgs")]
+    pub flake: String,
+}
+
+/// Where to find and store shared package builds. See
+/// [`Config::remote_cache`].
+#[derive(Debug, Deserialize, Eq, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct RemoteCache {
+    /// The base URL of the cache, like `"https://cache.example.com/cubicle"`.
+    ///
+    /// Package builds are read with `GET` and written with `PUT` at
+    /// `URL/ARCH/KEY.tar`, where `KEY` is a hash of the package's name,
+    /// source files, and dependencies. The URL may include a username and
+    /// password for HTTP basic authentication.
+    pub url: String,
+
+    /// If true, upload every package that this machine builds to the cache.
+    ///
+    /// Default: false.
+    #[serde(default)]
+    pub upload: bool,
+
+    /// For S3-compatible object storage, the region to sign requests for,
+    /// like `"us-east-1"`. The credentials come from the
+    /// `AWS_ACCESS_KEY_ID`, `AWS_SECRET_ACCESS_KEY`, and optionally
+    /// `AWS_SESSION_TOKEN` environment variables. The URL must then use
+    /// path-style addressing, like
+    /// `"https://s3.us-east-1.amazonaws.com/BUCKET/PREFIX"`.
+    ///
+    /// Default: requests are not signed.
+    #[serde(default)]
+    pub s3_region: Option<String>,
+
+    /// An SSH private key to sign uploaded package builds with. The
+    /// signature is made with `ssh-keygen -Y sign` in the `cubicle-package`
+    /// namespace and uploaded next to the build, at `URL/ARCH/KEY.tar.sig`.
+    ///
+    /// Default: uploads are not signed.
+    #[serde(default, deserialize_with = "deserialize_opt_path")]
+    pub signing_key: Option<PathBuf>,
+
+    /// An `ssh-keygen` allowed signers file (see `ssh-keygen(1)`). If set,
+    /// a downloaded package build is only used if it has a signature from
+    /// one of these keys, checked with `ssh-keygen -Y verify`. Otherwise,
+    /// the package is built locally.
+    ///
+    /// Default: downloaded builds are not checked for signatures.
+    #[serde(default, deserialize_with = "deserialize_opt_path")]
+    pub allowed_signers: Option<PathBuf>,
+}
+
+/// Where to find dotfiles. See [`Config::dotfiles`].
+#[derive(Debug, Deserialize, Eq, PartialEq)]
+#[serde(deny_unknown_fields, rename_all = "lowercase")]
+pub enum Dotfiles {
+    /// A directory on the host.
+    Dir(#[serde(deserialize_with = "deserialize_path")] PathBuf),
+
+    /// The URL of a git repository. Cubicle keeps a clone in its cache
+    /// directory and pulls new commits before each use.
+    Git(String),
+}
+
+/// Shell commands to run on the host at points in an environment's
+/// lifecycle. See [`Config::hooks`].
+///
+/// Each command runs with `sh -c` in Cubicle's working directory, with
+/// `$CUBICLE` set to the environment's name and `$CUBICLE_HOOK` set to the
+/// hook's name. If a `pre_` hook exits with an error, Cubicle aborts the
+/// operation.
+///
+/// The `pre_create` and `pre_purge` hooks run while Cubicle holds the
+/// environment's lock. They may run `cub enter` or `cub exec` on the
+/// environment, but `cub` commands that change it would wait forever.
+#[derive(Debug, Default, Deserialize, Eq, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Hooks {
+    /// Runs before an environment is created with `cub new` or `cub tmp`.
+    #[serde(default)]
+    pub pre_create: Option<String>,
+
+    /// Runs after an environment is created successfully. If this fails,
+    /// Cubicle warns but keeps the environment.
+    #[serde(default)]
+    pub post_create: Option<String>,
+
+    /// Runs before each interactive session in an environment, from `cub
+    /// enter` or `cub tmp`.
+    #[serde(default)]
+    pub pre_enter: Option<String>,
+
+    /// Runs before an existing environment is purged.
+    #[serde(default)]
+    pub pre_purge: Option<String>,
+}
+
+/// Settings for Cubicle's hints. See [`Config::hints`].
+///
+/// Hints are printed at most once a day each, even when enabled.
+#[derive(Debug, Default, Deserialize, Eq, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Hints {
+    /// The names of hints never to print, like `["disk-quota"]`.
+    ///
+    /// The hints are:
+    /// - `disk-quota`: an environment is over [`Config::disk_quota`].
+    /// - `git-identity-missing`: [`Config::git_identity`] is set, but the
+    ///   host's git config has no identity to copy.
+    /// - `host-docker-socket`: a new environment will have access to the
+    ///   host's Docker socket, from [`Config::nested_containers`].
+    /// - `legacy-home-dirs`: environments' home directories are still in
+    ///   the cache directory, where an older version kept them, because
+    ///   Cubicle couldn't move them. See `cub doctor`.
+    /// - `shadowed-packages`: a new or reset environment gets a package that
+    ///   hides another package of the same name, like a local package named
+    ///   after a built-in one.
+    #[serde(default)]
+    pub disable: Vec<String>,
+}
+
+/// Settings for the environments in a workspace. See [`Config::workspaces`].
+#[derive(Debug, Default, Deserialize, Eq, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Workspace {
+    /// Whether to connect the workspace's environments to a network of their
+    /// own, where they can reach each other by the part of their names after
+    /// the slash, like `backend`.
+    ///
+    /// The Docker runner creates a network for this when an environment in
+    /// the workspace starts and removes it when the workspace is purged. The
+    /// Bubblewrap and user runners ignore this setting, since their
+    /// environments already share the host's network.
+    #[serde(default)]
+    pub network: bool,
+
+    /// Environment variables to set in the workspace's environments, like
+    /// `env = { DATABASE_URL = "postgres://backend/app" }`.
+    #[serde(default)]
+    pub env: BTreeMap<String, String>,
+
+    /// Host directories to mount into the workspace's environments.
+    ///
+    /// The user runner ignores this setting.
+    #[serde(default)]
+    pub mounts: Vec<Mount>,
+}
+
+/// A host directory to mount into environments. See [`Workspace::mounts`].
+#[derive(Debug, Deserialize, Eq, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Mount {
+    /// The path on the host. This may start with `~/`.
+    #[serde(deserialize_with = "deserialize_path")]
+    pub source: PathBuf,
+
+    /// The absolute path inside the environment.
+    pub target: PathBuf,
+
+    /// Whether the environment may only read the files.
+    #[serde(default)]
+    pub read_only: bool,
+}
+
+/// Per-runner configuration sections.
+#[derive(Debug, Default, Deserialize, Eq, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Runners {
+    /// Configuration specific to the Bubblewrap runner. This is required when
+    /// using that runner. Set to `None` for other runners.
     #[serde(default)]
     pub bubblewrap: Option<Bubblewrap>,
 
@@ -48,8 +616,18 @@ pub struct Config {
     /// for other runners.
     #[serde(default)]
     pub docker: Docker,
+
+    /// Configuration specific to the system user account runner. Set to
+    /// `User::default()` for other runners.
+    #[serde(default)]
+    pub user: User,
 }
 
+/// Sections that used to be at the top level of the config file, before
+/// [`Config::runners`] existed. These are still accepted and moved into
+/// `runners` when the config is read.
+const LEGACY_RUNNER_SECTIONS: [&str; 2] = ["bubblewrap", "docker"];
+
 /// Configuration specific to the Bubblewrap runner.
 ///
 /// See the [Configuration](#configuration) section below for details.
@@ -60,12 +638,56 @@ pub struct Config {
 #[allow(missing_docs)]
 pub struct Bubblewrap {
     pub seccomp: PathOrDisabled,
+
+    #[serde(default)]
+    pub seccomps: BTreeMap<String, PathOrDisabled>,
+
+    #[serde(default)]
+    pub uid: Option<u64>,
+
+    #[serde(default)]
+    pub gid: Option<u64>,
+
+    #[serde(default)]
+    pub subids: bool,
+
+    #[serde(default)]
+    pub incremental_reset: bool,
+
+    #[serde(default)]
+    pub overlay_home: bool,
+}
+
+impl Bubblewrap {
+    /// Returns the seccomp filter for the environment, according to
+    /// [`Bubblewrap::seccomps`] and [`Bubblewrap::seccomp`].
+    pub fn seccomp_for(&self, name: &EnvironmentName) -> &PathOrDisabled {
+        self.seccomps.get(name.as_str()).unwrap_or(&self.seccomp)
+    }
 }
 
+/// Configuration specific to the system user account runner.
+///
+/// See the [Configuration](#configuration) section below for details.
+/// This documentation is included from `docs/User.md`.
+#[doc = include_str!("../docs/User.md")]
+#[derive(Debug, Default, Deserialize, Eq, PartialEq)]
+#[serde(deny_unknown_fields)]
+#[allow(missing_docs)]
+pub struct User {}
+
+/// Name of the work directory inside environments' home directories, unless
+/// overridden in [`Config::work_dirs`].
+pub const DEFAULT_WORK_DIR: &str = "w";
+
 fn twelve_hours() -> Option<Duration> {
     Some(Duration::from_secs(60 * 60 * 12))
 }
 
+fn thirty_minutes() -> Option<Duration> {
+    Some(Duration::from_secs(60 * 30))
+}
+
 fn deserialize_opt_duration<'de, D>(deserializer: D) -> Result<Option<Duration>, D::Error>
 where
     D: Deserializer<'de>,
@@ -76,7 +698,38 @@ where
     if s == "never" {
         return Ok(None);
     }
+    match parse_duration(&s) {
+        Some(duration) => Ok(Some(duration)),
+        None => Err(D::Error::custom(format!(
+            "could not parse {s:?}, expected `never` or duration like \
+            `10s`, `1.5m`, `2 hours`, `1 day`"
+        ))),
+    }
+}
+
+/// Like [`deserialize_opt_duration`], but `"forever"` means `None`, for
+/// [`Docker::keep_alive`].
+fn deserialize_keep_alive<'de, D>(deserializer: D) -> Result<Option<Duration>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    use serde::de::Error;
+
+    let s = String::deserialize(deserializer)?;
+    if s == "forever" {
+        return Ok(None);
+    }
+    match parse_duration(&s) {
+        Some(duration) => Ok(Some(duration)),
+        None => Err(D::Error::custom(format!(
+            "could not parse {s:?}, expected `forever` or duration like \
+            `10s`, `1.5m`, `2 hours`, `1 day`"
+        ))),
+    }
+}
 
+/// Parses a duration like `10s`, `1.5m`, `2 hours`, or `1 day`.
+fn parse_duration(s: &str) -> Option<Duration> {
     static RE: OnceLock<Regex> = OnceLock::new();
     let re = RE.get_or_init(|| {
         RegexBuilder::new(
@@ -101,28 +754,136 @@ where
         .unwrap()
     });
 
-    match re.captures(&s) {
-        Some(caps) => {
-            let value = caps.name("value").unwrap().as_str();
-            let value = f64::from_str(value).unwrap();
-            let unit = caps.name("unit").unwrap().as_str();
-            let multiple = f64::from(match unit.chars().next().unwrap() {
-                's' => 1,
-                'm' => 60,
-                'h' => 60 * 60,
-                'd' => 60 * 60 * 24,
-                _ => unreachable!(),
-            });
-            Ok(Some(Duration::from_secs_f64(value * multiple)))
+    let caps = re.captures(s)?;
+    let value = caps.name("value").unwrap().as_str();
+    let value = f64::from_str(value).unwrap();
+    let unit = caps.name("unit").unwrap().as_str();
+    let multiple = f64::from(match unit.chars().next().unwrap() {
+        's' => 1,
+        'm' => 60,
+        'h' => 60 * 60,
+        'd' => 60 * 60 * 24,
+        _ => unreachable!(),
+    });
+    Some(Duration::from_secs_f64(value * multiple))
+}
+
+fn deserialize_runner_choices<'de, D>(deserializer: D) -> Result<Vec<RunnerKind>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    use serde::de::Error;
+
+    struct Visitor;
+
+    impl<'de> serde::de::Visitor<'de> for Visitor {
+        type Value = Vec<RunnerKind>;
+
+        fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+            f.write_str("a runner name or a list of runner names")
+        }
+
+        fn visit_str<E: Error>(self, v: &str) -> Result<Self::Value, E> {
+            RunnerKind::deserialize(serde::de::value::StrDeserializer::new(v))
+                .map(|runner| vec![runner])
         }
 
+        fn visit_seq<A: serde::de::SeqAccess<'de>>(self, seq: A) -> Result<Self::Value, A::Error> {
+            Vec::deserialize(serde::de::value::SeqAccessDeserializer::new(seq))
+        }
+    }
+
+    let choices = deserializer.deserialize_any(Visitor)?;
+    if choices.is_empty() {
+        return Err(D::Error::custom("the list of runners must not be empty"));
+    }
+    Ok(choices)
+}
+
+/// Fills in [`Config::runner`] until [`Config::from_str`] sets it.
+fn placeholder_runner() -> RunnerKind {
+    RunnerKind::Docker
+}
+
+fn deserialize_opt_bytes<'de, D>(deserializer: D) -> Result<Option<u64>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    use serde::de::Error;
+
+    let s = String::deserialize(deserializer)?;
+
+    static RE: OnceLock<Regex> = OnceLock::new();
+    let re = RE.get_or_init(|| {
+        RegexBuilder::new(
+            r#"^(?x)
+            # integer
+            (?P<value> [0-9]+ )
+            # optional space
+            \ ?
+            # optional unit
+            (?P<unit> [KMGT] )?
+            ( i?B )?
+            $"#,
+        )
+        .case_insensitive(true)
+        .build()
+        .unwrap()
+    });
+
+    let parsed = re.captures(&s).and_then(|caps| {
+        let value = u64::from_str(caps.name("value").unwrap().as_str()).ok()?;
+        let shift = match caps
+            .name("unit")
+            .map(|unit| unit.as_str().to_ascii_uppercase())
+        {
+            None => 0,
+            Some(unit) => match unit.as_str() {
+                "K" => 10,
+                "M" => 20,
+                "G" => 30,
+                "T" => 40,
+                _ => unreachable!(),
+            },
+        };
+        value.checked_mul(1 << shift)
+    });
+
+    match parsed {
+        Some(bytes) => Ok(Some(bytes)),
         None => Err(D::Error::custom(format!(
-            "could not parse {s:?}, expected `never` or duration like \
-            `10s`, `1.5m`, `2 hours`, `1 day`"
+            "could not parse {s:?}, expected size like `512M`, `4 GiB`, `1T`"
         ))),
     }
 }
 
+/// Moves runner sections from the top level of the config file into
+/// `runners`, with a warning.
+fn migrate_runner_sections(table: &mut toml::Table) -> Result<()> {
+    for key in LEGACY_RUNNER_SECTIONS {
+        let Some(section) = table.remove(key) else {
+            continue;
+        };
+        let runners = table
+            .entry("runners")
+            .or_insert_with(|| toml::Value::Table(toml::Table::new()))
+            .as_table_mut()
+            .ok_or_else(|| anyhow!("`runners` must be a table"))?;
+        if runners.contains_key(key) {
+            return Err(anyhow!(
+                "found both `[{key}]` and `[runners.{key}]`: \
+                please merge them into `[runners.{key}]`"
+            ));
+        }
+        warn_brief(format!(
+            "the `[{key}]` section in the config file is deprecated: \
+            please rename it to `[runners.{key}]`"
+        ));
+        runners.insert(String::from(key), section);
+    }
+    Ok(())
+}
+
 /// Like an `Option<PathBuf>` but more opinionated about recommending a path be
 /// set.
 #[derive(Debug, Deserialize, Eq, PartialEq)]
@@ -154,38 +915,166 @@ impl std::convert::From<String> for PathOrDisabled {
 #[serde(deny_unknown_fields)]
 #[allow(missing_docs)]
 pub struct Docker {
+    #[serde(default)]
+    pub apparmor: Option<String>,
+
     #[serde(default)]
     pub bind_mounts: bool,
 
     #[serde(default, deserialize_with = "deserialize_opt_path")]
     pub seccomp: Option<PathBuf>,
 
+    #[serde(default, deserialize_with = "deserialize_path_map")]
+    pub seccomps: BTreeMap<String, PathBuf>,
+
     #[serde(default)]
     pub strict_debian_packages: bool,
 
+    #[serde(default)]
+    pub isolate_package_builds: bool,
+
+    #[serde(default, deserialize_with = "deserialize_keep_alive")]
+    pub keep_alive: Option<Duration>,
+
+    #[serde(default)]
+    pub lifetime: ContainerLifetime,
+
     #[serde(default = "cub_dash")]
     pub prefix: String,
 
     #[serde(default)]
     pub locales: Vec<String>,
+
+    #[serde(default = "debian_12")]
+    pub image: String,
+
+    #[serde(default)]
+    pub images: BTreeMap<String, String>,
+
+    #[serde(default)]
+    pub distros: BTreeMap<String, Distro>,
+
+    #[serde(default, deserialize_with = "deserialize_opt_path")]
+    pub dockerfile_append: Option<PathBuf>,
+
+    #[serde(default)]
+    pub platforms: BTreeMap<String, String>,
+
+    #[serde(default)]
+    pub selinux_labels: Vec<String>,
+
+    #[serde(default)]
+    pub uid: Option<u64>,
+
+    #[serde(default)]
+    pub gid: Option<u64>,
+
+    #[serde(default)]
+    pub userns: Option<String>,
+}
+
+impl Docker {
+    /// Returns the seccomp filter for the environment, according to
+    /// [`Docker::seccomps`] and [`Docker::seccomp`].
+    pub fn seccomp_for(&self, name: &EnvironmentName) -> Option<&Path> {
+        self.seccomps
+            .get(name.as_str())
+            .or(self.seccomp.as_ref())
+            .map(PathBuf::as_path)
+    }
+
+    /// Returns the Docker image that the environment's base image should be
+    /// built from, according to [`Docker::images`] and [`Docker::image`].
+    pub fn image_for(&self, name: &EnvironmentName) -> &str {
+        self.images
+            .get(name.as_str())
+            .map(String::as_str)
+            .unwrap_or(&self.image)
+    }
+
+    /// Returns the distribution of the environment's image, according to
+    /// [`Docker::distros`] or else guessed from the image name.
+    pub fn distro_for(&self, name: &EnvironmentName) -> Distro {
+        self.distro_for_image(self.image_for(name))
+    }
+
+    /// Returns the distribution of the image, according to
+    /// [`Docker::distros`] or else guessed from the image name.
+    pub fn distro_for_image(&self, image: &str) -> Distro {
+        self.distros
+            .get(image)
+            .copied()
+            .unwrap_or_else(|| Distro::guess_from_image(image))
+    }
+}
+
+/// When the Docker runner stops environments' containers. See
+/// [`Docker::lifetime`].
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum ContainerLifetime {
+    /// Containers keep running after they're started until
+    /// [`Docker::keep_alive`] runs out, `cub stop` stops them, or the host
+    /// restarts.
+    #[default]
+    KeepAlive,
+    /// Containers stop when a `cub enter` or `cub exec` session ends and no
+    /// other processes are left running in them.
+    StopOnLastExit,
+    /// Like [`ContainerLifetime::StopOnLastExit`], but containers also stop
+    /// once an environment is created or reset, so they run only while in
+    /// use.
+    OnDemand,
 }
 
 impl Default for Docker {
     fn default() -> Self {
         Self {
+            apparmor: None,
             bind_mounts: Default::default(),
             seccomp: None,
+            seccomps: BTreeMap::new(),
             strict_debian_packages: false,
+            isolate_package_builds: false,
+            keep_alive: None,
+            lifetime: ContainerLifetime::default(),
             prefix: cub_dash(),
             locales: Vec::new(),
+            image: debian_12(),
+            images: BTreeMap::new(),
+            distros: BTreeMap::new(),
+            dockerfile_append: None,
+            platforms: BTreeMap::new(),
+            selinux_labels: Vec::new(),
+            uid: None,
+            gid: None,
+            userns: None,
         }
     }
 }
 
+fn debian_12() -> String {
+    String::from("debian:12")
+}
+
+fn nixpkgs() -> String {
+    String::from("nixpkgs")
+}
+
 fn cub_dash() -> String {
     String::from("cub-")
 }
 
+fn deserialize_path<'de, D>(deserializer: D) -> Result<PathBuf, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    Ok(tilde_expand(
+        PathBuf::deserialize(deserializer)?,
+        host_home_dir(),
+    ))
+}
+
 fn deserialize_opt_path<'de, D>(deserializer: D) -> Result<Option<PathBuf>, D::Error>
 where
     D: Deserializer<'de>,
@@ -194,6 +1083,16 @@ where
         .map(|path| tilde_expand(path, host_home_dir())))
 }
 
+fn deserialize_path_map<'de, D>(deserializer: D) -> Result<BTreeMap<String, PathBuf>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    Ok(BTreeMap::<String, PathBuf>::deserialize(deserializer)?
+        .into_iter()
+        .map(|(key, path)| (key, tilde_expand(path, host_home_dir())))
+        .collect())
+}
+
 fn tilde_expand(path: PathBuf, home: &HostPath) -> PathBuf {
     if let Ok(suffix) = path.strip_prefix("~") {
         home.as_host_raw().join(suffix)
@@ -202,28 +1101,181 @@ fn tilde_expand(path: PathBuf, home: &HostPath) -> PathBuf {
     }
 }
 
+/// Returns true if any of the patterns match the environment name.
+fn selects(patterns: &[String], name: &EnvironmentName) -> bool {
+    patterns
+        .iter()
+        .any(|pattern| WildMatch::new(pattern).matches(name.as_str()))
+}
+
 impl Config {
+    /// Returns true if the environment should have access to the host's GPUs,
+    /// according to [`Config::gpu`].
+    pub fn gpu_enabled(&self, name: &EnvironmentName) -> bool {
+        selects(&self.gpu, name)
+    }
+
+    /// Returns true if the environment should have access to the host's
+    /// audio server, according to [`Config::audio`].
+    pub fn audio_enabled(&self, name: &EnvironmentName) -> bool {
+        selects(&self.audio, name)
+    }
+
+    /// Returns true if the environment should have access to the host's
+    /// clipboard, according to [`Config::clipboard`].
+    pub fn clipboard_enabled(&self, name: &EnvironmentName) -> bool {
+        selects(&self.clipboard, name)
+    }
+
+    /// Returns the device paths that the environment may use, according to
+    /// [`Config::devices`]. These may contain wildcards.
+    pub fn devices_for(&self, name: &EnvironmentName) -> BTreeSet<String> {
+        self.devices
+            .iter()
+            .filter(|(pattern, _)| WildMatch::new(pattern).matches(name.as_str()))
+            .flat_map(|(_, devices)| devices.iter().cloned())
+            .collect()
+    }
+
+    /// Returns how the environment may run containers of its own, according
+    /// to [`Config::nested_containers`].
+    pub fn nested_containers_for(&self, name: &EnvironmentName) -> Option<NestedContainers> {
+        self.nested_containers
+            .iter()
+            .find(|(pattern, _)| WildMatch::new(pattern).matches(name.as_str()))
+            .map(|(_, mode)| *mode)
+    }
+
+    /// Returns the commands that the environment may run on the host,
+    /// according to [`Config::host_commands`].
+    pub fn host_commands_for(&self, name: &EnvironmentName) -> BTreeSet<String> {
+        self.host_commands
+            .iter()
+            .filter(|(pattern, _)| WildMatch::new(pattern).matches(name.as_str()))
+            .flat_map(|(_, commands)| commands.iter().cloned())
+            .collect()
+    }
+
+    /// Returns the name of the work directory inside the environment's home
+    /// directory, according to [`Config::work_dirs`].
+    pub fn work_dir_name(&self, name: &EnvironmentName) -> &str {
+        self.work_dirs
+            .get(name.as_str())
+            .map(String::as_str)
+            .unwrap_or(DEFAULT_WORK_DIR)
+    }
+
+    /// Returns the settings for the environment's workspace, according to
+    /// [`Config::workspaces`], if it's in a workspace that has any.
+    pub fn workspace_for(&self, name: &EnvironmentName) -> Option<&Workspace> {
+        self.workspaces.get(name.workspace()?.as_str())
+    }
+
     /// Parses and validates a TOML-formatted string into a Config.
     fn from_str(s: &str) -> LowLevelResult<Self> {
-        let config: Self = toml::from_str(s)?;
+        let mut table: toml::Table = s.parse()?;
+        let mut config: Self = if LEGACY_RUNNER_SECTIONS
+            .iter()
+            .any(|key| table.contains_key(*key))
+        {
+            migrate_runner_sections(&mut table)?;
+            toml::Value::Table(table).try_into()?
+        } else {
+            // Parsing the string again gives better error messages, with line
+            // numbers.
+            toml::from_str(s)?
+        };
 
-        match config.runner {
-            RunnerKind::Bubblewrap => {
-                if config.bubblewrap.is_none() {
+        for (env, dir) in &config.work_dirs {
+            let mut components = Path::new(dir).components();
+            if !matches!(
+                (components.next(), components.next()),
+                (Some(Component::Normal(_)), None)
+            ) || dir.contains('/')
+            {
+                return Err(anyhow!(
+                    "work directory name for environment {env:?} must be a single \
+                    path component, got {dir:?}"
+                )
+                .into());
+            }
+        }
+
+        for command in config.host_commands.values().flatten() {
+            if command.is_empty() || command.contains('\0') {
+                return Err(anyhow!("invalid host command: {command:?}").into());
+            }
+        }
+
+        for (name, workspace) in &config.workspaces {
+            WorkspaceName::from_str(name)
+                .map_err(|e| anyhow!("invalid workspace name {name:?}: {e}"))?;
+            for var in workspace.env.keys() {
+                if var.is_empty() || var.contains(['=', '\0']) {
                     return Err(anyhow!(
-                        "Bubblewrap settings are required for that runner. \
-                        See `docs/Bubblewrap.md`."
+                        "invalid environment variable name {var:?} for workspace {name:?}"
+                    )
+                    .into());
+                }
+            }
+            for mount in &workspace.mounts {
+                if !mount.target.is_absolute() {
+                    return Err(anyhow!(
+                        "mount target for workspace {name:?} must be an absolute path, \
+                        got {:?}",
+                        mount.target
                     )
                     .into());
                 }
             }
-            RunnerKind::Docker => {}
-            RunnerKind::User => {}
         }
 
+        config.runner = config.runner_choices[0];
+        for runner in &config.runner_choices {
+            config.validate_runner(*runner)?;
+        }
         Ok(config)
     }
 
+    /// Checks the settings specific to the runner.
+    fn validate_runner(&self, runner: RunnerKind) -> LowLevelResult<()> {
+        match runner {
+            RunnerKind::Bubblewrap => {
+                if self.runners.bubblewrap.is_none() {
+                    return Err(anyhow!(
+                        "Bubblewrap settings are required for that runner \
+                        (in `[runners.bubblewrap]`). See `docs/Bubblewrap.md`."
+                    )
+                    .into());
+                }
+            }
+            RunnerKind::Docker => {
+                let prefix = &self.runners.docker.prefix;
+                let mut chars = prefix.chars();
+                if !chars.next().map_or(true, |c| c.is_ascii_alphanumeric())
+                    || !chars.all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '.' | '-'))
+                {
+                    return Err(anyhow!(
+                        "Docker prefix must start with a letter or digit and contain only \
+                        letters, digits, `_`, `.`, and `-`, got {prefix:?}"
+                    )
+                    .into());
+                }
+                let docker = &self.runners.docker;
+                for image in std::iter::once(&docker.image).chain(docker.images.values()) {
+                    if image.is_empty() || image.contains(char::is_whitespace) {
+                        return Err(anyhow!("invalid Docker image name: {image:?}").into());
+                    }
+                }
+                for platform in docker.platforms.values() {
+                    Platform::from_docker(platform)?;
+                }
+            }
+            RunnerKind::User => {}
+        }
+        Ok(())
+    }
+
     /// Parses a TOML-formatted config file.
     pub fn read_from_file(path: &Path) -> Result<Self> {
         let buf = std::fs::read_to_string(path)
@@ -271,6 +1323,39 @@ mod tests {
         );
     }
 
+    #[test]
+    fn deserialize_opt_bytes() {
+        #[derive(Debug, Deserialize, PartialEq, Eq)]
+        struct Test {
+            #[serde(deserialize_with = "super::deserialize_opt_bytes")]
+            value: Option<u64>,
+        }
+
+        for (input, expected) in [
+            ("100", 100),
+            ("100B", 100),
+            ("512M", 512 << 20),
+            ("4 GiB", 4 << 30),
+            ("4gb", 4 << 30),
+            ("1T", 1 << 40),
+        ] {
+            assert_eq!(
+                toml::from_str(&format!("value = '{input}'")),
+                Ok(Test {
+                    value: Some(expected)
+                }),
+                "deserialize_opt_bytes({input:?}) (left is actual, right is expected)"
+            );
+        }
+
+        for input in ["", "4.5G", "4P", "lots"] {
+            assert!(
+                toml::from_str::<Test>(&format!("value = '{input}'")).is_err(),
+                "deserialize_opt_bytes({input:?}) should fail"
+            );
+        }
+    }
+
     #[test]
     fn tilde_expand() {
         let home = HostPath::try_from(PathBuf::from("/home/foo")).unwrap();
@@ -284,6 +1369,23 @@ mod tests {
         assert_eq!(PathBuf::from("/~/~/baz"), expand("/~/~/baz"));
     }
 
+    #[test]
+    fn config_from_str_dotfiles() {
+        let dotfiles = |toml: &str| {
+            Config::from_str(&format!("runner = 'docker'\ndotfiles = {toml}"))
+                .enough_context()
+                .map(|config| config.dotfiles)
+        };
+        assert_eq!(
+            Some(Dotfiles::Dir(
+                host_home_dir().as_host_raw().join("dotfiles")
+            )),
+            dotfiles("{ dir = '~/dotfiles' }").unwrap()
+        );
+        assert!(dotfiles("{ dir = '/a', git = 'https://example.com/b' }").is_err());
+        assert!(dotfiles("{}").is_err());
+    }
+
     #[test]
     fn config_from_str_bad_runner() {
         assert_eq!(
@@ -343,11 +1445,37 @@ mod tests {
     #[test]
     fn config_from_str_ok() {
         let expected = Config {
+            runner_choices: vec![RunnerKind::Docker],
             runner: RunnerKind::Docker,
             auto_update: twelve_hours(),
             builtin_package_dir: None,
-            bubblewrap: None,
-            docker: Docker::default(),
+            package_precedence: Vec::new(),
+            package_repos: BTreeMap::new(),
+            remote_cache: None,
+            offline_package_builds: false,
+            nix: None,
+            seed_copy: SeedCopy::Extract,
+            gpu: Vec::new(),
+            audio: Vec::new(),
+            clipboard: Vec::new(),
+            host_commands: BTreeMap::new(),
+            devices: BTreeMap::new(),
+            nested_containers: BTreeMap::new(),
+            work_dirs: BTreeMap::new(),
+            cpus: None,
+            memory: None,
+            disk_quota: None,
+            idle_timeout: thirty_minutes(),
+            host_scoped_dirs: false,
+            host_user: None,
+            shell: None,
+            dotfiles: None,
+            git_identity: false,
+            git_signing_key: false,
+            hooks: Hooks::default(),
+            hints: Hints::default(),
+            workspaces: BTreeMap::new(),
+            runners: Runners::default(),
         };
         assert_eq!(
             expected,
@@ -360,7 +1488,7 @@ mod tests {
             Config::from_str(
                 "
                     runner = 'docker'
-                    [docker]
+                    [runners.docker]
                 "
             )
             .enough_context()
@@ -372,18 +1500,132 @@ mod tests {
     fn config_from_str_full() {
         assert_eq!(
             Config {
+                runner_choices: vec![RunnerKind::Docker],
                 runner: RunnerKind::Docker,
                 auto_update: Some(Duration::from_secs(60 * 60 * 24 * 10)),
                 builtin_package_dir: Some(PathBuf::from("/usr/local/share/cubicle/packages")),
-                bubblewrap: Some(Bubblewrap {
-                    seccomp: PathOrDisabled::Path(PathBuf::from("/tmp/seccomp.bpf")),
+                package_precedence: vec![String::from("built-in"), String::from("00local")],
+                package_repos: BTreeMap::from([
+                    (
+                        String::from("team"),
+                        PackageRepo::Git(String::from("https://example.com/packages.git"))
+                    ),
+                    (
+                        String::from("vendor"),
+                        PackageRepo::Tar(String::from("https://example.com/packages.tar.gz"))
+                    ),
+                ]),
+                remote_cache: Some(RemoteCache {
+                    url: String::from("https://s3.us-east-1.amazonaws.com/team/cubicle"),
+                    upload: true,
+                    s3_region: Some(String::from("us-east-1")),
+                    signing_key: Some(PathBuf::from("/keys/cubicle")),
+                    allowed_signers: Some(PathBuf::from("/keys/allowed_signers")),
                 }),
-                docker: Docker {
-                    bind_mounts: true,
-                    locales: vec![String::from("eo"), String::from("tg_TJ.UTF-8")],
-                    prefix: String::from("p"),
-                    seccomp: Some(PathBuf::from("/etc/seccomp.json")),
-                    strict_debian_packages: true,
+                offline_package_builds: true,
+                nix: Some(Nix {
+                    flake: String::from("github:NixOS/nixpkgs/nixos-24.05"),
+                }),
+                seed_copy: SeedCopy::Hardlink,
+                gpu: vec![String::from("ml-*")],
+                audio: vec![String::from("*")],
+                clipboard: vec![String::from("*")],
+                host_commands: BTreeMap::from([(
+                    String::from("*"),
+                    vec![String::from("xdg-open")]
+                )]),
+                devices: BTreeMap::from([(
+                    String::from("vm"),
+                    vec![String::from("/dev/kvm"), String::from("/dev/ttyUSB*")]
+                )]),
+                nested_containers: BTreeMap::from([
+                    (String::from("ci-*"), NestedContainers::Podman),
+                    (String::from("ops"), NestedContainers::HostSocket),
+                ]),
+                work_dirs: BTreeMap::from([(String::from("proj"), String::from("proj"))]),
+                cpus: Some(2),
+                memory: Some(4 << 30),
+                disk_quota: Some(20 << 30),
+                idle_timeout: Some(Duration::from_secs(60 * 60 * 8)),
+                host_scoped_dirs: true,
+                host_user: Some(String::from("ci")),
+                shell: Some(String::from("/bin/zsh")),
+                dotfiles: Some(Dotfiles::Git(String::from(
+                    "https://example.com/dotfiles.git"
+                ))),
+                git_identity: true,
+                git_signing_key: true,
+                hooks: Hooks {
+                    pre_create: Some(String::from("echo pre-create")),
+                    post_create: Some(String::from("tmux new -d -s $CUBICLE")),
+                    pre_enter: Some(String::from("true")),
+                    pre_purge: Some(String::from("backup $CUBICLE")),
+                },
+                hints: Hints {
+                    disable: vec![String::from("disk-quota")],
+                },
+                workspaces: BTreeMap::from([(
+                    String::from("proj"),
+                    Workspace {
+                        network: true,
+                        env: BTreeMap::from([(
+                            String::from("DATABASE_URL"),
+                            String::from("postgres://backend/app")
+                        )]),
+                        mounts: vec![Mount {
+                            source: PathBuf::from("/srv/datasets"),
+                            target: PathBuf::from("/data"),
+                            read_only: true,
+                        }],
+                    }
+                )]),
+                runners: Runners {
+                    bubblewrap: Some(Bubblewrap {
+                        seccomp: PathOrDisabled::Path(PathBuf::from("/tmp/seccomp.bpf")),
+                        seccomps: BTreeMap::from([(
+                            String::from("browser"),
+                            PathOrDisabled::Path(PathBuf::from("/tmp/browser.bpf"))
+                        )]),
+                        uid: Some(0),
+                        gid: Some(0),
+                        subids: true,
+                        incremental_reset: true,
+                        overlay_home: true,
+                    }),
+                    docker: Docker {
+                        apparmor: Some(String::from("cubicle")),
+                        bind_mounts: true,
+                        locales: vec![String::from("eo"), String::from("tg_TJ.UTF-8")],
+                        prefix: String::from("p"),
+                        seccomp: Some(PathBuf::from("/etc/seccomp.json")),
+                        seccomps: BTreeMap::from([(
+                            String::from("browser"),
+                            PathBuf::from("/etc/browser.json")
+                        )]),
+                        strict_debian_packages: true,
+                        isolate_package_builds: true,
+                        keep_alive: Some(Duration::from_secs(60 * 60 * 24 * 30)),
+                        lifetime: ContainerLifetime::OnDemand,
+                        image: String::from("debian:12-slim"),
+                        images: BTreeMap::from([(
+                            String::from("noble"),
+                            String::from("ubuntu:24.04")
+                        )]),
+                        distros: BTreeMap::from([(
+                            String::from("registry.example.com/rocky:9"),
+                            Distro::Fedora
+                        )]),
+                        dockerfile_append: Some(PathBuf::from("/etc/cubicle/Dockerfile")),
+                        platforms: BTreeMap::from([(
+                            String::from("pi"),
+                            String::from("linux/arm64")
+                        )]),
+                        selinux_labels: vec![String::from("type:container_runtime_t")],
+                        uid: Some(1001),
+                        gid: Some(1002),
+                        userns: Some(String::from("keep-id")),
+                    },
+                    user: User {},
                 },
             },
             Config::from_str(
@@ -391,16 +1633,84 @@ mod tests {
                 runner = 'docker'
                 auto_update = '10d'
                 builtin_package_dir = '/usr/local/share/cubicle/packages'
+                package_precedence = ['built-in', '00local']
+                package_repos.team = { git = 'https://example.com/packages.git' }
+                package_repos.vendor = { tar = 'https://example.com/packages.tar.gz' }
+                offline_package_builds = true
+                seed_copy = 'hardlink'
+                gpu = ['ml-*']
+                audio = ['*']
+                clipboard = ['*']
+                host_commands = { '*' = ['xdg-open'] }
+                devices = { vm = ['/dev/kvm', '/dev/ttyUSB*'] }
+                nested_containers = { 'ci-*' = 'podman', ops = 'host-socket' }
+                work_dirs = { proj = 'proj' }
+                cpus = 2
+                memory = '4G'
+                disk_quota = '20 GiB'
+                idle_timeout = '8h'
+                host_scoped_dirs = true
+                host_user = 'ci'
+                shell = '/bin/zsh'
+                dotfiles = { git = 'https://example.com/dotfiles.git' }
+                git_identity = true
+                git_signing_key = true
+
+                [hooks]
+                pre_create = 'echo pre-create'
+                post_create = 'tmux new -d -s $CUBICLE'
+                pre_enter = 'true'
+                pre_purge = 'backup $CUBICLE'
+
+                [hints]
+                disable = ['disk-quota']
+
+                [remote_cache]
+                url = 'https://s3.us-east-1.amazonaws.com/team/cubicle'
+                upload = true
+                s3_region = 'us-east-1'
+                signing_key = '/keys/cubicle'
+                allowed_signers = '/keys/allowed_signers'
 
-                [bubblewrap]
+                [nix]
+                flake = 'github:NixOS/nixpkgs/nixos-24.05'
+
+                [workspaces.proj]
+                network = true
+                env = { DATABASE_URL = 'postgres://backend/app' }
+                mounts = [{ source = '/srv/datasets', target = '/data', read_only = true }]
+
+                [runners.bubblewrap]
                 seccomp = '/tmp/seccomp.bpf'
+                seccomps = { browser = '/tmp/browser.bpf' }
+                uid = 0
+                gid = 0
+                subids = true
+                incremental_reset = true
+                overlay_home = true
 
-                [docker]
+                [runners.docker]
+                apparmor = 'cubicle'
                 bind_mounts = true
                 locales = ['eo', 'tg_TJ.UTF-8']
                 prefix = 'p'
                 seccomp = '/etc/seccomp.json'
+                seccomps = { browser = '/etc/browser.json' }
                 strict_debian_packages = true
+                isolate_package_builds = true
+                keep_alive = '30d'
+                lifetime = 'on-demand'
+                image = 'debian:12-slim'
+                images = { noble = 'ubuntu:24.04' }
+                distros = { 'registry.example.com/rocky:9' = 'fedora' }
+                dockerfile_append = '/etc/cubicle/Dockerfile'
+                platforms = { pi = 'linux/arm64' }
+                selinux_labels = ['type:container_runtime_t']
+                uid = 1001
+                gid = 1002
+                userns = 'keep-id'
+
+                [runners.user]
                 "
             )
             .enough_context()
@@ -408,6 +1718,184 @@ mod tests {
         );
     }
 
+    #[test]
+    fn config_from_str_legacy_sections() {
+        let config = Config::from_str(
+            "
+            runner = 'docker'
+
+            [bubblewrap]
+            seccomp = '/tmp/seccomp.bpf'
+
+            [docker]
+            prefix = 'p'
+            ",
+        )
+        .enough_context()
+        .unwrap();
+        assert_eq!(
+            Some(Bubblewrap {
+                seccomp: PathOrDisabled::Path(PathBuf::from("/tmp/seccomp.bpf")),
+                seccomps: BTreeMap::new(),
+                uid: None,
+                gid: None,
+                subids: false,
+                incremental_reset: false,
+                overlay_home: false,
+            }),
+            config.runners.bubblewrap
+        );
+        assert_eq!("p", config.runners.docker.prefix);
+
+        let err = Config::from_str(
+            "
+            runner = 'docker'
+            [docker]
+            prefix = 'p'
+            [runners.docker]
+            prefix = 'q'
+            ",
+        )
+        .enough_context()
+        .unwrap_err()
+        .to_string();
+        assert!(
+            err.contains("found both `[docker]` and `[runners.docker]`"),
+            "{err}"
+        );
+    }
+
+    #[test]
+    fn config_from_str_runner_choices() {
+        let config = Config::from_str(
+            "
+            runner = ['bubblewrap', 'docker', 'user']
+            [runners.bubblewrap]
+            seccomp = 'disabled'
+            ",
+        )
+        .enough_context()
+        .unwrap();
+        assert_eq!(
+            vec![RunnerKind::Bubblewrap, RunnerKind::Docker, RunnerKind::User],
+            config.runner_choices
+        );
+        assert_eq!(RunnerKind::Bubblewrap, config.runner);
+
+        // Each runner's settings are checked, even if it won't be used.
+        assert!(Config::from_str("runner = ['docker', 'bubblewrap']").is_err());
+        assert!(Config::from_str("runner = []").is_err());
+    }
+
+    #[test]
+    fn config_from_str_docker_prefix() {
+        let parse = |prefix: &str| {
+            Config::from_str(&format!(
+                "runner = 'docker'\n[runners.docker]\nprefix = '{prefix}'"
+            ))
+        };
+        for prefix in ["", "cub-", "p"] {
+            assert!(parse(prefix).is_ok(), "{prefix:?} should be accepted");
+        }
+        for prefix in ["-cub", "a b", "a/b"] {
+            assert!(parse(prefix).is_err(), "{prefix:?} should be rejected");
+        }
+    }
+
+    #[test]
+    fn gpu_enabled() {
+        let config = Config::from_str(
+            "
+            runner = 'docker'
+            gpu = ['ml-*', 'games']
+            ",
+        )
+        .enough_context()
+        .unwrap();
+        let enabled = |name: &str| config.gpu_enabled(&EnvironmentName::from_str(name).unwrap());
+        assert!(enabled("ml-torch"));
+        assert!(enabled("games"));
+        assert!(!enabled("games2"));
+        assert!(!enabled("web"));
+    }
+
+    #[test]
+    fn nested_containers_for() {
+        let config = Config::from_str(
+            "
+            runner = 'docker'
+            nested_containers = { 'ci-*' = 'podman', ops = 'host-socket' }
+            ",
+        )
+        .enough_context()
+        .unwrap();
+        let mode =
+            |name: &str| config.nested_containers_for(&EnvironmentName::from_str(name).unwrap());
+        assert_eq!(Some(NestedContainers::Podman), mode("ci-1"));
+        assert_eq!(Some(NestedContainers::HostSocket), mode("ops"));
+        assert_eq!(None, mode("web"));
+        assert!(
+            Config::from_str("runner = 'docker'\nnested_containers = { x = 'privileged' }")
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn work_dir_name() {
+        let config = Config::from_str(
+            "
+            runner = 'docker'
+            work_dirs = { proj = 'proj', other = 'work' }
+            ",
+        )
+        .enough_context()
+        .unwrap();
+        let name = |env: &str| config.work_dir_name(&EnvironmentName::from_str(env).unwrap());
+        assert_eq!("proj", name("proj"));
+        assert_eq!("work", name("other"));
+        assert_eq!("w", name("unlisted"));
+
+        for bad in ["''", "'a/b'", "'..'", "'.'", "'/w'"] {
+            assert!(
+                Config::from_str(&format!("runner = 'docker'\nwork_dirs = {{ x = {bad} }}"))
+                    .is_err(),
+                "{bad} should be rejected"
+            );
+        }
+    }
+
+    #[test]
+    fn workspace_for() {
+        let config = Config::from_str(
+            "
+            runner = 'docker'
+            [workspaces.proj]
+            network = true
+            ",
+        )
+        .enough_context()
+        .unwrap();
+        let network = |env: &str| {
+            config
+                .workspace_for(&EnvironmentName::from_str(env).unwrap())
+                .map(|workspace| workspace.network)
+        };
+        assert_eq!(Some(true), network("proj/frontend"));
+        assert_eq!(None, network("proj"));
+        assert_eq!(None, network("other/frontend"));
+
+        for bad in [
+            "[workspaces.'a/b']",
+            "[workspaces.proj]\nenv = { 'A=B' = 'c' }",
+            "[workspaces.proj]\nmounts = [{ source = '/srv', target = 'data' }]",
+        ] {
+            assert!(
+                Config::from_str(&format!("runner = 'docker'\n{bad}")).is_err(),
+                "{bad} should be rejected"
+            );
+        }
+    }
+
     #[test]
     fn config_from_str_full_seccomp_disabled() {
         assert_eq!(
@@ -415,12 +1903,13 @@ mod tests {
             Config::from_str(
                 "
                 runner = 'bubblewrap'
-                [bubblewrap]
+                [runners.bubblewrap]
                 seccomp = 'dangerously-disabled'
                 "
             )
             .enough_context()
             .unwrap()
+            .runners
             .bubblewrap
             .unwrap()
             .seccomp
@@ -0,0 +1,89 @@
+//! Asks the user before destructive operations.
+//!
+//! Commands like `cub purge` ask for confirmation through a [`Confirmer`].
+//! The default one asks on the terminal; programs that embed Cubicle can
+//! provide their own with [`Cubicle::set_confirmer`].
+
+use std::io::{self, IsTerminal, Write};
+
+use super::{Cubicle, CubicleShared};
+use crate::somehow::{somehow as anyhow, Context, Result};
+
+/// Decides whether destructive operations should proceed.
+///
+/// Only one question is asked at a time, even if [`Cubicle`] is used from
+/// multiple threads.
+pub trait Confirmer: Send {
+    /// Returns true if the operation described by `question` should proceed,
+    /// or an error if it's not possible to ask.
+    ///
+    /// The question is a complete sentence, like "Purge environment "web",
+    /// including its work directory?".
+    fn confirm(&self, question: &str) -> Result<bool>;
+}
+
+/// A [`Confirmer`] that asks on the terminal. This is the default.
+///
+/// When stdin isn't a terminal, it returns an error instead of guessing.
+#[derive(Debug, Default)]
+pub struct TerminalConfirmer;
+
+impl Confirmer for TerminalConfirmer {
+    fn confirm(&self, question: &str) -> Result<bool> {
+        if !io::stdin().is_terminal() {
+            return Err(anyhow!(
+                "{question} Not proceeding, since stdin is not a terminal to ask for \
+                confirmation (use `--yes` to proceed without asking)"
+            ));
+        }
+        print!("{question} [y/N] ");
+        io::stdout().flush().context("failed to flush stdout")?;
+        let mut answer = String::new();
+        io::stdin()
+            .read_line(&mut answer)
+            .context("failed to read answer from stdin")?;
+        Ok(is_yes(&answer))
+    }
+}
+
+/// A [`Confirmer`] that proceeds without asking, like `cub --yes`.
+#[derive(Debug, Default)]
+pub struct AssumeYes;
+
+impl Confirmer for AssumeYes {
+    fn confirm(&self, _question: &str) -> Result<bool> {
+        Ok(true)
+    }
+}
+
+impl Cubicle {
+    /// Sets how to confirm destructive operations. The default is
+    /// [`TerminalConfirmer`].
+    pub fn set_confirmer(&self, confirmer: Box<dyn Confirmer>) {
+        *self.shared.confirmer.lock().unwrap() = confirmer;
+    }
+}
+
+impl CubicleShared {
+    /// Asks the configured [`Confirmer`] whether to proceed.
+    pub(super) fn confirm(&self, question: &str) -> Result<bool> {
+        self.confirmer.lock().unwrap().confirm(question)
+    }
+}
+
+/// Returns true if the answer to a yes/no question means yes.
+fn is_yes(answer: &str) -> bool {
+    matches!(answer.trim(), "y" | "Y" | "yes" | "Yes" | "YES")
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn is_yes() {
+        assert!(super::is_yes("y\n"));
+        assert!(super::is_yes(" yes "));
+        assert!(!super::is_yes("\n"));
+        assert!(!super::is_yes("n"));
+        assert!(!super::is_yes("yeah"));
+    }
+}
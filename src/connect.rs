@@ -0,0 +1,107 @@
+//! Tells other programs how to reach an environment directly.
+//!
+//! Editors and IDE plugins can use `cub connect-info --format json` to
+//! attach to an environment's container or open its files, instead of
+//! wrapping an interactive `cub enter`.
+
+use serde::Serialize;
+
+use super::runner::{ConnectInfo, EnvironmentExists, Runner};
+use super::{Cubicle, EnvironmentName};
+use crate::somehow::{somehow as anyhow, Context, Result};
+
+/// Allowed formats for [`Cubicle::print_connect_info`].
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+pub enum ConnectInfoFormat {
+    /// Human-formatted "key: value" lines.
+    #[default]
+    Default,
+    /// JSON object for machine consumption.
+    Json,
+}
+
+/// The output of `cub connect-info`.
+#[derive(Debug, Serialize)]
+struct Output<'a> {
+    name: &'a EnvironmentName,
+    runner: &'static str,
+    running: bool,
+    #[serde(flatten)]
+    info: ConnectInfo,
+}
+
+impl Cubicle {
+    /// Corresponds to `cub connect-info`.
+    pub fn print_connect_info(
+        &self,
+        name: &EnvironmentName,
+        format: ConnectInfoFormat,
+    ) -> Result<()> {
+        match self.runner.exists(name)? {
+            EnvironmentExists::NoEnvironment => {
+                return Err(anyhow!("Environment {name} does not exist"))
+            }
+            EnvironmentExists::PartiallyExists => {
+                return Err(anyhow!(
+                    "Environment {name} in broken state (try '{} reset')",
+                    self.shared.exe_name
+                ))
+            }
+            EnvironmentExists::FullyExists => {}
+        }
+
+        let output = Output {
+            name,
+            runner: self.shared.config.runner.as_str(),
+            running: self.runner.is_running(name)?,
+            info: self.runner.connect_info(name)?,
+        };
+
+        match format {
+            ConnectInfoFormat::Default => {
+                let Output {
+                    name,
+                    runner,
+                    running,
+                    info,
+                } = output;
+                println!("name: {}", name.as_str());
+                println!("runner: {runner}");
+                println!("running: {running}");
+                if let Some(container) = &info.container {
+                    println!("container: {container}");
+                }
+                if let Some(user) = &info.user {
+                    println!("user: {user}");
+                }
+                for (key, path) in [
+                    ("home_dir", &info.home_dir),
+                    ("work_dir", &info.work_dir),
+                    ("host_home_dir", &info.host_home_dir),
+                    ("host_work_dir", &info.host_work_dir),
+                ] {
+                    if let Some(path) = path {
+                        println!("{key}: {}", path.display());
+                    }
+                }
+                if let Some(command) = &info.attach_command {
+                    println!(
+                        "attach_command: {}",
+                        shlex::try_join(command.iter().map(String::as_str))
+                            .context("failed to quote attach command")?
+                    );
+                }
+            }
+
+            ConnectInfoFormat::Json => {
+                println!(
+                    "{}",
+                    serde_json::to_string_pretty(&output)
+                        .context("failed to serialize JSON connection info")?
+                );
+            }
+        }
+        Ok(())
+    }
+}
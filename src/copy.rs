@@ -0,0 +1,410 @@
+//! Copies files and directories into and out of environments.
+//!
+//! Files move between the host and the runners as tar archives, so that
+//! directories, permissions, and symlinks come along. The archives' top-level
+//! entry is named after the file or directory being copied.
+
+use std::ffi::{OsStr, OsString};
+use std::fs::File;
+use std::io::{self, Seek};
+use std::path::{Component, Path, PathBuf};
+
+use super::runner::Runner;
+use super::{Cubicle, EnvironmentName, HostPath};
+use crate::somehow::{somehow as anyhow, Context, Result};
+
+/// One end of a copy with [`Cubicle::copy`].
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum CopyPath {
+    /// A path on the host, relative to the current directory if not
+    /// absolute.
+    Host(PathBuf),
+    /// A path in the environment, relative to its home directory. The
+    /// environment's work directory is within its home directory.
+    Env(PathBuf),
+}
+
+impl Cubicle {
+    /// Corresponds to `cub cp`.
+    ///
+    /// Copies the file or directory at `src` to `dst`, where at least one of
+    /// them is in the environment. Directories are copied recursively, and
+    /// symlinks are copied as symlinks.
+    ///
+    /// On the host, if `dst` is an existing directory, `src` is copied into
+    /// it. Otherwise, the copy is named `dst`, which must not exist yet. In
+    /// the environment, `dst` is treated as a directory to copy into if it's
+    /// empty or ends with a slash. Otherwise, the copy is named `dst`,
+    /// replacing any file there.
+    pub fn copy(&self, name: &EnvironmentName, src: &CopyPath, dst: &CopyPath) -> Result<()> {
+        if let (CopyPath::Host(_), CopyPath::Host(_)) = (src, dst) {
+            return Err(anyhow!(
+                "one of the paths must be in the environment, like \"ENV:PATH\""
+            ));
+        }
+        self.exec_in_existing(name, || self.copy_(name, src, dst))
+    }
+
+    fn copy_(&self, name: &EnvironmentName, src: &CopyPath, dst: &CopyPath) -> Result<()> {
+        let mut archive = tempfile::tempfile().context("failed to create temporary file")?;
+        let src_name = match src {
+            CopyPath::Host(path) => {
+                let src_name = file_name(path)?;
+                write_archive(path, src_name, &mut archive)?;
+                src_name
+            }
+            CopyPath::Env(path) => {
+                let path = check_env_path(path)?;
+                self.runner.copy_out(name, path, &mut archive)?;
+                file_name(path)?
+            }
+        };
+        archive
+            .rewind()
+            .context("failed to rewind temporary file")?;
+
+        match dst {
+            CopyPath::Host(path) => extract_to_host(&mut archive, src_name, path),
+            CopyPath::Env(path) => {
+                let into_dir = path.as_os_str().is_empty()
+                    || path == Path::new("~")
+                    || path.as_os_str().as_encoded_bytes().ends_with(b"/");
+                let path = check_env_path(path)?;
+                if into_dir || src_name == file_name(path)? {
+                    let dir = if into_dir { path } else { parent(path) };
+                    return self.runner.copy_in(name, dir, &mut archive);
+                }
+                // Rename the top-level entry on the way in.
+                let mut renamed =
+                    tempfile::tempfile().context("failed to create temporary file")?;
+                rename_archive(&mut archive, file_name(path)?, &mut renamed)?;
+                renamed
+                    .rewind()
+                    .context("failed to rewind temporary file")?;
+                self.runner.copy_in(name, parent(path), &mut renamed)
+            }
+        }
+    }
+}
+
+/// Returns the path if it's relative and stays within the home directory.
+fn check_env_path(path: &Path) -> Result<&Path> {
+    let path = path.strip_prefix("~").unwrap_or(path);
+    if path
+        .components()
+        .all(|c| matches!(c, Component::Normal(_) | Component::CurDir))
+    {
+        Ok(path)
+    } else {
+        Err(anyhow!(
+            "path in environment must be relative to its home directory, without `..`: {path:?}"
+        ))
+    }
+}
+
+fn file_name(path: &Path) -> Result<&OsStr> {
+    path.file_name()
+        .ok_or_else(|| anyhow!("can't copy {path:?}: it has no file name"))
+}
+
+fn parent(path: &Path) -> &Path {
+    path.parent().unwrap_or(Path::new(""))
+}
+
+/// Writes a tar archive of the file or directory at `path` to `w`, with the
+/// top-level entry named `name`.
+fn write_archive(path: &Path, name: &OsStr, w: &mut dyn io::Write) -> Result<()> {
+    let metadata = std::fs::symlink_metadata(path)
+        .with_context(|| format!("failed to read metadata for {path:?}"))?;
+    let mut builder = tar::Builder::new(w);
+    builder.follow_symlinks(false);
+    if metadata.is_dir() {
+        builder.append_dir_all(name, path)
+    } else {
+        builder.append_path_with_name(path, name)
+    }
+    .with_context(|| format!("failed to archive {path:?}"))?;
+    builder
+        .into_inner()
+        .with_context(|| format!("failed to archive {path:?}"))?;
+    Ok(())
+}
+
+/// Copies the tar archive from `r` to `w`, renaming its top-level entry to
+/// `name`.
+fn rename_archive(r: &mut dyn io::Read, name: &OsStr, w: &mut dyn io::Write) -> Result<()> {
+    let mut archive = tar::Archive::new(r);
+    let mut builder = tar::Builder::new(w);
+    for entry in archive.entries().context("failed to read archive")? {
+        let mut entry = entry.context("failed to read archive")?;
+        let path = entry.path().context("failed to read archive")?.into_owned();
+        let mut components = path.components();
+        components.next();
+        // Joining an empty path would add a trailing slash, which marks a
+        // directory.
+        let renamed = match components.as_path() {
+            rest if rest.as_os_str().is_empty() => PathBuf::from(name),
+            rest => Path::new(name).join(rest),
+        };
+        let mut header = entry.header().clone();
+        match entry.link_name().context("failed to read archive")? {
+            Some(target) => {
+                let target = target.into_owned();
+                builder.append_link(&mut header, &renamed, target)
+            }
+            None => builder.append_data(&mut header, &renamed, &mut entry),
+        }
+        .context("failed to write archive")?;
+    }
+    builder.into_inner().context("failed to write archive")?;
+    Ok(())
+}
+
+/// Extracts the tar archive in `archive`, whose top-level entry is named
+/// `src_name`, to `dst` on the host.
+fn extract_to_host(archive: &mut File, src_name: &OsStr, dst: &Path) -> Result<()> {
+    if dst.is_dir() {
+        return tar::Archive::new(archive)
+            .unpack(dst)
+            .with_context(|| format!("failed to extract files into {dst:?}"));
+    }
+    if dst.symlink_metadata().is_ok() {
+        return Err(anyhow!("{dst:?} already exists"));
+    }
+    // Extract next to the destination, then rename into place, so that
+    // nothing appears at `dst` until the copy is complete.
+    let dst_name = file_name(dst)?;
+    let dir = match parent(dst) {
+        dir if dir.as_os_str().is_empty() => Path::new("."),
+        dir => dir,
+    };
+    let tmp = tempfile::Builder::new()
+        .prefix(".cub-cp")
+        .tempdir_in(dir)
+        .with_context(|| format!("failed to create temporary directory in {dir:?}"))?;
+    tar::Archive::new(archive)
+        .unpack(tmp.path())
+        .with_context(|| format!("failed to extract files into {:?}", tmp.path()))?;
+    let extracted = tmp.path().join(src_name);
+    std::fs::rename(&extracted, dst)
+        .with_context(|| format!("failed to rename {extracted:?} to {dst_name:?}"))
+}
+
+/// Returns `root` joined with `path` after checking that the result is
+/// within `root`, even after following symlinks in the directories leading
+/// up to it. `path` must have been checked with `check_env_path`.
+///
+/// This is for runners that keep environments' files in host directories,
+/// since the environment controls what's in them.
+fn resolve_inside(root: &HostPath, path: &Path) -> Result<PathBuf> {
+    let root = std::fs::canonicalize(root.as_host_raw())
+        .with_context(|| format!("failed to resolve {root}"))?;
+    let full = root.join(path);
+    let parent = match full.parent() {
+        Some(parent) if full != root => parent,
+        _ => return Ok(full),
+    };
+    let resolved =
+        std::fs::canonicalize(parent).with_context(|| format!("failed to resolve {parent:?}"))?;
+    if !resolved.starts_with(&root) {
+        return Err(anyhow!("{path:?} leads outside the environment"));
+    }
+    Ok(resolved.join(full.file_name().unwrap()))
+}
+
+/// Implements [`Runner::copy_out`] for runners that keep environments' home
+/// and work directories on the host.
+pub(super) fn copy_out_from_host_dirs(
+    host_home: &HostPath,
+    host_work: &HostPath,
+    work_dir_name: &str,
+    path: &Path,
+    w: &mut dyn io::Write,
+) -> Result<()> {
+    let (root, path) = split_work_path(host_home, host_work, work_dir_name, path);
+    let full = resolve_inside(root, path)?;
+    write_archive(&full, file_name(&full)?, w)
+}
+
+/// Implements [`Runner::copy_in`] for runners that keep environments' home
+/// and work directories on the host.
+pub(super) fn copy_in_to_host_dirs(
+    host_home: &HostPath,
+    host_work: &HostPath,
+    work_dir_name: &str,
+    dir: &Path,
+    r: &mut dyn io::Read,
+) -> Result<()> {
+    let (root, dir) = split_work_path(host_home, host_work, work_dir_name, dir);
+    let root = std::fs::canonicalize(root.as_host_raw())
+        .with_context(|| format!("failed to resolve {root}"))?;
+    let resolved = std::fs::canonicalize(root.join(dir))
+        .with_context(|| format!("directory {dir:?} does not exist in the environment"))?;
+    if !resolved.starts_with(&root) {
+        return Err(anyhow!("{dir:?} leads outside the environment"));
+    }
+    if !resolved.is_dir() {
+        return Err(anyhow!("{dir:?} is not a directory in the environment"));
+    }
+    tar::Archive::new(r)
+        .unpack(&resolved)
+        .with_context(|| format!("failed to extract files into {resolved:?}"))
+}
+
+/// Returns the host directory that `path` (relative to the environment's
+/// home directory) is in, and the path relative to that.
+fn split_work_path<'a>(
+    host_home: &'a HostPath,
+    host_work: &'a HostPath,
+    work_dir_name: &str,
+    path: &'a Path,
+) -> (&'a HostPath, &'a Path) {
+    match path.strip_prefix(work_dir_name) {
+        Ok(rest) => (host_work, rest),
+        Err(_) => (host_home, path),
+    }
+}
+
+/// Returns the arguments for `tar` to archive `path`, relative to the
+/// current directory, as [`Runner::copy_out`] requires.
+pub(super) fn tar_create_args(path: &Path) -> Result<Vec<OsString>> {
+    let dir = match parent(path) {
+        dir if dir.as_os_str().is_empty() => Path::new("."),
+        dir => dir,
+    };
+    Ok(vec![
+        OsString::from("-C"),
+        dir.as_os_str().to_owned(),
+        OsString::from("-cf"),
+        OsString::from("-"),
+        OsString::from("--"),
+        file_name(path)?.to_owned(),
+    ])
+}
+
+/// Returns the arguments for `tar` to extract an archive from stdin into
+/// `dir`, relative to the current directory.
+pub(super) fn tar_extract_args(dir: &Path) -> Vec<OsString> {
+    let dir = if dir.as_os_str().is_empty() {
+        Path::new(".")
+    } else {
+        dir
+    };
+    vec![
+        OsString::from("-C"),
+        dir.as_os_str().to_owned(),
+        OsString::from("-xf"),
+        OsString::from("-"),
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::symlink;
+
+    #[test]
+    fn check_env_path() {
+        let ok = |path: &str| super::check_env_path(Path::new(path)).unwrap().to_owned();
+        assert_eq!(ok("w/a.txt"), Path::new("w/a.txt"));
+        assert_eq!(ok("~/w/a.txt"), Path::new("w/a.txt"));
+        assert_eq!(ok(""), Path::new(""));
+        assert!(super::check_env_path(Path::new("/etc/passwd")).is_err());
+        assert!(super::check_env_path(Path::new("w/../../x")).is_err());
+    }
+
+    #[test]
+    fn host_dirs_round_trip() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = HostPath::try_from(tmp.path().to_owned()).unwrap();
+        let home = root.join("home");
+        let work = root.join("work");
+        std::fs::create_dir_all(work.join("src").as_host_raw()).unwrap();
+        std::fs::create_dir_all(home.as_host_raw()).unwrap();
+        std::fs::write(work.join("src").join("a.txt").as_host_raw(), "a").unwrap();
+        symlink("a.txt", work.join("src").join("link").as_host_raw()).unwrap();
+
+        let mut archive = Vec::new();
+        copy_out_from_host_dirs(&home, &work, "w", Path::new("w/src"), &mut archive).unwrap();
+        copy_in_to_host_dirs(&home, &work, "w", Path::new(""), &mut archive.as_slice()).unwrap();
+        let copied = home.join("src");
+        assert_eq!(
+            std::fs::read_to_string(copied.join("a.txt").as_host_raw()).unwrap(),
+            "a"
+        );
+        assert_eq!(
+            std::fs::read_link(copied.join("link").as_host_raw()).unwrap(),
+            Path::new("a.txt")
+        );
+
+        // The environment can't point the copy at the host's other files.
+        symlink(tmp.path(), home.join("escape").as_host_raw()).unwrap();
+        let mut archive = Vec::new();
+        assert!(copy_out_from_host_dirs(
+            &home,
+            &work,
+            "w",
+            Path::new("escape/work/src"),
+            &mut archive
+        )
+        .is_err());
+        assert!(
+            copy_in_to_host_dirs(&home, &work, "w", Path::new("escape"), &mut io::empty()).is_err()
+        );
+    }
+
+    #[test]
+    fn extract_to_host() {
+        let tmp = tempfile::tempdir().unwrap();
+        let src = tmp.path().join("src.txt");
+        std::fs::write(&src, "hi").unwrap();
+        let mut archive = tempfile::tempfile().unwrap();
+        write_archive(&src, OsStr::new("src.txt"), &mut archive).unwrap();
+
+        let dst = tmp.path().join("dst.txt");
+        archive.rewind().unwrap();
+        super::extract_to_host(&mut archive, OsStr::new("src.txt"), &dst).unwrap();
+        assert_eq!(std::fs::read_to_string(&dst).unwrap(), "hi");
+
+        let dir = tmp.path().join("dir");
+        std::fs::create_dir(&dir).unwrap();
+        archive.rewind().unwrap();
+        super::extract_to_host(&mut archive, OsStr::new("src.txt"), &dir).unwrap();
+        assert_eq!(std::fs::read_to_string(dir.join("src.txt")).unwrap(), "hi");
+
+        archive.rewind().unwrap();
+        assert!(super::extract_to_host(&mut archive, OsStr::new("src.txt"), &dst).is_err());
+    }
+
+    #[test]
+    fn rename_archive() {
+        let tmp = tempfile::tempdir().unwrap();
+        let src = tmp.path().join("src");
+        std::fs::create_dir(&src).unwrap();
+        std::fs::write(src.join("a.txt"), "a").unwrap();
+        let mut archive = Vec::new();
+        write_archive(&src, OsStr::new("src"), &mut archive).unwrap();
+        let mut renamed = Vec::new();
+        super::rename_archive(&mut archive.as_slice(), OsStr::new("dst"), &mut renamed).unwrap();
+        tar::Archive::new(renamed.as_slice())
+            .unpack(tmp.path())
+            .unwrap();
+        assert_eq!(
+            std::fs::read_to_string(tmp.path().join("dst").join("a.txt")).unwrap(),
+            "a"
+        );
+
+        // Renaming a single file mustn't turn it into a directory.
+        let mut archive = Vec::new();
+        write_archive(&src.join("a.txt"), OsStr::new("a.txt"), &mut archive).unwrap();
+        let mut renamed = Vec::new();
+        super::rename_archive(&mut archive.as_slice(), OsStr::new("b.txt"), &mut renamed).unwrap();
+        tar::Archive::new(renamed.as_slice())
+            .unpack(tmp.path())
+            .unwrap();
+        assert_eq!(
+            std::fs::read_to_string(tmp.path().join("b.txt")).unwrap(),
+            "a"
+        );
+    }
+}
@@ -0,0 +1,90 @@
+//! Writes tables as comma- or tab-separated values.
+//!
+//! These are for `--format csv` and `--format tsv`, which are easier to load
+//! into spreadsheets and shell pipelines than the default tables. Columns
+//! are named like the fields in the JSON output, and their order should stay
+//! stable.
+
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// A delimited output format.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub(super) enum Delimited {
+    /// Comma-separated values, quoted as in RFC 4180.
+    Csv,
+    /// Tab-separated values, with tabs, newlines, and backslashes escaped
+    /// using backslashes.
+    Tsv,
+}
+
+impl Delimited {
+    /// Formats a single row, without a trailing newline.
+    pub fn row<S: AsRef<str>>(self, fields: &[S]) -> String {
+        let fields = fields.iter().map(|field| self.field(field.as_ref()));
+        match self {
+            Self::Csv => fields.collect::<Vec<_>>().join(","),
+            Self::Tsv => fields.collect::<Vec<_>>().join("\t"),
+        }
+    }
+
+    fn field(self, field: &str) -> String {
+        match self {
+            Self::Csv => {
+                if field.contains([',', '"', '\n', '\r']) {
+                    format!("\"{}\"", field.replace('"', "\"\""))
+                } else {
+                    field.to_owned()
+                }
+            }
+            Self::Tsv => field
+                .replace('\\', "\\\\")
+                .replace('\t', "\\t")
+                .replace('\n', "\\n")
+                .replace('\r', "\\r"),
+        }
+    }
+}
+
+/// Formats an optional time as seconds since the Unix epoch, like the JSON
+/// output, or as an empty field.
+pub(super) fn time_field(time: Option<SystemTime>) -> String {
+    match time.and_then(|time| time.duration_since(UNIX_EPOCH).ok()) {
+        Some(duration) => duration.as_secs_f64().to_string(),
+        None => String::new(),
+    }
+}
+
+/// Formats an optional path, or an empty field.
+pub(super) fn path_field(path: Option<&Path>) -> String {
+    match path {
+        Some(path) => path.to_string_lossy().into_owned(),
+        None => String::new(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Delimited;
+
+    #[test]
+    fn row() {
+        let fields = [
+            "web",
+            "a,b",
+            "say \"hi\"",
+            "tab\there",
+            "two\nlines",
+            r"C:\x",
+        ];
+        assert_eq!(
+            "web,\"a,b\",\"say \"\"hi\"\"\",tab\there,\"two\nlines\",C:\\x",
+            Delimited::Csv.row(&fields)
+        );
+        assert_eq!(
+            "web\ta,b\tsay \"hi\"\ttab\\there\ttwo\\nlines\tC:\\\\x",
+            Delimited::Tsv.row(&fields)
+        );
+        assert_eq!("", Delimited::Csv.row::<&str>(&[]));
+    }
+}
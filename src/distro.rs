@@ -0,0 +1,249 @@
+//! Operating system distributions that environments can be based on.
+//!
+//! Each distribution has its own package namespace (like `debian`), so that
+//! Cubicle packages can declare which OS-level packages they need on each
+//! one.
+
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::fmt::{self, Display};
+use std::str::FromStr;
+use std::sync::OnceLock;
+
+use super::apt;
+use super::command_ext::Command;
+use crate::somehow::{somehow as anyhow, warn, Error, Result};
+
+/// A Linux distribution and its package manager.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, Hash, Ord, PartialEq, PartialOrd)]
+#[serde(rename_all = "lowercase")]
+pub enum Distro {
+    /// Alpine Linux, using `apk`.
+    Alpine,
+    /// Debian and derivatives like Ubuntu, using `apt`.
+    Debian,
+    /// Fedora and derivatives, using `dnf`.
+    Fedora,
+}
+
+impl Distro {
+    /// All the distributions, in order.
+    pub const ALL: [Self; 3] = [Self::Alpine, Self::Debian, Self::Fedora];
+
+    /// Returns the name of the distribution, which is also the name of its
+    /// package namespace.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Alpine => "alpine",
+            Self::Debian => "debian",
+            Self::Fedora => "fedora",
+        }
+    }
+
+    /// Guesses the distribution of a Docker image from its name, like
+    /// `fedora:40` or `docker.io/library/alpine`. Defaults to Debian.
+    pub fn guess_from_image(image: &str) -> Self {
+        let repository = image.rsplit('/').next().unwrap_or(image);
+        let repository = repository.split([':', '@']).next().unwrap_or(repository);
+        match repository {
+            "alpine" => Self::Alpine,
+            "fedora" | "almalinux" | "rockylinux" | "centos" => Self::Fedora,
+            _ => Self::Debian,
+        }
+    }
+
+    /// Returns the distribution of the host, based on `/etc/os-release`.
+    /// Defaults to Debian, with a warning, if that can't be determined.
+    pub fn host() -> Self {
+        static HOST: OnceLock<Distro> = OnceLock::new();
+        *HOST.get_or_init(|| {
+            let os_release = match std::fs::read_to_string("/etc/os-release") {
+                Ok(os_release) => os_release,
+                Err(e) => {
+                    warn(anyhow!(
+                        "could not read /etc/os-release, assuming Debian: {e}"
+                    ));
+                    return Self::Debian;
+                }
+            };
+            match from_os_release(&os_release) {
+                Some(distro) => distro,
+                None => {
+                    warn(anyhow!(
+                        "unrecognized distribution in /etc/os-release, assuming Debian"
+                    ));
+                    Self::Debian
+                }
+            }
+        })
+    }
+
+    /// Warns if any of the given OS packages are not installed on the host.
+    pub fn check_host_packages(&self, packages: &[&str]) {
+        match self {
+            Self::Debian => apt::check_satisfied(packages),
+            Self::Alpine | Self::Fedora => {
+                if packages.is_empty() {
+                    return;
+                }
+                let query = match self {
+                    Self::Alpine => ["apk", "info", "--installed"],
+                    _ => ["rpm", "--query", "--whatprovides"],
+                };
+                match Command::query(query[0])
+                    .args(&query[1..])
+                    .arg("--")
+                    .args(packages)
+                    .stdout(std::process::Stdio::null())
+                    .stderr(std::process::Stdio::null())
+                    .status()
+                {
+                    Ok(status) if status.success() => {}
+                    Ok(_) => warn(anyhow!(
+                        "{} dependencies unsatisfied: {packages:?}",
+                        query[0]
+                    )),
+                    Err(e) => warn(e.context(format!(
+                        "could not check {} dependencies: {packages:?}",
+                        query[0]
+                    ))),
+                }
+            }
+        }
+    }
+
+    /// Returns a shell script that lists the OS packages installed on a
+    /// system with this distribution, one per line as the name and version
+    /// separated by a tab.
+    pub fn installed_packages_script(&self) -> &'static str {
+        match self {
+            Self::Alpine => {
+                r#"awk -F: '/^P:/ { name = $2 } /^V:/ { print name "\t" $2 }' /lib/apk/db/installed"#
+            }
+            Self::Debian => {
+                r#"dpkg-query --show --showformat '${db:Status-Status}\t${Package}\t${Version}\n' | awk -F '\t' '$1 == "installed" { print $2 "\t" $3 }'"#
+            }
+            Self::Fedora => r#"rpm --query --all --queryformat '%{NAME}\t%{VERSION}-%{RELEASE}\n'"#,
+        }
+    }
+
+    /// Returns a shell script that prints the names of the OS packages
+    /// available from the package manager's repositories, one per line.
+    ///
+    /// For Debian, this includes virtual packages, which other packages can
+    /// depend on too.
+    pub fn available_packages_script(&self) -> &'static str {
+        match self {
+            Self::Alpine => "apk search --quiet",
+            Self::Debian => "apt-cache --all-names pkgnames",
+            Self::Fedora => r#"dnf repoquery --quiet --queryformat '%{name}\n'"#,
+        }
+    }
+}
+
+/// Parses the output of [`Distro::installed_packages_script`], which has a
+/// package name and version per line, separated by a tab. Returns a map from
+/// package name to version.
+pub fn parse_installed_packages(output: &str) -> BTreeMap<String, String> {
+    output
+        .lines()
+        .filter_map(|line| {
+            let (name, version) = line.split_once('\t')?;
+            Some((name.to_owned(), version.to_owned()))
+        })
+        .collect()
+}
+
+/// Parses the `ID` and `ID_LIKE` fields of an `os-release` file.
+fn from_os_release(os_release: &str) -> Option<Distro> {
+    let field = |key: &str| -> Vec<String> {
+        os_release
+            .lines()
+            .filter_map(|line| line.strip_prefix(key)?.strip_prefix('='))
+            .flat_map(|value| {
+                value
+                    .trim_matches(|c| c == '"' || c == '\'')
+                    .split_whitespace()
+                    .map(String::from)
+                    .collect::<Vec<_>>()
+            })
+            .collect()
+    };
+    field("ID")
+        .into_iter()
+        .chain(field("ID_LIKE"))
+        .find_map(|id| match id.as_str() {
+            "alpine" => Some(Distro::Alpine),
+            "debian" | "ubuntu" => Some(Distro::Debian),
+            "fedora" | "rhel" | "centos" => Some(Distro::Fedora),
+            _ => None,
+        })
+}
+
+impl FromStr for Distro {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Self> {
+        Self::ALL
+            .into_iter()
+            .find(|distro| distro.as_str() == s)
+            .ok_or_else(|| anyhow!("unknown distribution: {s:?}"))
+    }
+}
+
+impl Display for Distro {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn guess_from_image() {
+        assert_eq!(Distro::guess_from_image("debian:12"), Distro::Debian);
+        assert_eq!(Distro::guess_from_image("ubuntu:24.04"), Distro::Debian);
+        assert_eq!(Distro::guess_from_image("fedora"), Distro::Fedora);
+        assert_eq!(
+            Distro::guess_from_image("registry.fedoraproject.org/fedora:40"),
+            Distro::Fedora
+        );
+        assert_eq!(
+            Distro::guess_from_image("docker.io/library/alpine@sha256:abc"),
+            Distro::Alpine
+        );
+        assert_eq!(
+            Distro::guess_from_image("localhost:5000/alpine-ish"),
+            Distro::Debian
+        );
+    }
+
+    #[test]
+    fn from_os_release() {
+        assert_eq!(
+            super::from_os_release("NAME=\"Ubuntu\"\nID=ubuntu\nID_LIKE=debian\n"),
+            Some(Distro::Debian)
+        );
+        assert_eq!(
+            super::from_os_release("ID=\"rocky\"\nID_LIKE=\"rhel centos fedora\"\n"),
+            Some(Distro::Fedora)
+        );
+        assert_eq!(
+            super::from_os_release("ID=alpine\nVERSION_ID=3.20.0\n"),
+            Some(Distro::Alpine)
+        );
+        assert_eq!(super::from_os_release("ID=arch\n"), None);
+    }
+
+    #[test]
+    fn parse_installed_packages() {
+        assert_eq!(
+            super::parse_installed_packages("bash\t5.2.15-2+b7\ncurl\t7.88.1-10\n\n"),
+            BTreeMap::from([
+                (String::from("bash"), String::from("5.2.15-2+b7")),
+                (String::from("curl"), String::from("7.88.1-10")),
+            ])
+        );
+    }
+}
@@ -2,35 +2,60 @@ use regex::{Regex, RegexBuilder};
 use std::collections::BTreeSet;
 use std::ffi::{OsStr, OsString};
 use std::io::{self, BufRead, IsTerminal, Write};
-use std::path::Path;
-use std::process::Stdio;
-use std::rc::Rc;
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::process::{ExitStatus, Stdio};
 use std::str::FromStr;
-use std::sync::OnceLock;
+use std::sync::{Arc, OnceLock};
 use std::time::{Duration, UNIX_EPOCH};
+use tracing::{debug, info};
 
-use super::command_ext::Command;
-use super::fs_util::{rmtree, summarize_dir, try_exists, try_iterdir_dirs, DirSummary};
-use super::os_util::{get_timezone, get_uids, Uids};
+use super::clipboard::env_clipboard_share;
+use super::command_ext::{Command, OutputStream};
+use super::config::{ContainerLifetime, NestedContainers, DEFAULT_WORK_DIR};
+use super::copy::{
+    copy_in_to_host_dirs, copy_out_from_host_dirs, tar_create_args, tar_extract_args,
+};
+use super::fs_util::{rmtree, summarize_dir, tar_size, try_exists, write_seeds, DirSummary};
+use super::host_commands::env_host_commands_share;
+use super::os_util::{get_timezone, get_uids, host_user, Uids};
+use super::packages::{shared_mount, SHARED_ENV_PATH};
 use super::paths::EnvPath;
 use super::runner::{
-    EnvFilesSummary, EnvironmentExists, Init, Runner, RunnerCommand, Target,
-    LOCALE_ENVIRONMENT_VARIABLES,
+    env_dir_names, host_audio_sockets, host_container_socket, host_devices, host_wayland_socket,
+    query_stdout, run_du, ConnectInfo, EnvDirectorySizes, EnvFilesSummary, EnvironmentExists,
+    HostSocket, Init, Orphan, Runner, RunnerCommand, Target, LOCALE_ENVIRONMENT_VARIABLES,
+};
+use super::seed_store;
+use super::{
+    CubicleShared, Distro, EnvironmentName, Event, ExitStatusError, HostPath, Platform,
+    WorkspaceName,
 };
-use super::{CubicleShared, EnvironmentName, ExitStatusError, HostPath};
 use crate::somehow::{somehow as anyhow, warn, Context, LowLevelResult, Result};
 
+/// The arguments of the keep-alive process that older versions ran in each
+/// container, which may still be running.
+const LEGACY_KEEP_ALIVE_ARGS: &str = "sleep 90d";
+
+/// Name of the Docker checkpoint created by [`Runner::checkpoint`].
+const CHECKPOINT_NAME: &str = "cubicle";
+
+/// Docker label set on containers from [`Runner::run_ephemeral`]. These have
+/// no home or work directory on the host, so they must not be mistaken for
+/// orphans.
+const EPHEMERAL_LABEL: &str = "cubicle.ephemeral";
+
 mod names;
-use names::{ContainerName, ImageName, VolumeName};
+use names::{ContainerName, ImageName, NetworkName, VolumeName};
 
 pub struct Docker {
-    pub(super) program: Rc<CubicleShared>,
+    pub(super) program: Arc<CubicleShared>,
     user: String,
     uids: Uids,
     timezone: String,
     locales: BTreeSet<String>,
     mounts: Mounts,
-    base_image: ImageName,
     container_home: EnvPath,
 }
 
@@ -42,6 +67,31 @@ enum Mounts {
     Volumes,
 }
 
+/// How [`Docker::spawn`] starts a container.
+#[derive(Clone, Copy)]
+enum SpawnMode<'a> {
+    /// Starts the environment's long-lived container in the background.
+    Detached,
+    /// Creates the environment's container and restores its processes from
+    /// the checkpoint in the given directory.
+    Checkpoint(&'a HostPath),
+    /// Runs the command in a temporary container in the foreground, with the
+    /// environment's home directory mounted read-only.
+    ReadOnlyHome(&'a RunnerCommand<'a>),
+    /// Starts a container in the background whose home directory is an
+    /// empty tmpfs, with no work directory mount.
+    Ephemeral,
+}
+
+/// A container listed by `docker ps`.
+struct ContainerSummary {
+    env_name: EnvironmentName,
+    /// Whether Docker reports the container's state as running.
+    running: bool,
+    /// Whether the container has the [`EPHEMERAL_LABEL`].
+    ephemeral: bool,
+}
+
 enum EnvMounts {
     BindMounts {
         host_home: HostPath,
@@ -54,9 +104,13 @@ enum EnvMounts {
 }
 
 impl Docker {
-    pub(super) fn new(program: Rc<CubicleShared>) -> Result<Self> {
-        let host_user = std::env::var("USER").context("Invalid $USER")?;
-        let (user, uids) = if host_user == "root" {
+    pub(super) fn new(program: Arc<CubicleShared>) -> Result<Self> {
+        let host_user = host_user(&program.config)?;
+        Self::with_host_user(program, host_user)
+    }
+
+    fn with_host_user(program: Arc<CubicleShared>, host_user: String) -> Result<Self> {
+        let (user, mut uids) = if host_user == "root" {
             (
                 String::from("cubicle"),
                 Uids {
@@ -67,25 +121,30 @@ impl Docker {
         } else {
             (host_user, get_uids())
         };
+        // With rootless Docker or Podman, the container's IDs may not match
+        // the host's, so these can be overridden.
+        let docker = &program.config.runners.docker;
+        if let Some(uid) = docker.uid {
+            if uid == 0 {
+                return Err(anyhow!(
+                    "Docker `uid` must not be 0: the user in the container can't be root"
+                ));
+            }
+            uids.real_user = uid;
+        }
+        if let Some(gid) = docker.gid {
+            uids.group = gid;
+        }
 
         let timezone = get_timezone();
         let locales: BTreeSet<String> = get_host_locales()
             .chain(["C.UTF-8", "en_US.UTF-8"].map(String::from))
-            .chain(program.config.docker.locales.iter().cloned())
+            .chain(program.config.runners.docker.locales.iter().cloned())
             .collect();
 
-        let mounts = if program.config.docker.bind_mounts {
-            let xdg_cache_home = match std::env::var("XDG_CACHE_HOME") {
-                Ok(path) => HostPath::try_from(path)?,
-                Err(_) => program.home.join(".cache"),
-            };
-            let home_dirs = xdg_cache_home.join("cubicle").join("home");
-
-            let xdg_data_home = match std::env::var("XDG_DATA_HOME") {
-                Ok(path) => HostPath::try_from(path)?,
-                Err(_) => program.home.join(".local").join("share"),
-            };
-            let work_dirs = xdg_data_home.join("cubicle").join("work");
+        let mounts = if program.config.runners.docker.bind_mounts {
+            let home_dirs = program.home_dirs.clone();
+            let work_dirs = program.data_dir.join("work");
             Mounts::BindMounts {
                 home_dirs,
                 work_dirs,
@@ -94,17 +153,15 @@ impl Docker {
             Mounts::Volumes
         };
 
-        let base_image = ImageName::new(format!("{}cubicle-base", program.config.docker.prefix));
-
         let container_home = EnvPath::try_from(String::from("/home"))
             .unwrap()
             .join(&user);
 
-        if let Some(path) = &program.config.docker.seccomp {
+        for path in docker.seccomp.iter().chain(docker.seccomps.values()) {
             // Better give an early error message if this isn't configured right.
             std::fs::metadata(path)
                 .with_context(|| format!("could not read Docker seccomp policy: {path:?}"))?;
-        };
+        }
 
         Ok(Self {
             program,
@@ -113,7 +170,6 @@ impl Docker {
             timezone,
             locales,
             mounts,
-            base_image,
             container_home,
         })
     }
@@ -121,11 +177,19 @@ impl Docker {
     fn container_from_environment(&self, env: &EnvironmentName) -> ContainerName {
         ContainerName::new(format!(
             "{}{}",
-            self.program.config.docker.prefix,
+            self.program.config.runners.docker.prefix,
             env.as_str()
         ))
     }
 
+    fn network_from_workspace(&self, workspace: &WorkspaceName) -> NetworkName {
+        NetworkName::new(format!(
+            "{}workspace-{}",
+            self.program.config.runners.docker.prefix,
+            workspace.as_str()
+        ))
+    }
+
     fn mounts(&self, env: &EnvironmentName) -> EnvMounts {
         match &self.mounts {
             Mounts::BindMounts {
@@ -142,12 +206,12 @@ impl Docker {
             Mounts::Volumes => EnvMounts::Volumes {
                 home_volume: VolumeName::new(format!(
                     "{}{}-home",
-                    self.program.config.docker.prefix,
+                    self.program.config.runners.docker.prefix,
                     env.as_str()
                 )),
                 work_volume: VolumeName::new(format!(
                     "{}{}-work",
-                    self.program.config.docker.prefix,
+                    self.program.config.runners.docker.prefix,
                     env.as_str()
                 )),
             },
@@ -160,7 +224,7 @@ impl Docker {
     }
 
     fn is_container_(&self, name: &ContainerName) -> Result<bool> {
-        let status = Command::new("docker")
+        let status = Command::query("docker")
             .arg("inspect")
             .args(["--type", "container"])
             .args(["--format", "{{ .Name }}"])
@@ -175,13 +239,64 @@ impl Docker {
         }
     }
 
+    fn is_network(&self, name: &NetworkName) -> Result<bool> {
+        self.is_network_(name)
+            .with_context(|| format!("failed to check if {name} is an existing Docker network"))
+    }
+
+    fn is_network_(&self, name: &NetworkName) -> Result<bool> {
+        let status = Command::query("docker")
+            .args(["network", "inspect"])
+            .args(["--format", "{{ .Name }}"])
+            .arg(name.encoded())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .status()?;
+        match status.code() {
+            Some(0) => Ok(true),
+            Some(1) => Ok(false),
+            _ => Err(anyhow!("`docker network inspect ...` exited with {status}")),
+        }
+    }
+
+    /// Creates the workspace's network if it doesn't already exist.
+    fn ensure_network(&self, name: &NetworkName) -> Result<()> {
+        if self.is_network(name)? {
+            return Ok(());
+        }
+        let status = Command::new("docker")
+            .args(["network", "create"])
+            .arg(name.encoded())
+            .stdout(Stdio::null())
+            .status()
+            .with_context(|| format!("failed to create Docker network {name}"))?;
+        if !status.success() {
+            return Err(anyhow!(
+                "failed to create Docker network {name}: `docker network create` exited with {status}"
+            ));
+        }
+        Ok(())
+    }
+
     fn ps(&self) -> Result<Vec<EnvironmentName>> {
-        self.ps_().context("failed to list Docker containers")
+        Ok(self
+            .ps_()
+            .context("failed to list Docker containers")?
+            .into_iter()
+            .map(|container| container.env_name)
+            .collect())
     }
 
-    fn ps_(&self) -> LowLevelResult<Vec<EnvironmentName>> {
-        let output = Command::new("docker")
-            .args(["ps", "--all", "--format", "{{ .Names }}"])
+    fn ps_(&self) -> LowLevelResult<Vec<ContainerSummary>> {
+        let output = Command::query("docker")
+            .args([
+                "ps",
+                "--all",
+                "--format",
+                &format!(
+                    "{{{{ .Names }}}}\t{{{{ .State }}}}\t{{{{ .Label \"{EPHEMERAL_LABEL}\" }}}}"
+                ),
+            ])
             .output()?;
         let status = output.status;
         if !status.success() {
@@ -193,41 +308,138 @@ impl Docker {
             .into());
         }
 
-        let mut envs = Vec::new();
+        let mut containers = Vec::new();
         for line in output.stdout.lines() {
             let line = line.context("could not read `docker ps` output")?;
-            if let Some(container_name) = ContainerName::decode(&line) {
+            let mut fields = line.split('\t');
+            let name = fields.next().unwrap_or_default();
+            let state = fields.next().unwrap_or_default();
+            let ephemeral = fields.next().unwrap_or_default();
+            if let Some(container_name) = ContainerName::decode(name) {
                 if let Some(name) = container_name
                     .decoded()
-                    .strip_prefix(&self.program.config.docker.prefix)
+                    .strip_prefix(&self.program.config.runners.docker.prefix)
                 {
-                    if let Ok(env) = EnvironmentName::from_str(name) {
-                        envs.push(env);
+                    if let Ok(env_name) = EnvironmentName::from_str(name) {
+                        containers.push(ContainerSummary {
+                            env_name,
+                            running: state == "running",
+                            ephemeral: !ephemeral.is_empty(),
+                        });
                     }
                 }
             }
         }
-        Ok(envs)
+        Ok(containers)
     }
 
-    fn build_base(&self, debian_packages: &[String]) -> LowLevelResult<()> {
-        let mut child = Command::new("docker")
-            .args(["build", "--tag", &self.base_image.encoded(), "-"])
-            .stdin(Stdio::piped())
-            .scoped_spawn()?;
+    /// Returns the containers that belong to no environment, for
+    /// [`Runner::orphans`].
+    ///
+    /// Unlike [`Docker::clean_orphan_containers`], this includes running
+    /// containers, since the user decides whether to remove them.
+    fn orphan_containers(&self, containers: Vec<ContainerSummary>) -> Result<Vec<Orphan>> {
+        let mut orphans = Vec::new();
+        for container in containers {
+            // Ephemeral containers never have a home or work directory.
+            if container.ephemeral {
+                continue;
+            }
+            if self.home_and_work_exist(&container.env_name)? == (false, false) {
+                orphans.push(Orphan::Container {
+                    container: self
+                        .container_from_environment(&container.env_name)
+                        .to_string(),
+                    env_name: container.env_name,
+                });
+            }
+        }
+        Ok(orphans)
+    }
+
+    /// Removes the containers that belong to no environment, for
+    /// [`Runner::clean_orphans`].
+    fn clean_orphan_containers(&self, containers: Vec<ContainerSummary>) -> Result<Vec<String>> {
+        let mut cleaned = Vec::new();
+        for container in containers {
+            // Ephemeral containers never have a home or work directory. A
+            // running container may be in use, even if its directories are
+            // gone, so it's left for `cub purge` or `cub stop` to remove.
+            if container.ephemeral || container.running {
+                continue;
+            }
+            // Otherwise, a container whose home and work directories are
+            // both gone belongs to no environment.
+            if self.home_and_work_exist(&container.env_name)? == (false, false) {
+                self.remove_container(&container.env_name)?;
+                cleaned.push(format!(
+                    "Docker container {} without an environment",
+                    self.container_from_environment(&container.env_name)
+                ));
+            }
+        }
+        Ok(cleaned)
+    }
+
+    /// Returns the platform that the environment's container runs on.
+    fn platform(&self, env: &EnvironmentName) -> Platform {
+        Platform::for_environment(&self.program.config, env)
+            .expect("Docker platforms should have been validated with the config")
+    }
+
+    /// Returns the name of the base image for the environment and the name of
+    /// the image that it's built from.
+    ///
+    /// Environments using the default image from the config share one base
+    /// image. Others get a base image per source image. Environments for
+    /// other platforms get a base image per platform, too.
+    fn base_image(&self, env: &EnvironmentName) -> (ImageName, &str) {
+        let config = &self.program.config.runners.docker;
+        let from = config.image_for(env);
+        let mut name = if from == config.image {
+            format!("{}cubicle-base", config.prefix)
+        } else {
+            format!("{}cubicle-base-{from}", config.prefix)
+        };
+        if let Some(platform) = self.platform(env).docker() {
+            name.push('@');
+            name.push_str(platform);
+        }
+        (ImageName::new(name), from)
+    }
+
+    fn build_base(&self, env: &EnvironmentName, distro_packages: &[String]) -> LowLevelResult<()> {
+        let (base_image, from) = self.base_image(env);
+        let distro = self.program.config.runners.docker.distro_for(env);
+        let append = match &self.program.config.runners.docker.dockerfile_append {
+            Some(path) => Some(
+                std::fs::read_to_string(path)
+                    .with_context(|| format!("failed to read Dockerfile fragment {path:?}"))?,
+            ),
+            None => None,
+        };
+        let mut command = Command::new("docker");
+        command.args(["build", "--tag", &base_image.encoded()]);
+        if let Some(platform) = self.platform(env).docker() {
+            command.args(["--platform", platform]);
+        }
+        let mut child = command.arg("-").stdin(Stdio::piped()).scoped_spawn()?;
 
         {
             let mut stdin = child.stdin().take().unwrap();
-            let mut packages: BTreeSet<&str> = BASE_PACKAGES.iter().copied().collect();
-            packages.extend(debian_packages.iter().map(String::as_str));
+            let mut packages: BTreeSet<&str> = base_packages(distro).iter().copied().collect();
+            packages.extend(distro_packages.iter().map(String::as_str));
             write_dockerfile(
                 &mut stdin,
                 DockerfileArgs {
+                    from,
+                    distro,
                     packages: &packages,
                     timezone: &self.timezone,
                     locales: &self.locales,
                     user: &self.user,
                     uids: &self.uids,
+                    append: append.as_deref(),
                 },
             )
             .and_then(|_| stdin.flush())
@@ -241,21 +453,204 @@ impl Docker {
         Ok(())
     }
 
-    fn spawn(&self, env_name: &EnvironmentName) -> LowLevelResult<()> {
+    /// Returns the name of the image with the OS packages that only this
+    /// environment needs, layered on its base image.
+    fn env_image(&self, env: &EnvironmentName) -> ImageName {
+        ImageName::new(format!(
+            "{}cubicle-env-{}",
+            self.program.config.runners.docker.prefix,
+            env.as_str()
+        ))
+    }
+
+    /// Returns the image to run the environment's containers from: its own
+    /// layer, if it has one, or else its base image.
+    fn run_image(&self, env: &EnvironmentName) -> Result<ImageName> {
+        let image = self.env_image(env);
+        if self.image_size(&image.encoded())?.is_some() {
+            return Ok(image);
+        }
+        Ok(self.base_image(env).0)
+    }
+
+    /// Builds the environment's own image layer with the given OS packages
+    /// on top of its base image, or removes the layer if there are none.
+    fn build_env_layer(&self, env: &EnvironmentName, distro_packages: &[String]) -> Result<()> {
+        let image = self.env_image(env);
+        if distro_packages.is_empty() {
+            return self.remove_env_layer(env);
+        }
+        let (base_image, _) = self.base_image(env);
+        let distro = self.program.config.runners.docker.distro_for(env);
+        let mut command = Command::new("docker");
+        command.args(["build", "--tag", &image.encoded()]);
+        if let Some(platform) = self.platform(env).docker() {
+            command.args(["--platform", platform]);
+        }
+        let mut child = command.arg("-").stdin(Stdio::piped()).scoped_spawn()?;
+        {
+            let mut stdin = child.stdin().take().unwrap();
+            let packages: BTreeSet<&str> = distro_packages.iter().map(String::as_str).collect();
+            write_layer_dockerfile(&mut stdin, &base_image.encoded(), distro, &packages)
+                .and_then(|_| stdin.flush())
+                .context("failed to write Dockerfile for environment image")?;
+        }
+        let status = child.wait()?;
+        if !status.success() {
+            return Err(anyhow!("`docker build` exited with {status}"));
+        }
+        Ok(())
+    }
+
+    /// Removes the environment's own image layer, if it has one.
+    fn remove_env_layer(&self, env: &EnvironmentName) -> Result<()> {
+        let image = self.env_image(env);
+        if self.image_size(&image.encoded())?.is_none() {
+            return Ok(());
+        }
+        let status = Command::new("docker")
+            .args(["image", "rm", "--force", &image.encoded()])
+            .stdout(Stdio::null())
+            .status()?;
+        if !status.success() {
+            return Err(anyhow!(
+                "failed to remove Docker image {image}: `docker image rm` exited with {status}"
+            ));
+        }
+        Ok(())
+    }
+
+    fn checkpoint_volume(&self, env: &EnvironmentName) -> VolumeName {
+        VolumeName::new(format!(
+            "{}{}-checkpoint",
+            self.program.config.runners.docker.prefix,
+            env.as_str()
+        ))
+    }
+
+    fn remove_container(&self, env_name: &EnvironmentName) -> Result<()> {
+        let container_name = self.container_from_environment(env_name);
+        let do_stop = || {
+            let status = Command::new("docker")
+                .args(["rm", "--force", &container_name.encoded()])
+                .stdout(Stdio::null())
+                .status()?;
+            if !status.success() {
+                return Err(anyhow!("`docker rm` exited with {status}"));
+            }
+            Ok(())
+        };
+        do_stop().with_context(|| format!("failed to remove Docker container {container_name}"))
+    }
+
+    /// Returns whether the environment's home and work directories exist.
+    fn home_and_work_exist(&self, env_name: &EnvironmentName) -> Result<(bool, bool)> {
+        match &self.mounts(env_name) {
+            EnvMounts::BindMounts {
+                host_home,
+                host_work,
+            } => Ok((
+                try_exists(host_home).todo_context()?,
+                try_exists(host_work).todo_context()?,
+            )),
+
+            EnvMounts::Volumes {
+                home_volume,
+                work_volume,
+            } => Ok((
+                self.volume_exists(home_volume)?,
+                self.volume_exists(work_volume)?,
+            )),
+        }
+    }
+
+    /// Starts a container for an existing environment that doesn't have one,
+    /// restoring its processes from a checkpoint if one exists.
+    fn restart(&self, env_name: &EnvironmentName) -> Result<()> {
+        let container_name = self.container_from_environment(env_name);
+        let volume = self.checkpoint_volume(env_name);
+        match self.volume_mountpoint(&volume)? {
+            Some(checkpoint_dir) => {
+                info!("Restoring processes in {env_name} from checkpoint");
+                self.spawn(env_name, SpawnMode::Checkpoint(&checkpoint_dir))
+                    .with_context(|| {
+                        format!(
+                            "failed to restore Docker container {container_name} from checkpoint"
+                        )
+                    })?;
+                self.ensure_no_volume(&volume)
+            }
+            None => self
+                .spawn(env_name, SpawnMode::Detached)
+                .with_context(|| format!("failed to start Docker container {container_name}")),
+        }
+    }
+
+    /// Creates and starts a container for the environment.
+    fn spawn(&self, env_name: &EnvironmentName, mode: SpawnMode) -> LowLevelResult<()> {
         let container_name = self.container_from_environment(env_name);
 
         let mut command = Command::new("docker");
-        command.arg("run");
-        command.arg("--detach");
+        match mode {
+            SpawnMode::Detached => {
+                command.arg("run");
+                command.arg("--detach");
+            }
+            SpawnMode::Ephemeral => {
+                command.arg("run");
+                command.arg("--detach");
+                command.args(["--label", &format!("{EPHEMERAL_LABEL}=1")]);
+            }
+            SpawnMode::Checkpoint(_) => {
+                command.arg("create");
+            }
+            SpawnMode::ReadOnlyHome(run_command) => {
+                command.arg("run");
+                self.session_args(&mut command, env_name, run_command, has_terminal());
+            }
+        }
         command.args(["--env", &format!("CUBICLE={}", env_name.as_str())]);
+        let work_dir_name = self.program.config.work_dir_name(env_name);
+        command.args(["--env", &format!("CUBICLE_WORK_DIR={work_dir_name}")]);
         command.arg("--init");
-        command.args(["--name", &container_name.encoded()]);
+        // The temporary container for a read-only home is left unnamed so
+        // that it doesn't conflict with the environment's usual container.
+        if !matches!(mode, SpawnMode::ReadOnlyHome(_)) {
+            command.args(["--name", &container_name.encoded()]);
+        }
+        if let Some(platform) = self.platform(env_name).docker() {
+            command.args(["--platform", platform]);
+        }
         command.arg("--rm");
-        if let Some(seccomp_json) = &self.program.config.docker.seccomp {
+        let nested = self.program.config.nested_containers_for(env_name);
+        let podman = nested == Some(NestedContainers::Podman);
+        let docker_config = &self.program.config.runners.docker;
+        // Rootless Podman needs to create user namespaces and mount
+        // filesystems, which the default confinement blocks. Explicit
+        // settings still take precedence.
+        if let Some(seccomp_json) = docker_config.seccomp_for(env_name) {
             command.args([
                 "--security-opt",
                 &format!("seccomp={}", seccomp_json.display()),
             ]);
+        } else if podman {
+            command.args(["--security-opt", "seccomp=unconfined"]);
+        }
+        if let Some(profile) = &docker_config.apparmor {
+            command.args(["--security-opt", &format!("apparmor={profile}")]);
+        } else if podman {
+            command.args(["--security-opt", "apparmor=unconfined"]);
+        }
+        for label in &docker_config.selinux_labels {
+            command.args(["--security-opt", &format!("label={label}")]);
+        }
+        if podman {
+            if docker_config.selinux_labels.is_empty() {
+                command.args(["--security-opt", "label=disable"]);
+            }
+            // Lets Podman mount `/proc` and `/sys` for its containers.
+            command.args(["--security-opt", "systempaths=unconfined"]);
+            command.args(["--device", "/dev/fuse"]);
         }
         // The default `/dev/shm` is limited to only 64 MiB under
         // Docker (v20.10.5), which causes many crashes in Chromium
@@ -263,98 +658,254 @@ impl Docker {
         // <https://github.com/ongardie/cubicle/issues/3>.
         command.args(["--shm-size", &1_000_000_000.to_string()]);
         command.args(["--user", &self.user]);
+        if let Some(userns) = &self.program.config.runners.docker.userns {
+            command.args(["--userns", userns]);
+        }
 
         command.args(["--volume", "/tmp/.X11-unix:/tmp/.X11-unix:ro"]);
+        // Nix packages are symlinks into the host's store.
+        if self.program.config.nix.is_some() && Path::new("/nix/store").exists() {
+            command.args(["--volume", "/nix/store:/nix/store:ro"]);
+        }
 
-        let container_home_str = self
-            .container_home
-            .as_env_raw()
-            .to_str()
-            .ok_or_else(|| anyhow!("path not valid UTF-8: {:#?}", self.program.home))?;
+        let mut sockets: Vec<HostSocket> = host_wayland_socket().into_iter().collect();
+        if self.program.config.audio_enabled(env_name) {
+            sockets.extend(host_audio_sockets());
+        }
+        sockets.extend(env_clipboard_share(&self.program, env_name)?);
+        sockets.extend(env_host_commands_share(&self.program, env_name)?);
+        if nested == Some(NestedContainers::HostSocket) {
+            if let Some(socket) = host_container_socket() {
+                // The socket is usually only accessible to a group, like
+                // `docker`, that the user inside the container isn't in.
+                if let Ok(metadata) = std::fs::metadata(socket.host_path.as_host_raw()) {
+                    command.args(["--group-add", &metadata.gid().to_string()]);
+                }
+                sockets.push(socket);
+            }
+        }
+        for HostSocket {
+            host_path,
+            env_path,
+            env_var: (var, value),
+        } in sockets
+        {
+            command
+                .arg("--volume")
+                .arg(os_concat(&[&host_path.as_host_raw(), &":", &env_path]));
+            command.args(["--env", &format!("{var}={value}")]);
+        }
+
+        let shared = shared_mount(&self.program)?;
+        command.arg("--volume").arg(os_concat(&[
+            &shared.as_host_raw(),
+            &":",
+            &SHARED_ENV_PATH,
+            &":ro",
+        ]));
+
+        if self.program.network_disabled(env_name) {
+            command.args(["--network", "none"]);
+        }
+        if let (Some(workspace), Some(settings)) = (
+            env_name.workspace(),
+            self.program.config.workspace_for(env_name),
+        ) {
+            if settings.network {
+                let network = self.network_from_workspace(&workspace);
+                self.ensure_network(&network)?;
+                command.args(["--network", &network.encoded()]);
+                command.args(["--network-alias", env_name.name_in_workspace()]);
+            }
+            for (var, value) in &settings.env {
+                command.args(["--env", &format!("{var}={value}")]);
+            }
+            for mount in &settings.mounts {
+                command.arg("--mount").arg(os_concat(&[
+                    &r#""type=bind","#,
+                    &csv_field("source", mount.source.as_os_str()),
+                    &",",
+                    &csv_field("target", mount.target.as_os_str()),
+                    &if mount.read_only {
+                        r#","readonly""#
+                    } else {
+                        ""
+                    },
+                ]));
+            }
+        }
+
+        if self.program.config.gpu_enabled(env_name) {
+            command.args(["--gpus", "all"]);
+        }
+        let mut device_groups = BTreeSet::new();
+        for device in host_devices(&self.program.config, env_name) {
+            // Devices like `/dev/kvm` are usually only accessible to a group
+            // that the user inside the container isn't in.
+            if let Ok(metadata) = std::fs::metadata(&device) {
+                device_groups.insert(metadata.gid());
+            }
+            command.arg("--device").arg(device);
+        }
+        for gid in device_groups {
+            command.args(["--group-add", &gid.to_string()]);
+        }
+        if let Some(cpus) = self.program.config.cpus {
+            command.args(["--cpus", &cpus.to_string()]);
+        }
+        if let Some(memory) = self.program.config.memory {
+            command.args(["--memory", &memory.to_string()]);
+        }
 
-        let container_work = self.container_home.join("w");
-        let container_work_str = container_work
-            .as_env_raw()
-            .to_str()
-            .ok_or_else(|| anyhow!("path not valid UTF-8: {:#?}", container_work))?;
+        let container_home = self.container_home.as_env_raw();
+        let container_work = self.container_home.join(work_dir_name);
 
+        let home_options = if matches!(mode, SpawnMode::ReadOnlyHome(_)) {
+            r#","readonly""#
+        } else {
+            ""
+        };
         match &self.mounts(env_name) {
+            _ if matches!(mode, SpawnMode::Ephemeral) => {
+                command.arg("--tmpfs").arg(os_concat(&[
+                    &container_home,
+                    &format!(
+                        ":exec,mode=0755,uid={},gid={}",
+                        self.uids.real_user, self.uids.group
+                    ),
+                ]));
+            }
+
             EnvMounts::BindMounts {
                 host_home,
                 host_work,
             } => {
-                command.args([
-                    "--mount",
-                    &format!(
-                        r#""type=bind","source={}","target={}""#,
-                        host_home
-                            .as_host_raw()
-                            .to_str()
-                            .ok_or_else(|| anyhow!("path not valid UTF-8: {:#?}", host_home))?,
-                        container_home_str,
-                    ),
-                ]);
-                command.args([
-                    "--mount",
-                    &format!(
-                        r#""type=bind","source={}","target={}""#,
-                        host_work
-                            .as_host_raw()
-                            .to_str()
-                            .ok_or_else(|| anyhow!("path not valid UTF-8: {:#?}", host_work))?,
-                        container_work_str,
-                    ),
-                ]);
+                command.arg("--mount").arg(os_concat(&[
+                    &r#""type=bind","#,
+                    &csv_field("source", host_home.as_host_raw().as_os_str()),
+                    &",",
+                    &csv_field("target", container_home.as_os_str()),
+                    &home_options,
+                ]));
+                command.arg("--mount").arg(os_concat(&[
+                    &r#""type=bind","#,
+                    &csv_field("source", host_work.as_host_raw().as_os_str()),
+                    &",",
+                    &csv_field("target", container_work.as_env_raw().as_os_str()),
+                ]));
             }
 
             EnvMounts::Volumes {
                 home_volume,
                 work_volume,
             } => {
-                command.args([
-                    "--mount",
-                    &format!(
-                        r#""type=volume","source={}","target={}""#,
-                        home_volume.encoded(),
-                        container_home_str,
-                    ),
-                ]);
-                command.args([
-                    "--mount",
-                    &format!(
-                        r#""type=volume","source={}","target={}""#,
-                        work_volume.encoded(),
-                        container_work_str,
-                    ),
-                ]);
+                command.arg("--mount").arg(os_concat(&[
+                    &format!(r#""type=volume","source={}","#, home_volume.encoded()),
+                    &csv_field("target", container_home.as_os_str()),
+                    &home_options,
+                ]));
+                command.arg("--mount").arg(os_concat(&[
+                    &format!(r#""type=volume","source={}","#, work_volume.encoded()),
+                    &csv_field("target", container_work.as_env_raw().as_os_str()),
+                ]));
             }
         }
 
-        command.arg("--workdir").arg(container_work.as_env_raw());
-        command.arg(self.base_image.encoded());
-        command.args(["sleep", "90d"]);
+        let start_dir = match mode {
+            SpawnMode::ReadOnlyHome(run_command) => match run_command.dir() {
+                Some(dir) => container_work.join(dir),
+                None => container_work.clone(),
+            },
+            _ => container_work.clone(),
+        };
+        command.arg("--workdir").arg(start_dir.as_env_raw());
+        command.arg(self.run_image(env_name)?.encoded());
+        if let SpawnMode::ReadOnlyHome(run_command) = mode {
+            // TMPDIR normally points into the home directory.
+            command.args(["--env", "TMPDIR=/tmp"]);
+            Self::shell_args(&mut command, &self.program.shell, run_command);
+            let status = command.status()?;
+            if !status.success() {
+                return Err(ExitStatusError::new(status, "docker run").into());
+            }
+            return Ok(());
+        }
+        command.args(self.keep_alive_command());
         command.stdout(Stdio::null());
         let status = command.status()?;
-        if status.success() {
-            Ok(())
-        } else {
-            Err(ExitStatusError::new(status, "docker run").into())
+        if !status.success() {
+            return Err(ExitStatusError::new(
+                status,
+                if matches!(mode, SpawnMode::Checkpoint(_)) {
+                    "docker create"
+                } else {
+                    "docker run"
+                },
+            )
+            .into());
+        }
+
+        if let SpawnMode::Checkpoint(checkpoint_dir) = mode {
+            let status = Command::new("docker")
+                .arg("start")
+                .arg("--checkpoint-dir")
+                .arg(checkpoint_dir.as_host_raw())
+                .args(["--checkpoint", CHECKPOINT_NAME])
+                .arg(container_name.encoded())
+                .stdout(Stdio::null())
+                .status()?;
+            if !status.success() {
+                return Err(ExitStatusError::new(status, "docker start --checkpoint").into());
+            }
+            return Ok(());
+        }
+
+        // The base image only creates `~/w` ahead of time, so Docker creates
+        // any other work directory mount point as root. See the similar
+        // comment in `write_dockerfile`.
+        if matches!(self.mounts, Mounts::Volumes)
+            && work_dir_name != DEFAULT_WORK_DIR
+            && !matches!(mode, SpawnMode::Ephemeral)
+        {
+            let status = Command::new("docker")
+                .arg("exec")
+                .args(["--user", "root"])
+                .arg(container_name.encoded())
+                .arg("chown")
+                .arg(format!("{}:{}", self.user, self.user))
+                .arg(container_work.as_env_raw())
+                .status()?;
+            if !status.success() {
+                return Err(ExitStatusError::new(status, "docker exec ... chown").into());
+            }
         }
+        Ok(())
     }
 
     fn init(
         &self,
         env_name: &EnvironmentName,
         Init {
-            debian_packages,
+            distro_packages,
+            env_distro_packages,
             env_vars,
             seeds,
         }: &Init,
+        mode: SpawnMode,
     ) -> Result<()> {
         let container_name = self.container_from_environment(env_name);
-        self.build_base(debian_packages)
-            .with_context(|| format!("failed to build {} Docker image", self.base_image))?;
-        self.spawn(env_name)
+        self.build_base(env_name, distro_packages)
+            .with_context(|| {
+                format!(
+                    "failed to build {} Docker image",
+                    self.base_image(env_name).0
+                )
+            })?;
+        self.build_env_layer(env_name, env_distro_packages)
+            .with_context(|| {
+                format!("failed to build {} Docker image", self.env_image(env_name))
+            })?;
+        self.spawn(env_name, mode)
             .with_context(|| format!("failed to start Docker container {container_name}"))?;
 
         let script_path = "../.cubicle-init";
@@ -389,17 +940,64 @@ impl Docker {
             format!("failed to copy init script into Docker container {container_name}")
         })?;
 
-        self.copy_seeds(&container_name, seeds).with_context(|| {
-            format!("failed to copy package seeds into Docker container {container_name}")
-        })?;
+        // With bind mounts, package files can be copied into the home
+        // directory on the host, if they'll have the right owner there.
+        let extract_seeds = match self.mounts(env_name) {
+            EnvMounts::BindMounts { host_home, .. }
+                if !matches!(mode, SpawnMode::Ephemeral)
+                    && self.uids.real_user == get_uids().real_user =>
+            {
+                seed_store::populate(&self.program, &host_home, seeds)
+            }
+            _ => seeds,
+        };
+        self.copy_seeds(&container_name, extract_seeds)
+            .with_context(|| {
+                format!("failed to copy package seeds into Docker container {container_name}")
+            })?;
+        if !seeds.is_empty() {
+            self.program.progress(Event::SeedCopied {
+                environment: env_name,
+            });
+        }
 
         self.run_(
             env_name,
             &RunnerCommand::Exec {
                 command: &[script_path.to_owned()],
                 env_vars,
+                dir: None,
             },
         )
+        .and_then(|status| ExitStatusError::check(status, "docker exec"))
+    }
+
+    /// Returns the size in bytes of the given image, or `None` if it doesn't
+    /// exist. Images may share layers, so sizes may add up to more than the
+    /// space they use.
+    fn image_size(&self, image: &str) -> Result<Option<u64>> {
+        let output = Command::query("docker")
+            .args(["image", "inspect", "--format", "{{ .Size }}", image])
+            .output()?;
+        if !output.status.success() {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            // Docker says "No such image" and Podman says "image not known".
+            let lower = stderr.to_lowercase();
+            if lower.contains("no such image") || lower.contains("image not known") {
+                return Ok(None);
+            }
+            return Err(anyhow!(
+                "`docker image inspect` exited with {} and stderr: {}",
+                output.status,
+                stderr.trim()
+            ));
+        }
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        stdout
+            .trim()
+            .parse::<u64>()
+            .map(Some)
+            .with_context(|| format!("unexpected output from `docker image inspect`: {stdout:?}"))
     }
 
     fn list_volumes(&self) -> Result<Vec<VolumeName>> {
@@ -408,7 +1006,7 @@ impl Docker {
     }
 
     fn list_volumes_(&self) -> LowLevelResult<Vec<VolumeName>> {
-        let output = Command::new("docker")
+        let output = Command::query("docker")
             .args(["volume", "ls", "--format", "{{ .Name }}"])
             .output()?;
         let status = output.status;
@@ -443,7 +1041,7 @@ impl Docker {
     }
 
     fn volume_mountpoint_(&self, name: &VolumeName) -> LowLevelResult<Option<HostPath>> {
-        let output = Command::new("docker")
+        let output = Command::query("docker")
             .arg("volume")
             .arg("inspect")
             .args(["--format", "{{ .Mountpoint }}"])
@@ -617,46 +1215,112 @@ impl Docker {
         Ok(())
     }
 
-    fn copy_seeds(
+    /// Returns a command that runs `tar` with the given arguments in a
+    /// temporary container, as the environment's user. The container mounts
+    /// the environment's volumes as the environment does, relative to its
+    /// working directory.
+    fn tar_in_volumes(
         &self,
-        container_name: &ContainerName,
-        seeds: &Vec<HostPath>,
-    ) -> LowLevelResult<()> {
-        if seeds.is_empty() {
-            return Ok(());
-        }
-        println!("Copying/extracting seed tarball");
-
-        // Use pv from inside the container since it may not be
-        // installed on the host. Since it's reading from a stream, it
-        // needs to know the total size to display a good progress bar.
-        #[cfg(not(unix))]
-        let size: Option<u64> = None;
-        #[cfg(unix)]
-        let size: Option<u64> = Some({
-            let mut size: u64 = 0;
-            for path in seeds {
-                use std::os::unix::fs::MetadataExt;
-                let metadata = std::fs::metadata(path.as_host_raw()).todo_context()?;
-                size += metadata.size();
-            }
-            size
-        });
-
-        let mut child = Command::new("docker")
-            .arg("exec")
+        env_name: &EnvironmentName,
+        home_volume: &VolumeName,
+        work_volume: &VolumeName,
+        args: Vec<OsString>,
+    ) -> Command {
+        let work_dir_name = self.program.config.work_dir_name(env_name);
+        let mut command = Command::new("docker");
+        command
+            .arg("run")
+            .arg("--interactive")
+            .arg("--mount")
+            .arg(format!(
+                r#""type=volume","source={}","target=/v""#,
+                home_volume.encoded()
+            ))
+            .arg("--mount")
+            .arg(format!(
+                r#""type=volume","source={}","target=/v/{work_dir_name}""#,
+                work_volume.encoded()
+            ))
+            .arg("--rm")
+            .args([
+                "--user",
+                &format!("{}:{}", self.uids.real_user, self.uids.group),
+            ])
+            .args(["--workdir", "/v"])
+            .arg("debian:12")
+            .arg("tar")
+            .args(args);
+        command
+    }
+
+    fn copy_out_of_volumes(
+        &self,
+        env_name: &EnvironmentName,
+        home_volume: &VolumeName,
+        work_volume: &VolumeName,
+        path: &Path,
+        w: &mut dyn io::Write,
+    ) -> Result<()> {
+        let mut child = self
+            .tar_in_volumes(env_name, home_volume, work_volume, tar_create_args(path)?)
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .scoped_spawn()?;
+        let mut stdout = child.stdout().take().unwrap();
+        io::copy(&mut stdout, w).context("error reading/writing data")?;
+        let status = child.wait()?;
+        if !status.success() {
+            return Err(anyhow!("`docker run ... tar` exited with {status}"));
+        }
+        Ok(())
+    }
+
+    fn copy_into_volumes(
+        &self,
+        env_name: &EnvironmentName,
+        home_volume: &VolumeName,
+        work_volume: &VolumeName,
+        dir: &Path,
+        r: &mut dyn io::Read,
+    ) -> Result<()> {
+        let mut child = self
+            .tar_in_volumes(env_name, home_volume, work_volume, tar_extract_args(dir))
+            .stdin(Stdio::piped())
+            .scoped_spawn()?;
+        let mut stdin = child.stdin().take().unwrap();
+        io::copy(r, &mut stdin).context("error reading/writing data")?;
+        drop(stdin);
+        let status = child.wait()?;
+        if !status.success() {
+            return Err(anyhow!("`docker run ... tar` exited with {status}"));
+        }
+        Ok(())
+    }
+
+    fn copy_seeds(&self, container_name: &ContainerName, seeds: &[HostPath]) -> LowLevelResult<()> {
+        if seeds.is_empty() {
+            return Ok(());
+        }
+        info!("Copying/extracting seed tarball");
+
+        // Use pv from inside the container since it may not be
+        // installed on the host. Since it's reading from a stream, it
+        // needs to know the total size to display a good progress bar.
+        let mut size: u64 = 0;
+        for path in seeds {
+            size += tar_size(path)?;
+        }
+
+        let mut child = Command::new("docker")
+            .arg("exec")
             .arg("--interactive")
             .arg(container_name.encoded())
             .args([
                 "sh",
                 "-c",
                 &format!(
-                    "pv --interval 0.1 --force {} | \
-                    tar --ignore-zero --directory ~ --extract",
-                    match size {
-                        Some(size) => format!("--size {size}"),
-                        None => String::new(),
-                    },
+                    "pv --interval 0.1 --force --size {size} | \
+                    tar --ignore-zero --directory ~ --extract"
                 ),
             ])
             .stdin(Stdio::piped())
@@ -664,10 +1328,7 @@ impl Docker {
 
         {
             let mut stdin = child.stdin().take().unwrap();
-            for path in seeds {
-                let mut file = std::fs::File::open(path.as_host_raw()).todo_context()?;
-                io::copy(&mut file, &mut stdin).todo_context()?;
-            }
+            write_seeds(seeds, &mut stdin)?;
         }
 
         let status = child.wait()?;
@@ -677,16 +1338,58 @@ impl Docker {
         Ok(())
     }
 
-    fn run_(&self, env_name: &EnvironmentName, run_command: &RunnerCommand) -> Result<()> {
-        let container_name = self.container_from_environment(env_name);
-        assert!(self.is_container(&container_name)?);
+    /// Returns the command run as the main process in each container, just
+    /// to keep it running, according to
+    /// [`config::Docker::keep_alive`](crate::config::Docker::keep_alive).
+    fn keep_alive_command(&self) -> Vec<String> {
+        match self.program.config.runners.docker.keep_alive {
+            // Unlike `sleep infinity`, this works with BusyBox too.
+            None => vec![
+                String::from("tail"),
+                String::from("-f"),
+                String::from("/dev/null"),
+            ],
+            Some(duration) => vec![String::from("sleep"), duration.as_secs().max(1).to_string()],
+        }
+    }
 
-        let mut command = Command::new("docker");
-        command.arg("exec");
+    /// Stops the environment's container if
+    /// [`config::Docker::lifetime`](crate::config::Docker::lifetime) says to
+    /// once sessions end and no other processes are left running in it.
+    fn stop_if_unused(&self, env_name: &EnvironmentName) -> Result<()> {
+        if self.program.config.runners.docker.lifetime == ContainerLifetime::KeepAlive {
+            return Ok(());
+        }
+        if self.running_processes(env_name)? == Some(0) {
+            debug!("Stopping idle container for environment {env_name}");
+            self.remove_container(env_name)?;
+        }
+        Ok(())
+    }
 
-        command
-            .arg("--env")
-            .arg(fallback_path(&self.container_home));
+    /// Stops the environment's container after creating or resetting it if
+    /// [`config::Docker::lifetime`](crate::config::Docker::lifetime) is
+    /// [`ContainerLifetime::OnDemand`].
+    fn stop_if_on_demand(&self, env_name: &EnvironmentName) -> Result<()> {
+        if self.program.config.runners.docker.lifetime == ContainerLifetime::OnDemand {
+            self.remove_container(env_name)?;
+        }
+        Ok(())
+    }
+
+    /// Adds the arguments to `docker exec` or `docker run` for an interactive
+    /// session or command in the environment.
+    fn session_args(
+        &self,
+        command: &mut Command,
+        env_name: &EnvironmentName,
+        run_command: &RunnerCommand,
+        tty: bool,
+    ) {
+        command.arg("--env").arg(fallback_path(
+            &self.container_home,
+            self.program.config.runners.docker.distro_for(env_name),
+        ));
 
         for var in ["DISPLAY", "SHELL", "TERM", "USER"]
             .iter()
@@ -696,7 +1399,7 @@ impl Docker {
         }
 
         match run_command {
-            RunnerCommand::Interactive => {}
+            RunnerCommand::Interactive { .. } => {}
             RunnerCommand::Exec { env_vars, .. } => {
                 for (var, value) in *env_vars {
                     command.arg("--env").arg(format!("{}={}", var, value));
@@ -705,29 +1408,57 @@ impl Docker {
         }
 
         command.arg("--interactive");
-
-        // If we really don't have a TTY, Docker will exit with status 1 when
-        // we request one.
-        if io::stdin().is_terminal() || io::stdout().is_terminal() || io::stderr().is_terminal() {
+        if tty {
             command.arg("--tty");
         }
+    }
 
-        command.arg(container_name.encoded());
-        command.args([&self.program.shell, "-l"]);
+    /// Adds the shell command line for the session or command.
+    fn shell_args(command: &mut Command, shell: &str, run_command: &RunnerCommand) {
+        command.args([shell, "-l"]);
         match run_command {
-            RunnerCommand::Interactive => {}
+            RunnerCommand::Interactive { .. } => {}
             RunnerCommand::Exec { command: exec, .. } => {
                 command.arg("-c");
                 command.arg(shlex::try_join(exec.iter().map(|a| a.as_str())).expect("TODO"));
             }
         }
+    }
 
-        let status = command.status()?;
-        if status.success() {
-            Ok(())
-        } else {
-            Err(ExitStatusError::new(status, "docker exec").into())
+    /// Runs the command in the environment's container, starting it first if
+    /// needed, and returns the command's exit status.
+    fn run_(&self, env_name: &EnvironmentName, run_command: &RunnerCommand) -> Result<ExitStatus> {
+        self.exec_command(env_name, run_command, has_terminal())?
+            .status()
+    }
+
+    /// Returns a `docker exec` command for the environment's container,
+    /// starting the container first if needed.
+    fn exec_command(
+        &self,
+        env_name: &EnvironmentName,
+        run_command: &RunnerCommand,
+        tty: bool,
+    ) -> Result<Command> {
+        let container_name = self.container_from_environment(env_name);
+        if !self.is_container(&container_name)? {
+            self.restart(env_name)?;
+        }
+
+        let mut command = Command::new("docker");
+        command.arg("exec");
+        self.session_args(&mut command, env_name, run_command, tty);
+        if let Some(dir) = run_command.dir() {
+            let work_dir = self
+                .container_home
+                .join(self.program.config.work_dir_name(env_name));
+            command
+                .arg("--workdir")
+                .arg(work_dir.join(dir).as_env_raw());
         }
+        command.arg(container_name.encoded());
+        Self::shell_args(&mut command, &self.program.shell, run_command);
+        Ok(command)
     }
 }
 
@@ -784,6 +1515,43 @@ impl Runner for Docker {
         }
     }
 
+    fn copy_out(
+        &self,
+        env_name: &EnvironmentName,
+        path: &Path,
+        w: &mut dyn io::Write,
+    ) -> Result<()> {
+        match &self.mounts(env_name) {
+            EnvMounts::BindMounts {
+                host_home,
+                host_work,
+            } => {
+                let work_dir_name = self.program.config.work_dir_name(env_name);
+                copy_out_from_host_dirs(host_home, host_work, work_dir_name, path, w)
+            }
+            EnvMounts::Volumes {
+                home_volume,
+                work_volume,
+            } => self.copy_out_of_volumes(env_name, home_volume, work_volume, path, w),
+        }
+    }
+
+    fn copy_in(&self, env_name: &EnvironmentName, dir: &Path, r: &mut dyn io::Read) -> Result<()> {
+        match &self.mounts(env_name) {
+            EnvMounts::BindMounts {
+                host_home,
+                host_work,
+            } => {
+                let work_dir_name = self.program.config.work_dir_name(env_name);
+                copy_in_to_host_dirs(host_home, host_work, work_dir_name, dir, r)
+            }
+            EnvMounts::Volumes {
+                home_volume,
+                work_volume,
+            } => self.copy_into_volumes(env_name, home_volume, work_volume, dir, r),
+        }
+    }
+
     fn create(&self, env_name: &EnvironmentName, init: &Init) -> Result<()> {
         let container_name = self.container_from_environment(env_name);
         if self.is_container(&container_name)? {
@@ -807,56 +1575,216 @@ impl Runner for Docker {
             }
         }
 
-        self.init(env_name, init)
+        self.init(env_name, init, SpawnMode::Detached)?;
+        self.stop_if_on_demand(env_name)
     }
 
     fn exists(&self, env_name: &EnvironmentName) -> Result<EnvironmentExists> {
         let container_name = self.container_from_environment(env_name);
         let is_container = self.is_container(&container_name)?;
+        let (has_home_dir, has_work_dir) = self.home_and_work_exist(env_name)?;
 
-        let has_home_dir;
-        let has_work_dir;
-        match &self.mounts(env_name) {
+        // The container itself holds no state, so it's started again as needed
+        // (after a reboot or a `stop`, for example).
+        use EnvironmentExists::*;
+        Ok(if has_home_dir && has_work_dir {
+            FullyExists
+        } else if is_container || has_home_dir || has_work_dir {
+            PartiallyExists
+        } else {
+            NoEnvironment
+        })
+    }
+
+    fn stop(&self, env_name: &EnvironmentName) -> Result<()> {
+        self.remove_container(env_name)?;
+        self.ensure_no_volume(&self.checkpoint_volume(env_name))
+    }
+
+    fn is_running(&self, env_name: &EnvironmentName) -> Result<bool> {
+        // The container only exists while it's running: `stop` removes it.
+        self.is_container(&self.container_from_environment(env_name))
+    }
+
+    fn directory_sizes(
+        &self,
+        env_name: &EnvironmentName,
+        depth: usize,
+    ) -> Result<EnvDirectorySizes> {
+        match self.mounts(env_name) {
             EnvMounts::BindMounts {
                 host_home,
                 host_work,
             } => {
-                has_home_dir = try_exists(host_home).todo_context()?;
-                has_work_dir = try_exists(host_work).todo_context()?;
+                let mut sizes = EnvDirectorySizes::default();
+                if try_exists(&host_home).todo_context()? {
+                    sizes.home_dir =
+                        run_du(&mut Command::query("du"), depth, host_home.as_host_raw())?;
+                }
+                if try_exists(&host_work).todo_context()? {
+                    sizes.work_dir =
+                        run_du(&mut Command::query("du"), depth, host_work.as_host_raw())?;
+                }
+                Ok(sizes)
             }
 
             EnvMounts::Volumes {
                 home_volume,
                 work_volume,
             } => {
-                has_home_dir = self.volume_exists(home_volume)?;
-                has_work_dir = self.volume_exists(work_volume)?;
+                // The volumes' files may not be accessible from the host, so
+                // this runs `du` in a container.
+                let du = |volume: &VolumeName| -> Result<Vec<(PathBuf, u64)>> {
+                    if !self.volume_exists(volume)? {
+                        return Ok(Vec::new());
+                    }
+                    let mut command = Command::query("docker");
+                    command
+                        .arg("run")
+                        .arg("--mount")
+                        .arg(format!(
+                            r#""type=volume","source={}","target=/v""#,
+                            volume.encoded()
+                        ))
+                        .arg("--rm")
+                        .arg("debian:12")
+                        .arg("du");
+                    run_du(&mut command, depth, Path::new("/v"))
+                        .with_context(|| format!("failed to run `du` on Docker volume {volume}"))
+                };
+                Ok(EnvDirectorySizes {
+                    home_dir: du(&home_volume)?,
+                    work_dir: du(&work_volume)?,
+                })
             }
         }
+    }
 
-        use EnvironmentExists::*;
-        Ok(if is_container && has_home_dir && has_work_dir {
-            FullyExists
-        } else if is_container || has_home_dir || has_work_dir {
-            PartiallyExists
-        } else {
-            NoEnvironment
+    fn connect_info(&self, env_name: &EnvironmentName) -> Result<ConnectInfo> {
+        let container = self.container_from_environment(env_name).encoded();
+        let (host_home_dir, host_work_dir) = match self.mounts(env_name) {
+            EnvMounts::BindMounts {
+                host_home,
+                host_work,
+            } => (
+                Some(host_home.as_host_raw().to_owned()),
+                Some(host_work.as_host_raw().to_owned()),
+            ),
+            EnvMounts::Volumes { .. } => (None, None),
+        };
+        let work_dir = self
+            .container_home
+            .join(self.program.config.work_dir_name(env_name));
+        Ok(ConnectInfo {
+            container: Some(container.clone()),
+            user: Some(self.user.clone()),
+            home_dir: Some(self.container_home.as_env_raw().to_owned()),
+            work_dir: Some(work_dir.as_env_raw().to_owned()),
+            host_home_dir,
+            host_work_dir,
+            attach_command: Some(vec![
+                String::from("docker"),
+                String::from("exec"),
+                String::from("--interactive"),
+                String::from("--tty"),
+                String::from("--workdir"),
+                work_dir.as_env_raw().to_string_lossy().into_owned(),
+                container,
+                self.program.shell.clone(),
+                String::from("-l"),
+            ]),
         })
     }
 
-    fn stop(&self, env_name: &EnvironmentName) -> Result<()> {
-        let container_name = self.container_from_environment(env_name);
-        let do_stop = || {
-            let status = Command::new("docker")
-                .args(["rm", "--force", &container_name.encoded()])
-                .stdout(Stdio::null())
-                .status()?;
-            if !status.success() {
-                return Err(anyhow!("`docker rm` exited with {status}"));
+    fn storage_usage(&self) -> Result<Vec<(&'static str, u64)>> {
+        let envs = self.list()?;
+        let mut images = BTreeSet::new();
+        images.insert(format!(
+            "{}cubicle-base",
+            self.program.config.runners.docker.prefix
+        ));
+        let mut checkpoints = 0;
+        for env in &envs {
+            images.insert(self.base_image(env).0.encoded());
+            images.insert(self.env_image(env).encoded());
+            let volume = self.checkpoint_volume(env);
+            if self.volume_exists(&volume)? {
+                checkpoints += self.volume_du(&volume)?.total_size;
             }
-            Ok(())
-        };
-        do_stop().with_context(|| format!("failed to remove Docker container {container_name}"))
+        }
+        let mut base_images = 0;
+        for image in &images {
+            base_images += self.image_size(image)?.unwrap_or(0);
+        }
+        Ok(vec![
+            ("Docker base images", base_images),
+            ("Docker checkpoint volumes", checkpoints),
+        ])
+    }
+
+    fn running_processes(&self, env_name: &EnvironmentName) -> Result<Option<usize>> {
+        let container_name = self.container_from_environment(env_name);
+        if !self.is_container(&container_name)? {
+            return Ok(None);
+        }
+        let output = Command::query("docker")
+            .arg("top")
+            .arg(container_name.encoded())
+            .args(["-o", "args"])
+            .output()?;
+        if !output.status.success() {
+            return Err(anyhow!(
+                "`docker top` exited with {}. Output: {}",
+                output.status,
+                String::from_utf8_lossy(&output.stderr)
+            ));
+        }
+        let keep_alive = self.keep_alive_command().join(" ");
+        let count = String::from_utf8_lossy(&output.stdout)
+            .lines()
+            .skip(1) // header
+            .filter(|args| {
+                *args != keep_alive
+                    && *args != LEGACY_KEEP_ALIVE_ARGS
+                    && !args.starts_with("/sbin/docker-init")
+            })
+            .count();
+        Ok(Some(count))
+    }
+
+    fn checkpoint(&self, env_name: &EnvironmentName) -> Result<()> {
+        let container_name = self.container_from_environment(env_name);
+        if !self.is_container(&container_name)? {
+            return Err(anyhow!("Docker container {container_name} is not running"));
+        }
+
+        // Docker normally keeps checkpoints with the container, but the
+        // container is removed once it stops. A volume gives them a place to
+        // live that Docker can clean up on its own.
+        let volume = self.checkpoint_volume(env_name);
+        self.ensure_no_volume(&volume)?;
+        self.ensure_volume_exists(&volume)?;
+        let checkpoint_dir = self
+            .volume_mountpoint(&volume)?
+            .ok_or_else(|| anyhow!("Docker volume {volume} disappeared"))?;
+
+        let status = Command::new("docker")
+            .args(["checkpoint", "create"])
+            .arg("--checkpoint-dir")
+            .arg(checkpoint_dir.as_host_raw())
+            .arg(container_name.encoded())
+            .arg(CHECKPOINT_NAME)
+            .stdout(Stdio::null())
+            .status()?;
+        if !status.success() {
+            // Don't leave an incomplete checkpoint around to restore later.
+            self.ensure_no_volume(&volume)?;
+            return Err(anyhow!(
+                "`docker checkpoint create` exited with {status} \
+                (checkpoints require Docker's experimental features and CRIU)"
+            ));
+        }
+        self.remove_container(env_name)
     }
 
     fn list(&self) -> Result<Vec<EnvironmentName>> {
@@ -867,32 +1795,16 @@ impl Runner for Docker {
                 home_dirs,
                 work_dirs,
             } => {
-                for name in try_iterdir_dirs(home_dirs)? {
-                    let env = EnvironmentName::from_filename(&name).with_context(|| {
-                        format!(
-                            "error parsing environment name from path {}",
-                            home_dirs.join(&name)
-                        )
-                    })?;
-                    envs.insert(env);
-                }
+                envs.extend(env_dir_names(home_dirs)?);
 
-                for name in try_iterdir_dirs(work_dirs)? {
-                    let env = EnvironmentName::from_filename(&name).with_context(|| {
-                        format!(
-                            "error parsing environment name from path {}",
-                            work_dirs.join(&name)
-                        )
-                    })?;
-                    envs.insert(env);
-                }
+                envs.extend(env_dir_names(work_dirs)?);
             }
 
             Mounts::Volumes => {
                 for name in self.list_volumes()? {
                     if let Some(name) = name
                         .decoded()
-                        .strip_prefix(&self.program.config.docker.prefix)
+                        .strip_prefix(&self.program.config.runners.docker.prefix)
                     {
                         if let Some(env) = name.strip_suffix("-home") {
                             envs.insert(EnvironmentName::from_str(env)?);
@@ -960,11 +1872,19 @@ impl Runner for Docker {
                 self.ensure_volume_exists(home_volume)?;
             }
         }
-        self.init(name, init)
+        self.init(name, init, SpawnMode::Detached)?;
+        self.stop_if_on_demand(name)
+    }
+
+    fn refresh(&self, name: &EnvironmentName, init: &Init) -> Result<()> {
+        self.stop(name)?;
+        self.init(name, init, SpawnMode::Detached)?;
+        self.stop_if_on_demand(name)
     }
 
     fn purge(&self, name: &EnvironmentName) -> Result<()> {
         self.stop(name)?;
+        self.remove_env_layer(name)?;
         match &self.mounts(name) {
             EnvMounts::BindMounts {
                 host_home,
@@ -984,8 +1904,87 @@ impl Runner for Docker {
         }
     }
 
-    fn run(&self, env_name: &EnvironmentName, run_command: &RunnerCommand) -> Result<()> {
-        self.run_(env_name, run_command)
+    fn purge_workspace(&self, name: &WorkspaceName) -> Result<()> {
+        let network = self.network_from_workspace(name);
+        if !self.is_network(&network)? {
+            return Ok(());
+        }
+        let status = Command::new("docker")
+            .args(["network", "rm"])
+            .arg(network.encoded())
+            .stdout(Stdio::null())
+            .status()
+            .with_context(|| format!("failed to remove Docker network {network}"))?;
+        if !status.success() {
+            return Err(anyhow!(
+                "failed to remove Docker network {network}: `docker network rm` exited with {status}"
+            ));
+        }
+        Ok(())
+    }
+
+    fn run(&self, env_name: &EnvironmentName, run_command: &RunnerCommand) -> Result<ExitStatus> {
+        let status = self.run_(env_name, run_command)?;
+        self.stop_if_unused(env_name)?;
+        Ok(status)
+    }
+
+    fn run_streaming(
+        &self,
+        env_name: &EnvironmentName,
+        run_command: &RunnerCommand,
+        on_output: &mut dyn FnMut(OutputStream, &[u8]),
+    ) -> Result<ExitStatus> {
+        let status = self
+            .exec_command(env_name, run_command, false)?
+            .stream(on_output)?;
+        self.stop_if_unused(env_name)?;
+        Ok(status)
+    }
+
+    fn run_ephemeral(&self, env_name: &EnvironmentName, init: &Init) -> Result<()> {
+        let result = self
+            .init(env_name, init, SpawnMode::Ephemeral)
+            .and_then(|()| self.run_(env_name, &RunnerCommand::Interactive { dir: None }))
+            .and_then(|status| ExitStatusError::check(status, "docker exec"));
+        // The container has `--rm`, so this also discards the tmpfs.
+        let removed = self.remove_container(env_name);
+        result.and(removed)
+    }
+
+    fn run_read_only_home(
+        &self,
+        env_name: &EnvironmentName,
+        run_command: &RunnerCommand,
+    ) -> Result<()> {
+        self.spawn(env_name, SpawnMode::ReadOnlyHome(run_command))
+            .with_context(|| format!("failed to run temporary Docker container for {env_name}"))
+    }
+
+    fn query_image(&self, env_name: Option<&EnvironmentName>, script: &str) -> Result<String> {
+        let (image, platform) = match env_name {
+            Some(env_name) => (self.run_image(env_name)?, self.platform(env_name)),
+            None => (
+                ImageName::new(format!(
+                    "{}cubicle-base",
+                    self.program.config.runners.docker.prefix
+                )),
+                Platform::host(),
+            ),
+        };
+        if self.image_size(&image.encoded())?.is_none() {
+            return Err(anyhow!(
+                "Docker base image {} doesn't exist yet (Cubicle builds it when creating an environment)",
+                image.encoded()
+            ));
+        }
+        let mut command = Command::query("docker");
+        command.args(["run", "--rm", "--entrypoint", "sh"]);
+        if let Some(platform) = platform.docker() {
+            command.args(["--platform", platform]);
+        }
+        let output = command.arg(image.encoded()).args(["-c", script]).output()?;
+        query_stdout(output, "docker run")
     }
 
     fn supports_any(&self, targets: &[Target]) -> Result<bool> {
@@ -999,18 +1998,68 @@ impl Runner for Docker {
             })
         }))
     }
+
+    fn clean_orphans(&self) -> Result<Vec<String>> {
+        let containers = self.ps_().context("failed to list Docker containers")?;
+        self.clean_orphan_containers(containers)
+    }
+
+    fn orphans(&self) -> Result<Vec<Orphan>> {
+        let containers = self.ps_().context("failed to list Docker containers")?;
+        let mut orphans = self.orphan_containers(containers)?;
+        let prefix = &self.program.config.runners.docker.prefix;
+        for volume in self.list_volumes()? {
+            // Home and work volumes are the environment itself, so only
+            // checkpoint volumes can be left over.
+            let Some(env_name) = volume
+                .decoded()
+                .strip_prefix(prefix.as_str())
+                .and_then(|name| name.strip_suffix("-checkpoint"))
+                .and_then(|name| EnvironmentName::from_str(name).ok())
+            else {
+                continue;
+            };
+            if self.home_and_work_exist(&env_name)? == (false, false) {
+                orphans.push(Orphan::Volume {
+                    name: volume.decoded().to_owned(),
+                });
+            }
+        }
+        Ok(orphans)
+    }
+
+    fn remove_orphan(&self, orphan: &Orphan) -> Result<()> {
+        match orphan {
+            Orphan::Container { env_name, .. } => self.remove_container(env_name),
+            Orphan::Volume { name } => self.ensure_no_volume(&VolumeName::new(name.clone())),
+            Orphan::WorkBackup { .. } => Err(anyhow!(
+                "the Docker runner doesn't make work directory backups"
+            )),
+        }
+    }
+}
+
+/// Returns true if Cubicle has a terminal to hand to `docker exec --tty`.
+///
+/// If we really don't have a TTY, Docker will exit with status 1 when we
+/// request one.
+fn has_terminal() -> bool {
+    io::stdin().is_terminal() || io::stdout().is_terminal() || io::stderr().is_terminal()
 }
 
-fn fallback_path(container_home: &EnvPath) -> OsString {
+fn fallback_path(container_home: &EnvPath, distro: Distro) -> OsString {
     let home_bin = container_home.join("bin");
-    let paths = [
+    let mut paths = vec![
         home_bin.as_env_raw(),
-        // The debian:12 image has usrmerge, so /bin and /sbin are symlinks and
-        // do not need to be included.
+        // The debian:12 and Fedora images have usrmerge, so /bin and /sbin are
+        // symlinks and do not need to be included.
         Path::new("/usr/bin"),
         Path::new("/usr/sbin"),
     ];
-    let joined = match std::env::join_paths(paths)
+    if distro == Distro::Alpine {
+        paths.extend([Path::new("/bin"), Path::new("/sbin")]);
+    }
+    let joined = match std::env::join_paths(&paths)
         .with_context(|| format!("unable to add container home dir ({container_home:?}) to $PATH"))
     {
         Ok(joined) => joined,
@@ -1022,6 +2071,28 @@ fn fallback_path(container_home: &EnvPath) -> OsString {
     [OsStr::new("PATH="), &joined].into_iter().collect()
 }
 
+/// Concatenates the pieces into a single command-line argument.
+///
+/// Unlike `format!`, this keeps host paths that aren't valid UTF-8 intact.
+/// Docker passes them through to the kernel as bytes.
+fn os_concat(pieces: &[&dyn AsRef<OsStr>]) -> OsString {
+    pieces.iter().map(|piece| piece.as_ref()).collect()
+}
+
+/// Formats a `key=value` field for `docker run --mount`, which Docker parses
+/// as CSV. The field is quoted, with any quotes in the value doubled.
+fn csv_field(key: &str, value: &OsStr) -> OsString {
+    let mut field = Vec::from(format!("\"{key}=").as_bytes());
+    for &byte in value.as_bytes() {
+        if byte == b'"' {
+            field.push(b'"');
+        }
+        field.push(byte);
+    }
+    field.push(b'"');
+    OsString::from_vec(field)
+}
+
 fn get_host_locales() -> impl Iterator<Item = String> {
     LOCALE_ENVIRONMENT_VARIABLES.iter().flat_map(|var| {
         let Ok(value) = std::env::var(var) else {
@@ -1035,6 +2106,14 @@ fn get_host_locales() -> impl Iterator<Item = String> {
     })
 }
 
+fn base_packages(distro: Distro) -> &'static [&'static str] {
+    match distro {
+        Distro::Alpine => ALPINE_BASE_PACKAGES,
+        Distro::Debian => BASE_PACKAGES,
+        Distro::Fedora => FEDORA_BASE_PACKAGES,
+    }
+}
+
 /// Debian packages that many packages might depend on for basic functionality.
 /// They are installed in the CI system.
 const BASE_PACKAGES: &[&str] = &[
@@ -1057,19 +2136,90 @@ const BASE_PACKAGES: &[&str] = &[
     "zstd",
 ];
 
+/// Alpine equivalents of [`BASE_PACKAGES`], plus GNU tools that Cubicle uses
+/// in place of BusyBox's.
+const ALPINE_BASE_PACKAGES: &[&str] = &[
+    "bash",
+    "bzip2",
+    "ca-certificates",
+    "coreutils",
+    "curl",
+    "findutils",
+    "git",
+    "jq",
+    "lz4",
+    "procps",
+    "pv",
+    "sudo",
+    "tar",
+    "tzdata",
+    "unzip",
+    "vim",
+    "wget",
+    "xz",
+    "zip",
+    "zstd",
+];
+
+/// Fedora equivalents of [`BASE_PACKAGES`].
+const FEDORA_BASE_PACKAGES: &[&str] = &[
+    "bzip2",
+    "ca-certificates",
+    "curl",
+    "findutils",
+    "git",
+    "glibc-langpack-en",
+    "jq",
+    "lz4",
+    "procps-ng",
+    "pv",
+    "sudo",
+    "tzdata",
+    "unzip",
+    "vim-enhanced",
+    "wget",
+    "xz",
+    "zip",
+    "zstd",
+];
+
 struct DockerfileArgs<'a> {
+    from: &'a str,
+    distro: Distro,
     packages: &'a BTreeSet<&'a str>,
     locales: &'a BTreeSet<String>,
     timezone: &'a str,
     user: &'a str,
     uids: &'a Uids,
+    /// Extra instructions from the user, added at the end.
+    append: Option<&'a str>,
 }
 
 fn write_dockerfile<W: io::Write>(w: &mut W, args: DockerfileArgs) -> std::io::Result<()> {
+    let distro = args.distro;
+
+    // Fedora doesn't generate locales but has a package for each language.
+    let langpacks: BTreeSet<String> = match distro {
+        Distro::Fedora => args
+            .locales
+            .iter()
+            .filter_map(|locale| {
+                let language = locale.split(['_', '.', '@']).next()?;
+                (language.len() >= 2 && language.chars().all(|c| c.is_ascii_lowercase()))
+                    .then(|| format!("glibc-langpack-{language}"))
+            })
+            .collect(),
+        Distro::Alpine | Distro::Debian => BTreeSet::new(),
+    };
+
     // Quote all the Strings that go into the file.
     let packages: Vec<String> = args
         .packages
         .iter()
+        .copied()
+        .chain(langpacks.iter().map(String::as_str))
+        .collect::<BTreeSet<&str>>()
+        .into_iter()
         .map(|p| shlex::try_quote(p).expect("TODO").into_owned())
         .collect();
     let locales: String = {
@@ -1097,20 +2247,24 @@ fn write_dockerfile<W: io::Write>(w: &mut W, args: DockerfileArgs) -> std::io::R
         locales.push(')');
         locales
     };
+    // Validated in the config to contain no whitespace, so this can't inject
+    // other instructions.
+    let from = args.from;
     let timezone = shlex::try_quote(args.timezone).expect("TODO");
     let user = shlex::try_quote(args.user).expect("TODO");
-    let has_apt_file = args.packages.contains("apt-file");
+    let has_apt_file = distro == Distro::Debian && args.packages.contains("apt-file");
     let has_sudo = args.packages.contains("sudo");
     let uid = args.uids.real_user;
     let gid = args.uids.group;
+    let append = args.append;
 
     // Don't let the code below here access unquoted 'args'.
     #[allow(clippy::drop_non_drop)]
     std::mem::drop(args);
 
     // Note: If we wanted to trim this down even more for CI, we might be able
-    // to use the '12-slim' base image here.
-    writeln!(w, "FROM debian:12")?;
+    // to use the 'debian:12-slim' base image here.
+    writeln!(w, "FROM {from}")?;
 
     // Set time zone.
     writeln!(w, "RUN echo {timezone} > /etc/timezone && \\")?;
@@ -1125,37 +2279,97 @@ fn write_dockerfile<W: io::Write>(w: &mut W, args: DockerfileArgs) -> std::io::R
     // hosts use a GID with a small number for many users (GitHub Actions Mac
     // OS appears to have GID 20). If the group ID is taken on the Debian image
     // already, this falls back to any available GID, even if the group
-    // permissions end up wonky for bind mounts.
+    // permissions end up wonky for bind mounts. Some images (like Ubuntu's)
+    // come with a user account that may already have the UID, so that is
+    // removed first. Fedora and Alpine name the group for sudo access `wheel`.
+    let sudo_group = match distro {
+        Distro::Debian => "sudo",
+        Distro::Alpine | Distro::Fedora => "wheel",
+    };
+    let userdel = match distro {
+        Distro::Alpine => "deluser --remove-home",
+        Distro::Debian | Distro::Fedora => "userdel --remove",
+    };
     writeln!(
         w,
-        "RUN addgroup --gid {gid} {user} || addgroup {user} && \\"
+        "RUN if existing=\"$(getent passwd {uid})\"; then {userdel} \"${{existing%%:*}}\"; fi && \\"
     )?;
+    match distro {
+        Distro::Alpine => {
+            writeln!(w, "    (addgroup -g {gid} {user} || addgroup {user}) && \\")?;
+            writeln!(w, "    adduser -D -g '' -u {uid} -G {user} {user} && \\")?;
+            writeln!(w, "    addgroup {user} {sudo_group} && \\")?;
+        }
+        Distro::Debian => {
+            writeln!(
+                w,
+                "    (addgroup --gid {gid} {user} || addgroup {user}) && \\"
+            )?;
+            writeln!(
+                w,
+                "    adduser --disabled-password --gecos '' --uid {uid} --ingroup {user} {user} && \\",
+            )?;
+            writeln!(w, "    adduser {user} {sudo_group} && \\")?;
+        }
+        Distro::Fedora => {
+            writeln!(
+                w,
+                "    (groupadd --gid {gid} {user} || groupadd {user}) && \\"
+            )?;
+            writeln!(
+                w,
+                "    useradd --create-home --uid {uid} --gid {user} --groups {sudo_group} {user} && \\",
+            )?;
+        }
+    }
     //
     // Prevent using gid below.
     #[allow(unused)]
     let gid: ();
     //
-    writeln!(
-        w,
-        "    adduser --disabled-password --gecos '' --uid {uid} --ingroup {user} {user} && \\",
-    )?;
-    writeln!(w, "    adduser {user} sudo && \\")?;
     // For a Docker volume to be owned/writable by a regular user, a directory
     // needs to exist there before the volume is mounted. See
     // <https://github.com/moby/moby/issues/2259>.
     writeln!(w, "    mkdir /home/{user}/w && \\")?;
-    writeln!(w, "    chown {user}:{user} /home/{user}/w")?;
+    writeln!(w, "    chown {user}:{user} /home/{user}/w && \\")?;
+    //
+    // Rootless Podman inside the container (see `nested_containers`) needs a
+    // range of subordinate IDs. Some tools that add users allot these
+    // already.
+    for file in ["/etc/subuid", "/etc/subgid"] {
+        writeln!(
+            w,
+            "    (cut -d: -f1 {file} 2>/dev/null | grep -qxF {user} || echo {user}:100000:65536 >> {file}){}",
+            if file == "/etc/subuid" { " && \\" } else { "" }
+        )?;
+    }
 
-    // Configure and Update apt.
-    writeln!(
-        w,
-        r#"RUN sed -i 's/^Components: main$/Components: main contrib non-free/' /etc/apt/sources.list.d/debian.sources"#
-    )?;
-    writeln!(w, "RUN apt-get update && apt-get upgrade --yes")?;
+    let install = install_command(distro);
+    match distro {
+        Distro::Alpine => {
+            writeln!(w, "RUN apk upgrade --no-cache")?;
+        }
+        Distro::Debian => {
+            // Configure and Update apt.
+            writeln!(
+                w,
+                "RUN if [ -f /etc/apt/sources.list.d/debian.sources ]; then \\"
+            )?;
+            writeln!(
+                w,
+                r#"    sed -i 's/^Components: main$/Components: main contrib non-free/' /etc/apt/sources.list.d/debian.sources; \"#
+            )?;
+            writeln!(w, "    fi")?;
+            writeln!(w, "RUN apt-get update && apt-get upgrade --yes")?;
+        }
+        Distro::Fedora => {
+            writeln!(w, "RUN dnf upgrade --assumeyes")?;
+        }
+    }
 
     // Install requested packages.
     if let Some((last, init)) = packages.split_last() {
-        writeln!(w, "RUN apt-get install --no-install-recommends --yes \\")?;
+        writeln!(w, "RUN {install} \\")?;
         for package in init {
             writeln!(w, "    {package} \\")?;
         }
@@ -1167,11 +2381,13 @@ fn write_dockerfile<W: io::Write>(w: &mut W, args: DockerfileArgs) -> std::io::R
         writeln!(w, "RUN apt-file update")?;
     }
 
-    // Generate locales.
-    writeln!(
-        w,
-        "RUN sed -E -i 's/^# {locales} /\\1 /' /etc/locale.gen && locale-gen",
-    )?;
+    // Generate locales. Alpine's C library (musl) doesn't have these.
+    if distro == Distro::Debian {
+        writeln!(
+            w,
+            "RUN sed -E -i 's/^# {locales} /\\1 /' /etc/locale.gen && locale-gen",
+        )?;
+    }
 
     // Configure sudo (after 'sudo' is installed, which creates the directory
     // with the right permissions).
@@ -1182,28 +2398,172 @@ fn write_dockerfile<W: io::Write>(w: &mut W, args: DockerfileArgs) -> std::io::R
         )?;
         writeln!(
             w,
-            r#"    sh -c 'echo "%sudo ALL=(ALL) CWD=* NOPASSWD: ALL" > /etc/sudoers.d/nopasswd'"#
+            r#"    sh -c 'echo "%{sudo_group} ALL=(ALL) CWD=* NOPASSWD: ALL" > /etc/sudoers.d/nopasswd'"#
         )?;
     }
 
+    // The user's own instructions are intentionally not quoted or checked.
+    if let Some(append) = append {
+        writeln!(w, "# From `dockerfile_append` in the Cubicle config:")?;
+        write!(w, "{append}")?;
+        if !append.ends_with('\n') {
+            writeln!(w)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Returns the command that installs OS packages on the distribution.
+fn install_command(distro: Distro) -> &'static str {
+    match distro {
+        Distro::Alpine => "apk add --no-cache",
+        Distro::Debian => "apt-get install --no-install-recommends --yes",
+        Distro::Fedora => "dnf install --setopt=install_weak_deps=False --assumeyes",
+    }
+}
+
+/// Writes a Dockerfile for an environment's own image layer, which installs
+/// the given OS packages on top of the base image `from`.
+fn write_layer_dockerfile<W: io::Write>(
+    w: &mut W,
+    from: &str,
+    distro: Distro,
+    packages: &BTreeSet<&str>,
+) -> std::io::Result<()> {
+    let packages = packages
+        .iter()
+        .map(|p| {
+            shlex::try_quote(p).map(|p| p.into_owned()).map_err(|e| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("failed to quote OS package name {p:?}: {e}"),
+                )
+            })
+        })
+        .collect::<io::Result<Vec<String>>>()?;
+    let install = install_command(distro);
+    writeln!(w, "FROM {from}")?;
+    // The base image may end with a `USER` from `dockerfile_append`.
+    writeln!(w, "USER root")?;
+    if distro == Distro::Debian {
+        writeln!(w, "RUN apt-get update && \\")?;
+    } else {
+        writeln!(w, "RUN \\")?;
+    }
+    writeln!(w, "    {install} \\")?;
+    if let Some((last, init)) = packages.split_last() {
+        for package in init {
+            writeln!(w, "    {package} \\")?;
+        }
+        writeln!(w, "    {last}")?;
+    }
+    if distro == Distro::Debian && packages.iter().any(|p| p == "apt-file") {
+        writeln!(w, "RUN apt-file update")?;
+    }
     Ok(())
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::config::Config;
+    use crate::{DryRun, Transcript};
     use expect_test::{expect, expect_file};
     use std::path::PathBuf;
 
+    #[test]
+    fn stop_commands() {
+        let config: Config = toml::from_str("runner = 'docker'").unwrap();
+        let docker = {
+            let _recording = Transcript::new().record(DryRun(true));
+            Docker::with_host_user(CubicleShared::for_tests(config), String::from("alice")).unwrap()
+        };
+        let transcript = Transcript::new();
+        let _recording = transcript.record(DryRun(true));
+        docker
+            .stop(&EnvironmentName::from_str("foo").unwrap())
+            .unwrap();
+        expect![[r#"
+            docker rm --force cub-foo
+            docker volume rm --force cub-foo-checkpoint
+        "#]]
+        .assert_eq(
+            &transcript
+                .entries()
+                .iter()
+                .map(|entry| format!("{entry}\n"))
+                .collect::<String>(),
+        );
+    }
+
+    #[test]
+    fn clean_orphans_skips_ephemeral_and_running() {
+        let config: Config =
+            toml::from_str("runner = 'docker'\n[runners.docker]\nbind_mounts = true").unwrap();
+        let docker = {
+            let _recording = Transcript::new().record(DryRun(true));
+            Docker::with_host_user(CubicleShared::for_tests(config), String::from("alice")).unwrap()
+        };
+        let transcript = Transcript::new();
+        let _recording = transcript.record(DryRun(true));
+        // None has a home or work directory under `/cubicle-test`.
+        let cleaned = docker
+            .clean_orphan_containers(vec![
+                ContainerSummary {
+                    env_name: EnvironmentName::from_str("tmp-eph").unwrap(),
+                    running: true,
+                    ephemeral: true,
+                },
+                ContainerSummary {
+                    env_name: EnvironmentName::from_str("busy").unwrap(),
+                    running: true,
+                    ephemeral: false,
+                },
+                ContainerSummary {
+                    env_name: EnvironmentName::from_str("gone").unwrap(),
+                    running: false,
+                    ephemeral: false,
+                },
+            ])
+            .unwrap();
+        assert_eq!(
+            cleaned,
+            ["Docker container \"cub-gone\" without an environment"]
+        );
+        expect![[r#"
+            docker rm --force cub-gone
+        "#]]
+        .assert_eq(
+            &transcript
+                .entries()
+                .iter()
+                .map(|entry| format!("{entry}\n"))
+                .collect::<String>(),
+        );
+    }
+
     #[test]
     fn fallback_path() {
-        expect!["PATH=/home/foo/bin:/usr/bin:/usr/sbin"].assert_eq(
-            &super::fallback_path(&EnvPath::try_from(PathBuf::from("/home/foo")).unwrap())
-                .to_string_lossy(),
+        let home = EnvPath::try_from(PathBuf::from("/home/foo")).unwrap();
+        expect!["PATH=/home/foo/bin:/usr/bin:/usr/sbin"]
+            .assert_eq(&super::fallback_path(&home, Distro::Debian).to_string_lossy());
+        expect!["PATH=/home/foo/bin:/usr/bin:/usr/sbin:/bin:/sbin"]
+            .assert_eq(&super::fallback_path(&home, Distro::Alpine).to_string_lossy());
+        let home = EnvPath::try_from(PathBuf::from("/home/fo:oo")).unwrap();
+        expect!["PATH=/usr/bin:/usr/sbin"]
+            .assert_eq(&super::fallback_path(&home, Distro::Debian).to_string_lossy());
+    }
+
+    #[test]
+    fn csv_field() {
+        assert_eq!(
+            super::csv_field("source", OsStr::new(r#"/home/a "b""#)),
+            OsStr::new(r#""source=/home/a ""b""""#)
         );
-        expect!["PATH=/usr/bin:/usr/sbin"].assert_eq(
-            &super::fallback_path(&EnvPath::try_from(PathBuf::from("/home/fo:oo")).unwrap())
-                .to_string_lossy(),
+        assert_eq!(
+            super::csv_field("source", OsStr::from_bytes(b"/home/caf\xe9")).as_bytes(),
+            b"\"source=/home/caf\xe9\""
         );
     }
 
@@ -1213,6 +2573,8 @@ mod tests {
         super::write_dockerfile(
             &mut buf,
             DockerfileArgs {
+                from: "debian:12",
+                distro: Distro::Debian,
                 packages: &BTreeSet::from(["apt-file", "pack#age1", "package2", "sudo"]),
                 timezone: "Etc/Timez'one",
                 locales: &BTreeSet::from(
@@ -1231,10 +2593,98 @@ mod tests {
                     real_user: 1337,
                     group: 7331,
                 },
+                append: Some("ENV http_proxy=http://proxy:3128\nRUN update-ca-certificates"),
             },
         )
         .unwrap();
         let dockerfile = String::from_utf8(buf).unwrap();
         expect_file!["snapshots/cubicle__docker__tests__Dockerfile.snap"].assert_eq(&dockerfile);
     }
+
+    #[test]
+    fn write_dockerfile_other_distros() {
+        for (distro, from) in [(Distro::Alpine, "alpine:3"), (Distro::Fedora, "fedora:40")] {
+            let mut buf: Vec<u8> = Vec::new();
+            super::write_dockerfile(
+                &mut buf,
+                DockerfileArgs {
+                    from,
+                    distro,
+                    packages: &BTreeSet::from(["apt-file", "package1", "sudo"]),
+                    timezone: "Etc/UTC",
+                    locales: &BTreeSet::from(["C.UTF-8", "en_US.UTF-8", "sv_SE"].map(String::from)),
+                    user: "hxr",
+                    uids: &Uids {
+                        real_user: 1337,
+                        group: 7331,
+                    },
+                    append: None,
+                },
+            )
+            .unwrap();
+            let dockerfile = String::from_utf8(buf).unwrap();
+            match distro {
+                Distro::Alpine => {
+                    expect_file!["snapshots/cubicle__docker__tests__Dockerfile_alpine.snap"]
+                        .assert_eq(&dockerfile)
+                }
+                Distro::Fedora => {
+                    expect_file!["snapshots/cubicle__docker__tests__Dockerfile_fedora.snap"]
+                        .assert_eq(&dockerfile)
+                }
+                Distro::Debian => unreachable!(),
+            }
+        }
+    }
+
+    #[test]
+    fn write_layer_dockerfile() {
+        let mut buf: Vec<u8> = Vec::new();
+        super::write_layer_dockerfile(
+            &mut buf,
+            "cub-cubicle-base",
+            Distro::Debian,
+            &BTreeSet::from(["apt-file", "sl"]),
+        )
+        .unwrap();
+        expect![[r#"
+            FROM cub-cubicle-base
+            USER root
+            RUN apt-get update && \
+                apt-get install --no-install-recommends --yes \
+                apt-file \
+                sl
+            RUN apt-file update
+        "#]]
+        .assert_eq(&String::from_utf8(buf).unwrap());
+
+        let mut buf: Vec<u8> = Vec::new();
+        super::write_layer_dockerfile(
+            &mut buf,
+            "cub-cubicle-base-alpine:3",
+            Distro::Alpine,
+            &BTreeSet::from(["sl"]),
+        )
+        .unwrap();
+        expect![[r#"
+            FROM cub-cubicle-base-alpine:3
+            USER root
+            RUN \
+                apk add --no-cache \
+                sl
+        "#]]
+        .assert_eq(&String::from_utf8(buf).unwrap());
+
+        let err = super::write_layer_dockerfile(
+            &mut Vec::new(),
+            "cub-cubicle-base",
+            Distro::Debian,
+            &BTreeSet::from(["s\0l"]),
+        )
+        .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            r#"failed to quote OS package name "s\0l": cannot shell-quote string containing nul byte"#
+        );
+    }
 }
@@ -154,6 +154,7 @@ macro_rules! name {
 
 name!(ContainerName);
 name!(ImageName);
+name!(NetworkName);
 name!(VolumeName);
 
 #[cfg(test)]
@@ -0,0 +1,25 @@
+//! Checks Cubicle's installation for problems that don't stop it from
+//! working yet.
+//!
+//! Unlike `cub self-test`, `cub doctor` doesn't create environments or run
+//! anything in them, so it's quick and safe to run at any time.
+
+use super::Cubicle;
+use crate::somehow::{somehow as anyhow, Result};
+
+impl Cubicle {
+    /// Corresponds to `cub doctor`.
+    ///
+    /// Prints each problem found and returns an error if there are any.
+    pub fn doctor(&self) -> Result<()> {
+        let problems = self.legacy_layout_problems();
+        for problem in &problems {
+            println!("WARN  {problem}");
+        }
+        if !problems.is_empty() {
+            return Err(anyhow!("found {} problem(s)", problems.len()));
+        }
+        println!("No problems found");
+        Ok(())
+    }
+}
@@ -0,0 +1,101 @@
+//! Copies the user's dotfiles into environments.
+//!
+//! With [`Config::dotfiles`](super::config::Config::dotfiles) set, Cubicle
+//! builds a seed from a host directory or a git repository each time an
+//! environment is created or reset. The seed is extracted into the home
+//! directory after the package seeds, so the dotfiles take precedence over
+//! files that packages provide.
+
+use std::io::Write;
+use std::path::PathBuf;
+use tracing::info;
+
+use super::command_ext::{Command, Stdio};
+use super::config::Dotfiles;
+use super::encoding::FilenameEncoder;
+use super::fs_util::{create_tar_from_dir, host_tempfile, try_exists, TarOptions};
+use super::lock::LockFile;
+use super::{Cubicle, HostPath};
+use crate::somehow::{somehow as anyhow, warn, Context, Result};
+
+impl Cubicle {
+    /// Returns the directory that holds the git clones of dotfiles
+    /// repositories.
+    pub(super) fn dotfiles_dir(&self) -> HostPath {
+        self.shared.cache_dir.join("dotfiles")
+    }
+
+    /// Builds a seed containing the configured dotfiles, or returns `None` if
+    /// none are configured.
+    ///
+    /// For a git repository, this clones it on first use and tries to pull
+    /// new commits afterwards. If pulling fails, such as when offline, this
+    /// warns and uses the existing clone.
+    ///
+    /// The returned temporary file must be kept until the environment is
+    /// initialized.
+    pub(super) fn dotfiles_seed(&self) -> Result<Option<tempfile::NamedTempFile>> {
+        let (dir, _lock) = match &self.shared.config.dotfiles {
+            None => return Ok(None),
+            Some(Dotfiles::Dir(dir)) => (HostPath::try_from(dir.clone())?, None),
+            Some(Dotfiles::Git(url)) => {
+                let (dir, lock) = self.update_dotfiles_clone(url)?;
+                (dir, Some(lock))
+            }
+        };
+
+        let file = host_tempfile().todo_context()?;
+        create_tar_from_dir(
+            &dir,
+            file.as_file(),
+            &TarOptions {
+                prefix: None,
+                exclude: vec![PathBuf::from(".git")],
+            },
+        )
+        .with_context(|| format!("failed to archive dotfiles from {dir}"))?;
+        file.as_file().flush().todo_context()?;
+        Ok(Some(file))
+    }
+
+    /// Clones or pulls the dotfiles repository and returns the clone's
+    /// directory, along with a lock that must be held while reading it.
+    fn update_dotfiles_clone(&self, url: &str) -> Result<(HostPath, LockFile)> {
+        let dotfiles_dir = self.dotfiles_dir();
+        std::fs::create_dir_all(dotfiles_dir.as_host_raw())
+            .with_context(|| format!("failed to create directory {dotfiles_dir}"))?;
+        let lock = LockFile::acquire(dotfiles_dir.join("lock"), "dotfiles")?;
+        // Each URL gets its own clone, so changing the config doesn't mix up
+        // repositories.
+        let clone = dotfiles_dir.join(FilenameEncoder::new().push(url).encode());
+
+        if try_exists(&clone).todo_context()? {
+            let status = Command::new("git")
+                .arg("-C")
+                .arg(clone.as_host_raw())
+                .args(["pull", "--quiet", "--ff-only"])
+                .stdin(Stdio::null())
+                .status()?;
+            if !status.success() {
+                warn(anyhow!(
+                    "failed to update dotfiles from {url:?} (`git pull` exited with {status}); \
+                    using the existing copy"
+                ));
+            }
+        } else {
+            info!("Cloning dotfiles from {url}");
+            let status = Command::new("git")
+                .args(["clone", "--quiet", "--"])
+                .arg(url)
+                .arg(clone.as_host_raw())
+                .stdin(Stdio::null())
+                .status()?;
+            if !status.success() {
+                return Err(anyhow!(
+                    "failed to clone dotfiles from {url:?}: `git clone` exited with {status}"
+                ));
+            }
+        }
+        Ok((clone, lock))
+    }
+}
@@ -0,0 +1,289 @@
+//! Caches the disk usage of environments, since summarizing large home and
+//! work directories for `cub list` can take many seconds.
+//!
+//! Each environment's summary is kept in `disk-usage.json` in its metadata
+//! directory. Writing the environment's metadata removes the summary, which
+//! happens when the environment is created or reset and whenever a session
+//! in it starts or ends. Package builds and `cub copy` also remove it for the
+//! environments they change. The summaries of running environments are
+//! always computed again, since they may be changing.
+//!
+//! Each summary also records the modification times of the home and work
+//! directories themselves, and it's computed again when either differs. That
+//! catches files added to or removed from the top of those directories
+//! without walking them. Changes deeper within aren't noticed until
+//! something else removes the summary.
+
+use serde::{Deserialize, Serialize};
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::time::SystemTime;
+
+use super::fs_util::DirSummary;
+use super::runner::{EnvFilesSummary, Runner};
+use super::{Cubicle, EnvironmentName, HostPath};
+use crate::somehow::{warn, Context, Result};
+
+/// The contents of an environment's `disk-usage.json`.
+#[derive(Debug, Deserialize, Serialize)]
+struct CachedSummary {
+    home_dir_path: Option<PathBuf>,
+    #[serde(default)]
+    home_dir_mtime: Option<SystemTime>,
+    home_dir: CachedDir,
+    work_dir_path: Option<PathBuf>,
+    #[serde(default)]
+    work_dir_mtime: Option<SystemTime>,
+    work_dir: CachedDir,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+struct CachedDir {
+    errors: bool,
+    total_size: u64,
+    last_modified: SystemTime,
+}
+
+impl From<&DirSummary> for CachedDir {
+    fn from(summary: &DirSummary) -> Self {
+        Self {
+            errors: summary.errors,
+            total_size: summary.total_size,
+            last_modified: summary.last_modified,
+        }
+    }
+}
+
+impl From<CachedDir> for DirSummary {
+    fn from(cached: CachedDir) -> Self {
+        Self {
+            errors: cached.errors,
+            total_size: cached.total_size,
+            last_modified: cached.last_modified,
+        }
+    }
+}
+
+/// Named boolean flag for [`Cubicle::list_environments`].
+///
+/// When unset, environments' disk usage comes only from the cache, and
+/// environments without a cached summary are shown without one.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Refresh(pub bool);
+
+/// Returns the modification time of the directory itself, or `None` if it's
+/// unknown.
+fn dir_mtime(path: Option<&HostPath>) -> Option<SystemTime> {
+    let metadata = std::fs::metadata(path?.as_host_raw()).ok()?;
+    metadata.modified().ok()
+}
+
+impl Cubicle {
+    fn du_cache_path(&self, name: &EnvironmentName) -> HostPath {
+        self.metadata_dir(name).join("disk-usage.json")
+    }
+
+    /// Returns the disk usage of the environment's home and work
+    /// directories, from the cache unless it's missing or `running` is set.
+    /// With `refresh` unset, this never summarizes the directories.
+    pub(super) fn cached_files_summary(
+        &self,
+        name: &EnvironmentName,
+        running: bool,
+        refresh: Refresh,
+    ) -> Result<EnvFilesSummary> {
+        if !(running && refresh.0) {
+            match self.read_du_cache(name) {
+                Ok(Some(summary)) => return Ok(summary),
+                Ok(None) => {}
+                Err(e) => warn(e),
+            }
+        }
+        if !refresh.0 {
+            return Ok(EnvFilesSummary {
+                home_dir_path: None,
+                home_dir: DirSummary::new_with_errors(),
+                work_dir_path: None,
+                work_dir: DirSummary::new_with_errors(),
+            });
+        }
+        let started = SystemTime::now();
+        let summary = self.runner.files_summary(name)?;
+        if !running {
+            if let Err(e) = self.write_du_cache(name, &summary, started) {
+                warn(e);
+            }
+        }
+        Ok(summary)
+    }
+
+    /// Returns the cached summary, or `None` if there isn't one or the
+    /// directories have changed since.
+    fn read_du_cache(&self, name: &EnvironmentName) -> Result<Option<EnvFilesSummary>> {
+        let path = self.du_cache_path(name);
+        let contents = match std::fs::read_to_string(path.as_host_raw()) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e).with_context(|| format!("failed to read {path}")),
+        };
+        let cached: CachedSummary =
+            serde_json::from_str(&contents).with_context(|| format!("failed to parse {path}"))?;
+        let summary = EnvFilesSummary {
+            home_dir_path: cached.home_dir_path.map(HostPath::try_from).transpose()?,
+            home_dir: cached.home_dir.into(),
+            work_dir_path: cached.work_dir_path.map(HostPath::try_from).transpose()?,
+            work_dir: cached.work_dir.into(),
+        };
+        if dir_mtime(summary.home_dir_path.as_ref()) != cached.home_dir_mtime
+            || dir_mtime(summary.work_dir_path.as_ref()) != cached.work_dir_mtime
+        {
+            return Ok(None);
+        }
+        Ok(Some(summary))
+    }
+
+    /// Caches the summary, which was started at `started`. A directory that
+    /// changed after that gets no modification time, so that the summary is
+    /// computed again the next time.
+    fn write_du_cache(
+        &self,
+        name: &EnvironmentName,
+        summary: &EnvFilesSummary,
+        started: SystemTime,
+    ) -> Result<()> {
+        let dir = self.metadata_dir(name);
+        std::fs::create_dir_all(dir.as_host_raw())
+            .with_context(|| format!("failed to create directory {dir}"))?;
+        let path = self.du_cache_path(name);
+        let mtime = |path| dir_mtime(path).filter(|mtime| *mtime < started);
+        let cached = CachedSummary {
+            home_dir_path: summary
+                .home_dir_path
+                .as_ref()
+                .map(|p| p.as_host_raw().to_owned()),
+            home_dir_mtime: mtime(summary.home_dir_path.as_ref()),
+            home_dir: (&summary.home_dir).into(),
+            work_dir_path: summary
+                .work_dir_path
+                .as_ref()
+                .map(|p| p.as_host_raw().to_owned()),
+            work_dir_mtime: mtime(summary.work_dir_path.as_ref()),
+            work_dir: (&summary.work_dir).into(),
+        };
+        let mut file = tempfile::NamedTempFile::new_in(dir.as_host_raw())
+            .with_context(|| format!("failed to create temporary file in {dir}"))?;
+        serde_json::to_writer(&mut file, &cached)
+            .map_err(io::Error::from)
+            .and_then(|()| file.write_all(b"\n"))
+            .with_context(|| format!("failed to write {path}"))?;
+        file.persist(path.as_host_raw())
+            .with_context(|| format!("failed to write {path}"))?;
+        Ok(())
+    }
+
+    /// Removes the environment's cached disk usage, after something may have
+    /// changed its files.
+    pub(super) fn forget_disk_usage(&self, name: &EnvironmentName) -> Result<()> {
+        let path = self.du_cache_path(name);
+        match std::fs::remove_file(path.as_host_raw()) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => {
+                Err(e).with_context(|| format!("failed to remove {path}"))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fs_util::{summarize_dir, try_exists};
+    use crate::runner::Init;
+    use crate::testing::{self, FakeRunner};
+    use std::str::FromStr;
+    use std::time::Duration;
+
+    fn init() -> Init {
+        Init {
+            distro_packages: Vec::new(),
+            env_distro_packages: Vec::new(),
+            env_vars: Vec::new(),
+            seeds: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn cached_files_summary() {
+        let root = tempfile::tempdir().unwrap();
+        let runner = FakeRunner::new();
+        let cub =
+            Cubicle::with_fake_runner(testing::config(""), runner.clone(), root.path()).unwrap();
+        let name = EnvironmentName::from_str("web").unwrap();
+        runner.create(&name, &init()).unwrap();
+        let cached = || try_exists(&cub.du_cache_path(&name)).unwrap();
+        let summarize = |running, refresh| {
+            cub.cached_files_summary(&name, running, Refresh(refresh))
+                .unwrap();
+        };
+
+        // Without refreshing, nothing is summarized or cached.
+        summarize(false, false);
+        assert!(!cached());
+        summarize(false, true);
+        assert!(cached());
+        summarize(false, false);
+        assert!(cached());
+
+        // Using the environment changes its files.
+        cub.touch_last_used(&name);
+        assert!(!cached());
+
+        // Running environments aren't cached.
+        summarize(true, true);
+        assert!(!cached());
+    }
+
+    #[test]
+    fn cached_files_summary_mtimes() {
+        let root = tempfile::tempdir().unwrap();
+        let work_dirs = HostPath::try_from(root.path().join("work")).unwrap();
+        let runner = FakeRunner::with_work_dirs(work_dirs.clone());
+        let cub =
+            Cubicle::with_fake_runner(testing::config(""), runner.clone(), root.path()).unwrap();
+        let name = EnvironmentName::from_str("web").unwrap();
+        runner.create(&name, &init()).unwrap();
+        let work_dir = work_dirs.join(name.as_filename());
+        let work_dir = work_dir.as_host_raw();
+        std::fs::create_dir_all(work_dir).unwrap();
+        std::fs::write(work_dir.join("a"), "a").unwrap();
+        // The directory must look older than the summary to be cached.
+        std::fs::File::open(work_dir)
+            .unwrap()
+            .set_modified(SystemTime::now() - Duration::from_secs(10))
+            .unwrap();
+        let size = || {
+            cub.cached_files_summary(&name, false, Refresh(true))
+                .unwrap()
+                .work_dir
+                .total_size
+        };
+        let actual = || {
+            summarize_dir(&work_dirs.join(name.as_filename()))
+                .unwrap()
+                .total_size
+        };
+
+        let before = size();
+        assert_eq!(before, actual());
+
+        // Changing a file in place doesn't touch the directory, so the
+        // cached size is used.
+        std::fs::write(work_dir.join("a"), "aaaa").unwrap();
+        assert_eq!(size(), before);
+
+        // Adding a file changes the directory's modification time.
+        std::fs::write(work_dir.join("b"), "bbbb").unwrap();
+        assert_ne!(size(), before);
+        assert_eq!(size(), actual());
+    }
+}
@@ -1,4 +1,5 @@
 use std::ffi::OsStr;
+use std::os::unix::ffi::OsStrExt;
 
 use crate::somehow::{somehow as anyhow, Context, Result};
 
@@ -63,6 +64,30 @@ where
     buf
 }
 
+/// Returns a string for the given input, percent-encoding `%` and any bytes
+/// that aren't valid UTF-8.
+pub fn percent_encode_os_str(input: &OsStr) -> String {
+    use std::fmt::Write;
+    let mut bytes = input.as_bytes();
+    let mut buf = String::with_capacity(bytes.len());
+    while !bytes.is_empty() {
+        let (valid, invalid) = match std::str::from_utf8(bytes) {
+            Ok(valid) => (valid, &[][..]),
+            Err(e) => {
+                let (valid, rest) = bytes.split_at(e.valid_up_to());
+                let invalid_len = e.error_len().unwrap_or(rest.len());
+                (std::str::from_utf8(valid).unwrap(), &rest[..invalid_len])
+            }
+        };
+        buf.push_str(&percent_encode(valid, |_i, c| c == '%'));
+        for byte in invalid {
+            write!(buf, "%{:02x}", byte).unwrap();
+        }
+        bytes = &bytes[valid.len() + invalid.len()..];
+    }
+    buf
+}
+
 pub fn percent_decode(input: &str) -> Result<String> {
     let mut bytes = input.bytes();
     let mut buf: Vec<u8> = Vec::with_capacity(input.len() / 4);
@@ -169,4 +194,18 @@ mod tests {
 
         assert!(!fail, "at least one encoding/decoding failure");
     }
+
+    #[test]
+    fn percent_encode_os_str() {
+        let encode = |bytes: &[u8]| super::percent_encode_os_str(OsStr::from_bytes(bytes));
+        assert_eq!(encode(b"wayland-0"), "wayland-0");
+        assert_eq!(encode("çb 50%".as_bytes()), "çb 50%25");
+        assert_eq!(encode(b"caf\xe9-\xff\xfe"), "caf%e9-%ff%fe");
+        // Truncated multi-byte sequence at the end.
+        assert_eq!(encode(b"a\xc3"), "a%c3");
+        assert_eq!(
+            percent_decode(&encode("çb 50%".as_bytes())).unwrap(),
+            "çb 50%"
+        );
+    }
 }
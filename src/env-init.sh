@@ -2,7 +2,8 @@
 set -eu
 
 cd
-mkdir -p .dev-init bin opt tmp w
+work="${CUBICLE_WORK_DIR:-w}"
+mkdir -p .dev-init bin opt tmp "$work"
 
 if [ -f ./.profile ]; then
     set +u
@@ -17,8 +18,8 @@ for f in ./.dev-init/*; do
     fi
 done
 
-cd w
+cd "$work"
 if [ -x ./update.sh ]; then
-    echo "Running ~/w/update.sh"
-    ./update.sh || echo "WARNING: ~/w/update.sh in $CUBICLE failed with status $?"
+    echo "Running ~/$work/update.sh"
+    ./update.sh || echo "WARNING: ~/$work/update.sh in $CUBICLE failed with status $?"
 fi
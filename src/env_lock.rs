@@ -0,0 +1,91 @@
+//! Keeps `cub` processes from changing the same environment at once.
+//!
+//! Creating, resetting, purging, and other changes to an environment hold
+//! its lock for as long as they take. `cub enter` and `cub exec` wait for
+//! any such change to finish before they start, but they don't hold the lock
+//! during their sessions, so that many sessions may run at once.
+//!
+//! Hooks that run while the lock is held get [`LOCK_HELD_VAR`], so that
+//! `cub enter` and `cub exec` from within them don't wait on their parent.
+
+use super::lock::LockFile;
+use super::{Cubicle, EnvironmentName, FilenameEncoder, HostPath};
+use crate::somehow::Result;
+
+/// Set to an environment's name for child processes that run while this
+/// process holds the environment's lock.
+pub(super) const LOCK_HELD_VAR: &str = "CUBICLE_LOCK_HELD";
+
+impl Cubicle {
+    /// Returns the directory of the environments' lock files.
+    ///
+    /// These aren't kept with the environments' metadata, since that's
+    /// removed when an environment is purged.
+    pub(super) fn environment_locks_dir(&self) -> HostPath {
+        self.shared.cache_dir.join("env-locks")
+    }
+
+    fn environment_lock_path(&self, name: &EnvironmentName) -> HostPath {
+        self.environment_locks_dir().join(
+            FilenameEncoder::new()
+                .push(name.as_str())
+                .push(".lock")
+                .encode(),
+        )
+    }
+
+    /// Acquires the lock for changing the environment, waiting while
+    /// another process changes it.
+    pub(super) fn lock_environment(&self, name: &EnvironmentName) -> Result<LockFile> {
+        LockFile::acquire(
+            self.environment_lock_path(name),
+            &format!("environment {name}"),
+        )
+    }
+
+    /// Like [`Cubicle::lock_environment`], but returns `None` instead of
+    /// waiting if another process is changing the environment.
+    pub(super) fn try_lock_environment(&self, name: &EnvironmentName) -> Result<Option<LockFile>> {
+        LockFile::try_acquire(&self.environment_lock_path(name))
+    }
+
+    /// Waits while another process changes the environment, for operations
+    /// that use the environment without changing it.
+    ///
+    /// This returns right away in a hook's child process if the parent
+    /// `cub` holds the lock, since the lock won't be released until the hook
+    /// exits.
+    pub(super) fn wait_for_environment(&self, name: &EnvironmentName) -> Result<()> {
+        if std::env::var_os(LOCK_HELD_VAR).is_some_and(|held| held == name.as_str()) {
+            return Ok(());
+        }
+        self.lock_environment(name).map(drop)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+    use std::str::FromStr;
+    use std::time::Duration;
+
+    #[test]
+    fn wait_for_environment() {
+        let root = tempfile::tempdir().unwrap();
+        let cub = testing::cubicle(root.path(), "");
+        let name = EnvironmentName::from_str("a/b").unwrap();
+        let other = EnvironmentName::from_str("c").unwrap();
+
+        let lock = cub.lock_environment(&name).unwrap();
+        // Other environments aren't affected.
+        cub.wait_for_environment(&other).unwrap();
+        std::thread::scope(|s| {
+            let waiter = s.spawn(|| cub.wait_for_environment(&name).unwrap());
+            std::thread::sleep(Duration::from_millis(100));
+            assert!(!waiter.is_finished());
+            drop(lock);
+            waiter.join().unwrap();
+        });
+    }
+}
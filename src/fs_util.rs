@@ -1,9 +1,12 @@
 use std::ffi::OsString;
-use std::io;
-use std::path::PathBuf;
+use std::fmt::Write as _;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
 use std::rc::Rc;
 use std::time::{SystemTime, UNIX_EPOCH};
 
+use sha2::{Digest, Sha256};
+
 use super::HostPath;
 use crate::somehow::{somehow as anyhow, Context, Result};
 
@@ -162,6 +165,114 @@ pub fn summarize_dir(path: &HostPath) -> Result<DirSummary> {
     Ok(summary)
 }
 
+/// Returns the SHA-256 hash of the file's contents, in hex.
+pub fn hash_file(path: &HostPath) -> Result<String> {
+    let mut file = std::fs::File::open(path.as_host_raw())
+        .with_context(|| format!("failed to open {path}"))?;
+    let mut hasher = Sha256::new();
+    io::copy(&mut file, &mut hasher).with_context(|| format!("failed to read {path}"))?;
+    Ok(hex(&hasher.finalize()))
+}
+
+/// Returns a SHA-256 hash of the directory's contents, in hex.
+///
+/// This covers the names, types, and contents of the files, directories,
+/// and symlinks within the directory, and whether files are executable.
+/// Unlike [`summarize_dir`], it ignores modification times, which change
+/// when tools like `git checkout` rewrite files with the same contents.
+pub fn hash_dir(path: &HostPath) -> Result<String> {
+    use cap_std::fs::PermissionsExt;
+    let mut entries = Vec::new();
+    for entry in WalkDir::new(path)? {
+        let WalkDirEntry {
+            parent,
+            path: relative,
+            entry,
+            file_type,
+        } = entry?;
+        let name = entry.file_name();
+        let context = || format!("failed to read {:?}", path.as_host_raw().join(&relative));
+        let line = if file_type.is_dir() {
+            String::from("dir")
+        } else if file_type.is_symlink() {
+            let target = parent.read_link(&name).with_context(context)?;
+            format!("symlink {}", hex(target.as_os_str().as_encoded_bytes()))
+        } else {
+            let mut file = parent.open(&name).with_context(context)?;
+            let executable =
+                file.metadata().with_context(context)?.permissions().mode() & 0o111 != 0;
+            let mut hasher = Sha256::new();
+            io::copy(&mut file, &mut hasher).with_context(context)?;
+            format!(
+                "{} {}",
+                if executable { "exe" } else { "file" },
+                hex(&hasher.finalize())
+            )
+        };
+        entries.push((relative, line));
+    }
+    entries.sort_unstable();
+    let mut hasher = Sha256::new();
+    for (relative, line) in entries {
+        hasher.update(relative.as_os_str().as_encoded_bytes());
+        hasher.update(b"\0");
+        hasher.update(line.as_bytes());
+        hasher.update(b"\n");
+    }
+    Ok(hex(&hasher.finalize()))
+}
+
+/// Formats bytes as lowercase hex.
+pub fn hex(bytes: &[u8]) -> String {
+    let mut hex = String::with_capacity(bytes.len() * 2);
+    for byte in bytes {
+        write!(hex, "{byte:02x}").unwrap();
+    }
+    hex
+}
+
+/// The filesystem queries that staleness checks depend on.
+///
+/// This is a trait so that those checks can be tested without touching the
+/// real filesystem. [`HostFilesystem`] is the real implementation.
+pub trait Filesystem {
+    /// Returns the modification time of the file, or `None` if it doesn't
+    /// exist or can't be read.
+    fn modified(&self, path: &HostPath) -> Option<SystemTime>;
+
+    /// See [`summarize_dir`].
+    fn summarize_dir(&self, path: &HostPath) -> Result<DirSummary>;
+
+    /// See [`hash_dir`].
+    fn hash_dir(&self, path: &HostPath) -> Result<String>;
+
+    /// Returns the contents of the file, or `None` if it doesn't exist or
+    /// can't be read.
+    fn read(&self, path: &HostPath) -> Option<String>;
+}
+
+/// Accesses the host's real filesystem.
+pub struct HostFilesystem;
+
+impl Filesystem for HostFilesystem {
+    fn modified(&self, path: &HostPath) -> Option<SystemTime> {
+        let metadata = std::fs::metadata(path.as_host_raw()).ok()?;
+        metadata.modified().ok()
+    }
+
+    fn summarize_dir(&self, path: &HostPath) -> Result<DirSummary> {
+        summarize_dir(path)
+    }
+
+    fn hash_dir(&self, path: &HostPath) -> Result<String> {
+        hash_dir(path)
+    }
+
+    fn read(&self, path: &HostPath) -> Option<String> {
+        std::fs::read_to_string(path.as_host_raw()).ok()
+    }
+}
+
 pub fn try_iterdir(path: &HostPath) -> Result<Vec<OsString>> {
     try_iterdir_with_filter(path, |_| Ok(true))
 }
@@ -291,6 +402,7 @@ impl Iterator for WalkDir {
 #[derive(Default)]
 pub struct TarOptions {
     pub prefix: Option<PathBuf>,
+    /// Relative paths to leave out, along with everything under them.
     pub exclude: Vec<PathBuf>,
 }
 
@@ -304,7 +416,7 @@ pub fn create_tar_from_dir<W: io::Write>(dir: &HostPath, w: W, opts: &TarOptions
             file_type,
         } = entry?;
         let mut add = || {
-            if opts.exclude.contains(&path) {
+            if opts.exclude.iter().any(|exclude| path.starts_with(exclude)) {
                 return Ok(());
             }
             let append_path = match &opts.prefix {
@@ -353,6 +465,151 @@ pub fn create_tar_from_dir<W: io::Write>(dir: &HostPath, w: W, opts: &TarOptions
     Ok(())
 }
 
+/// Prefix for the names of temporary files that Cubicle creates on the host.
+/// See [`host_tempfile`].
+pub const TEMP_FILE_PREFIX: &str = "cubicle-";
+
+/// Creates a temporary file in `$TMPDIR` on the host. Its name includes this
+/// process's ID, so that it can be cleaned up later if this process crashes.
+/// Writes a tar file into a new temporary file with a single regular file
+/// in it, at the given relative path. The file's owner and mode are taken
+/// from the temporary file.
+pub fn single_file_tar(path: &Path, contents: &[u8]) -> Result<tempfile::NamedTempFile> {
+    let file = host_tempfile().todo_context()?;
+    let metadata = file.as_file().metadata().todo_context()?;
+    let mut builder = tar::Builder::new(file.as_file());
+    let mut header = tar::Header::new_gnu();
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+        header.set_mtime(metadata.mtime() as u64);
+        header.set_uid(u64::from(metadata.uid()));
+        header.set_gid(u64::from(metadata.gid()));
+        header.set_mode(metadata.mode());
+    }
+    header.set_size(contents.len() as u64);
+    builder
+        .append_data(&mut header, path, contents)
+        .todo_context()?;
+    builder
+        .into_inner()
+        .and_then(|mut f| f.flush())
+        .todo_context()?;
+    Ok(file)
+}
+
+/// The first bytes of a zstd frame.
+const ZSTD_MAGIC: [u8; 4] = [0x28, 0xb5, 0x2f, 0xfd];
+
+/// The longest a zstd frame header can be (`ZSTD_FRAMEHEADERSIZE_MAX`).
+const ZSTD_FRAME_HEADER_MAX: u64 = 18;
+
+/// Returns true if the file starts with a zstd frame.
+fn is_zstd(file: &mut std::fs::File) -> io::Result<bool> {
+    use std::io::{Read, Seek, SeekFrom};
+    let mut magic = [0; ZSTD_MAGIC.len()];
+    let result = file.read_exact(&mut magic);
+    file.seek(SeekFrom::Start(0))?;
+    match result {
+        Ok(()) => Ok(magic == ZSTD_MAGIC),
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+/// Opens a tar file for reading, decompressing it if it's compressed with
+/// zstd.
+///
+/// Package builds are stored compressed (see [`compress_tar`]), but other
+/// seeds and builds from older versions of Cubicle are not, so readers of
+/// tar files should go through this.
+pub fn open_tar(path: &HostPath) -> Result<Box<dyn io::Read + Send>> {
+    let mut file = std::fs::File::open(path.as_host_raw())
+        .with_context(|| format!("failed to open {path}"))?;
+    if is_zstd(&mut file).with_context(|| format!("failed to read {path}"))? {
+        let decoder = zstd::Decoder::new(file)
+            .with_context(|| format!("failed to start decompressing {path}"))?;
+        Ok(Box::new(decoder))
+    } else {
+        Ok(Box::new(file))
+    }
+}
+
+/// Returns the size of the tar file once decompressed, for progress bars.
+/// This falls back to the compressed size if the size isn't recorded in the
+/// file.
+pub fn tar_size(path: &HostPath) -> Result<u64> {
+    use std::io::Read;
+    let mut file = std::fs::File::open(path.as_host_raw())
+        .with_context(|| format!("failed to open {path}"))?;
+    let len = file
+        .metadata()
+        .with_context(|| format!("failed to stat {path}"))?
+        .len();
+    if !is_zstd(&mut file).with_context(|| format!("failed to read {path}"))? {
+        return Ok(len);
+    }
+    let mut header = Vec::new();
+    (&mut file)
+        .take(ZSTD_FRAME_HEADER_MAX)
+        .read_to_end(&mut header)
+        .with_context(|| format!("failed to read {path}"))?;
+    Ok(match zstd::zstd_safe::get_frame_content_size(&header) {
+        Ok(Some(size)) => size,
+        _ => len,
+    })
+}
+
+/// Writes the contents of the seed tar files to `w`, one after another,
+/// decompressing any that are compressed.
+pub fn write_seeds<'a, I>(seeds: I, w: &mut dyn Write) -> Result<()>
+where
+    I: IntoIterator<Item = &'a HostPath>,
+{
+    for seed in seeds {
+        let mut r = open_tar(seed)?;
+        io::copy(&mut r, w).with_context(|| format!("failed to copy {seed}"))?;
+    }
+    w.flush().context("failed to flush seeds")
+}
+
+/// Compresses the tar file with zstd, in place.
+///
+/// The uncompressed size is recorded in the compressed file, for
+/// [`tar_size`].
+pub fn compress_tar(path: &HostPath) -> Result<()> {
+    let mut file = std::fs::File::open(path.as_host_raw())
+        .with_context(|| format!("failed to open {path}"))?;
+    let len = file
+        .metadata()
+        .with_context(|| format!("failed to stat {path}"))?
+        .len();
+    let dir = path
+        .as_host_raw()
+        .parent()
+        .expect("tar file should have parent");
+    let out = tempfile::NamedTempFile::new_in(dir)
+        .with_context(|| format!("failed to create temporary file in {dir:?}"))?;
+    let mut encoder = zstd::Encoder::new(out, 0).context("failed to start compressing")?;
+    encoder
+        .set_pledged_src_size(Some(len))
+        .and_then(|()| encoder.include_contentsize(true))
+        .context("failed to start compressing")?;
+    io::copy(&mut file, &mut encoder).with_context(|| format!("failed to compress {path}"))?;
+    let out = encoder
+        .finish()
+        .with_context(|| format!("failed to compress {path}"))?;
+    out.persist(path.as_host_raw())
+        .with_context(|| format!("failed to replace {path}"))?;
+    Ok(())
+}
+
+pub fn host_tempfile() -> io::Result<tempfile::NamedTempFile> {
+    tempfile::Builder::new()
+        .prefix(&format!("{TEMP_FILE_PREFIX}{}-", std::process::id()))
+        .tempfile()
+}
+
 pub fn try_exists(path: &HostPath) -> io::Result<bool> {
     // Adapted from rust `library/std/src/sys_common/fs.rs`
     // since `std::fs::try_exists` is unstable
@@ -363,3 +620,64 @@ pub fn try_exists(path: &HostPath) -> io::Result<bool> {
         Err(error) => Err(error),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hash_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = HostPath::try_from(dir.path().join("x.tar")).unwrap();
+        std::fs::write(path.as_host_raw(), "abc").unwrap();
+        assert_eq!(
+            super::hash_file(&path).unwrap(),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+    }
+
+    #[test]
+    fn compress_tar() {
+        use std::io::Read;
+        let dir = tempfile::tempdir().unwrap();
+        let path = HostPath::try_from(dir.path().join("x.tar")).unwrap();
+        let contents = "tar contents ".repeat(100);
+        std::fs::write(path.as_host_raw(), &contents).unwrap();
+        assert_eq!(tar_size(&path).unwrap(), 1300);
+
+        super::compress_tar(&path).unwrap();
+        assert!(std::fs::metadata(path.as_host_raw()).unwrap().len() < 100);
+        assert_eq!(tar_size(&path).unwrap(), 1300);
+        let mut decompressed = String::new();
+        open_tar(&path)
+            .unwrap()
+            .read_to_string(&mut decompressed)
+            .unwrap();
+        assert_eq!(decompressed, contents);
+
+        let plain = HostPath::try_from(dir.path().join("y.tar")).unwrap();
+        std::fs::write(plain.as_host_raw(), "plain").unwrap();
+        let mut seeds = Vec::new();
+        write_seeds([&path, &plain], &mut seeds).unwrap();
+        assert_eq!(seeds, format!("{contents}plain").as_bytes());
+    }
+
+    #[test]
+    fn hash_dir() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = HostPath::try_from(dir.path().to_owned()).unwrap();
+        std::fs::create_dir(dir.path().join("sub")).unwrap();
+        std::fs::write(dir.path().join("sub/build.sh"), "echo hi").unwrap();
+        let hash = super::hash_dir(&path).unwrap();
+
+        // Modification times don't matter.
+        std::fs::write(dir.path().join("sub/build.sh"), "echo hi").unwrap();
+        assert_eq!(super::hash_dir(&path).unwrap(), hash);
+
+        std::fs::write(dir.path().join("sub/build.sh"), "echo bye").unwrap();
+        assert_ne!(super::hash_dir(&path).unwrap(), hash);
+        std::fs::write(dir.path().join("sub/build.sh"), "echo hi").unwrap();
+        std::fs::rename(dir.path().join("sub"), dir.path().join("other")).unwrap();
+        assert_ne!(super::hash_dir(&path).unwrap(), hash);
+    }
+}
@@ -0,0 +1,126 @@
+//! Copies the host's git identity into environments.
+//!
+//! With [`Config::git_identity`](super::config::Config::git_identity) set,
+//! Cubicle reads the user's name and email from the host's git config and
+//! writes them to a `.gitconfig` in each new or reset environment, so that
+//! commits work right away.
+
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use super::command_ext::Command;
+use super::fs_util::single_file_tar;
+use super::{Cubicle, Hint};
+use crate::somehow::{somehow as anyhow, Context, Result};
+
+/// Settings copied with [`Config::git_identity`](super::config::Config::git_identity).
+const IDENTITY_KEYS: [&str; 2] = ["user.name", "user.email"];
+
+/// Settings copied with
+/// [`Config::git_signing_key`](super::config::Config::git_signing_key).
+/// These refer to a key but don't contain it.
+const SIGNING_KEYS: [&str; 3] = ["user.signingkey", "gpg.format", "commit.gpgsign"];
+
+impl Cubicle {
+    /// Builds a seed containing a `.gitconfig` with the host's git identity,
+    /// or returns `None` if that's not configured.
+    ///
+    /// The returned temporary file must be kept until the environment is
+    /// initialized.
+    pub(super) fn git_identity_seed(&self) -> Result<Option<tempfile::NamedTempFile>> {
+        let config = &self.shared.config;
+        if !config.git_identity {
+            return Ok(None);
+        }
+        let signing_keys: &[&str] = if config.git_signing_key {
+            &SIGNING_KEYS
+        } else {
+            &[]
+        };
+        let mut settings = Vec::new();
+        for key in IDENTITY_KEYS.iter().chain(signing_keys) {
+            if let Some(value) = host_git_config(key)? {
+                settings.push((*key, value));
+            }
+        }
+        if settings.is_empty() {
+            self.shared.hint(
+                Hint::GitIdentityMissing,
+                String::from(
+                    "`git_identity` is set, but the host's git config has no `user.name` or `user.email`",
+                ),
+            );
+            return Ok(None);
+        }
+        single_file_tar(Path::new(".gitconfig"), gitconfig(&settings).as_bytes()).map(Some)
+    }
+}
+
+/// Reads a setting from the host user's global git config.
+fn host_git_config(key: &str) -> Result<Option<String>> {
+    let output = Command::query("git")
+        .args(["config", "--global", "--includes", "--get", key])
+        .output()
+        .context("failed to read the host's git config")?;
+    match output.status.code() {
+        Some(0) => Ok(Some(
+            String::from_utf8_lossy(&output.stdout)
+                .trim_end_matches('\n')
+                .to_owned(),
+        )),
+        // The setting is not present.
+        Some(1) => Ok(None),
+        _ => Err(anyhow!(
+            "`git config --get {key}` exited with {}: {}",
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        )),
+    }
+}
+
+/// Formats the settings, given as `section.name` keys and values, as a git
+/// config file.
+fn gitconfig(settings: &[(&str, String)]) -> String {
+    let mut sections: BTreeMap<&str, Vec<(&str, &str)>> = BTreeMap::new();
+    for (key, value) in settings {
+        if let Some((section, name)) = key.split_once('.') {
+            sections.entry(section).or_default().push((name, value));
+        }
+    }
+    let mut buf = String::from("# Generated by Cubicle from the host's git config.\n");
+    for (section, values) in sections {
+        buf.push_str(&format!("[{section}]\n"));
+        for (name, value) in values {
+            let value = value
+                .replace('\\', "\\\\")
+                .replace('"', "\\\"")
+                .replace('\n', "\\n");
+            buf.push_str(&format!("\t{name} = \"{value}\"\n"));
+        }
+    }
+    buf
+}
+
+#[cfg(test)]
+mod tests {
+    use expect_test::expect;
+
+    #[test]
+    fn gitconfig() {
+        expect![[r#"
+            # Generated by Cubicle from the host's git config.
+            [gpg]
+            	format = "ssh"
+            [user]
+            	name = "Ada \"The Countess\" Lovelace"
+            	email = "ada@example.com"
+            	signingkey = "C:\\keys\\ada.pub"
+        "#]]
+        .assert_eq(&super::gitconfig(&[
+            ("user.name", String::from(r#"Ada "The Countess" Lovelace"#)),
+            ("user.email", String::from("ada@example.com")),
+            ("user.signingkey", String::from(r"C:\keys\ada.pub")),
+            ("gpg.format", String::from("ssh")),
+        ]));
+    }
+}
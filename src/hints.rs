@@ -0,0 +1,141 @@
+//! Prints advice that users may not want to see over and over.
+//!
+//! Each kind of hint has a name that can be listed in
+//! [`Config::hints`](super::config::Config::hints) to turn it off. Otherwise,
+//! each hint is printed at most once per [`INTERVAL`], tracked by the
+//! modification time of a marker file in the cache directory.
+
+use std::time::{Duration, SystemTime};
+
+use super::CubicleShared;
+use crate::somehow::{warn, warn_brief, Context};
+
+/// How long to wait before repeating a hint.
+const INTERVAL: Duration = Duration::from_secs(60 * 60 * 24);
+
+/// A kind of hint that Cubicle may print.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub(super) enum Hint {
+    /// An environment is over [`Config::disk_quota`](super::config::Config::disk_quota).
+    DiskQuota,
+    /// [`Config::git_identity`](super::config::Config::git_identity) is set,
+    /// but the host has no git identity to copy.
+    GitIdentityMissing,
+    /// A new environment will have access to the host's Docker socket.
+    HostDockerSocket,
+    /// Environments' home directories are still in the cache directory. See
+    /// [`layout`](super::layout).
+    LegacyHomeDirs,
+    /// A package in a new or reset environment hides another package of
+    /// the same name from a later package source.
+    ShadowedPackages,
+}
+
+impl Hint {
+    /// All the hints, for checking the names in the config file.
+    pub const ALL: [Self; 5] = [
+        Self::DiskQuota,
+        Self::GitIdentityMissing,
+        Self::HostDockerSocket,
+        Self::LegacyHomeDirs,
+        Self::ShadowedPackages,
+    ];
+
+    /// Returns the hint's name, as used in the config file.
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::DiskQuota => "disk-quota",
+            Self::GitIdentityMissing => "git-identity-missing",
+            Self::HostDockerSocket => "host-docker-socket",
+            Self::LegacyHomeDirs => "legacy-home-dirs",
+            Self::ShadowedPackages => "shadowed-packages",
+        }
+    }
+}
+
+impl CubicleShared {
+    /// Prints the hint's message, unless it's disabled in the config or was
+    /// printed recently.
+    pub(super) fn hint(&self, hint: Hint, message: String) {
+        if self
+            .config
+            .hints
+            .disable
+            .iter()
+            .any(|name| name == hint.name())
+        {
+            return;
+        }
+
+        let marker = self.cache_dir.join("hints").join(hint.name());
+        let last_shown = std::fs::metadata(marker.as_host_raw())
+            .and_then(|metadata| metadata.modified())
+            .ok();
+        if !is_due(last_shown, self.clock.now()) {
+            return;
+        }
+        warn_brief(format!(
+            "{message}\n(This hint is shown at most once a day. To turn it off, \
+            add {:?} to `hints.disable` in `cubicle.toml`.)",
+            hint.name()
+        ));
+
+        let dir = self.cache_dir.join("hints");
+        if let Err(e) = std::fs::create_dir_all(dir.as_host_raw())
+            .and_then(|()| std::fs::write(marker.as_host_raw(), ""))
+            .with_context(|| format!("failed to write {marker}"))
+        {
+            warn(e);
+        }
+    }
+}
+
+/// Returns the names in [`Config::hints`](super::config::Config::hints) that
+/// don't refer to any hint.
+pub(super) fn unknown_hint_names(disable: &[String]) -> Vec<&str> {
+    disable
+        .iter()
+        .map(String::as_str)
+        .filter(|name| !Hint::ALL.iter().any(|hint| hint.name() == *name))
+        .collect()
+}
+
+/// Returns true if a hint last shown at `last_shown` should be shown again.
+fn is_due(last_shown: Option<SystemTime>, now: SystemTime) -> bool {
+    match last_shown {
+        Some(last_shown) => !matches!(now.duration_since(last_shown), Ok(d) if d < INTERVAL),
+        None => true,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn is_due() {
+        let now = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        assert!(super::is_due(None, now));
+        assert!(!super::is_due(Some(now), now));
+        assert!(!super::is_due(
+            Some(now - Duration::from_secs(60 * 60 * 23)),
+            now
+        ));
+        assert!(super::is_due(Some(now - INTERVAL), now));
+        // A marker from the future (clock skew) shouldn't silence a hint
+        // forever.
+        assert!(super::is_due(Some(now + Duration::from_secs(60)), now));
+    }
+
+    #[test]
+    fn unknown_hint_names() {
+        assert_eq!(
+            vec!["disk-quotas"],
+            super::unknown_hint_names(&[
+                String::from("disk-quota"),
+                String::from("disk-quotas"),
+                String::from("host-docker-socket"),
+            ])
+        );
+    }
+}
@@ -0,0 +1,164 @@
+//! Runs the user's lifecycle hooks on the host.
+//!
+//! See [`Config::hooks`](super::config::Config::hooks).
+
+use super::command_ext::Command;
+use super::config::Hooks;
+use super::env_lock::LOCK_HELD_VAR;
+use super::{Cubicle, EnvironmentName};
+use crate::somehow::{somehow as anyhow, warn, Context, Result};
+
+/// A point in an environment's lifecycle where a hook may run.
+#[derive(Clone, Copy, Debug)]
+pub(super) enum Hook {
+    PreCreate,
+    PostCreate,
+    PreEnter,
+    PrePurge,
+}
+
+impl Hook {
+    /// Returns the hook's name, as in the config file.
+    fn name(self) -> &'static str {
+        match self {
+            Self::PreCreate => "pre_create",
+            Self::PostCreate => "post_create",
+            Self::PreEnter => "pre_enter",
+            Self::PrePurge => "pre_purge",
+        }
+    }
+
+    fn command(self, hooks: &Hooks) -> Option<&str> {
+        match self {
+            Self::PreCreate => hooks.pre_create.as_deref(),
+            Self::PostCreate => hooks.post_create.as_deref(),
+            Self::PreEnter => hooks.pre_enter.as_deref(),
+            Self::PrePurge => hooks.pre_purge.as_deref(),
+        }
+    }
+
+    /// Returns whether the hook runs while Cubicle holds the environment's
+    /// lock.
+    fn holds_lock(self) -> bool {
+        matches!(self, Self::PreCreate | Self::PrePurge)
+    }
+}
+
+impl Cubicle {
+    /// Runs the configured hook for the environment, if any, and returns an
+    /// error if it fails.
+    ///
+    /// Post-hooks only warn on failure, since the operation has already
+    /// happened.
+    pub(super) fn run_hook(&self, hook: Hook, name: &EnvironmentName) -> Result<()> {
+        let result = run_hook(&self.shared.config.hooks, hook, name);
+        match (hook, result) {
+            (Hook::PostCreate, Err(e)) => {
+                warn(e);
+                Ok(())
+            }
+            (_, result) => result,
+        }
+    }
+}
+
+fn run_hook(hooks: &Hooks, hook: Hook, name: &EnvironmentName) -> Result<()> {
+    let Some(command) = hook.command(hooks) else {
+        return Ok(());
+    };
+    let mut sh = Command::new("sh");
+    sh.arg("-c")
+        .arg(command)
+        .env("CUBICLE", name.as_str())
+        .env("CUBICLE_HOOK", hook.name());
+    if hook.holds_lock() {
+        sh.env(LOCK_HELD_VAR, name.as_str());
+    }
+    let status = sh
+        .status()
+        .with_context(|| format!("failed to run `{}` hook for {name}", hook.name()))?;
+    if !status.success() {
+        // This is deliberately not an `ExitStatusError`, which callers would
+        // mistake for the environment's shell exiting.
+        return Err(anyhow!(
+            "`{}` hook for {name} exited with {status}",
+            hook.name()
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{self, FakeRunner};
+    use crate::{Force, Quiet};
+    use std::collections::BTreeSet;
+    use std::path::Path;
+    use std::str::FromStr;
+    use std::sync::mpsc;
+    use std::time::Duration;
+
+    #[test]
+    fn run_hook() {
+        let env = EnvironmentName::from_str("web").unwrap();
+        let hooks = Hooks {
+            pre_create: Some(String::from(
+                r#"test "$CUBICLE" = web && test "$CUBICLE_HOOK" = pre_create"#,
+            )),
+            pre_purge: Some(String::from("exit 3")),
+            ..Hooks::default()
+        };
+        super::run_hook(&hooks, Hook::PreCreate, &env).unwrap();
+        super::run_hook(&hooks, Hook::PreEnter, &env).unwrap();
+        assert_eq!(
+            r#"`pre_purge` hook for "web" exited with exit status: 3"#,
+            super::run_hook(&hooks, Hook::PrePurge, &env)
+                .unwrap_err()
+                .to_string()
+        );
+    }
+
+    #[test]
+    fn hooks_reenter_cub() {
+        let root = tempfile::tempdir().unwrap();
+        // The hooks run `reentered_by_hook` below in a new process.
+        let command = format!(
+            "CUBICLE_TEST_ROOT={} {} --exact hooks::tests::reentered_by_hook >/dev/null",
+            shlex::try_quote(root.path().to_str().unwrap()).unwrap(),
+            shlex::try_quote(std::env::current_exe().unwrap().to_str().unwrap()).unwrap(),
+        );
+        let mut config = testing::config("");
+        config.hooks = Hooks {
+            post_create: Some(command.clone()),
+            pre_purge: Some(command),
+            ..Hooks::default()
+        };
+        let cub = Cubicle::with_fake_runner(config, FakeRunner::new(), root.path()).unwrap();
+        let name = EnvironmentName::from_str("web").unwrap();
+        cub.new_environment(&name, Some(BTreeSet::new())).unwrap();
+        assert!(root.path().join("post_create").exists());
+        cub.purge_environment(&name, Quiet(true), Force(true))
+            .unwrap();
+        assert!(root.path().join("pre_purge").exists());
+    }
+
+    /// Acts as a hook that runs `cub exec` for `hooks_reenter_cub`. This does
+    /// nothing when run on its own.
+    #[test]
+    fn reentered_by_hook() {
+        let Some(root) = std::env::var_os("CUBICLE_TEST_ROOT") else {
+            return;
+        };
+        let root = Path::new(&root);
+        let cub = testing::cubicle(root, "");
+        let name = EnvironmentName::from_str(&std::env::var("CUBICLE").unwrap()).unwrap();
+        let (sender, receiver) = mpsc::channel();
+        std::thread::spawn(move || sender.send(cub.wait_for_environment(&name)));
+        receiver
+            .recv_timeout(Duration::from_secs(10))
+            .expect("hook should not wait for the environment's lock")
+            .unwrap();
+        std::fs::write(root.join(std::env::var("CUBICLE_HOOK").unwrap()), "").unwrap();
+    }
+}
@@ -0,0 +1,197 @@
+//! Lets environments run a few allowlisted commands on the host.
+//!
+//! Environments selected by [`Config::host_commands`](super::config::Config::host_commands)
+//! get a directory shared with the host, in which `cub host-commands` listens
+//! on a Unix socket. Inside the environment, the `cub-host` script (from the
+//! `host-commands` package) sends a command line to this socket. The host
+//! runs it, without a shell, only if the command is on the environment's
+//! allowlist, then replies with its exit code. This is useful for opening
+//! links in the host's browser with `xdg-open`, for example.
+//!
+//! The protocol is simple: the client sends each argument followed by a NUL
+//! byte, then shuts down its side of the connection. The server replies with
+//! a single line: `exit N` if it ran the command, or `error: ...` otherwise.
+
+use std::collections::BTreeSet;
+use std::io::{Read, Write};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::process::Stdio;
+use std::thread::sleep;
+use std::time::Duration;
+use tracing::info;
+
+use super::command_ext::Command;
+use super::runner::HostSocket;
+use super::{Cubicle, CubicleShared, EnvironmentName, HostPath};
+use crate::somehow::{somehow as anyhow, warn, Context, Result};
+
+/// Where the shared directory is placed inside environments.
+const ENV_PATH: &str = "/run/cubicle-host";
+
+/// Name of the socket file within the shared directory.
+const SOCKET_NAME: &str = "socket";
+
+/// How often `cub host-commands` looks for new environments.
+const RESCAN_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Upper limit on the size of a request, to bound memory use.
+const MAX_REQUEST_BYTES: u64 = 64 * 1024;
+
+/// Returns the directory to share with the environment for running host
+/// commands, if any commands are allowed for the environment. Creates the
+/// directory on the host if needed.
+pub fn env_host_commands_share(
+    program: &CubicleShared,
+    name: &EnvironmentName,
+) -> Result<Option<HostSocket>> {
+    if program.config.host_commands_for(name).is_empty() {
+        return Ok(None);
+    }
+    let host_path = host_commands_dir(program, name);
+    std::fs::create_dir_all(host_path.as_host_raw())
+        .with_context(|| format!("failed to create host commands directory {host_path}"))?;
+    Ok(Some(HostSocket {
+        host_path,
+        env_path: String::from(ENV_PATH),
+        env_var: ("CUBICLE_HOST_COMMANDS", format!("{ENV_PATH}/{SOCKET_NAME}")),
+    }))
+}
+
+fn host_commands_dir(program: &CubicleShared, name: &EnvironmentName) -> HostPath {
+    program
+        .data_dir
+        .join("host-commands")
+        .join(name.as_filename())
+}
+
+impl Cubicle {
+    /// Corresponds to `cub host-commands`.
+    ///
+    /// Runs until interrupted, serving requests to run host commands from
+    /// the environments that have any allowed.
+    pub fn serve_host_commands(&self) -> Result<()> {
+        let mut serving: BTreeSet<EnvironmentName> = BTreeSet::new();
+        loop {
+            for name in self.get_environment_names()? {
+                let allowed = self.shared.config.host_commands_for(&name);
+                if allowed.is_empty() || serving.contains(&name) {
+                    continue;
+                }
+                match self.listen(&name) {
+                    Ok(listener) => {
+                        let env = name.clone();
+                        std::thread::spawn(move || serve(&env, &allowed, listener));
+                        serving.insert(name);
+                    }
+                    Err(e) => warn(e.context(format!(
+                        "failed to serve host commands for environment {name}"
+                    ))),
+                }
+            }
+            sleep(RESCAN_INTERVAL);
+        }
+    }
+
+    fn listen(&self, name: &EnvironmentName) -> Result<UnixListener> {
+        let share =
+            env_host_commands_share(&self.shared, name)?.expect("host commands should be allowed");
+        let path = share.host_path.join(SOCKET_NAME);
+        // Clean up after a previous `cub host-commands`.
+        match std::fs::remove_file(path.as_host_raw()) {
+            Ok(()) => {}
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e).with_context(|| format!("failed to remove {path}")),
+        }
+        UnixListener::bind(path.as_host_raw())
+            .with_context(|| format!("failed to listen on Unix socket {path}"))
+    }
+}
+
+fn serve(name: &EnvironmentName, allowed: &BTreeSet<String>, listener: UnixListener) {
+    for stream in listener.incoming() {
+        match stream {
+            Ok(stream) => {
+                let name = name.clone();
+                let allowed = allowed.clone();
+                std::thread::spawn(move || {
+                    if let Err(e) = handle(&name, &allowed, stream) {
+                        warn(e.context(format!("failed to handle host command from {name}")));
+                    }
+                });
+            }
+            Err(e) => {
+                warn(anyhow!(
+                    "failed to accept host command connection from {name}: {e}"
+                ));
+                sleep(RESCAN_INTERVAL);
+            }
+        }
+    }
+}
+
+fn handle(name: &EnvironmentName, allowed: &BTreeSet<String>, stream: UnixStream) -> Result<()> {
+    let mut request = Vec::new();
+    (&stream)
+        .take(MAX_REQUEST_BYTES + 1)
+        .read_to_end(&mut request)
+        .context("failed to read request")?;
+    let reply = match parse_request(&request, allowed) {
+        Ok(args) => {
+            info!("Running host command for {name}: {args:?}");
+            match Command::new(&args[0])
+                .args(&args[1..])
+                .stdin(Stdio::null())
+                .stdout(Stdio::null())
+                .stderr(Stdio::null())
+                .status()
+            {
+                Ok(status) => format!("exit {}", status.code().unwrap_or(1)),
+                Err(e) => {
+                    warn(e);
+                    format!("error: failed to run {:?} on host", args[0])
+                }
+            }
+        }
+        Err(e) => format!("error: {e}"),
+    };
+    writeln!(&stream, "{reply}").context("failed to write reply")
+}
+
+/// Parses a request from the client, checking that the command is allowed.
+fn parse_request(request: &[u8], allowed: &BTreeSet<String>) -> Result<Vec<String>> {
+    if request.len() as u64 > MAX_REQUEST_BYTES {
+        return Err(anyhow!("request too large"));
+    }
+    let request = std::str::from_utf8(request).map_err(|_| anyhow!("request not valid UTF-8"))?;
+    let Some(request) = request.strip_suffix('\0') else {
+        return Err(anyhow!("request not terminated"));
+    };
+    let args: Vec<String> = request.split('\0').map(String::from).collect();
+    if !allowed.contains(&args[0]) {
+        return Err(anyhow!("command not allowed: {:?}", args[0]));
+    }
+    Ok(args)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_request() {
+        let allowed = BTreeSet::from([String::from("xdg-open")]);
+        let parse = |request: &[u8]| match super::parse_request(request, &allowed) {
+            Ok(args) => format!("{args:?}"),
+            Err(e) => e.to_string(),
+        };
+        assert_eq!(
+            parse(b"xdg-open\0https://example.com/\0"),
+            r#"["xdg-open", "https://example.com/"]"#
+        );
+        assert_eq!(parse(b"xdg-open\0\0"), r#"["xdg-open", ""]"#);
+        assert_eq!(parse(b"rm\0-rf\0/\0"), r#"command not allowed: "rm""#);
+        assert_eq!(parse(b"\0"), r#"command not allowed: """#);
+        assert_eq!(parse(b"xdg-open"), "request not terminated");
+        assert_eq!(parse(b""), "request not terminated");
+    }
+}
@@ -0,0 +1,98 @@
+//! Inspects the OS image that environments' OS packages are installed into.
+//!
+//! For the Docker runner, this is the base image that Cubicle builds. Other
+//! runners share the host's OS, so their "image" is the host.
+
+use std::collections::{BTreeMap, BTreeSet};
+
+use super::distro::parse_installed_packages;
+use super::runner::Runner;
+use super::{distro_dependencies, Cubicle, Distro, EnvironmentName, Json, RunnerKind};
+use crate::somehow::{somehow as anyhow, Context, Result};
+
+impl Cubicle {
+    /// Returns the distribution of the image for the environment, or of the
+    /// image for environments without their own.
+    fn image_distro(&self, env: Option<&EnvironmentName>) -> Distro {
+        match (env, self.shared.config.runner) {
+            (Some(env), _) => self.env_distro(env),
+            (None, RunnerKind::Docker) => {
+                let docker = &self.shared.config.runners.docker;
+                docker.distro_for_image(&docker.image)
+            }
+            (None, RunnerKind::Bubblewrap | RunnerKind::User) => Distro::host(),
+        }
+    }
+
+    /// Returns the OS packages installed in the image for the environment
+    /// (or the default image), mapped to their versions.
+    pub fn get_image_packages(
+        &self,
+        env: Option<&EnvironmentName>,
+    ) -> Result<BTreeMap<String, String>> {
+        let distro = self.image_distro(env);
+        let output = self
+            .runner
+            .query_image(env, distro.installed_packages_script())?;
+        Ok(parse_installed_packages(&output))
+    }
+
+    /// Corresponds to `cub image packages`.
+    pub fn print_image_packages(&self, env: Option<&EnvironmentName>, json: Json) -> Result<()> {
+        let packages = self.get_image_packages(env)?;
+        if json.0 {
+            println!(
+                "{}",
+                serde_json::to_string_pretty(&packages)
+                    .context("failed to serialize JSON while listing image packages")?
+            );
+            return Ok(());
+        }
+        let w = packages.keys().map(String::len).max().unwrap_or(0);
+        for (name, version) in &packages {
+            println!("{name:w$}  {version}");
+        }
+        Ok(())
+    }
+
+    /// Corresponds to `cub image packages --check`.
+    ///
+    /// Checks that every OS package that package manifests depend on (in
+    /// the image's distribution's namespace, like `[depends.debian]`) is
+    /// installed in the image or available from its package repositories.
+    /// This catches typos before a long build fails while installing
+    /// packages. Prints each missing dependency and returns an error if
+    /// there are any.
+    pub fn check_image_packages(&self, env: Option<&EnvironmentName>) -> Result<()> {
+        let distro = self.image_distro(env);
+        let specs = self.scan_packages()?;
+
+        let mut known: BTreeSet<String> = self.get_image_packages(env)?.into_keys().collect();
+        known.extend(
+            self.runner
+                .query_image(env, distro.available_packages_script())?
+                .lines()
+                .map(|line| line.trim().to_owned()),
+        );
+
+        let mut missing = 0;
+        for (name, dep) in distro_dependencies(distro, &specs) {
+            if !known.contains(dep.as_str()) {
+                println!(
+                    "Package {name} depends on {distro} package {dep}, which the image doesn't provide"
+                );
+                missing += 1;
+            }
+        }
+        if missing > 0 {
+            return Err(anyhow!(
+                "found {missing} {distro} dependencies that the image doesn't provide"
+            ));
+        }
+        println!(
+            "All {distro} dependencies of {} packages are available",
+            specs.len()
+        );
+        Ok(())
+    }
+}
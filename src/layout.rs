@@ -0,0 +1,139 @@
+//! Moves files from older versions' directory layouts.
+//!
+//! Older versions kept environments' home directories in Cubicle's directory
+//! within `$XDG_CACHE_HOME`. Tools that free up disk space or skip caches in
+//! backups treat everything there as disposable, but home directories may
+//! hold the only copy of shell history and other state. They now live within
+//! `$XDG_STATE_HOME`.
+
+use tracing::{debug, info};
+
+use super::fs_util::try_exists;
+use super::hints::Hint;
+use super::{Cubicle, CubicleShared, HostPath};
+use crate::somehow::{Context, Result};
+
+/// Returns where older versions kept environments' home directories.
+fn legacy_home_dirs(cache_dir: &HostPath) -> HostPath {
+    cache_dir.join("home")
+}
+
+/// Returns the directory for environments' home directories.
+///
+/// If only the legacy directory exists, this moves it into `state_dir`
+/// first. Renaming is atomic, so this is safe even if another `cub` process
+/// does the same at once, and running environments keep their mounts. If
+/// the move fails, for example because the directories are on different
+/// filesystems, this keeps using the legacy directory.
+pub(super) fn home_dirs(cache_dir: &HostPath, state_dir: &HostPath) -> HostPath {
+    let legacy = legacy_home_dirs(cache_dir);
+    let current = state_dir.join("home");
+    if !try_exists(&legacy).unwrap_or(false) || try_exists(&current).unwrap_or(false) {
+        return current;
+    }
+    match migrate(&legacy, &current) {
+        Ok(()) => {
+            info!("Moved environment home directories from {legacy} to {current}");
+            current
+        }
+        Err(e) => {
+            // This would fail again on every run, so it's left to the hint
+            // and `cub doctor` to report.
+            debug!("{e:#}");
+            legacy
+        }
+    }
+}
+
+fn migrate(legacy: &HostPath, current: &HostPath) -> Result<()> {
+    let parent = current
+        .as_host_raw()
+        .parent()
+        .expect("home directories should have a parent");
+    std::fs::create_dir_all(parent)
+        .with_context(|| format!("failed to create directory {parent:?}"))?;
+    std::fs::rename(legacy.as_host_raw(), current.as_host_raw()).with_context(|| {
+        format!("failed to move environment home directories from {legacy} to {current}")
+    })
+}
+
+impl CubicleShared {
+    /// Returns true if environments' home directories are still in the
+    /// legacy location because they couldn't be moved.
+    fn uses_legacy_home_dirs(&self) -> bool {
+        self.home_dirs.as_host_raw() == legacy_home_dirs(&self.cache_dir).as_host_raw()
+    }
+
+    /// Prints a hint if environments' home directories are still in the
+    /// legacy location.
+    pub(super) fn hint_legacy_layout(&self) {
+        if self.uses_legacy_home_dirs() {
+            self.hint(
+                Hint::LegacyHomeDirs,
+                format!(
+                    "Environment home directories are in {}, where tools that clean \
+                    up caches may delete them. See `cub doctor`.",
+                    self.home_dirs
+                ),
+            );
+        }
+    }
+}
+
+impl Cubicle {
+    /// Returns descriptions of problems with the directory layout left
+    /// behind by older versions, for `cub doctor`.
+    pub(super) fn legacy_layout_problems(&self) -> Vec<String> {
+        let shared = &self.shared;
+        let legacy = legacy_home_dirs(&shared.cache_dir);
+        let current = shared.state_dir.join("home");
+        if shared.uses_legacy_home_dirs() {
+            vec![format!(
+                "Environment home directories are in {legacy}, where tools that \
+                clean up caches may delete them. Cubicle couldn't move them to \
+                {current} automatically. Move the directory there while no \
+                environments are running."
+            )]
+        } else if try_exists(&legacy).unwrap_or(false) {
+            vec![format!(
+                "{legacy} is left over from an older version of Cubicle, which \
+                kept environment home directories there. Cubicle now uses \
+                {current}. Move anything you need out of it, then delete it."
+            )]
+        } else {
+            Vec::new()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn home_dirs() {
+        let root = tempfile::tempdir().unwrap();
+        let root = HostPath::try_from(root.path().to_owned()).unwrap();
+        let cache_dir = root.join("cache");
+        let state_dir = root.join("state");
+        let current = state_dir.join("home");
+
+        // New installations use the state directory.
+        let dirs = super::home_dirs(&cache_dir, &state_dir);
+        assert_eq!(dirs.as_host_raw(), current.as_host_raw());
+
+        // Existing home directories are moved there.
+        let legacy = legacy_home_dirs(&cache_dir);
+        std::fs::create_dir_all(legacy.join("env").as_host_raw()).unwrap();
+        let dirs = super::home_dirs(&cache_dir, &state_dir);
+        assert_eq!(dirs.as_host_raw(), current.as_host_raw());
+        assert!(current.join("env").as_host_raw().is_dir());
+        assert!(!legacy.as_host_raw().exists());
+
+        // Once both exist, the legacy one is left alone.
+        std::fs::create_dir_all(legacy.as_host_raw()).unwrap();
+        let dirs = super::home_dirs(&cache_dir, &state_dir);
+        assert_eq!(dirs.as_host_raw(), current.as_host_raw());
+        assert!(legacy.as_host_raw().exists());
+    }
+}
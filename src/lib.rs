@@ -1,4 +1,10 @@
 #![warn(missing_docs)]
+// Builds without some runners leave behind helpers that only those runners
+// use. It's not worth cluttering the code to exclude each of them.
+#![cfg_attr(
+    not(all(feature = "bubblewrap", feature = "docker", feature = "user-runner")),
+    allow(dead_code, unused_imports)
+)]
 
 //! This crate is the library underneath the Cubicle command-line program.
 //!
@@ -6,21 +12,31 @@
 //! Rust and to allow for system-level tests. Most people should probably use
 //! the command-line program instead.
 //!
+//! Library users can leave out parts they don't need with Cargo features. The
+//! `bubblewrap`, `docker`, and `user-runner` features each include a runner,
+//! and `cli` builds the `cub` program. These are all enabled by default. At
+//! least one runner is required.
+//!
+//! Programs that embed Cubicle, rather than reimplement `cub`, should start
+//! with the [`ops`] module. Its interface takes and returns plain data and is
+//! meant to stay stable.
+//!
 //! The remainder of this header reproduces the README from the command-line
 //! program. Skip below to learn about the the library API.
 #![doc = include_str!("../README.md")]
 
-use clap::ValueEnum;
 use serde::Deserialize;
 use serde::Serialize;
+use std::cmp::Reverse;
 use std::collections::{BTreeMap, BTreeSet};
 use std::ffi::OsStr;
 use std::fmt::{self, Debug, Display};
-use std::path::PathBuf;
-use std::process::ExitStatus;
-use std::rc::Rc;
+use std::path::{Component, Path, PathBuf};
+use std::process::{ExitStatus, Output};
 use std::str::FromStr;
+use std::sync::{Arc, Mutex};
 use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tracing::info;
 
 pub mod somehow;
 pub use somehow::Result;
@@ -30,73 +46,305 @@ mod paths;
 use paths::HostPath;
 
 pub mod config;
-use config::Config;
+use config::{Config, NestedContainers};
 
 mod randname;
 use randname::RandomNameGenerator;
 
 mod runner;
-use runner::{CheckedRunner, EnvFilesSummary, EnvironmentExists, Init, Runner, RunnerCommand};
+use runner::{
+    select_runner, CheckedRunner, EnvFilesSummary, EnvironmentExists, Init, Runner, RunnerCommand,
+};
 
 mod bytes;
 use bytes::Bytes;
 
+mod clock;
+pub use clock::{Clock, FakeClock, SystemClock};
+
 mod encoding;
 use encoding::FilenameEncoder;
 
 mod fs_util;
 use fs_util::{try_exists, DirSummary};
 
+mod lock;
+
+mod env_lock;
+
+mod metadata;
+
+mod du_cache;
+pub use du_cache::Refresh;
+
+mod parallel;
+use parallel::parallel_map;
+
+mod cleanup;
+
+mod dotfiles;
+
+mod git_identity;
+
+mod hooks;
+
+mod hints;
+use hints::Hint;
+use hooks::Hook;
+
+mod recording;
+
+mod delimited;
+use delimited::{path_field, time_field, Delimited};
+
+mod confirm;
+pub use confirm::{AssumeYes, Confirmer, TerminalConfirmer};
+
+mod connect;
+pub use connect::ConnectInfoFormat;
+
+mod progress;
+use progress::Event;
+pub use progress::ProgressFormat;
+
+#[cfg(feature = "cli")]
+mod logging;
+#[cfg(feature = "cli")]
+pub use logging::{init_logging, Verbosity};
+
+mod workspace;
+pub use workspace::{WorkspaceDetails, WorkspaceListFormat, WorkspaceName};
+
+mod image;
+
+mod copy;
+pub use copy::CopyPath;
+
+mod layout;
+
+mod doctor;
+
+mod prune;
+
+pub mod ops;
+
+mod self_test;
+
+mod stats;
+pub use stats::{DiskUsage, EnvironmentDiskUsage, Json};
+
 mod os_util;
-use os_util::host_home_dir;
+use os_util::{host_home_dir, host_id, host_shell};
+
+mod systemd;
 
 mod packages;
-use packages::{write_package_list_tar, Target};
+use packages::{distro_dependencies, write_package_list_tar, SpecCache, Target};
 pub use packages::{
-    FullPackageName, ListPackagesFormat, PackageDetails, PackageName, PackageNamespace,
-    PackageSpec, PackageSpecs, ShouldPackageUpdate, UpdatePackagesConditions,
+    plan, FullPackageName, ListPackagesFormat, NewPackage, PackageDetails, PackageGraphFormat,
+    PackageName, PackageNamespace, PackageSpec, PackageSpecs, ReverseDependencies,
+    ShouldPackageUpdate, UpdatePackagesConditions,
 };
 
+#[cfg(not(any(feature = "bubblewrap", feature = "docker", feature = "user-runner")))]
+compile_error!("Cubicle needs at least one runner: enable the `bubblewrap`, `docker`, or `user-runner` feature");
+
+mod clipboard;
+
+mod host_commands;
+
 mod command_ext;
+pub use command_ext::{OutputStream, Recording, Transcript, TranscriptEntry};
 
-#[cfg(target_os = "linux")]
+#[cfg(all(feature = "bubblewrap", target_os = "linux"))]
 mod bubblewrap;
-#[cfg(target_os = "linux")]
+#[cfg(all(feature = "bubblewrap", target_os = "linux"))]
 use bubblewrap::Bubblewrap;
 
+#[cfg(all(feature = "bubblewrap", target_os = "linux"))]
+mod seed_delta;
+
+#[cfg(any(all(feature = "bubblewrap", target_os = "linux"), feature = "docker"))]
+mod seed_store;
+
+#[cfg(feature = "docker")]
 mod docker;
+#[cfg(feature = "docker")]
 use docker::Docker;
 
+#[cfg(feature = "user-runner")]
 mod user;
+#[cfg(feature = "user-runner")]
 use user::User;
 
 mod apt;
 
+mod distro;
+pub use distro::Distro;
+
+mod platform;
+pub use platform::Platform;
+
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
+
+#[cfg(feature = "tokio")]
+mod async_cubicle;
+#[cfg(feature = "tokio")]
+pub use async_cubicle::AsyncCubicle;
+
 /// The main Cubicle program functionality.
 ///
+/// A `Cubicle` may be shared between threads, for example to build several
+/// packages at once. Operations on different environments can run
+/// concurrently; the package cache and other shared files are protected by
+/// file locks.
+///
 // This struct is split in two so that the runner may also keep a reference to
 // `shared`.
 pub struct Cubicle {
-    shared: Rc<CubicleShared>,
+    shared: Arc<CubicleShared>,
     runner: CheckedRunner,
 }
 
+const _: () = {
+    const fn assert_send_sync<T: Send + Sync>() {}
+    assert_send_sync::<Cubicle>();
+};
+
 struct CubicleShared {
     config: Config,
     shell: String,
     exe_name: String,
     home: HostPath,
+    /// Cubicle's directory within `$XDG_CACHE_HOME`, possibly scoped to this
+    /// host. See [`Config::host_scoped_dirs`].
+    cache_dir: HostPath,
+    /// Cubicle's directory within `$XDG_DATA_HOME`, possibly scoped to this
+    /// host. See [`Config::host_scoped_dirs`].
+    data_dir: HostPath,
+    /// Cubicle's directory within `$XDG_STATE_HOME`, possibly scoped to this
+    /// host. See [`Config::host_scoped_dirs`].
+    state_dir: HostPath,
+    /// Where the Bubblewrap runner and the Docker runner with bind mounts
+    /// keep environments' home directories. This is normally within
+    /// `state_dir`, unless the [`layout`] migration failed.
+    home_dirs: HostPath,
     package_cache: HostPath,
     code_package_dir: HostPath,
     user_package_dir: HostPath,
     random_name_gen: RandomNameGenerator,
+    clock: Box<dyn Clock>,
+    progress: Mutex<ProgressFormat>,
+    confirmer: Mutex<Box<dyn Confirmer>>,
+    /// Package builder environments that are building a package with
+    /// networking disabled. See [`Config::offline_package_builds`].
+    offline_builders: Mutex<BTreeSet<EnvironmentName>>,
+    /// The last result of [`Cubicle::scan_packages`].
+    package_specs: Mutex<Option<SpecCache>>,
     env_init_script: &'static [u8],
 }
 
+#[cfg(any(test, feature = "testing"))]
+impl CubicleShared {
+    /// Returns an instance with fixed paths under `/cubicle-test`, which
+    /// don't need to exist, for unit tests of the runners.
+    #[cfg(test)]
+    fn for_tests(config: Config) -> Arc<Self> {
+        Self::with_root(
+            config,
+            HostPath::try_from(String::from("/cubicle-test")).unwrap(),
+        )
+    }
+
+    /// Returns an instance that keeps all its files under `root`, for tests.
+    ///
+    /// This uses the first of [`Config::runner_choices`], since a config
+    /// deserialized directly hasn't chosen one.
+    fn with_root(mut config: Config, root: HostPath) -> Arc<Self> {
+        config.runner = config.runner_choices[0];
+        Arc::new(Self {
+            config,
+            shell: String::from("/bin/sh"),
+            exe_name: String::from("cub"),
+            home: root.join("home"),
+            cache_dir: root.join("cache"),
+            data_dir: root.join("data"),
+            state_dir: root.join("state"),
+            home_dirs: root.join("state").join("home"),
+            package_cache: root.join("cache").join("packages"),
+            code_package_dir: root.join("code").join("packages"),
+            user_package_dir: root.join("data").join("packages"),
+            random_name_gen: RandomNameGenerator::new(root.join("cache")),
+            clock: Box::new(SystemClock),
+            progress: Mutex::new(ProgressFormat::Default),
+            confirmer: Mutex::new(Box::new(TerminalConfirmer)),
+            offline_builders: Mutex::default(),
+            package_specs: Mutex::default(),
+            env_init_script: std::include_bytes!("env-init.sh"),
+        })
+    }
+}
+
+/// The base directories from the XDG Base Directory Specification that
+/// Cubicle keeps its files in.
+struct XdgDirs {
+    cache_home: HostPath,
+    data_home: HostPath,
+    state_home: HostPath,
+}
+
+impl XdgDirs {
+    /// Reads the directories from the environment variables, with the
+    /// specification's defaults relative to `$HOME`.
+    fn from_env() -> Result<Self> {
+        let home = host_home_dir();
+        let var = |name: &str, default: HostPath| -> Result<HostPath> {
+            match std::env::var(name) {
+                Ok(path) => Ok(HostPath::try_from(path)?),
+                Err(_) => Ok(default),
+            }
+        };
+        Ok(Self {
+            cache_home: var("XDG_CACHE_HOME", home.join(".cache"))?,
+            data_home: var("XDG_DATA_HOME", home.join(".local").join("share"))?,
+            state_home: var("XDG_STATE_HOME", home.join(".local").join("state"))?,
+        })
+    }
+}
+
 /// Named boolean flag for [`Cubicle::purge_environment`].
 #[derive(Clone, Copy, Debug, Eq, PartialEq)]
 pub struct Quiet(pub bool);
 
+/// Named boolean flag for [`Cubicle::reset_environment`],
+/// [`Cubicle::purge_environment`], and [`Cubicle::purge_workspace`].
+///
+/// When set, these proceed without asking the [`Confirmer`].
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Force(pub bool);
+
+/// Named boolean flag for [`Cubicle::reset_environment`].
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct KeepHome(pub bool);
+
+/// Named boolean flag for [`Cubicle::reset_environment`].
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Locked(pub bool);
+
+/// Named boolean flag for [`Cubicle::remove_packages_from_environment`] and
+/// [`Cubicle::prune`].
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct DryRun(pub bool);
+
+/// Named boolean flag for [`Cubicle::create_enter_tmp_environment`].
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Ephemeral(pub bool);
+
+/// Named boolean flag for [`Cubicle::enter_environment`].
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct ReadOnlyHome(pub bool);
+
 impl Cubicle {
     /// Creates a new instance.
     ///
@@ -110,18 +358,30 @@ impl Cubicle {
     /// - Loading and initializing filesystem structures.
     /// - Creating a runner.
     pub fn new(config: Config) -> Result<Self> {
-        let home = host_home_dir().clone();
-        let shell = std::env::var("SHELL").unwrap_or_else(|_| String::from("/bin/sh"));
+        Self::with_clock(config, Box::new(SystemClock))
+    }
 
-        let xdg_cache_home = match std::env::var("XDG_CACHE_HOME") {
-            Ok(path) => HostPath::try_from(path)?,
-            Err(_) => home.join(".cache"),
-        };
+    /// Like [`Cubicle::new`] but reads the current time from the given clock,
+    /// such as a [`FakeClock`] in tests.
+    pub fn with_clock(config: Config, clock: Box<dyn Clock>) -> Result<Self> {
+        Self::with_xdg_dirs(config, clock, XdgDirs::from_env()?)
+    }
 
-        let xdg_data_home = match std::env::var("XDG_DATA_HOME") {
-            Ok(path) => HostPath::try_from(path)?,
-            Err(_) => home.join(".local").join("share"),
+    /// Like [`Cubicle::with_clock`] but uses the given directories in place
+    /// of the XDG base directories from the environment.
+    fn with_xdg_dirs(mut config: Config, clock: Box<dyn Clock>, xdg: XdgDirs) -> Result<Self> {
+        let XdgDirs {
+            cache_home: xdg_cache_home,
+            data_home: xdg_data_home,
+            state_home: xdg_state_home,
+        } = xdg;
+        config.runner = if config.runner_choices.len() > 1 {
+            select_runner(&config)?
+        } else {
+            config.runner_choices[0]
         };
+        let home = host_home_dir().clone();
+        let shell = host_shell(&config);
 
         let exe =
             std::env::current_exe().context("error getting the path of the current executable")?;
@@ -192,21 +452,45 @@ impl Cubicle {
             }
         };
 
-        let package_cache = xdg_cache_home.join("cubicle").join("packages");
+        let (cache_dir, data_dir, state_dir) = if config.host_scoped_dirs {
+            let host = FilenameEncoder::new().push(host_id()).encode();
+            (
+                xdg_cache_home.join("cubicle").join("hosts").join(&host),
+                xdg_data_home.join("cubicle").join("hosts").join(&host),
+                xdg_state_home.join("cubicle").join("hosts").join(&host),
+            )
+        } else {
+            (
+                xdg_cache_home.join("cubicle"),
+                xdg_data_home.join("cubicle"),
+                xdg_state_home.join("cubicle"),
+            )
+        };
+        let home_dirs = layout::home_dirs(&cache_dir, &state_dir);
+        let package_cache = cache_dir.join("packages");
         let user_package_dir = xdg_data_home.join("cubicle").join("packages");
 
         let eff_word_list_dir = xdg_cache_home.join("cubicle");
         let random_name_gen = RandomNameGenerator::new(eff_word_list_dir);
 
-        let shared = Rc::new(CubicleShared {
+        let shared = Arc::new(CubicleShared {
             config,
             shell,
             exe_name,
             home,
+            cache_dir,
+            data_dir,
+            state_dir,
+            home_dirs,
             package_cache,
             code_package_dir,
             user_package_dir,
             random_name_gen,
+            clock,
+            progress: Mutex::new(ProgressFormat::Default),
+            confirmer: Mutex::new(Box::new(TerminalConfirmer)),
+            offline_builders: Mutex::default(),
+            package_specs: Mutex::default(),
             env_init_script: std::include_bytes!("env-init.sh"),
         });
 
@@ -214,54 +498,201 @@ impl Cubicle {
             RunnerKind::Bubblewrap => {
                 #[cfg(not(target_os = "linux"))]
                 return Err(anyhow!("The Bubblewrap runner is only available on Linux"));
-                #[cfg(target_os = "linux")]
+                #[cfg(all(target_os = "linux", not(feature = "bubblewrap")))]
+                return Err(missing_runner_feature("Bubblewrap", "bubblewrap"));
+                #[cfg(all(target_os = "linux", feature = "bubblewrap"))]
                 Box::new(Bubblewrap::new(shared.clone())?)
             }
-            RunnerKind::Docker => Box::new(Docker::new(shared.clone())?),
-            RunnerKind::User => Box::new(User::new(shared.clone())?),
+            RunnerKind::Docker => {
+                #[cfg(not(feature = "docker"))]
+                return Err(missing_runner_feature("Docker", "docker"));
+                #[cfg(feature = "docker")]
+                Box::new(Docker::new(shared.clone())?)
+            }
+            RunnerKind::User => {
+                #[cfg(not(feature = "user-runner"))]
+                return Err(missing_runner_feature("User", "user-runner"));
+                #[cfg(feature = "user-runner")]
+                Box::new(User::new(shared.clone())?)
+            }
         });
 
-        Ok(Self { shared, runner })
+        for name in hints::unknown_hint_names(&shared.config.hints.disable) {
+            warn_brief(format!(
+                "unknown hint {name:?} in `hints.disable` in `cubicle.toml`"
+            ));
+        }
+
+        shared.hint_legacy_layout();
+
+        let cubicle = Self { shared, runner };
+        cubicle.clean_up_after_crashes();
+        Ok(cubicle)
+    }
+
+    /// Sets whether to report progress events to other programs, from `cub
+    /// --progress`.
+    pub fn set_progress_format(&self, format: ProgressFormat) {
+        *self.shared.progress.lock().unwrap() = format;
     }
 
     /// Corresponds to `cub enter`.
-    pub fn enter_environment(&self, name: &EnvironmentName) -> Result<()> {
+    ///
+    /// With `read_only_home`, the environment's home directory is read-only
+    /// for this session, while its work directory remains writable. This is
+    /// useful for inspecting or demoing an environment without changing it.
+    /// Not all runners support this.
+    ///
+    /// The shell starts in `dir`, relative to the environment's work
+    /// directory and without `..` components, or in the work directory
+    /// itself if `None`.
+    ///
+    /// This is kept fast, since a person is waiting for the shell to appear:
+    /// it checks once that the environment exists and doesn't scan packages
+    /// or list other environments.
+    pub fn enter_environment(
+        &self,
+        name: &EnvironmentName,
+        read_only_home: ReadOnlyHome,
+        dir: Option<&Path>,
+    ) -> Result<()> {
         use EnvironmentExists::*;
+        if let Some(dir) = dir {
+            if !dir.is_relative() {
+                return Err(anyhow!(
+                    "starting directory must be relative to the work directory, got {dir:?}"
+                ));
+            }
+            if dir.components().any(|c| c == Component::ParentDir) {
+                return Err(anyhow!(
+                    "starting directory must be within the work directory, got {dir:?}"
+                ));
+            }
+        }
+        self.wait_for_environment(name)?;
         match self.runner.exists(name)? {
             NoEnvironment => Err(anyhow!("Environment {name} does not exist")),
             PartiallyExists => Err(anyhow!(
                 "Environment {name} in broken state (try '{} reset')",
                 self.shared.exe_name
             )),
-            FullyExists => self
-                .runner
-                .run(name, &RunnerCommand::Interactive)
-                .or_else(|e| match e.downcast_ref::<ExitStatusError>() {
+            FullyExists => {
+                self.run_hook(Hook::PreEnter, name)?;
+                self.check_runner_and_touch(name);
+                let result = self.runner.enter(name, read_only_home, dir);
+                self.touch_last_used(name);
+                result.or_else(|e| match e.downcast_ref::<ExitStatusError>() {
                     Some(e) => {
                         warn_brief(format!("exited from {name} with {}", e.status));
                         Ok(())
                     }
                     None => Err(e),
-                }),
+                })
+            }
         }
     }
 
-    /// Corresponds to `cub exec`.
+    /// Runs a command in an existing environment.
+    ///
+    /// Returns an error if the command exits unsuccessfully. Use
+    /// [`Cubicle::exec_environment_status`] to get its exit status instead.
     pub fn exec_environment(&self, name: &EnvironmentName, command: &[String]) -> Result<()> {
+        let status = self.exec_environment_status(name, command)?;
+        ExitStatusError::check(status, &command.join(" "))
+    }
+
+    /// Corresponds to `cub exec`.
+    ///
+    /// Runs a command in an existing environment and returns its exit
+    /// status. Errors mean that Cubicle couldn't run the command, not that
+    /// the command failed.
+    pub fn exec_environment_status(
+        &self,
+        name: &EnvironmentName,
+        command: &[String],
+    ) -> Result<ExitStatus> {
+        self.exec_in_existing(name, || {
+            self.runner.run(
+                name,
+                &RunnerCommand::Exec {
+                    command,
+                    env_vars: &[],
+                    dir: None,
+                },
+            )
+        })
+    }
+
+    /// Runs a command in an existing environment and returns its exit status
+    /// and everything it wrote to stdout and stderr.
+    ///
+    /// Unlike [`Cubicle::exec_environment_status`], the command gets no
+    /// terminal and no stdin, and its output isn't shown to the user. This is
+    /// meant for programs that run commands in environments and parse their
+    /// results. Errors mean that Cubicle couldn't run the command, not that
+    /// the command failed.
+    pub fn exec_environment_captured(
+        &self,
+        name: &EnvironmentName,
+        command: &[String],
+    ) -> Result<Output> {
+        let mut stdout = Vec::new();
+        let mut stderr = Vec::new();
+        let status =
+            self.exec_environment_streaming(name, command, &mut |stream, chunk| match stream {
+                OutputStream::Stdout => stdout.extend_from_slice(chunk),
+                OutputStream::Stderr => stderr.extend_from_slice(chunk),
+            })?;
+        Ok(Output {
+            status,
+            stdout,
+            stderr,
+        })
+    }
+
+    /// Like [`Cubicle::exec_environment_captured`], but passes the command's
+    /// output to `on_output` as it arrives, for programs that show it as
+    /// the command runs.
+    pub fn exec_environment_streaming(
+        &self,
+        name: &EnvironmentName,
+        command: &[String],
+        on_output: &mut dyn FnMut(OutputStream, &[u8]),
+    ) -> Result<ExitStatus> {
+        self.exec_in_existing(name, || {
+            self.runner.run_streaming(
+                name,
+                &RunnerCommand::Exec {
+                    command,
+                    env_vars: &[],
+                    dir: None,
+                },
+                on_output,
+            )
+        })
+    }
+
+    /// Checks that the environment fully exists, then calls `run` to run a
+    /// command in it, marking the environment as used.
+    fn exec_in_existing<T, F>(&self, name: &EnvironmentName, run: F) -> Result<T>
+    where
+        F: FnOnce() -> Result<T>,
+    {
         use EnvironmentExists::*;
+        self.wait_for_environment(name)?;
         match self.runner.exists(name)? {
             NoEnvironment => Err(anyhow!("Environment {name} does not exist")),
             PartiallyExists => Err(anyhow!(
                 "Environment {name} in broken state (try '{} reset')",
                 self.shared.exe_name
             )),
-            FullyExists => self.runner.run(
-                name,
-                &RunnerCommand::Exec {
-                    command,
-                    env_vars: &[],
-                },
-            ),
+            FullyExists => {
+                self.warn_if_other_runner(name);
+                self.touch_last_used(name);
+                let result = run();
+                self.touch_last_used(name);
+                result
+            }
         }
     }
 
@@ -272,11 +703,27 @@ impl Cubicle {
 
     /// Returns a detailed description of the current environments.
     pub fn get_environments(&self) -> Result<BTreeMap<EnvironmentName, EnvironmentDetails>> {
-        Ok(self
-            .get_environment_names()?
-            .into_iter()
-            .map(|name| {
-                let summary = self.runner.files_summary(&name).unwrap_or_else(|e| {
+        self.get_environments_with(Refresh(true))
+    }
+
+    /// Like [`Cubicle::get_environments`], but with `refresh` unset, the
+    /// disk usage only comes from the cache. See [`du_cache`].
+    fn get_environments_with(
+        &self,
+        refresh: Refresh,
+    ) -> Result<BTreeMap<EnvironmentName, EnvironmentDetails>> {
+        let names: Vec<EnvironmentName> = self.get_environment_names()?.into_iter().collect();
+        // Summarizing disk usage dominates the time this takes, and each
+        // environment's summary is independent.
+        Ok(parallel_map(&names, |name| {
+            let status = self.environment_status(name).unwrap_or_else(|e| {
+                warn(e);
+                None
+            });
+            let running = status == Some(EnvironmentStatus::Running);
+            let summary = self
+                .cached_files_summary(name, running, refresh)
+                .unwrap_or_else(|e| {
                     warn(e.context(format!("failed to summarize disk usage for {name}")));
                     EnvFilesSummary {
                         home_dir_path: None,
@@ -285,66 +732,211 @@ impl Cubicle {
                         work_dir: DirSummary::new_with_errors(),
                     }
                 });
-                (
-                    name,
-                    EnvironmentDetails {
-                        home_dir: summary.home_dir_path.map(|p| p.as_host_raw().to_owned()),
-                        home_dir_du_error: summary.home_dir.errors,
-                        home_dir_size: summary.home_dir.total_size,
-                        home_dir_mtime: nonzero_time(summary.home_dir.last_modified),
-                        work_dir: summary.work_dir_path.map(|p| p.as_host_raw().to_owned()),
-                        work_dir_du_error: summary.work_dir.errors,
-                        work_dir_size: summary.work_dir.total_size,
-                        work_dir_mtime: nonzero_time(summary.work_dir.last_modified),
-                    },
-                )
+            let metadata = self.read_metadata(name).unwrap_or_else(|e| {
+                warn(e.context(format!("failed to read metadata for {name}")));
+                Default::default()
+            });
+            (
+                name.clone(),
+                EnvironmentDetails {
+                    status,
+                    labels: metadata.labels,
+                    created: metadata.created,
+                    last_used: metadata.last_used,
+                    home_dir: summary.home_dir_path.map(|p| p.as_host_raw().to_owned()),
+                    home_dir_du_error: summary.home_dir.errors,
+                    home_dir_size: summary.home_dir.total_size,
+                    home_dir_mtime: nonzero_time(summary.home_dir.last_modified),
+                    work_dir: summary.work_dir_path.map(|p| p.as_host_raw().to_owned()),
+                    work_dir_du_error: summary.work_dir.errors,
+                    work_dir_size: summary.work_dir.total_size,
+                    work_dir_mtime: nonzero_time(summary.work_dir.last_modified),
+                },
+            )
+        })
+        .into_iter()
+        .collect())
+    }
+
+    /// Returns whether the environment is running, stopped, or broken, or
+    /// `None` if it doesn't exist.
+    fn environment_status(&self, name: &EnvironmentName) -> Result<Option<EnvironmentStatus>> {
+        Ok(match self.runner.exists(name)? {
+            EnvironmentExists::NoEnvironment => None,
+            EnvironmentExists::PartiallyExists => Some(EnvironmentStatus::Broken),
+            EnvironmentExists::FullyExists => Some(if self.runner.is_running(name)? {
+                EnvironmentStatus::Running
+            } else {
+                EnvironmentStatus::Stopped
+            }),
+        })
+    }
+
+    /// Returns a detailed description of the current environments that match
+    /// all of the `filters`.
+    fn get_filtered_environments(
+        &self,
+        filters: &[ListFilter],
+        refresh: Refresh,
+    ) -> Result<BTreeMap<EnvironmentName, EnvironmentDetails>> {
+        let mut envs = self.get_environments_with(refresh)?;
+        envs.retain(|name, env| {
+            filters
+                .iter()
+                .all(|filter| filter.matches(name, &env.labels))
+        });
+        Ok(envs)
+    }
+
+    /// Prints a hint listing the environments using more space than
+    /// [`Config::disk_quota`].
+    fn warn_over_quota(&self, envs: &BTreeMap<EnvironmentName, EnvironmentDetails>) {
+        let Some(quota) = self.shared.config.disk_quota else {
+            return;
+        };
+        let over = envs
+            .iter()
+            .filter_map(|(name, env)| {
+                let used = env.home_dir_size + env.work_dir_size;
+                (used > quota).then(|| format!("{name} uses {}", Bytes(used)))
             })
-            .collect())
+            .collect::<Vec<_>>();
+        if !over.is_empty() {
+            self.shared.hint(
+                Hint::DiskQuota,
+                format!(
+                    "Environments over the disk quota of {}: {}",
+                    Bytes(quota),
+                    over.join(", ")
+                ),
+            );
+        }
     }
 
     /// Corresponds to `cub list`.
-    pub fn list_environments(&self, format: ListFormat) -> Result<()> {
+    ///
+    /// Only environments that match all of the `filters` are listed, in the
+    /// order given by `sort`. Disk usage comes from a cache in each
+    /// environment's metadata directory where possible, and with `refresh`
+    /// unset, only from that cache.
+    pub fn list_environments(
+        &self,
+        format: ListFormat,
+        filters: &[ListFilter],
+        sort: ListSort,
+        refresh: Refresh,
+    ) -> Result<()> {
         match format {
+            ListFormat::Names if sort != ListSort::Name => {
+                for (name, _) in sort.sorted(&self.get_filtered_environments(filters, refresh)?) {
+                    println!("{}", name.as_str());
+                }
+            }
+
             ListFormat::Names => {
                 for name in self.get_environment_names()? {
+                    if !filters.is_empty() {
+                        let labels = self.read_metadata(&name)?.labels;
+                        if !filters.iter().all(|filter| filter.matches(&name, &labels)) {
+                            continue;
+                        }
+                    }
                     println!("{}", name.as_str());
                 }
             }
 
             ListFormat::Json => {
-                let envs = self.get_environments()?;
+                let envs = self.get_filtered_environments(filters, refresh)?;
                 println!(
                     "{}",
-                    serde_json::to_string_pretty(&envs)
+                    serde_json::to_string_pretty(&SortedEnvironments(sort.sorted(&envs)))
                         .context("failed to serialize JSON while listing environments")?
                 );
+                self.warn_over_quota(&envs);
+            }
+
+            ListFormat::Csv | ListFormat::Tsv => {
+                let delimited = if format == ListFormat::Csv {
+                    Delimited::Csv
+                } else {
+                    Delimited::Tsv
+                };
+                let envs = self.get_filtered_environments(filters, refresh)?;
+                println!(
+                    "{}",
+                    delimited.row(&[
+                        "name",
+                        "status",
+                        "created",
+                        "last_used",
+                        "home_dir",
+                        "home_dir_du_error",
+                        "home_dir_size",
+                        "home_dir_mtime",
+                        "work_dir",
+                        "work_dir_du_error",
+                        "work_dir_size",
+                        "work_dir_mtime",
+                    ])
+                );
+                for (name, env) in sort.sorted(&envs) {
+                    println!(
+                        "{}",
+                        delimited.row(&[
+                            name.as_str().to_owned(),
+                            env.status.map(|s| s.to_string()).unwrap_or_default(),
+                            time_field(env.created),
+                            time_field(env.last_used),
+                            path_field(env.home_dir.as_deref()),
+                            env.home_dir_du_error.to_string(),
+                            env.home_dir_size.to_string(),
+                            time_field(env.home_dir_mtime),
+                            path_field(env.work_dir.as_deref()),
+                            env.work_dir_du_error.to_string(),
+                            env.work_dir_size.to_string(),
+                            time_field(env.work_dir_mtime),
+                        ])
+                    );
+                }
+                self.warn_over_quota(&envs);
             }
 
             ListFormat::Default => {
-                let envs = self.get_environments()?;
+                let envs = self.get_filtered_environments(filters, refresh)?;
                 let nw = envs
                     .keys()
                     .map(|name| name.as_str().len())
                     .max()
                     .unwrap_or(10);
-                let now = SystemTime::now();
+                let now = self.shared.clock.now();
+                println!(
+                    "{:<nw$} | {:<7} | {:<13} | {:^24} | {:^24}",
+                    "", "", "", "home directory", "work directory",
+                );
                 println!(
-                    "{:<nw$} | {:^24} | {:^24}",
-                    "", "home directory", "work directory",
+                    "{:<nw$} | {:<7} | {:>13} | {:>10} {:>13} | {:>10} {:>13}",
+                    "name", "status", "last used", "size", "modified", "size", "modified",
                 );
                 println!(
-                    "{:<nw$} | {:>10} {:>13} | {:>10} {:>13}",
-                    "name", "size", "modified", "size", "modified",
+                    "{0:-<nw$} + {0:-<7} + {0:-<13} + {0:-<10} {0:-<13} + {0:-<10} {0:-<13}",
+                    "",
                 );
-                println!("{0:-<nw$} + {0:-<10} {0:-<13} + {0:-<10} {0:-<13}", "",);
 
                 // `Bytes` doesn't implement width/alignment, so it needs an
                 // extra `to_string()`.
                 #[allow(clippy::to_string_in_format_args)]
-                for (name, env) in envs {
+                for (name, env) in sort.sorted(&envs) {
                     println!(
-                        "{:<nw$} | {:>9}{} {:>13} | {:>9}{} {:>13}",
+                        "{:<nw$} | {:<7} | {:>13} | {:>9}{} {:>13} | {:>9}{} {:>13}",
                         name.as_str(),
+                        match env.status {
+                            Some(status) => status.to_string(),
+                            None => String::from("N/A"),
+                        },
+                        match env.last_used {
+                            Some(last_used) => rel_time(now.duration_since(last_used).ok()),
+                            None => String::from("N/A"),
+                        },
                         Bytes(env.home_dir_size).to_string(),
                         if env.home_dir_du_error { '+' } else { ' ' },
                         match env.home_dir_mtime {
@@ -359,6 +951,7 @@ impl Cubicle {
                         },
                     );
                 }
+                self.warn_over_quota(&envs);
             }
         }
         Ok(())
@@ -371,6 +964,7 @@ impl Cubicle {
         packages: Option<BTreeSet<FullPackageName>>,
     ) -> Result<()> {
         use EnvironmentExists::*;
+        let lock = self.lock_environment(name)?;
         match self.runner.exists(name)? {
             NoEnvironment => {}
             PartiallyExists => {
@@ -381,7 +975,52 @@ impl Cubicle {
             }
             FullyExists => return Err(anyhow!("environment {name} already exists")),
         }
+        self.check_workspace(name)?;
+
+        if self.shared.config.nested_containers_for(name) == Some(NestedContainers::HostSocket) {
+            self.shared.hint(
+                Hint::HostDockerSocket,
+                format!(
+                    "Environment {name} will have access to the host's Docker socket \
+                    (from `nested_containers`), which amounts to root access on the host"
+                ),
+            );
+        }
+        self.run_hook(Hook::PreCreate, name)?;
+        let (init, _temp_files) = self.new_environment_init(name, packages)?;
+        self.runner
+            .create(name, &init)
+            .with_context(|| format!("failed to initialize new environment {name}"))?;
+        self.shared
+            .progress(Event::EnvironmentCreated { environment: name });
+        if let Err(e) = self.set_created(name) {
+            warn(e.context(format!("failed to record creation time of {name}")));
+        }
+        // The hook may use the new environment, so it runs without the lock.
+        drop(lock);
+        self.run_hook(Hook::PostCreate, name)
+    }
+
+    /// Builds the seeds that come from the config rather than from packages:
+    /// the git identity and then the dotfiles. These go after the package
+    /// seeds, so they take precedence. The returned temporary files must be
+    /// kept until the environment is initialized.
+    fn config_seeds(&self) -> Result<Vec<tempfile::NamedTempFile>> {
+        Ok(self
+            .git_identity_seed()?
+            .into_iter()
+            .chain(self.dotfiles_seed()?)
+            .collect())
+    }
 
+    /// Builds the packages for a new environment and returns how to
+    /// initialize it. The returned temporary files must be kept until the
+    /// environment is initialized.
+    fn new_environment_init(
+        &self,
+        name: &EnvironmentName,
+        packages: Option<BTreeSet<FullPackageName>>,
+    ) -> Result<(Init, Vec<tempfile::NamedTempFile>)> {
         let packages = {
             let mut packages = packages.unwrap_or_else(|| {
                 BTreeSet::from([FullPackageName::from_str(packages::special::DEFAULT).unwrap()])
@@ -399,94 +1038,344 @@ impl Cubicle {
                 dependencies: ShouldPackageUpdate::IfStale,
                 named: ShouldPackageUpdate::IfStale,
             },
+            &self.env_platform(name)?,
         )?;
-        let packages_txt = write_package_list_tar(&packages)?;
-        let debian_packages = self.resolve_debian_packages(&packages, &specs)?;
-
-        let mut seeds = self.packages_to_seeds(&packages, &specs)?;
-        seeds.push(HostPath::try_from(packages_txt.path().to_owned())?);
+        let packages_txt =
+            write_package_list_tar(&packages, self.shared.config.work_dir_name(name))?;
+        let (distro_packages, env_distro_packages) =
+            self.resolve_env_distro_packages(name, &packages, &specs)?;
+
+        self.check_package_sources(&packages, &specs, &self.env_platform(name)?)?;
+        let mut seeds = self.packages_to_seeds(&packages, &specs, name)?;
+        let mut temp_files = self.config_seeds()?;
+        temp_files.push(packages_txt);
+        for file in &temp_files {
+            seeds.push(HostPath::try_from(file.path().to_owned())?);
+        }
 
-        self.runner
-            .create(
-                name,
-                &Init {
-                    debian_packages: debian_packages
-                        .iter()
-                        .map(|name| name.as_str().to_owned())
-                        .collect(),
-                    env_vars: Vec::new(),
-                    seeds,
-                },
-            )
-            .with_context(|| format!("failed to initialize new environment {name}"))
+        Ok((
+            Init {
+                distro_packages: distro_packages
+                    .iter()
+                    .map(|name| name.as_str().to_owned())
+                    .collect(),
+                env_distro_packages: env_distro_packages
+                    .iter()
+                    .map(|name| name.as_str().to_owned())
+                    .collect(),
+                env_vars: Vec::new(),
+                seeds,
+            },
+            temp_files,
+        ))
     }
 
     /// Corresponds to `cub tmp`.
+    ///
+    /// With `ephemeral`, the environment's home directory is on a tmpfs and
+    /// the environment is gone as soon as the shell exits, so it never needs
+    /// to be purged. Not all runners support this.
     pub fn create_enter_tmp_environment(
         &self,
         packages: Option<BTreeSet<FullPackageName>>,
+        ephemeral: Ephemeral,
     ) -> Result<()> {
-        let name = {
-            let name = self
-                .shared
-                .random_name_gen
-                .random_name(|name| {
-                    if name.starts_with("cub") {
-                        // that'd be confusing
-                        return Ok(false);
-                    }
-                    match EnvironmentName::from_string(format!("tmp-{name}")) {
-                        Ok(env) => {
-                            let exists = self.runner.exists(&env)?;
-                            Ok(exists == EnvironmentExists::NoEnvironment)
-                        }
-                        Err(_) => Ok(false),
+        let name = self.random_environment_name("tmp")?;
+        if ephemeral.0 {
+            let (init, _temp_files) = self.new_environment_init(&name, packages)?;
+            self.run_hook(Hook::PreEnter, &name)?;
+            self.runner.run_ephemeral(&name, &init)
+        } else {
+            self.new_environment(&name, packages)?;
+            self.run_hook(Hook::PreEnter, &name)?;
+            self.runner
+                .run(&name, &RunnerCommand::Interactive { dir: None })
+                .and_then(|status| ExitStatusError::check(status, "shell"))
+        }
+        .or_else(|e| match e.downcast_ref::<ExitStatusError>() {
+            Some(e) => {
+                warn_brief(format!("exited from {name} with {}", e.status));
+                Ok(())
+            }
+            None => Err(e),
+        })
+    }
+
+    /// Returns a random name of the form `{prefix}-{word}` that's not used by
+    /// any existing environment.
+    fn random_environment_name(&self, prefix: &str) -> Result<EnvironmentName> {
+        let name = self
+            .shared
+            .random_name_gen
+            .random_name(|name| {
+                if name.starts_with("cub") {
+                    // that'd be confusing
+                    return Ok(false);
+                }
+                match EnvironmentName::from_string(format!("{prefix}-{name}")) {
+                    Ok(env) => {
+                        let exists = self.runner.exists(&env)?;
+                        Ok(exists == EnvironmentExists::NoEnvironment)
                     }
-                })
-                .context("Failed to generate random environment name")?;
-            EnvironmentName::from_string(format!("tmp-{name}")).unwrap()
-        };
-        self.new_environment(&name, packages)?;
-        self.runner
-            .run(&name, &RunnerCommand::Interactive)
-            .or_else(|e| match e.downcast_ref::<ExitStatusError>() {
-                Some(e) => {
-                    warn_brief(format!("exited from {name} with {}", e.status));
-                    Ok(())
+                    Err(_) => Ok(false),
                 }
-                None => Err(e),
             })
+            .context("Failed to generate random environment name")?;
+        Ok(EnvironmentName::from_string(format!("{prefix}-{name}")).unwrap())
     }
 
     /// Corresponds to `cub purge`.
-    pub fn purge_environment(&self, name: &EnvironmentName, quiet: Quiet) -> Result<()> {
-        if !quiet.0 && self.runner.exists(name)? == EnvironmentExists::NoEnvironment {
-            warn(anyhow!(
-                "environment {name} does not exist (nothing to purge)"
-            ));
+    ///
+    /// Unless `force` is set, this asks the [`Confirmer`] first.
+    pub fn purge_environment(
+        &self,
+        name: &EnvironmentName,
+        quiet: Quiet,
+        force: Force,
+    ) -> Result<()> {
+        let _lock = self.lock_environment(name)?;
+        if self.runner.exists(name)? == EnvironmentExists::NoEnvironment {
+            if !quiet.0 {
+                warn(anyhow!(
+                    "environment {name} does not exist (nothing to purge)"
+                ));
+            }
+        } else {
+            if !force.0
+                && !self.shared.confirm(&format!(
+                    "Purge environment {name}, including its work directory?"
+                ))?
+            {
+                return Err(anyhow!("Not purging {name}"));
+            }
+            self.run_hook(Hook::PrePurge, name)?;
         }
         // Call purge regardless in case it disagrees with `exists` and finds
         // something useful to do.
         self.runner.purge(name)?;
-        Ok(())
+        self.purge_metadata(name)
+    }
+
+    /// Corresponds to `cub stop`.
+    ///
+    /// Stops any processes running in the environment. The environment's
+    /// files are kept, so it may be entered again later.
+    pub fn stop_environment(&self, name: &EnvironmentName) -> Result<()> {
+        let _lock = self.lock_environment(name)?;
+        if self.runner.exists(name)? == EnvironmentExists::NoEnvironment {
+            return Err(anyhow!("Environment {name} does not exist"));
+        }
+        self.runner.stop(name)
+    }
+
+    /// Returns the configured [`Config::idle_timeout`], which is the default
+    /// for [`Cubicle::stop_idle_environments`].
+    pub fn idle_timeout(&self) -> Option<Duration> {
+        self.shared.config.idle_timeout
+    }
+
+    /// Corresponds to `cub stop-idle`.
+    ///
+    /// Runs until interrupted, periodically checking which environments have
+    /// processes running in them. Environments that have been running but
+    /// without any processes or `enter`/`exec` sessions for `idle_timeout`
+    /// are stopped.
+    pub fn stop_idle_environments(&self, idle_timeout: Duration) -> Result<()> {
+        let poll_interval =
+            (idle_timeout / 4).clamp(Duration::from_secs(1), Duration::from_secs(60));
+        let mut idle_since: BTreeMap<EnvironmentName, SystemTime> = BTreeMap::new();
+        loop {
+            let now = self.shared.clock.now();
+            for name in self.get_environment_names()? {
+                match self.runner.running_processes(&name) {
+                    Ok(Some(0)) => {
+                        let mut since = *idle_since.entry(name.clone()).or_insert(now);
+                        // A session may have ended since the last check, or
+                        // before this started watching.
+                        match self.read_metadata(&name) {
+                            Ok(metadata) => {
+                                if let Some(last_used) = metadata.last_used {
+                                    since = since.max(last_used);
+                                }
+                            }
+                            Err(e) => warn(e),
+                        }
+                        if now.duration_since(since).unwrap_or_default() >= idle_timeout {
+                            // Another process may be changing the
+                            // environment. If so, check it again later.
+                            match self.try_lock_environment(&name) {
+                                Ok(Some(_lock)) => {
+                                    info!("Stopping idle environment {name}");
+                                    if let Err(e) = self.runner.stop(&name) {
+                                        warn(e);
+                                    }
+                                    idle_since.remove(&name);
+                                }
+                                Ok(None) => {}
+                                Err(e) => warn(e),
+                            }
+                        }
+                    }
+                    Ok(_) => {
+                        idle_since.remove(&name);
+                    }
+                    Err(e) => warn(e),
+                }
+            }
+            std::thread::sleep(poll_interval);
+        }
+    }
+
+    /// Corresponds to `cub stop --checkpoint`.
+    ///
+    /// Saves the state of the processes running in the environment and stops
+    /// them. The next time the environment is entered, those processes are
+    /// resumed. This is currently only supported with the Docker runner.
+    pub fn checkpoint_environment(&self, name: &EnvironmentName) -> Result<()> {
+        use EnvironmentExists::*;
+        let _lock = self.lock_environment(name)?;
+        match self.runner.exists(name)? {
+            NoEnvironment => Err(anyhow!("Environment {name} does not exist")),
+            PartiallyExists => Err(anyhow!(
+                "Environment {name} in broken state (try '{} reset')",
+                self.shared.exe_name
+            )),
+            FullyExists => self.runner.checkpoint(name),
+        }
+    }
+
+    /// Returns true if the environment's work directory appears to have work
+    /// in progress: uncommitted changes in Git repositories or recently
+    /// modified files. Details are printed to stdout.
+    ///
+    /// Returns false if the environment isn't in a state to check.
+    fn work_dir_has_changes(&self, name: &EnvironmentName) -> Result<bool> {
+        if self.runner.exists(name)? != EnvironmentExists::FullyExists {
+            return Ok(false);
+        }
+        let script = include_str!("work-dir-changes.sh");
+        let result = self
+            .runner
+            .run(
+                name,
+                &RunnerCommand::Exec {
+                    command: &[String::from("sh"), String::from("-c"), String::from(script)],
+                    env_vars: &[],
+                    dir: None,
+                },
+            )
+            .and_then(|status| match status.code() {
+                Some(3) => Ok(true),
+                _ => ExitStatusError::check(status, "work directory check").map(|()| false),
+            });
+        match result {
+            Ok(changes) => Ok(changes),
+            Err(e) => {
+                warn(e.context(format!(
+                    "failed to check for changes in the work directory of {name}"
+                )));
+                Ok(false)
+            }
+        }
+    }
+
+    /// Asks the [`Confirmer`] whether to reset the environment with the given
+    /// packages, if they differ from its current ones.
+    fn confirm_package_change(
+        &self,
+        name: &EnvironmentName,
+        packages: &BTreeSet<FullPackageName>,
+    ) -> Result<()> {
+        let current = match self.read_package_list_from_env(name) {
+            Ok(current) => current,
+            // There's nothing to compare against in a broken environment.
+            Err(_) => return Ok(()),
+        };
+        let describe = |names: Vec<&FullPackageName>| {
+            names
+                .iter()
+                .map(|name| name.unquoted())
+                .collect::<Vec<_>>()
+                .join(", ")
+        };
+        let added = describe(packages.difference(&current).collect());
+        let removed = describe(current.difference(packages).collect());
+        let change = match (added.is_empty(), removed.is_empty()) {
+            (true, true) => return Ok(()),
+            (false, true) => format!("adding {added}"),
+            (true, false) => format!("removing {removed}"),
+            (false, false) => format!("adding {added} and removing {removed}"),
+        };
+        if self.shared.confirm(&format!("Reset {name}, {change}?"))? {
+            Ok(())
+        } else {
+            Err(anyhow!("Not resetting {name}"))
+        }
     }
 
     /// Corresponds to `cub reset`.
+    ///
+    /// Unless `force` is set, this first checks the work directory for work
+    /// in progress, since it may be disturbed by `update.sh` or other
+    /// scripts. If it finds any, or if `packages` differs from the
+    /// environment's current packages, it asks the [`Confirmer`] whether to
+    /// proceed.
+    ///
+    /// If `keep_home` is set, the package files are copied over the existing
+    /// home directory instead of a new one, keeping any other files there.
+    ///
+    /// If `locked` is set, this builds no packages and seeds the environment
+    /// with the package builds recorded by [`Cubicle::lock_packages`]
+    /// instead of the current ones. Then `packages` must be `None`.
     pub fn reset_environment(
         &self,
         name: &EnvironmentName,
         packages: Option<BTreeSet<FullPackageName>>,
+        force: Force,
+        keep_home: KeepHome,
+        locked: Locked,
     ) -> Result<()> {
-        if self.runner.exists(name)? == EnvironmentExists::NoEnvironment {
+        if locked.0 && packages.is_some() {
             return Err(anyhow!(
-                "Environment {name} does not exist (did you mean '{} new'?)",
-                self.shared.exe_name,
+                "can't reset {name} with both locked package builds and a new list of packages"
             ));
         }
+        let _lock = self.lock_environment(name)?;
+        match self.runner.exists(name)? {
+            EnvironmentExists::NoEnvironment => {
+                return Err(anyhow!(
+                    "Environment {name} does not exist (did you mean '{} new'?)",
+                    self.shared.exe_name,
+                ));
+            }
+            EnvironmentExists::PartiallyExists if keep_home.0 => {
+                return Err(anyhow!(
+                    "Environment {name} in broken state (try '{} reset' without --keep-home)",
+                    self.shared.exe_name,
+                ));
+            }
+            EnvironmentExists::PartiallyExists | EnvironmentExists::FullyExists => {}
+        }
+
+        if !force.0 && self.work_dir_has_changes(name)? {
+            let question = format!(
+                "The work directory of {name} seems to have work in progress. \
+                It will be kept, but scripts run during the reset may change it. \
+                Reset anyway?"
+            );
+            if !self.shared.confirm(&question)? {
+                return Err(anyhow!("Not resetting {name}"));
+            }
+        }
 
         let packages = {
             let mut packages = match packages {
-                Some(packages) => packages,
+                Some(packages) => {
+                    if !force.0 {
+                        self.confirm_package_change(name, &packages)?;
+                    }
+                    packages
+                }
                 None => self
                     .read_package_list_from_env(name)
                     .with_context(|| format!("failed to parse `packages.txt` from {name}"))?,
@@ -496,32 +1385,69 @@ impl Cubicle {
             packages
         };
 
+        if let Some(quota) = self.shared.config.disk_quota {
+            // The home directory will be replaced, so only the work directory
+            // counts here.
+            let work_dir_size = self.runner.files_summary(name)?.work_dir.total_size;
+            if work_dir_size > quota {
+                return Err(anyhow!(
+                    "Environment {name}'s work directory uses {}, over its disk quota of {}. \
+                    Free up some space or raise `disk_quota` in the config file.",
+                    Bytes(work_dir_size),
+                    Bytes(quota),
+                ));
+            }
+        }
+
         let specs = self.scan_packages()?;
-        self.update_packages(
-            &packages,
-            &specs,
-            &UpdatePackagesConditions {
-                dependencies: ShouldPackageUpdate::IfStale,
-                named: ShouldPackageUpdate::IfStale,
-            },
-        )?;
-        let debian_packages = self.resolve_debian_packages(&packages, &specs)?;
-        let mut seeds = self.packages_to_seeds(&packages, &specs)?;
+        let mut seeds = if locked.0 {
+            let lock = self.read_package_lock(name)?;
+            self.locked_seeds(&packages, &specs, name, &lock)?
+        } else {
+            self.update_packages(
+                &packages,
+                &specs,
+                &UpdatePackagesConditions {
+                    dependencies: ShouldPackageUpdate::IfStale,
+                    named: ShouldPackageUpdate::IfStale,
+                },
+                &self.env_platform(name)?,
+            )?;
+            self.check_package_sources(&packages, &specs, &self.env_platform(name)?)?;
+            self.packages_to_seeds(&packages, &specs, name)?
+        };
+        let (distro_packages, env_distro_packages) =
+            self.resolve_env_distro_packages(name, &packages, &specs)?;
+        let config_seeds = self.config_seeds()?;
+        for file in &config_seeds {
+            seeds.push(HostPath::try_from(file.path().to_owned())?);
+        }
 
-        let packages_txt = write_package_list_tar(&packages)?;
+        let packages_txt =
+            write_package_list_tar(&packages, self.shared.config.work_dir_name(name))?;
         seeds.push(HostPath::try_from(packages_txt.path().to_owned())?);
 
-        self.runner.reset(
-            name,
-            &Init {
-                debian_packages: debian_packages
-                    .iter()
-                    .map(|name| name.as_str().to_owned())
-                    .collect(),
-                env_vars: Vec::new(),
-                seeds,
-            },
-        )
+        let init = Init {
+            distro_packages: distro_packages
+                .iter()
+                .map(|name| name.as_str().to_owned())
+                .collect(),
+            env_distro_packages: env_distro_packages
+                .iter()
+                .map(|name| name.as_str().to_owned())
+                .collect(),
+            env_vars: Vec::new(),
+            seeds,
+        };
+        if keep_home.0 {
+            self.runner.refresh(name, &init)?;
+        } else {
+            self.runner.reset(name, &init)?;
+        }
+        if let Err(e) = self.set_runner(name) {
+            warn(e.context(format!("failed to record runner for {name}")));
+        }
+        Ok(())
     }
 }
 
@@ -533,24 +1459,35 @@ struct ExitStatusError {
 
 impl ExitStatusError {
     fn new(status: ExitStatus, context: &str) -> Self {
-        assert!(matches!(status.code(), Some(c) if c != 0));
+        assert!(!status.success());
         Self {
             status,
             context: context.to_owned(),
         }
     }
+
+    /// Returns an error if the status is unsuccessful.
+    fn check(status: ExitStatus, context: &str) -> Result<()> {
+        if status.success() {
+            Ok(())
+        } else {
+            Err(Self::new(status, context).into())
+        }
+    }
 }
 
 impl std::error::Error for ExitStatusError {}
 
 impl Display for ExitStatusError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(
-            f,
-            "Non-zero exit status ({}) from {}",
-            self.status.code().unwrap(),
-            self.context
-        )
+        match self.status.code() {
+            Some(code) => write!(f, "Non-zero exit status ({code}) from {}", self.context),
+            None => write!(
+                f,
+                "Unsuccessful exit ({}) from {}",
+                self.status, self.context
+            ),
+        }
     }
 }
 
@@ -587,14 +1524,25 @@ impl EnvironmentName {
         Self::from_string(FilenameEncoder::decode(filename)?)
     }
 
-    /// Returns the name of the environment used to build the package.
-    pub fn for_builder_package(FullPackageName(ns, name): &FullPackageName) -> Self {
-        Self::from_string(if ns == &PackageNamespace::Root {
+    /// Returns the name of the environment used to build the package for the
+    /// given platform.
+    ///
+    /// For platforms other than the host's, the name ends with `@` and the
+    /// platform, like `package-rust@linux/arm64`.
+    pub fn for_builder_package(
+        FullPackageName(ns, name): &FullPackageName,
+        platform: &Platform,
+    ) -> Self {
+        let mut s = if ns == &PackageNamespace::Root {
             format!("package-{}", name.as_str())
         } else {
             format!("package-{}-{}", ns.as_str(), name.as_str())
-        })
-        .unwrap()
+        };
+        if let Some(platform) = platform.docker() {
+            s.push('@');
+            s.push_str(platform);
+        }
+        Self::from_string(s).unwrap()
     }
 
     fn from_string(s: String) -> Result<Self> {
@@ -635,7 +1583,8 @@ impl std::convert::AsRef<str> for EnvironmentName {
 }
 
 /// Allowed formats for [`Cubicle::list_environments`].
-#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, ValueEnum)]
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
 pub enum ListFormat {
     /// Human-formatted table.
     #[default]
@@ -644,10 +1593,128 @@ pub enum ListFormat {
     Json,
     /// Newline-delimited list of environment names only.
     Names,
+    /// Comma-separated values with a header row, for spreadsheets.
+    Csv,
+    /// Tab-separated values with a header row.
+    Tsv,
+}
+
+/// Orders for [`Cubicle::list_environments`].
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+pub enum ListSort {
+    /// Alphabetically by name.
+    #[default]
+    Name,
+    /// Largest home directory first.
+    HomeSize,
+    /// Largest work directory first.
+    WorkSize,
+    /// Least recently modified first, by the newer of the home and work
+    /// directories.
+    Modified,
+    /// Oldest first. Environments created before Cubicle recorded this come
+    /// first.
+    Created,
+    /// Least recently entered or used with `exec` first. Environments that
+    /// haven't been used since Cubicle started recording this come first.
+    LastUsed,
+}
+
+impl ListSort {
+    /// Returns the environments in this order, breaking ties by name.
+    fn sorted(
+        self,
+        envs: &BTreeMap<EnvironmentName, EnvironmentDetails>,
+    ) -> Vec<(&EnvironmentName, &EnvironmentDetails)> {
+        let mut sorted = envs.iter().collect::<Vec<_>>();
+        // This is a stable sort, and `envs` is already sorted by name.
+        match self {
+            Self::Name => {}
+            Self::HomeSize => sorted.sort_by_key(|(_, env)| Reverse(env.home_dir_size)),
+            Self::WorkSize => sorted.sort_by_key(|(_, env)| Reverse(env.work_dir_size)),
+            Self::Modified => {
+                sorted.sort_by_key(|(_, env)| env.home_dir_mtime.max(env.work_dir_mtime));
+            }
+            Self::Created => sorted.sort_by_key(|(_, env)| env.created),
+            Self::LastUsed => sorted.sort_by_key(|(_, env)| env.last_used),
+        }
+        sorted
+    }
+}
+
+/// Serializes environments as a JSON object in the given order.
+struct SortedEnvironments<'a>(Vec<(&'a EnvironmentName, &'a EnvironmentDetails)>);
+
+impl Serialize for SortedEnvironments<'_> {
+    fn serialize<S>(&self, ser: S) -> std::result::Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        ser.collect_map(self.0.iter().copied())
+    }
+}
+
+/// A condition for [`Cubicle::list_environments`].
+///
+/// This is parsed from strings like `label=team`, which matches environments
+/// with a `team` label, `label=team:infra`, which matches environments whose
+/// `team` label is `infra`, or `workspace=proj`, which matches environments
+/// in the `proj` workspace.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum ListFilter {
+    /// Matches environments that have a label with the key and, if given, the
+    /// value.
+    Label {
+        /// The label's key.
+        key: String,
+        /// The label's value, or `None` to match any value.
+        value: Option<String>,
+    },
+    /// Matches environments in the workspace.
+    Workspace(WorkspaceName),
+}
+
+impl ListFilter {
+    fn matches(&self, name: &EnvironmentName, labels: &BTreeMap<String, String>) -> bool {
+        match self {
+            Self::Workspace(workspace) => name.workspace().as_ref() == Some(workspace),
+            Self::Label { key, value } => match (labels.get(key), value) {
+                (Some(actual), Some(value)) => actual == value,
+                (Some(_), None) => true,
+                (None, _) => false,
+            },
+        }
+    }
+}
+
+impl FromStr for ListFilter {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Self> {
+        match s.split_once('=') {
+            Some(("label", label)) if !label.is_empty() => Ok(match label.split_once(':') {
+                Some((key, value)) => Self::Label {
+                    key: key.to_owned(),
+                    value: Some(value.to_owned()),
+                },
+                None => Self::Label {
+                    key: label.to_owned(),
+                    value: None,
+                },
+            }),
+            Some(("workspace", workspace)) => {
+                Ok(Self::Workspace(WorkspaceName::from_str(workspace)?))
+            }
+            _ => Err(anyhow!(
+                "invalid filter {s:?} (expected `label=KEY`, `label=KEY:VALUE`, or \
+                `workspace=NAME`)"
+            )),
+        }
+    }
 }
 
 /// The type of runner to use to run isolated environments.
-#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq)]
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq, Serialize)]
 pub enum RunnerKind {
     /// Use the Bubblewrap runner (Linux only).
     #[serde(alias = "bubblewrap")]
@@ -665,6 +1732,17 @@ pub enum RunnerKind {
     User,
 }
 
+impl RunnerKind {
+    /// Returns the runner's name, as in the config file.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Bubblewrap => "bubblewrap",
+            Self::Docker => "docker",
+            Self::User => "user",
+        }
+    }
+}
+
 fn time_serialize_opt<S>(time: &Option<SystemTime>, ser: S) -> std::result::Result<S::Ok, S::Error>
 where
     S: serde::Serializer,
@@ -678,6 +1756,14 @@ where
     }
 }
 
+/// Returns an error for a runner that was left out of this build.
+#[allow(dead_code)]
+fn missing_runner_feature(runner: &str, feature: &str) -> Error {
+    anyhow!(
+        "The {runner} runner is not available: Cubicle was built without the `{feature}` feature"
+    )
+}
+
 fn rel_time(duration: Option<Duration>) -> String {
     let mut duration = match duration {
         Some(duration) => duration.as_secs_f64(),
@@ -703,10 +1789,45 @@ fn nonzero_time(t: SystemTime) -> Option<SystemTime> {
     }
 }
 
+/// Whether an environment is running, as reported in
+/// [`EnvironmentDetails::status`].
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum EnvironmentStatus {
+    /// The environment has processes running or is being kept alive.
+    Running,
+    /// The environment exists but isn't running.
+    Stopped,
+    /// The environment only partially exists (see `cub reset`).
+    Broken,
+}
+
+impl fmt::Display for EnvironmentStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Running => "running",
+            Self::Stopped => "stopped",
+            Self::Broken => "broken",
+        })
+    }
+}
+
 /// Description of an environment as returned by [`Cubicle::get_environments`].
 #[derive(Debug, Serialize)]
 #[non_exhaustive]
 pub struct EnvironmentDetails {
+    /// Whether the environment is running, or `None` if that couldn't be
+    /// determined.
+    pub status: Option<EnvironmentStatus>,
+    /// User-defined labels, set with [`Cubicle::label_environment`].
+    pub labels: BTreeMap<String, String>,
+    /// When the environment was created, if known.
+    #[serde(serialize_with = "time_serialize_opt")]
+    pub created: Option<SystemTime>,
+    /// When a `cub enter` or `cub exec` session in the environment last
+    /// started or ended, if known.
+    #[serde(serialize_with = "time_serialize_opt")]
+    pub last_used: Option<SystemTime>,
     /// The path on the host of the environment's home directory, if available.
     pub home_dir: Option<PathBuf>,
     /// If true, at least one error was encountered while calculating the
@@ -744,3 +1865,135 @@ pub mod hidden {
         super::host_home_dir().as_host_raw()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn list_filter() {
+        let labels = BTreeMap::from([(String::from("team"), String::from("infra"))]);
+        let name = EnvironmentName::from_str("proj/web").unwrap();
+        let matches = |filter: &str| {
+            ListFilter::from_str(filter)
+                .unwrap()
+                .matches(&name, &labels)
+        };
+        assert!(matches("label=team"));
+        assert!(matches("label=team:infra"));
+        assert!(!matches("label=team:web"));
+        assert!(!matches("label=owner"));
+        assert!(!matches("label=team:"));
+        assert!(matches("workspace=proj"));
+        assert!(!matches("workspace=web"));
+        assert!(ListFilter::from_str("label=").is_err());
+        assert!(ListFilter::from_str("workspace=").is_err());
+        assert!(ListFilter::from_str("name=web").is_err());
+        assert!(ListFilter::from_str("team").is_err());
+    }
+
+    #[test]
+    fn list_sort() {
+        let env = |home_dir_size, work_dir_size, mtime: Option<u64>| EnvironmentDetails {
+            status: None,
+            labels: BTreeMap::new(),
+            created: None,
+            last_used: mtime.map(|secs| UNIX_EPOCH + Duration::from_secs(secs * 2)),
+            home_dir: None,
+            home_dir_du_error: false,
+            home_dir_size,
+            home_dir_mtime: None,
+            work_dir: None,
+            work_dir_du_error: false,
+            work_dir_size,
+            work_dir_mtime: mtime.map(|secs| UNIX_EPOCH + Duration::from_secs(secs)),
+        };
+        let envs = BTreeMap::from([
+            (
+                EnvironmentName::from_str("a").unwrap(),
+                env(1, 30, Some(200)),
+            ),
+            (EnvironmentName::from_str("b").unwrap(), env(3, 20, None)),
+            (
+                EnvironmentName::from_str("c").unwrap(),
+                env(2, 30, Some(100)),
+            ),
+        ]);
+        let order = |sort: ListSort| {
+            sort.sorted(&envs)
+                .into_iter()
+                .map(|(name, _)| name.as_str())
+                .collect::<Vec<_>>()
+                .join(" ")
+        };
+        assert_eq!("a b c", order(ListSort::Name));
+        assert_eq!("b c a", order(ListSort::HomeSize));
+        assert_eq!("a c b", order(ListSort::WorkSize));
+        assert_eq!("b c a", order(ListSort::Modified));
+        assert_eq!("a b c", order(ListSort::Created));
+        assert_eq!("b c a", order(ListSort::LastUsed));
+    }
+
+    #[test]
+    fn rel_time() {
+        let check = |secs: Option<u64>| super::rel_time(secs.map(Duration::from_secs));
+        assert_eq!(check(None), "N/A");
+        assert_eq!(check(Some(20)), "0 minutes");
+        assert_eq!(check(Some(59 * 60)), "59 minutes");
+        assert_eq!(check(Some(60 * 60)), "1 hours");
+        assert_eq!(check(Some(23 * 60 * 60)), "23 hours");
+        assert_eq!(check(Some(24 * 60 * 60)), "1 days");
+        assert_eq!(check(Some(100 * 24 * 60 * 60)), "100 days");
+    }
+
+    #[test]
+    fn enter_latency() {
+        use crate::testing;
+        use std::time::Instant;
+
+        // The fake runner returns immediately, so this measures Cubicle's own
+        // overhead before the shell would appear.
+        let root = tempfile::tempdir().unwrap();
+        let cub = testing::cubicle(root.path(), "");
+        let name = EnvironmentName::from_str("fast").unwrap();
+        cub.new_environment(&name, Some(BTreeSet::new())).unwrap();
+
+        let runs = 20;
+        let start = Instant::now();
+        for _ in 0..runs {
+            cub.enter_environment(&name, ReadOnlyHome(false), None)
+                .unwrap();
+        }
+        let latency = start.elapsed() / runs;
+        assert!(
+            latency < Duration::from_millis(100),
+            "entering took {latency:?}"
+        );
+    }
+
+    #[test]
+    fn enter_at() {
+        use crate::testing;
+
+        let root = tempfile::tempdir().unwrap();
+        let cub = testing::cubicle(root.path(), "");
+        let name = EnvironmentName::from_str("at").unwrap();
+        cub.new_environment(&name, Some(BTreeSet::new())).unwrap();
+        cub.enter_environment(&name, ReadOnlyHome(false), Some(Path::new("src")))
+            .unwrap();
+        let err = cub
+            .enter_environment(&name, ReadOnlyHome(false), Some(Path::new("/etc")))
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "starting directory must be relative to the work directory, got \"/etc\""
+        );
+        let err = cub
+            .enter_environment(&name, ReadOnlyHome(false), Some(Path::new("src/../..")))
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "starting directory must be within the work directory, got \"src/../..\""
+        );
+    }
+}
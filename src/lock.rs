@@ -0,0 +1,188 @@
+//! Advisory lock files that are safe to use over NFS.
+
+use std::os::unix::fs::MetadataExt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::thread::sleep;
+use std::time::Duration;
+use tracing::info;
+
+use super::encoding::FilenameEncoder;
+use super::os_util::{host_id, process_exists};
+use super::HostPath;
+use crate::somehow::{warn, Context, Result};
+
+/// An exclusive lock held while this value is alive.
+///
+/// `O_EXCL` is not reliable on older NFS versions, so this uses the approach
+/// recommended in `open(2)`: create a uniquely named file, hard link it to the
+/// lock path, and check that the unique file's link count became 2. This
+/// works whether or not the `link` call itself reports success, since NFS
+/// may lose the reply.
+#[derive(Debug)]
+pub struct LockFile {
+    path: HostPath,
+}
+
+impl LockFile {
+    /// Blocks until the lock at the given path is acquired.
+    ///
+    /// The `what` string describes what's being locked, for the message
+    /// printed while waiting.
+    pub fn acquire(path: HostPath, what: &str) -> Result<Self> {
+        let mut waiting = false;
+        loop {
+            if let Some(lock) = Self::try_acquire(&path)? {
+                return Ok(lock);
+            }
+            if !waiting {
+                let holder = std::fs::read_to_string(path.as_host_raw()).unwrap_or_default();
+                info!(
+                    "Waiting for lock on {what} (held by {})",
+                    match holder.trim() {
+                        "" => "unknown",
+                        holder => holder,
+                    }
+                );
+                waiting = true;
+            }
+            sleep(Duration::from_millis(500));
+        }
+    }
+
+    /// Tries to acquire the lock at the given path without blocking.
+    ///
+    /// Returns `None` if another process holds the lock.
+    pub fn try_acquire(path: &HostPath) -> Result<Option<Self>> {
+        let dir = HostPath::try_from(
+            path.as_host_raw()
+                .parent()
+                .expect("lock path should have a parent")
+                .to_owned(),
+        )?;
+        std::fs::create_dir_all(dir.as_host_raw())
+            .with_context(|| format!("failed to create directory {dir}"))?;
+
+        let holder = format!("{} {}", host_id(), std::process::id());
+        // Threads in this process need distinct temporary files, or one
+        // could see the link count from another's attempt.
+        static ATTEMPTS: AtomicU64 = AtomicU64::new(0);
+        let attempt = ATTEMPTS.fetch_add(1, Ordering::Relaxed);
+        let unique = dir.join(
+            FilenameEncoder::new()
+                .push(
+                    &path
+                        .as_host_raw()
+                        .file_name()
+                        .expect("lock path should have a file name")
+                        .to_string_lossy(),
+                )
+                .push(".")
+                .push(&holder.replace(' ', "."))
+                .push(&format!(".{attempt}"))
+                .encode(),
+        );
+        std::fs::write(unique.as_host_raw(), format!("{holder}\n"))
+            .with_context(|| format!("failed to write lock file {unique}"))?;
+
+        // The result of `hard_link` is deliberately ignored: the link count
+        // is the source of truth.
+        let _ = std::fs::hard_link(unique.as_host_raw(), path.as_host_raw());
+        let acquired = std::fs::metadata(unique.as_host_raw())
+            .map(|metadata| metadata.nlink() == 2)
+            .with_context(|| format!("failed to check link count of lock file {unique}"));
+
+        if let Err(e) = std::fs::remove_file(unique.as_host_raw())
+            .with_context(|| format!("failed to remove temporary lock file {unique}"))
+        {
+            warn(e);
+        }
+
+        Ok(acquired?.then(|| Self { path: path.clone() }))
+    }
+
+    /// Removes the lock at the given path if it's held by a process on this
+    /// host that no longer exists, as happens when Cubicle crashes or is
+    /// killed. Returns whether it removed the lock.
+    ///
+    /// Locks held from other hosts are left alone, since their processes
+    /// can't be checked from here.
+    pub fn remove_if_stale(path: &HostPath) -> Result<bool> {
+        let holder = match std::fs::read_to_string(path.as_host_raw()) {
+            Ok(holder) => holder,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(false),
+            Err(e) => return Err(e).with_context(|| format!("failed to read lock file {path}")),
+        };
+        let stale = match holder.trim().rsplit_once(' ') {
+            Some((host, pid)) if host == host_id() => match pid.parse() {
+                Ok(pid) => !process_exists(pid),
+                Err(_) => false,
+            },
+            _ => false,
+        };
+        if stale {
+            std::fs::remove_file(path.as_host_raw())
+                .with_context(|| format!("failed to remove stale lock file {path}"))?;
+        }
+        Ok(stale)
+    }
+}
+
+impl Drop for LockFile {
+    fn drop(&mut self) {
+        if let Err(e) = std::fs::remove_file(self.path.as_host_raw())
+            .with_context(|| format!("failed to remove lock file {}", self.path))
+        {
+            warn(e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn exclusive() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = HostPath::try_from(dir.path().join("a.lock")).unwrap();
+        let lock = LockFile::try_acquire(&path).unwrap().unwrap();
+        assert!(LockFile::try_acquire(&path).unwrap().is_none());
+        drop(lock);
+        assert!(LockFile::try_acquire(&path).unwrap().is_some());
+    }
+
+    #[test]
+    fn exclusive_between_threads() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = HostPath::try_from(dir.path().join("a.lock")).unwrap();
+        // Races are rare, so this tries many times.
+        for _ in 0..100 {
+            let locks: Vec<Option<LockFile>> = std::thread::scope(|s| {
+                let threads: Vec<_> = (0..8)
+                    .map(|_| s.spawn(|| LockFile::try_acquire(&path).unwrap()))
+                    .collect();
+                threads.into_iter().map(|t| t.join().unwrap()).collect()
+            });
+            assert_eq!(locks.iter().flatten().count(), 1);
+        }
+    }
+
+    #[test]
+    fn remove_if_stale() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = HostPath::try_from(dir.path().join("a.lock")).unwrap();
+        assert!(!LockFile::remove_if_stale(&path).unwrap());
+
+        let lock = LockFile::try_acquire(&path).unwrap().unwrap();
+        assert!(!LockFile::remove_if_stale(&path).unwrap());
+        std::mem::forget(lock);
+
+        // PIDs are limited to 2^22 on Linux.
+        std::fs::write(path.as_host_raw(), format!("{} 99999999\n", host_id())).unwrap();
+        assert!(LockFile::remove_if_stale(&path).unwrap());
+        assert!(!path.as_host_raw().exists());
+
+        std::fs::write(path.as_host_raw(), "other-host 99999999\n").unwrap();
+        assert!(!LockFile::remove_if_stale(&path).unwrap());
+    }
+}
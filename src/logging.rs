@@ -0,0 +1,86 @@
+//! Prints Cubicle's log messages to stderr.
+//!
+//! The library logs through [`tracing`], so programs that embed it may
+//! install their own subscriber instead. This one keeps the plain format that
+//! `cub` has always used: informational messages as-is and warnings prefixed
+//! with `WARNING:`.
+
+use std::fmt;
+use tracing::{Event, Level, Subscriber};
+use tracing_subscriber::fmt::format::{FormatEvent, FormatFields, Writer};
+use tracing_subscriber::fmt::FmtContext;
+use tracing_subscriber::registry::LookupSpan;
+
+/// How much to log, for [`init_logging`].
+///
+/// 0 logs informational messages and above. Each step up adds a more
+/// detailed level: 1 includes every external command that Cubicle runs, and
+/// 2 includes their exit statuses. Each step down removes a level: -1 logs
+/// only warnings and errors, and -2 logs only errors.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct Verbosity(pub i8);
+
+impl Verbosity {
+    fn max_level(self) -> Level {
+        match self.0 {
+            i8::MIN..=-2 => Level::ERROR,
+            -1 => Level::WARN,
+            0 => Level::INFO,
+            1 => Level::DEBUG,
+            2..=i8::MAX => Level::TRACE,
+        }
+    }
+}
+
+/// Prints log messages at or above the given verbosity to stderr, for the
+/// rest of the process.
+///
+/// This should be called once, early in `main`.
+pub fn init_logging(verbosity: Verbosity) {
+    tracing_subscriber::fmt()
+        .with_max_level(verbosity.max_level())
+        .with_writer(std::io::stderr)
+        .event_format(PlainFormat)
+        .init();
+}
+
+/// Formats events as one line each, with a prefix for the level unless it's
+/// informational.
+struct PlainFormat;
+
+impl<S, N> FormatEvent<S, N> for PlainFormat
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    N: for<'a> FormatFields<'a> + 'static,
+{
+    fn format_event(
+        &self,
+        ctx: &FmtContext<'_, S, N>,
+        mut writer: Writer<'_>,
+        event: &Event<'_>,
+    ) -> fmt::Result {
+        match *event.metadata().level() {
+            Level::ERROR => write!(writer, "ERROR: ")?,
+            Level::WARN => write!(writer, "WARNING: ")?,
+            Level::INFO => {}
+            Level::DEBUG => write!(writer, "DEBUG: ")?,
+            Level::TRACE => write!(writer, "TRACE: ")?,
+        }
+        ctx.field_format().format_fields(writer.by_ref(), event)?;
+        writeln!(writer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn max_level() {
+        assert_eq!(Level::ERROR, Verbosity(-5).max_level());
+        assert_eq!(Level::WARN, Verbosity(-1).max_level());
+        assert_eq!(Level::INFO, Verbosity::default().max_level());
+        assert_eq!(Level::DEBUG, Verbosity(1).max_level());
+        assert_eq!(Level::TRACE, Verbosity(3).max_level());
+    }
+}
@@ -1,9 +1,10 @@
 mod cli;
 
-use cubicle::{config::Config, Cubicle, Result};
+use cubicle::{config::Config, init_logging, Cubicle, Result};
 
 fn main() -> Result<()> {
     let args = cli::parse();
+    init_logging(args.verbosity());
     let config = Config::read_from_file(args.config_path())?;
     let program = Cubicle::new(config)?;
     cli::run(args, &program)
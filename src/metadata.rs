@@ -0,0 +1,203 @@
+//! Stores information about environments that the runners don't track.
+//!
+//! Each environment gets a directory on the host under Cubicle's data
+//! directory, holding a `metadata.json` file. The directory is removed when
+//! the environment is purged.
+
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::time::SystemTime;
+
+use super::fs_util::{rmtree, try_exists};
+use super::{Cubicle, EnvironmentName, HostPath, RunnerKind};
+use crate::somehow::{somehow as anyhow, warn, warn_brief, Context, Result};
+
+/// The contents of an environment's `metadata.json`.
+///
+/// New fields must have defaults, since existing files won't have them.
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub(super) struct EnvironmentMetadata {
+    /// User-defined labels. See [`Cubicle::label_environment`].
+    #[serde(default)]
+    pub labels: BTreeMap<String, String>,
+    /// When the environment was created, if it was created by a version of
+    /// Cubicle that recorded this.
+    #[serde(default)]
+    pub created: Option<SystemTime>,
+    /// The runner that created or last reset the environment. See
+    /// [`Config::runner_choices`](super::config::Config::runner_choices).
+    #[serde(default)]
+    pub runner: Option<RunnerKind>,
+    /// When a `cub enter` or `cub exec` session in the environment last
+    /// started or ended.
+    #[serde(default)]
+    pub last_used: Option<SystemTime>,
+}
+
+impl Cubicle {
+    /// Returns the directory on the host that holds the environment's
+    /// metadata.
+    pub(super) fn metadata_dir(&self, name: &EnvironmentName) -> HostPath {
+        self.shared
+            .data_dir
+            .join("metadata")
+            .join(name.as_filename())
+    }
+
+    /// Reads the environment's metadata, returning the defaults if it has
+    /// none.
+    pub(super) fn read_metadata(&self, name: &EnvironmentName) -> Result<EnvironmentMetadata> {
+        let path = self.metadata_dir(name).join("metadata.json");
+        if !try_exists(&path).todo_context()? {
+            return Ok(EnvironmentMetadata::default());
+        }
+        let contents = std::fs::read_to_string(path.as_host_raw())
+            .with_context(|| format!("failed to read {path}"))?;
+        serde_json::from_str(&contents).with_context(|| format!("failed to parse {path}"))
+    }
+
+    /// Replaces the environment's metadata.
+    pub(super) fn write_metadata(
+        &self,
+        name: &EnvironmentName,
+        metadata: &EnvironmentMetadata,
+    ) -> Result<()> {
+        let dir = self.metadata_dir(name);
+        std::fs::create_dir_all(dir.as_host_raw())
+            .with_context(|| format!("failed to create directory {dir}"))?;
+        let path = dir.join("metadata.json");
+        // Write to a temporary file first so that readers never see a
+        // partial file.
+        let mut file = tempfile::NamedTempFile::new_in(dir.as_host_raw())
+            .with_context(|| format!("failed to create temporary file in {dir}"))?;
+        serde_json::to_writer_pretty(&mut file, metadata)
+            .map_err(std::io::Error::from)
+            .and_then(|()| file.write_all(b"\n"))
+            .with_context(|| format!("failed to write {path}"))?;
+        file.persist(path.as_host_raw())
+            .with_context(|| format!("failed to write {path}"))?;
+        // Whatever changed the metadata may have changed the environment's
+        // files too.
+        self.forget_disk_usage(name)
+    }
+
+    /// Records that the environment was just created by the current runner.
+    pub(super) fn set_created(&self, name: &EnvironmentName) -> Result<()> {
+        let mut metadata = self.read_metadata(name)?;
+        metadata.created = Some(self.shared.clock.now());
+        metadata.runner = Some(self.shared.config.runner);
+        self.write_metadata(name, &metadata)
+    }
+
+    /// Records that the environment was just reset by the current runner.
+    pub(super) fn set_runner(&self, name: &EnvironmentName) -> Result<()> {
+        let mut metadata = self.read_metadata(name)?;
+        metadata.runner = Some(self.shared.config.runner);
+        self.write_metadata(name, &metadata)
+    }
+
+    /// Records that a session in the environment just started or ended.
+    ///
+    /// Failures are only warnings, since they shouldn't keep anyone from
+    /// using the environment.
+    pub(super) fn touch_last_used(&self, name: &EnvironmentName) {
+        let result = self.read_metadata(name).and_then(|mut metadata| {
+            metadata.last_used = Some(self.shared.clock.now());
+            self.write_metadata(name, &metadata)
+        });
+        if let Err(e) = result {
+            warn(e.context(format!("failed to record last use of {name}")));
+        }
+    }
+
+    /// Warns if the environment was created by a different runner than the
+    /// current one, which can happen with a list of
+    /// [`Config::runner_choices`](super::config::Config::runner_choices).
+    pub(super) fn warn_if_other_runner(&self, name: &EnvironmentName) {
+        match self.read_metadata(name) {
+            Ok(metadata) => self.check_runner(name, &metadata),
+            Err(e) => warn(e),
+        }
+    }
+
+    /// Does the work of both [`Cubicle::warn_if_other_runner`] and
+    /// [`Cubicle::touch_last_used`] while reading the metadata only once.
+    pub(super) fn check_runner_and_touch(&self, name: &EnvironmentName) {
+        let result = self.read_metadata(name).and_then(|mut metadata| {
+            self.check_runner(name, &metadata);
+            metadata.last_used = Some(self.shared.clock.now());
+            self.write_metadata(name, &metadata)
+        });
+        if let Err(e) = result {
+            warn(e.context(format!("failed to record last use of {name}")));
+        }
+    }
+
+    fn check_runner(&self, name: &EnvironmentName, metadata: &EnvironmentMetadata) {
+        match metadata.runner {
+            Some(runner) if runner != self.shared.config.runner => warn_brief(format!(
+                "Environment {name} was set up by the {} runner, but Cubicle is using \
+                the {} runner on this host",
+                runner.as_str(),
+                self.shared.config.runner.as_str(),
+            )),
+            _ => {}
+        }
+    }
+
+    /// Deletes the environment's metadata, if any.
+    pub(super) fn purge_metadata(&self, name: &EnvironmentName) -> Result<()> {
+        rmtree(&self.metadata_dir(name))
+    }
+
+    /// Corresponds to `cub env label`.
+    ///
+    /// Sets the given labels on an existing environment, then removes the
+    /// labels with the keys in `remove`.
+    pub fn label_environment(
+        &self,
+        name: &EnvironmentName,
+        set: &BTreeMap<String, String>,
+        remove: &[String],
+    ) -> Result<()> {
+        if !self.get_environment_names()?.contains(name) {
+            return Err(anyhow!("Environment {name} does not exist"));
+        }
+        for key in set.keys() {
+            check_label_key(key)?;
+        }
+        let mut metadata = self.read_metadata(name)?;
+        metadata.labels.extend(set.clone());
+        for key in remove {
+            metadata.labels.remove(key);
+        }
+        self.write_metadata(name, &metadata)
+    }
+}
+
+/// Returns an error if the label key can't be used in a `cub list --filter`.
+fn check_label_key(key: &str) -> Result<()> {
+    if key.is_empty() {
+        return Err(anyhow!("label key cannot be empty"));
+    }
+    if key.contains(|c: char| c == ':' || c == '=' || c.is_whitespace() || c.is_control()) {
+        return Err(anyhow!(
+            "label key {key:?} cannot contain ':', '=', whitespace, or control characters"
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn check_label_key() {
+        assert!(super::check_label_key("team").is_ok());
+        assert!(super::check_label_key("app.kubernetes.io/name").is_ok());
+        assert!(super::check_label_key("").is_err());
+        assert!(super::check_label_key("team:infra").is_err());
+        assert!(super::check_label_key("a=b").is_err());
+        assert!(super::check_label_key("my team").is_err());
+    }
+}
@@ -0,0 +1,233 @@
+//! A minimal, stable interface for embedding Cubicle in other Rust programs,
+//! such as editor plugin daemons and chat bots.
+//!
+//! The rest of the library mirrors the `cub` command line: its methods may
+//! print to stdout, ask questions on the terminal, and read the XDG
+//! environment variables. The [`Ops`] methods instead take and return plain
+//! data, never print results or prompt, and only use the paths given to the
+//! [`Builder`]. Log messages still go through [`tracing`], which prints
+//! nothing unless the program installs a subscriber.
+//!
+//! ```no_run
+//! use cubicle::config::Config;
+//! use cubicle::ops::Builder;
+//! use std::path::Path;
+//! use std::str::FromStr;
+//!
+//! # fn main() -> cubicle::Result<()> {
+//! let config = Config::read_from_file(Path::new("/etc/my-bot/cubicle.toml"))?;
+//! let ops = Builder::new(config)
+//!     .cache_dir("/var/cache/my-bot")
+//!     .data_dir("/var/lib/my-bot")
+//!     .build()?;
+//! let name = cubicle::EnvironmentName::from_str("scratch")?;
+//! ops.create(&name, None)?;
+//! let output = ops.exec(&name, &["uname", "-a"])?;
+//! print!("{}", String::from_utf8_lossy(&output.stdout));
+//! ops.purge(&name)?;
+//! # Ok(())
+//! # }
+//! ```
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::PathBuf;
+use std::process::Output;
+
+use super::config::Config;
+use super::{
+    AssumeYes, Clock, Cubicle, EnvironmentDetails, EnvironmentName, Force, FullPackageName,
+    HostPath, KeepHome, Locked, PackageDetails, Platform, Quiet, ShouldPackageUpdate, SystemClock,
+    UpdatePackagesConditions, XdgDirs,
+};
+use crate::somehow::Result;
+
+/// Configures and creates an [`Ops`].
+pub struct Builder {
+    config: Config,
+    clock: Box<dyn Clock>,
+    cache_dir: Option<PathBuf>,
+    data_dir: Option<PathBuf>,
+    state_dir: Option<PathBuf>,
+}
+
+impl Builder {
+    /// Starts with the given configuration, which the caller may have read
+    /// from a file with [`Config::read_from_file`] or built some other way.
+    pub fn new(config: Config) -> Self {
+        Self {
+            config,
+            clock: Box::new(SystemClock),
+            cache_dir: None,
+            data_dir: None,
+            state_dir: None,
+        }
+    }
+
+    /// Sets the directory to use in place of `$XDG_CACHE_HOME`. Cubicle
+    /// keeps its package builds and other files it can recreate in a
+    /// `cubicle` subdirectory. The default follows `$XDG_CACHE_HOME`.
+    pub fn cache_dir<P: Into<PathBuf>>(mut self, dir: P) -> Self {
+        self.cache_dir = Some(dir.into());
+        self
+    }
+
+    /// Sets the directory to use in place of `$XDG_DATA_HOME`. Cubicle
+    /// keeps environments' work directories, metadata, and user packages in
+    /// a `cubicle` subdirectory. The default follows `$XDG_DATA_HOME`.
+    pub fn data_dir<P: Into<PathBuf>>(mut self, dir: P) -> Self {
+        self.data_dir = Some(dir.into());
+        self
+    }
+
+    /// Sets the directory to use in place of `$XDG_STATE_HOME`. Cubicle
+    /// keeps environments' home directories in a `cubicle` subdirectory.
+    /// The default follows `$XDG_STATE_HOME`.
+    pub fn state_dir<P: Into<PathBuf>>(mut self, dir: P) -> Self {
+        self.state_dir = Some(dir.into());
+        self
+    }
+
+    /// Sets the clock to read the current time from, such as a
+    /// [`FakeClock`](super::FakeClock) in tests.
+    pub fn clock(mut self, clock: Box<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Creates the [`Ops`], including its runner.
+    pub fn build(self) -> Result<Ops> {
+        let mut xdg = XdgDirs::from_env()?;
+        if let Some(dir) = self.cache_dir {
+            xdg.cache_home = HostPath::try_from(dir)?;
+        }
+        if let Some(dir) = self.data_dir {
+            xdg.data_home = HostPath::try_from(dir)?;
+        }
+        if let Some(dir) = self.state_dir {
+            xdg.state_home = HostPath::try_from(dir)?;
+        }
+        let cubicle = Cubicle::with_xdg_dirs(self.config, self.clock, xdg)?;
+        // The caller asks for each operation explicitly, and there may be no
+        // terminal to ask on.
+        cubicle.set_confirmer(Box::new(AssumeYes));
+        Ok(Ops { cubicle })
+    }
+}
+
+/// Creates, runs commands in, and removes environments, and manages
+/// packages. Create one with a [`Builder`].
+pub struct Ops {
+    cubicle: Cubicle,
+}
+
+impl Ops {
+    /// Creates a new environment with the given packages, or the configured
+    /// default packages if `None`.
+    pub fn create(
+        &self,
+        name: &EnvironmentName,
+        packages: Option<BTreeSet<FullPackageName>>,
+    ) -> Result<()> {
+        self.cubicle.new_environment(name, packages)
+    }
+
+    /// Runs a command in an existing environment and returns its exit status
+    /// and output. An unsuccessful exit status is not an error.
+    pub fn exec<S: AsRef<str>>(&self, name: &EnvironmentName, command: &[S]) -> Result<Output> {
+        let command: Vec<String> = command.iter().map(|s| s.as_ref().to_owned()).collect();
+        self.cubicle.exec_environment_captured(name, &command)
+    }
+
+    /// Returns the existing environments and their details.
+    pub fn list(&self) -> Result<BTreeMap<EnvironmentName, EnvironmentDetails>> {
+        self.cubicle.get_environments()
+    }
+
+    /// Recreates an existing environment from its packages, or from the
+    /// given packages if not `None`. The work directory is kept, and so is
+    /// the home directory with `keep_home`.
+    pub fn reset(
+        &self,
+        name: &EnvironmentName,
+        packages: Option<BTreeSet<FullPackageName>>,
+        keep_home: KeepHome,
+    ) -> Result<()> {
+        self.cubicle
+            .reset_environment(name, packages, Force(true), keep_home, Locked(false))
+    }
+
+    /// Deletes an environment, including its work directory. This succeeds
+    /// if the environment doesn't exist.
+    pub fn purge(&self, name: &EnvironmentName) -> Result<()> {
+        self.cubicle
+            .purge_environment(name, Quiet(true), Force(true))
+    }
+
+    /// Returns the available packages and their details.
+    pub fn packages(&self) -> Result<BTreeMap<FullPackageName, PackageDetails>> {
+        self.cubicle.get_packages()
+    }
+
+    /// Rebuilds the given packages for the host's platform, along with any
+    /// of their dependencies that are stale.
+    pub fn update_packages(&self, packages: &BTreeSet<FullPackageName>) -> Result<()> {
+        self.cubicle.update_packages(
+            packages,
+            &self.cubicle.scan_packages()?,
+            &UpdatePackagesConditions {
+                dependencies: ShouldPackageUpdate::IfStale,
+                named: ShouldPackageUpdate::Always,
+            },
+            &Platform::host(),
+        )
+    }
+
+    /// Returns the underlying [`Cubicle`], for operations that this
+    /// interface doesn't cover. Unlike these methods, its methods may print
+    /// and may change between versions.
+    pub fn cubicle(&self) -> &Cubicle {
+        &self.cubicle
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn builder_paths() {
+        let root = tempfile::tempdir().unwrap();
+        // This creates a real runner, so it must be one that's built in.
+        let runner = if cfg!(feature = "bubblewrap") {
+            "bubblewrap"
+        } else if cfg!(feature = "user-runner") {
+            "user"
+        } else {
+            "docker"
+        };
+        let config: Config = toml::from_str(&format!(
+            "runner = '{runner}'\nbuiltin_package_dir = {:?}",
+            concat!(env!("CARGO_MANIFEST_DIR"), "/packages")
+        ))
+        .unwrap();
+        let ops = Builder::new(config)
+            .cache_dir(root.path().join("cache"))
+            .data_dir(root.path().join("data"))
+            .state_dir(root.path().join("state"))
+            .build()
+            .unwrap();
+        let shared = &ops.cubicle().shared;
+        assert_eq!(
+            shared.cache_dir.as_host_raw(),
+            root.path().join("cache").join("cubicle")
+        );
+        assert_eq!(
+            shared.home_dirs.as_host_raw(),
+            root.path().join("state").join("cubicle").join("home")
+        );
+        assert_eq!(
+            shared.user_package_dir.as_host_raw(),
+            root.path().join("data").join("cubicle").join("packages")
+        );
+    }
+}
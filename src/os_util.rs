@@ -3,13 +3,18 @@ use std::fmt::Write;
 use std::path::Path;
 use std::sync::OnceLock;
 
+use super::command_ext::Command;
+use super::config::Config;
 use super::HostPath;
-use crate::somehow::{somehow as anyhow, warn, Context, Error};
+use crate::somehow::{somehow as anyhow, warn, Context, Error, Result};
 
 fn get_home_dir() -> HostPath {
-    let result = match std::env::var_os("HOME") {
-        Some(home) => HostPath::try_from(home),
-        None => Err(anyhow!("environment variable $HOME not set")),
+    let result = match (std::env::var_os("HOME"), passwd_entry()) {
+        (Some(home), _) => HostPath::try_from(home),
+        (None, Some(entry)) if !entry.home.is_empty() => HostPath::try_from(entry.home.clone()),
+        (None, _) => Err(anyhow!(
+            "environment variable $HOME not set and no home directory found in /etc/passwd"
+        )),
     }
     .context("failed to locate home directory on host");
     match result {
@@ -24,6 +29,141 @@ pub fn host_home_dir() -> &'static HostPath {
     HOME_DIR.get_or_init(get_home_dir)
 }
 
+/// The fields of an `/etc/passwd` entry that Cubicle uses.
+#[derive(Debug, Eq, PartialEq)]
+struct PasswdEntry {
+    name: String,
+    home: String,
+    shell: String,
+}
+
+/// Finds the entry for the given user ID in the contents of `/etc/passwd`.
+fn parse_passwd(contents: &str, uid: u32) -> Option<PasswdEntry> {
+    contents.lines().find_map(|line| {
+        let fields = line.split(':').collect::<Vec<_>>();
+        match fields.as_slice() {
+            [name, _password, entry_uid, _gid, _gecos, home, shell]
+                if entry_uid.parse() == Ok(uid) =>
+            {
+                Some(PasswdEntry {
+                    name: (*name).to_owned(),
+                    home: (*home).to_owned(),
+                    shell: (*shell).to_owned(),
+                })
+            }
+            _ => None,
+        }
+    })
+}
+
+static PASSWD_ENTRY: OnceLock<Option<PasswdEntry>> = OnceLock::new();
+
+/// Returns the current user's entry in `/etc/passwd`, if any.
+///
+/// This reads the file directly rather than going through NSS, so it won't
+/// find users from LDAP and similar. It's only a fallback for when the
+/// environment variables are missing, as in minimal containers.
+fn passwd_entry() -> Option<&'static PasswdEntry> {
+    PASSWD_ENTRY
+        .get_or_init(|| {
+            let contents = std::fs::read_to_string("/etc/passwd").ok()?;
+            parse_passwd(&contents, rustix::process::getuid().as_raw())
+        })
+        .as_ref()
+}
+
+/// Returns the name of the user running Cubicle on the host.
+///
+/// This comes from [`Config::host_user`], `$USER`, or `/etc/passwd`, in that
+/// order.
+pub fn host_user(config: &Config) -> Result<String> {
+    if let Some(user) = &config.host_user {
+        return Ok(user.clone());
+    }
+    if let Ok(user) = std::env::var("USER") {
+        if !user.is_empty() {
+            return Ok(user);
+        }
+    }
+    match passwd_entry() {
+        Some(entry) => Ok(entry.name.clone()),
+        None => Err(anyhow!(
+            "could not determine the host user: $USER is not set and the current \
+            user ID is not in /etc/passwd (hint: set `host_user` in `cubicle.toml`)"
+        )),
+    }
+}
+
+/// Returns the shell to run inside environments.
+///
+/// This comes from [`Config::shell`], `$SHELL`, or `/etc/passwd`, in that
+/// order, falling back to `/bin/sh`. Login shells that only refuse logins,
+/// like `nologin`, are skipped.
+pub fn host_shell(config: &Config) -> String {
+    if let Some(shell) = &config.shell {
+        return shell.clone();
+    }
+    if let Ok(shell) = std::env::var("SHELL") {
+        if !shell.is_empty() {
+            return shell;
+        }
+    }
+    match passwd_entry() {
+        Some(entry) if is_usable_shell(&entry.shell) => entry.shell.clone(),
+        _ => String::from("/bin/sh"),
+    }
+}
+
+fn is_usable_shell(shell: &str) -> bool {
+    !shell.is_empty()
+        && !matches!(
+            Path::new(shell).file_name().and_then(OsStr::to_str),
+            Some("nologin" | "false")
+        )
+}
+
+static HOST_ID: OnceLock<String> = OnceLock::new();
+
+/// Returns a string identifying this host: its machine ID if available, or
+/// its hostname otherwise.
+pub fn host_id() -> &'static str {
+    HOST_ID.get_or_init(|| {
+        if let Ok(id) = std::fs::read_to_string("/etc/machine-id") {
+            let id = id.trim();
+            if !id.is_empty() {
+                return id.to_owned();
+            }
+        }
+        match Command::query("hostname").output() {
+            Ok(output) if output.status.success() => {
+                String::from_utf8_lossy(&output.stdout).trim().to_owned()
+            }
+            _ => {
+                warn(anyhow!(
+                    "could not determine machine ID or hostname, using \"unknown\""
+                ));
+                String::from("unknown")
+            }
+        }
+    })
+}
+
+/// Returns whether a process with the given ID exists on this host.
+pub fn process_exists(pid: u32) -> bool {
+    let Some(pid) = i32::try_from(pid)
+        .ok()
+        .and_then(rustix::process::Pid::from_raw)
+    else {
+        return false;
+    };
+    match rustix::process::test_kill_process(pid) {
+        Ok(()) => true,
+        // The process exists but belongs to another user.
+        Err(rustix::io::Errno::PERM) => true,
+        Err(_) => false,
+    }
+}
+
 pub struct Uids {
     pub real_user: u64,
     pub group: u64,
@@ -96,8 +236,32 @@ fn timezone_from_localtime_target(path: &Path) -> Option<String> {
 
 #[cfg(test)]
 mod tests {
+    use super::PasswdEntry;
     use std::path::Path;
 
+    #[test]
+    fn parse_passwd() {
+        let contents = "\
+            root:x:0:0:root:/root:/bin/bash\n\
+            # comment\n\
+            ci:x:1001:1001:CI,,,:/home/ci:/usr/sbin/nologin\n\
+            short:x:1002:1002\n";
+        assert_eq!(
+            Some(PasswdEntry {
+                name: String::from("ci"),
+                home: String::from("/home/ci"),
+                shell: String::from("/usr/sbin/nologin"),
+            }),
+            super::parse_passwd(contents, 1001)
+        );
+        assert_eq!(None, super::parse_passwd(contents, 1002));
+        assert_eq!(None, super::parse_passwd(contents, 5));
+        assert!(super::is_usable_shell("/bin/bash"));
+        assert!(!super::is_usable_shell("/usr/sbin/nologin"));
+        assert!(!super::is_usable_shell("/bin/false"));
+        assert!(!super::is_usable_shell(""));
+    }
+
     #[test]
     fn timezone_from_localtime_target() {
         use super::timezone_from_localtime_target as tz;
@@ -0,0 +1,82 @@
+# Helpers for the `build.sh` scripts of package managers. Cubicle puts this
+# file in the builder environment's work directory, so a package manager's
+# `build.sh` can load it with:
+#
+#     . ~/w/.package-manager.sh
+#
+# The build script runs with `$PACKAGE` set to the name of the third-party
+# package to build, `$PACKAGE_VERSION` set to its version constraint (or empty),
+# and `$PACKAGE_MANAGER` set to the name of the package manager. It calls `provide`, `provide_new`, or `link_bins` to list the
+# package's files, then `finish` to write `~/provides.tar`.
+
+_cubicle_provides="$(mktemp)"
+_cubicle_snapshot="$(mktemp)"
+
+# Usage: provide PATH...
+#
+# Adds the paths, relative to the home directory, to the package's files.
+provide() {
+    for _cubicle_path in "$@"; do
+        printf '%s\n' "$_cubicle_path" >> "$_cubicle_provides"
+    done
+}
+
+# Prints the files (not directories) under the given directories, relative to
+# the home directory. Missing directories are skipped.
+_cubicle_list_files() {
+    (
+        cd ~
+        for _cubicle_dir in "$@"; do
+            if [ -e "$_cubicle_dir" ]; then
+                find "$_cubicle_dir" ! -type d
+            fi
+        done
+    )
+}
+
+# Usage: snapshot DIR...
+#
+# Records the files that already exist in the given directories, relative to
+# the home directory, so that `provide_new` leaves them out. Call this before
+# installing anything, since the directories may already have files from the
+# package manager's own dependencies.
+snapshot() {
+    _cubicle_list_files "$@" >> "$_cubicle_snapshot"
+}
+
+# Usage: provide_new DIR...
+#
+# Adds the files in the given directories that weren't there at the time of
+# `snapshot` to the package's files.
+provide_new() {
+    _cubicle_list_files "$@" | grep -vxF -f "$_cubicle_snapshot" >> "$_cubicle_provides" || true
+}
+
+# Usage: link_bins DIR
+#
+# Symlinks every executable in the directory, relative to the home directory,
+# into `~/bin` and adds the links to the package's files.
+link_bins() {
+    mkdir -p ~/bin
+    for _cubicle_bin in ~/"$1"/*; do
+        if [ -f "$_cubicle_bin" ] && [ -x "$_cubicle_bin" ]; then
+            _cubicle_name="$(basename "$_cubicle_bin")"
+            echo "Found executable: $_cubicle_name"
+            ln -fs "../$1/$_cubicle_name" ~/bin/
+            provide "bin/$_cubicle_name"
+        fi
+    done
+}
+
+# Usage: finish
+#
+# Writes `~/provides.tar` with all of the package's files.
+finish() {
+    if ! [ -s "$_cubicle_provides" ]; then
+        echo "ERROR: $PACKAGE_MANAGER package $PACKAGE provides no files" >&2
+        return 1
+    fi
+    tar --create --directory ~ --file ~/provides.tar \
+        --verbatim-files-from --files-from "$_cubicle_provides"
+    rm -f "$_cubicle_provides" "$_cubicle_snapshot"
+}
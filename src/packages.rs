@@ -1,29 +1,59 @@
-use clap::ValueEnum;
 use serde::Serialize;
 use std::borrow::Borrow;
+use std::cell::RefCell;
 use std::cmp::Ordering;
 use std::collections::{BTreeMap, BTreeSet};
 use std::fmt::{self, Debug, Display};
+use std::fs::File;
 use std::io::{self, BufRead, Write};
+use std::os::unix::fs::MetadataExt;
 use std::path::{Path, PathBuf};
 use std::str::FromStr;
-use std::time::SystemTime;
-use tempfile::NamedTempFile;
+use std::time::{Duration, Instant, SystemTime};
+use tracing::info;
 
-use crate::somehow::{somehow as anyhow, warn, Context, Error, LowLevelResult, Result};
+use crate::somehow::{somehow as anyhow, warn, warn_brief, Context, Error, LowLevelResult, Result};
 
+use super::command_ext::OutputStream;
+use super::delimited::{path_field, time_field, Delimited};
 use super::encoding::FilenameEncoder;
 use super::fs_util::{
-    create_tar_from_dir, file_size, summarize_dir, try_exists, try_iterdir, try_iterdir_dirs,
-    DirSummary, TarOptions,
+    compress_tar, create_tar_from_dir, file_size, hash_file, host_tempfile, open_tar,
+    single_file_tar, summarize_dir, try_exists, try_iterdir, try_iterdir_dirs, Filesystem,
+    HostFilesystem, TarOptions,
 };
+use super::hints::Hint;
+use super::lock::LockFile;
+use super::parallel::parallel_map;
 use super::runner::{EnvironmentExists, Init, Runner, RunnerCommand};
-use super::{rel_time, time_serialize_opt, Bytes, Cubicle, EnvironmentName, HostPath, RunnerKind};
+use super::{
+    rel_time, time_serialize_opt, Bytes, Cubicle, CubicleShared, Distro, DryRun, EnvironmentName,
+    Event, ExitStatusError, HostPath, Platform, RunnerKind, Transcript,
+};
 
 mod manifest;
 pub(crate) use manifest::Target;
 use manifest::{Dependency, Manifest};
 
+pub mod plan;
+use plan::{build_order, transitive_depends, BuildDepends, BuildHistory, Planner};
+
+mod choose;
+
+mod lockfile;
+
+mod nix;
+
+mod remote;
+
+mod repos;
+
+mod scaffold;
+pub use scaffold::NewPackage;
+
+mod shared;
+pub(crate) use shared::{shared_mount, ENV_PATH as SHARED_ENV_PATH};
+
 pub mod special {
     pub const AUTO_BATCH: &str = "auto-batch";
 
@@ -35,13 +65,46 @@ pub mod special {
     pub const DEFAULT: &str = "default";
 }
 
+/// The origin of the package sources shipped with Cubicle.
+const BUILT_IN_ORIGIN: &str = "built-in";
+
+/// Where a package manager's builder environment gets the helper library
+/// for its `build.sh`, relative to the home directory. See
+/// `src/package-manager.sh`.
+const MANAGER_LIB: &str = "w/.package-manager.sh";
+
 /// Information about a package's source files.
+#[derive(Clone)]
 pub struct PackageSpec {
     manifest: Manifest,
     dir: HostPath,
     origin: String,
+    /// The origins and directories of other sources with a package of the
+    /// same name, which this one hides.
+    shadows: Vec<(String, HostPath)>,
     update: Option<String>,
     test: Option<String>,
+    remove: Option<HostPath>,
+}
+
+impl PackageSpec {
+    /// Creates a package spec from the contents of a `package.toml` file,
+    /// without reading anything else from the filesystem.
+    ///
+    /// This is mainly useful with the functions in [`plan`]. `dir` is where
+    /// the package's source files would be, and `buildable` is whether the
+    /// package has a `build.sh` script.
+    pub fn from_manifest(dir: PathBuf, manifest: &str, buildable: bool) -> Result<Self> {
+        Ok(Self {
+            manifest: manifest::parse(manifest)?,
+            dir: HostPath::try_from(dir)?,
+            origin: String::from("in-memory"),
+            shadows: Vec::new(),
+            update: buildable.then(|| String::from("./build.sh")),
+            test: None,
+            remove: None,
+        })
+    }
 }
 
 /// Information about all available package sources.
@@ -50,6 +113,39 @@ pub struct PackageSpec {
 /// [`Cubicle::scan_packages`] to build one.
 pub type PackageSpecs = BTreeMap<PackageName, PackageSpec>;
 
+/// The result of a [`Cubicle::scan_packages`], which is reused while the
+/// package sources are unchanged.
+pub(super) struct SpecCache {
+    stamps: Vec<Stamp>,
+    specs: PackageSpecs,
+}
+
+/// A path with its modification time and size, if it exists.
+type Stamp = (PathBuf, Option<(SystemTime, u64)>);
+
+/// Returns the modification times and sizes of the package source
+/// directories, the package directories in them, and their manifests. Adding
+/// or removing a package or one of its scripts changes its directory's
+/// modification time, so these change whenever a scan's result would.
+fn package_stamps(sources: &[(String, HostPath)]) -> Result<Vec<Stamp>> {
+    let stamp = |path: HostPath| -> Stamp {
+        let stamp = std::fs::metadata(path.as_host_raw())
+            .and_then(|metadata| Ok((metadata.modified()?, metadata.len())))
+            .ok();
+        (path.as_host_raw().to_owned(), stamp)
+    };
+    let mut stamps = Vec::new();
+    for (_, dir) in sources {
+        stamps.push(stamp(dir.clone()));
+        for name in try_iterdir_dirs(dir)? {
+            let dir = dir.join(name);
+            stamps.push(stamp(dir.join("package.toml")));
+            stamps.push(stamp(dir));
+        }
+    }
+    Ok(stamps)
+}
+
 /// Used in [`Cubicle::update_packages`] to describe when packages should be
 /// updated.
 pub struct UpdatePackagesConditions {
@@ -81,101 +177,115 @@ pub enum ShouldPackageUpdate {
     IfRequired,
 }
 
-#[derive(Clone, Copy)]
-struct BuildDepends(bool);
+/// Formats a duration roughly, like "~6 min".
+fn approx_duration(duration: Duration) -> String {
+    let secs = duration.as_secs_f64();
+    if secs < 59.5 {
+        format!("~{secs:.0} sec")
+    } else {
+        format!("~{:.0} min", secs / 60.0)
+    }
+}
 
-fn transitive_depends(
-    packages: &BTreeSet<FullPackageName>,
-    specs: &PackageSpecs,
-    build_depends: BuildDepends,
-) -> Result<BTreeSet<FullPackageName>> {
-    struct Visitor<'a> {
-        specs: &'a PackageSpecs,
-        build_depends: BuildDepends,
-        visited: BTreeSet<FullPackageName>,
-    }
-
-    impl<'a> Visitor<'a> {
-        fn visit(
-            &mut self,
-            p: &FullPackageName,
-            needed_by: Option<&FullPackageName>,
-        ) -> Result<()> {
-            if !self.visited.contains(p) {
-                self.visited.insert(p.clone());
-                let spec = match &p.0 {
-                    PackageNamespace::Debian => {
-                        return Ok(());
-                    }
-                    PackageNamespace::Root => {
-                        self.specs.get(&p.1).ok_or_else(|| match needed_by {
-                            Some(other) => {
-                                anyhow!(
-                                    "could not find package definition for {p}, needed by {other}"
-                                )
-                            }
-                            None => anyhow!("could not find package definition for {p}"),
-                        })?
-                    }
-                    PackageNamespace::Managed(manager) => {
-                        let spec = self.specs.get(manager).ok_or_else(|| match needed_by {
-                        Some(other) => {
-                            anyhow!("could not find package definition for package manager {}, needed by {other}", p.0)
-                        }
-                        None => anyhow!("could not find package definition for {p}"),
-                    })?;
-                        if !spec.manifest.package_manager {
-                            return Err(anyhow!("package {} is not a package manager", p.0));
-                        }
-                        spec
-                    }
-                };
-                for (ns, table) in &spec.manifest.depends {
-                    for name in table.keys() {
-                        self.visit(&FullPackageName(ns.clone(), name.clone()), Some(p))?;
-                    }
-                }
-                if self.build_depends.0 {
-                    for (ns, table) in &spec.manifest.build_depends {
-                        for name in table.keys() {
-                            self.visit(&FullPackageName(ns.clone(), name.clone()), Some(p))?;
-                        }
-                    }
-                }
-            }
-            Ok(())
+/// Returns the sum of the known durations and the number of unknown ones.
+fn sum_estimates<'a>(estimates: impl Iterator<Item = &'a Option<Duration>>) -> (Duration, usize) {
+    let mut known = Duration::ZERO;
+    let mut unknown = 0;
+    for estimate in estimates {
+        match estimate {
+            Some(duration) => known += *duration,
+            None => unknown += 1,
         }
     }
+    (known, unknown)
+}
 
-    let mut visitor = Visitor {
-        specs,
-        build_depends,
-        visited: BTreeSet::new(),
-    };
-    for p in packages {
-        visitor.visit(p, None)?;
+/// Formats estimated build times, like "rust: ~6 min, firefox: ~12 min,
+/// total ~18 min".
+fn describe_estimates(planned: &[(FullPackageName, Option<Duration>)]) -> String {
+    let mut parts = planned
+        .iter()
+        .map(|(name, estimate)| match estimate {
+            Some(duration) => format!("{}: {}", name.unquoted(), approx_duration(*duration)),
+            None => format!("{}: unknown", name.unquoted()),
+        })
+        .collect::<Vec<_>>();
+    if planned.len() > 1 {
+        let (known, unknown) = sum_estimates(planned.iter().map(|(_, estimate)| estimate));
+        parts.push(match unknown {
+            0 => format!("total {}", approx_duration(known)),
+            _ if unknown < planned.len() => {
+                format!("total {} or more", approx_duration(known))
+            }
+            _ => String::from("total unknown"),
+        });
     }
-    Ok(visitor.visited)
+    parts.join(", ")
 }
 
 impl Cubicle {
-    pub(super) fn resolve_debian_packages(
+    /// Returns the distribution that the environment's OS packages come
+    /// from.
+    pub(super) fn env_distro(&self, env: &EnvironmentName) -> Distro {
+        match self.shared.config.runner {
+            RunnerKind::Bubblewrap | RunnerKind::User => Distro::host(),
+            RunnerKind::Docker => self.shared.config.runners.docker.distro_for(env),
+        }
+    }
+
+    /// Returns the platform that the environment runs on, which determines
+    /// which architecture's package builds it uses.
+    pub(super) fn env_platform(&self, env: &EnvironmentName) -> Result<Platform> {
+        Platform::for_environment(&self.shared.config, env)
+    }
+
+    /// Returns the OS packages that the environment needs for the given
+    /// packages, from the environment's distribution.
+    pub(super) fn resolve_distro_packages(
         &self,
+        env: &EnvironmentName,
         packages: &BTreeSet<FullPackageName>,
         specs: &PackageSpecs,
     ) -> Result<BTreeSet<PackageName>> {
+        let distro = self.env_distro(env);
         let strict = match self.shared.config.runner {
             RunnerKind::Bubblewrap => true,
-            RunnerKind::Docker => self.shared.config.docker.strict_debian_packages,
+            RunnerKind::Docker => self.shared.config.runners.docker.strict_debian_packages,
             RunnerKind::User => true,
         };
         if strict {
-            strict_debian_packages(packages, specs)
+            strict_distro_packages(distro, packages, specs)
         } else {
-            Ok(all_debian_packages(specs))
+            Ok(all_distro_packages(distro, specs))
         }
     }
 
+    /// Returns the OS packages for an environment with the given packages,
+    /// split into those for the shared base and those that only this
+    /// environment needs. See [`Init::env_distro_packages`].
+    ///
+    /// Without `strict_debian_packages`, the shared base has every OS
+    /// package that any package depends on, so the environment's own are
+    /// only those it names directly. Otherwise, all of the environment's OS
+    /// packages are its own.
+    pub(super) fn resolve_env_distro_packages(
+        &self,
+        env: &EnvironmentName,
+        packages: &BTreeSet<FullPackageName>,
+        specs: &PackageSpecs,
+    ) -> Result<(BTreeSet<PackageName>, BTreeSet<PackageName>)> {
+        let distro = self.env_distro(env);
+        let needed = strict_distro_packages(distro, packages, specs)?;
+        let shared = match self.shared.config.runner {
+            RunnerKind::Docker if !self.shared.config.runners.docker.strict_debian_packages => {
+                all_distro_packages(distro, specs)
+            }
+            _ => BTreeSet::new(),
+        };
+        let own = needed.difference(&shared).cloned().collect();
+        Ok((shared, own))
+    }
+
     fn add_packages(
         &self,
         packages: &mut PackageSpecs,
@@ -183,15 +293,22 @@ impl Cubicle {
         origin: &str,
     ) -> Result<()> {
         for name in try_iterdir_dirs(dir)? {
+            // Skip directories like `.git`.
+            if name.as_encoded_bytes().starts_with(b".") {
+                continue;
+            }
             let name = match name.to_str() {
                 Some(name) => PackageName::strict_from_str(name)?,
                 None => {
-                    return Err(anyhow!(
-                        "package names must be valid UTF-8, found {name:#?} in {dir:#?}"
-                    ))
+                    warn(anyhow!(
+                        "skipping package directory {}: package names must be valid UTF-8",
+                        dir.join(&name)
+                    ));
+                    continue;
                 }
             };
-            if packages.contains_key(&name) {
+            if let Some(spec) = packages.get_mut(&name) {
+                spec.shadows.push((origin.to_owned(), dir.join(&name.0)));
                 continue;
             }
             let dir = dir.join(&name.0);
@@ -236,14 +353,19 @@ impl Cubicle {
             let update = try_exists(&dir.join("build.sh"))
                 .todo_context()?
                 .then_some(String::from("./build.sh"));
+            let remove = try_exists(&dir.join("remove.sh"))
+                .todo_context()?
+                .then(|| dir.join("remove.sh"));
             packages.insert(
                 name,
                 PackageSpec {
                     manifest,
                     dir,
                     origin: origin.to_owned(),
+                    shadows: Vec::new(),
                     test,
                     update,
+                    remove,
                 },
             );
         }
@@ -265,51 +387,104 @@ impl Cubicle {
             Ok(())
         };
         // Don't use try_iterdir_dirs to allow symlinks at this level.
-        for dir in try_iterdir(&self.shared.user_package_dir)? {
-            add(&self.shared.user_package_dir.join(dir))?;
+        for (_, dir) in self.package_sources()? {
+            add(&dir)?;
         }
-        add(&self.shared.code_package_dir)?;
 
-        names.extend(self.package_names_from_tars()?);
+        names.extend(self.package_names_from_tars(&Platform::host())?);
 
         Ok(names)
     }
 
+    /// Returns the package sources as `(origin, directory)` pairs, in the
+    /// order they're searched. See
+    /// [`Config::package_precedence`](crate::config::Config::package_precedence).
+    fn package_sources(&self) -> Result<Vec<(String, HostPath)>> {
+        // Don't use try_iterdir_dirs to allow symlinks at this level.
+        let mut sources: Vec<(String, HostPath)> = try_iterdir(&self.shared.user_package_dir)?
+            .into_iter()
+            .map(|dir| {
+                (
+                    dir.to_string_lossy().into_owned(),
+                    self.shared.user_package_dir.join(dir),
+                )
+            })
+            .collect();
+        for (origin, dir) in self.package_repo_sources()? {
+            if sources.iter().any(|(o, _)| *o == origin) {
+                warn(anyhow!(
+                    "skipping package repository {origin:?}, since a local package \
+                    directory has the same name"
+                ));
+                continue;
+            }
+            sources.push((origin, dir));
+        }
+        sources.push((
+            String::from(BUILT_IN_ORIGIN),
+            self.shared.code_package_dir.clone(),
+        ));
+
+        let precedence = &self.shared.config.package_precedence;
+        for origin in precedence {
+            if !sources.iter().any(|(o, _)| o == origin) {
+                warn(anyhow!(
+                    "unknown package source {origin:?} in `package_precedence`"
+                ));
+            }
+        }
+        // This sort is stable, so unlisted sources keep the default order.
+        sources.sort_by_key(|(origin, _)| {
+            precedence
+                .iter()
+                .position(|o| o == origin)
+                .unwrap_or(precedence.len())
+        });
+        Ok(sources)
+    }
+
     /// Returns information about available package sources.
+    ///
+    /// The result is cached for the rest of the process and reused until a
+    /// package source directory, package directory, or manifest changes.
     pub fn scan_packages(&self) -> Result<PackageSpecs> {
+        let sources = self.package_sources()?;
+        let stamps = package_stamps(&sources)?;
+        let mut cache = self.shared.package_specs.lock().unwrap();
+        if let Some(cache) = cache.as_ref().filter(|cache| cache.stamps == stamps) {
+            return Ok(cache.specs.clone());
+        }
+        let specs = self.read_packages(sources)?;
+        *cache = Some(SpecCache {
+            stamps,
+            specs: specs.clone(),
+        });
+        Ok(specs)
+    }
+
+    /// Reads the package specs from the package sources, for
+    /// [`Cubicle::scan_packages`].
+    fn read_packages(&self, sources: Vec<(String, HostPath)>) -> Result<PackageSpecs> {
         let mut specs = PackageSpecs::new();
 
-        // Don't use try_iterdir_dirs to allow symlinks at this level.
-        for dir in try_iterdir(&self.shared.user_package_dir)? {
-            let origin = dir.to_string_lossy();
-            self.add_packages(
-                &mut specs,
-                &self.shared.user_package_dir.join(&dir),
-                &origin,
-            )
-            .with_context(|| {
-                format!(
-                    "error scanning user packages in {}",
-                    self.shared.user_package_dir.join(dir)
-                )
-            })?;
+        for (origin, dir) in sources {
+            self.add_packages(&mut specs, &dir, &origin)
+                .with_context(|| {
+                    if origin == BUILT_IN_ORIGIN {
+                        format!("error scanning built-in packages in {dir}")
+                    } else {
+                        format!("error scanning user packages in {dir}")
+                    }
+                })?;
         }
 
-        self.add_packages(&mut specs, &self.shared.code_package_dir, "built-in")
-            .with_context(|| {
-                format!(
-                    "error scanning built-in packages in {}",
-                    self.shared.code_package_dir
-                )
-            })?;
-
         // Packages that `special::AUTO_BATCH` depends on can't implicitly
         // depend on `special::AUTO_BATCH`.
         let auto = FullPackageName::from_str(special::AUTO_BATCH).unwrap();
         let auto_deps = transitive_depends(&BTreeSet::from([auto]), &specs, BuildDepends(true))?;
         for FullPackageName(ns, name) in &auto_deps {
             let spec = match ns {
-                PackageNamespace::Debian => continue,
+                PackageNamespace::Distro(_) => continue,
                 PackageNamespace::Root => specs.get_mut(name).ok_or_else(|| {
                     anyhow!(
                         "package {:?} depends on {name} but package not found",
@@ -334,105 +509,103 @@ impl Cubicle {
     }
 
     /// Rebuilds some of the given packages and their transitive dependencies,
-    /// as requested.
+    /// as requested, for the given platform.
     pub fn update_packages(
         &self,
         packages: &BTreeSet<FullPackageName>,
         specs: &PackageSpecs,
         conditions: &UpdatePackagesConditions,
+        platform: &Platform,
     ) -> Result<()> {
-        let mut todo: Vec<(FullPackageName, &PackageSpec)> =
-            transitive_depends(packages, specs, BuildDepends(true))?
-                .into_iter()
-                .filter(|FullPackageName(ns, _name)| ns != &PackageNamespace::Debian)
-                .map(|full_name| {
-                    let spec = match &full_name.0 {
-                        PackageNamespace::Debian => unreachable!(),
-                        PackageNamespace::Root => specs.get(&full_name.1).ok_or_else(|| {
-                            anyhow!("could not find definition for package {}", full_name.1)
-                        })?,
-                        PackageNamespace::Managed(manager) => {
-                            let spec = specs.get(manager).ok_or_else(|| {
-                                anyhow!("could not find definition for package manager {manager}")
-                            })?;
-                            if !spec.manifest.package_manager {
-                                return Err(anyhow!("package {manager} is not a package manager"));
-                            }
-                            spec
-                        }
-                    };
-                    Ok((full_name, spec))
-                })
-                .collect::<Result<_>>()?;
+        if platform.docker().is_some() && self.shared.config.runner != RunnerKind::Docker {
+            return Err(anyhow!(
+                "building packages for platform {platform} requires the Docker runner"
+            ));
+        }
+        let order = build_order(packages, specs)?;
+        let started = SystemTime::now();
+        let package_tar = |name: &FullPackageName| self.package_tar(name, platform);
+        let build_key_file = |name: &FullPackageName| self.build_key_file(name, platform);
+        let history = HostBuildHistory {
+            fs: &HostFilesystem,
+            package_tar: &package_tar,
+            build_key_file: &build_key_file,
+            source_modified: RefCell::default(),
+            source_hashes: RefCell::default(),
+        };
+        let planner = Planner {
+            history: &history,
+            auto_update: self.shared.config.auto_update,
+            now: self.shared.clock.now(),
+        };
 
-        let now = SystemTime::now();
-        let mut done: BTreeSet<FullPackageName> = BTreeSet::new();
-        loop {
-            let start_todos = todo.len();
-            if start_todos == 0 {
-                return Ok(());
-            }
-            let mut later: Vec<(FullPackageName, &PackageSpec)> = Vec::new();
+        let planned: Vec<(FullPackageName, Option<Duration>)> = planner
+            .planned_builds(&order, packages, conditions)?
+            .into_iter()
+            .map(|name| {
+                let estimate = self.last_build_duration(&name, platform);
+                (name, estimate)
+            })
+            .collect();
+        if !planned.is_empty() {
+            info!(
+                "Expected package build times: {}",
+                describe_estimates(&planned)
+            );
+        }
+        let mut remaining: BTreeMap<FullPackageName, Option<Duration>> =
+            planned.into_iter().collect();
+        let batch = self.batch_distro_packages(
+            order
+                .iter()
+                .filter(|(name, _)| remaining.contains_key(name))
+                .map(|(name, spec)| (name, *spec)),
+            specs,
+            platform,
+        )?;
 
-            for (full_name, spec) in todo {
-                let deps_ready = spec
-                    .manifest
-                    .depends
-                    .iter()
-                    .chain(spec.manifest.build_depends.iter())
-                    .all(|(ns, deps)| {
-                        ns == &PackageNamespace::Debian
-                            || deps
-                                .keys()
-                                .all(|dep| done.contains(&FullPackageName(ns.clone(), dep.clone())))
-                    });
-
-                if deps_ready {
-                    let needs_build = {
-                        if spec.update.is_none() {
-                            false
-                        } else {
-                            let when = if packages.contains(&full_name) {
-                                conditions.named
-                            } else {
-                                conditions.dependencies
-                            };
-                            match when {
-                                ShouldPackageUpdate::Always => true,
-                                ShouldPackageUpdate::IfStale => {
-                                    self.package_is_stale(&full_name, spec, now)?
-                                }
-                                ShouldPackageUpdate::IfRequired => {
-                                    self.last_built(&full_name).is_none()
-                                }
-                            }
-                        }
-                    };
-                    if needs_build {
-                        self.update_package(&full_name, spec, specs)?;
+        for (full_name, spec) in order {
+            let when = if packages.contains(&full_name) {
+                conditions.named
+            } else {
+                conditions.dependencies
+            };
+            if planner.needs_build(&full_name, spec, when)? {
+                let _lock = self.lock_package(&full_name, platform)?;
+                if planner.built_since(&full_name, started) {
+                    info!("Package {full_name} was just updated by another process");
+                    remaining.remove(&full_name);
+                    continue;
+                }
+                if remaining.len() > 1 {
+                    let (known, unknown) = sum_estimates(remaining.values());
+                    if known > Duration::ZERO {
+                        info!(
+                            "Estimated time remaining for {} package builds: {}{}",
+                            remaining.len(),
+                            approx_duration(known),
+                            if unknown > 0 { " or more" } else { "" },
+                        );
                     }
-                    done.insert(full_name);
-                } else {
-                    later.push((full_name, spec));
+                }
+                let key = planner.build_key(&full_name, spec)?;
+                if !self.download_package(&full_name, spec, platform, &key) {
+                    self.update_package(&full_name, spec, specs, platform, &batch, &key)?;
                 }
             }
-            if later.len() == start_todos {
-                let mut names = later
-                    .iter()
-                    .map(|(full_name, _)| full_name.to_string())
-                    .collect::<Vec<_>>();
-                names.sort_unstable();
-                return Err(anyhow!(
-                    "package dependencies are unsatisfiable for: {}",
-                    names.join(", ")
-                ));
-            }
-            todo = later;
+            remaining.remove(&full_name);
         }
+        Ok(())
     }
 
-    fn package_tar(&self, name: &FullPackageName) -> HostPath {
-        self.shared.package_cache.join(
+    /// Returns the directory holding the package builds for the platform's
+    /// architecture.
+    fn package_cache(&self, platform: &Platform) -> HostPath {
+        self.shared.package_cache.join(platform.arch())
+    }
+
+    fn package_tar(&self, name: &FullPackageName, platform: &Platform) -> HostPath {
+        self.package_cache(platform).join(
             FilenameEncoder::new()
                 .push(&name.unquoted())
                 .push(".tar")
@@ -440,8 +613,8 @@ impl Cubicle {
         )
     }
 
-    fn package_names_from_tars(&self) -> Result<Vec<FullPackageName>> {
-        Ok(try_iterdir(&self.shared.package_cache)?
+    fn package_names_from_tars(&self, platform: &Platform) -> Result<Vec<FullPackageName>> {
+        Ok(try_iterdir(&self.package_cache(platform))?
             .iter()
             .filter_map(|filename| {
                 FilenameEncoder::decode(filename)
@@ -453,8 +626,48 @@ impl Cubicle {
             .collect())
     }
 
-    fn testing_tar(&self, name: &FullPackageName) -> HostPath {
-        self.shared.package_cache.join(
+    /// Returns the package builder and test environments that no package
+    /// needs anymore, for `cub prune`.
+    ///
+    /// Builder environments are kept between builds to speed up the next
+    /// one, but only for packages that still exist: root packages with
+    /// sources, or other packages with cached builds. Test environments are
+    /// normally purged after each test, so any that remain are from tests
+    /// that failed or were interrupted.
+    pub(super) fn leftover_builders(
+        &self,
+        names: &BTreeSet<EnvironmentName>,
+    ) -> Result<Vec<EnvironmentName>> {
+        let specs = self.scan_packages()?;
+        let mut expected: BTreeMap<Platform, BTreeSet<EnvironmentName>> = BTreeMap::new();
+        let mut leftover = Vec::new();
+        for name in names {
+            if name.as_str().starts_with("test-package-") {
+                leftover.push(name.clone());
+                continue;
+            }
+            if !name.as_str().starts_with("package-") {
+                continue;
+            }
+            let platform = self.env_platform(name)?;
+            if !expected.contains_key(&platform) {
+                let builders = specs
+                    .keys()
+                    .map(|name| FullPackageName(PackageNamespace::Root, name.clone()))
+                    .chain(self.package_names_from_tars(&platform)?)
+                    .map(|package| EnvironmentName::for_builder_package(&package, &platform))
+                    .collect();
+                expected.insert(platform.clone(), builders);
+            }
+            if !expected[&platform].contains(name) {
+                leftover.push(name.clone());
+            }
+        }
+        Ok(leftover)
+    }
+
+    fn testing_tar(&self, name: &FullPackageName, platform: &Platform) -> HostPath {
+        self.package_cache(platform).join(
             FilenameEncoder::new()
                 .push(&name.unquoted())
                 .push(".testing.tar")
@@ -462,8 +675,8 @@ impl Cubicle {
         )
     }
 
-    fn failed_marker(&self, name: &FullPackageName) -> HostPath {
-        self.shared.package_cache.join(
+    fn failed_marker(&self, name: &FullPackageName, platform: &Platform) -> HostPath {
+        self.package_cache(platform).join(
             FilenameEncoder::new()
                 .push(&name.unquoted())
                 .push(".failed")
@@ -471,51 +684,215 @@ impl Cubicle {
         )
     }
 
-    fn last_built(&self, name: &FullPackageName) -> Option<SystemTime> {
-        let path = self.package_tar(name);
-        let metadata = std::fs::metadata(path.as_host_raw()).ok()?;
-        metadata.modified().ok()
+    /// Returns the path of the lock held while building the package for the
+    /// given platform. It covers the package's builder and test environments
+    /// and its files in the package cache.
+    fn package_lock(&self, name: &FullPackageName, platform: &Platform) -> HostPath {
+        self.package_cache(platform).join(
+            FilenameEncoder::new()
+                .push(&name.unquoted())
+                .push(".lock")
+                .encode(),
+        )
     }
 
-    fn package_is_stale(
-        &self,
-        package_name: &FullPackageName,
-        spec: &PackageSpec,
-        now: SystemTime,
-    ) -> Result<bool> {
-        let built = match self.last_built(package_name) {
-            Some(built) => built,
-            None => return Ok(true),
-        };
-        if let Some(threshold) = self.shared.config.auto_update {
-            match now.duration_since(built) {
-                Ok(d) if d > threshold => return Ok(true),
-                Err(_) => return Ok(true),
-                _ => {}
+    /// Returns the paths of the package locks in the package cache, for all
+    /// platforms. These may be held, stale, or (in a race) already gone.
+    pub(super) fn package_locks(&self) -> Result<Vec<HostPath>> {
+        let mut locks = Vec::new();
+        for arch in try_iterdir_dirs(&self.shared.package_cache)? {
+            let dir = self.shared.package_cache.join(arch);
+            for filename in try_iterdir(&dir)? {
+                if FilenameEncoder::decode(&filename).is_ok_and(|name| name.ends_with(".lock")) {
+                    locks.push(dir.join(filename));
+                }
             }
         }
-        let DirSummary { last_modified, .. } = summarize_dir(&spec.dir)?;
-        if last_modified > built {
-            return Ok(true);
-        }
-        for (ns, table) in spec
-            .manifest
-            .build_depends
-            .iter()
-            .chain(spec.manifest.depends.iter())
-        {
-            for name in table.keys() {
-                let full_name = FullPackageName(ns.clone(), name.clone());
-                if matches!(self.last_built(&full_name), Some(b) if b > built) {
-                    return Ok(true);
+        Ok(locks)
+    }
+
+    /// Acquires the lock for building the package for the given platform,
+    /// waiting while another process builds it.
+    ///
+    /// Other processes, possibly on other hosts sharing the same package
+    /// cache, may be updating packages too. Builds of different packages
+    /// may run at once, but each package is built by only one process at a
+    /// time. The cache-wide lock is held only while taking a package's lock,
+    /// so that cleanup and `cub prune` can hold it to keep new builds from
+    /// starting.
+    fn lock_package(&self, name: &FullPackageName, platform: &Platform) -> Result<LockFile> {
+        let cache_lock = self.shared.package_cache.join("update.lock");
+        let path = self.package_lock(name, platform);
+        loop {
+            {
+                let _cache_lock = LockFile::acquire(cache_lock.clone(), "package cache")?;
+                if let Some(lock) = LockFile::try_acquire(&path)? {
+                    return Ok(lock);
                 }
             }
+            // Wait without holding the cache-wide lock, then try again.
+            drop(LockFile::acquire(path.clone(), &format!("package {name}"))?);
+        }
+    }
+
+    /// Returns whether another process is building any package, for any
+    /// platform. Locks left behind by processes that crashed are removed.
+    ///
+    /// The caller should hold the cache-wide lock, so that no new builds
+    /// start.
+    pub(super) fn package_builds_in_progress(&self) -> Result<bool> {
+        for lock in self.package_locks()? {
+            LockFile::remove_if_stale(&lock)?;
+            if try_exists(&lock).with_context(|| format!("failed to check for {lock}"))? {
+                return Ok(true);
+            }
         }
         Ok(false)
     }
 
-    fn package_build_failed(&self, package_name: &FullPackageName) -> Result<bool> {
-        let failed_marker = self.failed_marker(package_name);
+    fn build_duration_file(&self, name: &FullPackageName, platform: &Platform) -> HostPath {
+        self.package_cache(platform).join(
+            FilenameEncoder::new()
+                .push(&name.unquoted())
+                .push(".duration")
+                .encode(),
+        )
+    }
+
+    /// Returns the file that holds the [`Planner::build_key`] of the
+    /// package's last successful build.
+    fn build_key_file(&self, name: &FullPackageName, platform: &Platform) -> HostPath {
+        self.package_cache(platform).join(
+            FilenameEncoder::new()
+                .push(&name.unquoted())
+                .push(".key")
+                .encode(),
+        )
+    }
+
+    /// Returns the file that holds the output of the package's last build
+    /// and of its tests since then.
+    fn build_log(&self, name: &FullPackageName, platform: &Platform) -> HostPath {
+        self.package_cache(platform).join(
+            FilenameEncoder::new()
+                .push(&name.unquoted())
+                .push(".log")
+                .encode(),
+        )
+    }
+
+    /// Returns the file that holds the SHA-256 hash of the package's tar
+    /// file, recorded when the build was made or downloaded.
+    fn build_checksum_file(&self, name: &FullPackageName, platform: &Platform) -> HostPath {
+        self.package_cache(platform).join(
+            FilenameEncoder::new()
+                .push(&name.unquoted())
+                .push(".sha256")
+                .encode(),
+        )
+    }
+
+    /// Returns the file that records the hash, size, and modification time
+    /// of the package's tar file when it was last verified.
+    fn build_verified_file(&self, name: &FullPackageName, platform: &Platform) -> HostPath {
+        self.package_cache(platform).join(
+            FilenameEncoder::new()
+                .push(&name.unquoted())
+                .push(".verified")
+                .encode(),
+        )
+    }
+
+    /// Checks that the package's tar file still has the hash recorded for
+    /// it, so that a corrupted or tampered build doesn't get into
+    /// environments. Builds from before hashes were recorded can't be
+    /// checked, which this warns about.
+    ///
+    /// Hashing a large build takes a while, so a tar file with the same size
+    /// and modification time as when it was last verified isn't hashed
+    /// again.
+    pub(super) fn verify_package_tar(
+        &self,
+        name: &FullPackageName,
+        platform: &Platform,
+    ) -> Result<()> {
+        let checksum_file = self.build_checksum_file(name, platform);
+        let tar = self.package_tar(name, platform);
+        let expected = match std::fs::read_to_string(checksum_file.as_host_raw()) {
+            Ok(contents) => contents.trim().to_owned(),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                warn(anyhow!(
+                    "the build of package {name} at {tar} has no recorded SHA-256, so it can't \
+                    be verified (try '{} package update {name}')",
+                    self.shared.exe_name
+                ));
+                return Ok(());
+            }
+            Err(e) => return Err(e).with_context(|| format!("failed to read {checksum_file}")),
+        };
+
+        let metadata = std::fs::metadata(tar.as_host_raw())
+            .with_context(|| format!("failed to stat {tar}"))?;
+        let stamp = format!(
+            "{expected} {} {}.{:09}",
+            metadata.len(),
+            metadata.mtime(),
+            metadata.mtime_nsec()
+        );
+        let verified_file = self.build_verified_file(name, platform);
+        if std::fs::read_to_string(verified_file.as_host_raw())
+            .is_ok_and(|contents| contents.trim() == stamp)
+        {
+            return Ok(());
+        }
+
+        let actual = hash_file(&tar)?;
+        if actual != expected {
+            return Err(anyhow!(
+                "the build of package {name} at {tar} has SHA-256 {actual}, but {expected} \
+                was recorded when it was built (try '{} package update {name}')",
+                self.shared.exe_name
+            ));
+        }
+        if let Err(e) = std::fs::write(verified_file.as_host_raw(), format!("{stamp}\n")) {
+            warn(anyhow!(
+                "failed to record package verification in {verified_file:?}: {e}"
+            ));
+        }
+        Ok(())
+    }
+
+    fn build_origin_file(&self, name: &FullPackageName, platform: &Platform) -> HostPath {
+        self.package_cache(platform).join(
+            FilenameEncoder::new()
+                .push(&name.unquoted())
+                .push(".origin")
+                .encode(),
+        )
+    }
+
+    /// Returns the origin of the package source that the last successful
+    /// build came from, if that was recorded.
+    fn built_origin(&self, name: &FullPackageName, platform: &Platform) -> Option<String> {
+        let path = self.build_origin_file(name, platform);
+        let contents = std::fs::read_to_string(path.as_host_raw()).ok()?;
+        Some(contents.trim_end_matches('\n').to_owned())
+    }
+
+    /// Returns how long the last successful build and test of the package
+    /// took, if that was recorded.
+    fn last_build_duration(&self, name: &FullPackageName, platform: &Platform) -> Option<Duration> {
+        let path = self.build_duration_file(name, platform);
+        let contents = std::fs::read_to_string(path.as_host_raw()).ok()?;
+        Duration::try_from_secs_f64(contents.trim().parse().ok()?).ok()
+    }
+
+    fn package_build_failed(
+        &self,
+        package_name: &FullPackageName,
+        platform: &Platform,
+    ) -> Result<bool> {
+        let failed_marker = self.failed_marker(package_name, platform);
         try_exists(&failed_marker)
             .with_context(|| format!("error while checking if {failed_marker:?} exists"))
     }
@@ -525,13 +902,26 @@ impl Cubicle {
         package_name: &FullPackageName,
         spec: &PackageSpec,
         specs: &PackageSpecs,
+        platform: &Platform,
+        batch: &DistroBatch,
+        key: &str,
     ) -> Result<()> {
-        let failed_marker = self.failed_marker(package_name);
-
-        match self
-            .update_package_(package_name, spec, specs)
-            .with_context(|| format!("failed to update package: {package_name}"))
-        {
+        let failed_marker = self.failed_marker(package_name, platform);
+
+        self.shared.progress(Event::PackageBuildStarted {
+            package: package_name,
+            platform: platform.docker(),
+        });
+        let start = Instant::now();
+        let result = self.update_package_(package_name, spec, specs, platform, batch, key);
+        self.shared.progress(Event::PackageBuildFinished {
+            package: package_name,
+            platform: platform.docker(),
+            success: result.is_ok(),
+            seconds: start.elapsed().as_secs_f64(),
+        });
+
+        match result.with_context(|| format!("failed to update package: {package_name}")) {
             Ok(_) => {
                 if let Err(e) = std::fs::remove_file(failed_marker.as_host_raw()) {
                     if e.kind() != io::ErrorKind::NotFound {
@@ -544,7 +934,7 @@ impl Cubicle {
                 Ok(())
             }
             Err(update_error) => {
-                let package_cache = &self.shared.package_cache;
+                let package_cache = self.package_cache(platform);
                 std::fs::create_dir_all(package_cache.as_host_raw())
                     .with_context(|| format!("failed to create directory {package_cache:?}"))?;
                 if let Err(e2) = std::fs::File::create(failed_marker.as_host_raw())
@@ -552,7 +942,7 @@ impl Cubicle {
                 {
                     warn(e2);
                 }
-                let cached = self.package_tar(package_name);
+                let cached = self.package_tar(package_name, platform);
                 let use_stale = match try_exists(&cached)
                     .with_context(|| format!("error while checking if {cached:?} exists"))
                 {
@@ -577,27 +967,50 @@ impl Cubicle {
         package_name: &FullPackageName,
         spec: &PackageSpec,
         specs: &PackageSpecs,
+        platform: &Platform,
+        batch: &DistroBatch,
+        key: &str,
     ) -> LowLevelResult<()> {
-        println!("Updating {package_name} package");
-        let env_name = EnvironmentName::for_builder_package(package_name);
-        self.build_package(package_name, &env_name, spec, specs)
-            .with_context(|| format!("error building package {package_name}"))?;
-
-        let package_cache = &self.shared.package_cache;
+        if platform.docker().is_some() {
+            info!("Updating {package_name} package for {platform}");
+        } else {
+            info!("Updating {package_name} package");
+        }
+        let start = Instant::now();
+        let package_cache = &self.package_cache(platform);
         std::fs::create_dir_all(package_cache.as_host_raw())
             .with_context(|| format!("failed to create directory {package_cache:?}"))?;
+        let log_path = self.build_log(package_name, platform);
+        let mut log = File::create(log_path.as_host_raw())
+            .with_context(|| format!("failed to create build log {log_path}"))?;
+
         let package_cache_dir = cap_std::fs::Dir::open_ambient_dir(
             package_cache.as_host_raw(),
             cap_std::ambient_authority(),
         )
         .with_context(|| format!("failed to open directory {package_cache:?}"))?;
 
-        let testing_tar_abs = self.testing_tar(package_name);
+        let testing_tar_abs = self.testing_tar(package_name, platform);
         let testing_tar_name = testing_tar_abs
             .as_host_raw()
             .strip_prefix(package_cache.as_host_raw())
             .unwrap();
-        {
+        if self.is_nix_package(package_name) {
+            self.build_nix_package(package_name, platform, &testing_tar_abs, &mut log)
+                .with_context(|| {
+                    format!(
+                        "error building package {package_name} (build output is in {log_path})"
+                    )
+                })?;
+        } else {
+            let env_name = EnvironmentName::for_builder_package(package_name, platform);
+            self.build_package(package_name, &env_name, spec, specs, batch, &mut log)
+                .with_context(|| {
+                    format!(
+                        "error building package {package_name} (build output is in {log_path})"
+                    )
+                })?;
+
             let mut file = package_cache_dir
                 .open_with(
                     testing_tar_name,
@@ -612,14 +1025,25 @@ impl Cubicle {
             self.runner
                 .copy_out_from_home(&env_name, Path::new("provides.tar"), &mut file)
                 .with_context(|| format!("failed to copy build output for package {package_name} to {testing_tar_abs}"))?;
+            drop(file);
+            compress_tar(&testing_tar_abs).with_context(|| {
+                format!("failed to compress build output for package {package_name}")
+            })?;
         }
 
-        if let Some(test_script) = &spec.test {
-            self.test_package(package_name, &testing_tar_abs, test_script, spec, specs)
-                .with_context(|| format!("error testing package {package_name}"))?;
-        }
+        self.test_package(
+            package_name,
+            &testing_tar_abs,
+            spec,
+            specs,
+            platform,
+            &mut log,
+        )
+        .with_context(|| {
+            format!("error testing package {package_name} (test output is in {log_path})")
+        })?;
 
-        let package_tar_abs = self.package_tar(package_name);
+        let package_tar_abs = self.package_tar(package_name, platform);
         let package_tar_name = package_tar_abs
             .as_host_raw()
             .strip_prefix(package_cache.as_host_raw())
@@ -635,39 +1059,132 @@ impl Cubicle {
                     "failed to rename {testing_tar_name:?} to {package_tar_name:?} in {package_cache:?}"
                 )
             })?;
+
+        let duration_file = self.build_duration_file(package_name, platform);
+        if let Err(e) = std::fs::write(
+            duration_file.as_host_raw(),
+            format!("{:.3}\n", start.elapsed().as_secs_f64()),
+        ) {
+            warn(anyhow!(
+                "failed to record build duration in {duration_file:?}: {e}"
+            ));
+        }
+        self.record_build(package_name, spec, platform, key);
+        self.upload_package(package_name, platform, key);
         Ok(())
     }
 
-    fn build_package(
+    /// Records where a new build of the package came from, its
+    /// [`Planner::build_key`], and the hash of its tar file, next to the tar
+    /// file.
+    fn record_build(
         &self,
         package_name: &FullPackageName,
-        env_name: &EnvironmentName,
         spec: &PackageSpec,
-        specs: &PackageSpecs,
-    ) -> Result<()> {
-        let packages: BTreeSet<FullPackageName> = spec
-            .manifest
-            .build_depends
-            .iter()
-            .chain(spec.manifest.depends.iter())
-            .flat_map(|(ns, table)| {
-                table
-                    .keys()
-                    .map(|name| FullPackageName(ns.clone(), name.clone()))
-            })
-            .collect();
+        platform: &Platform,
+        key: &str,
+    ) {
+        let origin_file = self.build_origin_file(package_name, platform);
+        if let Err(e) = std::fs::write(origin_file.as_host_raw(), format!("{}\n", spec.origin)) {
+            warn(anyhow!(
+                "failed to record package source in {origin_file:?}: {e}"
+            ));
+        }
+        let key_file = self.build_key_file(package_name, platform);
+        if let Err(e) = std::fs::write(key_file.as_host_raw(), format!("{key}\n")) {
+            warn(anyhow!("failed to record build key in {key_file:?}: {e}"));
+        }
+        let checksum_file = self.build_checksum_file(package_name, platform);
+        let result = hash_file(&self.package_tar(package_name, platform)).and_then(|sha256| {
+            std::fs::write(checksum_file.as_host_raw(), format!("{sha256}\n"))
+                .with_context(|| format!("failed to write {checksum_file}"))
+        });
+        if let Err(e) = result {
+            warn(e.context("failed to record package build hash"));
+        }
+    }
 
-        let mut debian_packages = self.resolve_debian_packages(&packages, specs)?;
-        if let Some(debian) = spec.manifest.depends.get(&PackageNamespace::Debian) {
-            debian_packages.extend(debian.keys().cloned());
+    /// Returns the OS packages to install in the builder environments of an
+    /// update run, if they should all get the same ones. See [`DistroBatch`].
+    fn batch_distro_packages<'a, I>(
+        &self,
+        builds: I,
+        specs: &PackageSpecs,
+        platform: &Platform,
+    ) -> Result<DistroBatch>
+    where
+        I: Iterator<Item = (&'a FullPackageName, &'a PackageSpec)>,
+    {
+        let mut batch = DistroBatch::new();
+        let docker = &self.shared.config.runners.docker;
+        if self.shared.config.runner != RunnerKind::Docker
+            || !docker.strict_debian_packages
+            || docker.isolate_package_builds
+        {
+            return Ok(batch);
         }
-        if let Some(debian) = spec.manifest.build_depends.get(&PackageNamespace::Debian) {
-            debian_packages.extend(debian.keys().cloned());
+        for (package_name, spec) in builds {
+            let env_name = EnvironmentName::for_builder_package(package_name, platform);
+            batch
+                .entry(docker.image_for(&env_name).to_owned())
+                .or_default()
+                .extend(self.builder_distro_packages(&env_name, spec, specs)?);
         }
+        Ok(batch)
+    }
 
-        let mut seeds = self.packages_to_seeds(&packages, specs)?;
+    /// Returns the OS packages that a package's builder environment needs.
+    fn builder_distro_packages(
+        &self,
+        env_name: &EnvironmentName,
+        spec: &PackageSpec,
+        specs: &PackageSpecs,
+    ) -> Result<BTreeSet<PackageName>> {
+        let distro = PackageNamespace::Distro(self.env_distro(env_name));
+        let mut distro_packages =
+            self.resolve_distro_packages(env_name, &build_inputs(spec), specs)?;
+        if let Some(deps) = spec.manifest.depends.get(&distro) {
+            distro_packages.extend(deps.keys().cloned());
+        }
+        if let Some(deps) = spec.manifest.build_depends.get(&distro) {
+            distro_packages.extend(deps.keys().cloned());
+        }
+        Ok(distro_packages)
+    }
 
-        let tar_file = NamedTempFile::new().todo_context()?;
+    fn build_package(
+        &self,
+        package_name: &FullPackageName,
+        env_name: &EnvironmentName,
+        spec: &PackageSpec,
+        specs: &PackageSpecs,
+        batch: &DistroBatch,
+        log: &mut File,
+    ) -> Result<()> {
+        let packages = build_inputs(spec);
+        let distro_packages = match batch.get(self.shared.config.runners.docker.image_for(env_name))
+        {
+            Some(batched) => batched.clone(),
+            None => self.builder_distro_packages(env_name, spec, specs)?,
+        };
+
+        let mut seeds = self.packages_to_seeds(&packages, specs, env_name)?;
+
+        let offline = spec
+            .manifest
+            .network
+            .map_or(self.shared.config.offline_package_builds, |network| {
+                !network
+            });
+        if offline && self.shared.config.runner == RunnerKind::User {
+            return Err(anyhow!(
+                "package {package_name} must be built with networking disabled, \
+                which the user runner can't do"
+            ));
+        }
+        let _offline = offline.then(|| OfflineBuilder::new(&self.shared, env_name));
+
+        let tar_file = host_tempfile().todo_context()?;
         create_tar_from_dir(
             &spec.dir,
             tar_file.as_file(),
@@ -679,15 +1196,28 @@ impl Cubicle {
         .with_context(|| format!("failed to tar package source for {package_name}"))?;
         seeds.push(HostPath::try_from(tar_file.path().to_owned()).unwrap());
 
+        let manager_lib = match package_name.0 {
+            PackageNamespace::Managed(_) => Some(single_file_tar(
+                Path::new(MANAGER_LIB),
+                include_bytes!("package-manager.sh"),
+            )?),
+            _ => None,
+        };
+        if let Some(tar_file) = &manager_lib {
+            seeds.push(HostPath::try_from(tar_file.path().to_owned()).unwrap());
+        }
+
         let init = Init {
-            debian_packages: debian_packages
+            distro_packages: distro_packages
                 .iter()
                 .map(|name| name.as_str().to_owned())
                 .collect(),
+            env_distro_packages: Vec::new(),
             env_vars: Vec::new(),
             seeds,
         };
 
+        self.forget_disk_usage(env_name)?;
         use EnvironmentExists::*;
         match self.runner.exists(env_name)? {
             FullyExists | PartiallyExists => self.runner.reset(env_name, &init),
@@ -695,18 +1225,17 @@ impl Cubicle {
         }?;
 
         if let Some(update) = &spec.update {
-            let env_vars = if package_name.0 == PackageNamespace::Root {
-                vec![]
-            } else {
-                vec![("PACKAGE", package_name.1.as_str().to_owned())]
-            };
-            self.runner.run(
+            let env_vars = manager_env_vars(package_name);
+            self.run_logged(
                 env_name,
                 &RunnerCommand::Exec {
                     command: &[update.clone()],
                     env_vars: env_vars.as_slice(),
+                    dir: None,
                 },
-            )?;
+                log,
+            )
+            .and_then(|status| ExitStatusError::check(status, "package build script"))?;
         }
         Ok(())
     }
@@ -715,18 +1244,23 @@ impl Cubicle {
         &self,
         package_name: &FullPackageName,
         testing_tar: &HostPath,
-        test_script: &str,
         spec: &PackageSpec,
         specs: &PackageSpecs,
+        platform: &Platform,
+        log: &mut File,
     ) -> Result<()> {
-        println!("Testing {package_name} package");
+        let Some(test_script) = &spec.test else {
+            return Ok(());
+        };
+        info!("Testing {package_name} package");
         let test_name = EnvironmentName::from_string(format!(
             "test-{}",
-            EnvironmentName::for_builder_package(package_name).as_str()
+            EnvironmentName::for_builder_package(package_name, platform).as_str()
         ))
         .unwrap();
 
         self.runner.purge(&test_name)?;
+        self.forget_disk_usage(&test_name)?;
 
         let packages: BTreeSet<FullPackageName> = spec
             .manifest
@@ -740,16 +1274,17 @@ impl Cubicle {
             .chain([FullPackageName::from_str(special::AUTO_BATCH).unwrap()])
             .collect();
 
-        let mut seeds = self.packages_to_seeds(&packages, specs)?;
+        let mut seeds = self.packages_to_seeds(&packages, specs, &test_name)?;
         seeds.push(testing_tar.clone());
 
-        let mut debian_packages = self.resolve_debian_packages(&packages, specs)?;
-        if let Some(debian) = spec.manifest.depends.get(&PackageNamespace::Debian) {
-            debian_packages.extend(debian.keys().cloned());
+        let distro = PackageNamespace::Distro(self.env_distro(&test_name));
+        let mut distro_packages = self.resolve_distro_packages(&test_name, &packages, specs)?;
+        if let Some(deps) = spec.manifest.depends.get(&distro) {
+            distro_packages.extend(deps.keys().cloned());
         }
 
         {
-            let tar_file = NamedTempFile::new().todo_context()?;
+            let tar_file = host_tempfile().todo_context()?;
             create_tar_from_dir(
                 &spec.dir,
                 tar_file.as_file(),
@@ -764,47 +1299,591 @@ impl Cubicle {
             self.runner.create(
                 &test_name,
                 &Init {
-                    debian_packages: debian_packages
+                    distro_packages: distro_packages
                         .iter()
                         .map(|name| name.as_str().to_owned())
                         .collect(),
+                    env_distro_packages: Vec::new(),
                     env_vars: Vec::new(),
                     seeds,
                 },
             )?;
         }
 
-        let env_vars = if package_name.0 == PackageNamespace::Root {
-            vec![]
-        } else {
-            vec![("PACKAGE", package_name.1.as_str().to_owned())]
-        };
-        self.runner.run(
+        let env_vars = manager_env_vars(package_name);
+        self.run_logged(
             &test_name,
             &RunnerCommand::Exec {
                 command: &[test_script.to_owned()],
                 env_vars: env_vars.as_slice(),
+                dir: None,
             },
-        )?;
+            log,
+        )
+        .and_then(|status| ExitStatusError::check(status, "package test script"))?;
 
         self.runner.purge(&test_name)
     }
 
-    /// Returns details of available packages.
+    /// Runs a package build or test script, passing its output on to the
+    /// terminal and also writing it to the package's build log.
+    ///
+    /// Capturing the output means the script's stdout and stderr are pipes,
+    /// even when Cubicle's are a terminal, and its stdin is closed. Tools
+    /// that check for a terminal act as they would in CI, and scripts can't
+    /// prompt.
+    fn run_logged(
+        &self,
+        env_name: &EnvironmentName,
+        command: &RunnerCommand,
+        log: &mut File,
+    ) -> Result<std::process::ExitStatus> {
+        let mut log_error = None;
+        let status = self
+            .runner
+            .run_streaming(env_name, command, &mut |stream, chunk| {
+                // The terminal going away shouldn't stop the build.
+                let _ = match stream {
+                    OutputStream::Stdout => io::stdout().write_all(chunk),
+                    OutputStream::Stderr => io::stderr().write_all(chunk),
+                };
+                if log_error.is_none() {
+                    log_error = log.write_all(chunk).err();
+                }
+            })?;
+        if let Some(e) = log_error {
+            warn(anyhow!("failed to write package build log: {e}"));
+        }
+        Ok(status)
+    }
+
+    /// Corresponds to `cub package logs`.
+    ///
+    /// Prints the output of the package's last build, and of any tests of
+    /// it since then.
+    pub fn package_logs(&self, name: &FullPackageName, platform: &Platform) -> Result<()> {
+        let path = self.build_log(name, platform);
+        let mut file = match File::open(path.as_host_raw()) {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                return Err(anyhow!("package {name} has no build log (expected {path})"));
+            }
+            Err(e) => return Err(e).with_context(|| format!("failed to open {path}")),
+        };
+        io::copy(&mut file, &mut io::stdout().lock())
+            .with_context(|| format!("failed to print {path}"))?;
+        Ok(())
+    }
+
+    /// Corresponds to `cub package test`.
+    ///
+    /// Runs the `test.sh` scripts of the given packages against their current
+    /// builds, without rebuilding the packages. This builds any of their
+    /// dependencies that have never been built. Packages without a test
+    /// script are skipped.
+    pub fn test_packages(
+        &self,
+        packages: &BTreeSet<FullPackageName>,
+        platform: &Platform,
+    ) -> Result<()> {
+        let specs = self.scan_packages()?;
+        for package in packages {
+            let tar = self.package_tar(package, platform);
+            if !try_exists(&tar).with_context(|| format!("failed to check for {tar}"))? {
+                return Err(anyhow!(
+                    "package {package} has not been built (try '{} package update {package}')",
+                    self.shared.exe_name
+                ));
+            }
+        }
+        self.update_packages(
+            packages,
+            &specs,
+            &UpdatePackagesConditions {
+                dependencies: ShouldPackageUpdate::IfRequired,
+                named: ShouldPackageUpdate::IfRequired,
+            },
+            platform,
+        )?;
+
+        let mut failed = Vec::new();
+        for (package_name, spec) in build_order(packages, &specs)? {
+            if !packages.contains(&package_name) {
+                continue;
+            }
+            if spec.test.is_none() {
+                println!("Package {package_name} has no test script");
+                continue;
+            }
+            let _lock = self.lock_package(&package_name, platform)?;
+            // The test's output is added to the log of the build it tests.
+            let log_path = self.build_log(&package_name, platform);
+            let mut log = File::options()
+                .create(true)
+                .append(true)
+                .open(log_path.as_host_raw())
+                .with_context(|| format!("failed to open build log {log_path}"))?;
+            let result = self.test_package(
+                &package_name,
+                &self.package_tar(&package_name, platform),
+                spec,
+                &specs,
+                platform,
+                &mut log,
+            );
+            match result {
+                Ok(()) => println!("Package {package_name} passed its test"),
+                Err(e) => {
+                    warn(e.context(format!(
+                        "error testing package {package_name} (test output is in {log_path})"
+                    )));
+                    failed.push(package_name);
+                }
+            }
+        }
+        if !failed.is_empty() {
+            return Err(anyhow!(
+                "{} package(s) failed their tests: {}",
+                failed.len(),
+                failed
+                    .iter()
+                    .map(|name| name.to_string())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ));
+        }
+        Ok(())
+    }
+
+    /// Corresponds to `cub env add-packages`.
+    ///
+    /// Adds the given packages to the environment's `packages.txt` without
+    /// resetting it. This builds the new packages if they're stale and any
+    /// of their dependencies that have never been built, copies the files of
+    /// the packages that the environment didn't have yet into its home
+    /// directory, and runs their `~/.dev-init/` scripts.
+    ///
+    /// OS-level packages can't be installed this way. If the new packages
+    /// need any that the environment's other packages don't, this warns that
+    /// they'll be installed on the next `cub reset`.
+    pub fn add_packages_to_environment(
+        &self,
+        name: &EnvironmentName,
+        packages: &BTreeSet<FullPackageName>,
+    ) -> Result<()> {
+        let _lock = self.lock_environment(name)?;
+        if self.runner.exists(name)? != EnvironmentExists::FullyExists {
+            return Err(anyhow!(
+                "Environment {name} does not exist or is in a broken state"
+            ));
+        }
+        self.forget_disk_usage(name)?;
+        let current = self
+            .read_package_list_from_env(name)
+            .with_context(|| format!("failed to parse `packages.txt` from {name}"))?;
+        let present = packages
+            .intersection(&current)
+            .map(|package| package.to_string())
+            .collect::<Vec<_>>();
+        if !present.is_empty() {
+            return Err(anyhow!(
+                "Environment {name} already has package(s): {}",
+                present.join(", ")
+            ));
+        }
+        let combined: BTreeSet<FullPackageName> = current.union(packages).cloned().collect();
+
+        let specs = self.scan_packages()?;
+        let platform = self.env_platform(name)?;
+        self.update_packages(
+            packages,
+            &specs,
+            &UpdatePackagesConditions {
+                dependencies: ShouldPackageUpdate::IfRequired,
+                named: ShouldPackageUpdate::IfStale,
+            },
+            &platform,
+        )?;
+        let with_auto = |packages: &BTreeSet<FullPackageName>| {
+            let mut packages = packages.clone();
+            packages.insert(FullPackageName::from_str(special::AUTO_INTERACTIVE).unwrap());
+            packages
+        };
+        self.check_package_sources(&with_auto(packages), &specs, &platform)?;
+        let missing_distro = self
+            .resolve_distro_packages(name, &with_auto(&combined), &specs)?
+            .difference(&self.resolve_distro_packages(name, &with_auto(&current), &specs)?)
+            .map(|package| package.as_str().to_owned())
+            .collect::<Vec<_>>();
+        if !missing_distro.is_empty() {
+            warn_brief(format!(
+                "The new packages need OS packages that {name} may not have: {}. \
+                Run `{} reset --keep-home {name}` to install them.",
+                missing_distro.join(", "),
+                self.shared.exe_name,
+            ));
+        }
+
+        let before = plan::seed_packages(&with_auto(&current), &specs)?;
+        let added: Vec<FullPackageName> = plan::seed_packages(&with_auto(&combined), &specs)?
+            .difference(&before)
+            .cloned()
+            .collect();
+        info!(
+            "Adding packages to {name}: {}",
+            added
+                .iter()
+                .map(|package| package.unquoted())
+                .collect::<Vec<_>>()
+                .join(", ")
+        );
+        let mut init_scripts = Vec::new();
+        for package in &added {
+            let Some(seed) = self.package_seed(package, &specs, name)? else {
+                continue;
+            };
+            let mut file = std::fs::File::open(seed.as_host_raw())
+                .with_context(|| format!("failed to open {seed}"))?;
+            self.runner
+                .copy_in(name, Path::new(""), &mut file)
+                .with_context(|| format!("failed to copy package {package} into {name}"))?;
+            for (path, is_dir) in self.provided_paths(package, &platform)? {
+                if !is_dir && path.parent() == Some(Path::new(".dev-init")) {
+                    init_scripts.push(path.to_string_lossy().into_owned());
+                }
+            }
+        }
+
+        let packages_txt = single_file_tar(
+            Path::new("packages.txt"),
+            package_list(&combined).as_bytes(),
+        )?;
+        let mut file = packages_txt
+            .reopen()
+            .with_context(|| format!("failed to reopen {:?}", packages_txt.path()))?;
+        self.runner.copy_in(
+            name,
+            Path::new(self.shared.config.work_dir_name(name)),
+            &mut file,
+        )?;
+
+        if !init_scripts.is_empty() {
+            let mut command = vec![
+                String::from("sh"),
+                String::from("-c"),
+                String::from(
+                    r#"cd && if [ -f ./.profile ]; then . ./.profile; fi && for f; do if [ -x "$f" ]; then "$f"; fi; done"#,
+                ),
+                String::from("sh"),
+            ];
+            command.extend(init_scripts);
+            self.runner
+                .run(
+                    name,
+                    &RunnerCommand::Exec {
+                        command: &command,
+                        env_vars: &[],
+                        dir: None,
+                    },
+                )
+                .and_then(|status| ExitStatusError::check(status, "`~/.dev-init` scripts"))
+                .with_context(|| format!("failed to run new packages' init scripts in {name}"))?;
+        }
+        Ok(())
+    }
+
+    /// Corresponds to `cub env remove-packages`.
+    ///
+    /// Removes the given packages from the environment's `packages.txt` and
+    /// deletes their files from its home directory, along with the files of
+    /// any dependencies that the remaining packages don't need. Files that a
+    /// remaining package also provides are kept, as are OS-level packages.
+    /// Before deleting anything, this runs each removed package's
+    /// `remove.sh`, if any, in the environment's home directory.
+    ///
+    /// With `dry_run`, this only prints what it would do, including the
+    /// external commands it would run.
+    pub fn remove_packages_from_environment(
+        &self,
+        name: &EnvironmentName,
+        packages: &BTreeSet<FullPackageName>,
+        dry_run: DryRun,
+    ) -> Result<()> {
+        let _lock = self.lock_environment(name)?;
+        if self.runner.exists(name)? != EnvironmentExists::FullyExists {
+            return Err(anyhow!(
+                "Environment {name} does not exist or is in a broken state"
+            ));
+        }
+        self.forget_disk_usage(name)?;
+        let current = self
+            .read_package_list_from_env(name)
+            .with_context(|| format!("failed to parse `packages.txt` from {name}"))?;
+        let missing = packages
+            .difference(&current)
+            .map(|package| package.to_string())
+            .collect::<Vec<_>>();
+        if !missing.is_empty() {
+            return Err(anyhow!(
+                "Environment {name} does not have package(s): {}",
+                missing.join(", ")
+            ));
+        }
+        let remaining: BTreeSet<FullPackageName> = current.difference(packages).cloned().collect();
+
+        let specs = self.scan_packages()?;
+        let closure = |packages: &BTreeSet<FullPackageName>| -> Result<BTreeSet<FullPackageName>> {
+            let mut packages = packages.clone();
+            packages.insert(FullPackageName::from_str(special::AUTO_INTERACTIVE).unwrap());
+            plan::seed_packages(&packages, &specs)
+        };
+        let platform = self.env_platform(name)?;
+        let kept = closure(&remaining)?;
+        let removed: Vec<FullPackageName> = closure(&current)?.difference(&kept).cloned().collect();
+
+        let mut kept_paths = BTreeSet::new();
+        for package in &kept {
+            kept_paths.extend(self.provided_paths(package, &platform)?.into_keys());
+        }
+        let mut files = BTreeSet::new();
+        let mut dirs = BTreeSet::new();
+        let mut scripts = Vec::new();
+        for package in &removed {
+            for (path, is_dir) in self.provided_paths(package, &platform)? {
+                if kept_paths.contains(&path) {
+                    continue;
+                }
+                if is_dir {
+                    dirs.insert(path);
+                } else {
+                    files.insert(path);
+                }
+            }
+            let spec = match &package.0 {
+                PackageNamespace::Managed(manager) => specs.get(manager),
+                _ => specs.get(&package.1),
+            }
+            .ok_or_else(|| anyhow!("could not find definition for package {package}"))?;
+            for path in &spec.manifest.removes {
+                files.insert(home_relative_path(Path::new(path)).ok_or_else(|| {
+                    anyhow!("invalid path in `removes` for package {package}: {path:?}")
+                })?);
+            }
+            if let Some(script) = &spec.remove {
+                scripts.push((package, script));
+            }
+        }
+
+        let removed_names = removed
+            .iter()
+            .map(|package| package.unquoted())
+            .collect::<Vec<_>>()
+            .join(", ");
+        if dry_run.0 {
+            println!("Would remove packages from {name}: {removed_names}");
+            println!(
+                "Would delete {} files from the home directory:",
+                files.len()
+            );
+            for path in &files {
+                println!("    {}", path.display());
+            }
+        } else {
+            info!("Removing packages from {name}: {removed_names}");
+        }
+        let run = |script: &str, args: Vec<String>, env_vars: &[(&'static str, String)]| {
+            let mut command = vec![
+                String::from("sh"),
+                String::from("-c"),
+                String::from(script),
+                String::from("sh"),
+            ];
+            command.extend(args);
+            self.runner
+                .run(
+                    name,
+                    &RunnerCommand::Exec {
+                        command: &command,
+                        env_vars,
+                        dir: None,
+                    },
+                )
+                .and_then(|status| ExitStatusError::check(status, "sh"))
+        };
+        let remove = || -> Result<()> {
+            for (package, script) in &scripts {
+                let contents = std::fs::read_to_string(script.as_host_raw())
+                    .with_context(|| format!("failed to read {script}"))?;
+                let env_vars = manager_env_vars(package);
+                run(
+                r#"cd && f="$(mktemp)" && printf '%s' "$1" > "$f" && chmod +x "$f" && "$f"; s=$?; rm -f "$f"; exit $s"#,
+                vec![contents],
+                &env_vars,
+            )
+            .with_context(|| format!("failed to run `remove.sh` for {package}"))?;
+            }
+            if !files.is_empty() {
+                run(
+                    r#"cd && rm -f -- "$@""#,
+                    files
+                        .iter()
+                        .map(|path| path.to_string_lossy().into_owned())
+                        .collect(),
+                    &[],
+                )?;
+            }
+            // Remove emptied directories, deepest first, but leave the top-level
+            // ones, which the environments normally have anyway.
+            let mut dirs = dirs
+                .into_iter()
+                .filter(|path| path.components().count() > 1)
+                .collect::<Vec<_>>();
+            dirs.sort_by_key(|path| std::cmp::Reverse(path.components().count()));
+            if !dirs.is_empty() {
+                run(
+                    r#"cd && for d; do rmdir -- "$d" 2>/dev/null || true; done"#,
+                    dirs.iter()
+                        .map(|path| path.to_string_lossy().into_owned())
+                        .collect(),
+                    &[],
+                )?;
+            }
+            run(
+                r#"cd && cd -- "$1" && printf '%s' "$2" > packages.txt"#,
+                vec![
+                    self.shared.config.work_dir_name(name).to_owned(),
+                    package_list(&remaining),
+                ],
+                &[],
+            )
+        };
+
+        if !dry_run.0 {
+            return remove();
+        }
+        let transcript = Transcript::new();
+        {
+            let _recording = transcript.record(DryRun(true));
+            remove()?;
+        }
+        println!("Would run these commands:");
+        for entry in transcript.entries() {
+            if !entry.read_only {
+                println!("    {entry}");
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the paths, relative to the home directory, that a built
+    /// package provides, mapped to whether each one is a directory. Returns
+    /// an empty map if the package hasn't been built.
+    fn provided_paths(
+        &self,
+        name: &FullPackageName,
+        platform: &Platform,
+    ) -> Result<BTreeMap<PathBuf, bool>> {
+        let tar_path = self.package_tar(name, platform);
+        if !try_exists(&tar_path).with_context(|| format!("failed to check for {tar_path}"))? {
+            return Ok(BTreeMap::new());
+        }
+        let mut paths = BTreeMap::new();
+        let mut archive = tar::Archive::new(open_tar(&tar_path)?);
+        for entry in archive
+            .entries()
+            .with_context(|| format!("failed to read {tar_path}"))?
+        {
+            let entry = entry.with_context(|| format!("failed to read {tar_path}"))?;
+            let path = entry
+                .path()
+                .with_context(|| format!("failed to read {tar_path}"))?;
+            if let Some(path) = home_relative_path(&path) {
+                paths.insert(path, entry.header().entry_type().is_dir());
+            }
+        }
+        Ok(paths)
+    }
+
+    /// Returns the built packages that provide the given path, relative to
+    /// the home directory, or anything under it, with the paths that each
+    /// one provides.
+    pub fn get_package_providers(
+        &self,
+        path: &Path,
+        platform: &Platform,
+    ) -> Result<BTreeMap<FullPackageName, Vec<PathBuf>>> {
+        // The shell expands `~` to the home directory on the host, which
+        // stands in for the one in environments.
+        let path = path
+            .strip_prefix("~")
+            .or_else(|_| path.strip_prefix(self.shared.home.as_host_raw()))
+            .unwrap_or(path);
+        let path = home_relative_path(path).ok_or_else(|| {
+            anyhow!(
+                "expected a path relative to the home directory, like `~/.bashrc`, got {}",
+                path.display()
+            )
+        })?;
+        let mut providers = BTreeMap::new();
+        for package in self.package_names_from_tars(platform)? {
+            let paths: Vec<PathBuf> = self
+                .provided_paths(&package, platform)?
+                .into_keys()
+                .filter(|provided| provided.starts_with(&path))
+                .collect();
+            if !paths.is_empty() {
+                providers.insert(package, paths);
+            }
+        }
+        Ok(providers)
+    }
+
+    /// Corresponds to `cub package provides`.
+    pub fn list_package_providers(&self, path: &Path, platform: &Platform) -> Result<()> {
+        let providers = self.get_package_providers(path, platform)?;
+        if providers.is_empty() {
+            println!("No built package provides {}", path.display());
+            return Ok(());
+        }
+        for (package, paths) in &providers {
+            println!("{}:", package.unquoted());
+            for path in paths {
+                println!("    ~/{}", path.display());
+            }
+        }
+        if providers.len() > 1 {
+            println!(
+                "{} packages provide {}. Environments with more than one of \
+                them get whichever is unpacked last.",
+                providers.len(),
+                path.display()
+            );
+        }
+        Ok(())
+    }
+
+    /// Returns details of available packages, with the builds for the host's
+    /// platform.
     pub fn get_packages(&self) -> Result<BTreeMap<FullPackageName, PackageDetails>> {
+        let platform = Platform::host();
         let metadata = |name: &FullPackageName| -> (Option<SystemTime>, Option<u64>) {
-            match std::fs::metadata(self.package_tar(name).as_host_raw()) {
+            match std::fs::metadata(self.package_tar(name, &platform).as_host_raw()) {
                 Ok(metadata) => (metadata.modified().ok(), file_size(&metadata)),
                 Err(_) => (None, None),
             }
         };
 
-        let root_packages = self.scan_packages()?.into_iter().map(
-            |(name, spec)| -> Result<(FullPackageName, PackageDetails)> {
+        let specs = self.scan_packages()?;
+        let dirs: Vec<&HostPath> = specs.values().map(|spec| &spec.dir).collect();
+        let edited = parallel_map(&dirs, |dir| {
+            summarize_dir(dir).ok().map(|summary| summary.last_modified)
+        });
+        let root_packages = specs.into_iter().zip(edited).map(
+            |((name, spec), edited)| -> Result<(FullPackageName, PackageDetails)> {
                 let full_name = FullPackageName(PackageNamespace::Root, name);
                 let (built, size) = metadata(&full_name);
-                let edited = summarize_dir(&spec.dir).ok().map(|s| s.last_modified);
-                let last_build_failed = self.package_build_failed(&full_name)?;
+                let last_build_failed = self.package_build_failed(&full_name, &platform)?;
+                let built_origin = self.built_origin(&full_name, &platform);
                 Ok((
                     full_name,
                     PackageDetails {
@@ -823,6 +1902,7 @@ impl Cubicle {
                             })
                             .collect(),
                         built,
+                        built_origin,
                         depends: spec
                             .manifest
                             .depends
@@ -837,11 +1917,13 @@ impl Cubicle {
                                 )
                             })
                             .collect(),
+                        description: spec.manifest.description,
                         dir: Some(spec.dir.as_host_raw().to_owned()),
                         edited,
                         last_build_failed,
                         package_manager: spec.manifest.package_manager,
                         origin: spec.origin,
+                        shadows: spec.shadows.into_iter().map(|(origin, _)| origin).collect(),
                         size,
                     },
                 ))
@@ -849,23 +1931,27 @@ impl Cubicle {
         );
 
         let non_root_packages = self
-            .package_names_from_tars()?
+            .package_names_from_tars(&platform)?
             .into_iter()
             .filter(|FullPackageName(ns, _name)| ns != &PackageNamespace::Root)
             .map(|name| {
                 let (built, size) = metadata(&name);
-                let last_build_failed = self.package_build_failed(&name)?;
+                let last_build_failed = self.package_build_failed(&name, &platform)?;
+                let built_origin = self.built_origin(&name, &platform);
                 Ok((
                     name,
                     PackageDetails {
                         build_depends: BTreeMap::new(),
                         built,
+                        built_origin,
                         depends: BTreeMap::new(),
+                        description: None,
                         edited: None,
                         dir: None,
                         last_build_failed,
                         package_manager: false,
                         origin: String::from("N/A"),
+                        shadows: Vec::new(),
                         size,
                     },
                 ))
@@ -893,6 +1979,49 @@ impl Cubicle {
                 );
             }
 
+            Csv | Tsv => {
+                let delimited = if format == Csv {
+                    Delimited::Csv
+                } else {
+                    Delimited::Tsv
+                };
+                let packages = self.get_packages()?;
+                println!(
+                    "{}",
+                    delimited.row(&[
+                        "name",
+                        "origin",
+                        "package_manager",
+                        "dir",
+                        "size",
+                        "built",
+                        "edited",
+                        "last_build_failed",
+                        "built_origin",
+                        "shadows",
+                        "description",
+                    ])
+                );
+                for (name, package) in &packages {
+                    println!(
+                        "{}",
+                        delimited.row(&[
+                            name.unquoted(),
+                            package.origin.clone(),
+                            package.package_manager.to_string(),
+                            path_field(package.dir.as_deref()),
+                            package.size.map(|s| s.to_string()).unwrap_or_default(),
+                            time_field(package.built),
+                            time_field(package.edited),
+                            package.last_build_failed.to_string(),
+                            package.built_origin.clone().unwrap_or_default(),
+                            package.shadows.join(" "),
+                            package.description.clone().unwrap_or_default(),
+                        ])
+                    );
+                }
+            }
+
             Default => {
                 let packages = self.get_packages()?;
                 let names: Vec<String> = packages
@@ -907,7 +2036,7 @@ impl Cubicle {
                     .collect();
                 let nw = names.iter().map(|s| s.len()).max().unwrap_or(10);
                 let ow = packages.values().map(|p| p.origin.len()).max().unwrap_or(8);
-                let now = SystemTime::now();
+                let now = self.shared.clock.now();
                 println!(
                     "{:<nw$}  {:<ow$}  {:>10}  {:>13}  {:>13}  {:>8}",
                     "name", "origin", "size", "built", "edited", "status"
@@ -945,6 +2074,168 @@ impl Cubicle {
         Ok(())
     }
 
+    /// Corresponds to `cub package graph`.
+    ///
+    /// Prints the given packages and everything they transitively depend on
+    /// or build-depend on, with the edges between them. With no packages
+    /// given, this covers all packages.
+    pub fn package_graph(
+        &self,
+        packages: &BTreeSet<FullPackageName>,
+        format: PackageGraphFormat,
+    ) -> Result<()> {
+        let specs = self.scan_packages()?;
+        let all;
+        let packages = if packages.is_empty() {
+            all = specs
+                .keys()
+                .map(|name| FullPackageName(PackageNamespace::Root, name.clone()))
+                .collect();
+            &all
+        } else {
+            packages
+        };
+        let graph = plan::dependency_graph(packages, &specs)?;
+        match format {
+            PackageGraphFormat::Dot => print!("{}", graph_dot(&graph)),
+            PackageGraphFormat::Json => println!(
+                "{}",
+                serde_json::to_string_pretty(&graph)
+                    .context("failed to serialize JSON for package graph")?
+            ),
+        }
+        Ok(())
+    }
+
+    /// Prints the packages that are hidden by packages of the same name from
+    /// sources that take precedence, for `cub package list --verbose`.
+    pub fn list_hidden_packages(&self) -> Result<()> {
+        let packages = self.get_packages()?;
+        let hidden: Vec<(String, &String, &String)> = packages
+            .iter()
+            .flat_map(|(name, package)| {
+                package
+                    .shadows
+                    .iter()
+                    .map(move |origin| (name.unquoted(), origin, &package.origin))
+            })
+            .collect();
+        if hidden.is_empty() {
+            return Ok(());
+        }
+        let nw = hidden.iter().map(|(n, _, _)| n.len()).max().unwrap();
+        let ow = hidden.iter().map(|(_, o, _)| o.len()).max().unwrap();
+        println!();
+        println!("Hidden by packages of the same name:");
+        println!("{:<nw$}  {:<ow$}  hidden by", "name", "origin");
+        println!("{0:-<nw$}  {0:-<ow$}  {0:-<9}", "");
+        for (name, origin, winner) in hidden {
+            println!("{name:<nw$}  {origin:<ow$}  {winner}");
+        }
+        Ok(())
+    }
+
+    /// Corresponds to `cub package which`.
+    ///
+    /// Prints which package source defines the package, which other sources
+    /// it hides, and why it takes precedence over them.
+    pub fn which_package(&self, name: &FullPackageName) -> Result<()> {
+        let FullPackageName(PackageNamespace::Root, short_name) = name else {
+            return Err(anyhow!(
+                "{name} isn't defined in a package source: it comes from \
+                the {} namespace",
+                name.0
+            ));
+        };
+        let specs = self.scan_packages()?;
+        let spec = specs
+            .get(short_name)
+            .ok_or_else(|| anyhow!("package {name} not found"))?;
+        println!("{name} comes from {:?}: {}", spec.origin, spec.dir);
+        if spec.shadows.is_empty() {
+            println!("No other package source defines it.");
+            return Ok(());
+        }
+        println!("It hides:");
+        for (origin, dir) in &spec.shadows {
+            println!("  {origin:?}: {dir}");
+        }
+        if self.shared.config.package_precedence.contains(&spec.origin) {
+            println!(
+                "{:?} comes earlier in `package_precedence` in the config file.",
+                spec.origin
+            );
+        } else {
+            println!(
+                "By default, local package directories come before the \
+                built-in packages, and they're searched in name order. Set \
+                `package_precedence` in the config file to change this."
+            );
+        }
+        Ok(())
+    }
+
+    /// Returns the packages and environments that depend on the given
+    /// package, directly or transitively.
+    ///
+    /// Packages count if they depend on or build-depend on it, since they'd
+    /// be rebuilt after it changes. Environments count if their
+    /// `packages.txt` leads to it the same way. Environments without a
+    /// readable `packages.txt`, such as package builders, are skipped.
+    pub fn get_reverse_dependencies(
+        &self,
+        package: &FullPackageName,
+    ) -> Result<ReverseDependencies> {
+        let specs = self.scan_packages()?;
+        if let FullPackageName(PackageNamespace::Root, name) = package {
+            if !specs.contains_key(name) {
+                return Err(anyhow!("package {package} not found"));
+            }
+        }
+        let packages = plan::reverse_depends(package, &specs);
+
+        let mut environments = BTreeSet::new();
+        for name in self.get_environment_names()? {
+            let Ok(mut env_packages) = self.read_package_list_from_env(&name) else {
+                continue;
+            };
+            env_packages.insert(FullPackageName::from_str(special::AUTO_INTERACTIVE).unwrap());
+            let depends = env_packages.contains(package)
+                || env_packages.iter().any(|p| packages.contains(p))
+                || transitive_depends(&env_packages, &specs, BuildDepends(true))
+                    .is_ok_and(|deps| deps.contains(package));
+            if depends {
+                environments.insert(name);
+            }
+        }
+        Ok(ReverseDependencies {
+            packages,
+            environments,
+        })
+    }
+
+    /// Corresponds to `cub package rdeps`.
+    pub fn list_reverse_dependencies(&self, package: &FullPackageName) -> Result<()> {
+        let rdeps = self.get_reverse_dependencies(package)?;
+        if rdeps.packages.is_empty() {
+            println!("No packages depend on {package}.");
+        } else {
+            println!("Packages that depend on {package}:");
+            for name in &rdeps.packages {
+                println!("  {}", name.unquoted());
+            }
+        }
+        if rdeps.environments.is_empty() {
+            println!("No environments depend on {package}.");
+        } else {
+            println!("Environments that depend on {package}:");
+            for name in &rdeps.environments {
+                println!("  {name}");
+            }
+        }
+        Ok(())
+    }
+
     pub(super) fn read_package_list_from_env(
         &self,
         name: &EnvironmentName,
@@ -964,18 +2255,74 @@ impl Cubicle {
         Ok(names)
     }
 
+    /// Warns about packages to be seeded into an environment that may not
+    /// come from the sources the user expects: those whose builds came from
+    /// different sources than they now would, and (as a hint) those that
+    /// hide other packages of the same name.
+    pub(super) fn check_package_sources(
+        &self,
+        packages: &BTreeSet<FullPackageName>,
+        specs: &PackageSpecs,
+        platform: &Platform,
+    ) -> Result<()> {
+        let mut mixed = Vec::new();
+        let mut shadowing = Vec::new();
+        for name in plan::seed_packages(packages, specs)? {
+            let Some(spec) = specs
+                .get(&name.1)
+                .filter(|_| name.0 == PackageNamespace::Root)
+            else {
+                continue;
+            };
+            if let Some(built_origin) = self.built_origin(&name, platform) {
+                if built_origin != spec.origin {
+                    mixed.push(format!(
+                        "{name} (built from {built_origin:?}, now from {:?})",
+                        spec.origin
+                    ));
+                }
+            }
+            if !spec.shadows.is_empty() {
+                shadowing.push(format!(
+                    "{name} from {:?} hides {}",
+                    spec.origin,
+                    spec.shadows
+                        .iter()
+                        .map(|(origin, _)| format!("{origin:?}"))
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                ));
+            }
+        }
+        if !mixed.is_empty() {
+            warn_brief(format!(
+                "Some packages were last built from different sources than \
+                they'd be built from now: {}. Run `cub package update` on \
+                them to rebuild them.",
+                mixed.join("; ")
+            ));
+        }
+        if !shadowing.is_empty() {
+            self.shared.hint(
+                Hint::ShadowedPackages,
+                format!(
+                    "Some packages hide others of the same name: {}.",
+                    shadowing.join("; ")
+                ),
+            );
+        }
+        Ok(())
+    }
+
     pub(super) fn packages_to_seeds(
         &self,
         packages: &BTreeSet<FullPackageName>,
         specs: &PackageSpecs,
+        env_name: &EnvironmentName,
     ) -> Result<Vec<HostPath>> {
         let mut seeds = Vec::with_capacity(packages.len());
-        let deps = transitive_depends(packages, specs, BuildDepends(false))?;
-        for name in deps {
-            let provides = self.package_tar(&name);
-            if try_exists(&provides).todo_context()? {
-                seeds.push(provides);
-            }
+        for name in plan::seed_packages(packages, specs)? {
+            seeds.extend(self.package_seed(&name, specs, env_name)?);
         }
         Ok(seeds)
     }
@@ -1007,7 +2354,7 @@ impl PackageName {
         Ok(Self(s))
     }
 
-    fn strict_from_str(s: &str) -> Result<Self> {
+    pub(crate) fn strict_from_str(s: &str) -> Result<Self> {
         strict_package_name(s, "Cubicle package name")?;
         Self::loose_from_str(s)
     }
@@ -1050,8 +2397,8 @@ impl Display for PackageName {
 pub enum PackageNamespace {
     /// Top-level, normal Cubicle packages live here.
     Root,
-    /// OS-level packages provided by Debian.
-    Debian,
+    /// OS-level packages provided by a Linux distribution, like Debian.
+    Distro(Distro),
     /// A special Cubicle package that acts as a package manager to install
     /// other packages.
     Managed(PackageName),
@@ -1064,7 +2411,7 @@ impl PackageNamespace {
     pub fn as_str(&self) -> &str {
         match self {
             Self::Root => "root",
-            Self::Debian => "debian",
+            Self::Distro(distro) => distro.as_str(),
             Self::Managed(package) => package.as_str(),
         }
     }
@@ -1074,9 +2421,9 @@ impl FromStr for PackageNamespace {
     type Err = Error;
     fn from_str(s: &str) -> Result<Self> {
         strict_package_name(s, "package namespace")?;
-        Ok(match s {
-            "debian" => Self::Debian,
-            _ => Self::Managed(PackageName::strict_from_str(s)?),
+        Ok(match Distro::from_str(s) {
+            Ok(distro) => Self::Distro(distro),
+            Err(_) => Self::Managed(PackageName::strict_from_str(s)?),
         })
     }
 }
@@ -1125,6 +2472,49 @@ impl FullPackageName {
             format!("{}.{}", self.0.as_str(), self.1.as_str())
         }
     }
+
+    /// Returns the package's name within its namespace, without any version
+    /// constraint.
+    pub fn base_name(&self) -> &str {
+        split_version(self.1.as_str()).0
+    }
+
+    /// Returns the version constraint of a package from a package manager.
+    /// This is written after an `@` in the name, like `pypi.django@>=4,<5`,
+    /// in the package manager's own syntax.
+    pub fn version(&self) -> Option<&str> {
+        match self.0 {
+            PackageNamespace::Managed(_) => split_version(self.1.as_str()).1,
+            _ => None,
+        }
+    }
+
+    /// Returns an error if the name has a version constraint where that's
+    /// not supported or the constraint is empty.
+    fn check_version(&self) -> Result<()> {
+        match (&self.0, split_version(self.1.as_str()).1) {
+            (_, None) => Ok(()),
+            (PackageNamespace::Managed(_), Some(version)) => {
+                if version.trim().is_empty() {
+                    return Err(anyhow!("package {self} has an empty version constraint"));
+                }
+                Ok(())
+            }
+            (_, Some(_)) => Err(anyhow!(
+                "version constraints are only supported for packages from \
+                package managers (got {self})"
+            )),
+        }
+    }
+}
+
+/// Splits a package name at the `@` that begins its version constraint, if
+/// any. An `@` at the start, as in npm's `@scope/name`, is part of the name.
+fn split_version(name: &str) -> (&str, Option<&str>) {
+    match name.rfind('@') {
+        Some(i) if i > 0 => (&name[..i], Some(&name[i + 1..])),
+        _ => (name, None),
+    }
 }
 
 impl Display for FullPackageName {
@@ -1145,18 +2535,21 @@ impl Display for FullPackageName {
 impl FromStr for FullPackageName {
     type Err = Error;
     fn from_str(s: &str) -> Result<Self> {
-        Ok(match s.trim().split_once('.') {
+        let name = match s.trim().split_once('.') {
             Some((ns, name)) => Self(
                 PackageNamespace::from_str(ns)?,
                 PackageName::loose_from_str(name)?,
             ),
             None => Self(PackageNamespace::Root, PackageName::strict_from_str(s)?),
-        })
+        };
+        name.check_version()?;
+        Ok(name)
     }
 }
 
 /// Allowed formats for [`Cubicle::list_packages`].
-#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, ValueEnum)]
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
 pub enum ListPackagesFormat {
     /// Human-formatted table.
     #[default]
@@ -1165,67 +2558,221 @@ pub enum ListPackagesFormat {
     Json,
     /// Newline-delimited list of package names only.
     Names,
+    /// Comma-separated values with a header row, for spreadsheets.
+    Csv,
+    /// Tab-separated values with a header row.
+    Tsv,
 }
 
-pub fn write_package_list_tar(
-    packages: &BTreeSet<FullPackageName>,
-) -> Result<tempfile::NamedTempFile> {
-    let file = tempfile::NamedTempFile::new().todo_context()?;
-    let metadata = file.as_file().metadata().todo_context()?;
-    let mut builder = tar::Builder::new(file.as_file());
-    let mut header = tar::Header::new_gnu();
-    #[cfg(unix)]
-    {
-        use std::os::unix::fs::MetadataExt;
-        header.set_mtime(metadata.mtime() as u64);
-        header.set_uid(u64::from(metadata.uid()));
-        header.set_gid(u64::from(metadata.gid()));
-        header.set_mode(metadata.mode());
+/// Allowed formats for [`Cubicle::package_graph`].
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+pub enum PackageGraphFormat {
+    /// Graphviz input, for `dot -Tsvg` and similar. Build-dependencies are
+    /// dashed.
+    #[default]
+    Dot,
+    /// JSON object mapping each package name to its direct `depends` and
+    /// `build_depends`.
+    Json,
+}
+
+/// Formats a [`plan::dependency_graph`] for Graphviz.
+fn graph_dot(graph: &BTreeMap<FullPackageName, plan::GraphNode>) -> String {
+    let quote = |name: &FullPackageName| {
+        format!(
+            "\"{}\"",
+            name.unquoted().replace('\\', "\\\\").replace('"', "\\\"")
+        )
+    };
+    let mut dot = String::from("digraph packages {\n");
+    for (name, node) in graph {
+        dot.push_str(&format!("    {};\n", quote(name)));
+        for dep in &node.depends {
+            dot.push_str(&format!("    {} -> {};\n", quote(name), quote(dep)));
+        }
+        for dep in &node.build_depends {
+            dot.push_str(&format!(
+                "    {} -> {} [style=dashed];\n",
+                quote(name),
+                quote(dep)
+            ));
+        }
     }
+    dot.push_str("}\n");
+    dot
+}
 
-    let mut buf = Vec::new();
+/// Formats the contents of a `packages.txt` file.
+fn package_list(packages: &BTreeSet<FullPackageName>) -> String {
+    let mut buf = String::new();
     for name in packages {
         if name.0 == PackageNamespace::Root && name.1.as_str() == special::AUTO_INTERACTIVE {
             continue;
         }
-        writeln!(buf, "{}", name.unquoted()).todo_context()?;
+        buf.push_str(&name.unquoted());
+        buf.push('\n');
     }
-    header.set_size(buf.len() as u64);
-    builder
-        .append_data(
-            &mut header,
-            Path::new("w").join("packages.txt"),
-            buf.as_slice(),
-        )
-        .todo_context()?;
-    builder
-        .into_inner()
-        .and_then(|mut f| f.flush())
-        .todo_context()?;
-    Ok(file)
+    buf
+}
+
+/// Returns a normalized path relative to the home directory, or `None` if the
+/// path is empty or tries to escape the home directory.
+fn home_relative_path(path: &Path) -> Option<PathBuf> {
+    use std::path::Component;
+    let mut normal = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::Normal(c) => normal.push(c),
+            Component::ParentDir | Component::Prefix(_) | Component::RootDir => return None,
+        }
+    }
+    (!normal.as_os_str().is_empty()).then_some(normal)
+}
+
+pub fn write_package_list_tar(
+    packages: &BTreeSet<FullPackageName>,
+    work_dir_name: &str,
+) -> Result<tempfile::NamedTempFile> {
+    single_file_tar(
+        &Path::new(work_dir_name).join("packages.txt"),
+        package_list(packages).as_bytes(),
+    )
+}
+
+/// Marks a package builder environment as having networking disabled while
+/// this is held. The runners check [`CubicleShared::network_disabled`] when
+/// they start the environment and run commands in it.
+struct OfflineBuilder<'a> {
+    shared: &'a CubicleShared,
+    env_name: EnvironmentName,
+}
+
+impl<'a> OfflineBuilder<'a> {
+    fn new(shared: &'a CubicleShared, env_name: &EnvironmentName) -> Self {
+        shared
+            .offline_builders
+            .lock()
+            .unwrap()
+            .insert(env_name.clone());
+        Self {
+            shared,
+            env_name: env_name.clone(),
+        }
+    }
+}
+
+impl Drop for OfflineBuilder<'_> {
+    fn drop(&mut self) {
+        self.shared
+            .offline_builders
+            .lock()
+            .unwrap()
+            .remove(&self.env_name);
+    }
+}
+
+impl CubicleShared {
+    /// Returns true if the environment must run without networking, because
+    /// it's building a package with
+    /// [`Config::offline_package_builds`](crate::config::Config::offline_package_builds)
+    /// or `network = false` in its manifest.
+    pub(super) fn network_disabled(&self, env_name: &EnvironmentName) -> bool {
+        self.offline_builders.lock().unwrap().contains(env_name)
+    }
+}
+
+/// Returns the environment variables that tell a package manager's scripts
+/// which third-party package to act on. These are empty for other packages.
+fn manager_env_vars(package: &FullPackageName) -> Vec<(&'static str, String)> {
+    match &package.0 {
+        PackageNamespace::Managed(manager) => vec![
+            ("PACKAGE", package.base_name().to_owned()),
+            ("PACKAGE_MANAGER", manager.as_str().to_owned()),
+            (
+                "PACKAGE_VERSION",
+                package.version().unwrap_or_default().to_owned(),
+            ),
+        ],
+        _ => Vec::new(),
+    }
+}
+
+/// Returns the packages that a package depends on or build-depends on.
+fn build_inputs(spec: &PackageSpec) -> BTreeSet<FullPackageName> {
+    spec.manifest
+        .build_depends
+        .iter()
+        .chain(spec.manifest.depends.iter())
+        .flat_map(|(ns, table)| {
+            table
+                .keys()
+                .map(|name| FullPackageName(ns.clone(), name.clone()))
+        })
+        .collect()
 }
 
-fn strict_debian_packages(
+/// OS packages to install in every builder environment of an update run,
+/// by the Docker image that the environments are based on.
+///
+/// With [`crate::config::Docker::strict_debian_packages`], each builder
+/// environment's base image would otherwise get only the OS packages for
+/// its own package, so Docker would rebuild the base image and run
+/// `apt-get install` again for every package built. With the union of those
+/// OS packages, the first build creates the base image and the rest reuse it
+/// from Docker's build cache. This is empty for other configurations,
+/// including with [`crate::config::Docker::isolate_package_builds`].
+type DistroBatch = BTreeMap<String, BTreeSet<PackageName>>;
+
+fn strict_distro_packages(
+    distro: Distro,
     packages: &BTreeSet<FullPackageName>,
     specs: &PackageSpecs,
 ) -> Result<BTreeSet<PackageName>> {
     Ok(transitive_depends(packages, specs, BuildDepends(false))?
         .into_iter()
-        .filter_map(|FullPackageName(ns, name)| (ns == PackageNamespace::Debian).then_some(name))
+        .filter_map(|FullPackageName(ns, name)| {
+            (ns == PackageNamespace::Distro(distro)).then_some(name)
+        })
         .collect())
 }
 
-fn all_debian_packages(specs: &PackageSpecs) -> BTreeSet<PackageName> {
-    let mut debian_packages = BTreeSet::new();
-    for spec in specs.values() {
-        if let Some(debian) = spec.manifest.depends.get(&PackageNamespace::Debian) {
-            debian_packages.extend(debian.keys().cloned());
-        }
-        if let Some(debian) = spec.manifest.build_depends.get(&PackageNamespace::Debian) {
-            debian_packages.extend(debian.keys().cloned());
+fn all_distro_packages(distro: Distro, specs: &PackageSpecs) -> BTreeSet<PackageName> {
+    distro_dependencies(distro, specs)
+        .into_iter()
+        .map(|(_, dep)| dep.clone())
+        .collect()
+}
+
+/// Returns each dependency or build dependency that a package has on an OS
+/// package from the distribution, as pairs of package and OS package.
+pub(super) fn distro_dependencies(
+    distro: Distro,
+    specs: &PackageSpecs,
+) -> Vec<(&PackageName, &PackageName)> {
+    let ns = PackageNamespace::Distro(distro);
+    let mut dependencies = Vec::new();
+    for (name, spec) in specs {
+        for depends in [&spec.manifest.depends, &spec.manifest.build_depends] {
+            if let Some(deps) = depends.get(&ns) {
+                dependencies.extend(deps.keys().map(|dep| (name, dep)));
+            }
         }
     }
-    debian_packages
+    dependencies
+}
+
+/// What depends on a package, as returned by
+/// [`Cubicle::get_reverse_dependencies`].
+#[derive(Debug, Serialize)]
+#[non_exhaustive]
+pub struct ReverseDependencies {
+    /// Packages that depend on or build-depend on the package, directly or
+    /// transitively.
+    pub packages: BTreeSet<FullPackageName>,
+    /// Existing environments whose packages depend on the package.
+    pub environments: BTreeSet<EnvironmentName>,
 }
 
 /// Description of a package as returned by [`Cubicle::get_packages`].
@@ -1238,9 +2785,15 @@ pub struct PackageDetails {
     #[serde(serialize_with = "time_serialize_opt")]
     /// The last time the package was successfully built, if available.
     pub built: Option<SystemTime>,
+    /// The [`origin`](Self::origin) of the package sources that the last
+    /// successful build came from, if recorded. This differs from `origin`
+    /// if the package sources have moved since.
+    pub built_origin: Option<String>,
     /// Map from package namespaces to package names for packages this package
     /// needs at build-time and run-time.
     pub depends: BTreeMap<String, Vec<String>>,
+    /// A short description of the package, from its manifest.
+    pub description: Option<String>,
     #[serde(serialize_with = "time_serialize_opt")]
     /// The last time the package sources were changed (or `UNIX_EPOCH` if
     /// unavailable).
@@ -1258,13 +2811,72 @@ pub struct PackageDetails {
     /// Cubicle, this is `"built-in"`. For local packages, it is the name of
     /// the parent directory above the package source.
     pub origin: String,
+    /// The origins of other package sources with the same name, which are
+    /// ignored in favor of this one.
+    pub shadows: Vec<String>,
     /// The size of the last successful package build output, if available.
     pub size: Option<u64>,
 }
 
+/// Reads the [`BuildHistory`] from the package cache.
+struct HostBuildHistory<'a> {
+    fs: &'a dyn Filesystem,
+    /// Maps package names to their cached build outputs.
+    package_tar: &'a dyn Fn(&FullPackageName) -> HostPath,
+    /// Maps package names to the files holding their recorded build keys.
+    build_key_file: &'a dyn Fn(&FullPackageName) -> HostPath,
+    /// Last modification times by package directory. Like `source_hashes`,
+    /// these are only found for packages that need them.
+    source_modified: RefCell<BTreeMap<PathBuf, SystemTime>>,
+    /// Source hashes by package directory, since all of a package manager's
+    /// packages share its directory.
+    source_hashes: RefCell<BTreeMap<PathBuf, String>>,
+}
+
+impl BuildHistory for HostBuildHistory<'_> {
+    fn last_built(&self, name: &FullPackageName) -> Option<SystemTime> {
+        self.fs.modified(&(self.package_tar)(name))
+    }
+
+    fn source_modified(&self, _name: &FullPackageName, spec: &PackageSpec) -> Result<SystemTime> {
+        let dir = spec.dir.as_host_raw();
+        if let Some(modified) = self.source_modified.borrow().get(dir) {
+            return Ok(*modified);
+        }
+        let modified = self.fs.summarize_dir(&spec.dir)?.last_modified;
+        self.source_modified
+            .borrow_mut()
+            .insert(dir.to_owned(), modified);
+        Ok(modified)
+    }
+
+    fn source_hash(&self, _name: &FullPackageName, spec: &PackageSpec) -> Result<String> {
+        let dir = spec.dir.as_host_raw();
+        if let Some(hash) = self.source_hashes.borrow().get(dir) {
+            return Ok(hash.clone());
+        }
+        let hash = self
+            .fs
+            .hash_dir(&spec.dir)
+            .with_context(|| format!("failed to hash package source files in {}", spec.dir))?;
+        self.source_hashes
+            .borrow_mut()
+            .insert(dir.to_owned(), hash.clone());
+        Ok(hash)
+    }
+
+    fn recorded_key(&self, name: &FullPackageName) -> Option<String> {
+        let key = self.fs.read(&(self.build_key_file)(name))?;
+        Some(key.trim().to_owned())
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::fs_util::DirSummary;
+    use std::cell::RefCell;
+    use std::time::UNIX_EPOCH;
 
     #[test]
     fn full_package_name_ord() {
@@ -1274,4 +2886,592 @@ mod tests {
 
         assert_eq!("b b.a c c.x d", names.map(|name| name.unquoted()).join(" "));
     }
+
+    #[test]
+    fn full_package_name_version() {
+        let name = FullPackageName::from_str("pypi.django@>=4,<5").unwrap();
+        assert_eq!(name.base_name(), "django");
+        assert_eq!(name.version(), Some(">=4,<5"));
+        let name = FullPackageName::from_str("npm.@scope/tool").unwrap();
+        assert_eq!(name.base_name(), "@scope/tool");
+        assert_eq!(name.version(), None);
+
+        assert!(FullPackageName::from_str("pypi.django@").is_err());
+        assert!(FullPackageName::from_str("debian.sl@5").is_err());
+        assert!(FullPackageName::from_str("rust@1").is_err());
+    }
+
+    #[test]
+    fn home_relative_path() {
+        let check = |path: &str| super::home_relative_path(Path::new(path));
+        assert_eq!(check("./bin/foo"), Some(PathBuf::from("bin/foo")));
+        assert_eq!(check(".config/x/"), Some(PathBuf::from(".config/x")));
+        assert_eq!(check("./"), None);
+        assert_eq!(check("../etc/passwd"), None);
+        assert_eq!(check("/etc/passwd"), None);
+    }
+
+    #[test]
+    fn staleness() {
+        struct FakeFilesystem {
+            modified: RefCell<BTreeMap<PathBuf, SystemTime>>,
+            hashes: RefCell<BTreeMap<PathBuf, String>>,
+            files: RefCell<BTreeMap<PathBuf, String>>,
+        }
+
+        impl Filesystem for FakeFilesystem {
+            fn modified(&self, path: &HostPath) -> Option<SystemTime> {
+                self.modified.borrow().get(path.as_host_raw()).copied()
+            }
+
+            fn summarize_dir(&self, path: &HostPath) -> Result<DirSummary> {
+                Ok(DirSummary {
+                    errors: false,
+                    total_size: 0,
+                    last_modified: self.modified(path).unwrap_or(UNIX_EPOCH),
+                })
+            }
+
+            fn hash_dir(&self, path: &HostPath) -> Result<String> {
+                Ok(self.hashes.borrow()[path.as_host_raw()].clone())
+            }
+
+            fn read(&self, path: &HostPath) -> Option<String> {
+                self.files.borrow().get(path.as_host_raw()).cloned()
+            }
+        }
+
+        let time = |hours: u64| UNIX_EPOCH + Duration::from_secs(hours * 60 * 60);
+        let fs = FakeFilesystem {
+            modified: RefCell::new(BTreeMap::from([
+                (PathBuf::from("/packages/rust"), time(1)),
+                (PathBuf::from("/cache/rust.tar"), time(2)),
+                (PathBuf::from("/cache/dep.tar"), time(2)),
+            ])),
+            hashes: RefCell::new(BTreeMap::from([(
+                PathBuf::from("/packages/rust"),
+                String::from("abc"),
+            )])),
+            files: RefCell::default(),
+        };
+        let package_tar = |name: &FullPackageName| {
+            HostPath::try_from(PathBuf::from(format!("/cache/{}.tar", name.unquoted()))).unwrap()
+        };
+        let dep = FullPackageName::from_str("dep").unwrap();
+        let spec = PackageSpec {
+            manifest: Manifest {
+                description: None,
+                package_manager: false,
+                targets: None,
+                depends: BTreeMap::from([(
+                    dep.0.clone(),
+                    BTreeMap::from([(dep.1.clone(), Dependency {})]),
+                )]),
+                build_depends: BTreeMap::new(),
+                removes: Vec::new(),
+                shared_paths: Vec::new(),
+                profile_dirs: Vec::new(),
+                version: None,
+                network: None,
+            },
+            dir: HostPath::try_from(PathBuf::from("/packages/rust")).unwrap(),
+            origin: String::from("test"),
+            shadows: Vec::new(),
+            update: None,
+            test: None,
+            remove: None,
+        };
+        let rust = FullPackageName::from_str("rust").unwrap();
+        let build_key_file = |name: &FullPackageName| {
+            HostPath::try_from(PathBuf::from(format!("/cache/{}.key", name.unquoted()))).unwrap()
+        };
+        let history = HostBuildHistory {
+            fs: &fs,
+            package_tar: &package_tar,
+            build_key_file: &build_key_file,
+            source_modified: RefCell::default(),
+            source_hashes: RefCell::default(),
+        };
+        let planner = |auto_update: Option<u64>, now: u64| Planner {
+            history: &history,
+            auto_update: auto_update.map(|hours| Duration::from_secs(hours * 60 * 60)),
+            now: time(now),
+        };
+        let is_stale = |auto_update: Option<u64>, now: u64| {
+            planner(auto_update, now).is_stale(&rust, &spec).unwrap()
+        };
+
+        assert!(!is_stale(None, 100));
+        assert!(!is_stale(Some(10), 12));
+        assert!(is_stale(Some(10), 13));
+        // The clock went backwards.
+        assert!(is_stale(Some(10), 1));
+
+        let set = |path: &str, hours: u64| {
+            fs.modified
+                .borrow_mut()
+                .insert(PathBuf::from(path), time(hours));
+            // Source modification times are cached for the whole update run.
+            history.source_modified.borrow_mut().clear();
+        };
+        set("/cache/dep.tar", 3);
+        assert!(is_stale(None, 100));
+        set("/cache/rust.tar", 3);
+        assert!(!is_stale(None, 100));
+        set("/packages/rust", 4);
+        assert!(is_stale(None, 100));
+        fs.modified
+            .borrow_mut()
+            .remove(Path::new("/cache/rust.tar"));
+        assert!(is_stale(None, 100));
+
+        // Once a build key is recorded, modification times of the source
+        // files no longer matter, only their contents.
+        set("/cache/rust.tar", 3);
+        let key = planner(None, 100).build_key(&rust, &spec).unwrap();
+        let record = |path: &str, contents: &str| {
+            fs.files
+                .borrow_mut()
+                .insert(PathBuf::from(path), format!("{contents}\n"));
+        };
+        record("/cache/rust.key", &key);
+        set("/packages/rust", 5);
+        assert!(!is_stale(None, 100));
+        record("/cache/dep.key", "new");
+        assert!(is_stale(None, 100));
+        record(
+            "/cache/rust.key",
+            &planner(None, 100).build_key(&rust, &spec).unwrap(),
+        );
+        assert!(!is_stale(None, 100));
+        fs.hashes
+            .borrow_mut()
+            .insert(PathBuf::from("/packages/rust"), String::from("def"));
+        // Source hashes are cached for the whole update run.
+        assert!(!is_stale(None, 100));
+        history.source_hashes.borrow_mut().clear();
+        assert!(is_stale(None, 100));
+    }
+
+    #[test]
+    fn graph_dot() {
+        let graph = BTreeMap::from([
+            (
+                FullPackageName::from_str("app").unwrap(),
+                plan::GraphNode {
+                    depends: BTreeSet::from([FullPackageName::from_str("npm.left-pad").unwrap()]),
+                    build_depends: BTreeSet::from([FullPackageName::from_str("gcc").unwrap()]),
+                },
+            ),
+            (
+                FullPackageName::from_str("gcc").unwrap(),
+                plan::GraphNode::default(),
+            ),
+        ]);
+        assert_eq!(
+            super::graph_dot(&graph),
+            "digraph packages {\n    \"app\";\n    \"app\" -> \"npm.left-pad\";\n    \
+            \"app\" -> \"gcc\" [style=dashed];\n    \"gcc\";\n}\n"
+        );
+    }
+
+    #[test]
+    fn describe_estimates() {
+        let planned = [
+            ("rust", Some(Duration::from_secs(370))),
+            ("firefox", Some(Duration::from_secs(700))),
+        ]
+        .map(|(name, estimate)| (FullPackageName::from_str(name).unwrap(), estimate));
+        assert_eq!(
+            "rust: ~6 min, firefox: ~12 min, total ~18 min",
+            super::describe_estimates(&planned)
+        );
+
+        let planned = [("a", Some(Duration::from_secs(20))), ("b", None)]
+            .map(|(name, estimate)| (FullPackageName::from_str(name).unwrap(), estimate));
+        assert_eq!(
+            "a: ~20 sec, b: unknown, total ~20 sec or more",
+            super::describe_estimates(&planned)
+        );
+
+        let describe = |planned: &[(&str, Option<u64>)]| {
+            super::describe_estimates(
+                &planned
+                    .iter()
+                    .map(|(name, secs)| {
+                        (
+                            FullPackageName::from_str(name).unwrap(),
+                            secs.map(Duration::from_secs),
+                        )
+                    })
+                    .collect::<Vec<_>>(),
+            )
+        };
+        // A single package gets no total.
+        assert_eq!(describe(&[("a", Some(20))]), "a: ~20 sec");
+        assert_eq!(describe(&[("a", None)]), "a: unknown");
+        assert_eq!(describe(&[]), "");
+        // Instant builds count as known.
+        assert_eq!(
+            describe(&[("a", Some(0)), ("b", Some(0))]),
+            "a: ~0 sec, b: ~0 sec, total ~0 sec"
+        );
+        assert_eq!(
+            describe(&[("a", Some(0)), ("b", None)]),
+            "a: ~0 sec, b: unknown, total ~0 sec or more"
+        );
+        // With nothing known to add up, the total is unknown.
+        assert_eq!(
+            describe(&[("a", None), ("b", None)]),
+            "a: unknown, b: unknown, total unknown"
+        );
+    }
+
+    #[test]
+    fn approx_duration() {
+        let approx = |secs: f64| super::approx_duration(Duration::from_secs_f64(secs));
+        assert_eq!(approx(0.0), "~0 sec");
+        assert_eq!(approx(0.4), "~0 sec");
+        assert_eq!(approx(0.6), "~1 sec");
+        assert_eq!(approx(59.4), "~59 sec");
+        // This would round up to 60 seconds, so it's shown as a minute.
+        assert_eq!(approx(59.5), "~1 min");
+        assert_eq!(approx(60.0), "~1 min");
+        assert_eq!(approx(89.0), "~1 min");
+        assert_eq!(approx(91.0), "~2 min");
+        assert_eq!(approx(3600.0), "~60 min");
+    }
+
+    #[test]
+    fn package_sources() {
+        use crate::testing;
+
+        let root = tempfile::tempdir().unwrap();
+        let cub = testing::cubicle(root.path(), "");
+        let default = FullPackageName::from_str(special::DEFAULT).unwrap();
+        let details = |cub: &Cubicle| cub.get_packages().unwrap().remove(&default).unwrap();
+        // Only packages with build scripts get built.
+        let add_build_script = |dir: &HostPath| {
+            std::fs::write(dir.join("build.sh").as_host_raw(), "").unwrap();
+        };
+        add_build_script(&cub.shared.code_package_dir.join(special::DEFAULT));
+        let update = |cub: &Cubicle| {
+            cub.update_packages(
+                &BTreeSet::from([default.clone()]),
+                &cub.scan_packages().unwrap(),
+                &UpdatePackagesConditions {
+                    dependencies: ShouldPackageUpdate::IfRequired,
+                    named: ShouldPackageUpdate::Always,
+                },
+                &Platform::host(),
+            )
+            .unwrap();
+        };
+
+        update(&cub);
+        let built_in = details(&cub);
+        assert_eq!(built_in.origin, "built-in");
+        assert_eq!(built_in.built_origin.as_deref(), Some("built-in"));
+        assert!(built_in.shadows.is_empty());
+
+        // A local package of the same name hides the built-in one, but the
+        // cached build still came from the built-in one.
+        let local = cub
+            .shared
+            .user_package_dir
+            .join("local")
+            .join(special::DEFAULT);
+        std::fs::create_dir_all(local.as_host_raw()).unwrap();
+        std::fs::write(local.join("package.toml").as_host_raw(), "").unwrap();
+        add_build_script(&local);
+        let shadowing = details(&cub);
+        assert_eq!(shadowing.origin, "local");
+        assert_eq!(shadowing.built_origin.as_deref(), Some("built-in"));
+        assert_eq!(shadowing.shadows, vec![String::from("built-in")]);
+
+        update(&cub);
+        assert_eq!(details(&cub).built_origin.as_deref(), Some("local"));
+    }
+
+    #[test]
+    fn scan_packages_cache() {
+        use crate::testing;
+
+        let root = tempfile::tempdir().unwrap();
+        let cub = testing::cubicle(root.path(), "");
+        let name = PackageName::strict_from_str("tool").unwrap();
+        let description = |cub: &Cubicle| {
+            cub.scan_packages()
+                .unwrap()
+                .get(&name)
+                .map(|spec| spec.manifest.description.clone())
+        };
+        assert_eq!(description(&cub), None);
+
+        // New packages and manifest changes are picked up.
+        let dir = cub.shared.user_package_dir.join("local").join("tool");
+        std::fs::create_dir_all(dir.as_host_raw()).unwrap();
+        std::fs::write(dir.join("package.toml").as_host_raw(), "").unwrap();
+        assert_eq!(description(&cub), Some(None));
+        std::fs::write(
+            dir.join("package.toml").as_host_raw(),
+            "description = 'Does things'",
+        )
+        .unwrap();
+        assert_eq!(description(&cub), Some(Some(String::from("Does things"))));
+        assert_eq!(description(&cub), Some(Some(String::from("Does things"))));
+
+        crate::fs_util::rmtree(&dir).unwrap();
+        assert_eq!(description(&cub), None);
+    }
+
+    #[test]
+    fn package_locks() {
+        use crate::os_util::host_id;
+        use crate::testing;
+
+        let root = tempfile::tempdir().unwrap();
+        let cub = testing::cubicle(root.path(), "");
+        let default = FullPackageName::from_str(special::DEFAULT).unwrap();
+        let platform = Platform::host();
+        assert!(!cub.package_builds_in_progress().unwrap());
+
+        let lock = cub.lock_package(&default, &platform).unwrap();
+        assert_eq!(
+            cub.package_locks().unwrap()[0].as_host_raw(),
+            cub.package_lock(&default, &platform).as_host_raw()
+        );
+        assert!(cub.package_builds_in_progress().unwrap());
+        drop(lock);
+        assert!(!cub.package_builds_in_progress().unwrap());
+
+        // Locks from processes that crashed don't count. PIDs are limited
+        // to 2^22 on Linux.
+        let path = cub.package_lock(&default, &platform);
+        std::fs::write(path.as_host_raw(), format!("{} 99999999\n", host_id())).unwrap();
+        assert!(!cub.package_builds_in_progress().unwrap());
+        assert!(!path.as_host_raw().exists());
+    }
+
+    #[test]
+    fn test_packages() {
+        use crate::testing;
+
+        let root = tempfile::tempdir().unwrap();
+        let cub = testing::cubicle(root.path(), "");
+        let default = FullPackageName::from_str(special::DEFAULT).unwrap();
+        let dir = cub.shared.code_package_dir.join(special::DEFAULT);
+        std::fs::write(dir.join("build.sh").as_host_raw(), "").unwrap();
+        std::fs::write(dir.join("test.sh").as_host_raw(), "").unwrap();
+        let packages = BTreeSet::from([default.clone()]);
+        let platform = Platform::host();
+
+        // Testing doesn't build the named packages.
+        assert!(cub.test_packages(&packages, &platform).is_err());
+        assert!(!try_exists(&cub.package_tar(&default, &platform)).unwrap());
+        assert!(cub.package_logs(&default, &platform).is_err());
+
+        let specs = cub.scan_packages().unwrap();
+        cub.update_packages(
+            &packages,
+            &specs,
+            &UpdatePackagesConditions {
+                dependencies: ShouldPackageUpdate::IfRequired,
+                named: ShouldPackageUpdate::IfRequired,
+            },
+            &platform,
+        )
+        .unwrap();
+        let built = std::fs::metadata(cub.package_tar(&default, &platform).as_host_raw())
+            .unwrap()
+            .modified()
+            .unwrap();
+        assert!(try_exists(&cub.build_log(&default, &platform)).unwrap());
+        cub.test_packages(&packages, &platform).unwrap();
+        assert_eq!(
+            std::fs::metadata(cub.package_tar(&default, &platform).as_host_raw())
+                .unwrap()
+                .modified()
+                .unwrap(),
+            built
+        );
+    }
+
+    #[test]
+    fn manager_env_vars() {
+        let vars = |name: &str| super::manager_env_vars(&FullPackageName::from_str(name).unwrap());
+        assert_eq!(
+            vars("pypi.requests"),
+            [
+                ("PACKAGE", String::from("requests")),
+                ("PACKAGE_MANAGER", String::from("pypi")),
+                ("PACKAGE_VERSION", String::new()),
+            ]
+        );
+        assert_eq!(
+            vars("npm.@scope/tool@^2"),
+            [
+                ("PACKAGE", String::from("@scope/tool")),
+                ("PACKAGE_MANAGER", String::from("npm")),
+                ("PACKAGE_VERSION", String::from("^2")),
+            ]
+        );
+        assert_eq!(vars("rust"), []);
+        assert_eq!(vars("debian.sl"), []);
+    }
+
+    #[test]
+    fn verify_package_tar() {
+        use crate::testing;
+
+        let root = tempfile::tempdir().unwrap();
+        let cub = testing::cubicle(root.path(), "");
+        let default = FullPackageName::from_str(special::DEFAULT).unwrap();
+        let dir = cub.shared.code_package_dir.join(special::DEFAULT);
+        std::fs::write(dir.join("build.sh").as_host_raw(), "").unwrap();
+        let platform = Platform::host();
+        let specs = cub.scan_packages().unwrap();
+        cub.update_packages(
+            &BTreeSet::from([default.clone()]),
+            &specs,
+            &UpdatePackagesConditions {
+                dependencies: ShouldPackageUpdate::IfRequired,
+                named: ShouldPackageUpdate::IfRequired,
+            },
+            &platform,
+        )
+        .unwrap();
+        cub.verify_package_tar(&default, &platform).unwrap();
+
+        let tar = cub.package_tar(&default, &platform);
+        let original = std::fs::read(tar.as_host_raw()).unwrap();
+        let modified = std::fs::metadata(tar.as_host_raw())
+            .unwrap()
+            .modified()
+            .unwrap();
+        std::fs::write(tar.as_host_raw(), "tampered").unwrap();
+        assert!(cub.verify_package_tar(&default, &platform).is_err());
+
+        // A build with the size and modification time it had when it was
+        // last verified isn't hashed again.
+        let mut same_size = vec![0; original.len()];
+        same_size[..8].copy_from_slice(b"tampered");
+        std::fs::write(tar.as_host_raw(), same_size).unwrap();
+        std::fs::File::options()
+            .write(true)
+            .open(tar.as_host_raw())
+            .unwrap()
+            .set_modified(modified)
+            .unwrap();
+        cub.verify_package_tar(&default, &platform).unwrap();
+        std::fs::remove_file(cub.build_verified_file(&default, &platform).as_host_raw()).unwrap();
+        assert!(cub.verify_package_tar(&default, &platform).is_err());
+
+        // Builds from before hashes were recorded can't be checked.
+        std::fs::remove_file(cub.build_checksum_file(&default, &platform).as_host_raw()).unwrap();
+        cub.verify_package_tar(&default, &platform).unwrap();
+    }
+
+    #[test]
+    fn offline_builder() {
+        use crate::testing;
+
+        let shared = CubicleShared::for_tests(testing::config(""));
+        let env = EnvironmentName::from_str("package-rust").unwrap();
+        assert!(!shared.network_disabled(&env));
+        let offline = OfflineBuilder::new(&shared, &env);
+        assert!(shared.network_disabled(&env));
+        assert!(!shared.network_disabled(&EnvironmentName::from_str("package-go").unwrap()));
+        drop(offline);
+        assert!(!shared.network_disabled(&env));
+    }
+
+    #[test]
+    fn get_package_providers() {
+        use crate::testing;
+
+        let root = tempfile::tempdir().unwrap();
+        let cub = testing::cubicle(root.path(), "");
+        let platform = Platform::host();
+        let cache = cub.package_cache(&platform);
+        std::fs::create_dir_all(cache.as_host_raw()).unwrap();
+        let write_tar = |name: &str, paths: &[&str]| {
+            let file = File::create(
+                cub.package_tar(&FullPackageName::from_str(name).unwrap(), &platform)
+                    .as_host_raw(),
+            )
+            .unwrap();
+            let mut builder = tar::Builder::new(file);
+            for path in paths {
+                let mut header = tar::Header::new_gnu();
+                header.set_size(0);
+                header.set_mode(0o644);
+                builder.append_data(&mut header, path, io::empty()).unwrap();
+            }
+            builder.finish().unwrap();
+        };
+        write_tar("vim", &[".vimrc", ".config/nvim/init.lua"]);
+        write_tar("dotfiles", &[".bashrc", ".vimrc"]);
+
+        let providers = |path: &str| {
+            cub.get_package_providers(Path::new(path), &platform)
+                .unwrap()
+                .into_iter()
+                .map(|(package, paths)| {
+                    let paths = paths
+                        .iter()
+                        .map(|path| path.display().to_string())
+                        .collect::<Vec<_>>()
+                        .join(",");
+                    format!("{}:{paths}", package.unquoted())
+                })
+                .collect::<Vec<_>>()
+                .join(" ")
+        };
+        assert_eq!(providers("~/.vimrc"), "dotfiles:.vimrc vim:.vimrc");
+        assert_eq!(providers(".config"), "vim:.config/nvim/init.lua");
+        assert_eq!(
+            providers(
+                &cub.shared
+                    .home
+                    .join(".bashrc")
+                    .as_host_raw()
+                    .display()
+                    .to_string()
+            ),
+            "dotfiles:.bashrc"
+        );
+        assert_eq!(providers(".profile"), "");
+        assert!(cub
+            .get_package_providers(Path::new("../x"), &platform)
+            .is_err());
+    }
+
+    #[test]
+    fn package_precedence() {
+        use crate::testing;
+
+        let origins = |precedence: &str| {
+            let root = tempfile::tempdir().unwrap();
+            let cub = testing::cubicle(root.path(), &format!("package_precedence = {precedence}"));
+            for origin in ["a", "b"] {
+                let dir = cub
+                    .shared
+                    .user_package_dir
+                    .join(origin)
+                    .join(special::DEFAULT);
+                std::fs::create_dir_all(dir.as_host_raw()).unwrap();
+                std::fs::write(dir.join("package.toml").as_host_raw(), "").unwrap();
+            }
+            let specs = cub.scan_packages().unwrap();
+            let spec = &specs[&PackageName::strict_from_str(special::DEFAULT).unwrap()];
+            let mut origins = vec![spec.origin.clone()];
+            origins.extend(spec.shadows.iter().map(|(origin, _)| origin.clone()));
+            origins.join(" ")
+        };
+        assert_eq!(origins("[]"), "a b built-in");
+        assert_eq!(origins("['built-in']"), "built-in a b");
+        assert_eq!(origins("['b', 'built-in']"), "b built-in a");
+        assert_eq!(origins("['unknown']"), "a b built-in");
+    }
 }
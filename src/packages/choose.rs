@@ -0,0 +1,267 @@
+//! Asks the user on the terminal which packages a new environment should
+//! have, for `cub new --interactive`.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::{self, BufRead, IsTerminal, Write};
+
+use super::{
+    special, Bytes, Cubicle, EnvironmentName, FullPackageName, PackageDetails, PackageNamespace,
+};
+use crate::somehow::{somehow as anyhow, Context, Result};
+
+/// A package that can be chosen, with what's shown about it.
+#[derive(Debug)]
+struct Choice {
+    name: FullPackageName,
+    size: Option<u64>,
+    description: Option<String>,
+}
+
+impl Cubicle {
+    /// Corresponds to `cub new --interactive`.
+    ///
+    /// Lists the packages that make sense to add to an environment directly,
+    /// with their sizes and descriptions, and lets the user select some of
+    /// them. The packages that the "default" package depends on are
+    /// selected to start with. Returns an error if stdin isn't a terminal
+    /// or the user gives up.
+    pub fn choose_packages(&self, name: &EnvironmentName) -> Result<BTreeSet<FullPackageName>> {
+        if !io::stdin().is_terminal() {
+            return Err(anyhow!(
+                "can't choose packages interactively, since stdin is not a terminal \
+                (use `--packages` instead)"
+            ));
+        }
+        let packages = self.get_packages()?;
+        let (choices, selected) = choices(&packages);
+        let selected = choose(
+            &format!("Packages for new environment {name}:"),
+            &choices,
+            selected,
+            &mut io::stdin().lock(),
+            &mut io::stdout(),
+        )?;
+        Ok(choices
+            .into_iter()
+            .zip(selected)
+            .filter_map(|(choice, selected)| selected.then_some(choice.name))
+            .collect())
+    }
+}
+
+/// Returns the packages to offer and which of them start out selected.
+///
+/// This leaves out package managers, whose packages are chosen by name, and
+/// the special packages, along with the packages that every environment gets
+/// through them.
+fn choices(packages: &BTreeMap<FullPackageName, PackageDetails>) -> (Vec<Choice>, Vec<bool>) {
+    let root_depends = |name: &str| -> Vec<&str> {
+        packages
+            .iter()
+            .find(|(full_name, _)| {
+                full_name.0 == PackageNamespace::Root && full_name.1.as_str() == name
+            })
+            .and_then(|(_, details)| details.depends.get(PackageNamespace::Root.as_str()))
+            .map(|names| names.iter().map(|name| name.as_str()).collect())
+            .unwrap_or_default()
+    };
+    let defaults: BTreeSet<&str> = root_depends(special::DEFAULT).into_iter().collect();
+    let mut hidden = BTreeSet::from([special::AUTO_BATCH, special::DEFAULT]);
+    let mut queue = vec![special::AUTO_INTERACTIVE];
+    while let Some(name) = queue.pop() {
+        if hidden.insert(name) {
+            queue.extend(root_depends(name));
+        }
+    }
+    let choices: Vec<Choice> = packages
+        .iter()
+        .filter(|(name, details)| {
+            name.0 == PackageNamespace::Root
+                && !details.package_manager
+                && !hidden.contains(name.1.as_str())
+        })
+        .map(|(name, details)| Choice {
+            name: name.clone(),
+            size: details.size,
+            description: details.description.clone(),
+        })
+        .collect();
+    let selected = choices
+        .iter()
+        .map(|choice| defaults.contains(choice.name.1.as_str()))
+        .collect();
+    (choices, selected)
+}
+
+/// Shows the choices on `output` and reads changes to the selection from
+/// `input` until the user accepts it with an empty line.
+fn choose(
+    title: &str,
+    choices: &[Choice],
+    mut selected: Vec<bool>,
+    input: &mut dyn BufRead,
+    output: &mut dyn Write,
+) -> Result<Vec<bool>> {
+    let names: Vec<String> = choices.iter().map(|c| c.name.unquoted()).collect();
+    let nw = names.iter().map(|name| name.len()).max().unwrap_or(0);
+    let iw = choices.len().to_string().len();
+    let mut print = |text: String| -> Result<()> {
+        output
+            .write_all(text.as_bytes())
+            .and_then(|()| output.flush())
+            .context("failed to write to stdout")
+    };
+    loop {
+        let mut text = format!("{title}\n");
+        for (i, choice) in choices.iter().enumerate() {
+            let size = match choice.size {
+                Some(size) => Bytes(size).to_string(),
+                None => String::from("not built"),
+            };
+            let line = format!(
+                "  {:>iw$} [{}] {:<nw$}  {:>9}  {}",
+                i + 1,
+                if selected[i] { 'x' } else { ' ' },
+                names[i],
+                size,
+                choice.description.as_deref().unwrap_or(""),
+            );
+            text.push_str(line.trim_end());
+            text.push('\n');
+        }
+        text.push_str(
+            "Toggle packages by number, range (like 2-4), or name, or enter \"all\" \
+            or \"none\". Press Enter when done: ",
+        );
+        print(text)?;
+
+        let mut line = String::new();
+        if input
+            .read_line(&mut line)
+            .context("failed to read answer from stdin")?
+            == 0
+        {
+            return Err(anyhow!("no packages chosen"));
+        }
+        if line.trim().is_empty() {
+            return Ok(selected);
+        }
+        match parse_selection(&line, &names) {
+            Ok(Selection::All) => selected.fill(true),
+            Ok(Selection::None) => selected.fill(false),
+            Ok(Selection::Toggle(indexes)) => {
+                for i in indexes {
+                    selected[i] = !selected[i];
+                }
+            }
+            Err(e) => print(format!("{e}\n"))?,
+        }
+        print(String::from("\n"))?;
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum Selection {
+    All,
+    None,
+    /// Indexes into the choices.
+    Toggle(BTreeSet<usize>),
+}
+
+/// Parses a line of input to [`choose`], where `names` are the choices'
+/// names.
+fn parse_selection(line: &str, names: &[String]) -> Result<Selection> {
+    let line = line.trim();
+    match line {
+        "all" => return Ok(Selection::All),
+        "none" => return Ok(Selection::None),
+        _ => {}
+    }
+    let number = |s: &str| -> Result<usize> {
+        match s.parse::<usize>() {
+            Ok(n) if (1..=names.len()).contains(&n) => Ok(n - 1),
+            _ => Err(anyhow!(
+                "{s:?} is not a package name or a number from 1 to {}",
+                names.len()
+            )),
+        }
+    };
+    let mut toggle = BTreeSet::new();
+    for word in line.split(|c: char| c == ',' || c.is_whitespace()) {
+        if word.is_empty() {
+            continue;
+        }
+        if let Some(i) = names.iter().position(|name| name == word) {
+            toggle.insert(i);
+        } else if let Some((start, end)) = word.split_once('-') {
+            let (start, end) = (number(start)?, number(end)?);
+            if start > end {
+                return Err(anyhow!("range {word:?} is backwards"));
+            }
+            toggle.extend(start..=end);
+        } else {
+            toggle.insert(number(word)?);
+        }
+    }
+    Ok(Selection::Toggle(toggle))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    #[test]
+    fn parse_selection() {
+        let names = ["firefox", "go", "node", "rust", "zsh"].map(String::from);
+        let parse = |line: &str| super::parse_selection(line, &names);
+        let toggle = |indexes: &[usize]| Selection::Toggle(indexes.iter().copied().collect());
+        assert_eq!(parse("all\n").unwrap(), Selection::All);
+        assert_eq!(parse(" none ").unwrap(), Selection::None);
+        assert_eq!(parse("1 3").unwrap(), toggle(&[0, 2]));
+        assert_eq!(parse("2-4, zsh").unwrap(), toggle(&[1, 2, 3, 4]));
+        assert!(parse("0").is_err());
+        assert!(parse("6").is_err());
+        assert!(parse("4-2").is_err());
+        assert!(parse("python").is_err());
+    }
+
+    #[test]
+    fn choose() {
+        let choices: Vec<Choice> = [("go", Some(2_500_000), Some("Go")), ("rust", None, None)]
+            .into_iter()
+            .map(|(name, size, description)| Choice {
+                name: FullPackageName::from_str(name).unwrap(),
+                size,
+                description: description.map(String::from),
+            })
+            .collect();
+        let mut output = Vec::new();
+        let selected = super::choose(
+            "Packages:",
+            &choices,
+            vec![true, false],
+            &mut "2\nbogus\n\n".as_bytes(),
+            &mut output,
+        )
+        .unwrap();
+        assert_eq!(selected, vec![true, true]);
+        let output = String::from_utf8(output).unwrap();
+        assert!(output.contains("  1 [x] go       2.5 MB  Go\n"), "{output}");
+        assert!(output.contains("  2 [x] rust  not built\n"), "{output}");
+        assert!(
+            output.contains("\"bogus\" is not a package name"),
+            "{output}"
+        );
+
+        // Giving up on the input is an error rather than accepting.
+        assert!(super::choose(
+            "Packages:",
+            &choices,
+            vec![true, false],
+            &mut io::empty(),
+            &mut Vec::new()
+        )
+        .is_err());
+    }
+}
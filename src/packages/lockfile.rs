@@ -0,0 +1,301 @@
+//! Pins the exact package builds that an environment uses.
+//!
+//! Package builds are normally replaced whenever they become stale, so
+//! resetting an environment later gives it whatever is fresh at that time.
+//! `cub package lock` records the content hash of every package build that
+//! seeds an environment, along with the package's version if its manifest
+//! declares one, in a `packages.lock` file next to `packages.txt` in the
+//! work directory. It also keeps those builds in the package cache by hash,
+//! so that `cub reset --locked` can seed the environment with exactly the
+//! same files again.
+
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::Path;
+use std::str::FromStr;
+
+use super::{
+    plan, special, FullPackageName, PackageNamespace, PackageSpecs, ShouldPackageUpdate,
+    UpdatePackagesConditions,
+};
+use crate::fs_util::{hash_file, single_file_tar, try_exists};
+use crate::runner::{EnvironmentExists, Runner};
+use crate::somehow::{somehow as anyhow, Context, Result};
+use crate::{Cubicle, EnvironmentName, HostPath, Platform};
+
+/// The name of the lock file in the environment's work directory.
+const LOCK_FILE: &str = "packages.lock";
+
+/// The contents of a `packages.lock` file.
+#[derive(Debug, Default, Deserialize, PartialEq, Serialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct PackageLock {
+    /// Every package that seeds the environment, keyed by its unquoted
+    /// name.
+    #[serde(default)]
+    packages: BTreeMap<String, LockedPackage>,
+}
+
+#[derive(Debug, Deserialize, PartialEq, Serialize)]
+#[serde(deny_unknown_fields)]
+struct LockedPackage {
+    /// The SHA-256 hash of the package build's tar file, in hex.
+    sha256: String,
+    /// The package's version, from its manifest.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    version: Option<String>,
+}
+
+impl Cubicle {
+    /// Corresponds to `cub package lock`.
+    ///
+    /// Builds any of the environment's packages that have never been built,
+    /// then records the current builds of all the packages that seed the
+    /// environment in its `packages.lock`, replacing any earlier lock.
+    pub fn lock_packages(&self, name: &EnvironmentName) -> Result<()> {
+        let _lock = self.lock_environment(name)?;
+        if self.runner.exists(name)? != EnvironmentExists::FullyExists {
+            return Err(anyhow!(
+                "Environment {name} does not exist or is in a broken state"
+            ));
+        }
+        let mut packages = self
+            .read_package_list_from_env(name)
+            .with_context(|| format!("failed to parse `packages.txt` from {name}"))?;
+        packages.insert(FullPackageName::from_str(special::AUTO_INTERACTIVE).unwrap());
+        let specs = self.scan_packages()?;
+        let platform = self.env_platform(name)?;
+        self.update_packages(
+            &packages,
+            &specs,
+            &UpdatePackagesConditions {
+                dependencies: ShouldPackageUpdate::IfRequired,
+                named: ShouldPackageUpdate::IfRequired,
+            },
+            &platform,
+        )?;
+
+        let mut lock = PackageLock::default();
+        for package in plan::seed_packages(&packages, &specs)? {
+            let tar = self.package_tar(&package, &platform);
+            if !try_exists(&tar).with_context(|| format!("failed to check for {tar}"))? {
+                // Some packages, like "auto", have nothing to build.
+                continue;
+            }
+            let sha256 = hash_file(&tar)?;
+            self.keep_locked_build(&tar, &sha256, &platform)?;
+            let version = match &package.0 {
+                PackageNamespace::Root => specs
+                    .get(&package.1)
+                    .and_then(|spec| spec.manifest.version.clone()),
+                _ => None,
+            };
+            lock.packages
+                .insert(package.unquoted(), LockedPackage { sha256, version });
+        }
+
+        let contents = format!(
+            "# Written by `{} package lock`. Use `{} reset --locked` to reset\n\
+            # the environment with exactly these package builds.\n\n{}",
+            self.shared.exe_name,
+            self.shared.exe_name,
+            toml::to_string(&lock).context("failed to serialize package lock")?,
+        );
+        let tar = single_file_tar(Path::new(LOCK_FILE), contents.as_bytes())?;
+        let mut file = tar
+            .reopen()
+            .with_context(|| format!("failed to reopen {:?}", tar.path()))?;
+        self.runner.copy_in(
+            name,
+            Path::new(self.shared.config.work_dir_name(name)),
+            &mut file,
+        )?;
+        println!("Locked {} package builds for {name}", lock.packages.len());
+        Ok(())
+    }
+
+    /// Reads the environment's `packages.lock`.
+    pub(crate) fn read_package_lock(&self, name: &EnvironmentName) -> Result<PackageLock> {
+        let mut buf = Vec::new();
+        self.runner
+            .copy_out_from_work(name, Path::new(LOCK_FILE), &mut buf)
+            .with_context(|| {
+                format!(
+                    "failed to read `{LOCK_FILE}` from {name} (try '{} package lock {name}')",
+                    self.shared.exe_name
+                )
+            })?;
+        let contents = String::from_utf8(buf)
+            .with_context(|| format!("`{LOCK_FILE}` from {name} is not UTF-8"))?;
+        toml::from_str(&contents)
+            .with_context(|| format!("failed to parse `{LOCK_FILE}` from {name}"))
+    }
+
+    /// Returns the tar files to seed an environment with the given packages,
+    /// using the builds recorded in `lock` instead of the current ones.
+    ///
+    /// Locked builds are always copied into the home directory, even for
+    /// packages with `shared_paths`, since the shared install on the host
+    /// holds the current build.
+    pub(crate) fn locked_seeds(
+        &self,
+        packages: &BTreeSet<FullPackageName>,
+        specs: &PackageSpecs,
+        env_name: &EnvironmentName,
+        lock: &PackageLock,
+    ) -> Result<Vec<HostPath>> {
+        let platform = self.env_platform(env_name)?;
+        let mut seeds = Vec::new();
+        for package in plan::seed_packages(packages, specs)? {
+            let Some(locked) = lock.packages.get(&package.unquoted()) else {
+                if try_exists(&self.package_tar(&package, &platform)).unwrap_or(true) {
+                    return Err(anyhow!(
+                        "package {package} is not in the `{LOCK_FILE}` of {env_name} \
+                        (try '{} package lock {env_name}')",
+                        self.shared.exe_name
+                    ));
+                }
+                continue;
+            };
+            if locked.sha256.len() != 64 || !locked.sha256.bytes().all(|b| b.is_ascii_hexdigit()) {
+                return Err(anyhow!(
+                    "invalid sha256 for package {package} in the `{LOCK_FILE}` of {env_name}: {:?}",
+                    locked.sha256
+                ));
+            }
+            let tar = self.locked_build(&locked.sha256, &platform);
+            if !try_exists(&tar).with_context(|| format!("failed to check for {tar}"))? {
+                return Err(anyhow!(
+                    "the locked build of package {package} for {env_name} is no longer \
+                    in the package cache (expected {tar})"
+                ));
+            }
+            seeds.push(tar);
+        }
+        Ok(seeds)
+    }
+
+    /// Returns where a locked package build with the given hash is kept.
+    fn locked_build(&self, sha256: &str, platform: &Platform) -> HostPath {
+        self.package_cache(platform)
+            .join("locked")
+            .join(format!("{sha256}.tar"))
+    }
+
+    /// Keeps a package build so that it outlives later builds of the
+    /// package. This uses a hard link where possible, so it takes no extra
+    /// space until the package is rebuilt.
+    fn keep_locked_build(&self, tar: &HostPath, sha256: &str, platform: &Platform) -> Result<()> {
+        let path = self.locked_build(sha256, platform);
+        if try_exists(&path).with_context(|| format!("failed to check for {path}"))? {
+            return Ok(());
+        }
+        let dir = self.package_cache(platform).join("locked");
+        std::fs::create_dir_all(dir.as_host_raw())
+            .with_context(|| format!("failed to create directory {dir}"))?;
+        if std::fs::hard_link(tar.as_host_raw(), path.as_host_raw()).is_ok() {
+            return Ok(());
+        }
+        let temp = tempfile::NamedTempFile::new_in(dir.as_host_raw())
+            .with_context(|| format!("failed to create temporary file in {dir}"))?;
+        std::fs::copy(tar.as_host_raw(), temp.path())
+            .with_context(|| format!("failed to copy {tar} to {path}"))?;
+        temp.persist(path.as_host_raw())
+            .with_context(|| format!("failed to write {path}"))?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+
+    #[test]
+    fn locked_seeds() {
+        let root = tempfile::tempdir().unwrap();
+        let cub = testing::cubicle(root.path(), "");
+        let env = EnvironmentName::from_str("env").unwrap();
+        let platform = Platform::host();
+        let default = FullPackageName::from_str(special::DEFAULT).unwrap();
+        let dir = cub.shared.code_package_dir.join(special::DEFAULT);
+        std::fs::write(dir.join("build.sh").as_host_raw(), "").unwrap();
+        let specs = cub.scan_packages().unwrap();
+        let packages = BTreeSet::from([default.clone()]);
+        cub.update_packages(
+            &packages,
+            &specs,
+            &UpdatePackagesConditions {
+                dependencies: ShouldPackageUpdate::IfRequired,
+                named: ShouldPackageUpdate::Always,
+            },
+            &platform,
+        )
+        .unwrap();
+
+        // Packages that have been built must be in the lock.
+        assert!(cub
+            .locked_seeds(&packages, &specs, &env, &PackageLock::default())
+            .is_err());
+
+        let tar = cub.package_tar(&default, &platform);
+        let sha256 = hash_file(&tar).unwrap();
+        cub.keep_locked_build(&tar, &sha256, &platform).unwrap();
+        let lock = |sha256: &str| PackageLock {
+            packages: BTreeMap::from([(
+                default.unquoted(),
+                LockedPackage {
+                    sha256: sha256.to_owned(),
+                    version: None,
+                },
+            )]),
+        };
+        // The locked build outlives the package's current build.
+        std::fs::remove_file(tar.as_host_raw()).unwrap();
+        let seeds = cub
+            .locked_seeds(&packages, &specs, &env, &lock(&sha256))
+            .unwrap();
+        assert_eq!(
+            seeds
+                .iter()
+                .map(|seed| seed.as_host_raw().to_owned())
+                .collect::<Vec<_>>(),
+            [cub.locked_build(&sha256, &platform)
+                .as_host_raw()
+                .to_owned()]
+        );
+
+        assert!(cub
+            .locked_seeds(&packages, &specs, &env, &lock("../../default"))
+            .is_err());
+    }
+
+    #[test]
+    fn package_lock_toml() {
+        let lock = PackageLock {
+            packages: BTreeMap::from([
+                (
+                    String::from("npm.prettier"),
+                    LockedPackage {
+                        sha256: String::from("12ab"),
+                        version: None,
+                    },
+                ),
+                (
+                    String::from("rust"),
+                    LockedPackage {
+                        sha256: String::from("34cd"),
+                        version: Some(String::from("1.80")),
+                    },
+                ),
+            ]),
+        };
+        let toml = toml::to_string(&lock).unwrap();
+        assert_eq!(
+            toml,
+            "[packages.\"npm.prettier\"]\nsha256 = \"12ab\"\n\n\
+            [packages.rust]\nsha256 = \"34cd\"\nversion = \"1.80\"\n"
+        );
+        assert_eq!(toml::from_str::<PackageLock>(&toml).unwrap(), lock);
+    }
+}
@@ -3,12 +3,14 @@ use std::collections::BTreeMap;
 use std::io;
 use std::str::FromStr;
 
-use super::{HostPath, PackageName, PackageNamespace};
-use crate::somehow::{Context, LowLevelResult, Result};
+use super::{FullPackageName, HostPath, PackageName, PackageNamespace};
+use crate::somehow::{somehow as anyhow, Context, LowLevelResult, Result};
 
 #[derive(Debug, Deserialize, PartialEq, Eq)]
 #[serde(deny_unknown_fields)]
 pub struct TomlManifest {
+    #[serde(default)]
+    description: Option<String>,
     #[serde(default)]
     package_manager: bool,
     #[serde(default)]
@@ -17,33 +19,75 @@ pub struct TomlManifest {
     depends: BTreeMap<String, DependencyOrTable>,
     #[serde(default)]
     build_depends: BTreeMap<String, DependencyOrTable>,
+    #[serde(default)]
+    removes: Vec<String>,
+    #[serde(default)]
+    shared_paths: Vec<String>,
+    #[serde(default)]
+    profile_dirs: Vec<String>,
+    #[serde(default)]
+    version: Option<String>,
+    #[serde(default)]
+    network: Option<bool>,
 }
 
 #[derive(Debug, Deserialize, PartialEq, Eq)]
 #[serde(untagged)]
 #[serde(deny_unknown_fields)]
 enum DependencyOrTable {
-    Dependency(Dependency),
-    Table(BTreeMap<String, Dependency>),
+    Dependency(TomlDependency),
+    Table(BTreeMap<String, TomlDependency>),
+}
+
+/// A dependency as written in the manifest. Packages from a package manager
+/// may have a version constraint, given as a string or as `version` in the
+/// table.
+#[derive(Debug, Deserialize, PartialEq, Eq)]
+#[serde(untagged)]
+enum TomlDependency {
+    Version(String),
+    Table(TomlDependencyTable),
 }
 
 #[derive(Debug, Deserialize, PartialEq, Eq)]
 #[serde(deny_unknown_fields)]
+struct TomlDependencyTable {
+    #[serde(default)]
+    version: Option<String>,
+}
+
+impl TomlDependency {
+    fn version(self) -> Option<String> {
+        match self {
+            Self::Version(version) => Some(version),
+            Self::Table(table) => table.version,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
 pub struct Dependency {}
 
-#[derive(Debug, Deserialize, PartialEq, Eq)]
+#[derive(Clone, Debug, Deserialize, PartialEq, Eq)]
 #[serde(deny_unknown_fields)]
 pub struct Target {
     pub arch: Option<String>,
     pub os: Option<String>,
 }
 
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Clone, Debug, PartialEq, Eq)]
 pub struct Manifest {
+    pub description: Option<String>,
     pub package_manager: bool,
     pub targets: Option<Vec<Target>>,
     pub depends: BTreeMap<PackageNamespace, BTreeMap<PackageName, Dependency>>,
     pub build_depends: BTreeMap<PackageNamespace, BTreeMap<PackageName, Dependency>>,
+    pub removes: Vec<String>,
+    pub shared_paths: Vec<String>,
+    pub profile_dirs: Vec<String>,
+    pub version: Option<String>,
+    pub network: Option<bool>,
 }
 
 impl Manifest {
@@ -71,17 +115,23 @@ impl Manifest {
     }
 }
 
-fn parse(buf: &str) -> Result<Manifest> {
+pub fn parse(buf: &str) -> Result<Manifest> {
     let manifest: TomlManifest = toml::from_str(buf).enough_context()?;
     convert(manifest)
 }
 
 fn convert(manifest: TomlManifest) -> Result<Manifest> {
     Ok(Manifest {
+        description: manifest.description,
         package_manager: manifest.package_manager,
         targets: manifest.targets,
         depends: convert_depends(manifest.depends)?,
         build_depends: convert_depends(manifest.build_depends)?,
+        removes: manifest.removes,
+        shared_paths: manifest.shared_paths,
+        profile_dirs: manifest.profile_dirs,
+        version: manifest.version,
+        network: manifest.network,
     })
 }
 
@@ -93,10 +143,18 @@ fn convert_depends(
     for (key, value) in deps {
         match value {
             DependencyOrTable::Dependency(dep) => {
-                root.insert(PackageName::strict_from_str(&key)?, dep);
+                if dep.version().is_some() {
+                    return Err(anyhow!(
+                        "version constraints are only supported for packages from \
+                        package managers (found one for package {key:?})"
+                    ));
+                }
+                root.insert(PackageName::strict_from_str(&key)?, Dependency {});
             }
             DependencyOrTable::Table(table) => {
-                map.insert(PackageNamespace::from_str(&key)?, convert_table(table)?);
+                let ns = PackageNamespace::from_str(&key)?;
+                let table = convert_table(&ns, table)?;
+                map.insert(ns, table);
             }
         }
     }
@@ -104,10 +162,24 @@ fn convert_depends(
     Ok(map)
 }
 
-fn convert_table(table: BTreeMap<String, Dependency>) -> Result<BTreeMap<PackageName, Dependency>> {
+/// Converts the dependencies in a namespace. A version constraint is kept as
+/// part of the package name, like `django@>=4,<5`, so that each constraint
+/// gets its own build.
+fn convert_table(
+    ns: &PackageNamespace,
+    table: BTreeMap<String, TomlDependency>,
+) -> Result<BTreeMap<PackageName, Dependency>> {
     table
         .into_iter()
-        .map(|(name, dep)| Ok((PackageName::loose_from_str(&name)?, dep)))
+        .map(|(name, dep)| {
+            let name = match dep.version() {
+                Some(version) => format!("{name}@{version}"),
+                None => name,
+            };
+            let full = FullPackageName(ns.clone(), PackageName::loose_from_str(&name)?);
+            full.check_version()?;
+            Ok((full.1, Dependency {}))
+        })
         .collect()
 }
 
@@ -120,16 +192,25 @@ mod tests {
     fn parse() {
         assert_eq!(
             Manifest {
+                description: None,
                 package_manager: false,
                 targets: None,
                 depends: BTreeMap::from([(PackageNamespace::Root, BTreeMap::new())]),
                 build_depends: BTreeMap::from([(PackageNamespace::Root, BTreeMap::new())]),
+                removes: Vec::new(),
+                shared_paths: Vec::new(),
+                profile_dirs: Vec::new(),
+                version: None,
+                network: None,
             },
             super::parse("").unwrap()
         );
 
         expect![[r#"
             Manifest {
+                description: Some(
+                    "X tools",
+                ),
                 package_manager: true,
                 targets: Some(
                     [
@@ -152,11 +233,25 @@ mod tests {
                             "y",
                         ): Dependency,
                     },
-                    Debian: {
+                    Distro(
+                        Debian,
+                    ): {
                         PackageName(
                             "ca-certificates",
                         ): Dependency,
                     },
+                    Managed(
+                        PackageName(
+                            "pypi",
+                        ),
+                    ): {
+                        PackageName(
+                            "django@>=4,<5",
+                        ): Dependency,
+                        PackageName(
+                            "requests@==2.32.3",
+                        ): Dependency,
+                    },
                 },
                 build_depends: {
                     Root: {
@@ -164,7 +259,9 @@ mod tests {
                             "z",
                         ): Dependency,
                     },
-                    Debian: {
+                    Distro(
+                        Debian,
+                    ): {
                         PackageName(
                             "clang",
                         ): Dependency,
@@ -173,12 +270,33 @@ mod tests {
                         ): Dependency,
                     },
                 },
+                removes: [
+                    ".cache/x",
+                ],
+                shared_paths: [
+                    "opt/x",
+                ],
+                profile_dirs: [
+                    ".x",
+                ],
+                version: Some(
+                    "1.2",
+                ),
+                network: Some(
+                    false,
+                ),
             }
         "#]]
         .assert_debug_eq(
             &super::parse(
                 "
+                description = 'X tools'
                 package_manager = true
+                removes = ['.cache/x']
+                shared_paths = ['opt/x']
+                profile_dirs = ['.x']
+                version = '1.2'
+                network = false
                 [[targets]]
                 arch = 'x86_64'
                 os = 'linux'
@@ -189,6 +307,9 @@ mod tests {
                 z = {}
                 [depends.debian]
                 ca-certificates = {}
+                [depends.pypi]
+                django = '>=4,<5'
+                requests = { version = '==2.32.3' }
                 [build_depends.debian]
                 clang = {}
                 cmake = {}
@@ -197,4 +318,34 @@ mod tests {
             .unwrap(),
         );
     }
+
+    #[test]
+    fn parse_version_errors() {
+        assert!(super::parse("[depends]\nx = '1.0'").is_err());
+        assert!(super::parse("[depends.debian]\nsl = '5'").is_err());
+        assert!(super::parse("[depends.pypi]\ndjango = ''").is_err());
+    }
+
+    #[test]
+    fn parse_generated() {
+        use crate::testing;
+        use rand::rngs::StdRng;
+        use rand::SeedableRng;
+
+        let mut rng = StdRng::seed_from_u64(0);
+        for _ in 0..200 {
+            let available: Vec<_> = (0..5).map(|_| testing::package_name(&mut rng)).collect();
+            let toml = testing::manifest(&mut rng, &available);
+            let manifest = super::parse(&toml).unwrap();
+            let root = &manifest.depends[&PackageNamespace::Root];
+            let build_root = &manifest.build_depends[&PackageNamespace::Root];
+            for name in root.keys() {
+                assert!(available.contains(name), "{toml}");
+                assert!(!build_root.contains_key(name), "{toml}");
+            }
+            for name in build_root.keys() {
+                assert!(available.contains(name), "{toml}");
+            }
+        }
+    }
 }
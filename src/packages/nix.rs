@@ -0,0 +1,160 @@
+//! Builds packages in the `nix` namespace with Nix on the host.
+//!
+//! With a [`Config::nix`](crate::config::Config::nix) section, Cubicle
+//! builds `nix.ATTR` with `nix build FLAKE#ATTR` on the host, instead of
+//! running the `nix` package manager's `build.sh` in a builder environment.
+//! The build's closure stays in the host's `/nix/store`, which the runners
+//! make available read-only in environments, so the package's files are only
+//! symlinks into it: `~/.nix-cubicle/ATTR` for the build output and `~/bin/X`
+//! for each of its programs. An out-link in the cache directory keeps the
+//! closure from being garbage collected.
+
+use std::fs::File;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use super::{Cubicle, FullPackageName, PackageNamespace};
+use crate::command_ext::{Command, Stdio};
+use crate::encoding::FilenameEncoder;
+use crate::somehow::{somehow as anyhow, Context, Result};
+use crate::{HostPath, Platform};
+
+/// The name of the package manager whose packages Nix builds.
+const MANAGER: &str = "nix";
+
+/// Where the build output's symlink goes, relative to the home directory.
+const OUT_DIR: &str = ".nix-cubicle";
+
+impl Cubicle {
+    /// Returns true if the package should be built with Nix on the host.
+    pub(super) fn is_nix_package(&self, name: &FullPackageName) -> bool {
+        self.shared.config.nix.is_some()
+            && matches!(&name.0, PackageNamespace::Managed(manager) if manager.as_str() == MANAGER)
+    }
+
+    /// Builds the package with `nix build` and writes a tar file of its
+    /// symlinks to `tar`. The build's output goes to `log`.
+    pub(super) fn build_nix_package(
+        &self,
+        name: &FullPackageName,
+        platform: &Platform,
+        tar: &HostPath,
+        log: &mut File,
+    ) -> Result<()> {
+        let config = self.shared.config.nix.as_ref().unwrap();
+        if *platform != Platform::host() {
+            return Err(anyhow!(
+                "Nix packages can only be built for the host platform, not {platform}"
+            ));
+        }
+        if name.version().is_some() {
+            return Err(anyhow!(
+                "Nix packages don't support version constraints; pin the flake instead"
+            ));
+        }
+        let gc_roots = self.shared.cache_dir.join("nix");
+        std::fs::create_dir_all(gc_roots.as_host_raw())
+            .with_context(|| format!("failed to create directory {gc_roots}"))?;
+        let gc_root = gc_roots.join(FilenameEncoder::new().push(&name.unquoted()).encode());
+        let installable = format!("{}#{}", config.flake, name.base_name());
+
+        let stderr = log.try_clone().context("failed to reopen build log")?;
+        let output = Command::new("nix")
+            .args([
+                "--extra-experimental-features",
+                "nix-command flakes",
+                "build",
+                "--print-out-paths",
+                "--out-link",
+            ])
+            .arg(gc_root.as_host_raw())
+            .arg(&installable)
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(stderr)
+            .scoped_spawn()?
+            .wait_with_output()?;
+        log.write_all(&output.stdout)
+            .context("failed to write build log")?;
+        if !output.status.success() {
+            return Err(anyhow!(
+                "`nix build {installable}` exited with {}",
+                output.status
+            ));
+        }
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        let out = stdout
+            .lines()
+            .map(Path::new)
+            .find(|path| path.starts_with("/nix/store"))
+            .ok_or_else(|| anyhow!("`nix build {installable}` printed no store path"))?;
+
+        let file = File::create(tar.as_host_raw())
+            .with_context(|| format!("failed to create {tar}"))?;
+        write_nix_tar(name.base_name(), out, file)
+            .with_context(|| format!("failed to write {tar}"))
+    }
+}
+
+/// Writes a tar file with symlinks into the store path `out`, for the Nix
+/// attribute `attr`.
+fn write_nix_tar(attr: &str, out: &Path, file: File) -> std::io::Result<()> {
+    let mut links = vec![(Path::new(OUT_DIR).join(attr), out.to_owned())];
+    // Programs are found through `~/bin`, which is already on the `$PATH`.
+    if let Ok(entries) = std::fs::read_dir(out.join("bin")) {
+        let mut bins = entries
+            .map(|entry| entry.map(|entry| entry.file_name()))
+            .collect::<std::io::Result<Vec<_>>>()?;
+        bins.sort();
+        links.extend(
+            bins.into_iter()
+                .map(|bin| (PathBuf::from("bin").join(&bin), out.join("bin").join(&bin))),
+        );
+    }
+
+    let mut builder = tar::Builder::new(file);
+    for (path, target) in links {
+        let mut header = tar::Header::new_gnu();
+        header.set_entry_type(tar::EntryType::Symlink);
+        header.set_mode(0o777);
+        header.set_size(0);
+        builder.append_link(&mut header, &path, &target)?;
+    }
+    builder.into_inner()?.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn write_nix_tar() {
+        let store = tempfile::tempdir().unwrap();
+        let out = store.path().join("abc123-ripgrep-14.1.0");
+        std::fs::create_dir_all(out.join("bin")).unwrap();
+        std::fs::write(out.join("bin/rg"), "").unwrap();
+        let mut file = tempfile::tempfile().unwrap();
+        super::write_nix_tar("ripgrep", &out, file.try_clone().unwrap()).unwrap();
+
+        use std::io::{Seek, SeekFrom};
+        file.seek(SeekFrom::Start(0)).unwrap();
+        let links = tar::Archive::new(file)
+            .entries()
+            .unwrap()
+            .map(|entry| {
+                let entry = entry.unwrap();
+                (
+                    entry.path().unwrap().into_owned(),
+                    entry.link_name().unwrap().unwrap().into_owned(),
+                )
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            links,
+            [
+                (PathBuf::from(".nix-cubicle/ripgrep"), out.clone()),
+                (PathBuf::from("bin/rg"), out.join("bin/rg")),
+            ]
+        );
+    }
+}
@@ -0,0 +1,712 @@
+//! Decides which packages Cubicle would build and install.
+//!
+//! These functions are pure: they operate on in-memory [`PackageSpecs`] and
+//! a [`BuildHistory`] and never touch the filesystem. That lets other tools
+//! compute what `cub` would build, and it lets tests cover the resolver's
+//! edge cases directly. [`Cubicle`](crate::Cubicle) uses the same functions
+//! with a history read from its package cache.
+
+use serde::Serialize;
+use std::collections::{BTreeMap, BTreeSet};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use sha2::{Digest, Sha256};
+
+use super::{
+    build_inputs, FullPackageName, PackageName, PackageNamespace, PackageSpec, PackageSpecs,
+    ShouldPackageUpdate, UpdatePackagesConditions,
+};
+use crate::fs_util::hex;
+use crate::somehow::{somehow as anyhow, Result};
+
+/// Whether to follow build-dependencies in [`transitive_depends`].
+#[derive(Clone, Copy, Debug)]
+pub struct BuildDepends(pub bool);
+
+/// Returns the given packages and all of their transitive dependencies,
+/// including distro packages.
+///
+/// Returns an error if a package definition is missing or if a managed
+/// package's namespace isn't a package manager.
+pub fn transitive_depends(
+    packages: &BTreeSet<FullPackageName>,
+    specs: &PackageSpecs,
+    build_depends: BuildDepends,
+) -> Result<BTreeSet<FullPackageName>> {
+    struct Visitor<'a> {
+        specs: &'a PackageSpecs,
+        build_depends: BuildDepends,
+        visited: BTreeSet<FullPackageName>,
+    }
+
+    impl<'a> Visitor<'a> {
+        fn visit(
+            &mut self,
+            p: &FullPackageName,
+            needed_by: Option<&FullPackageName>,
+        ) -> Result<()> {
+            if !self.visited.contains(p) {
+                self.visited.insert(p.clone());
+                let spec = match &p.0 {
+                    PackageNamespace::Distro(_) => {
+                        return Ok(());
+                    }
+                    PackageNamespace::Root => {
+                        self.specs.get(&p.1).ok_or_else(|| match needed_by {
+                            Some(other) => {
+                                anyhow!(
+                                    "could not find package definition for {p}, needed by {other}"
+                                )
+                            }
+                            None => anyhow!("could not find package definition for {p}"),
+                        })?
+                    }
+                    PackageNamespace::Managed(manager) => {
+                        let spec = self.specs.get(manager).ok_or_else(|| match needed_by {
+                        Some(other) => {
+                            anyhow!("could not find package definition for package manager {}, needed by {other}", p.0)
+                        }
+                        None => anyhow!("could not find package definition for {p}"),
+                    })?;
+                        if !spec.manifest.package_manager {
+                            return Err(anyhow!("package {} is not a package manager", p.0));
+                        }
+                        spec
+                    }
+                };
+                for (ns, table) in &spec.manifest.depends {
+                    for name in table.keys() {
+                        self.visit(&FullPackageName(ns.clone(), name.clone()), Some(p))?;
+                    }
+                }
+                if self.build_depends.0 {
+                    for (ns, table) in &spec.manifest.build_depends {
+                        for name in table.keys() {
+                            self.visit(&FullPackageName(ns.clone(), name.clone()), Some(p))?;
+                        }
+                    }
+                }
+            }
+            Ok(())
+        }
+    }
+
+    let mut visitor = Visitor {
+        specs,
+        build_depends,
+        visited: BTreeSet::new(),
+    };
+    for p in packages {
+        visitor.visit(p, None)?;
+    }
+    Ok(visitor.visited)
+}
+
+/// Returns the packages that would be built to update the given packages,
+/// with their definitions, in the order they'd be built.
+///
+/// This includes transitive dependencies and build-dependencies but not
+/// distro packages. Each package comes after its dependencies and
+/// build-dependencies. Packages in a package manager's namespace use the
+/// package manager's definition.
+pub fn build_order<'a>(
+    packages: &BTreeSet<FullPackageName>,
+    specs: &'a PackageSpecs,
+) -> Result<Vec<(FullPackageName, &'a PackageSpec)>> {
+    let todo = transitive_depends(packages, specs, BuildDepends(true))?
+        .into_iter()
+        .filter(|FullPackageName(ns, _name)| !matches!(ns, PackageNamespace::Distro(_)))
+        .map(|full_name| {
+            let spec = definition_spec(&full_name, specs)?;
+            Ok((full_name, spec))
+        })
+        .collect::<Result<_>>()?;
+    sort_build_order(todo)
+}
+
+/// Returns the spec used to build a non-distro package: its own, or its
+/// package manager's. See [`definition`].
+fn definition_spec<'a>(
+    full_name: &FullPackageName,
+    specs: &'a PackageSpecs,
+) -> Result<&'a PackageSpec> {
+    match &full_name.0 {
+        PackageNamespace::Distro(_) => unreachable!(),
+        PackageNamespace::Root => specs
+            .get(&full_name.1)
+            .ok_or_else(|| anyhow!("could not find definition for package {}", full_name.1)),
+        PackageNamespace::Managed(manager) => {
+            let spec = specs.get(manager).ok_or_else(|| {
+                anyhow!("could not find definition for package manager {manager}")
+            })?;
+            if !spec.manifest.package_manager {
+                return Err(anyhow!("package {manager} is not a package manager"));
+            }
+            Ok(spec)
+        }
+    }
+}
+
+/// The direct dependencies of a package in a [`dependency_graph`].
+#[derive(Debug, Default, Eq, PartialEq, Serialize)]
+pub struct GraphNode {
+    /// Packages needed to use this one.
+    pub depends: BTreeSet<FullPackageName>,
+    /// Packages needed only to build this one.
+    pub build_depends: BTreeSet<FullPackageName>,
+}
+
+/// Returns the given packages and all of their transitive dependencies and
+/// build-dependencies, each with its direct dependencies.
+///
+/// A package is rebuilt when any package it reaches in this graph changes.
+/// Distro packages have no dependencies here. Unlike [`build_order`], this
+/// allows cycles.
+pub fn dependency_graph(
+    packages: &BTreeSet<FullPackageName>,
+    specs: &PackageSpecs,
+) -> Result<BTreeMap<FullPackageName, GraphNode>> {
+    let flatten = |deps: &BTreeMap<PackageNamespace, BTreeMap<PackageName, _>>| {
+        deps.iter()
+            .flat_map(|(ns, table)| {
+                table
+                    .keys()
+                    .map(|name| FullPackageName(ns.clone(), name.clone()))
+            })
+            .collect()
+    };
+    transitive_depends(packages, specs, BuildDepends(true))?
+        .into_iter()
+        .map(|full_name| {
+            let node = match &full_name.0 {
+                PackageNamespace::Distro(_) => GraphNode::default(),
+                _ => {
+                    let spec = definition_spec(&full_name, specs)?;
+                    GraphNode {
+                        depends: flatten(&spec.manifest.depends),
+                        build_depends: flatten(&spec.manifest.build_depends),
+                    }
+                }
+            };
+            Ok((full_name, node))
+        })
+        .collect()
+}
+
+/// Sorts the packages so that each one comes after its (non-distro)
+/// dependencies and build-dependencies.
+fn sort_build_order(
+    mut todo: Vec<(FullPackageName, &PackageSpec)>,
+) -> Result<Vec<(FullPackageName, &PackageSpec)>> {
+    let mut order = Vec::with_capacity(todo.len());
+    let mut done: BTreeSet<FullPackageName> = BTreeSet::new();
+    while !todo.is_empty() {
+        let start_todos = todo.len();
+        let mut later: Vec<(FullPackageName, &PackageSpec)> = Vec::new();
+        for (full_name, spec) in todo {
+            let deps_ready = spec
+                .manifest
+                .depends
+                .iter()
+                .chain(spec.manifest.build_depends.iter())
+                .all(|(ns, deps)| {
+                    matches!(ns, PackageNamespace::Distro(_))
+                        || deps
+                            .keys()
+                            .all(|dep| done.contains(&FullPackageName(ns.clone(), dep.clone())))
+                });
+            if deps_ready {
+                done.insert(full_name.clone());
+                order.push((full_name, spec));
+            } else {
+                later.push((full_name, spec));
+            }
+        }
+        if later.len() == start_todos {
+            let mut names = later
+                .iter()
+                .map(|(full_name, _)| full_name.to_string())
+                .collect::<Vec<_>>();
+            names.sort_unstable();
+            return Err(anyhow!(
+                "package dependencies are unsatisfiable for: {}",
+                names.join(", ")
+            ));
+        }
+        todo = later;
+    }
+    Ok(order)
+}
+
+/// Returns the packages whose build outputs would seed a new environment
+/// with the given packages.
+///
+/// These are the packages and their transitive (non-build) dependencies,
+/// excluding distro packages. Packages that have never been built won't
+/// actually have seeds.
+pub fn seed_packages(
+    packages: &BTreeSet<FullPackageName>,
+    specs: &PackageSpecs,
+) -> Result<BTreeSet<FullPackageName>> {
+    Ok(transitive_depends(packages, specs, BuildDepends(false))?
+        .into_iter()
+        .filter(|FullPackageName(ns, _name)| !matches!(ns, PackageNamespace::Distro(_)))
+        .collect())
+}
+
+/// Returns the packages defined in `specs` that transitively depend on or
+/// build-depend on the given package, so they'd be rebuilt after it changes.
+///
+/// Packages whose dependencies can't be resolved are skipped.
+pub fn reverse_depends(
+    package: &FullPackageName,
+    specs: &PackageSpecs,
+) -> BTreeSet<FullPackageName> {
+    specs
+        .keys()
+        .map(|name| FullPackageName(PackageNamespace::Root, name.clone()))
+        .filter(|name| name != package)
+        .filter(|name| {
+            transitive_depends(&BTreeSet::from([name.clone()]), specs, BuildDepends(true))
+                .is_ok_and(|deps| deps.contains(package))
+        })
+        .collect()
+}
+
+/// What's known about past package builds, as needed to decide which
+/// packages are stale.
+pub trait BuildHistory {
+    /// Returns when the package was last built successfully, or `None` if it
+    /// never has been.
+    fn last_built(&self, name: &FullPackageName) -> Option<SystemTime>;
+
+    /// Returns when the package's source files (in the directory of `spec`)
+    /// were last modified.
+    ///
+    /// This is only used for builds that have no recorded build key, which
+    /// older versions of Cubicle didn't keep.
+    fn source_modified(&self, name: &FullPackageName, spec: &PackageSpec) -> Result<SystemTime>;
+
+    /// Returns a hash of the package's source files (in the directory of
+    /// `spec`).
+    fn source_hash(&self, name: &FullPackageName, spec: &PackageSpec) -> Result<String>;
+
+    /// Returns the [`Planner::build_key`] recorded with the package's last
+    /// successful build, if any.
+    fn recorded_key(&self, name: &FullPackageName) -> Option<String>;
+}
+
+/// An in-memory [`BuildHistory`].
+#[derive(Debug, Default)]
+pub struct BuildState {
+    /// When each package was last built successfully. Packages that have
+    /// never been built are absent.
+    pub last_built: BTreeMap<FullPackageName, SystemTime>,
+    /// When each package definition's source files were last modified. For
+    /// packages in a package manager's namespace, this is keyed by the
+    /// package manager's name. Absent entries were never modified.
+    pub source_modified: BTreeMap<PackageName, SystemTime>,
+    /// The hash of each package definition's source files, keyed like
+    /// `source_modified`. Absent entries hash to the empty string.
+    pub source_hash: BTreeMap<PackageName, String>,
+    /// The build key recorded with each package's last successful build.
+    /// Packages whose builds have no key are absent.
+    pub recorded_key: BTreeMap<FullPackageName, String>,
+}
+
+impl BuildHistory for BuildState {
+    fn last_built(&self, name: &FullPackageName) -> Option<SystemTime> {
+        self.last_built.get(name).copied()
+    }
+
+    fn source_modified(&self, name: &FullPackageName, _spec: &PackageSpec) -> Result<SystemTime> {
+        Ok(definition(name)
+            .and_then(|definition| self.source_modified.get(definition))
+            .copied()
+            .unwrap_or(UNIX_EPOCH))
+    }
+
+    fn source_hash(&self, name: &FullPackageName, _spec: &PackageSpec) -> Result<String> {
+        Ok(definition(name)
+            .and_then(|definition| self.source_hash.get(definition))
+            .cloned()
+            .unwrap_or_default())
+    }
+
+    fn recorded_key(&self, name: &FullPackageName) -> Option<String> {
+        self.recorded_key.get(name).cloned()
+    }
+}
+
+/// Returns the name of the package definition that builds the package: the
+/// package manager for packages in its namespace.
+fn definition(name: &FullPackageName) -> Option<&PackageName> {
+    match &name.0 {
+        PackageNamespace::Root => Some(&name.1),
+        PackageNamespace::Managed(manager) => Some(manager),
+        PackageNamespace::Distro(_) => None,
+    }
+}
+
+/// Decides which packages need to be rebuilt.
+pub struct Planner<'a> {
+    /// Past package builds.
+    pub history: &'a dyn BuildHistory,
+    /// See [`Config::auto_update`](crate::Config::auto_update).
+    pub auto_update: Option<Duration>,
+    /// The current time, to compare against `auto_update`.
+    pub now: SystemTime,
+}
+
+impl Planner<'_> {
+    /// Returns whether the package should be rebuilt under
+    /// [`ShouldPackageUpdate::IfStale`].
+    pub fn is_stale(&self, package_name: &FullPackageName, spec: &PackageSpec) -> Result<bool> {
+        let built = match self.history.last_built(package_name) {
+            Some(built) => built,
+            None => return Ok(true),
+        };
+        if let Some(threshold) = self.auto_update {
+            match self.now.duration_since(built) {
+                Ok(d) if d > threshold => return Ok(true),
+                Err(_) => return Ok(true),
+                _ => {}
+            }
+        }
+        let changed = match self.history.recorded_key(package_name) {
+            Some(key) => key != self.build_key(package_name, spec)?,
+            None => self.history.source_modified(package_name, spec)? > built,
+        };
+        if changed {
+            return Ok(true);
+        }
+        for (ns, table) in spec
+            .manifest
+            .build_depends
+            .iter()
+            .chain(spec.manifest.depends.iter())
+        {
+            for name in table.keys() {
+                let full_name = FullPackageName(ns.clone(), name.clone());
+                if matches!(self.history.last_built(&full_name), Some(b) if b > built) {
+                    return Ok(true);
+                }
+            }
+        }
+        Ok(false)
+    }
+
+    /// Returns a hash of everything that goes into building the package: its
+    /// name, its source files, and the build keys of the packages it depends
+    /// on. A build is stale once this no longer matches the key recorded
+    /// with it.
+    ///
+    /// This doesn't use modification times, so that touching the source files
+    /// without changing them, as `git checkout` may, doesn't cause rebuilds.
+    pub fn build_key(&self, package_name: &FullPackageName, spec: &PackageSpec) -> Result<String> {
+        let mut hasher = Sha256::new();
+        hasher.update(format!(
+            "cubicle build key v1\n{}\n",
+            package_name.unquoted()
+        ));
+        hasher.update(self.history.source_hash(package_name, spec)?);
+        hasher.update("\n");
+        for input in build_inputs(spec) {
+            let key = self.history.recorded_key(&input).unwrap_or_default();
+            hasher.update(format!("{} {key}\n", input.unquoted()));
+        }
+        Ok(hex(&hasher.finalize()))
+    }
+
+    /// Returns whether the package was built successfully after the given
+    /// time, such as by another process while this one waited for it.
+    pub fn built_since(&self, package_name: &FullPackageName, since: SystemTime) -> bool {
+        matches!(self.history.last_built(package_name), Some(built) if built > since)
+    }
+
+    /// Returns whether the given package should be built now, given its
+    /// update condition.
+    ///
+    /// This assumes the package's dependencies have already been updated.
+    pub fn needs_build(
+        &self,
+        package_name: &FullPackageName,
+        spec: &PackageSpec,
+        when: ShouldPackageUpdate,
+    ) -> Result<bool> {
+        if spec.update.is_none() {
+            return Ok(false);
+        }
+        Ok(match when {
+            ShouldPackageUpdate::Always => true,
+            ShouldPackageUpdate::IfStale => self.is_stale(package_name, spec)?,
+            ShouldPackageUpdate::IfRequired => self.history.last_built(package_name).is_none(),
+        })
+    }
+
+    /// Predicts which packages
+    /// [`Cubicle::update_packages`](crate::Cubicle::update_packages) will
+    /// build, given the [`build_order`] for `packages`.
+    ///
+    /// This differs from [`Planner::needs_build`] in that it doesn't wait for
+    /// dependencies to be rebuilt: a stale package will become stale once one
+    /// of its dependencies is rebuilt.
+    pub fn planned_builds(
+        &self,
+        order: &[(FullPackageName, &PackageSpec)],
+        packages: &BTreeSet<FullPackageName>,
+        conditions: &UpdatePackagesConditions,
+    ) -> Result<Vec<FullPackageName>> {
+        let mut planned: Vec<FullPackageName> = Vec::new();
+        for (full_name, spec) in order {
+            let when = if packages.contains(full_name) {
+                conditions.named
+            } else {
+                conditions.dependencies
+            };
+            let build = spec.update.is_some()
+                && (self.needs_build(full_name, spec, when)?
+                    || (when == ShouldPackageUpdate::IfStale
+                        && spec
+                            .manifest
+                            .depends
+                            .iter()
+                            .chain(spec.manifest.build_depends.iter())
+                            .any(|(ns, deps)| {
+                                deps.keys().any(|dep| {
+                                    planned.contains(&FullPackageName(ns.clone(), dep.clone()))
+                                })
+                            })));
+            if build {
+                planned.push(full_name.clone());
+            }
+        }
+        Ok(planned)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+    use std::str::FromStr;
+
+    fn specs(packages: &[(&str, &str)]) -> PackageSpecs {
+        packages
+            .iter()
+            .map(|(name, manifest)| {
+                (
+                    PackageName::strict_from_str(name).unwrap(),
+                    PackageSpec::from_manifest(
+                        PathBuf::from(format!("/packages/{name}")),
+                        manifest,
+                        true,
+                    )
+                    .unwrap(),
+                )
+            })
+            .collect()
+    }
+
+    fn names(names: &str) -> BTreeSet<FullPackageName> {
+        names
+            .split_whitespace()
+            .map(|name| FullPackageName::from_str(name).unwrap())
+            .collect()
+    }
+
+    fn unquoted<'a>(names: impl IntoIterator<Item = &'a FullPackageName>) -> String {
+        names
+            .into_iter()
+            .map(|name| name.unquoted())
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+
+    #[test]
+    fn resolution() {
+        let specs = specs(&[
+            ("app", "depends.lib = {}\nbuild_depends.gcc = {}\n"),
+            ("gcc", "depends.debian.build-essential = {}\n"),
+            ("lib", "depends.npm.left-pad = {}\n"),
+            ("npm", "package_manager = true\n"),
+            ("plain", ""),
+        ]);
+
+        let deps = transitive_depends(&names("app"), &specs, BuildDepends(false)).unwrap();
+        assert_eq!(unquoted(&deps), "app lib npm.left-pad");
+        let deps = transitive_depends(&names("app"), &specs, BuildDepends(true)).unwrap();
+        assert_eq!(
+            unquoted(&deps),
+            "app debian.build-essential gcc lib npm.left-pad"
+        );
+        assert_eq!(
+            unquoted(&seed_packages(&names("app"), &specs).unwrap()),
+            "app lib npm.left-pad"
+        );
+
+        let order = build_order(&names("app"), &specs).unwrap();
+        assert_eq!(
+            unquoted(order.iter().map(|(name, _)| name)),
+            "gcc npm.left-pad lib app"
+        );
+
+        let error = |packages: &str| {
+            transitive_depends(&names(packages), &specs, BuildDepends(true))
+                .unwrap_err()
+                .to_string()
+        };
+        assert_eq!(
+            error("nope"),
+            r#"could not find package definition for "nope""#
+        );
+        assert_eq!(
+            error("plain.x"),
+            r#"package "plain" is not a package manager"#
+        );
+        assert_eq!(
+            error("pip.x"),
+            r#"could not find package definition for "pip.x""#
+        );
+    }
+
+    #[test]
+    fn cycles() {
+        let specs = specs(&[
+            ("a", "depends.b = {}\n"),
+            ("b", "build_depends.a = {}\n"),
+            ("c", "depends.a = {}\n"),
+        ]);
+        assert_eq!(
+            unquoted(&transitive_depends(&names("c"), &specs, BuildDepends(true)).unwrap()),
+            "a b c"
+        );
+        assert_eq!(
+            build_order(&names("c"), &specs)
+                .map(|_| ())
+                .unwrap_err()
+                .to_string(),
+            r#"package dependencies are unsatisfiable for: "a", "b", "c""#
+        );
+    }
+
+    #[test]
+    fn build_order_ties() {
+        let specs = specs(&[
+            ("a", "depends.z = {}\n"),
+            ("b", ""),
+            ("c", "depends.b = {}\n"),
+            ("z", ""),
+        ]);
+        let order = |packages: &str| {
+            unquoted(
+                build_order(&names(packages), &specs)
+                    .unwrap()
+                    .iter()
+                    .map(|(name, _)| name),
+            )
+        };
+        // Packages without dependencies between them are built in name
+        // order, but dependencies come first.
+        assert_eq!(order("b z"), "b z");
+        assert_eq!(order("a"), "z a");
+        // A package whose dependencies were just placed goes right after
+        // them, ahead of later names.
+        assert_eq!(order("c z"), "b c z");
+        assert_eq!(order("a b c z"), "b c z a");
+        assert_eq!(order(""), "");
+    }
+
+    #[test]
+    fn reverse_depends() {
+        let specs = specs(&[
+            ("app", "depends.lib = {}\nbuild_depends.gcc = {}\n"),
+            ("broken", "depends.nope = {}\n"),
+            ("gcc", "depends.debian.build-essential = {}\n"),
+            ("lib", "depends.npm.left-pad = {}\n"),
+            ("npm", "package_manager = true\n"),
+            ("plain", ""),
+        ]);
+        let rdeps = |name: &str| {
+            unquoted(&super::reverse_depends(
+                &FullPackageName::from_str(name).unwrap(),
+                &specs,
+            ))
+        };
+        assert_eq!(rdeps("lib"), "app");
+        assert_eq!(rdeps("debian.build-essential"), "app gcc");
+        assert_eq!(rdeps("npm.left-pad"), "app lib");
+        assert_eq!(rdeps("app"), "");
+    }
+
+    #[test]
+    fn dependency_graph() {
+        let specs = specs(&[
+            ("a", "depends.b = {}\n"),
+            ("b", "build_depends.a = {}\ndepends.debian.sl = {}\n"),
+            ("c", "depends.a = {}\n"),
+        ]);
+        // Cycles are allowed.
+        let graph = super::dependency_graph(&names("c"), &specs).unwrap();
+        assert_eq!(unquoted(graph.keys()), "a b c debian.sl");
+        let node = |name: &str| &graph[&FullPackageName::from_str(name).unwrap()];
+        assert_eq!(unquoted(&node("b").depends), "debian.sl");
+        assert_eq!(unquoted(&node("b").build_depends), "a");
+        assert_eq!(unquoted(&node("c").depends), "a");
+        assert_eq!(node("debian.sl"), &GraphNode::default());
+    }
+
+    #[test]
+    fn planned_builds() {
+        let specs = specs(&[
+            ("app", "depends.lib = {}\n"),
+            ("lib", "depends.base = {}\n"),
+            ("base", ""),
+        ]);
+        let time = |hours: u64| UNIX_EPOCH + Duration::from_secs(hours * 60 * 60);
+        let mut state = BuildState::default();
+        for name in ["app", "lib", "base"] {
+            state
+                .last_built
+                .insert(FullPackageName::from_str(name).unwrap(), time(10));
+        }
+        let order = build_order(&names("app"), &specs).unwrap();
+        let plan = |state: &BuildState, named, dependencies| {
+            let planner = Planner {
+                history: state,
+                auto_update: None,
+                now: time(20),
+            };
+            unquoted(
+                &planner
+                    .planned_builds(
+                        &order,
+                        &names("app"),
+                        &UpdatePackagesConditions {
+                            dependencies,
+                            named,
+                        },
+                    )
+                    .unwrap(),
+            )
+        };
+        use ShouldPackageUpdate::*;
+
+        assert_eq!(plan(&state, IfStale, IfStale), "");
+        assert_eq!(plan(&state, Always, IfRequired), "app");
+        assert_eq!(plan(&state, IfRequired, Always), "base lib");
+
+        state
+            .source_modified
+            .insert(PackageName::strict_from_str("base").unwrap(), time(11));
+        assert_eq!(plan(&state, IfStale, IfStale), "base lib app");
+        assert_eq!(plan(&state, IfStale, IfRequired), "");
+
+        state.source_modified.clear();
+        state
+            .last_built
+            .remove(&FullPackageName::from_str("lib").unwrap());
+        assert_eq!(plan(&state, IfRequired, IfRequired), "lib");
+        assert_eq!(plan(&state, IfStale, IfStale), "lib app");
+    }
+}
@@ -0,0 +1,589 @@
+//! Shares package builds between machines through a remote cache.
+//!
+//! Some packages take a long time to build, and the machines on a team
+//! usually build them from the same sources. With a `[remote_cache]` section
+//! in the configuration file, Cubicle looks for a package build in the
+//! remote cache before building the package itself, and with `upload =
+//! true`, it uploads the packages it builds. Builds are stored under their
+//! [`Planner::build_key`](super::Planner::build_key), which covers the
+//! package's name, source files, and dependencies, so a build is only reused
+//! where the same build would have resulted.
+//!
+//! Builds can also be signed with an SSH key when they're uploaded, and
+//! downloads can be limited to builds signed by trusted keys, using
+//! `ssh-keygen -Y`.
+
+use reqwest::blocking::{Body, Client, RequestBuilder};
+use reqwest::{Method, StatusCode, Url};
+use sha2::{Digest, Sha256};
+use std::fs::File;
+use std::io;
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tracing::{debug, info};
+
+use super::{Cubicle, FullPackageName, PackageSpec};
+use crate::command_ext::{Command, Stdio};
+use crate::config;
+use crate::fs_util::hex;
+use crate::somehow::{somehow as anyhow, warn, Context, Result};
+use crate::Platform;
+
+impl Cubicle {
+    /// Downloads the build of the package with the given build key from the
+    /// remote cache, if one is configured and has it. Returns true if it
+    /// did, so the package doesn't need to be built.
+    ///
+    /// Errors are only printed as warnings, since the package can still be
+    /// built locally.
+    pub(super) fn download_package(
+        &self,
+        name: &FullPackageName,
+        spec: &PackageSpec,
+        platform: &Platform,
+        key: &str,
+    ) -> bool {
+        let Some(config) = &self.shared.config.remote_cache else {
+            return false;
+        };
+        match self.download_package_(config, name, spec, platform, key) {
+            Ok(found) => found,
+            Err(e) => {
+                warn(e.context(format!(
+                    "failed to download package {name} from the remote cache"
+                )));
+                false
+            }
+        }
+    }
+
+    fn download_package_(
+        &self,
+        config: &config::RemoteCache,
+        name: &FullPackageName,
+        spec: &PackageSpec,
+        platform: &Platform,
+        key: &str,
+    ) -> Result<bool> {
+        let package_cache = self.package_cache(platform);
+        std::fs::create_dir_all(package_cache.as_host_raw())
+            .with_context(|| format!("failed to create directory {package_cache}"))?;
+        let mut temp = tempfile::NamedTempFile::new_in(package_cache.as_host_raw())
+            .with_context(|| format!("failed to create temporary file in {package_cache}"))?;
+        let cache = RemoteCache::new(config)?;
+        let arch = platform.arch();
+        if !cache.get(&format!("{arch}/{key}.tar"), temp.as_file_mut())? {
+            debug!("Package {name} is not in the remote cache");
+            return Ok(false);
+        }
+        if let Some(allowed_signers) = &config.allowed_signers {
+            let mut signature = tempfile::NamedTempFile::new_in(package_cache.as_host_raw())
+                .with_context(|| format!("failed to create temporary file in {package_cache}"))?;
+            if !cache.get(&format!("{arch}/{key}.tar.sig"), signature.as_file_mut())? {
+                return Err(anyhow!(
+                    "the remote cache's build of package {name} is not signed"
+                ));
+            }
+            verify_signature(temp.path(), signature.path(), allowed_signers)
+                .with_context(|| format!("bad signature on remote build of package {name}"))?;
+        }
+        let tar = self.package_tar(name, platform);
+        temp.persist(tar.as_host_raw())
+            .with_context(|| format!("failed to write {tar}"))?;
+        self.record_build(name, spec, platform, key);
+        let failed_marker = self.failed_marker(name, platform);
+        if let Err(e) = std::fs::remove_file(failed_marker.as_host_raw()) {
+            if e.kind() != io::ErrorKind::NotFound {
+                warn(anyhow!("failed to remove file {failed_marker}: {e}"));
+            }
+        }
+        info!("Downloaded {name} package from the remote cache");
+        Ok(true)
+    }
+
+    /// Uploads a new build of the package to the remote cache, if one is
+    /// configured with `upload = true`.
+    ///
+    /// Errors are only printed as warnings, since the build itself
+    /// succeeded.
+    pub(super) fn upload_package(&self, name: &FullPackageName, platform: &Platform, key: &str) {
+        let Some(config) = &self.shared.config.remote_cache else {
+            return;
+        };
+        if !config.upload {
+            return;
+        }
+        let tar = self.package_tar(name, platform);
+        let result = (|| {
+            let cache = RemoteCache::new(config)?;
+            let path = format!("{}/{key}.tar", platform.arch());
+            // Sign first, so that a build is never in the cache without its
+            // signature.
+            if let Some(signing_key) = &config.signing_key {
+                let signature = sign(tar.as_host_raw(), signing_key)?;
+                cache.put(&format!("{path}.sig"), signature)?;
+            }
+            let file =
+                File::open(tar.as_host_raw()).with_context(|| format!("failed to open {tar}"))?;
+            cache.put(&path, file)
+        })();
+        match result {
+            Ok(()) => info!("Uploaded {name} package to the remote cache"),
+            Err(e) => warn(e.context(format!(
+                "failed to upload package {name} to the remote cache"
+            ))),
+        }
+    }
+}
+
+/// A client for the configured remote cache.
+struct RemoteCache<'a> {
+    config: &'a config::RemoteCache,
+    client: Client,
+}
+
+impl<'a> RemoteCache<'a> {
+    fn new(config: &'a config::RemoteCache) -> Result<Self> {
+        let client = Client::builder()
+            // Package builds can be large, so only connecting is limited.
+            .timeout(None)
+            .connect_timeout(Duration::from_secs(10))
+            .build()
+            .context("failed to create HTTP client")?;
+        Ok(Self { config, client })
+    }
+
+    /// Returns the URL of the object at `path` (like `"x86_64/KEY.tar"`)
+    /// within the cache.
+    fn url(&self, path: &str) -> Result<Url> {
+        let url = format!("{}/{path}", self.config.url.trim_end_matches('/'));
+        Url::parse(&url).with_context(|| format!("invalid remote cache URL: {url:?}"))
+    }
+
+    fn request(&self, method: Method, url: &Url) -> Result<RequestBuilder> {
+        let mut request = self.client.request(method.clone(), url.clone());
+        if let Some(region) = &self.config.s3_region {
+            let credentials = Credentials::from_env()?;
+            for (header, value) in sign_s3(&method, url, region, &credentials, SystemTime::now())? {
+                request = request.header(header, value);
+            }
+        }
+        Ok(request)
+    }
+
+    /// Downloads the object at `path` into `file`. Returns false if the
+    /// cache doesn't have it.
+    fn get(&self, path: &str, file: &mut File) -> Result<bool> {
+        let url = self.url(path)?;
+        let mut response = self
+            .request(Method::GET, &url)?
+            .send()
+            .with_context(|| format!("failed to request {url}"))?;
+        if response.status() == StatusCode::NOT_FOUND {
+            return Ok(false);
+        }
+        if !response.status().is_success() {
+            return Err(anyhow!("GET {url} failed: {}", response.status()));
+        }
+        response
+            .copy_to(file)
+            .with_context(|| format!("failed to download {url}"))?;
+        Ok(true)
+    }
+
+    /// Uploads `body` as the object at `path`.
+    fn put(&self, path: &str, body: impl Into<Body>) -> Result<()> {
+        let url = self.url(path)?;
+        let response = self
+            .request(Method::PUT, &url)?
+            .body(body)
+            .send()
+            .with_context(|| format!("failed to upload to {url}"))?;
+        if !response.status().is_success() {
+            return Err(anyhow!("PUT {url} failed: {}", response.status()));
+        }
+        Ok(())
+    }
+}
+
+/// The `ssh-keygen -Y` namespace for package build signatures, so that
+/// signatures made for other purposes with the same key aren't accepted.
+const SIGNATURE_NAMESPACE: &str = "cubicle-package";
+
+/// Signs the file at `path` with the SSH private key at `key`. Returns the
+/// armored signature.
+fn sign(path: &Path, key: &Path) -> Result<Vec<u8>> {
+    let file = File::open(path).with_context(|| format!("failed to open {path:?}"))?;
+    let output = Command::new("ssh-keygen")
+        .args(["-q", "-Y", "sign", "-n", SIGNATURE_NAMESPACE, "-f"])
+        .arg(key)
+        .stdin(file)
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .scoped_spawn()?
+        .wait_with_output()?;
+    if !output.status.success() {
+        return Err(anyhow!(
+            "`ssh-keygen -Y sign` with key {key:?} exited with {}: {}",
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    Ok(output.stdout)
+}
+
+/// Checks that `signature` is a valid signature of the file at `path` by
+/// one of the keys in the `allowed_signers` file.
+fn verify_signature(path: &Path, signature: &Path, allowed_signers: &Path) -> Result<()> {
+    // `ssh-keygen -Y verify` needs to be told which signer to expect, so
+    // this finds who made the signature first.
+    let output = Command::new("ssh-keygen")
+        .args(["-Y", "find-principals", "-f"])
+        .arg(allowed_signers)
+        .arg("-s")
+        .arg(signature)
+        .output()?;
+    if !output.status.success() {
+        return Err(anyhow!(
+            "signature is not from a key in {allowed_signers:?}: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let Some(principal) = stdout.lines().next().filter(|line| !line.is_empty()) else {
+        return Err(anyhow!(
+            "signature is not from a key in {allowed_signers:?}"
+        ));
+    };
+
+    let file = File::open(path).with_context(|| format!("failed to open {path:?}"))?;
+    let output = Command::new("ssh-keygen")
+        .args(["-Y", "verify", "-n", SIGNATURE_NAMESPACE, "-f"])
+        .arg(allowed_signers)
+        .arg("-I")
+        .arg(principal)
+        .arg("-s")
+        .arg(signature)
+        .stdin(file)
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .scoped_spawn()?
+        .wait_with_output()?;
+    if !output.status.success() {
+        return Err(anyhow!(
+            "`ssh-keygen -Y verify` exited with {}: {}",
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    Ok(())
+}
+
+/// AWS-style credentials for S3-compatible storage.
+struct Credentials {
+    access_key_id: String,
+    secret_access_key: String,
+    session_token: Option<String>,
+}
+
+impl Credentials {
+    fn from_env() -> Result<Self> {
+        let var = |name: &str| {
+            std::env::var(name)
+                .with_context(|| format!("remote cache needs ${name} set for `s3_region`"))
+        };
+        Ok(Self {
+            access_key_id: var("AWS_ACCESS_KEY_ID")?,
+            secret_access_key: var("AWS_SECRET_ACCESS_KEY")?,
+            session_token: std::env::var("AWS_SESSION_TOKEN").ok(),
+        })
+    }
+}
+
+/// Returns the headers that sign an S3 request with AWS Signature Version 4.
+///
+/// The request body is not covered by the signature, which S3 allows over
+/// HTTPS.
+fn sign_s3(
+    method: &Method,
+    url: &Url,
+    region: &str,
+    credentials: &Credentials,
+    now: SystemTime,
+) -> Result<Vec<(&'static str, String)>> {
+    let host = match (url.host_str(), url.port()) {
+        (Some(host), Some(port)) => format!("{host}:{port}"),
+        (Some(host), None) => host.to_owned(),
+        (None, _) => return Err(anyhow!("remote cache URL has no host: {url}")),
+    };
+    let timestamp = amz_date(now);
+    let date = &timestamp[..8];
+    let mut headers = vec![
+        ("host", host),
+        ("x-amz-content-sha256", String::from("UNSIGNED-PAYLOAD")),
+        ("x-amz-date", timestamp.clone()),
+    ];
+    if let Some(token) = &credentials.session_token {
+        headers.push(("x-amz-security-token", token.clone()));
+    }
+    let signed_headers = headers
+        .iter()
+        .map(|(header, _)| *header)
+        .collect::<Vec<_>>()
+        .join(";");
+    let canonical_request = format!(
+        "{method}\n{}\n{}\n{}\n{signed_headers}\nUNSIGNED-PAYLOAD",
+        url.path(),
+        url.query().unwrap_or(""),
+        headers
+            .iter()
+            .map(|(header, value)| format!("{header}:{value}\n"))
+            .collect::<String>(),
+    );
+    let scope = format!("{date}/{region}/s3/aws4_request");
+    let string_to_sign = format!(
+        "AWS4-HMAC-SHA256\n{timestamp}\n{scope}\n{}",
+        hex(&Sha256::digest(canonical_request))
+    );
+    let mut key = hmac_sha256(
+        format!("AWS4{}", credentials.secret_access_key).as_bytes(),
+        date.as_bytes(),
+    );
+    for part in [region, "s3", "aws4_request"] {
+        key = hmac_sha256(&key, part.as_bytes());
+    }
+    let signature = hex(&hmac_sha256(&key, string_to_sign.as_bytes()));
+    // reqwest sets the host header itself.
+    headers.remove(0);
+    headers.push((
+        "authorization",
+        format!(
+            "AWS4-HMAC-SHA256 Credential={}/{scope}, SignedHeaders={signed_headers}, \
+            Signature={signature}",
+            credentials.access_key_id
+        ),
+    ));
+    Ok(headers)
+}
+
+/// Formats the time like `20231114T221320Z`, in UTC.
+fn amz_date(time: SystemTime) -> String {
+    let secs = time
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let (days, secs) = (secs / 86_400, secs % 86_400);
+    // This is Howard Hinnant's `civil_from_days` algorithm.
+    let z = days + 719_468;
+    let era = z / 146_097;
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + u64::from(month <= 2);
+    format!(
+        "{year:04}{month:02}{day:02}T{:02}{:02}{:02}Z",
+        secs / 3600,
+        secs / 60 % 60,
+        secs % 60
+    )
+}
+
+fn hmac_sha256(key: &[u8], data: &[u8]) -> [u8; 32] {
+    let mut block = [0u8; 64];
+    if key.len() > block.len() {
+        block[..32].copy_from_slice(&Sha256::digest(key));
+    } else {
+        block[..key.len()].copy_from_slice(key);
+    }
+    let mut inner = Sha256::new();
+    inner.update(block.map(|b| b ^ 0x36));
+    inner.update(data);
+    let mut outer = Sha256::new();
+    outer.update(block.map(|b| b ^ 0x5c));
+    outer.update(inner.finalize());
+    outer.finalize().into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeMap;
+    use std::io::{BufRead, BufReader, Read, Seek, Write};
+    use std::net::TcpListener;
+    use std::sync::{Arc, Mutex};
+
+    #[test]
+    fn amz_date() {
+        let at = |secs: u64| super::amz_date(UNIX_EPOCH + Duration::from_secs(secs));
+        assert_eq!(at(0), "19700101T000000Z");
+        assert_eq!(at(1_700_000_000), "20231114T221320Z");
+        assert_eq!(at(951_782_400), "20000229T000000Z");
+    }
+
+    #[test]
+    fn hmac_sha256() {
+        // From RFC 4231.
+        assert_eq!(
+            hex(&super::hmac_sha256(
+                b"Jefe",
+                b"what do ya want for nothing?"
+            )),
+            "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+        assert_eq!(
+            hex(&super::hmac_sha256(
+                &[0xaa; 131],
+                b"Test Using Larger Than Block-Size Key - Hash Key First"
+            )),
+            "60e431591ee0b67f0d8a26aacbf5b77f8e0bc6213728c5140546040f0ee37f54"
+        );
+    }
+
+    #[test]
+    fn sign_s3() {
+        let credentials = Credentials {
+            access_key_id: String::from("AKIDEXAMPLE"),
+            secret_access_key: String::from("secret"),
+            session_token: None,
+        };
+        let url = Url::parse("https://s3.example.com:9000/bucket/x86_64/abc.tar").unwrap();
+        let headers = super::sign_s3(
+            &Method::GET,
+            &url,
+            "us-east-1",
+            &credentials,
+            UNIX_EPOCH + Duration::from_secs(1_700_000_000),
+        )
+        .unwrap();
+        let names: Vec<&str> = headers.iter().map(|(name, _)| *name).collect();
+        assert_eq!(
+            names,
+            ["x-amz-content-sha256", "x-amz-date", "authorization"]
+        );
+        let authorization = &headers[2].1;
+        assert!(
+            authorization.starts_with(
+                "AWS4-HMAC-SHA256 Credential=AKIDEXAMPLE/20231114/us-east-1/s3/aws4_request, \
+                SignedHeaders=host;x-amz-content-sha256;x-amz-date, Signature="
+            ),
+            "{authorization}"
+        );
+    }
+
+    /// Object contents by path.
+    type Objects = Arc<Mutex<BTreeMap<String, Vec<u8>>>>;
+
+    /// Serves `GET` and `PUT` requests from memory, for `count` requests.
+    fn serve(count: usize) -> (String, Objects) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/cache/", listener.local_addr().unwrap());
+        let objects = Arc::new(Mutex::new(BTreeMap::new()));
+        let server_objects = objects.clone();
+        std::thread::spawn(move || {
+            for stream in listener.incoming().take(count) {
+                let mut stream = stream.unwrap();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut request_line = String::new();
+                reader.read_line(&mut request_line).unwrap();
+                let mut words = request_line.split(' ');
+                let (method, path) = (words.next().unwrap(), words.next().unwrap());
+                let mut length = 0;
+                loop {
+                    let mut line = String::new();
+                    reader.read_line(&mut line).unwrap();
+                    if line == "\r\n" {
+                        break;
+                    }
+                    if let Some((name, value)) = line.split_once(':') {
+                        if name.eq_ignore_ascii_case("content-length") {
+                            length = value.trim().parse().unwrap();
+                        }
+                    }
+                }
+                let mut objects = server_objects.lock().unwrap();
+                let (status, body) = match method {
+                    "PUT" => {
+                        let mut body = vec![0; length];
+                        reader.read_exact(&mut body).unwrap();
+                        objects.insert(path.to_owned(), body);
+                        ("200 OK", Vec::new())
+                    }
+                    _ => match objects.get(path) {
+                        Some(body) => ("200 OK", body.clone()),
+                        None => ("404 Not Found", Vec::new()),
+                    },
+                };
+                write!(
+                    stream,
+                    "HTTP/1.1 {status}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                    body.len()
+                )
+                .unwrap();
+                stream.write_all(&body).unwrap();
+            }
+        });
+        (url, objects)
+    }
+
+    #[test]
+    fn sign_and_verify() {
+        let dir = tempfile::tempdir().unwrap();
+        let key = dir.path().join("key");
+        let status = Command::new("ssh-keygen")
+            .args(["-q", "-t", "ed25519", "-N", "", "-C", "builder", "-f"])
+            .arg(&key)
+            .stdin(Stdio::null())
+            .status();
+        if !status.is_ok_and(|status| status.success()) {
+            // `ssh-keygen` isn't installed.
+            return;
+        }
+        let public = std::fs::read_to_string(key.with_extension("pub")).unwrap();
+        let allowed_signers = dir.path().join("allowed_signers");
+        std::fs::write(&allowed_signers, format!("builder@example.com {public}")).unwrap();
+        let build = dir.path().join("build.tar");
+        std::fs::write(&build, "package files").unwrap();
+        let signature = dir.path().join("build.tar.sig");
+        std::fs::write(&signature, super::sign(&build, &key).unwrap()).unwrap();
+
+        verify_signature(&build, &signature, &allowed_signers).unwrap();
+        std::fs::write(&build, "tampered files").unwrap();
+        assert!(verify_signature(&build, &signature, &allowed_signers).is_err());
+        std::fs::write(&allowed_signers, "").unwrap();
+        std::fs::write(&build, "package files").unwrap();
+        assert!(verify_signature(&build, &signature, &allowed_signers).is_err());
+    }
+
+    #[test]
+    fn get_and_put() {
+        let (url, objects) = serve(3);
+        let config = config::RemoteCache {
+            url,
+            upload: true,
+            s3_region: None,
+            signing_key: None,
+            allowed_signers: None,
+        };
+        let cache = RemoteCache::new(&config).unwrap();
+        let mut file = tempfile::tempfile().unwrap();
+        assert!(!cache.get("x86_64/abc.tar", &mut file).unwrap());
+
+        let mut build = tempfile::tempfile().unwrap();
+        build.write_all(b"package files").unwrap();
+        build.rewind().unwrap();
+        cache.put("x86_64/abc.tar", build).unwrap();
+        assert_eq!(
+            objects.lock().unwrap().keys().collect::<Vec<_>>(),
+            ["/cache/x86_64/abc.tar"]
+        );
+
+        assert!(cache.get("x86_64/abc.tar", &mut file).unwrap());
+        file.rewind().unwrap();
+        let mut contents = String::new();
+        file.read_to_string(&mut contents).unwrap();
+        assert_eq!(contents, "package files");
+    }
+}
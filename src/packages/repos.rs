@@ -0,0 +1,231 @@
+//! Fetches package sources from git repositories and tar file URLs.
+//!
+//! Cubicle keeps a copy of each of the
+//! [`Config::package_repos`](crate::config::Config::package_repos) in its
+//! cache directory. The copies are scanned like local package directories,
+//! with the repository's configured name as the origin of their packages.
+
+use std::fs::File;
+use tracing::info;
+
+use super::Cubicle;
+use crate::command_ext::{Command, Stdio};
+use crate::config::PackageRepo;
+use crate::encoding::FilenameEncoder;
+use crate::fs_util::{try_exists, try_iterdir};
+use crate::lock::LockFile;
+use crate::somehow::{somehow as anyhow, warn, Context, Result};
+use crate::HostPath;
+
+impl Cubicle {
+    /// Returns the directory that holds the copies of package repositories.
+    fn package_repos_dir(&self) -> HostPath {
+        self.shared.cache_dir.join("package-repos")
+    }
+
+    /// Returns the configured package repositories as `(origin, directory)`
+    /// pairs, fetching or updating their copies first as needed.
+    ///
+    /// A repository that can't be fetched is left out with a warning, and
+    /// one that can't be updated, such as when offline, keeps its existing
+    /// copy.
+    pub(super) fn package_repo_sources(&self) -> Result<Vec<(String, HostPath)>> {
+        let repos = &self.shared.config.package_repos;
+        if repos.is_empty() {
+            return Ok(Vec::new());
+        }
+        let dir = self.package_repos_dir();
+        std::fs::create_dir_all(dir.as_host_raw())
+            .with_context(|| format!("failed to create directory {dir}"))?;
+        let _lock = LockFile::acquire(dir.join("lock"), "package repositories")?;
+        let mut sources = Vec::new();
+        for (name, repo) in repos {
+            match self.update_package_repo(&dir, repo) {
+                Ok(copy) => sources.push((name.clone(), copy)),
+                Err(e) => warn(e.context(format!("skipping package repository {name:?}"))),
+            }
+        }
+        Ok(sources)
+    }
+
+    /// Fetches the repository if there's no copy of it yet or if the copy is
+    /// due for an update. Returns the copy's directory.
+    fn update_package_repo(&self, dir: &HostPath, repo: &PackageRepo) -> Result<HostPath> {
+        let url = match repo {
+            PackageRepo::Git(url) | PackageRepo::Tar(url) => url,
+        };
+        // Each URL gets its own copy, so changing the config doesn't mix up
+        // repositories.
+        let copy = dir.join(FilenameEncoder::new().push(url).encode());
+        let stamp = dir.join(FilenameEncoder::new().push(url).push(".fetched").encode());
+        let exists = try_exists(&copy).with_context(|| format!("failed to check for {copy}"))?;
+        if exists && !self.package_repo_due(&stamp) {
+            return Ok(copy);
+        }
+
+        if !exists {
+            info!("Fetching packages from {url}");
+        }
+        let result = match repo {
+            PackageRepo::Git(url) => fetch_git(url, &copy, exists),
+            PackageRepo::Tar(url) => fetch_tar(url, dir, &copy),
+        };
+        match result {
+            Ok(()) => {
+                if let Err(e) = File::create(stamp.as_host_raw()) {
+                    warn(anyhow!("failed to create file {stamp}: {e}"));
+                }
+            }
+            Err(e) if exists => warn(e.context(format!(
+                "failed to update packages from {url:?}; using the existing copy"
+            ))),
+            Err(e) => return Err(e),
+        }
+        Ok(copy)
+    }
+
+    /// Returns true if the copy of a repository whose last fetch is recorded
+    /// in `stamp` should be updated, according to
+    /// [`Config::auto_update`](crate::config::Config::auto_update).
+    fn package_repo_due(&self, stamp: &HostPath) -> bool {
+        let Some(interval) = self.shared.config.auto_update else {
+            return false;
+        };
+        let fetched = std::fs::metadata(stamp.as_host_raw()).and_then(|m| m.modified());
+        let Ok(fetched) = fetched else {
+            return true;
+        };
+        let now = self.shared.clock.now();
+        // A fetch in the future means the clock went backwards.
+        fetched > now || fetched + interval <= now
+    }
+}
+
+/// Clones the git repository into `copy`, or pulls new commits into an
+/// existing clone.
+fn fetch_git(url: &str, copy: &HostPath, exists: bool) -> Result<()> {
+    let status = if exists {
+        Command::new("git")
+            .arg("-C")
+            .arg(copy.as_host_raw())
+            .args(["pull", "--quiet", "--ff-only"])
+            .stdin(Stdio::null())
+            .status()?
+    } else {
+        Command::new("git")
+            .args(["clone", "--quiet", "--depth", "1", "--"])
+            .arg(url)
+            .arg(copy.as_host_raw())
+            .stdin(Stdio::null())
+            .status()?
+    };
+    if !status.success() {
+        return Err(anyhow!(
+            "`git {}` exited with {status}",
+            if exists { "pull" } else { "clone" }
+        ));
+    }
+    Ok(())
+}
+
+/// Downloads the tar file and replaces `copy` with its contents.
+fn fetch_tar(url: &str, dir: &HostPath, copy: &HostPath) -> Result<()> {
+    let mut download = tempfile::NamedTempFile::new_in(dir.as_host_raw())
+        .with_context(|| format!("failed to create temporary file in {dir}"))?;
+    let mut response = reqwest::blocking::get(url)
+        .and_then(|response| response.error_for_status())
+        .with_context(|| format!("failed to download {url:?}"))?;
+    response
+        .copy_to(download.as_file_mut())
+        .with_context(|| format!("failed to download {url:?}"))?;
+
+    let extracted = tempfile::TempDir::new_in(dir.as_host_raw())
+        .with_context(|| format!("failed to create temporary directory in {dir}"))?;
+    // The `tar` program detects compression on its own.
+    let status = Command::new("tar")
+        .arg("-xf")
+        .arg(download.path())
+        .arg("-C")
+        .arg(extracted.path())
+        .stdin(Stdio::null())
+        .status()?;
+    if !status.success() {
+        return Err(anyhow!(
+            "failed to extract {url:?}: `tar` exited with {status}"
+        ));
+    }
+    let root = tar_root(&HostPath::try_from(extracted.path().to_owned())?)?;
+
+    if try_exists(copy).with_context(|| format!("failed to check for {copy}"))? {
+        std::fs::remove_dir_all(copy.as_host_raw())
+            .with_context(|| format!("failed to remove old copy {copy}"))?;
+    }
+    std::fs::rename(root.as_host_raw(), copy.as_host_raw())
+        .with_context(|| format!("failed to move extracted files to {copy}"))?;
+    Ok(())
+}
+
+/// Returns the directory within an extracted tar file that holds the
+/// package definitions: the only entry if it's a directory, or else the top
+/// level.
+fn tar_root(extracted: &HostPath) -> Result<HostPath> {
+    let entries = try_iterdir(extracted)?;
+    if let [entry] = entries.as_slice() {
+        let path = extracted.join(entry);
+        if path.as_host_raw().is_dir() {
+            return Ok(path);
+        }
+    }
+    Ok(extracted.clone())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+
+    #[test]
+    fn tar_root() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = HostPath::try_from(dir.path().to_owned()).unwrap();
+        std::fs::create_dir(dir.path().join("repo-main")).unwrap();
+        assert_eq!(
+            super::tar_root(&path).unwrap().as_host_raw(),
+            dir.path().join("repo-main")
+        );
+        std::fs::create_dir(dir.path().join("rust")).unwrap();
+        assert_eq!(super::tar_root(&path).unwrap().as_host_raw(), dir.path());
+    }
+
+    #[test]
+    fn package_repo_sources() {
+        let root = tempfile::tempdir().unwrap();
+        let cub = testing::cubicle(
+            root.path(),
+            "package_repos.team = { git = 'https://example.com/packages.git' }",
+        );
+        let url = "https://example.com/packages.git";
+        let copy = cub
+            .package_repos_dir()
+            .join(FilenameEncoder::new().push(url).encode());
+        std::fs::create_dir_all(copy.join("tool").as_host_raw()).unwrap();
+        std::fs::write(copy.join("tool/package.toml").as_host_raw(), "").unwrap();
+        std::fs::create_dir_all(copy.join(".git").as_host_raw()).unwrap();
+        let stamp = cub
+            .package_repos_dir()
+            .join(FilenameEncoder::new().push(url).push(".fetched").encode());
+        File::create(stamp.as_host_raw()).unwrap();
+
+        // A recently fetched copy is used without fetching it again.
+        let sources = cub.package_repo_sources().unwrap();
+        assert_eq!(sources.len(), 1);
+        assert_eq!(sources[0].0, "team");
+        assert_eq!(sources[0].1.as_host_raw(), copy.as_host_raw());
+
+        let specs = cub.scan_packages().unwrap();
+        let tool = specs
+            .get(&crate::PackageName::strict_from_str("tool").unwrap())
+            .unwrap();
+        assert_eq!(tool.origin, "team");
+    }
+}
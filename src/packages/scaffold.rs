@@ -0,0 +1,242 @@
+//! Creates new local packages from templates, for `cub package new`.
+
+use std::collections::BTreeSet;
+use std::io::{self, BufRead, IsTerminal, Write};
+use std::os::unix::fs::PermissionsExt;
+use std::str::FromStr;
+
+use super::{Cubicle, FullPackageName, PackageName, PackageNamespace};
+use crate::fs_util::try_exists;
+use crate::somehow::{somehow as anyhow, warn, Context, Result};
+use crate::HostPath;
+
+/// What to put in a new package. See [`Cubicle::new_package`].
+///
+/// Fields left as `None` are asked for on the terminal, if stdin is one, or
+/// else get their defaults.
+#[derive(Debug, Default)]
+pub struct NewPackage {
+    /// The local package directory to create the package in, like
+    /// `"local"`. Defaults to the local package directory that's searched
+    /// first, or `"local"` if there are none.
+    pub source: Option<String>,
+    /// A one-line description for `cub package list`. Defaults to none.
+    pub description: Option<String>,
+    /// The packages that the new package depends on. Defaults to none.
+    pub depends: Option<BTreeSet<FullPackageName>>,
+}
+
+const BUILD_SH: &str = r#"#!/bin/sh
+set -eu
+
+# This runs in the package's builder environment, with the package's files in
+# ~/w. Download, build, or configure the package in the home directory here,
+# then list the paths that environments should get in provides.txt.
+
+tar -c -C ~ --verbatim-files-from --files-from ~/w/provides.txt -f ~/provides.tar
+"#;
+
+const TEST_SH: &str = r#"#!/bin/sh
+set -eu
+
+# This runs in a new environment with the package and its dependencies. Exit
+# with an error if the package doesn't work.
+
+true
+"#;
+
+impl Cubicle {
+    /// Corresponds to `cub package new`.
+    ///
+    /// Creates a directory for a new package in one of the local package
+    /// directories, with a `package.toml` manifest and template `build.sh`
+    /// and `test.sh` scripts. Returns the new package's directory.
+    pub fn new_package(&self, name: &str, mut new: NewPackage) -> Result<HostPath> {
+        let name = &PackageName::strict_from_str(name)?;
+        let interactive = io::stdin().is_terminal();
+        let ask = |question: &str| -> Result<Option<String>> {
+            if !interactive {
+                return Ok(None);
+            }
+            ask(question, &mut io::stdin().lock(), &mut io::stdout())
+        };
+
+        if new.source.is_none() {
+            let default = self.default_local_source()?;
+            new.source =
+                Some(ask(&format!("Local package directory [{default}]: "))?.unwrap_or(default));
+        }
+        if new.description.is_none() {
+            new.description = ask("Description (optional): ")?;
+        }
+        if new.depends.is_none() {
+            let answer = ask("Dependencies, separated by commas (optional): ")?;
+            new.depends = Some(parse_depends(answer.as_deref().unwrap_or(""))?);
+        }
+        let source = new.source.unwrap_or_default();
+        let depends = new.depends.unwrap_or_default();
+
+        PackageName::strict_from_str(&source)
+            .with_context(|| format!("invalid local package directory name: {source:?}"))?;
+        let known = self.get_package_names()?;
+        for dep in &depends {
+            if !matches!(dep.0, PackageNamespace::Distro(_)) && !known.contains(dep) {
+                warn(anyhow!("dependency {dep} is not a known package"));
+            }
+        }
+        let specs = self.scan_packages()?;
+        if let Some(existing) = specs.get(name) {
+            warn(anyhow!(
+                "the new package will hide or be hidden by package {name} from {:?}; \
+                see `{} package which {name}`",
+                existing.origin,
+                self.shared.exe_name,
+            ));
+        }
+
+        let dir = self
+            .shared
+            .user_package_dir
+            .join(&source)
+            .join(name.as_str());
+        if try_exists(&dir).with_context(|| format!("failed to check for {dir}"))? {
+            return Err(anyhow!("{dir} already exists"));
+        }
+        std::fs::create_dir_all(dir.as_host_raw())
+            .with_context(|| format!("failed to create directory {dir}"))?;
+        let write = |file: &str, contents: &str, executable: bool| -> Result<()> {
+            let path = dir.join(file);
+            std::fs::write(path.as_host_raw(), contents)
+                .with_context(|| format!("failed to write {path}"))?;
+            if executable {
+                std::fs::set_permissions(
+                    path.as_host_raw(),
+                    std::fs::Permissions::from_mode(0o755),
+                )
+                .with_context(|| format!("failed to make {path} executable"))?;
+            }
+            Ok(())
+        };
+        write(
+            "package.toml",
+            &manifest(new.description.as_deref(), &depends),
+            false,
+        )?;
+        write("build.sh", BUILD_SH, true)?;
+        write("provides.txt", "", false)?;
+        write("test.sh", TEST_SH, true)?;
+        println!("Created package {name} in {dir}");
+        println!(
+            "Edit build.sh and provides.txt there, then run `{} package update {name}`",
+            self.shared.exe_name
+        );
+        Ok(dir)
+    }
+
+    /// Returns the name of the local package directory that's searched
+    /// first, or `"local"` if there are none.
+    fn default_local_source(&self) -> Result<String> {
+        let user_dir = self.shared.user_package_dir.as_host_raw();
+        Ok(self
+            .package_sources()?
+            .into_iter()
+            .find(|(_, dir)| dir.as_host_raw().parent() == Some(user_dir))
+            .map(|(origin, _)| origin)
+            .unwrap_or_else(|| String::from("local")))
+    }
+}
+
+/// Prints the question to `output` and reads a line from `input`. Returns
+/// `None` for an empty answer.
+fn ask(question: &str, input: &mut dyn BufRead, output: &mut dyn Write) -> Result<Option<String>> {
+    output
+        .write_all(question.as_bytes())
+        .and_then(|()| output.flush())
+        .context("failed to write to stdout")?;
+    let mut line = String::new();
+    input
+        .read_line(&mut line)
+        .context("failed to read answer from stdin")?;
+    let line = line.trim();
+    Ok((!line.is_empty()).then(|| line.to_owned()))
+}
+
+/// Parses a list of package names separated by commas or whitespace.
+fn parse_depends(s: &str) -> Result<BTreeSet<FullPackageName>> {
+    s.split(|c: char| c == ',' || c.is_whitespace())
+        .filter(|word| !word.is_empty())
+        .map(FullPackageName::from_str)
+        .collect()
+}
+
+/// Returns the contents of a new package's `package.toml`.
+fn manifest(description: Option<&str>, depends: &BTreeSet<FullPackageName>) -> String {
+    let mut toml = String::new();
+    if let Some(description) = description {
+        toml.push_str(&format!(
+            "description = {}\n",
+            toml::Value::String(description.to_owned())
+        ));
+    }
+    let key = |s: &str| {
+        if s.chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+        {
+            s.to_owned()
+        } else {
+            toml::Value::String(s.to_owned()).to_string()
+        }
+    };
+    let mut section = None;
+    for FullPackageName(ns, name) in depends {
+        let header = match ns {
+            PackageNamespace::Root => String::from("[depends]"),
+            _ => format!("[depends.{}]", key(ns.as_str())),
+        };
+        if section.as_ref() != Some(&header) {
+            toml.push_str(&format!("\n{header}\n"));
+            section = Some(header);
+        }
+        toml.push_str(&format!("{} = {{}}\n", key(name.as_str())));
+    }
+    toml
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::manifest;
+    use super::*;
+
+    #[test]
+    fn new_package_manifest() {
+        let depends =
+            parse_depends("npm.prettier, asdf go-install.golang.org/x/tools/gopls").unwrap();
+        let toml = manifest(Some("A \"new\" tool"), &depends);
+        assert_eq!(
+            toml,
+            "description = 'A \"new\" tool'\n\
+            \n[depends]\nasdf = {}\n\
+            \n[depends.go-install]\n\"golang.org/x/tools/gopls\" = {}\n\
+            \n[depends.npm]\nprettier = {}\n"
+        );
+        let parsed = manifest::parse(&toml).unwrap();
+        assert_eq!(parsed.description.as_deref(), Some("A \"new\" tool"));
+        assert_eq!(parsed.depends.len(), 3);
+
+        assert_eq!(manifest(None, &BTreeSet::new()), "");
+    }
+
+    #[test]
+    fn ask() {
+        let mut output = Vec::new();
+        assert_eq!(
+            super::ask("Name: ", &mut " x \n".as_bytes(), &mut output).unwrap(),
+            Some(String::from("x"))
+        );
+        assert_eq!(output, b"Name: ");
+        assert_eq!(
+            super::ask("Name: ", &mut "\n".as_bytes(), &mut Vec::new()).unwrap(),
+            None
+        );
+    }
+}
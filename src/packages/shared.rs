@@ -0,0 +1,384 @@
+//! Shared, read-only installs of large packages, like web browsers.
+//!
+//! A package can list `shared_paths` in its manifest. Instead of copying
+//! those paths into every environment's home directory, Cubicle extracts
+//! them once on the host and mounts them read-only into environments, with
+//! a symlink in the home directory pointing to the mount.
+//!
+//! A package can also list `profile_dirs`, such as `.mozilla`. These are
+//! kept in the environment's work directory, with a symlink from the home
+//! directory, so that each environment has its own browser profile that
+//! survives `cub reset`.
+//!
+//! Only the runners that can mount host directories (Bubblewrap and Docker)
+//! support this. Other runners get the package's files copied as usual.
+
+use std::io;
+use std::path::{Path, PathBuf};
+
+use super::{home_relative_path, FullPackageName, PackageNamespace, PackageSpecs};
+use crate::encoding::FilenameEncoder;
+use crate::fs_util::{open_tar, try_exists};
+use crate::lock::LockFile;
+use crate::somehow::{somehow as anyhow, Context, Result};
+use crate::{Cubicle, CubicleShared, EnvironmentName, HostPath, RunnerKind};
+
+/// Where the shared installs are mounted inside environments.
+pub const ENV_PATH: &str = "/run/cubicle-shared";
+
+/// Name of the directory within the work directory that holds the profile
+/// directories.
+const PROFILES_DIR: &str = ".cubicle-profiles";
+
+/// Returns the host directory to mount read-only at [`ENV_PATH`] in every
+/// environment. Creates the directory if needed.
+pub fn shared_mount(program: &CubicleShared) -> Result<HostPath> {
+    let dir = program.cache_dir.join("shared");
+    std::fs::create_dir_all(dir.as_host_raw())
+        .with_context(|| format!("failed to create shared package directory {dir}"))?;
+    Ok(dir)
+}
+
+/// The paths from a package manifest's `shared_paths` and `profile_dirs`.
+struct Layout {
+    shared: Vec<PathBuf>,
+    profiles: Vec<PathBuf>,
+}
+
+impl Layout {
+    fn new(name: &FullPackageName, shared: &[String], profiles: &[String]) -> Result<Self> {
+        let convert = |key: &str, paths: &[String]| -> Result<Vec<PathBuf>> {
+            paths
+                .iter()
+                .map(|path| {
+                    home_relative_path(Path::new(path)).ok_or_else(|| {
+                        anyhow!("package {name} has invalid path in {key}: {path:?}")
+                    })
+                })
+                .collect()
+        };
+        Ok(Self {
+            shared: convert("shared_paths", shared)?,
+            profiles: convert("profile_dirs", profiles)?,
+        })
+    }
+
+    fn is_shared(&self, path: &Path) -> bool {
+        self.shared.iter().any(|shared| path.starts_with(shared))
+    }
+
+    fn is_profile(&self, path: &Path) -> bool {
+        self.profiles
+            .iter()
+            .any(|profile| path.starts_with(profile))
+    }
+}
+
+/// Returns a relative symlink target that leads from the home-relative path
+/// `link` to the home-relative path `target`.
+fn relative_link(link: &Path, target: &Path) -> PathBuf {
+    let depth = link.components().count();
+    let mut path = PathBuf::new();
+    for _ in 1..depth {
+        path.push("..");
+    }
+    path.join(target)
+}
+
+impl Cubicle {
+    /// Returns the tar file to seed an environment with the given package,
+    /// if the package has been built.
+    ///
+    /// This is usually the package's `provides.tar`. For packages with
+    /// `shared_paths` or `profile_dirs`, this extracts the shared paths on
+    /// the host and returns a rewritten tar file with symlinks instead.
+    pub(super) fn package_seed(
+        &self,
+        name: &FullPackageName,
+        specs: &PackageSpecs,
+        env_name: &EnvironmentName,
+    ) -> Result<Option<HostPath>> {
+        let platform = self.env_platform(env_name)?;
+        let provides = self.package_tar(name, &platform);
+        if !try_exists(&provides).todo_context()? {
+            return Ok(None);
+        }
+        self.verify_package_tar(name, &platform)?;
+        let manifest = match (&name.0, specs.get(&name.1)) {
+            (PackageNamespace::Root, Some(spec)) => &spec.manifest,
+            _ => return Ok(Some(provides)),
+        };
+        if (manifest.shared_paths.is_empty() && manifest.profile_dirs.is_empty())
+            || self.shared.config.runner == RunnerKind::User
+        {
+            return Ok(Some(provides));
+        }
+        let layout = Layout::new(name, &manifest.shared_paths, &manifest.profile_dirs)?;
+
+        let encoded = FilenameEncoder::new().push(&name.unquoted()).encode();
+        let dir = shared_mount(&self.shared)?
+            .join(platform.arch())
+            .join(&encoded);
+        let work_dir_name = self.shared.config.work_dir_name(env_name);
+        let seed = dir.join(
+            FilenameEncoder::new()
+                .push("seed-")
+                .push(work_dir_name)
+                .push(".tar")
+                .encode(),
+        );
+
+        let _lock = LockFile::acquire(dir.join("lock"), &format!("shared package {name}"))?;
+        let modified = |path: &HostPath| {
+            std::fs::metadata(path.as_host_raw()).and_then(|metadata| metadata.modified())
+        };
+        let provides_modified =
+            modified(&provides).with_context(|| format!("failed to stat {provides}"))?;
+        match modified(&seed) {
+            Ok(seed_modified) if seed_modified >= provides_modified => return Ok(Some(seed)),
+            Ok(_) => {}
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e).with_context(|| format!("failed to stat {seed}")),
+        }
+
+        self.extract_shared(&provides, &layout, &dir)
+            .with_context(|| format!("failed to extract shared paths for package {name}"))?;
+        let env_files = Path::new(ENV_PATH)
+            .join(platform.arch())
+            .join(encoded)
+            .join("files");
+        write_seed(&provides, &layout, &env_files, work_dir_name, &seed)
+            .with_context(|| format!("failed to write seed for package {name}"))?;
+        Ok(Some(seed))
+    }
+
+    /// Extracts the shared paths from the package's tar file into
+    /// `{dir}/files`, replacing any previous version.
+    fn extract_shared(&self, provides: &HostPath, layout: &Layout, dir: &HostPath) -> Result<()> {
+        let files = dir.join("files");
+        let new = dir.join("files.new");
+        let old = dir.join("files.old");
+        for path in [&new, &old] {
+            if try_exists(path).todo_context()? {
+                std::fs::remove_dir_all(path.as_host_raw())
+                    .with_context(|| format!("failed to remove {path}"))?;
+            }
+        }
+        std::fs::create_dir(new.as_host_raw())
+            .with_context(|| format!("failed to create {new}"))?;
+
+        let mut archive = tar::Archive::new(open_tar(provides)?);
+        for entry in archive
+            .entries()
+            .with_context(|| format!("failed to read {provides}"))?
+        {
+            let mut entry = entry.with_context(|| format!("failed to read {provides}"))?;
+            let path = entry
+                .path()
+                .with_context(|| format!("failed to read {provides}"))?;
+            if home_relative_path(&path).is_some_and(|path| layout.is_shared(&path)) {
+                entry
+                    .unpack_in(new.as_host_raw())
+                    .with_context(|| format!("failed to extract {provides} to {new}"))?;
+            }
+        }
+
+        if try_exists(&files).todo_context()? {
+            std::fs::rename(files.as_host_raw(), old.as_host_raw())
+                .with_context(|| format!("failed to rename {files} to {old}"))?;
+        }
+        std::fs::rename(new.as_host_raw(), files.as_host_raw())
+            .with_context(|| format!("failed to rename {new} to {files}"))?;
+        if try_exists(&old).todo_context()? {
+            std::fs::remove_dir_all(old.as_host_raw())
+                .with_context(|| format!("failed to remove {old}"))?;
+        }
+        Ok(())
+    }
+}
+
+/// Copies the package's tar file to `seed`, leaving out the shared paths and
+/// the contents of the profile directories, and adding symlinks in their
+/// place.
+fn write_seed(
+    provides: &HostPath,
+    layout: &Layout,
+    env_files: &Path,
+    work_dir_name: &str,
+    seed: &HostPath,
+) -> Result<()> {
+    let metadata = std::fs::metadata(provides.as_host_raw())
+        .with_context(|| format!("failed to stat {provides}"))?;
+    let mut archive = tar::Archive::new(open_tar(provides)?);
+
+    let seed_dir = seed
+        .as_host_raw()
+        .parent()
+        .expect("seed should have parent");
+    let out = tempfile::NamedTempFile::new_in(seed_dir).todo_context()?;
+    let mut builder = tar::Builder::new(out.as_file());
+
+    for entry in archive
+        .entries()
+        .with_context(|| format!("failed to read {provides}"))?
+    {
+        let mut entry = entry.with_context(|| format!("failed to read {provides}"))?;
+        let path = entry
+            .path()
+            .with_context(|| format!("failed to read {provides}"))?
+            .into_owned();
+        if home_relative_path(&path)
+            .is_some_and(|path| layout.is_shared(&path) || layout.is_profile(&path))
+        {
+            continue;
+        }
+        let mut header = entry.header().clone();
+        match entry
+            .link_name()
+            .with_context(|| format!("failed to read {provides}"))?
+        {
+            Some(target) => {
+                let target = target.into_owned();
+                builder.append_link(&mut header, &path, target)
+            }
+            None => builder.append_data(&mut header, &path, &mut entry),
+        }
+        .with_context(|| format!("failed to write {seed}"))?;
+    }
+
+    let new_header = |entry_type: tar::EntryType, mode: u32| {
+        let mut header = tar::Header::new_gnu();
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::MetadataExt;
+            header.set_mtime(metadata.mtime() as u64);
+            header.set_uid(u64::from(metadata.uid()));
+            header.set_gid(u64::from(metadata.gid()));
+        }
+        header.set_entry_type(entry_type);
+        header.set_mode(mode);
+        header.set_size(0);
+        header
+    };
+
+    for path in &layout.shared {
+        builder
+            .append_link(
+                &mut new_header(tar::EntryType::Symlink, 0o777),
+                Path::new(".").join(path),
+                env_files.join(path),
+            )
+            .with_context(|| format!("failed to write {seed}"))?;
+    }
+    for path in &layout.profiles {
+        let target = Path::new(work_dir_name).join(PROFILES_DIR).join(path);
+        builder
+            .append_data(
+                &mut new_header(tar::EntryType::Directory, 0o700),
+                Path::new(".").join(&target),
+                io::empty(),
+            )
+            .with_context(|| format!("failed to write {seed}"))?;
+        builder
+            .append_link(
+                &mut new_header(tar::EntryType::Symlink, 0o777),
+                Path::new(".").join(path),
+                relative_link(path, &target),
+            )
+            .with_context(|| format!("failed to write {seed}"))?;
+    }
+
+    builder
+        .into_inner()
+        .and_then(|file| file.sync_all())
+        .with_context(|| format!("failed to write {seed}"))?;
+    out.persist(seed.as_host_raw())
+        .with_context(|| format!("failed to write {seed}"))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    #[test]
+    fn relative_link() {
+        let check = |link: &str, target: &str| {
+            super::relative_link(Path::new(link), Path::new(target))
+                .to_string_lossy()
+                .into_owned()
+        };
+        assert_eq!(
+            check(".mozilla", "w/.cubicle-profiles/.mozilla"),
+            "w/.cubicle-profiles/.mozilla"
+        );
+        assert_eq!(
+            check(".config/chromium", "w/.cubicle-profiles/.config/chromium"),
+            "../w/.cubicle-profiles/.config/chromium"
+        );
+    }
+
+    #[test]
+    fn write_seed() {
+        let dir = tempfile::tempdir().unwrap();
+        let dir = HostPath::try_from(dir.path().to_owned()).unwrap();
+        let provides = dir.join("provides.tar");
+        {
+            let file = std::fs::File::create(provides.as_host_raw()).unwrap();
+            let mut builder = tar::Builder::new(file);
+            for (path, data) in [
+                ("./bin/firefox", "#!/bin/sh"),
+                ("./opt/firefox/firefox", "binary"),
+                ("./.mozilla/defaults", "prefs"),
+            ] {
+                let mut header = tar::Header::new_gnu();
+                header.set_size(data.len() as u64);
+                header.set_mode(0o755);
+                builder
+                    .append_data(&mut header, path, data.as_bytes())
+                    .unwrap();
+            }
+            builder.finish().unwrap();
+        }
+
+        let layout = Layout::new(
+            &FullPackageName::from_str("firefox").unwrap(),
+            &[String::from("opt/firefox")],
+            &[String::from(".mozilla")],
+        )
+        .unwrap();
+        let seed = dir.join("seed.tar");
+        super::write_seed(
+            &provides,
+            &layout,
+            Path::new("/run/cubicle-shared/firefox/files"),
+            "w",
+            &seed,
+        )
+        .unwrap();
+
+        let file = std::fs::File::open(seed.as_host_raw()).unwrap();
+        let entries = tar::Archive::new(file)
+            .entries()
+            .unwrap()
+            .map(|entry| {
+                let entry = entry.unwrap();
+                let path = entry.path().unwrap().display().to_string();
+                match entry.link_name().unwrap() {
+                    Some(target) => format!("{path} -> {}", target.display()),
+                    None => path,
+                }
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            entries,
+            [
+                "bin/firefox",
+                "opt/firefox -> /run/cubicle-shared/firefox/files/opt/firefox",
+                "w/.cubicle-profiles/.mozilla",
+                ".mozilla -> w/.cubicle-profiles/.mozilla",
+            ]
+        );
+    }
+}
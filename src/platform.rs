@@ -0,0 +1,143 @@
+//! Target platforms for environments, like `linux/arm64`.
+//!
+//! Environments normally run on the host's architecture. The Docker runner
+//! can also run environments for other architectures (through emulation),
+//! as configured with [`Docker::platforms`](super::config::Docker::platforms).
+//! Packages are built and cached separately for each architecture.
+
+use std::fmt::{self, Display};
+
+use super::config::Config;
+use super::{EnvironmentName, RunnerKind};
+use crate::somehow::{somehow as anyhow, Result};
+
+/// Docker platform names and the corresponding architecture names (as in
+/// [`std::env::consts::ARCH`]).
+const ARCHES: &[(&str, &str)] = &[
+    ("linux/386", "x86"),
+    ("linux/amd64", "x86_64"),
+    ("linux/arm/v7", "arm"),
+    ("linux/arm64", "aarch64"),
+    ("linux/arm64/v8", "aarch64"),
+    ("linux/ppc64le", "powerpc64"),
+    ("linux/riscv64", "riscv64"),
+    ("linux/s390x", "s390x"),
+];
+
+/// The platform that an environment runs on and that packages are built for.
+#[derive(Clone, Debug, Default, Eq, Ord, PartialEq, PartialOrd)]
+pub struct Platform {
+    /// The Docker platform name, or `None` for the host's platform.
+    docker: Option<String>,
+}
+
+impl Platform {
+    /// Returns the host's platform.
+    pub fn host() -> Self {
+        Self::default()
+    }
+
+    /// Parses a Docker platform name, like `linux/arm64`.
+    ///
+    /// Platforms with the same architecture as the host are treated as the
+    /// host's platform.
+    pub fn from_docker(platform: &str) -> Result<Self> {
+        let arch = docker_arch(platform)?;
+        Ok(Self {
+            docker: (arch != std::env::consts::ARCH).then(|| platform.to_owned()),
+        })
+    }
+
+    /// Returns the platform that the environment runs on, according to the
+    /// config.
+    ///
+    /// Package builder and test environments for other platforms have the
+    /// platform in their names (see [`EnvironmentName::for_builder_package`]).
+    pub fn for_environment(config: &Config, name: &EnvironmentName) -> Result<Self> {
+        if config.runner != RunnerKind::Docker {
+            return Ok(Self::host());
+        }
+        if let Some(platform) = config.runners.docker.platforms.get(name.as_str()) {
+            return Self::from_docker(platform);
+        }
+        Ok(name
+            .as_str()
+            .strip_prefix("test-")
+            .unwrap_or(name.as_str())
+            .strip_prefix("package-")
+            .and_then(|rest| rest.rsplit_once('@'))
+            .and_then(|(_, platform)| Self::from_docker(platform).ok())
+            .unwrap_or_default())
+    }
+
+    /// Returns the Docker platform name, or `None` for the host's platform.
+    pub fn docker(&self) -> Option<&str> {
+        self.docker.as_deref()
+    }
+
+    /// Returns the CPU architecture name, as in [`std::env::consts::ARCH`].
+    pub fn arch(&self) -> &'static str {
+        match &self.docker {
+            Some(platform) => docker_arch(platform).expect("platform should be validated"),
+            None => std::env::consts::ARCH,
+        }
+    }
+}
+
+fn docker_arch(platform: &str) -> Result<&'static str> {
+    ARCHES
+        .iter()
+        .find(|(name, _)| *name == platform)
+        .map(|(_, arch)| *arch)
+        .ok_or_else(|| {
+            anyhow!(
+                "unsupported platform {platform:?} (expected one of: {})",
+                ARCHES
+                    .iter()
+                    .map(|(name, _)| *name)
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            )
+        })
+}
+
+impl Display for Platform {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.docker {
+            Some(platform) => f.write_str(platform),
+            None => f.write_str("host"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    #[test]
+    fn for_environment() {
+        let foreign = if std::env::consts::ARCH == "aarch64" {
+            "linux/amd64"
+        } else {
+            "linux/arm64"
+        };
+        let mut config: Config = toml::from_str("runner = 'docker'").unwrap();
+        config
+            .runners
+            .docker
+            .platforms
+            .insert(String::from("pi"), String::from(foreign));
+        let platform = |name: &str| {
+            Platform::for_environment(&config, &EnvironmentName::from_str(name).unwrap())
+                .unwrap()
+                .to_string()
+        };
+        assert_eq!(platform("pi"), foreign);
+        assert_eq!(platform("other"), "host");
+        assert_eq!(platform(&format!("package-rust@{foreign}")), foreign);
+        assert_eq!(platform(&format!("test-package-rust@{foreign}")), foreign);
+        assert_eq!(platform("package-npm-@types/node"), "host");
+        assert_eq!(platform(&format!("other@{foreign}")), "host");
+    }
+}
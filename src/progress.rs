@@ -0,0 +1,115 @@
+//! Reports progress on long operations to other programs.
+//!
+//! With `--progress json`, Cubicle writes newline-delimited JSON events to
+//! stderr, so that wrappers and editor integrations can show their own
+//! progress UI around long `cub new` runs. The human-readable messages on
+//! stdout are unaffected.
+
+use serde::Serialize;
+use std::time::{Duration, UNIX_EPOCH};
+
+use super::{CubicleShared, EnvironmentName, FullPackageName};
+use crate::somehow::{warn, Context};
+
+/// Allowed formats for [`Cubicle::set_progress_format`](super::Cubicle::set_progress_format).
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+pub enum ProgressFormat {
+    /// Only print human-readable messages.
+    #[default]
+    Default,
+    /// Also write newline-delimited JSON events to stderr.
+    Json,
+}
+
+/// Something that happened during a long operation.
+///
+/// Each event is serialized as a JSON object with an `"event"` field naming
+/// its kind, like `"package-build-started"`, and a `"time"` field in seconds
+/// since the Unix epoch. New kinds of events and fields may be added.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "kebab-case", tag = "event")]
+pub(super) enum Event<'a> {
+    /// Cubicle started building a package.
+    PackageBuildStarted {
+        package: &'a FullPackageName,
+        /// The Docker platform, if building for a non-default one.
+        platform: Option<&'a str>,
+    },
+    /// Cubicle finished building a package, successfully or not.
+    PackageBuildFinished {
+        package: &'a FullPackageName,
+        platform: Option<&'a str>,
+        success: bool,
+        seconds: f64,
+    },
+    /// The runner finished copying packages and other files into an
+    /// environment.
+    SeedCopied { environment: &'a EnvironmentName },
+    /// The runner created an environment, such as a Docker container.
+    EnvironmentCreated { environment: &'a EnvironmentName },
+}
+
+/// An [`Event`] with its time, as written to stderr.
+#[derive(Serialize)]
+struct Timestamped<'a> {
+    time: f64,
+    #[serde(flatten)]
+    event: Event<'a>,
+}
+
+impl CubicleShared {
+    /// Reports the event, if enabled.
+    pub(super) fn progress(&self, event: Event) {
+        if *self.progress.lock().unwrap() != ProgressFormat::Json {
+            return;
+        }
+        let time = self
+            .clock
+            .now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or(Duration::ZERO)
+            .as_secs_f64();
+        match serde_json::to_string(&Timestamped { time, event })
+            .context("failed to serialize progress event")
+        {
+            Ok(json) => eprintln!("{json}"),
+            Err(e) => warn(e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    #[test]
+    fn serialize() {
+        let package = FullPackageName::from_str("rust").unwrap();
+        let environment = EnvironmentName::from_str("web").unwrap();
+        let json = |event| serde_json::to_string(&Timestamped { time: 1.5, event }).unwrap();
+        assert_eq!(
+            r#"{"time":1.5,"event":"package-build-started","package":"rust","platform":null}"#,
+            json(Event::PackageBuildStarted {
+                package: &package,
+                platform: None,
+            })
+        );
+        assert_eq!(
+            r#"{"time":1.5,"event":"package-build-finished","package":"rust","platform":"linux/arm64","success":true,"seconds":2.25}"#,
+            json(Event::PackageBuildFinished {
+                package: &package,
+                platform: Some("linux/arm64"),
+                success: true,
+                seconds: 2.25,
+            })
+        );
+        assert_eq!(
+            r#"{"time":1.5,"event":"environment-created","environment":"web"}"#,
+            json(Event::EnvironmentCreated {
+                environment: &environment,
+            })
+        );
+    }
+}
@@ -0,0 +1,117 @@
+//! Finds and removes things that no environment or package uses anymore.
+//!
+//! Cubicle already cleans up after crashes on startup, but only where that's
+//! clearly safe. `cub prune` goes further: it lists leftover package builder
+//! and test environments and anything the runner left behind, then asks
+//! before removing them.
+
+use tracing::info;
+
+use super::lock::LockFile;
+use super::runner::Runner;
+use super::{Cubicle, DryRun};
+use crate::somehow::{somehow as anyhow, warn, Result};
+
+impl Cubicle {
+    /// Corresponds to `cub prune`.
+    ///
+    /// Prints what would be removed, then asks for confirmation before
+    /// removing it. With `dry_run`, only prints it.
+    pub fn prune(&self, dry_run: DryRun) -> Result<()> {
+        // Builder environments are in use while packages are being built.
+        // Holding this lock keeps new builds from starting.
+        let _lock = LockFile::acquire(
+            self.shared.package_cache.join("update.lock"),
+            "package cache",
+        )?;
+        if self.package_builds_in_progress()? {
+            return Err(anyhow!(
+                "packages are being built, so not pruning (try again once that's done)"
+            ));
+        }
+
+        let builders = self.leftover_builders(&self.get_environment_names()?)?;
+        let orphans = self.runner.orphans()?;
+        let count = builders.len() + orphans.len();
+        if count == 0 {
+            println!("Nothing to prune");
+            return Ok(());
+        }
+        println!("{}:", if dry_run.0 { "Would remove" } else { "Found" });
+        for name in &builders {
+            println!("    leftover package builder environment {name}");
+        }
+        for orphan in &orphans {
+            println!("    {orphan}");
+        }
+        if dry_run.0 {
+            return Ok(());
+        }
+        if !self.shared.confirm("Remove everything listed above?")? {
+            return Err(anyhow!("Not pruning"));
+        }
+
+        let mut failed = 0;
+        for name in &builders {
+            info!("Purging {name}");
+            if let Err(e) = self
+                .runner
+                .purge(name)
+                .and_then(|()| self.purge_metadata(name))
+            {
+                warn(e);
+                failed += 1;
+            }
+        }
+        for orphan in &orphans {
+            info!("Removing {orphan}");
+            if let Err(e) = self.runner.remove_orphan(orphan) {
+                warn(e);
+                failed += 1;
+            }
+        }
+        if failed > 0 {
+            return Err(anyhow!("failed to remove {failed} of {count} leftovers"));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::testing;
+    use crate::{EnvironmentName, FullPackageName, Platform};
+    use std::collections::BTreeSet;
+    use std::str::FromStr;
+
+    #[test]
+    fn leftover_builders() {
+        let root = tempfile::tempdir().unwrap();
+        let cub = testing::cubicle(root.path(), "");
+        let builder = |package: &str| {
+            EnvironmentName::for_builder_package(
+                &FullPackageName::from_str(package).unwrap(),
+                &Platform::host(),
+            )
+        };
+        let names = BTreeSet::from([
+            builder("default"),
+            builder("gone"),
+            builder("npm.prettier"),
+            EnvironmentName::from_str("test-package-default").unwrap(),
+            EnvironmentName::from_str("web").unwrap(),
+        ]);
+        let leftover = cub.leftover_builders(&names).unwrap();
+        assert_eq!(
+            leftover
+                .iter()
+                .map(|name| name.as_str())
+                .collect::<Vec<_>>(),
+            [
+                "package-gone",
+                "package-npm-prettier",
+                "test-package-default"
+            ]
+        );
+    }
+}
@@ -1,5 +1,6 @@
 use rand::seq::SliceRandom;
 use std::io::{self, BufRead};
+use tracing::info;
 
 use super::HostPath;
 use crate::somehow::{somehow as anyhow, warn, Context, Result};
@@ -76,7 +77,7 @@ impl RandomNameGenerator {
         let file = match std::fs::File::open(eff_word_list.as_host_raw()) {
             Ok(file) => file,
             Err(e) if e.kind() == io::ErrorKind::NotFound => {
-                println!("Downloading EFF short wordlist");
+                info!("Downloading EFF short wordlist");
                 let body = reqwest::blocking::get(self.eff_url)
                     .and_then(|resp| resp.text())
                     .with_context(|| {
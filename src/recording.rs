@@ -0,0 +1,348 @@
+//! Records interactive sessions and plays them back.
+//!
+//! `cub enter --record` runs the session under util-linux's `script`, which
+//! gives it a pseudo-terminal and logs its output along with timing
+//! information. Cubicle then converts that log to an
+//! [asciinema](https://asciinema.org) cast file (format version 2) in the
+//! environment's metadata directory, so it can be played back with
+//! `cub replay` or with `asciinema play`.
+
+use serde::{Deserialize, Serialize};
+use std::ffi::OsString;
+use std::io::{self, BufWriter, Write};
+use std::time::{Duration, UNIX_EPOCH};
+use tracing::info;
+
+use super::command_ext::Command;
+use super::fs_util::try_exists;
+use super::runner::{EnvironmentExists, Runner};
+use super::{rel_time, Cubicle, EnvironmentName, HostPath};
+use crate::somehow::{somehow as anyhow, Context, LowLevelResult, Result};
+
+/// Pauses longer than this are shortened during playback.
+const MAX_IDLE: Duration = Duration::from_secs(2);
+
+/// The first line of a cast file.
+#[derive(Debug, Deserialize, Serialize)]
+struct CastHeader {
+    version: u32,
+    width: u16,
+    height: u16,
+    timestamp: u64,
+    title: String,
+}
+
+impl Cubicle {
+    /// Returns the directory on the host that holds the environment's session
+    /// recordings.
+    fn recordings_dir(&self, name: &EnvironmentName) -> HostPath {
+        self.metadata_dir(name).join("recordings")
+    }
+
+    /// Corresponds to `cub enter --record`.
+    ///
+    /// Runs `command` under `script` and saves the recording of its terminal
+    /// output. `command` should be a `cub enter` of the same environment
+    /// without `--record`.
+    pub fn record_session(&self, name: &EnvironmentName, command: &[OsString]) -> Result<()> {
+        match self.runner.exists(name)? {
+            EnvironmentExists::NoEnvironment => {
+                return Err(anyhow!("Environment {name} does not exist"))
+            }
+            EnvironmentExists::PartiallyExists => {
+                return Err(anyhow!(
+                    "Environment {name} in broken state (try '{} reset')",
+                    self.shared.exe_name
+                ))
+            }
+            EnvironmentExists::FullyExists => {}
+        }
+
+        let command = command
+            .iter()
+            .map(|arg| {
+                arg.to_str()
+                    .ok_or_else(|| anyhow!("command to record is not valid UTF-8: {arg:?}"))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let command = shlex::try_join(command).context("failed to quote command to record")?;
+
+        let dir = self.recordings_dir(name);
+        std::fs::create_dir_all(dir.as_host_raw())
+            .with_context(|| format!("failed to create directory {dir}"))?;
+        let log = tempfile::NamedTempFile::new_in(dir.as_host_raw())
+            .with_context(|| format!("failed to create temporary file in {dir}"))?;
+        let timing = tempfile::NamedTempFile::new_in(dir.as_host_raw())
+            .with_context(|| format!("failed to create temporary file in {dir}"))?;
+
+        let started = self
+            .shared
+            .clock
+            .now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let (width, height) = terminal_size();
+        let status = Command::new("script")
+            .args(["--quiet", "--return", "--log-out"])
+            .arg(log.path())
+            .arg("--log-timing")
+            .arg(timing.path())
+            .arg("--command")
+            .arg(command)
+            .status()
+            .context("failed to run `script` to record the session")?;
+
+        let header = CastHeader {
+            version: 2,
+            width,
+            height,
+            timestamp: started,
+            title: format!("{} enter {}", self.shared.exe_name, name.as_str()),
+        };
+        let log = std::fs::read(log.path())
+            .with_context(|| format!("failed to read {:?}", log.path()))?;
+        let timing = std::fs::read_to_string(timing.path())
+            .with_context(|| format!("failed to read {:?}", timing.path()))?;
+        let path = dir.join(format!("{started}.cast"));
+        let mut file = BufWriter::new(
+            std::fs::File::create(path.as_host_raw())
+                .with_context(|| format!("failed to create {path}"))?,
+        );
+        write_cast(&header, &log, &timing, &mut file)
+            .and_then(|()| Ok(file.flush()?))
+            .with_context(|| format!("failed to write {path}"))?;
+        info!("Saved recording {started} of {name}");
+
+        if !status.success() {
+            return Err(anyhow!("recorded session exited with {status}"));
+        }
+        Ok(())
+    }
+
+    /// Returns the names of the environment's recordings, oldest first.
+    fn recordings(&self, name: &EnvironmentName) -> Result<Vec<u64>> {
+        let dir = self.recordings_dir(name);
+        if !try_exists(&dir).todo_context()? {
+            return Ok(Vec::new());
+        }
+        let mut recordings = Vec::new();
+        for entry in std::fs::read_dir(dir.as_host_raw())
+            .with_context(|| format!("failed to read directory {dir}"))?
+        {
+            let entry = entry.with_context(|| format!("failed to read directory {dir}"))?;
+            let file_name = entry.file_name();
+            if let Some(stem) = file_name
+                .to_str()
+                .and_then(|name| name.strip_suffix(".cast"))
+            {
+                if let Ok(recording) = stem.parse() {
+                    recordings.push(recording);
+                }
+            }
+        }
+        recordings.sort_unstable();
+        Ok(recordings)
+    }
+
+    /// Corresponds to `cub replay --list`.
+    pub fn list_recordings(&self, name: &EnvironmentName) -> Result<()> {
+        let now = self.shared.clock.now();
+        for recording in self.recordings(name)? {
+            let started = UNIX_EPOCH + Duration::from_secs(recording);
+            println!(
+                "{recording} ({} ago)",
+                rel_time(now.duration_since(started).ok())
+            );
+        }
+        Ok(())
+    }
+
+    /// Corresponds to `cub replay`.
+    ///
+    /// Plays the given recording, or the most recent one, to stdout.
+    pub fn replay_session(&self, name: &EnvironmentName, recording: Option<u64>) -> Result<()> {
+        let recording = match recording {
+            Some(recording) => recording,
+            None => match self.recordings(name)?.last() {
+                Some(recording) => *recording,
+                None => {
+                    return Err(anyhow!(
+                        "environment {name} has no recordings \
+                        (hint: record one with `{} enter --record`)",
+                        self.shared.exe_name
+                    ))
+                }
+            },
+        };
+        let path = self.recordings_dir(name).join(format!("{recording}.cast"));
+        if !try_exists(&path).todo_context()? {
+            return Err(anyhow!("environment {name} has no recording {recording}"));
+        }
+        let contents = std::fs::read_to_string(path.as_host_raw())
+            .with_context(|| format!("failed to read {path}"))?;
+        play_cast(&contents, &mut io::stdout().lock())
+            .with_context(|| format!("failed to play {path}"))
+    }
+}
+
+/// Returns the width and height of the terminal on stdout, or a common
+/// default if it's not a terminal.
+fn terminal_size() -> (u16, u16) {
+    match rustix::termios::tcgetwinsize(io::stdout()) {
+        Ok(size) if size.ws_col > 0 && size.ws_row > 0 => (size.ws_col, size.ws_row),
+        _ => (80, 24),
+    }
+}
+
+/// Converts the output of `script --log-out` and `--log-timing` to a cast
+/// file.
+fn write_cast(
+    header: &CastHeader,
+    log: &[u8],
+    timing: &str,
+    w: &mut dyn Write,
+) -> LowLevelResult<()> {
+    serde_json::to_writer(&mut *w, header)?;
+    writeln!(w)?;
+
+    // The log starts with a "Script started on ..." line that the timing
+    // doesn't cover.
+    let mut log = match log.iter().position(|b| *b == b'\n') {
+        Some(i) => &log[i + 1..],
+        None => &[],
+    };
+    let mut elapsed = 0.0;
+    let mut pending = Vec::new();
+    for line in timing.lines() {
+        let (delay, len) = line
+            .split_once(' ')
+            .and_then(|(delay, len)| Some((delay.parse::<f64>().ok()?, len.parse::<usize>().ok()?)))
+            .ok_or_else(|| anyhow!("unexpected line in `script` timing file: {line:?}"))?;
+        elapsed += delay;
+        let (chunk, rest) = log.split_at(len.min(log.len()));
+        log = rest;
+        let data = decode_utf8(&mut pending, chunk);
+        if !data.is_empty() {
+            // Round to microseconds, like `asciinema rec` does.
+            let time = (elapsed * 1e6).round() / 1e6;
+            serde_json::to_writer(&mut *w, &(time, "o", data))?;
+            writeln!(w)?;
+        }
+    }
+    Ok(())
+}
+
+/// Decodes `bytes` as UTF-8, replacing invalid sequences.
+///
+/// A character may be split across chunks of terminal output, so a partial
+/// character at the end is kept in `pending` and decoded with the next chunk.
+fn decode_utf8(pending: &mut Vec<u8>, bytes: &[u8]) -> String {
+    pending.extend_from_slice(bytes);
+    let mut decoded = String::new();
+    let mut start = 0;
+    while start < pending.len() {
+        match std::str::from_utf8(&pending[start..]) {
+            Ok(s) => {
+                decoded.push_str(s);
+                start = pending.len();
+            }
+            Err(e) => {
+                let valid = start + e.valid_up_to();
+                decoded.push_str(std::str::from_utf8(&pending[start..valid]).unwrap());
+                match e.error_len() {
+                    Some(len) => {
+                        decoded.push(char::REPLACEMENT_CHARACTER);
+                        start = valid + len;
+                    }
+                    None => {
+                        start = valid;
+                        break;
+                    }
+                }
+            }
+        }
+    }
+    pending.drain(..start);
+    decoded
+}
+
+/// Writes the output events from a cast file to `w`, pausing between them as
+/// recorded (up to [`MAX_IDLE`]).
+fn play_cast(contents: &str, w: &mut dyn Write) -> LowLevelResult<()> {
+    let mut lines = contents.lines();
+    let header: CastHeader = serde_json::from_str(lines.next().unwrap_or_default())
+        .context("failed to parse cast header")?;
+    if header.version != 2 {
+        return Err(anyhow!(
+            "unsupported cast file version {} (expected 2)",
+            header.version
+        )
+        .into());
+    }
+    let mut last = 0.0;
+    for line in lines {
+        let (time, kind, data): (f64, String, String) =
+            serde_json::from_str(line).context("failed to parse cast event")?;
+        if kind != "o" {
+            continue;
+        }
+        std::thread::sleep(Duration::from_secs_f64((time - last).max(0.0)).min(MAX_IDLE));
+        last = time;
+        w.write_all(data.as_bytes())?;
+        w.flush()?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use expect_test::expect;
+
+    #[test]
+    fn decode_utf8() {
+        let mut pending = Vec::new();
+        let snowman = "\u{2603}".as_bytes();
+        assert_eq!("a", super::decode_utf8(&mut pending, &[b'a', snowman[0]]));
+        assert_eq!(vec![snowman[0]], pending);
+        assert_eq!(
+            "\u{2603}b",
+            super::decode_utf8(&mut pending, &[snowman[1], snowman[2], b'b'])
+        );
+        assert!(pending.is_empty());
+        assert_eq!("\u{fffd}c", super::decode_utf8(&mut pending, &[0xff, b'c']));
+        assert!(pending.is_empty());
+    }
+
+    #[test]
+    fn write_cast() {
+        let header = CastHeader {
+            version: 2,
+            width: 80,
+            height: 24,
+            timestamp: 1_700_000_000,
+            title: String::from("cub enter web"),
+        };
+        let log = b"Script started on 2023-11-14 22:13:20+00:00 [COMMAND=\"cub enter web\"]\n\
+            $ ls\r\nwork\r\n\nScript done on 2023-11-14 22:13:21+00:00 [COMMAND_EXIT_CODE=\"0\"]\n";
+        let timing = "0.05 2\n0.025 4\n0.0125 6\n";
+        let mut cast = Vec::new();
+        super::write_cast(&header, log, timing, &mut cast)
+            .enough_context()
+            .unwrap();
+        expect![[r#"
+            {"version":2,"width":80,"height":24,"timestamp":1700000000,"title":"cub enter web"}
+            [0.05,"o","$ "]
+            [0.075,"o","ls\r\n"]
+            [0.0875,"o","work\r\n"]
+        "#]]
+        .assert_eq(&String::from_utf8(cast.clone()).unwrap());
+
+        let mut played = Vec::new();
+        super::play_cast(std::str::from_utf8(&cast).unwrap(), &mut played)
+            .enough_context()
+            .unwrap();
+        assert_eq!(b"$ ls\r\nwork\r\n", played.as_slice());
+    }
+}
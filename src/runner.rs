@@ -1,13 +1,25 @@
+use serde::Serialize;
+use std::ffi::OsStr;
+use std::fmt;
 use std::io;
-use std::path::Path;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::process::{ExitStatus, Output};
+use wildmatch::WildMatch;
 
-use super::fs_util::DirSummary;
+use super::command_ext::{Command, OutputStream};
+use super::config::Config;
+use super::encoding::percent_encode_os_str;
+use super::fs_util::{try_exists, try_iterdir_dirs, DirSummary};
+use super::os_util::get_uids;
 pub(crate) use super::Target;
-use super::{EnvironmentName, HostPath};
-use crate::somehow::{Context, Result};
+use super::{EnvironmentName, ExitStatusError, HostPath, ReadOnlyHome, RunnerKind, WorkspaceName};
+use crate::somehow::{somehow as anyhow, warn, Context, Result};
 
 /// Manages isolated operating system environments.
-pub trait Runner {
+///
+/// Runners may be used from multiple threads at once.
+pub trait Runner: Send + Sync {
     /// Returns a list of existing environments.
     ///
     /// The returned list includes environments that partially exist.
@@ -44,6 +56,23 @@ pub trait Runner {
         w: &mut dyn io::Write,
     ) -> Result<()>;
 
+    /// Writes a tar archive of the file or directory at `path` in the
+    /// environment to `w`. The archive's top-level entry is named after the
+    /// file or directory, and symlinks are archived as symlinks.
+    ///
+    /// The path is relative to the environment's home directory, which
+    /// contains the work directory. The caller must check that it doesn't
+    /// contain `..`. Runners must not let symlinks in the environment lead
+    /// the copy outside it.
+    fn copy_out(&self, name: &EnvironmentName, path: &Path, w: &mut dyn io::Write) -> Result<()>;
+
+    /// Extracts a tar archive from `r` into the existing directory `dir` in
+    /// the environment, as the environment's user.
+    ///
+    /// The directory is relative to the environment's home directory, with
+    /// the same requirements as for [`Runner::copy_out`].
+    fn copy_in(&self, name: &EnvironmentName, dir: &Path, r: &mut dyn io::Read) -> Result<()>;
+
     /// Creates a new environment with the given name.
     ///
     /// Fails if an environment already (partially or fully) exists with that
@@ -58,6 +87,19 @@ pub trait Runner {
     /// the environment.
     fn files_summary(&self, name: &EnvironmentName) -> Result<EnvFilesSummary>;
 
+    /// Returns the sizes of the directories in the environment's home and
+    /// work directories, down to `depth` levels below each.
+    fn directory_sizes(&self, name: &EnvironmentName, depth: usize) -> Result<EnvDirectorySizes>;
+
+    /// Returns how other programs can reach the environment directly, without
+    /// going through Cubicle.
+    fn connect_info(&self, name: &EnvironmentName) -> Result<ConnectInfo>;
+
+    /// Returns the disk space in bytes that the runner uses outside of
+    /// environments' home and work directories, like for base images, along
+    /// with a short description of each kind of use.
+    fn storage_usage(&self) -> Result<Vec<(&'static str, u64)>>;
+
     /// Stops the environment, if running, and any processes running in it.
     ///
     /// Only returns once the environment has been stopped.
@@ -65,6 +107,24 @@ pub trait Runner {
     /// Does not remove the environment's home or work directories.
     fn stop(&self, name: &EnvironmentName) -> Result<()>;
 
+    /// Returns whether the environment is currently running, meaning that
+    /// [`Runner::stop`] would have something to stop.
+    fn is_running(&self, name: &EnvironmentName) -> Result<bool>;
+
+    /// Returns the number of processes running in the environment, not
+    /// counting any that the runner itself uses to keep the environment
+    /// alive.
+    ///
+    /// Returns `None` if the environment isn't running or the runner can't
+    /// tell.
+    fn running_processes(&self, name: &EnvironmentName) -> Result<Option<usize>>;
+
+    /// Saves the state of the processes running in the environment, then
+    /// stops it. The next call to `run` resumes those processes.
+    ///
+    /// Runners that can't do this return an error.
+    fn checkpoint(&self, name: &EnvironmentName) -> Result<()>;
+
     /// Stops the environment, if running, and any processes running in it, and
     /// deletes its home directory except for its work directory.
     ///
@@ -72,6 +132,14 @@ pub trait Runner {
     /// returns an error saying why they can't).
     fn reset(&self, name: &EnvironmentName, init: &Init) -> Result<()>;
 
+    /// Stops the environment, if running, and any processes running in it,
+    /// then copies the seeds into its existing home directory and runs the
+    /// init script again, like [`Runner::reset`] but without deleting
+    /// anything first.
+    ///
+    /// The environment must fully exist already.
+    fn refresh(&self, name: &EnvironmentName, init: &Init) -> Result<()>;
+
     /// Stops the environment, if running, and any processes running in it, and
     /// deletes the environment completely, including its home directory and
     /// work directory.
@@ -79,13 +147,103 @@ pub trait Runner {
     /// This makes partially existing environments no longer exist.
     fn purge(&self, name: &EnvironmentName) -> Result<()>;
 
-    /// Runs a command or interactive shell in the environment.
+    /// Removes anything the runner set up for the workspace, like a shared
+    /// network. This is called after the workspace's environments have been
+    /// purged.
+    fn purge_workspace(&self, name: &WorkspaceName) -> Result<()>;
+
+    /// Runs a command or interactive shell in the environment and returns
+    /// its exit status.
     ///
-    /// The environment must fully exist already.
-    fn run(&self, name: &EnvironmentName, command: &RunnerCommand) -> Result<()>;
+    /// The environment must fully exist already. An unsuccessful exit status
+    /// is not an error: errors mean that the runner failed to run the
+    /// command at all.
+    fn run(&self, name: &EnvironmentName, command: &RunnerCommand) -> Result<ExitStatus>;
+
+    /// Like [`Runner::run`] but passes the command's stdout and stderr to
+    /// `on_output` as they arrive, instead of to the terminal. The command
+    /// gets no terminal and no stdin.
+    fn run_streaming(
+        &self,
+        name: &EnvironmentName,
+        command: &RunnerCommand,
+        on_output: &mut dyn FnMut(OutputStream, &[u8]),
+    ) -> Result<ExitStatus>;
+
+    /// Runs an interactive shell in a new environment whose home directory
+    /// is on a tmpfs, initialized from `init`. Nothing of the environment
+    /// remains after the shell exits, so it never needs to be purged.
+    ///
+    /// Runners that can't do this return an error.
+    fn run_ephemeral(&self, name: &EnvironmentName, init: &Init) -> Result<()>;
+
+    /// Like [`Runner::run`] but with the environment's home directory
+    /// read-only. The work directory remains writable.
+    ///
+    /// Runners that can't do this return an error.
+    fn run_read_only_home(&self, name: &EnvironmentName, command: &RunnerCommand) -> Result<()>;
+
+    /// Runs a shell script that inspects the OS image that the environment's
+    /// OS packages get installed into and returns its stdout. For runners
+    /// that use the host's OS, this runs the script on the host. Without an
+    /// environment, this uses the image for environments without their own.
+    ///
+    /// The script must not change anything.
+    fn query_image(&self, name: Option<&EnvironmentName>, script: &str) -> Result<String>;
 
     /// Checks if the runner will run on any of the given platform patterns.
     fn supports_any(&self, targets: &[Target]) -> Result<bool>;
+
+    /// Removes resources that the runner left behind without an environment,
+    /// like containers from an interrupted `cub purge`. Returns a description
+    /// of each thing removed.
+    ///
+    /// This runs unattended on startup, so it must leave alone anything that
+    /// could still be in use, such as running containers.
+    fn clean_orphans(&self) -> Result<Vec<String>>;
+
+    /// Returns the resources that the runner left behind without an
+    /// environment, for `cub prune`.
+    ///
+    /// Unlike [`Runner::clean_orphans`], this only looks. The user decides
+    /// what to remove, so this may include things that could still be
+    /// useful, like backups.
+    fn orphans(&self) -> Result<Vec<Orphan>>;
+
+    /// Removes one of the resources returned by [`Runner::orphans`].
+    fn remove_orphan(&self, orphan: &Orphan) -> Result<()>;
+}
+
+/// A resource that a runner left behind, as returned by [`Runner::orphans`].
+#[derive(Debug)]
+pub enum Orphan {
+    /// A Docker container for an environment that has neither a home nor a
+    /// work directory.
+    Container {
+        env_name: EnvironmentName,
+        container: String,
+    },
+    /// A Docker volume, such as a checkpoint, for an environment that has
+    /// neither a home nor a work directory.
+    Volume { name: String },
+    /// A copy of a work directory that the User runner saved during a `cub
+    /// reset` that failed.
+    WorkBackup { path: HostPath, days: u64 },
+}
+
+impl fmt::Display for Orphan {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Container { container, .. } => {
+                write!(f, "Docker container {container} without an environment")
+            }
+            Self::Volume { name } => write!(f, "Docker volume {name} without an environment"),
+            Self::WorkBackup { path, days } => write!(
+                f,
+                "work directory backup {path} from a failed reset {days} days ago"
+            ),
+        }
+    }
 }
 
 #[derive(Debug, PartialEq, Eq)]
@@ -102,28 +260,409 @@ pub struct EnvFilesSummary {
     pub work_dir: DirSummary,
 }
 
+/// Sizes of directories within an environment, as returned by
+/// [`Runner::directory_sizes`].
+///
+/// Each entry is a path relative to the home or work directory (empty for
+/// the directory itself) and the total size in bytes of everything under it.
+#[derive(Debug, Default)]
+pub struct EnvDirectorySizes {
+    pub home_dir: Vec<(PathBuf, u64)>,
+    pub work_dir: Vec<(PathBuf, u64)>,
+}
+
+/// How to reach an environment without `cub enter`, as returned by
+/// [`Runner::connect_info`]. This is meant for editors and IDE plugins.
+///
+/// Fields are `None` where they don't apply to the runner.
+#[derive(Debug, Default, Serialize)]
+#[non_exhaustive]
+pub struct ConnectInfo {
+    /// The name of the Docker container, for `docker exec`.
+    pub container: Option<String>,
+    /// The user that runs processes in the environment.
+    pub user: Option<String>,
+    /// The environment's home directory, as seen from inside it.
+    pub home_dir: Option<PathBuf>,
+    /// The environment's work directory, as seen from inside it.
+    pub work_dir: Option<PathBuf>,
+    /// The environment's home directory on the host, if its files can be
+    /// accessed there directly.
+    pub host_home_dir: Option<PathBuf>,
+    /// The environment's work directory on the host, if its files can be
+    /// accessed there directly.
+    pub host_work_dir: Option<PathBuf>,
+    /// A command to run on the host that starts a login shell in the
+    /// environment, if the runner allows joining an environment from
+    /// outside. The environment may need to be running first.
+    pub attach_command: Option<Vec<String>>,
+}
+
 #[derive(Debug)]
 pub struct Init {
-    pub debian_packages: Vec<String>,
+    pub distro_packages: Vec<String>,
+    /// OS packages that only this environment needs, beyond
+    /// `distro_packages`. The Docker runner installs these in an image layer
+    /// of the environment's own, so they don't cause the shared base image to
+    /// be rebuilt.
+    pub env_distro_packages: Vec<String>,
     pub env_vars: Vec<(&'static str, String)>,
     pub seeds: Vec<HostPath>,
 }
 
 #[derive(Debug)]
 pub enum RunnerCommand<'a> {
-    Interactive,
+    Interactive {
+        /// Directory to start in, relative to the environment's work
+        /// directory. `None` starts in the work directory.
+        dir: Option<&'a Path>,
+    },
     Exec {
         command: &'a [String],
         env_vars: &'a [(&'static str, String)],
+        /// Directory to run in, relative to the environment's work
+        /// directory. `None` runs in the work directory.
+        dir: Option<&'a Path>,
     },
 }
 
+impl RunnerCommand<'_> {
+    /// Returns the directory to start in, relative to the environment's work
+    /// directory, if it's not the work directory itself.
+    pub fn dir(&self) -> Option<&Path> {
+        match self {
+            Self::Interactive { dir } | Self::Exec { dir, .. } => *dir,
+        }
+    }
+}
+
+/// A host socket (such as a Wayland or audio server) that can be shared with
+/// environments.
+pub struct HostSocket {
+    /// Path to the socket on the host.
+    pub host_path: HostPath,
+    /// Where to place the socket inside the environment.
+    pub env_path: String,
+    /// Environment variable (and value) telling clients where to find the
+    /// socket inside the environment.
+    pub env_var: (&'static str, String),
+}
+
+/// Environment variables that `systemd-run --user` needs to reach the user's
+/// service manager.
+pub const SYSTEMD_RUN_ENV_VARS: [&str; 2] = ["XDG_RUNTIME_DIR", "DBUS_SESSION_BUS_ADDRESS"];
+
+/// Implements [`Runner::query_image`] for runners that use the host's OS.
+pub fn query_host(script: &str) -> Result<String> {
+    let output = Command::query("sh").args(["-c", script]).output()?;
+    query_stdout(output, "sh")
+}
+
+/// Returns the stdout of a command run for [`Runner::query_image`], or an
+/// error with its stderr if it exited unsuccessfully.
+pub fn query_stdout(output: Output, program: &str) -> Result<String> {
+    if !output.status.success() {
+        return Err(anyhow!(
+            "`{program}` exited with {} and stderr: {}",
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    String::from_utf8(output.stdout).with_context(|| format!("`{program}` printed invalid UTF-8"))
+}
+
+/// Returns arguments for `systemd-run` to start a command in a transient scope
+/// with the resource limits from [`Config::cpus`] and [`Config::memory`], or
+/// an empty list if there are no limits.
+///
+/// The arguments end with `--`, so the command should follow.
+pub fn systemd_scope_args(config: &Config) -> Vec<String> {
+    let mut properties = Vec::new();
+    if let Some(cpus) = config.cpus {
+        properties.push(format!("CPUQuota={}%", u64::from(cpus) * 100));
+    }
+    if let Some(memory) = config.memory {
+        properties.push(format!("MemoryMax={memory}"));
+    }
+    if properties.is_empty() {
+        return Vec::new();
+    }
+    let mut args = vec![
+        String::from("--user"),
+        String::from("--scope"),
+        String::from("--quiet"),
+        String::from("--collect"),
+    ];
+    for property in properties {
+        args.push(String::from("--property"));
+        args.push(property);
+    }
+    args.push(String::from("--"));
+    args
+}
+
+/// Returns the environment names encoded in the names of the subdirectories
+/// of `dir`.
+///
+/// This warns about and skips subdirectories that don't encode a valid name,
+/// such as a name that isn't UTF-8, so that a stray directory doesn't hide
+/// every environment.
+pub fn env_dir_names(dir: &HostPath) -> Result<Vec<EnvironmentName>> {
+    let mut envs = Vec::new();
+    for name in try_iterdir_dirs(dir)? {
+        match EnvironmentName::from_filename(&name) {
+            Ok(env) => envs.push(env),
+            Err(e) => warn(e.context(format!(
+                "skipping {}: error parsing environment name from path",
+                dir.join(&name)
+            ))),
+        }
+    }
+    Ok(envs)
+}
+
+/// Returns `$XDG_RUNTIME_DIR` on the host, or its usual location if unset.
+fn host_runtime_dir() -> Option<HostPath> {
+    match std::env::var_os("XDG_RUNTIME_DIR") {
+        Some(dir) => HostPath::try_from(dir).ok(),
+        None => HostPath::try_from(format!("/run/user/{}", get_uids().real_user)).ok(),
+    }
+}
+
+/// Returns the host's Wayland display socket, if `$WAYLAND_DISPLAY` names one
+/// that exists.
+pub fn host_wayland_socket() -> Option<HostSocket> {
+    let display = std::env::var_os("WAYLAND_DISPLAY")?;
+    // `$WAYLAND_DISPLAY` may be an absolute path or relative to
+    // `$XDG_RUNTIME_DIR`.
+    let host_path = match HostPath::try_from(display.clone()) {
+        Ok(path) => path,
+        Err(_) => host_runtime_dir()?.join(display),
+    };
+    let name = host_path.as_host_raw().file_name()?.to_owned();
+    if !matches!(try_exists(&host_path), Ok(true)) {
+        return None;
+    }
+    // The socket's name inside the environment is arbitrary, so a name that
+    // isn't valid UTF-8 is percent-encoded rather than dropped.
+    let env_path = format!("/run/cubicle-wayland/{}", percent_encode_os_str(&name));
+    Some(HostSocket {
+        host_path,
+        env_var: ("WAYLAND_DISPLAY", env_path.clone()),
+        env_path,
+    })
+}
+
+/// Returns the PulseAudio and PipeWire sockets that exist on the host.
+///
+/// PipeWire typically provides a PulseAudio-compatible socket too, so sharing
+/// both covers clients of either.
+pub fn host_audio_sockets() -> Vec<HostSocket> {
+    let Some(runtime_dir) = host_runtime_dir() else {
+        return Vec::new();
+    };
+    [
+        HostSocket {
+            host_path: runtime_dir.join("pulse").join("native"),
+            env_path: String::from("/run/cubicle-audio/pulse"),
+            env_var: (
+                "PULSE_SERVER",
+                String::from("unix:/run/cubicle-audio/pulse"),
+            ),
+        },
+        HostSocket {
+            host_path: runtime_dir.join("pipewire-0"),
+            env_path: String::from("/run/cubicle-audio/pipewire-0"),
+            env_var: (
+                "PIPEWIRE_REMOTE",
+                String::from("/run/cubicle-audio/pipewire-0"),
+            ),
+        },
+    ]
+    .into_iter()
+    .filter(|socket| matches!(try_exists(&socket.host_path), Ok(true)))
+    .collect()
+}
+
+/// Returns the host's Docker (or Podman) API socket, for
+/// [`NestedContainers::HostSocket`](super::config::NestedContainers::HostSocket).
+///
+/// This uses `$DOCKER_HOST` if it names a Unix socket, then
+/// `/var/run/docker.sock`, then rootless Podman's socket. It warns and
+/// returns `None` if none of these exist.
+pub fn host_container_socket() -> Option<HostSocket> {
+    let from_env = std::env::var("DOCKER_HOST")
+        .ok()
+        .and_then(|host| HostPath::try_from(host.strip_prefix("unix://")?.to_owned()).ok());
+    let candidates = from_env
+        .into_iter()
+        .chain(HostPath::try_from(String::from("/var/run/docker.sock")).ok())
+        .chain(host_runtime_dir().map(|dir| dir.join("podman").join("podman.sock")));
+    for host_path in candidates {
+        if matches!(try_exists(&host_path), Ok(true)) {
+            return Some(HostSocket {
+                host_path,
+                env_path: String::from(CONTAINER_SOCKET_ENV_PATH),
+                env_var: ("DOCKER_HOST", format!("unix://{CONTAINER_SOCKET_ENV_PATH}")),
+            });
+        }
+    }
+    warn(anyhow!(
+        "`nested_containers` is set to \"host-socket\", but no Docker or Podman socket \
+        was found on the host"
+    ));
+    None
+}
+
+/// Returns the first of [`Config::runner_choices`] whose prerequisites are
+/// available on the host.
+pub fn select_runner(config: &Config) -> Result<RunnerKind> {
+    first_available(&config.runner_choices, runner_unavailable)
+}
+
+fn first_available(
+    choices: &[RunnerKind],
+    unavailable: impl Fn(RunnerKind) -> Option<String>,
+) -> Result<RunnerKind> {
+    let mut reasons = Vec::new();
+    for runner in choices {
+        match unavailable(*runner) {
+            None => return Ok(*runner),
+            Some(reason) => reasons.push(format!("{}: {reason}", runner.as_str())),
+        }
+    }
+    Err(anyhow!(
+        "none of the configured runners are available on this host ({})",
+        reasons.join("; ")
+    ))
+}
+
+/// Returns why the runner can't be used on this host, or `None` if its
+/// prerequisites are available.
+///
+/// These are quick checks for the programs that the runner needs, not a
+/// guarantee that the runner will work.
+fn runner_unavailable(runner: RunnerKind) -> Option<String> {
+    let succeeds = |program: &str, args: &[&str]| {
+        Command::query(program)
+            .args(args)
+            .output()
+            .is_ok_and(|output| output.status.success())
+    };
+    match runner {
+        RunnerKind::Bubblewrap => {
+            if !cfg!(all(target_os = "linux", feature = "bubblewrap")) {
+                Some(String::from("not supported by this build"))
+            } else if !succeeds("bwrap", &["--version"]) {
+                Some(String::from("`bwrap --version` failed"))
+            } else {
+                None
+            }
+        }
+        RunnerKind::Docker => {
+            if !cfg!(feature = "docker") {
+                Some(String::from("not supported by this build"))
+            } else if !succeeds("docker", &["info", "--format", "{{ .ServerVersion }}"]) {
+                Some(String::from(
+                    "`docker info` failed (is the Docker daemon running?)",
+                ))
+            } else {
+                None
+            }
+        }
+        RunnerKind::User => {
+            if !cfg!(all(target_os = "linux", feature = "user-runner")) {
+                Some(String::from("not supported by this build"))
+            } else if !succeeds("sudo", &["--version"]) {
+                Some(String::from("`sudo --version` failed"))
+            } else {
+                None
+            }
+        }
+    }
+}
+
+/// Returns the host device files that the environment may use, according to
+/// [`Config::devices`], expanding any wildcards.
+///
+/// This warns about and skips devices that don't exist.
+pub fn host_devices(config: &Config, name: &EnvironmentName) -> Vec<PathBuf> {
+    let mut devices = Vec::new();
+    for pattern in config.devices_for(name) {
+        let found = expand_device_pattern(Path::new(&pattern));
+        if found.is_empty() {
+            warn(anyhow!(
+                "device {pattern:?} for environment {name} not found on host (from `devices`)"
+            ));
+        }
+        devices.extend(found);
+    }
+    devices.sort();
+    devices.dedup();
+    devices
+}
+
+/// Returns the existing paths that match the pattern, which may contain
+/// wildcards in its last component.
+fn expand_device_pattern(pattern: &Path) -> Vec<PathBuf> {
+    let (Some(parent), Some(file_name)) = (pattern.parent(), pattern.file_name()) else {
+        return Vec::new();
+    };
+    let file_name = file_name.to_string_lossy();
+    if !file_name.contains(['*', '?']) {
+        return if pattern.exists() {
+            vec![pattern.to_owned()]
+        } else {
+            Vec::new()
+        };
+    }
+    let matcher = WildMatch::new(&file_name);
+    let Ok(entries) = std::fs::read_dir(parent) else {
+        return Vec::new();
+    };
+    let mut paths = entries
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| matcher.matches(&entry.file_name().to_string_lossy()))
+        .map(|entry| entry.path())
+        .collect::<Vec<_>>();
+    paths.sort();
+    paths
+}
+
+/// Where [`host_container_socket`] is placed inside environments.
+const CONTAINER_SOCKET_ENV_PATH: &str = "/run/cubicle-docker.sock";
+
 pub struct CheckedRunner(Box<dyn Runner>);
 
 impl CheckedRunner {
     pub fn new(runner: Box<dyn Runner>) -> Self {
         Self(runner)
     }
+
+    /// Runs an interactive shell in the environment, for `cub enter`.
+    ///
+    /// Unlike [`Runner::run`], this doesn't check that the environment
+    /// exists before and after, since the caller has just checked and each
+    /// check can take a noticeable fraction of the time before the shell
+    /// appears. The environment may also be purged from another terminal
+    /// during a long session.
+    pub fn enter(
+        &self,
+        name: &EnvironmentName,
+        read_only_home: ReadOnlyHome,
+        dir: Option<&Path>,
+    ) -> Result<()> {
+        let command = RunnerCommand::Interactive { dir };
+        let context = || format!("failed to run command in environment {name}");
+        if read_only_home.0 {
+            self.0
+                .run_read_only_home(name, &command)
+                .with_context(context)
+        } else {
+            let status = self.0.run(name, &command).with_context(context)?;
+            ExitStatusError::check(status, "shell")
+        }
+    }
 }
 
 impl Runner for CheckedRunner {
@@ -163,6 +702,28 @@ impl Runner for CheckedRunner {
         })
     }
 
+    fn copy_out(&self, name: &EnvironmentName, path: &Path, w: &mut dyn io::Write) -> Result<()> {
+        assert_eq!(
+            self.exists(name)?,
+            EnvironmentExists::FullyExists,
+            "Environment {name} should fully exist before copy_out"
+        );
+        self.0
+            .copy_out(name, path, w)
+            .with_context(|| format!("failed to copy {path:?} from environment {name}"))
+    }
+
+    fn copy_in(&self, name: &EnvironmentName, dir: &Path, r: &mut dyn io::Read) -> Result<()> {
+        assert_eq!(
+            self.exists(name)?,
+            EnvironmentExists::FullyExists,
+            "Environment {name} should fully exist before copy_in"
+        );
+        self.0
+            .copy_in(name, dir, r)
+            .with_context(|| format!("failed to copy files into {dir:?} in environment {name}"))
+    }
+
     fn create(&self, name: &EnvironmentName, init: &Init) -> Result<()> {
         assert_eq!(
             self.exists(name)?,
@@ -214,6 +775,63 @@ impl Runner for CheckedRunner {
         Ok(())
     }
 
+    fn is_running(&self, name: &EnvironmentName) -> Result<bool> {
+        self.0
+            .is_running(name)
+            .with_context(|| format!("failed to check if environment {name} is running"))
+    }
+
+    fn directory_sizes(&self, name: &EnvironmentName, depth: usize) -> Result<EnvDirectorySizes> {
+        assert_ne!(
+            self.exists(name)?,
+            EnvironmentExists::NoEnvironment,
+            "Environment {name} should partially or fully exist before directory_sizes"
+        );
+        self.0
+            .directory_sizes(name, depth)
+            .with_context(|| format!("failed to summarize directory sizes in environment {name}"))
+    }
+
+    fn connect_info(&self, name: &EnvironmentName) -> Result<ConnectInfo> {
+        assert_ne!(
+            self.exists(name)?,
+            EnvironmentExists::NoEnvironment,
+            "Environment {name} should partially or fully exist before connect_info"
+        );
+        self.0
+            .connect_info(name)
+            .with_context(|| format!("failed to get connection info for environment {name}"))
+    }
+
+    fn storage_usage(&self) -> Result<Vec<(&'static str, u64)>> {
+        self.0
+            .storage_usage()
+            .context("failed to summarize disk usage of runner")
+    }
+
+    fn running_processes(&self, name: &EnvironmentName) -> Result<Option<usize>> {
+        self.0
+            .running_processes(name)
+            .with_context(|| format!("failed to list processes in environment {name}"))
+    }
+
+    fn checkpoint(&self, name: &EnvironmentName) -> Result<()> {
+        assert_eq!(
+            self.exists(name)?,
+            EnvironmentExists::FullyExists,
+            "Environment {name} should fully exist before checkpoint"
+        );
+        self.0
+            .checkpoint(name)
+            .with_context(|| format!("failed to checkpoint environment {name}"))?;
+        assert_eq!(
+            self.exists(name)?,
+            EnvironmentExists::FullyExists,
+            "Environment {name} should fully exist after checkpoint"
+        );
+        Ok(())
+    }
+
     fn reset(&self, name: &EnvironmentName, init: &Init) -> Result<()> {
         assert_ne!(
             self.exists(name)?,
@@ -231,6 +849,23 @@ impl Runner for CheckedRunner {
         Ok(())
     }
 
+    fn refresh(&self, name: &EnvironmentName, init: &Init) -> Result<()> {
+        assert_eq!(
+            self.exists(name)?,
+            EnvironmentExists::FullyExists,
+            "Environment {name} should fully exist before refresh"
+        );
+        self.0
+            .refresh(name, init)
+            .with_context(|| format!("failed to refresh environment {name}"))?;
+        assert_eq!(
+            self.exists(name)?,
+            EnvironmentExists::FullyExists,
+            "Environment {name} should fully exist after refresh"
+        );
+        Ok(())
+    }
+
     fn purge(&self, name: &EnvironmentName) -> Result<()> {
         self.0
             .purge(name)
@@ -243,13 +878,20 @@ impl Runner for CheckedRunner {
         Ok(())
     }
 
-    fn run(&self, name: &EnvironmentName, command: &RunnerCommand) -> Result<()> {
+    fn purge_workspace(&self, name: &WorkspaceName) -> Result<()> {
+        self.0
+            .purge_workspace(name)
+            .with_context(|| format!("failed to purge workspace {name}"))
+    }
+
+    fn run(&self, name: &EnvironmentName, command: &RunnerCommand) -> Result<ExitStatus> {
         assert_eq!(
             self.exists(name)?,
             EnvironmentExists::FullyExists,
             "Environment {name} should fully exist before run"
         );
-        self.0
+        let status = self
+            .0
             .run(name, command)
             .with_context(|| format!("failed to run command in environment {name}"))?;
         assert_eq!(
@@ -257,14 +899,92 @@ impl Runner for CheckedRunner {
             EnvironmentExists::FullyExists,
             "Environment {name} should fully exist after run"
         );
+        Ok(status)
+    }
+
+    fn run_streaming(
+        &self,
+        name: &EnvironmentName,
+        command: &RunnerCommand,
+        on_output: &mut dyn FnMut(OutputStream, &[u8]),
+    ) -> Result<ExitStatus> {
+        assert_eq!(
+            self.exists(name)?,
+            EnvironmentExists::FullyExists,
+            "Environment {name} should fully exist before run"
+        );
+        let status = self
+            .0
+            .run_streaming(name, command, on_output)
+            .with_context(|| format!("failed to run command in environment {name}"))?;
+        assert_eq!(
+            self.exists(name)?,
+            EnvironmentExists::FullyExists,
+            "Environment {name} should fully exist after run"
+        );
+        Ok(status)
+    }
+
+    fn run_ephemeral(&self, name: &EnvironmentName, init: &Init) -> Result<()> {
+        assert_eq!(
+            self.exists(name)?,
+            EnvironmentExists::NoEnvironment,
+            "Environment {name} should not exist before ephemeral run"
+        );
+        self.0
+            .run_ephemeral(name, init)
+            .with_context(|| format!("failed to run ephemeral environment {name}"))?;
+        assert_eq!(
+            self.exists(name)?,
+            EnvironmentExists::NoEnvironment,
+            "Environment {name} should not exist after ephemeral run"
+        );
         Ok(())
     }
 
+    fn run_read_only_home(&self, name: &EnvironmentName, command: &RunnerCommand) -> Result<()> {
+        assert_eq!(
+            self.exists(name)?,
+            EnvironmentExists::FullyExists,
+            "Environment {name} should fully exist before run"
+        );
+        self.0
+            .run_read_only_home(name, command)
+            .with_context(|| format!("failed to run command in environment {name}"))
+    }
+
+    fn query_image(&self, name: Option<&EnvironmentName>, script: &str) -> Result<String> {
+        self.0
+            .query_image(name, script)
+            .with_context(|| match name {
+                Some(name) => format!("failed to inspect image for environment {name}"),
+                None => String::from("failed to inspect default image"),
+            })
+    }
+
     fn supports_any(&self, targets: &[Target]) -> Result<bool> {
         self.0
             .supports_any(targets)
             .context("failed to check if targets are supported")
     }
+
+    fn clean_orphans(&self) -> Result<Vec<String>> {
+        self.0
+            .clean_orphans()
+            .context("failed to clean up orphaned runner resources")
+    }
+
+    fn orphans(&self) -> Result<Vec<Orphan>> {
+        self.0
+            .orphans()
+            .context("failed to look for orphaned runner resources")
+    }
+
+    fn remove_orphan(&self, orphan: &Orphan) -> Result<()> {
+        self.0
+            .remove_orphan(orphan)
+            .with_context(|| format!("failed to remove {orphan}"))
+    }
 }
 
 /// Environment variables related to locales, which should generally be passed
@@ -289,3 +1009,102 @@ pub const LOCALE_ENVIRONMENT_VARIABLES: &[&str] = &[
     "LC_TELEPHONE",
     "LC_TIME",
 ];
+
+/// Runs `du` to list the sizes of the directories in `root`, down to `depth`
+/// levels below it.
+///
+/// `command` should run `du` wherever `root` is accessible: on the host, as
+/// another user, or in a container. This adds the arguments.
+pub fn run_du(command: &mut Command, depth: usize, root: &Path) -> Result<Vec<(PathBuf, u64)>> {
+    let output = command
+        .arg("--block-size=1")
+        .arg(format!("--max-depth={depth}"))
+        .arg("--")
+        .arg(root)
+        .output()?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        if output.stdout.is_empty() {
+            return Err(anyhow!(
+                "`du` exited with {} and stderr: {}",
+                output.status,
+                stderr.trim()
+            ));
+        }
+        // `du` still reports what it could read, such as when some
+        // directories aren't readable.
+        warn(anyhow!(
+            "`du` exited with {}, so sizes may be incomplete: {}",
+            output.status,
+            stderr.trim()
+        ));
+    }
+    Ok(parse_du(&output.stdout, root))
+}
+
+/// Parses the output of `du` run on `root`, making the paths relative to it.
+fn parse_du(stdout: &[u8], root: &Path) -> Vec<(PathBuf, u64)> {
+    stdout
+        .split(|b| *b == b'\n')
+        .filter_map(|line| {
+            let tab = line.iter().position(|b| *b == b'\t')?;
+            let size = std::str::from_utf8(&line[..tab]).ok()?.parse().ok()?;
+            let path = Path::new(OsStr::from_bytes(&line[tab + 1..]));
+            Some((path.strip_prefix(root).ok()?.to_owned(), size))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::{Path, PathBuf};
+
+    #[test]
+    fn first_available() {
+        use super::RunnerKind::*;
+        let unavailable = |runner| (runner == Bubblewrap).then(|| String::from("no bwrap"));
+        assert_eq!(
+            Docker,
+            super::first_available(&[Bubblewrap, Docker, User], unavailable).unwrap()
+        );
+        assert_eq!(
+            User,
+            super::first_available(&[User, Docker], unavailable).unwrap()
+        );
+        let err = super::first_available(&[Bubblewrap], unavailable)
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("bubblewrap: no bwrap"), "{err}");
+    }
+
+    #[test]
+    fn parse_du() {
+        let stdout = b"4096\t/v/a/b\n8192\t/v/a\n100\t/v/c d\nbad line\n12388\t/v\n";
+        assert_eq!(
+            vec![
+                (PathBuf::from("a/b"), 4096),
+                (PathBuf::from("a"), 8192),
+                (PathBuf::from("c d"), 100),
+                (PathBuf::new(), 12388),
+            ],
+            super::parse_du(stdout, Path::new("/v"))
+        );
+    }
+
+    #[test]
+    fn expand_device_pattern() {
+        let dir = tempfile::tempdir().unwrap();
+        for name in ["ttyUSB0", "ttyUSB1", "ttyS0"] {
+            std::fs::write(dir.path().join(name), "").unwrap();
+        }
+        let expand = |pattern: &str| super::expand_device_pattern(&dir.path().join(pattern));
+        assert_eq!(
+            vec![dir.path().join("ttyUSB0"), dir.path().join("ttyUSB1")],
+            expand("ttyUSB*")
+        );
+        assert_eq!(vec![dir.path().join("ttyS0")], expand("ttyS0"));
+        assert!(expand("ttyACM*").is_empty());
+        assert!(expand("kvm").is_empty());
+        assert!(expand("missing/tty*").is_empty());
+    }
+}
@@ -0,0 +1,322 @@
+//! Computes the changes needed to bring an existing home directory up to date
+//! with an environment's seed tar files, for incremental resets.
+//!
+//! A full reset removes the home directory and extracts every seed again,
+//! which is slow when the seeds hold large toolchains. Instead, [`prepare`]
+//! compares the seeds' entries with the files already in the home directory,
+//! removes the files that the seeds don't have or that have changed, and
+//! writes a single tar file with only the entries that need extracting.
+//!
+//! Like rsync's quick check, a regular file is considered unchanged if its
+//! size and modification time (in whole seconds) match the seed's entry, which
+//! works because `tar` restores modification times on extraction.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::io;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Component, Path, PathBuf};
+
+use crate::fs_util::{host_tempfile, open_tar, rmtree};
+use crate::somehow::{somehow as anyhow, Context, Result};
+use crate::HostPath;
+
+/// The result of [`prepare`].
+pub struct SeedDelta {
+    /// A tar file with the seed entries that need extracting into the home
+    /// directory.
+    pub tar: tempfile::NamedTempFile,
+    /// The number of entries in `tar`.
+    pub changed: usize,
+    /// The number of seed entries that were already up to date.
+    pub unchanged: usize,
+    /// The number of files and directories removed from the home directory.
+    pub removed: usize,
+}
+
+/// What a seed wants at a path. Later seeds override earlier ones.
+struct Wanted {
+    /// Which seed and which entry within it (by position) is wanted.
+    source: (usize, usize),
+    kind: Kind,
+}
+
+enum Kind {
+    Dir,
+    File {
+        size: u64,
+        mtime: u64,
+    },
+    Symlink(PathBuf),
+    /// Hard links and special files, which are always extracted again.
+    Other,
+}
+
+/// Removes files from `home` that don't match the `seeds` and returns a tar
+/// file of the seed entries that should then be extracted into `home`.
+///
+/// The top-level directory named `work_dir_name` is left alone, since it's
+/// where the environment's work directory is mounted.
+pub fn prepare(home: &HostPath, work_dir_name: &str, seeds: &[HostPath]) -> Result<SeedDelta> {
+    let mut wanted = BTreeMap::new();
+    for (i, seed) in seeds.iter().enumerate() {
+        for_each_entry(seed, |j, path, entry| {
+            let header = entry.header();
+            let kind = match header.entry_type() {
+                tar::EntryType::Directory => Kind::Dir,
+                tar::EntryType::Regular | tar::EntryType::Continuous => Kind::File {
+                    size: entry.size(),
+                    mtime: header.mtime()?,
+                },
+                tar::EntryType::Symlink => match entry.link_name()? {
+                    Some(target) => Kind::Symlink(target.into_owned()),
+                    None => Kind::Other,
+                },
+                _ => Kind::Other,
+            };
+            wanted.insert(
+                path,
+                Wanted {
+                    source: (i, j),
+                    kind,
+                },
+            );
+            Ok(())
+        })
+        .with_context(|| format!("failed to read seed {seed}"))?;
+    }
+    // Directories that seeds only mention through their contents.
+    let implied: BTreeSet<&Path> = wanted.keys().flat_map(|path| path.ancestors()).collect();
+
+    let mut sync = Sync {
+        wanted: &wanted,
+        implied: &implied,
+        unchanged: BTreeSet::new(),
+        removed: 0,
+    };
+    sync.walk(home, Path::new(""), Some(work_dir_name))?;
+    let Sync {
+        unchanged, removed, ..
+    } = sync;
+
+    let changed: BTreeSet<(usize, usize)> = wanted
+        .iter()
+        .filter(|(path, _)| !unchanged.contains(path.as_path()))
+        .map(|(_, wanted)| wanted.source)
+        .collect();
+    let tar = host_tempfile().context("failed to create temporary file")?;
+    let mut builder = tar::Builder::new(tar.as_file());
+    for (i, seed) in seeds.iter().enumerate() {
+        for_each_entry(seed, |j, path, mut entry| {
+            if !changed.contains(&(i, j)) {
+                return Ok(());
+            }
+            let mut header = entry.header().clone();
+            match entry.link_name()? {
+                Some(target) => builder.append_link(&mut header, &path, target),
+                None => builder.append_data(&mut header, &path, &mut entry),
+            }
+        })
+        .with_context(|| format!("failed to copy entries from seed {seed}"))?;
+    }
+    builder
+        .finish()
+        .context("failed to write incremental seed")?;
+    drop(builder);
+
+    Ok(SeedDelta {
+        tar,
+        changed: changed.len(),
+        unchanged: unchanged.len(),
+        removed,
+    })
+}
+
+/// Calls `f` with the position, normalized path, and entry for each file,
+/// directory, or link in the tar file.
+fn for_each_entry<F>(seed: &HostPath, mut f: F) -> Result<()>
+where
+    F: FnMut(usize, PathBuf, tar::Entry<'_, Box<dyn io::Read + Send>>) -> io::Result<()>,
+{
+    let mut archive = tar::Archive::new(open_tar(seed)?);
+    archive.set_ignore_zeros(true);
+    for (j, entry) in archive.entries().todo_context()?.enumerate() {
+        let entry = entry.todo_context()?;
+        if matches!(
+            entry.header().entry_type(),
+            tar::EntryType::XGlobalHeader | tar::EntryType::XHeader
+        ) {
+            continue;
+        }
+        let path = normalize(&entry.path().todo_context()?)?;
+        if path.as_os_str().is_empty() {
+            continue;
+        }
+        f(j, path, entry).todo_context()?;
+    }
+    Ok(())
+}
+
+/// Strips `./` components and rejects paths that would leave the home
+/// directory.
+fn normalize(path: &Path) -> Result<PathBuf> {
+    let mut normal = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::Normal(c) => normal.push(c),
+            _ => return Err(anyhow!("unsupported path in seed: {path:?}")),
+        }
+    }
+    Ok(normal)
+}
+
+struct Sync<'a> {
+    wanted: &'a BTreeMap<PathBuf, Wanted>,
+    implied: &'a BTreeSet<&'a Path>,
+    unchanged: BTreeSet<PathBuf>,
+    removed: usize,
+}
+
+impl Sync<'_> {
+    /// Compares the contents of the directory `rel` (relative to `home`)
+    /// with the seeds. It skips the entry named `skip`.
+    fn walk(&mut self, home: &HostPath, rel: &Path, skip: Option<&str>) -> Result<()> {
+        let dir = home.join(rel);
+        let entries = std::fs::read_dir(dir.as_host_raw())
+            .with_context(|| format!("failed to read directory {dir}"))?;
+        for entry in entries {
+            let entry = entry.with_context(|| format!("failed to read directory {dir}"))?;
+            let name = entry.file_name();
+            if skip.is_some_and(|skip| name == skip) {
+                continue;
+            }
+            let rel = rel.join(&name);
+            let path = home.join(&rel);
+            let metadata = std::fs::symlink_metadata(path.as_host_raw())
+                .with_context(|| format!("failed to read metadata for {path}"))?;
+            let file_type = metadata.file_type();
+
+            let keep = match self.wanted.get(&rel).map(|wanted| &wanted.kind) {
+                None if self.implied.contains(rel.as_path()) => file_type.is_dir(),
+                None | Some(Kind::Other) => false,
+                Some(Kind::Dir) => file_type.is_dir(),
+                Some(Kind::File { size, mtime }) => {
+                    file_type.is_file()
+                        && metadata.len() == *size
+                        && u64::try_from(metadata.mtime()) == Ok(*mtime)
+                }
+                Some(Kind::Symlink(target)) => {
+                    file_type.is_symlink()
+                        && std::fs::read_link(path.as_host_raw()).ok().as_ref() == Some(target)
+                }
+            };
+            if !keep {
+                if file_type.is_dir() {
+                    rmtree(&path)?;
+                } else {
+                    std::fs::remove_file(path.as_host_raw())
+                        .with_context(|| format!("failed to remove {path}"))?;
+                }
+                self.removed += 1;
+                continue;
+            }
+            if file_type.is_dir() {
+                self.walk(home, &rel, None)?;
+            }
+            if self.wanted.contains_key(&rel) {
+                self.unchanged.insert(rel);
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::File;
+    use std::io::{Seek, SeekFrom};
+
+    fn seed(entries: &[(&str, Option<&str>)]) -> tempfile::NamedTempFile {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        let mut builder = tar::Builder::new(file.as_file());
+        for (path, contents) in entries {
+            let mut header = tar::Header::new_gnu();
+            header.set_mtime(1_000_000);
+            match contents {
+                Some(contents) => {
+                    header.set_entry_type(tar::EntryType::Regular);
+                    header.set_mode(0o644);
+                    header.set_size(contents.len() as u64);
+                    builder
+                        .append_data(&mut header, path, contents.as_bytes())
+                        .unwrap();
+                }
+                None => {
+                    header.set_entry_type(tar::EntryType::Directory);
+                    header.set_mode(0o755);
+                    header.set_size(0);
+                    builder.append_data(&mut header, path, io::empty()).unwrap();
+                }
+            }
+        }
+        builder.finish().unwrap();
+        drop(builder);
+        file
+    }
+
+    fn names(mut file: &File) -> Vec<String> {
+        file.seek(SeekFrom::Start(0)).unwrap();
+        tar::Archive::new(file)
+            .entries()
+            .unwrap()
+            .map(|entry| entry.unwrap().path().unwrap().display().to_string())
+            .collect()
+    }
+
+    #[test]
+    fn prepare() {
+        let dir = tempfile::tempdir().unwrap();
+        let home = HostPath::try_from(dir.path().to_owned()).unwrap();
+        let seeds = [
+            seed(&[
+                ("./", None),
+                ("./.config/", None),
+                ("./.config/a", Some("old")),
+                ("./b", Some("same")),
+                ("./c", Some("changed")),
+            ]),
+            seed(&[("./.config/a", Some("new")), ("bin/d", Some("new"))]),
+        ];
+        let seed_paths = seeds
+            .iter()
+            .map(|seed| HostPath::try_from(seed.path().to_owned()).unwrap())
+            .collect::<Vec<_>>();
+
+        // Set up a home directory as if after a full extraction and some use.
+        let mut archive = tar::Archive::new(File::open(seeds[0].path()).unwrap());
+        archive.unpack(dir.path()).unwrap();
+        let mut archive = tar::Archive::new(File::open(seeds[1].path()).unwrap());
+        archive.unpack(dir.path()).unwrap();
+        std::fs::write(dir.path().join("c"), "CHANGED").unwrap();
+        std::fs::write(dir.path().join("extra"), "").unwrap();
+        std::fs::create_dir_all(dir.path().join(".cache/x")).unwrap();
+        std::fs::create_dir_all(dir.path().join("w/project")).unwrap();
+
+        let delta = super::prepare(&home, "w", &seed_paths).unwrap();
+        assert_eq!(names(delta.tar.as_file()), ["c"]);
+        assert_eq!(delta.changed, 1);
+        // `.config`, `.config/a`, `b`, and `bin/d`.
+        assert_eq!(delta.unchanged, 4);
+        // `c`, `extra`, and `.cache`.
+        assert_eq!(delta.removed, 3);
+        assert!(!dir.path().join("c").exists());
+        assert!(!dir.path().join("extra").exists());
+        assert!(!dir.path().join(".cache").exists());
+        assert!(dir.path().join("w/project").exists());
+        assert_eq!(
+            std::fs::read_to_string(dir.path().join(".config/a")).unwrap(),
+            "new"
+        );
+    }
+}
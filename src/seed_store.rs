@@ -0,0 +1,461 @@
+//! Extracts package builds once into a store on the host, so that home
+//! directories can be filled from them without extracting the same tar files
+//! into every environment.
+//!
+//! Each seed from Cubicle's cache directory (package builds and their
+//! rewritten seeds) gets a directory in the store with one subdirectory per
+//! version of the seed, named after the seed's size and modification time.
+//! Other seeds, such as temporary files with the environment's config, are
+//! extracted as usual.
+//!
+//! The store is used in two ways:
+//!
+//! - With [`Config::seed_copy`](crate::config::Config::seed_copy) set to
+//!   reflink or hardlink, [`populate`] copies the files into home
+//!   directories with `cp --reflink=always` or `cp --link`.
+//!
+//! - With the Bubblewrap runner's `overlay_home`, [`lower_layers`] returns the
+//!   extracted seeds for use as read-only overlayfs layers under the home
+//!   directory. Those environments keep using a version until they're reset,
+//!   so the runner records them with [`set_references`].
+//!
+//! Old versions stay until [`prune`] finds them unreferenced.
+
+use std::collections::BTreeSet;
+use std::ffi::OsStr;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use tracing::debug;
+
+use crate::command_ext::{Command, Stdio};
+use crate::config::SeedCopy;
+use crate::encoding::FilenameEncoder;
+use crate::fs_util::{open_tar, rmtree, try_exists, try_iterdir, try_iterdir_dirs};
+use crate::lock::LockFile;
+use crate::somehow::{somehow as anyhow, warn, Context, Result};
+use crate::{CubicleShared, HostPath};
+
+/// Copies the leading seeds that come from the cache directory into `home`
+/// through the store, if [`Config::seed_copy`](crate::config::Config::seed_copy)
+/// says to. Returns the remaining seeds, which the caller must extract in
+/// order as usual.
+///
+/// If this fails, such as when the store and the home directory are on
+/// different filesystems, it warns and returns all of the seeds.
+pub fn populate<'a>(
+    program: &CubicleShared,
+    home: &HostPath,
+    seeds: &'a [HostPath],
+) -> &'a [HostPath] {
+    let flag = match program.config.seed_copy {
+        SeedCopy::Extract => return seeds,
+        SeedCopy::Reflink => "--reflink=always",
+        SeedCopy::Hardlink => "--link",
+    };
+    let (stored, rest) = split_stored(program, seeds);
+    for seed in stored {
+        let result = extract(program, seed).and_then(|(_lock, files)| {
+            let mut source = files.as_host_raw().as_os_str().to_owned();
+            source.push("/.");
+            let status = Command::new("cp")
+                .args(["--archive", "--remove-destination", flag, "--"])
+                .arg(source)
+                .arg(home.as_host_raw())
+                .stdin(Stdio::null())
+                .status()?;
+            if !status.success() {
+                return Err(anyhow!("`cp` exited with {status}"));
+            }
+            if program.config.seed_copy == SeedCopy::Hardlink {
+                copy_writable_files(files.as_host_raw(), home.as_host_raw()).with_context(
+                    || format!("failed to replace hard links to writable files in {home}"),
+                )?;
+            }
+            Ok(())
+        });
+        if let Err(e) = result {
+            warn(e.context(format!(
+                "failed to copy {seed} into {home} through the seed store; extracting it instead"
+            )));
+            return seeds;
+        }
+    }
+    if let Err(e) = prune(program) {
+        warn(e.context("failed to remove unused builds from the seed store"));
+    }
+    rest
+}
+
+/// Returns the extracted copies of the leading seeds that come from the
+/// cache directory, lowest first, and the remaining seeds, which the caller
+/// must extract in order as usual.
+pub fn lower_layers<'a>(
+    program: &CubicleShared,
+    seeds: &'a [HostPath],
+) -> Result<(Vec<HostPath>, &'a [HostPath])> {
+    let (stored, rest) = split_stored(program, seeds);
+    let layers = stored
+        .iter()
+        .map(|seed| extract(program, seed).map(|(_lock, files)| files))
+        .collect::<Result<_>>()?;
+    Ok((layers, rest))
+}
+
+/// Records that `user`, a unique name for something like an environment's
+/// overlay home directory, uses the given `layers` from [`lower_layers`]
+/// instead of any it used before. An empty list forgets the user.
+///
+/// This doesn't remove the layers that are no longer used; see [`prune`].
+pub fn set_references(program: &CubicleShared, user: &str, layers: &[HostPath]) -> Result<()> {
+    let path = references_path(program, user);
+    if layers.is_empty() {
+        return match std::fs::remove_file(path.as_host_raw()) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => {
+                Err(e).with_context(|| format!("failed to remove {path}"))
+            }
+            _ => Ok(()),
+        };
+    }
+    let dir = references_dir(program);
+    std::fs::create_dir_all(dir.as_host_raw())
+        .with_context(|| format!("failed to create directory {dir}"))?;
+    let mut contents = Vec::new();
+    for layer in layers {
+        contents.extend(layer.as_host_raw().as_os_str().as_bytes());
+        contents.push(b'\n');
+    }
+    let mut file = tempfile::NamedTempFile::new_in(dir.as_host_raw())
+        .with_context(|| format!("failed to create temporary file in {dir}"))?;
+    io::Write::write_all(&mut file, &contents)
+        .with_context(|| format!("failed to write {path}"))?;
+    file.persist(path.as_host_raw())
+        .with_context(|| format!("failed to write {path}"))?;
+    Ok(())
+}
+
+/// Returns the layers that `user` uses, as recorded by [`set_references`],
+/// lowest first.
+pub fn references(program: &CubicleShared, user: &str) -> Result<Vec<HostPath>> {
+    let path = references_path(program, user);
+    match std::fs::read(path.as_host_raw()) {
+        Ok(contents) => parse_layers(&contents).with_context(|| format!("failed to parse {path}")),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Vec::new()),
+        Err(e) => Err(e).with_context(|| format!("failed to read {path}")),
+    }
+}
+
+/// Removes the versions of seeds in the store that are out of date and not
+/// referenced through [`set_references`], along with everything for seeds
+/// that no longer exist.
+pub fn prune(program: &CubicleShared) -> Result<()> {
+    let mut in_use = BTreeSet::new();
+    let refs = references_dir(program);
+    for user in try_iterdir(&refs)? {
+        let path = refs.join(&user);
+        let contents = match std::fs::read(path.as_host_raw()) {
+            Ok(contents) => contents,
+            // This was a temporary file from `set_references`.
+            Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e).with_context(|| format!("failed to read {path}")),
+        };
+        in_use.extend(
+            parse_layers(&contents)
+                .with_context(|| format!("failed to parse {path}"))?
+                .into_iter()
+                .map(|layer| layer.as_host_raw().to_owned()),
+        );
+    }
+
+    let store = store_dir(program);
+    for name in try_iterdir_dirs(&store)? {
+        let dir = store.join(&name);
+        let Ok(relative) = FilenameEncoder::decode(&name) else {
+            continue;
+        };
+        let lock = LockFile::acquire(dir.join("lock"), &format!("seed store for {relative}"))?;
+        let current = version(&program.cache_dir.join(&relative)).ok();
+        let mut kept = false;
+        for version in try_iterdir_dirs(&dir)? {
+            let path = dir.join(&version);
+            if current.as_deref() == version.to_str() || in_use.contains(path.as_host_raw()) {
+                kept = true;
+            } else {
+                debug!("Removing {path} from seed store");
+                rmtree(&path)?;
+            }
+        }
+        if !kept {
+            drop(lock);
+            rmtree(&dir)?;
+        }
+    }
+    Ok(())
+}
+
+fn store_dir(program: &CubicleShared) -> HostPath {
+    program.cache_dir.join("seed-store")
+}
+
+/// Returns the directory with a file for each user of the store's versions,
+/// listing the versions it uses. This isn't in the store's directory, where
+/// every subdirectory is for a seed.
+fn references_dir(program: &CubicleShared) -> HostPath {
+    program.cache_dir.join("seed-store-refs")
+}
+
+fn references_path(program: &CubicleShared, user: &str) -> HostPath {
+    references_dir(program).join(FilenameEncoder::new().push(user).encode())
+}
+
+/// Parses a list of layers, one absolute path per line.
+fn parse_layers(contents: &[u8]) -> Result<Vec<HostPath>> {
+    contents
+        .split(|b| *b == b'\n')
+        .filter(|line| !line.is_empty())
+        .map(|line| HostPath::try_from(PathBuf::from(OsStr::from_bytes(line))))
+        .collect()
+}
+
+/// Splits the seeds into the leading ones from the cache directory and the
+/// rest. Seeds must be applied in order, so only a prefix can come from the
+/// store.
+fn split_stored<'a>(
+    program: &CubicleShared,
+    seeds: &'a [HostPath],
+) -> (&'a [HostPath], &'a [HostPath]) {
+    let count = seeds
+        .iter()
+        .take_while(|seed| {
+            seed.as_host_raw()
+                .starts_with(program.cache_dir.as_host_raw())
+        })
+        .count();
+    seeds.split_at(count)
+}
+
+/// Returns the name of the store's directory for the current version of the
+/// seed.
+fn version(seed: &HostPath) -> Result<String> {
+    let metadata =
+        std::fs::metadata(seed.as_host_raw()).with_context(|| format!("failed to stat {seed}"))?;
+    Ok(format!(
+        "{}-{}.{:09}",
+        metadata.len(),
+        metadata.mtime(),
+        metadata.mtime_nsec()
+    ))
+}
+
+/// Extracts the seed into the store, unless the current version is already
+/// there. Returns the directory with the extracted files and the lock on
+/// the seed's store directory, which keeps them from changing.
+///
+/// The seed's other versions are left for [`prune`], since environments may
+/// still use them.
+fn extract(program: &CubicleShared, seed: &HostPath) -> Result<(LockFile, HostPath)> {
+    let relative = seed
+        .as_host_raw()
+        .strip_prefix(program.cache_dir.as_host_raw())
+        .expect("seed should be in cache directory");
+    let dir = store_dir(program).join(
+        FilenameEncoder::new()
+            .push(&relative.to_string_lossy())
+            .encode(),
+    );
+    std::fs::create_dir_all(dir.as_host_raw())
+        .with_context(|| format!("failed to create directory {dir}"))?;
+    let lock = LockFile::acquire(dir.join("lock"), &format!("seed store for {seed}"))?;
+
+    let version = version(seed)?;
+    let files = dir.join(&version);
+    if try_exists(&files).todo_context()? {
+        return Ok((lock, files));
+    }
+
+    debug!("Extracting {seed} into seed store");
+    let new = dir.join(format!("{version}.new"));
+    rmtree(&new)?;
+    let mut archive = tar::Archive::new(open_tar(seed)?);
+    archive.set_preserve_permissions(true);
+    archive.set_preserve_mtime(true);
+    archive
+        .unpack(new.as_host_raw())
+        .with_context(|| format!("failed to extract {seed} to {new}"))?;
+    std::fs::rename(new.as_host_raw(), files.as_host_raw())
+        .with_context(|| format!("failed to rename {new} to {files}"))?;
+    Ok((lock, files))
+}
+
+/// Replaces the hard links under `home` to the writable regular files under
+/// `store` with copies, so that an environment changing them in place
+/// doesn't change them for the store and other environments. `std::fs::copy`
+/// makes reflinks where the filesystem supports them.
+fn copy_writable_files(store: &Path, home: &Path) -> io::Result<()> {
+    for entry in std::fs::read_dir(store)? {
+        let entry = entry?;
+        let file_type = entry.file_type()?;
+        let target = home.join(entry.file_name());
+        if file_type.is_dir() {
+            copy_writable_files(&entry.path(), &target)?;
+        } else if file_type.is_file() {
+            let metadata = entry.metadata()?;
+            if metadata.permissions().mode() & 0o222 != 0 {
+                std::fs::remove_file(&target)?;
+                std::fs::copy(entry.path(), &target)?;
+                std::fs::File::options()
+                    .write(true)
+                    .open(&target)?
+                    .set_modified(metadata.modified()?)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+
+    /// Writes a seed with the given files, as (path, mode, contents).
+    fn write_seed(path: &HostPath, files: &[(&str, u32, &str)]) {
+        let file = std::fs::File::create(path.as_host_raw()).unwrap();
+        let mut builder = tar::Builder::new(file);
+        for (name, mode, contents) in files {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(contents.len() as u64);
+            header.set_mode(*mode);
+            builder
+                .append_data(&mut header, name, contents.as_bytes())
+                .unwrap();
+        }
+        builder.finish().unwrap();
+    }
+
+    /// Rewrites the seed with new contents, making sure it gets a new
+    /// version.
+    fn rebuild_seed(path: &HostPath, contents: &str) {
+        let modified = std::fs::metadata(path.as_host_raw())
+            .unwrap()
+            .modified()
+            .unwrap();
+        write_seed(path, &[("./bin/tool", 0o755, contents)]);
+        std::fs::File::options()
+            .write(true)
+            .open(path.as_host_raw())
+            .unwrap()
+            .set_modified(modified + std::time::Duration::from_secs(1))
+            .unwrap();
+    }
+
+    #[test]
+    fn populate() {
+        let root = tempfile::tempdir().unwrap();
+        let cub = testing::cubicle(root.path(), "seed_copy = 'hardlink'");
+        let program = &cub.shared;
+
+        let packages = program.cache_dir.join("packages");
+        std::fs::create_dir_all(packages.as_host_raw()).unwrap();
+        let seed = packages.join("tool.tar");
+        write_seed(
+            &seed,
+            &[("./bin/tool", 0o555, "hi"), ("./tool.conf", 0o644, "x")],
+        );
+        let temp = tempfile::NamedTempFile::new().unwrap();
+        let temp = HostPath::try_from(temp.path().to_owned()).unwrap();
+
+        let home = HostPath::try_from(root.path().join("home")).unwrap();
+        std::fs::create_dir(home.as_host_raw()).unwrap();
+        let seeds = [seed, temp];
+        let rest = super::populate(program, &home, &seeds);
+        assert_eq!(rest.len(), 1);
+        assert_eq!(
+            rest[0].as_host_raw(),
+            seeds[1].as_host_raw(),
+            "temporary seed should be left to extract"
+        );
+
+        // Read-only files are shared.
+        let tool = home.join("bin/tool");
+        assert_eq!(std::fs::read_to_string(tool.as_host_raw()).unwrap(), "hi");
+        let metadata = std::fs::metadata(tool.as_host_raw()).unwrap();
+        assert_eq!(metadata.nlink(), 2);
+        assert_eq!(metadata.permissions().mode() & 0o777, 0o555);
+
+        // Writable files are copied.
+        let conf = home.join("tool.conf");
+        assert_eq!(std::fs::read_to_string(conf.as_host_raw()).unwrap(), "x");
+        let metadata = std::fs::metadata(conf.as_host_raw()).unwrap();
+        assert_eq!(metadata.nlink(), 1);
+        assert_eq!(metadata.permissions().mode() & 0o777, 0o644);
+    }
+
+    #[test]
+    fn references_and_prune() {
+        let root = tempfile::tempdir().unwrap();
+        let cub = testing::cubicle(root.path(), "seed_copy = 'hardlink'");
+        let program = &cub.shared;
+
+        let packages = program.cache_dir.join("packages");
+        std::fs::create_dir_all(packages.as_host_raw()).unwrap();
+        let seed = packages.join("tool.tar");
+        write_seed(&seed, &[("./bin/tool", 0o755, "v1")]);
+        let (layers, rest) = lower_layers(program, std::slice::from_ref(&seed)).unwrap();
+        assert!(rest.is_empty());
+        let v1 = layers[0].clone();
+        assert_eq!(
+            std::fs::read_to_string(v1.join("bin/tool").as_host_raw()).unwrap(),
+            "v1"
+        );
+        set_references(program, "env", &layers).unwrap();
+        assert_eq!(
+            references(program, "env").unwrap()[0].as_host_raw(),
+            v1.as_host_raw()
+        );
+
+        // A rebuild gets a new version next to the old one.
+        rebuild_seed(&seed, "v2");
+        let (layers, _) = lower_layers(program, std::slice::from_ref(&seed)).unwrap();
+        let v2 = layers[0].clone();
+        assert_ne!(v1.as_host_raw(), v2.as_host_raw());
+        assert!(try_exists(&v1).unwrap());
+
+        // Old versions are kept while referenced, even when copying the
+        // seed into another home directory cleans up.
+        let home = HostPath::try_from(root.path().join("home")).unwrap();
+        std::fs::create_dir(home.as_host_raw()).unwrap();
+        rebuild_seed(&seed, "v3");
+        super::populate(program, &home, std::slice::from_ref(&seed));
+        assert!(try_exists(&v1).unwrap());
+        assert!(!try_exists(&v2).unwrap());
+        set_references(program, "env", &[]).unwrap();
+        assert!(references(program, "env").unwrap().is_empty());
+        prune(program).unwrap();
+        assert!(!try_exists(&v1).unwrap());
+
+        // Everything goes once the seed is gone.
+        std::fs::remove_file(seed.as_host_raw()).unwrap();
+        prune(program).unwrap();
+        assert!(try_iterdir_dirs(&store_dir(program)).unwrap().is_empty());
+    }
+
+    #[test]
+    fn parse_layers() {
+        let layers = super::parse_layers(b"/cache/seed-store/a/1-2.3\n/cache/seed-store/b/4-5.6\n")
+            .unwrap()
+            .into_iter()
+            .map(|layer| layer.as_host_raw().to_owned())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            layers,
+            [
+                Path::new("/cache/seed-store/a/1-2.3"),
+                Path::new("/cache/seed-store/b/4-5.6")
+            ]
+        );
+        assert!(super::parse_layers(b"").unwrap().is_empty());
+        assert!(super::parse_layers(b"relative\n").is_err());
+    }
+}
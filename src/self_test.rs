@@ -0,0 +1,122 @@
+//! Checks that Cubicle works with the configured runner on this machine.
+//!
+//! `cub self-test` takes a disposable environment through its whole life,
+//! the way a user would, and prints which steps passed. It's meant to be
+//! run after installing or upgrading Cubicle or the runner's dependencies.
+
+use std::collections::BTreeSet;
+use std::path::Path;
+use std::str::FromStr;
+
+use super::runner::{EnvironmentExists, Runner};
+use super::{Cubicle, EnvironmentName, Force, FullPackageName, KeepHome, Locked, Quiet};
+use crate::somehow::{somehow as anyhow, Result};
+
+/// A trivial built-in package, used to check that packages are built and
+/// copied into the environment. It provides a command of the same name.
+const PACKAGE: &str = "no-op";
+
+/// The file that the self-test writes in the environment's work directory
+/// and copies back out.
+const FILE_NAME: &str = "self-test.txt";
+
+/// The contents of [`FILE_NAME`].
+const FILE_CONTENTS: &str = "cubicle self-test\n";
+
+/// Tracks and prints the results of the self-test's steps.
+#[derive(Default)]
+struct Report {
+    failed: bool,
+}
+
+impl Report {
+    /// Runs a step and prints its result, unless an earlier step failed.
+    fn step<F>(&mut self, description: &str, f: F)
+    where
+        F: FnOnce() -> Result<()>,
+    {
+        if self.failed {
+            println!("SKIP  {description}");
+        } else {
+            self.always(description, f);
+        }
+    }
+
+    /// Runs a step and prints its result, even if an earlier step failed.
+    fn always<F>(&mut self, description: &str, f: F)
+    where
+        F: FnOnce() -> Result<()>,
+    {
+        match f() {
+            Ok(()) => println!("PASS  {description}"),
+            Err(e) => {
+                println!("FAIL  {description}: {e:#}");
+                self.failed = true;
+            }
+        }
+    }
+}
+
+impl Cubicle {
+    /// Corresponds to `cub self-test`.
+    ///
+    /// Prints one line per step and returns an error if any step failed. The
+    /// environment is purged at the end, even after a failure.
+    pub fn self_test(&self) -> Result<()> {
+        let name = self.random_environment_name("self-test")?;
+        println!(
+            "Testing the {} runner with environment {name}",
+            self.shared.config.runner.as_str()
+        );
+        let mut report = Report::default();
+
+        report.step("create environment with a package", || {
+            let packages = BTreeSet::from([FullPackageName::from_str(PACKAGE)?]);
+            self.new_environment(&name, Some(packages))
+        });
+        report.step("run a command from the package", || {
+            self.exec_environment(&name, &[String::from(PACKAGE)])
+        });
+        report.step("write a file in the work directory", || {
+            let script = format!("printf '{FILE_CONTENTS}' > {FILE_NAME}");
+            self.exec_environment(&name, &["sh", "-c", &script].map(String::from))
+        });
+        report.step("copy the file out", || self.check_self_test_file(&name));
+        report.step("reset environment", || {
+            self.reset_environment(&name, None, Force(true), KeepHome(false), Locked(false))
+        });
+        report.step("keep the work directory across reset", || {
+            self.check_self_test_file(&name)
+        });
+        report.step("run a command from the package after reset", || {
+            self.exec_environment(&name, &[String::from(PACKAGE)])
+        });
+        report.always("purge environment", || {
+            self.purge_environment(&name, Quiet(true), Force(true))?;
+            match self.runner.exists(&name)? {
+                EnvironmentExists::NoEnvironment => Ok(()),
+                exists => Err(anyhow!("environment still exists after purge: {exists:?}")),
+            }
+        });
+
+        if report.failed {
+            return Err(anyhow!("self-test failed"));
+        }
+        println!("All steps passed");
+        Ok(())
+    }
+
+    /// Copies [`FILE_NAME`] out of the environment and checks its contents.
+    fn check_self_test_file(&self, name: &EnvironmentName) -> Result<()> {
+        let mut buf = Vec::new();
+        self.runner
+            .copy_out_from_work(name, Path::new(FILE_NAME), &mut buf)?;
+        if buf != FILE_CONTENTS.as_bytes() {
+            return Err(anyhow!(
+                "expected {FILE_CONTENTS:?}, found {:?}",
+                String::from_utf8_lossy(&buf)
+            ));
+        }
+        Ok(())
+    }
+}
@@ -304,14 +304,16 @@ impl From<Error> for Box<dyn std::error::Error + Send + Sync + 'static> {
     }
 }
 
-/// Print a warning to stderr, with error chain and backtrace and all.
+/// Log a warning, with error chain and backtrace and all.
+///
+/// `cub` prints these to stderr (see [`crate::init_logging`]).
 pub fn warn(error: Error) {
-    eprintln!("WARNING: {error:?}");
+    tracing::warn!("{error:?}");
 }
 
-/// Print a brief warning to stderr.
+/// Log a brief warning.
 pub fn warn_brief(warning: String) {
-    eprintln!("WARNING: {warning}");
+    tracing::warn!("{warning}");
 }
 
 #[cfg(test)]
@@ -0,0 +1,202 @@
+//! Reports on the resources that Cubicle uses.
+
+use serde::Serialize;
+use std::collections::BTreeMap;
+
+use super::fs_util::{summarize_dir, try_exists};
+use super::runner::{EnvironmentExists, Runner};
+use super::{Bytes, Cubicle, EnvironmentName, HostPath};
+use crate::somehow::{somehow as anyhow, warn, Context, Result};
+
+/// Disk space used by Cubicle, as returned by [`Cubicle::get_disk_usage`].
+///
+/// All sizes are in bytes.
+#[derive(Debug, Serialize)]
+#[non_exhaustive]
+pub struct DiskUsage {
+    /// The space used by each environment.
+    pub environments: BTreeMap<EnvironmentName, EnvironmentDiskUsage>,
+    /// Built packages, ready to copy into environments.
+    pub package_cache: u64,
+    /// Environment metadata, such as labels and session recordings.
+    pub metadata: u64,
+    /// Clones of the dotfiles repository.
+    pub dotfiles: u64,
+    /// Space the runner uses outside of environments, such as for base
+    /// images, by description.
+    pub runner: BTreeMap<String, u64>,
+    /// The sum of all of the above.
+    pub total: u64,
+}
+
+/// Disk space used by an environment, as part of [`DiskUsage`].
+#[derive(Debug, Serialize)]
+#[non_exhaustive]
+pub struct EnvironmentDiskUsage {
+    /// The total size in bytes of the environment's home directory.
+    pub home_dir: u64,
+    /// The total size in bytes of the environment's work directory.
+    pub work_dir: u64,
+}
+
+/// Named boolean flag for [`Cubicle::print_disk_usage`].
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Json(pub bool);
+
+impl Cubicle {
+    /// Calculates how much disk space Cubicle is using.
+    pub fn get_disk_usage(&self) -> Result<DiskUsage> {
+        let environments = self
+            .get_environments()?
+            .into_iter()
+            .map(|(name, env)| {
+                (
+                    name,
+                    EnvironmentDiskUsage {
+                        home_dir: env.home_dir_size,
+                        work_dir: env.work_dir_size,
+                    },
+                )
+            })
+            .collect::<BTreeMap<_, _>>();
+        let package_cache = dir_size(&self.shared.package_cache);
+        let metadata = dir_size(&self.shared.data_dir.join("metadata"));
+        let dotfiles = dir_size(&self.dotfiles_dir());
+        let runner = self
+            .runner
+            .storage_usage()
+            .unwrap_or_else(|e| {
+                warn(e);
+                Vec::new()
+            })
+            .into_iter()
+            .map(|(description, size)| (description.to_owned(), size))
+            .collect::<BTreeMap<_, _>>();
+        let total = environments
+            .values()
+            .map(|env| env.home_dir + env.work_dir)
+            .chain([package_cache, metadata, dotfiles])
+            .chain(runner.values().copied())
+            .sum();
+        Ok(DiskUsage {
+            environments,
+            package_cache,
+            metadata,
+            dotfiles,
+            runner,
+            total,
+        })
+    }
+
+    /// Corresponds to `cub stats disk`.
+    pub fn print_disk_usage(&self, json: Json) -> Result<()> {
+        let usage = self.get_disk_usage()?;
+        if json.0 {
+            println!(
+                "{}",
+                serde_json::to_string_pretty(&usage)
+                    .context("failed to serialize JSON while reporting disk usage")?
+            );
+            return Ok(());
+        }
+
+        let mut rows: Vec<(String, u64)> = Vec::new();
+        let mut env_home = 0;
+        let mut env_work = 0;
+        for env in usage.environments.values() {
+            env_home += env.home_dir;
+            env_work += env.work_dir;
+        }
+        rows.push((
+            format!(
+                "environment home directories ({})",
+                usage.environments.len()
+            ),
+            env_home,
+        ));
+        rows.push((
+            format!(
+                "environment work directories ({})",
+                usage.environments.len()
+            ),
+            env_work,
+        ));
+        rows.push((String::from("package cache"), usage.package_cache));
+        rows.push((String::from("environment metadata"), usage.metadata));
+        rows.push((String::from("dotfiles"), usage.dotfiles));
+        rows.extend(usage.runner);
+        let w = rows.iter().map(|(what, _)| what.len()).max().unwrap_or(10);
+
+        // `Bytes` doesn't implement width/alignment, so it needs an extra
+        // `to_string()`.
+        #[allow(clippy::to_string_in_format_args)]
+        {
+            for (what, size) in rows {
+                println!("{what:<w$}  {:>10}", Bytes(size).to_string());
+            }
+            println!("{0:-<w$}  {0:-<10}", "");
+            println!("{:<w$}  {:>10}", "total", Bytes(usage.total).to_string());
+        }
+        Ok(())
+    }
+
+    /// Corresponds to `cub du`.
+    ///
+    /// Prints the `top` largest directories in the environment's home and
+    /// work directories, looking as far as `depth` levels below each.
+    pub fn du_environment(&self, name: &EnvironmentName, depth: usize, top: usize) -> Result<()> {
+        if self.runner.exists(name)? == EnvironmentExists::NoEnvironment {
+            return Err(anyhow!("Environment {name} does not exist"));
+        }
+        let sizes = self.runner.directory_sizes(name, depth)?;
+
+        // `Bytes` doesn't implement width/alignment, so it needs an extra
+        // `to_string()`.
+        #[allow(clippy::to_string_in_format_args)]
+        for (i, (title, dirs)) in [
+            ("Home directory", sizes.home_dir),
+            ("Work directory", sizes.work_dir),
+        ]
+        .into_iter()
+        .enumerate()
+        {
+            if i > 0 {
+                println!();
+            }
+            let (root, mut dirs): (Vec<_>, Vec<_>) = dirs
+                .into_iter()
+                .partition(|(path, _)| path.as_os_str().is_empty());
+            match root.first() {
+                Some((_, total)) => println!("{title} ({} total):", Bytes(*total)),
+                None => println!("{title} (not found):"),
+            }
+            dirs.sort_by(|(a_path, a_size), (b_path, b_size)| {
+                b_size.cmp(a_size).then_with(|| a_path.cmp(b_path))
+            });
+            for (path, size) in dirs.into_iter().take(top) {
+                println!("{:>10}  {}", Bytes(size).to_string(), path.display());
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Returns the total size of the directory, or 0 if it doesn't exist. This
+/// warns on errors, since a partial report is more useful than none.
+fn dir_size(dir: &HostPath) -> u64 {
+    match try_exists(dir).todo_context() {
+        Ok(true) => {}
+        Ok(false) => return 0,
+        Err(e) => {
+            warn(e.context(format!("failed to check if {dir} exists")));
+            return 0;
+        }
+    }
+    match summarize_dir(dir) {
+        Ok(summary) => summary.total_size,
+        Err(e) => {
+            warn(e.context(format!("failed to summarize disk usage of {dir}")));
+            0
+        }
+    }
+}
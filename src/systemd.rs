@@ -0,0 +1,202 @@
+//! Generates systemd user units for long-running Cubicle commands.
+
+use std::fmt::Write;
+
+use super::runner::{EnvironmentExists, Runner};
+use super::{Cubicle, EnvironmentName};
+use crate::somehow::{somehow as anyhow, warn, Context, Result};
+
+/// The services that run on the host (outside any environment) that
+/// [`Cubicle::systemd_unit`] knows about.
+const HOST_SERVICES: [&str; 3] = ["clipboard", "host-commands", "stop-idle"];
+
+impl Cubicle {
+    /// Corresponds to `cub generate systemd`.
+    ///
+    /// If `env` is given, returns a unit that runs the `service` command line
+    /// inside that environment and stops the environment afterwards.
+    /// Otherwise, `service` must name one of the Cubicle commands that runs
+    /// on the host, like `clipboard`.
+    pub fn systemd_unit(&self, env: Option<&EnvironmentName>, service: &str) -> Result<String> {
+        let exe =
+            std::env::current_exe().context("error getting the path of the current executable")?;
+        // Unit files are UTF-8 text. systemd also searches a few standard
+        // directories for commands given without a path.
+        let exe = match exe.to_str() {
+            Some(exe) => exe,
+            None => {
+                warn(anyhow!(
+                    "path of current executable is not valid UTF-8: {exe:?}; \
+                    using `cub` from systemd's search path instead"
+                ));
+                "cub"
+            }
+        };
+        match env {
+            Some(env) => {
+                if self.runner.exists(env)? == EnvironmentExists::NoEnvironment {
+                    return Err(anyhow!("Environment {env} does not exist"));
+                }
+                let command = shlex::split(service)
+                    .filter(|command| !command.is_empty())
+                    .ok_or_else(|| anyhow!("could not parse service command: {service:?}"))?;
+                Ok(env_service_unit(exe, env, &command))
+            }
+            None => host_service_unit(exe, service),
+        }
+    }
+}
+
+fn env_service_unit(exe: &str, env: &EnvironmentName, command: &[String]) -> String {
+    let mut exec_start = vec![exe, "exec", env.as_str(), "--"];
+    exec_start.extend(command.iter().map(|arg| arg.as_str()));
+    let mut unit = String::new();
+    writeln!(unit, "[Unit]").unwrap();
+    writeln!(
+        unit,
+        "Description=Cubicle environment {}: {}",
+        escape_specifiers(env.as_str()),
+        escape_specifiers(&command.join(" "))
+    )
+    .unwrap();
+    writeln!(unit).unwrap();
+    writeln!(unit, "[Service]").unwrap();
+    writeln!(unit, "ExecStart={}", exec_line(&exec_start)).unwrap();
+    // Processes in the environment may outlive the `cub exec` process,
+    // depending on the runner.
+    writeln!(
+        unit,
+        "ExecStopPost=-{}",
+        exec_line(&[exe, "stop", env.as_str()])
+    )
+    .unwrap();
+    writeln!(unit, "Restart=on-failure").unwrap();
+    writeln!(unit).unwrap();
+    writeln!(unit, "[Install]").unwrap();
+    writeln!(unit, "WantedBy=default.target").unwrap();
+    unit
+}
+
+fn host_service_unit(exe: &str, service: &str) -> Result<String> {
+    let (description, target) = match service {
+        "clipboard" => (
+            "Cubicle clipboard bridge",
+            // Needs `$DISPLAY` or `$WAYLAND_DISPLAY` from the desktop session.
+            "graphical-session.target",
+        ),
+        "host-commands" => (
+            "Cubicle host command broker",
+            // Commands like `xdg-open` need the desktop session too.
+            "graphical-session.target",
+        ),
+        "stop-idle" => ("Cubicle idle environment stopper", "default.target"),
+        _ => {
+            return Err(anyhow!(
+                "unknown service {service:?} (expected one of {}, or use `--env` \
+                to run a command in an environment)",
+                HOST_SERVICES.join(", ")
+            ))
+        }
+    };
+    let mut unit = String::new();
+    writeln!(unit, "[Unit]").unwrap();
+    writeln!(unit, "Description={description}").unwrap();
+    if target == "graphical-session.target" {
+        writeln!(unit, "PartOf={target}").unwrap();
+        writeln!(unit, "After={target}").unwrap();
+    }
+    writeln!(unit).unwrap();
+    writeln!(unit, "[Service]").unwrap();
+    writeln!(unit, "ExecStart={}", exec_line(&[exe, service])).unwrap();
+    writeln!(unit, "Restart=on-failure").unwrap();
+    writeln!(unit).unwrap();
+    writeln!(unit, "[Install]").unwrap();
+    writeln!(unit, "WantedBy={target}").unwrap();
+    Ok(unit)
+}
+
+/// Escapes `%`, which systemd would otherwise interpret as a specifier.
+fn escape_specifiers(s: &str) -> String {
+    s.replace('%', "%%")
+}
+
+/// Formats a command line for `ExecStart=` and similar, quoting each argument
+/// as needed. See `systemd.service(5)`.
+fn exec_line(args: &[&str]) -> String {
+    args.iter()
+        .map(|arg| {
+            let plain = !arg.is_empty()
+                && arg.chars().all(|c| {
+                    c.is_ascii_alphanumeric()
+                        || matches!(c, '/' | '.' | '_' | '-' | ':' | '=' | ',' | '+' | '@')
+                });
+            if plain {
+                arg.to_string()
+            } else {
+                let mut quoted = String::from("\"");
+                for c in arg.chars() {
+                    match c {
+                        '"' | '\\' => {
+                            quoted.push('\\');
+                            quoted.push(c);
+                        }
+                        '\n' => quoted.push_str("\\n"),
+                        '%' => quoted.push_str("%%"),
+                        '$' => quoted.push_str("$$"),
+                        _ => quoted.push(c),
+                    }
+                }
+                quoted.push('"');
+                quoted
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    #[test]
+    fn exec_line() {
+        assert_eq!(
+            super::exec_line(&[
+                "/bin/cub",
+                "exec",
+                "x",
+                "--",
+                "sh",
+                "-c",
+                "echo \"$HOME\" 50%"
+            ]),
+            r#"/bin/cub exec x -- sh -c "echo \"$$HOME\" 50%%""#
+        );
+        assert_eq!(super::exec_line(&["a", ""]), r#"a """#);
+    }
+
+    #[test]
+    fn env_service_unit() {
+        let env = EnvironmentName::from_str("web").unwrap();
+        assert_eq!(
+            super::env_service_unit(
+                "/bin/cub",
+                &env,
+                &[String::from("npm"), String::from("start")]
+            ),
+            "\
+[Unit]
+Description=Cubicle environment web: npm start
+
+[Service]
+ExecStart=/bin/cub exec web -- npm start
+ExecStopPost=-/bin/cub stop web
+Restart=on-failure
+
+[Install]
+WantedBy=default.target
+"
+        );
+    }
+}
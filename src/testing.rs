@@ -0,0 +1,608 @@
+//! Helpers for property-based tests of Cubicle and of tools built on it.
+//!
+//! This module is only available with the `testing` feature. It provides
+//! random generators for environment names, package names, and package
+//! manifests, and a [`FakeRunner`] that keeps environments in memory and can
+//! be scripted to fail. Tests can generate sequences of [`Op`]s with
+//! [`ops`], apply them to a [`Cubicle`] created with
+//! [`Cubicle::with_fake_runner`] or [`cubicle`], and check the runner's
+//! invariants after each step.
+//!
+//! The generators take any [`rand::Rng`], so tests can use a seeded RNG to
+//! reproduce failures.
+
+use rand::seq::SliceRandom;
+use rand::Rng;
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+use std::fmt::Write as _;
+use std::io;
+use std::path::Path;
+use std::process::ExitStatus;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+
+use super::config::Config;
+use super::fs_util::{summarize_dir, DirSummary};
+use super::runner::{
+    CheckedRunner, ConnectInfo, EnvDirectorySizes, EnvFilesSummary, EnvironmentExists, Init,
+    Orphan, Runner, RunnerCommand,
+};
+use super::{
+    packages, Cubicle, CubicleShared, EnvironmentName, Force, HostPath, KeepHome, Locked,
+    OutputStream, PackageName, Quiet, RunnerKind, Target, WorkspaceName,
+};
+use crate::somehow::{somehow as anyhow, Context, Result};
+
+/// Characters that environment names are built from. These include
+/// characters that are awkward in filenames and shells.
+const NAME_CHARS: &[char] = &[
+    'a', 'b', 'z', 'A', 'Z', '0', '9', '-', '_', '.', ' ', '/', '@', '%', '\'', '"', '$', '*',
+    '\\', 'é', '日', '🦀',
+];
+
+/// Characters that package names are built from.
+const PACKAGE_CHARS: &[char] = &['a', 'b', 'z', 'A', 'Z', '0', '9', '-', '_'];
+
+/// Returns a random valid environment name.
+pub fn environment_name(rng: &mut impl Rng) -> EnvironmentName {
+    let len = rng.gen_range(1..=12);
+    let mut name: String = (0..len).map(|_| *NAME_CHARS.choose(rng).unwrap()).collect();
+    // Names can't start or end with whitespace.
+    if name.starts_with(' ') {
+        name.replace_range(..1, "a");
+    }
+    if name.ends_with(' ') {
+        name.pop();
+        name.push('z');
+    }
+    EnvironmentName::from_str(&name).expect("generated environment name should be valid")
+}
+
+/// Returns a random valid package name.
+pub fn package_name(rng: &mut impl Rng) -> PackageName {
+    let len = rng.gen_range(1..=12);
+    let name: String = (0..len)
+        .map(|_| *PACKAGE_CHARS.choose(rng).unwrap())
+        .collect();
+    PackageName::strict_from_str(&name).expect("generated package name should be valid")
+}
+
+/// Returns the contents of a random valid `package.toml` file.
+///
+/// The package depends on a random subset of `available`, split between
+/// `depends` and `build_depends`.
+pub fn manifest(rng: &mut impl Rng, available: &[PackageName]) -> String {
+    let mut toml = String::new();
+    if rng.gen_bool(0.2) {
+        toml.push_str("package_manager = true\n");
+    }
+    let mut depends = Vec::new();
+    let mut build_depends = Vec::new();
+    for name in available.iter().collect::<BTreeSet<_>>() {
+        match rng.gen_range(0..4) {
+            0 => depends.push(name),
+            1 => build_depends.push(name),
+            _ => {}
+        }
+    }
+    for (table, names) in [("depends", depends), ("build_depends", build_depends)] {
+        if names.is_empty() && rng.gen_bool(0.5) {
+            continue;
+        }
+        writeln!(toml, "\n[{table}]").unwrap();
+        for name in names {
+            writeln!(toml, "{:?} = {{}}", name.as_str()).unwrap();
+        }
+    }
+    if rng.gen_bool(0.3) {
+        toml.push_str("\n[[targets]]\narch = \"x86_64\"\nos = \"linux\"\n");
+    }
+    toml
+}
+
+/// An operation on an environment, like a `cub` subcommand.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Op {
+    /// Like `cub new`: fails if the environment already exists.
+    Create(EnvironmentName),
+    /// Like `cub reset`: fails if the environment doesn't exist.
+    Reset(EnvironmentName),
+    /// Like `cub purge`: succeeds even if the environment doesn't exist.
+    Purge(EnvironmentName),
+}
+
+impl Op {
+    /// Returns the environment that this operation acts on.
+    pub fn name(&self) -> &EnvironmentName {
+        match self {
+            Self::Create(name) | Self::Reset(name) | Self::Purge(name) => name,
+        }
+    }
+
+    /// Applies the operation through the same [`Cubicle`] method as the
+    /// corresponding `cub` subcommand, without asking for confirmation.
+    pub fn apply(&self, cub: &Cubicle) -> Result<()> {
+        match self {
+            Self::Create(name) => cub.new_environment(name, Some(BTreeSet::new())),
+            Self::Reset(name) => {
+                cub.reset_environment(name, None, Force(true), KeepHome(false), Locked(false))
+            }
+            Self::Purge(name) => cub.purge_environment(name, Quiet(true), Force(true)),
+        }
+    }
+}
+
+/// Returns `len` random operations on the given environments.
+///
+/// Using only a few names makes interesting interleavings more likely.
+pub fn ops(rng: &mut impl Rng, names: &[EnvironmentName], len: usize) -> Vec<Op> {
+    (0..len)
+        .map(|_| {
+            let name = names.choose(rng).expect("need some names").clone();
+            match rng.gen_range(0..3) {
+                0 => Op::Create(name),
+                1 => Op::Reset(name),
+                _ => Op::Purge(name),
+            }
+        })
+        .collect()
+}
+
+/// A problem that [`FakeRunner`] simulates on its next change to an
+/// environment.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Fault {
+    /// The operation fails without changing anything.
+    Fail,
+    /// The operation fails partway, leaving the environment partially
+    /// existing, as if Cubicle crashed.
+    Crash,
+}
+
+/// The state of an environment in a [`FakeRunner`].
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum FakeState {
+    /// The environment partially exists, in a broken state.
+    Partial,
+    /// The environment fully exists.
+    Full,
+}
+
+/// A runner that keeps environments in memory.
+///
+/// It doesn't run anything. Use [`FakeRunner::inject`] to script failures.
+/// Clones share the same environments, so a test can keep one to inspect
+/// after passing another to [`Cubicle::with_fake_runner`].
+#[derive(Clone, Debug, Default)]
+pub struct FakeRunner {
+    inner: Arc<Inner>,
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+    envs: Mutex<BTreeMap<EnvironmentName, FakeState>>,
+    faults: Mutex<VecDeque<Fault>>,
+    log: Mutex<Vec<String>>,
+    work_dirs: Option<HostPath>,
+}
+
+impl FakeRunner {
+    /// Creates a runner with no environments.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Creates a runner with no environments that reports the disk usage of
+    /// real work directories in `dir`, one for each environment, with the
+    /// environment's name encoded as a filename. The runner doesn't create or
+    /// remove these.
+    pub fn with_work_dirs(dir: HostPath) -> Self {
+        Self {
+            inner: Arc::new(Inner {
+                work_dirs: Some(dir),
+                ..Inner::default()
+            }),
+        }
+    }
+
+    /// Queues a fault for the next change to an environment. Faults are
+    /// used up in the order they are injected.
+    pub fn inject(&self, fault: Fault) {
+        self.inner.faults.lock().unwrap().push_back(fault);
+    }
+
+    /// Returns the environments that exist, at least partially.
+    pub fn environments(&self) -> BTreeMap<EnvironmentName, FakeState> {
+        self.inner.envs.lock().unwrap().clone()
+    }
+
+    /// Returns a description of each change made to an environment, in
+    /// order.
+    pub fn log(&self) -> Vec<String> {
+        self.inner.log.lock().unwrap().clone()
+    }
+
+    /// Checks that the runner's answers agree with each other.
+    pub fn check_invariants(&self) -> Result<()> {
+        let listed: BTreeSet<EnvironmentName> = self.list()?.into_iter().collect();
+        let envs = self.environments();
+        if listed.len() != envs.len() || !envs.keys().all(|name| listed.contains(name)) {
+            return Err(anyhow!(
+                "listed environments {listed:?} don't match existing environments {envs:?}"
+            ));
+        }
+        for (name, state) in envs {
+            let expected = match state {
+                FakeState::Partial => EnvironmentExists::PartiallyExists,
+                FakeState::Full => EnvironmentExists::FullyExists,
+            };
+            let exists = self.exists(&name)?;
+            if exists != expected {
+                return Err(anyhow!(
+                    "environment {name} is {state:?} but exists() returned {exists:?}"
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Records a change to the environment, applying the next fault, if any.
+    fn change(&self, what: &str, name: &EnvironmentName, after: Option<FakeState>) -> Result<()> {
+        let fault = self.inner.faults.lock().unwrap().pop_front();
+        let mut envs = self.inner.envs.lock().unwrap();
+        self.inner.log.lock().unwrap().push(format!(
+            "{what} {name}{}",
+            match fault {
+                Some(fault) => format!(" ({fault:?})"),
+                None => String::new(),
+            }
+        ));
+        match fault {
+            Some(Fault::Fail) => Err(anyhow!("injected failure in {what} {name}")),
+            Some(Fault::Crash) => {
+                envs.insert(name.clone(), FakeState::Partial);
+                Err(anyhow!("injected crash in {what} {name}"))
+            }
+            None => {
+                match after {
+                    Some(state) => envs.insert(name.clone(), state),
+                    None => envs.remove(name),
+                };
+                Ok(())
+            }
+        }
+    }
+
+    fn require_full(&self, name: &EnvironmentName) -> Result<()> {
+        match self.inner.envs.lock().unwrap().get(name) {
+            Some(FakeState::Full) => Ok(()),
+            _ => Err(anyhow!("environment {name} does not fully exist")),
+        }
+    }
+}
+
+impl Runner for FakeRunner {
+    fn list(&self) -> Result<Vec<EnvironmentName>> {
+        Ok(self.inner.envs.lock().unwrap().keys().cloned().collect())
+    }
+
+    fn copy_out_from_home(
+        &self,
+        name: &EnvironmentName,
+        _path: &Path,
+        _w: &mut dyn io::Write,
+    ) -> Result<()> {
+        self.require_full(name)
+    }
+
+    fn copy_out_from_work(
+        &self,
+        name: &EnvironmentName,
+        _path: &Path,
+        _w: &mut dyn io::Write,
+    ) -> Result<()> {
+        // The work directory outlives crashes, so this works even for
+        // partially existing environments.
+        if self.inner.envs.lock().unwrap().contains_key(name) {
+            Ok(())
+        } else {
+            Err(anyhow!("environment {name} does not exist"))
+        }
+    }
+
+    fn copy_out(&self, name: &EnvironmentName, _path: &Path, w: &mut dyn io::Write) -> Result<()> {
+        self.require_full(name)?;
+        tar::Builder::new(w)
+            .finish()
+            .context("failed to write archive")
+    }
+
+    fn copy_in(&self, name: &EnvironmentName, _dir: &Path, r: &mut dyn io::Read) -> Result<()> {
+        self.require_full(name)?;
+        io::copy(r, &mut io::sink()).context("failed to read archive")?;
+        Ok(())
+    }
+
+    fn create(&self, name: &EnvironmentName, _init: &Init) -> Result<()> {
+        if self.inner.envs.lock().unwrap().contains_key(name) {
+            return Err(anyhow!("environment {name} already exists"));
+        }
+        self.change("create", name, Some(FakeState::Full))
+    }
+
+    fn exists(&self, name: &EnvironmentName) -> Result<EnvironmentExists> {
+        Ok(match self.inner.envs.lock().unwrap().get(name) {
+            None => EnvironmentExists::NoEnvironment,
+            Some(FakeState::Partial) => EnvironmentExists::PartiallyExists,
+            Some(FakeState::Full) => EnvironmentExists::FullyExists,
+        })
+    }
+
+    fn files_summary(&self, name: &EnvironmentName) -> Result<EnvFilesSummary> {
+        let work_dir_path = self
+            .inner
+            .work_dirs
+            .as_ref()
+            .map(|dir| dir.join(name.as_filename()));
+        Ok(EnvFilesSummary {
+            home_dir_path: None,
+            home_dir: DirSummary::new_with_errors(),
+            work_dir: match &work_dir_path {
+                Some(path) => summarize_dir(path)?,
+                None => DirSummary::new_with_errors(),
+            },
+            work_dir_path,
+        })
+    }
+
+    fn directory_sizes(&self, _name: &EnvironmentName, _depth: usize) -> Result<EnvDirectorySizes> {
+        Ok(EnvDirectorySizes::default())
+    }
+
+    fn connect_info(&self, _name: &EnvironmentName) -> Result<ConnectInfo> {
+        Ok(ConnectInfo::default())
+    }
+
+    fn storage_usage(&self) -> Result<Vec<(&'static str, u64)>> {
+        Ok(Vec::new())
+    }
+
+    fn stop(&self, _name: &EnvironmentName) -> Result<()> {
+        Ok(())
+    }
+
+    fn is_running(&self, _name: &EnvironmentName) -> Result<bool> {
+        Ok(false)
+    }
+
+    fn running_processes(&self, _name: &EnvironmentName) -> Result<Option<usize>> {
+        Ok(None)
+    }
+
+    fn checkpoint(&self, _name: &EnvironmentName) -> Result<()> {
+        Err(anyhow!("the fake runner does not support checkpoints"))
+    }
+
+    fn reset(&self, name: &EnvironmentName, _init: &Init) -> Result<()> {
+        self.change("reset", name, Some(FakeState::Full))
+    }
+
+    fn refresh(&self, name: &EnvironmentName, _init: &Init) -> Result<()> {
+        self.require_full(name)?;
+        self.change("refresh", name, Some(FakeState::Full))
+    }
+
+    fn purge(&self, name: &EnvironmentName) -> Result<()> {
+        // Purging an environment that doesn't exist changes nothing.
+        if !self.inner.envs.lock().unwrap().contains_key(name) {
+            return Ok(());
+        }
+        self.change("purge", name, None)
+    }
+
+    fn purge_workspace(&self, _name: &WorkspaceName) -> Result<()> {
+        Ok(())
+    }
+
+    fn run(&self, name: &EnvironmentName, _command: &RunnerCommand) -> Result<ExitStatus> {
+        self.require_full(name)?;
+        Ok(ExitStatus::default())
+    }
+
+    fn run_streaming(
+        &self,
+        name: &EnvironmentName,
+        _command: &RunnerCommand,
+        _on_output: &mut dyn FnMut(OutputStream, &[u8]),
+    ) -> Result<ExitStatus> {
+        self.require_full(name)?;
+        Ok(ExitStatus::default())
+    }
+
+    fn run_ephemeral(&self, _name: &EnvironmentName, _init: &Init) -> Result<()> {
+        Ok(())
+    }
+
+    fn run_read_only_home(&self, name: &EnvironmentName, _command: &RunnerCommand) -> Result<()> {
+        self.require_full(name)
+    }
+
+    fn query_image(&self, _name: Option<&EnvironmentName>, _script: &str) -> Result<String> {
+        Ok(String::new())
+    }
+
+    fn supports_any(&self, _targets: &[Target]) -> Result<bool> {
+        Ok(true)
+    }
+
+    fn clean_orphans(&self) -> Result<Vec<String>> {
+        Ok(Vec::new())
+    }
+
+    fn orphans(&self) -> Result<Vec<Orphan>> {
+        Ok(Vec::new())
+    }
+
+    fn remove_orphan(&self, orphan: &Orphan) -> Result<()> {
+        Err(anyhow!("the fake runner has no {orphan}"))
+    }
+}
+
+/// Parses a config for tests from `toml`, which shouldn't set `runner`.
+///
+/// This always chooses the Bubblewrap runner, whichever runners are compiled
+/// in. Tests using a [`FakeRunner`] never run it, but the config needs one,
+/// and code that looks at [`Config::runner`] then sees the same one in every
+/// test.
+pub fn config(toml: &str) -> Config {
+    let mut config: Config = toml::from_str(&format!("runner = 'bubblewrap'\n{toml}"))
+        .expect("test config should be valid");
+    config.runner = RunnerKind::Bubblewrap;
+    config
+}
+
+/// Creates an instance for tests with a new [`FakeRunner`] and a config from
+/// [`config`], keeping all its files under `root`.
+pub fn cubicle(root: &Path, toml: &str) -> Cubicle {
+    Cubicle::with_fake_runner(config(toml), FakeRunner::new(), root)
+        .expect("test Cubicle should be created")
+}
+
+impl Cubicle {
+    /// Creates an instance that uses the given runner instead of the
+    /// configured one and keeps all its files under `root`.
+    ///
+    /// Unless the config sets `builtin_package_dir`, this writes empty
+    /// definitions for the built-in packages under `root`, so that creating
+    /// and resetting environments doesn't build any packages. Only available
+    /// with the `testing` feature.
+    pub fn with_fake_runner(config: Config, runner: FakeRunner, root: &Path) -> Result<Self> {
+        let root = HostPath::try_from(root.to_owned())?;
+        let builtin_package_dir = config.builtin_package_dir.clone();
+        let mut shared = CubicleShared::with_root(config, root);
+        let code_package_dir = match builtin_package_dir {
+            Some(dir) => HostPath::try_from(dir)?,
+            None => {
+                let dir = shared.code_package_dir.clone();
+                for name in [
+                    packages::special::AUTO_BATCH,
+                    packages::special::AUTO_INTERACTIVE,
+                    packages::special::CONFIGS_CORE,
+                    packages::special::DEFAULT,
+                ] {
+                    let package_dir = dir.join(name);
+                    std::fs::create_dir_all(package_dir.as_host_raw())
+                        .and_then(|()| {
+                            std::fs::write(package_dir.join("package.toml").as_host_raw(), "")
+                        })
+                        .with_context(|| format!("failed to write package {package_dir}"))?;
+                }
+                dir
+            }
+        };
+        Arc::get_mut(&mut shared)
+            .expect("new CubicleShared should not be shared yet")
+            .code_package_dir = code_package_dir;
+        Ok(Self {
+            shared,
+            runner: CheckedRunner::new(Box::new(runner)),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    #[test]
+    fn environment_name_filename_round_trip() {
+        let mut rng = StdRng::seed_from_u64(0);
+        for _ in 0..1000 {
+            let name = environment_name(&mut rng);
+            let filename = name.as_filename();
+            assert!(!filename.contains('/'), "{filename:?}");
+            assert_eq!(
+                EnvironmentName::from_filename(filename.as_ref()).unwrap(),
+                name
+            );
+        }
+    }
+
+    #[test]
+    fn op_sequences() {
+        for seed in 0..200 {
+            let mut rng = StdRng::seed_from_u64(seed);
+            let names: Vec<_> = (0..3).map(|_| environment_name(&mut rng)).collect();
+            let runner = FakeRunner::new();
+            let root = tempfile::tempdir().unwrap();
+            let cub = Cubicle::with_fake_runner(config(""), runner.clone(), root.path()).unwrap();
+            // Creating environments in a workspace requires the workspace.
+            let workspaces: BTreeSet<_> = names
+                .iter()
+                .filter_map(EnvironmentName::workspace)
+                .collect();
+            for workspace in workspaces {
+                cub.create_workspace(&workspace).unwrap();
+            }
+            for op in ops(&mut rng, &names, 30) {
+                let before = runner.environments().get(op.name()).copied();
+                let fault = match rng.gen_range(0..10) {
+                    0 => Some(Fault::Fail),
+                    1 => Some(Fault::Crash),
+                    _ => None,
+                };
+                if let Some(fault) = fault {
+                    runner.inject(fault);
+                }
+                let result = op.apply(&cub);
+                let after = runner.environments().get(op.name()).copied();
+                runner.check_invariants().unwrap();
+
+                let context = format!("seed {seed}, {op:?}, log: {:#?}", runner.log());
+                match &op {
+                    Op::Create(_) if before.is_some() => {
+                        assert!(result.is_err(), "{context}");
+                        assert_eq!(before, after, "{context}");
+                    }
+                    Op::Reset(_) if before.is_none() => {
+                        assert!(result.is_err(), "{context}");
+                        assert_eq!(after, None, "{context}");
+                    }
+                    Op::Purge(_) if before.is_none() => {
+                        assert!(result.is_ok(), "{context}");
+                        assert_eq!(after, None, "{context}");
+                    }
+                    _ => match fault {
+                        None => {
+                            assert!(result.is_ok(), "{context}");
+                            let expected = match op {
+                                Op::Purge(_) => None,
+                                _ => Some(FakeState::Full),
+                            };
+                            assert_eq!(after, expected, "{context}");
+                        }
+                        Some(Fault::Fail) => {
+                            assert!(result.is_err(), "{context}");
+                            assert_eq!(before, after, "{context}");
+                        }
+                        Some(Fault::Crash) => {
+                            assert!(result.is_err(), "{context}");
+                            assert_eq!(after, Some(FakeState::Partial), "{context}");
+                        }
+                    },
+                }
+                // Unused faults would leak into the next operation.
+                runner.inner.faults.lock().unwrap().clear();
+            }
+
+            // Purging always cleans up, whatever state things were left in.
+            for name in &names {
+                Op::Purge(name.clone()).apply(&cub).unwrap();
+            }
+            assert!(runner.environments().is_empty());
+            runner.check_invariants().unwrap();
+        }
+    }
+}
@@ -1,21 +1,34 @@
+use std::ffi::{OsStr, OsString};
 use std::io::{self, BufRead, Write};
-use std::path::Path;
-use std::process::Stdio;
-use std::rc::Rc;
-use std::time::{SystemTime, UNIX_EPOCH};
-
-use super::command_ext::Command;
-use super::fs_util::{summarize_dir, DirSummary};
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::process::{ExitStatus, Stdio};
+use std::sync::Arc;
+use std::time::{Duration, UNIX_EPOCH};
+use tracing::{error, info};
+
+use super::command_ext::{Command, OutputStream};
+use super::copy::{tar_create_args, tar_extract_args};
+use super::fs_util::{
+    host_tempfile, summarize_dir, tar_size, try_iterdir, write_seeds, DirSummary,
+};
 use super::runner::{
-    EnvFilesSummary, EnvironmentExists, Init, Runner, RunnerCommand, Target,
-    LOCALE_ENVIRONMENT_VARIABLES,
+    query_host, run_du, systemd_scope_args, ConnectInfo, EnvDirectorySizes, EnvFilesSummary,
+    EnvironmentExists, Init, Orphan, Runner, RunnerCommand, Target, LOCALE_ENVIRONMENT_VARIABLES,
+    SYSTEMD_RUN_ENV_VARS,
+};
+use super::{
+    CubicleShared, Distro, EnvironmentName, Event, ExitStatusError, HostPath, WorkspaceName,
 };
-use super::{apt, CubicleShared, EnvironmentName, ExitStatusError, HostPath};
 use crate::encoding::{percent_decode, percent_encode, FilenameEncoder};
 use crate::somehow::{somehow as anyhow, Context, LowLevelResult, Result};
 
+/// How old a work directory backup from a failed `cub reset` must be before
+/// `cub prune` offers to remove it.
+const STALE_WORK_BACKUP: Duration = Duration::from_secs(60 * 60 * 24 * 7);
+
 pub struct User {
-    pub(super) program: Rc<CubicleShared>,
+    pub(super) program: Arc<CubicleShared>,
     username_prefix: &'static str,
     work_tars: HostPath,
 }
@@ -73,12 +86,8 @@ mod newtypes {
 use newtypes::Username;
 
 impl User {
-    pub(super) fn new(program: Rc<CubicleShared>) -> Result<Self> {
-        let xdg_data_home = match std::env::var("XDG_DATA_HOME") {
-            Ok(path) => HostPath::try_from(path)?,
-            Err(_) => program.home.join(".local").join("share"),
-        };
-        let work_tars = xdg_data_home.join("cubicle").join("work");
+    pub(super) fn new(program: Arc<CubicleShared>) -> Result<Self> {
+        let work_tars = program.data_dir.join("work");
 
         Ok(Self {
             program,
@@ -97,7 +106,7 @@ impl User {
     }
 
     fn user_exists_(&self, username: &Username) -> LowLevelResult<bool> {
-        let status = Command::new("sudo")
+        let status = Command::query("sudo")
             .args(["--user", username.as_str()])
             .arg("--")
             .arg("true")
@@ -144,14 +153,14 @@ impl User {
             .args(["--user", username.as_str()])
             .arg("--")
             .arg("mkdir")
-            .arg("w")
+            .arg(self.program.config.work_dir_name(env_name))
             .env_clear()
             .status()
             .and_then(|status| {
                 if status.success() {
                     Ok(())
                 } else {
-                    Err(anyhow!("`sudo ... mkdir w` exited with {status}"))
+                    Err(anyhow!("`sudo ... mkdir` exited with {status}"))
                 }
             })
             .with_context(|| format!("failed to create work directory for {username}"))?;
@@ -185,12 +194,19 @@ impl User {
         }
 
         println!("Copying/extracting seed tarball");
+        let mut size: u64 = 0;
+        for seed in seeds {
+            size += tar_size(seed)?;
+        }
+        // The seeds are written to `pv` from here, since some are
+        // compressed.
         let mut source = Command::new("pv")
-            .args(["-i", "0.1"])
-            .args(seeds.iter().map(|s| s.as_host_raw()))
+            .args(["-i", "0.1", "--size", &size.to_string()])
+            .stdin(Stdio::piped())
             .stdout(Stdio::piped())
             .scoped_spawn()?;
-        let mut source_stdout = source.stdout().take().unwrap();
+        let mut source_stdin = source.stdin().take().unwrap();
+        let source_stdout = source.stdout().take().unwrap();
 
         let mut dest = Command::new("sudo")
             // This used to use `--chdir ~`, but that was introduced
@@ -207,11 +223,16 @@ impl User {
             .stdin(Stdio::piped())
             .scoped_spawn()?;
 
-        {
+        std::thread::scope(|s| -> LowLevelResult<()> {
+            // Moved in here so `pv` isn't left blocked if this fails.
+            let mut source_stdout = source_stdout;
+            let writer = s.spawn(move || write_seeds(seeds.iter().copied(), &mut source_stdin));
             let mut dest_stdin = dest.stdin().take().unwrap();
             io::copy(&mut source_stdout, &mut dest_stdin)?;
             dest_stdin.flush()?;
-        }
+            writer.join().expect("seed writer thread panicked")?;
+            Ok(())
+        })?;
 
         let status = dest.wait()?;
         if !status.success() {
@@ -262,24 +283,41 @@ impl User {
         }
     }
 
+    /// Returns a command that runs `tar` with the given arguments as the
+    /// user, in the user's home directory.
+    fn tar_as_user(&self, username: &Username, args: Vec<OsString>) -> Command {
+        let mut command = Command::new("sudo");
+        command
+            // See notes about `--chdir` elsewhere.
+            .arg("--login")
+            .args(["--user", username.as_str()])
+            .arg("--")
+            .arg("tar")
+            .args(args)
+            .env_clear();
+        command
+    }
+
     fn init(
         &self,
         env_name: &EnvironmentName,
         Init {
-            debian_packages,
+            distro_packages,
+            env_distro_packages,
             env_vars,
             seeds,
         }: &Init,
     ) -> Result<()> {
-        apt::check_satisfied(
-            &debian_packages
+        Distro::host().check_host_packages(
+            &distro_packages
                 .iter()
+                .chain(env_distro_packages)
                 .map(|s| s.as_str())
                 .collect::<Vec<&str>>(),
         );
 
         let username = self.username_from_environment(env_name);
-        let script_tar = tempfile::NamedTempFile::new().todo_context()?;
+        let script_tar = host_tempfile().todo_context()?;
         let mut builder = tar::Builder::new(script_tar.as_file());
 
         let mut header = tar::Header::new_gnu();
@@ -302,19 +340,43 @@ impl User {
         let script_tar_path = HostPath::try_from(script_tar.path().to_owned())?;
         seeds.push(&script_tar_path);
         self.copy_in_seeds(&username, &seeds)?;
+        self.program.progress(Event::SeedCopied {
+            environment: env_name,
+        });
         self.run_(
             env_name,
             &RunnerCommand::Exec {
                 command: &["../.cubicle-init-script".to_owned()],
                 env_vars,
+                dir: None,
             },
         )
+        .and_then(|status| ExitStatusError::check(status, "sudo --user"))
+    }
+
+    /// Runs the command as the environment's user and returns its exit
+    /// status.
+    fn run_(&self, env_name: &EnvironmentName, run_command: &RunnerCommand) -> Result<ExitStatus> {
+        self.sudo_command(env_name, run_command)?.status()
     }
 
-    fn run_(&self, env_name: &EnvironmentName, run_command: &RunnerCommand) -> Result<()> {
+    /// Returns a command that runs the given command as the environment's
+    /// user.
+    fn sudo_command(
+        &self,
+        env_name: &EnvironmentName,
+        run_command: &RunnerCommand,
+    ) -> Result<Command> {
         let username = self.username_from_environment(env_name);
 
-        let mut command = Command::new("sudo");
+        let limits = systemd_scope_args(&self.program.config);
+        let mut command = if limits.is_empty() {
+            Command::new("sudo")
+        } else {
+            let mut command = Command::new("systemd-run");
+            command.args(&limits).arg("sudo");
+            command
+        };
 
         command
             // This used to use `--chdir ~//w`, but that was introduced
@@ -326,9 +388,31 @@ impl User {
             .args(["--user", username.as_str()]);
 
         command.env_clear();
+        // `systemd-run` needs these. `sudo` won't pass them through.
+        if !limits.is_empty() {
+            for var in SYSTEMD_RUN_ENV_VARS {
+                if let Ok(value) = std::env::var(var) {
+                    command.env(var, value);
+                }
+            }
+        }
         command
             .env("CUBICLE", env_name.as_str())
             .arg("--preserve-env=CUBICLE");
+        let work_dir_name = self.program.config.work_dir_name(env_name);
+        command
+            .env("CUBICLE_WORK_DIR", work_dir_name)
+            .arg("--preserve-env=CUBICLE_WORK_DIR");
+        let start_dir = match run_command.dir() {
+            Some(dir) => Path::new(work_dir_name).join(dir),
+            None => PathBuf::from(work_dir_name),
+        };
+        // The directory is quoted as bytes, since it may not be UTF-8.
+        let mut cd_work = OsString::from("cd ");
+        cd_work.push(OsStr::from_bytes(
+            &shlex::bytes::try_quote(start_dir.as_os_str().as_bytes())
+                .with_context(|| format!("failed to quote starting directory {start_dir:?}"))?,
+        ));
         command
             .env("SHELL", &self.program.shell)
             .arg("--preserve-env=SHELL");
@@ -340,8 +424,13 @@ impl User {
                 command.env(var, value).arg(format!("--preserve-env={var}"));
             }
         }
+        if let Some(workspace) = self.program.config.workspace_for(env_name) {
+            for (var, value) in &workspace.env {
+                command.env(var, value).arg(format!("--preserve-env={var}"));
+            }
+        }
         match run_command {
-            RunnerCommand::Interactive => {}
+            RunnerCommand::Interactive { .. } => {}
             RunnerCommand::Exec { env_vars, .. } => {
                 for (var, value) in *env_vars {
                     command.env(var, value).arg(format!("--preserve-env={var}"));
@@ -352,24 +441,20 @@ impl User {
         command.arg("--").arg(&self.program.shell);
 
         match run_command {
-            RunnerCommand::Interactive => {
-                command.args(["-c", &format!("cd w && exec {}", self.program.shell)]);
+            RunnerCommand::Interactive { .. } => {
+                cd_work.push(format!(" && exec {}", self.program.shell));
+                command.arg("-c").arg(cd_work);
             }
             RunnerCommand::Exec { command: exec, .. } => {
-                command.arg("-c");
-                command.arg(format!(
-                    "cd w && {}",
+                cd_work.push(format!(
+                    " && {}",
                     shlex::try_join(exec.iter().map(|a| a.as_str())).expect("TODO")
                 ));
+                command.arg("-c").arg(cd_work);
             }
         }
 
-        let status = command.status()?;
-        if status.success() {
-            Ok(())
-        } else {
-            Err(ExitStatusError::new(status, "sudo --user").into())
-        }
+        Ok(command)
     }
 }
 
@@ -391,7 +476,45 @@ impl Runner for User {
         w: &mut dyn io::Write,
     ) -> Result<()> {
         let username = self.username_from_environment(env_name);
-        self.copy_out(&username, &Path::new("w").join(path), w)
+        let work_dir_name = self.program.config.work_dir_name(env_name);
+        self.copy_out(&username, &Path::new(work_dir_name).join(path), w)
+    }
+
+    fn copy_out(
+        &self,
+        env_name: &EnvironmentName,
+        path: &Path,
+        w: &mut dyn io::Write,
+    ) -> Result<()> {
+        let username = self.username_from_environment(env_name);
+        let mut child = self
+            .tar_as_user(&username, tar_create_args(path)?)
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .scoped_spawn()?;
+        let mut stdout = child.stdout().take().unwrap();
+        io::copy(&mut stdout, w).context("error reading/writing data")?;
+        let status = child.wait()?;
+        if !status.success() {
+            return Err(anyhow!("`sudo ... tar` exited with {status}"));
+        }
+        Ok(())
+    }
+
+    fn copy_in(&self, env_name: &EnvironmentName, dir: &Path, r: &mut dyn io::Read) -> Result<()> {
+        let username = self.username_from_environment(env_name);
+        let mut child = self
+            .tar_as_user(&username, tar_extract_args(dir))
+            .stdin(Stdio::piped())
+            .scoped_spawn()?;
+        let mut stdin = child.stdin().take().unwrap();
+        io::copy(r, &mut stdin).context("error reading/writing data")?;
+        drop(stdin);
+        let status = child.wait()?;
+        if !status.success() {
+            return Err(anyhow!("`sudo ... tar` exited with {status}"));
+        }
+        Ok(())
     }
 
     fn create(&self, env_name: &EnvironmentName, init: &Init) -> Result<()> {
@@ -454,7 +577,7 @@ impl Runner for User {
                 // but it'd need to be tolerant of different versions of `du`.
                 let summary =
                     summarize_dir(&home).unwrap_or_else(|_| DirSummary::new_with_errors());
-                let work_dir_path = Some(home.join("w"));
+                let work_dir_path = Some(home.join(self.program.config.work_dir_name(env_name)));
                 Ok(EnvFilesSummary {
                     home_dir_path: Some(home),
                     home_dir: summary,
@@ -471,11 +594,100 @@ impl Runner for User {
         }
     }
 
+    fn directory_sizes(
+        &self,
+        env_name: &EnvironmentName,
+        depth: usize,
+    ) -> Result<EnvDirectorySizes> {
+        let username = self.username_from_environment(env_name);
+        let Some(home) = Passwd::open()?
+            .filter_map(|account| account.ok())
+            .find(|account| account.username == username.as_str())
+            .map(|account| account.home)
+        else {
+            return Ok(EnvDirectorySizes::default());
+        };
+        // The host user may not be able to read the environment user's
+        // files, so this runs `du` as that user.
+        let du = || {
+            let mut command = Command::query("sudo");
+            command
+                .args(["--user", username.as_str()])
+                .args(["--", "du"])
+                .env_clear();
+            command
+        };
+        let work_dir = home.join(self.program.config.work_dir_name(env_name));
+        Ok(EnvDirectorySizes {
+            home_dir: run_du(&mut du(), depth, home.as_host_raw())?,
+            work_dir: run_du(&mut du(), depth, work_dir.as_host_raw())?,
+        })
+    }
+
+    fn connect_info(&self, env_name: &EnvironmentName) -> Result<ConnectInfo> {
+        let username = self.username_from_environment(env_name);
+        let home = Passwd::open()?
+            .filter_map(|account| account.ok())
+            .find(|account| account.username == username.as_str())
+            .map(|account| account.home);
+        let work_dir = home
+            .as_ref()
+            .map(|home| home.join(self.program.config.work_dir_name(env_name)));
+        // The environment's files are on the host, but they're likely only
+        // accessible to its user.
+        Ok(ConnectInfo {
+            user: Some(username.as_str().to_owned()),
+            home_dir: home.as_ref().map(|home| home.as_host_raw().to_owned()),
+            work_dir: work_dir.map(|dir| dir.as_host_raw().to_owned()),
+            attach_command: Some(vec![
+                String::from("sudo"),
+                String::from("--login"),
+                String::from("--user"),
+                username.as_str().to_owned(),
+            ]),
+            ..ConnectInfo::default()
+        })
+    }
+
+    fn storage_usage(&self) -> Result<Vec<(&'static str, u64)>> {
+        Ok(Vec::new())
+    }
+
     fn stop(&self, env_name: &EnvironmentName) -> Result<()> {
         let username = self.username_from_environment(env_name);
         self.kill_username(&username)
     }
 
+    fn is_running(&self, env_name: &EnvironmentName) -> Result<bool> {
+        Ok(self.running_processes(env_name)?.is_some())
+    }
+
+    fn running_processes(&self, env_name: &EnvironmentName) -> Result<Option<usize>> {
+        let username = self.username_from_environment(env_name);
+        if !self.user_exists(&username)? {
+            return Ok(None);
+        }
+        let output = Command::query("pgrep")
+            .arg("--count")
+            .args(["--uid", username.as_str()])
+            .output()
+            .with_context(|| format!("failed to run pgrep for user {username}"))?;
+        match output.status.code() {
+            Some(0) | Some(1) => {}
+            _ => return Err(anyhow!("`pgrep` exited with {}", output.status)),
+        }
+        let count = String::from_utf8_lossy(&output.stdout);
+        let count = count
+            .trim()
+            .parse::<usize>()
+            .with_context(|| format!("unexpected output from `pgrep`: {count:?}"))?;
+        Ok((count > 0).then_some(count))
+    }
+
+    fn checkpoint(&self, _env_name: &EnvironmentName) -> Result<()> {
+        Err(anyhow!("checkpointing is not supported by the user runner"))
+    }
+
     fn reset(&self, env_name: &EnvironmentName, init: &Init) -> Result<()> {
         let username = self.username_from_environment(env_name);
         self.kill_username(&username)?;
@@ -487,7 +699,9 @@ impl Runner for User {
                 .push("-")
                 .push(&format!(
                     "{}",
-                    SystemTime::now()
+                    self.program
+                        .clock
+                        .now()
                         .duration_since(UNIX_EPOCH)
                         .unwrap()
                         .as_secs()
@@ -497,7 +711,7 @@ impl Runner for User {
         );
 
         let save = || -> LowLevelResult<()> {
-            println!("Saving work directory to {work_tar}");
+            info!("Saving work directory to {work_tar}");
             let mut child = Command::new("sudo")
                 // See notes about `--chdir` elsewhere.
                 .arg("--login")
@@ -505,7 +719,7 @@ impl Runner for User {
                 .arg("--")
                 .arg("tar")
                 .arg("--create")
-                .arg("w")
+                .arg(self.program.config.work_dir_name(env_name))
                 .env_clear()
                 .stdout(Stdio::piped())
                 .scoped_spawn()?;
@@ -535,11 +749,12 @@ impl Runner for User {
         let purge_and_restore = || -> Result<()> {
             self.purge(env_name)?;
             self.create(env_name, init)?;
-            println!("Restoring work directory from {work_tar}");
+            info!("Restoring work directory from {work_tar}");
             self.init(
                 env_name,
                 &Init {
-                    debian_packages: Vec::new(),
+                    distro_packages: Vec::new(),
+                    env_distro_packages: Vec::new(),
                     env_vars: Vec::new(),
                     seeds: vec![work_tar.clone()],
                 },
@@ -555,13 +770,21 @@ impl Runner for User {
                 Ok(())
             }
             Err(e) => {
-                println!("Encountered an error while resetting environment {env_name}.");
-                println!("A copy of its work directory is here: {work_tar}");
+                error!(
+                    "Encountered an error while resetting environment {env_name}. \
+                    A copy of its work directory is here: {work_tar}"
+                );
                 Err(e)
             }
         }
     }
 
+    fn refresh(&self, env_name: &EnvironmentName, init: &Init) -> Result<()> {
+        let username = self.username_from_environment(env_name);
+        self.kill_username(&username)?;
+        self.init(env_name, init)
+    }
+
     fn purge(&self, env_name: &EnvironmentName) -> Result<()> {
         if !self.list()?.contains(env_name) {
             return Ok(());
@@ -584,10 +807,43 @@ impl Runner for User {
             .with_context(|| format!("failed to delete user {username}"))
     }
 
-    fn run(&self, env_name: &EnvironmentName, run_command: &RunnerCommand) -> Result<()> {
+    fn purge_workspace(&self, _name: &WorkspaceName) -> Result<()> {
+        Ok(())
+    }
+
+    fn run(&self, env_name: &EnvironmentName, run_command: &RunnerCommand) -> Result<ExitStatus> {
         self.run_(env_name, run_command)
     }
 
+    fn run_streaming(
+        &self,
+        env_name: &EnvironmentName,
+        run_command: &RunnerCommand,
+        on_output: &mut dyn FnMut(OutputStream, &[u8]),
+    ) -> Result<ExitStatus> {
+        self.sudo_command(env_name, run_command)?.stream(on_output)
+    }
+
+    fn run_ephemeral(&self, _env_name: &EnvironmentName, _init: &Init) -> Result<()> {
+        Err(anyhow!(
+            "ephemeral environments are not supported by the user runner"
+        ))
+    }
+
+    fn run_read_only_home(
+        &self,
+        _env_name: &EnvironmentName,
+        _run_command: &RunnerCommand,
+    ) -> Result<()> {
+        Err(anyhow!(
+            "a read-only home directory is not supported by the user runner"
+        ))
+    }
+
+    fn query_image(&self, _name: Option<&EnvironmentName>, script: &str) -> Result<String> {
+        query_host(script)
+    }
+
     fn supports_any(&self, targets: &[Target]) -> Result<bool> {
         Ok(targets.iter().any(|Target { arch, os }| {
             (match arch {
@@ -599,6 +855,44 @@ impl Runner for User {
             })
         }))
     }
+
+    fn clean_orphans(&self) -> Result<Vec<String>> {
+        Ok(Vec::new())
+    }
+
+    fn orphans(&self) -> Result<Vec<Orphan>> {
+        let now = self.program.clock.now();
+        let mut orphans = Vec::new();
+        for filename in try_iterdir(&self.work_tars)? {
+            // These are named `ENV-SECONDS.tar`, from when they were saved.
+            let Some(saved) = FilenameEncoder::decode(&filename)
+                .ok()
+                .as_deref()
+                .and_then(|name| name.strip_suffix(".tar"))
+                .and_then(|name| name.rsplit_once('-'))
+                .and_then(|(_, secs)| secs.parse().ok())
+                .map(|secs| UNIX_EPOCH + Duration::from_secs(secs))
+            else {
+                continue;
+            };
+            let age = now.duration_since(saved).unwrap_or_default();
+            if age >= STALE_WORK_BACKUP {
+                orphans.push(Orphan::WorkBackup {
+                    path: self.work_tars.join(filename),
+                    days: age.as_secs() / (60 * 60 * 24),
+                });
+            }
+        }
+        Ok(orphans)
+    }
+
+    fn remove_orphan(&self, orphan: &Orphan) -> Result<()> {
+        match orphan {
+            Orphan::WorkBackup { path, .. } => std::fs::remove_file(path.as_host_raw())
+                .with_context(|| format!("failed to remove {path}")),
+            _ => Err(anyhow!("the User runner has no {orphan}")),
+        }
+    }
 }
 
 /// An iterator over `/etc/passwd` accounts.
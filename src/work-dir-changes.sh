@@ -0,0 +1,29 @@
+#!/bin/sh
+# Looks for work in progress in the current directory (an environment's work
+# directory) that `cub reset` could disturb. Prints what it finds and exits
+# with status 3 if it finds anything.
+set -u
+
+found=0
+
+for git in ./.git ./*/.git; do
+    if [ -e "$git" ]; then
+        repo="$(dirname "$git")"
+        if [ -n "$(git -C "$repo" status --porcelain 2>/dev/null)" ]; then
+            echo "Uncommitted changes in Git repository: $repo"
+            found=1
+        fi
+    fi
+done
+
+recent="$(find . -name .git -prune -o -type f -mmin -10 \
+    ! -name packages.txt ! -name packages.lock ! -name '.*_history' -print 2>/dev/null | head -n 5)"
+if [ -n "$recent" ]; then
+    echo "Files modified in the last 10 minutes (up to 5 shown):"
+    echo "$recent" | sed 's/^/    /'
+    found=1
+fi
+
+if [ "$found" -ne 0 ]; then
+    exit 3
+fi
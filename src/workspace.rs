@@ -0,0 +1,438 @@
+//! Groups environments into workspaces.
+//!
+//! An environment named `proj/frontend` belongs to the workspace `proj`. A
+//! workspace must be created before environments can be added to it. Each
+//! workspace gets a directory on the host under Cubicle's data directory,
+//! holding a `workspace.json` file, and the settings that its environments
+//! share come from [`Config::workspaces`](super::config::Config::workspaces).
+
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::ffi::OsStr;
+use std::fmt::{self, Debug, Display};
+use std::io::{self, IsTerminal, Write};
+use std::str::FromStr;
+use std::time::SystemTime;
+use tracing::info;
+
+use super::fs_util::{rmtree, try_exists, try_iterdir_dirs};
+use super::runner::Runner;
+use super::{
+    time_serialize_opt, Cubicle, EnvironmentName, FilenameEncoder, Force, HostPath, Quiet,
+    ReadOnlyHome,
+};
+use crate::somehow::{somehow as anyhow, warn, Context, Error, Result};
+
+/// The name of a workspace, which groups environments.
+///
+/// Workspace names follow the same rules as [`EnvironmentName`] and also may
+/// not contain `/`, `*`, `?`, or `@`. (Package builder environments have names
+/// like `package-rust@linux/arm64`, which aren't in a workspace.)
+#[derive(Clone, Debug, Eq, Ord, PartialEq, PartialOrd, Serialize)]
+pub struct WorkspaceName(String);
+
+impl WorkspaceName {
+    /// Returns a string slice representing the workspace name.
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    /// Returns a string representing the workspace name for use as a
+    /// filename.
+    fn as_filename(&self) -> String {
+        FilenameEncoder::new().push(&self.0).encode()
+    }
+
+    /// Returns the workspace name that is encoded in the given filename, if
+    /// valid.
+    fn from_filename(filename: &OsStr) -> Result<Self> {
+        Self::from_str(&FilenameEncoder::decode(filename)?)
+    }
+}
+
+impl FromStr for WorkspaceName {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Self> {
+        if s.is_empty() {
+            return Err(anyhow!("workspace name cannot be empty"));
+        }
+        if s.starts_with(char::is_whitespace) || s.ends_with(char::is_whitespace) {
+            return Err(anyhow!(
+                "workspace name cannot start or end with whitespace"
+            ));
+        }
+        if s.contains(|c: char| c.is_ascii_control()) {
+            return Err(anyhow!("workspace name cannot contain control characters"));
+        }
+        if s.contains(['/', '*', '?', '@']) {
+            return Err(anyhow!(
+                "workspace name cannot contain '/', '*', '?', or '@'"
+            ));
+        }
+        Ok(Self(s.to_owned()))
+    }
+}
+
+impl Display for WorkspaceName {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        Debug::fmt(&self.0, f)
+    }
+}
+
+impl EnvironmentName {
+    /// Returns the workspace that the environment belongs to, if any.
+    ///
+    /// This is the part of the name before the first `/`, as long as that's
+    /// a valid workspace name and something follows the `/`.
+    pub fn workspace(&self) -> Option<WorkspaceName> {
+        match self.as_str().split_once('/') {
+            Some((workspace, rest)) if !rest.is_empty() => WorkspaceName::from_str(workspace).ok(),
+            _ => None,
+        }
+    }
+
+    /// Returns the environment's name within its workspace, like `frontend`
+    /// for `proj/frontend`, or the whole name if it's not in a workspace.
+    pub fn name_in_workspace(&self) -> &str {
+        match self.workspace() {
+            Some(workspace) => &self.as_str()[workspace.as_str().len() + 1..],
+            None => self.as_str(),
+        }
+    }
+}
+
+/// The contents of a workspace's `workspace.json`.
+///
+/// New fields must have defaults, since existing files won't have them.
+#[derive(Debug, Default, Deserialize, Serialize)]
+struct WorkspaceMetadata {
+    /// When the workspace was created.
+    #[serde(default)]
+    created: Option<SystemTime>,
+}
+
+/// Information about a workspace, as returned by [`Cubicle::get_workspaces`].
+#[derive(Debug, Serialize)]
+#[non_exhaustive]
+pub struct WorkspaceDetails {
+    /// When the workspace was created, if known.
+    #[serde(serialize_with = "time_serialize_opt")]
+    pub created: Option<SystemTime>,
+    /// The environments in the workspace, sorted by name.
+    pub environments: Vec<EnvironmentName>,
+}
+
+/// Allowed formats for [`Cubicle::list_workspaces`].
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+pub enum WorkspaceListFormat {
+    /// Human-formatted table.
+    #[default]
+    Default,
+    /// Detailed JSON output for machine consumption.
+    Json,
+    /// Newline-delimited list of workspace names only.
+    Names,
+}
+
+impl Cubicle {
+    /// Returns the directory on the host that holds the workspace's
+    /// metadata.
+    fn workspace_dir(&self, name: &WorkspaceName) -> HostPath {
+        self.shared
+            .data_dir
+            .join("workspaces")
+            .join(name.as_filename())
+    }
+
+    fn workspace_exists(&self, name: &WorkspaceName) -> Result<bool> {
+        try_exists(&self.workspace_dir(name).join("workspace.json")).todo_context()
+    }
+
+    /// Returns an error if the environment's name puts it in a workspace that
+    /// doesn't exist.
+    pub(super) fn check_workspace(&self, env: &EnvironmentName) -> Result<()> {
+        match env.workspace() {
+            Some(workspace) if !self.workspace_exists(&workspace)? => Err(anyhow!(
+                "Workspace {workspace} does not exist (try '{} workspace create {}')",
+                self.shared.exe_name,
+                workspace.as_str(),
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    /// Corresponds to `cub workspace create`.
+    pub fn create_workspace(&self, name: &WorkspaceName) -> Result<()> {
+        if self.workspace_exists(name)? {
+            return Err(anyhow!("Workspace {name} already exists"));
+        }
+        let dir = self.workspace_dir(name);
+        std::fs::create_dir_all(dir.as_host_raw())
+            .with_context(|| format!("failed to create directory {dir}"))?;
+        let path = dir.join("workspace.json");
+        let metadata = WorkspaceMetadata {
+            created: Some(self.shared.clock.now()),
+        };
+        let json = serde_json::to_string_pretty(&metadata)
+            .context("failed to serialize workspace metadata")?;
+        std::fs::write(path.as_host_raw(), json + "\n")
+            .with_context(|| format!("failed to write {path}"))?;
+        if !self.shared.config.workspaces.contains_key(name.as_str()) {
+            info!(
+                "Created workspace {name}. Its environments can share settings in a \
+                `[workspaces.{}]` section of `cubicle.toml`.",
+                name.as_str()
+            );
+        }
+        Ok(())
+    }
+
+    /// Returns the existing workspaces and their environments.
+    pub fn get_workspaces(&self) -> Result<BTreeMap<WorkspaceName, WorkspaceDetails>> {
+        let dir = self.shared.data_dir.join("workspaces");
+        let mut workspaces = BTreeMap::new();
+        for filename in try_iterdir_dirs(&dir)? {
+            let name = match WorkspaceName::from_filename(&filename) {
+                Ok(name) => name,
+                Err(e) => {
+                    warn(e.context(format!(
+                        "skipping {}: error parsing workspace name from path",
+                        dir.join(&filename)
+                    )));
+                    continue;
+                }
+            };
+            let path = dir.join(&filename).join("workspace.json");
+            let metadata: WorkspaceMetadata = match std::fs::read_to_string(path.as_host_raw()) {
+                Ok(contents) => serde_json::from_str(&contents)
+                    .with_context(|| format!("failed to parse {path}"))?,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(e).with_context(|| format!("failed to read {path}")),
+            };
+            workspaces.insert(
+                name,
+                WorkspaceDetails {
+                    created: metadata.created,
+                    environments: Vec::new(),
+                },
+            );
+        }
+        for env in self.get_environment_names()? {
+            if let Some(details) = env
+                .workspace()
+                .and_then(|workspace| workspaces.get_mut(&workspace))
+            {
+                details.environments.push(env);
+            }
+        }
+        Ok(workspaces)
+    }
+
+    /// Returns the environments in the workspace, sorted by name.
+    pub fn workspace_environments(&self, name: &WorkspaceName) -> Result<Vec<EnvironmentName>> {
+        if !self.workspace_exists(name)? {
+            return Err(anyhow!("Workspace {name} does not exist"));
+        }
+        Ok(self
+            .get_environment_names()?
+            .into_iter()
+            .filter(|env| env.workspace().as_ref() == Some(name))
+            .collect())
+    }
+
+    /// Corresponds to `cub workspace list`.
+    pub fn list_workspaces(&self, format: WorkspaceListFormat) -> Result<()> {
+        let workspaces = self.get_workspaces()?;
+        match format {
+            WorkspaceListFormat::Default => {
+                let w = workspaces
+                    .keys()
+                    .map(|name| name.as_str().len())
+                    .max()
+                    .unwrap_or(0);
+                for (name, details) in &workspaces {
+                    let envs = details
+                        .environments
+                        .iter()
+                        .map(EnvironmentName::name_in_workspace)
+                        .collect::<Vec<_>>();
+                    println!(
+                        "{:<w$} | {} environment{}{}",
+                        name.as_str(),
+                        envs.len(),
+                        if envs.len() == 1 { "" } else { "s" },
+                        if envs.is_empty() {
+                            String::new()
+                        } else {
+                            format!(": {}", envs.join(", "))
+                        }
+                    );
+                }
+            }
+
+            WorkspaceListFormat::Json => {
+                println!(
+                    "{}",
+                    serde_json::to_string_pretty(&workspaces)
+                        .context("failed to serialize JSON while listing workspaces")?
+                );
+            }
+
+            WorkspaceListFormat::Names => {
+                for name in workspaces.keys() {
+                    println!("{}", name.as_str());
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Corresponds to `cub workspace enter`.
+    ///
+    /// Runs a shell in the workspace's only environment, or asks which one to
+    /// enter if it has several.
+    pub fn enter_workspace(&self, name: &WorkspaceName) -> Result<()> {
+        let envs = self.workspace_environments(name)?;
+        let env = match envs.as_slice() {
+            [] => {
+                return Err(anyhow!(
+                    "Workspace {name} has no environments (try '{} new {}/NAME')",
+                    self.shared.exe_name,
+                    name.as_str()
+                ))
+            }
+            [env] => env,
+            _ => {
+                let choices = envs
+                    .iter()
+                    .map(EnvironmentName::name_in_workspace)
+                    .collect::<Vec<_>>();
+                if !io::stdin().is_terminal() {
+                    return Err(anyhow!(
+                        "Workspace {name} has several environments ({}); \
+                        use '{} enter' with one of them",
+                        choices.join(", "),
+                        self.shared.exe_name,
+                    ));
+                }
+                for (i, choice) in choices.iter().enumerate() {
+                    println!("{:>3}) {choice}", i + 1);
+                }
+                print!("Enter which environment? ");
+                io::stdout().flush().context("failed to flush stdout")?;
+                let mut answer = String::new();
+                io::stdin()
+                    .read_line(&mut answer)
+                    .context("failed to read answer from stdin")?;
+                match parse_choice(answer.trim(), &choices) {
+                    Some(i) => &envs[i],
+                    None => {
+                        return Err(anyhow!(
+                            "No environment {:?} in workspace {name}",
+                            answer.trim()
+                        ))
+                    }
+                }
+            }
+        };
+        self.enter_environment(env, ReadOnlyHome(false), None)
+    }
+
+    /// Corresponds to `cub workspace stop`.
+    ///
+    /// Stops the workspace's environments that are running.
+    pub fn stop_workspace(&self, name: &WorkspaceName) -> Result<()> {
+        for env in self.workspace_environments(name)? {
+            if self.runner.is_running(&env)? {
+                self.stop_environment(&env)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Corresponds to `cub workspace purge`.
+    ///
+    /// Purges the workspace's environments, then the workspace itself. Unless
+    /// `force` is set, this asks the [`Confirmer`](super::Confirmer) first.
+    pub fn purge_workspace(&self, name: &WorkspaceName, force: Force) -> Result<()> {
+        let envs = self.workspace_environments(name)?;
+        if !force.0 && !envs.is_empty() {
+            let question = format!(
+                "Purge workspace {name} and its environments ({}), including their work \
+                directories?",
+                envs.iter()
+                    .map(EnvironmentName::name_in_workspace)
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            );
+            if !self.shared.confirm(&question)? {
+                return Err(anyhow!("Not purging workspace {name}"));
+            }
+        }
+        for env in envs {
+            self.purge_environment(&env, Quiet(true), Force(true))?;
+        }
+        self.runner.purge_workspace(name)?;
+        rmtree(&self.workspace_dir(name))
+    }
+}
+
+/// Returns the index of the choice that the user picked, by number (starting
+/// at 1) or by name.
+fn parse_choice(answer: &str, choices: &[&str]) -> Option<usize> {
+    match answer.parse::<usize>() {
+        Ok(n) if (1..=choices.len()).contains(&n) => Some(n - 1),
+        _ => choices.iter().position(|choice| *choice == answer),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn workspace_name() {
+        assert!(WorkspaceName::from_str("proj").is_ok());
+        assert!(WorkspaceName::from_str("my proj").is_ok());
+        for bad in ["", " proj", "a/b", "p*", "p?", "package-rust@linux"] {
+            assert!(
+                WorkspaceName::from_str(bad).is_err(),
+                "{bad:?} should be rejected"
+            );
+        }
+    }
+
+    #[test]
+    fn environment_workspace() {
+        let workspace = |env: &str| {
+            EnvironmentName::from_str(env)
+                .unwrap()
+                .workspace()
+                .map(|workspace| workspace.0)
+        };
+        assert_eq!(Some(String::from("proj")), workspace("proj/frontend"));
+        assert_eq!(Some(String::from("proj")), workspace("proj/a/b"));
+        assert_eq!(None, workspace("proj"));
+        assert_eq!(None, workspace("proj/"));
+        assert_eq!(None, workspace("/frontend"));
+        assert_eq!(None, workspace("package-rust@linux/arm64"));
+
+        let env = EnvironmentName::from_str("proj/a/b").unwrap();
+        assert_eq!("a/b", env.name_in_workspace());
+        let env = EnvironmentName::from_str("web").unwrap();
+        assert_eq!("web", env.name_in_workspace());
+    }
+
+    #[test]
+    fn parse_choice() {
+        let choices = ["backend", "frontend", "2"];
+        assert_eq!(Some(0), super::parse_choice("1", &choices));
+        assert_eq!(Some(1), super::parse_choice("2", &choices));
+        assert_eq!(Some(1), super::parse_choice("frontend", &choices));
+        assert_eq!(None, super::parse_choice("0", &choices));
+        assert_eq!(None, super::parse_choice("4", &choices));
+        assert_eq!(None, super::parse_choice("db", &choices));
+        assert_eq!(None, super::parse_choice("", &choices));
+    }
+}